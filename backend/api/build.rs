@@ -0,0 +1,5 @@
+//! Rebuilds the API whenever a migration is added or changed, since migrations
+//! are embedded into the binary.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,5 @@
+-- Orders may be fulfilled an item at a time, with each item recording how
+-- many of its units have been fulfilled so far.
+ALTER TYPE app_order_status ADD VALUE 'PartiallyFulfilled' BEFORE 'Fulfilled';
+ALTER TABLE order_item
+    ADD COLUMN fulfilled_count BIGINT NOT NULL DEFAULT 0 CHECK (fulfilled_count >= 0 AND fulfilled_count <= count);
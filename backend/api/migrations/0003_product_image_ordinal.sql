@@ -0,0 +1,3 @@
+-- The position of each image among its product's images, so that the first
+-- (primary) image can be chosen. Existing images are left unordered.
+ALTER TABLE product_image ADD COLUMN ordinal INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- When each user last logged in, so that inactive accounts can be found. NULL
+-- for users who have not logged in since this was recorded.
+ALTER TABLE appuser ADD COLUMN last_login_at TIMESTAMP;
@@ -0,0 +1,13 @@
+-- The stock held of each product, with an audit trail of every adjustment
+-- made to it and by which administrator.
+ALTER TABLE product ADD COLUMN stock BIGINT NOT NULL DEFAULT 0 CHECK (stock >= 0);
+CREATE TABLE stock_adjustment (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    product_id UUID NOT NULL,
+    administrator_id UUID,
+    delta BIGINT NOT NULL,
+    resulting_stock BIGINT NOT NULL,
+    adjusted_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_product FOREIGN KEY (product_id) REFERENCES product(id) ON DELETE CASCADE,
+    CONSTRAINT fk_administrator FOREIGN KEY (administrator_id) REFERENCES appuser(id) ON DELETE SET NULL
+);
@@ -0,0 +1,9 @@
+-- Stripe payment events, recorded when received and processed later so that
+-- a failure confirming the order does not lose the event.
+CREATE TABLE stripe_event (
+    id TEXT PRIMARY KEY,
+    order_id UUID NOT NULL,
+    received_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    processed_at TIMESTAMP
+);
@@ -0,0 +1,2 @@
+-- An optional (encrypted) E.164 phone number for each user.
+ALTER TABLE appuser ADD COLUMN phone BYTEA;
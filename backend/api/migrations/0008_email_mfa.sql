@@ -0,0 +1,3 @@
+-- Whether each user has opted in to one-time codes sent by email as a second
+-- factor.
+ALTER TABLE appuser ADD COLUMN email_mfa_enabled BOOLEAN NOT NULL DEFAULT false;
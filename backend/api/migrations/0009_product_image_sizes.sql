@@ -0,0 +1,9 @@
+-- The resized copies generated of each product image, one per width.
+CREATE TABLE product_image_size (
+    product_id UUID NOT NULL,
+    path TEXT NOT NULL,
+    width INTEGER NOT NULL,
+    size_path TEXT NOT NULL,
+    PRIMARY KEY(product_id, path, width),
+    CONSTRAINT fk_product_image FOREIGN KEY (product_id, path) REFERENCES product_image(product_id, path) ON DELETE CASCADE ON UPDATE CASCADE
+);
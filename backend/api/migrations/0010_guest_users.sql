@@ -0,0 +1,3 @@
+-- Guest users, created for guest checkout, which have no password and may be
+-- upgraded to full accounts.
+ALTER TABLE appuser ADD COLUMN guest BOOLEAN NOT NULL DEFAULT false;
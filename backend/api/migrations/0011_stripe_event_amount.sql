@@ -0,0 +1,5 @@
+-- The amount a Stripe payment actually received, checked against the order's
+-- amount charged before it is confirmed, since unconfirmed orders may be
+-- changed after payment has begun. NULL for events received before this was
+-- recorded.
+ALTER TABLE stripe_event ADD COLUMN amount BIGINT;
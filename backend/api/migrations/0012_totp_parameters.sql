@@ -0,0 +1,9 @@
+-- Record the parameters each TOTP secret generates codes with, so that
+-- changing the configured parameters does not invalidate existing secrets.
+-- Existing secrets were all created with the RFC 6238 defaults.
+CREATE TYPE totp_algorithm AS ENUM ('Sha1', 'Sha256', 'Sha512');
+
+ALTER TABLE totp
+    ADD COLUMN algorithm totp_algorithm NOT NULL DEFAULT 'Sha1',
+    ADD COLUMN digits SMALLINT NOT NULL DEFAULT 6,
+    ADD COLUMN step INTEGER NOT NULL DEFAULT 30;
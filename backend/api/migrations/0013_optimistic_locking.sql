@@ -0,0 +1,5 @@
+-- Versions used to detect concurrent updates to orders and products. Each
+-- update increments the version, and only applies if the version is unchanged
+-- since the record was read.
+ALTER TABLE apporder ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE product ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
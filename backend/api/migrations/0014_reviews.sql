@@ -0,0 +1,13 @@
+-- Reviews of products by the customers who purchased them. Each customer may
+-- review each product at most once.
+CREATE TABLE review (
+    user_id UUID NOT NULL,
+    product_id UUID NOT NULL,
+    rating SMALLINT NOT NULL CHECK (rating >= 1 AND rating <= 5),
+    body TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    PRIMARY KEY (user_id, product_id),
+    CONSTRAINT fk_user FOREIGN KEY (user_id) REFERENCES appuser(id) ON DELETE CASCADE,
+    CONSTRAINT fk_product FOREIGN KEY (product_id) REFERENCES product(id) ON DELETE CASCADE
+);
+CREATE INDEX review_product_id ON review (product_id);
@@ -0,0 +1,9 @@
+-- Aggregate statistics about each product, such as how often it has been
+-- viewed. Views are counted in the session store and added here periodically,
+-- so that popular products don't cause a write to the same row on every view.
+CREATE TABLE product_stats (
+    product_id UUID PRIMARY KEY,
+    views BIGINT NOT NULL DEFAULT 0 CHECK (views >= 0),
+    CONSTRAINT fk_product FOREIGN KEY (product_id) REFERENCES product(id) ON DELETE CASCADE
+);
+CREATE INDEX product_stats_views ON product_stats (views DESC);
@@ -0,0 +1,10 @@
+-- Discount codes which customers can apply to an order, taking a percentage
+-- off its total. A coupon may expire, and may be limited to a number of
+-- redemptions.
+CREATE TABLE coupon (
+    code TEXT PRIMARY KEY,
+    percent_off SMALLINT NOT NULL CHECK (percent_off >= 1 AND percent_off <= 100),
+    expires_at TIMESTAMP,
+    max_redemptions INTEGER CHECK (max_redemptions > 0),
+    redemptions INTEGER NOT NULL DEFAULT 0 CHECK (redemptions >= 0)
+);
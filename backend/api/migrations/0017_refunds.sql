@@ -0,0 +1,19 @@
+-- Refunds of paid orders, which may be partial. The amount refunded so far is
+-- kept on the order, and every refund is recorded for auditing.
+ALTER TYPE app_order_status ADD VALUE 'PartiallyRefunded';
+ALTER TYPE app_order_status ADD VALUE 'Refunded';
+ALTER TABLE apporder ADD COLUMN refunded_amount BIGINT NOT NULL DEFAULT 0
+    CHECK (refunded_amount >= 0);
+-- The PaymentIntent a Stripe payment was made through, which refunds are
+-- issued against. NULL for events received before this was recorded.
+ALTER TABLE stripe_event ADD COLUMN payment_intent TEXT;
+CREATE TABLE order_refund (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL,
+    administrator_id UUID,
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    stripe_refund TEXT,
+    refunded_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_order FOREIGN KEY (order_id) REFERENCES apporder(id) ON DELETE CASCADE,
+    CONSTRAINT fk_administrator FOREIGN KEY (administrator_id) REFERENCES appuser(id) ON DELETE SET NULL
+);
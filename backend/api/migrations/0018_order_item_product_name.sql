@@ -0,0 +1,7 @@
+-- The name of each ordered product as it was when the order was placed, so
+-- that orders keep showing what was bought after a product is renamed.
+-- Existing items are given their product's current name.
+ALTER TABLE order_item ADD COLUMN product_name TEXT;
+UPDATE order_item SET product_name = product.name
+    FROM product WHERE product.id = order_item.product_id;
+ALTER TABLE order_item ALTER COLUMN product_name SET NOT NULL;
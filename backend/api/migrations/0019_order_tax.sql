@@ -0,0 +1,8 @@
+-- The tax breakdown of each order, so invoices show the tax actually
+-- charged. amount_charged remains the total, i.e. subtotal plus tax. Orders
+-- placed before tax was recorded are treated as untaxed.
+ALTER TABLE apporder ADD COLUMN subtotal BIGINT,
+    ADD COLUMN tax BIGINT NOT NULL DEFAULT 0 CHECK (tax >= 0);
+UPDATE apporder SET subtotal = amount_charged;
+ALTER TABLE apporder ALTER COLUMN subtotal SET NOT NULL,
+    ADD CHECK (subtotal >= 0);
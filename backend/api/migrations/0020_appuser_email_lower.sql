@@ -0,0 +1,4 @@
+-- Emails are unique regardless of case, enforced by the database since
+-- checking before inserting is not atomic with the insert. Fails if
+-- existing users' emails differ only in case, which must be resolved first.
+CREATE UNIQUE INDEX appuser_email_lower ON appuser (lower(email));
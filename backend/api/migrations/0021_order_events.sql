@@ -0,0 +1,16 @@
+-- Every status an order has passed through, forming its timeline. The actor
+-- is the user whose action caused the transition, or NULL if it happened
+-- without one (e.g. a payment confirmation from Stripe). Existing orders are
+-- given only the event of being placed, since their history is unknown.
+CREATE TABLE order_event (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    order_id UUID NOT NULL,
+    status app_order_status NOT NULL,
+    actor_id UUID,
+    occurred_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_order FOREIGN KEY (order_id) REFERENCES apporder(id) ON DELETE CASCADE,
+    CONSTRAINT fk_actor FOREIGN KEY (actor_id) REFERENCES appuser(id) ON DELETE SET NULL
+);
+CREATE INDEX order_event_order_id ON order_event (order_id);
+INSERT INTO order_event (order_id, status, actor_id, occurred_at)
+    SELECT id, 'Unconfirmed', user_id, order_placed FROM apporder;
@@ -0,0 +1,6 @@
+-- The shipping weight and packaged dimensions of each product, used to
+-- calculate shipping. They are optional, as existing products have none.
+ALTER TABLE product ADD COLUMN weight_grams BIGINT CHECK (weight_grams >= 0),
+    ADD COLUMN length_mm BIGINT CHECK (length_mm >= 0),
+    ADD COLUMN width_mm BIGINT CHECK (width_mm >= 0),
+    ADD COLUMN height_mm BIGINT CHECK (height_mm >= 0);
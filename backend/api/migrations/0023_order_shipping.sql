@@ -0,0 +1,3 @@
+-- The shipping cost of each order, which is included in amount_charged on
+-- top of the subtotal and tax. Existing orders were not charged shipping.
+ALTER TABLE apporder ADD COLUMN shipping BIGINT NOT NULL DEFAULT 0 CHECK (shipping >= 0);
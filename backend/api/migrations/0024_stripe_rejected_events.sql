@@ -0,0 +1,10 @@
+-- Verified Stripe webhook events which could never be processed, e.g.
+-- because their order_id metadata is missing or malformed. They are
+-- acknowledged so that Stripe stops redelivering them, and kept here to be
+-- investigated.
+CREATE TABLE stripe_rejected_event (
+    id TEXT PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    received_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc')
+);
@@ -0,0 +1,4 @@
+-- The shipping method each order is to be sent by, chosen by the customer.
+-- Existing orders were all sent by the standard method.
+CREATE TYPE shipping_method AS ENUM ('Standard', 'Express');
+ALTER TABLE apporder ADD COLUMN shipping_method shipping_method NOT NULL DEFAULT 'Standard';
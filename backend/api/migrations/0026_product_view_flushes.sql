@@ -0,0 +1,8 @@
+-- The batches of product view counts which have been added to product_stats,
+-- so that a batch added again after the API stopped part way through
+-- flushing it is not counted twice. Batches are kept only long enough for
+-- the API to be restarted.
+CREATE TABLE product_view_flush (
+    batch_id UUID PRIMARY KEY,
+    flushed_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc')
+);
@@ -0,0 +1,10 @@
+-- The discount taken off each order before tax, recorded alongside the
+-- subtotal (which is after the discount), tax and shipping. Orders placed
+-- before discounts were recorded had none. amount_charged is now required
+-- to be the sum of the subtotal, tax and shipping; any legacy order for
+-- which it is not is treated as charged its subtotal only.
+ALTER TABLE apporder ADD COLUMN discount BIGINT NOT NULL DEFAULT 0 CHECK (discount >= 0);
+UPDATE apporder SET subtotal = amount_charged, tax = 0, shipping = 0
+    WHERE amount_charged <> subtotal + tax + shipping;
+ALTER TABLE apporder ADD CONSTRAINT apporder_amount_charged_sum
+    CHECK (amount_charged = subtotal + tax + shipping);
@@ -0,0 +1,5 @@
+-- When each user's account was deleted, or NULL if it has not been. Deleted
+-- accounts can't be logged in to, and can be restored by an administrator
+-- until the grace period after deletion passes, when they are purged.
+ALTER TABLE appuser ADD COLUMN deleted_at TIMESTAMP;
+CREATE INDEX appuser_deleted_at ON appuser (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,12 @@
+-- Requests from customers to be emailed when an out of stock product is back
+-- in stock. Each customer may ask about each product at most once, and their
+-- request is removed when the notification is sent.
+CREATE TABLE stock_notification (
+    user_id UUID NOT NULL,
+    product_id UUID NOT NULL,
+    requested_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+    PRIMARY KEY (user_id, product_id),
+    CONSTRAINT fk_user FOREIGN KEY (user_id) REFERENCES appuser(id) ON DELETE CASCADE,
+    CONSTRAINT fk_product FOREIGN KEY (product_id) REFERENCES product(id) ON DELETE CASCADE
+);
+CREATE INDEX stock_notification_product_id ON stock_notification (product_id);
@@ -0,0 +1,6 @@
+-- A new guest user is created for every guest order, rather than an existing
+-- one being reused by anyone who knows its email, so several guests may share
+-- an email. Emails remain unique (regardless of case) among registered users.
+ALTER TABLE appuser DROP CONSTRAINT appuser_email_key;
+DROP INDEX appuser_email_lower;
+CREATE UNIQUE INDEX appuser_email_lower ON appuser (lower(email)) WHERE NOT guest;
@@ -0,0 +1,9 @@
+-- A product's stock may be left unmanaged (NULL), in which case it can be
+-- ordered in any quantity. Products whose stock has never been adjusted are
+-- made unmanaged, since their stock of 0 was only ever the column's default,
+-- and would otherwise make the whole existing catalog unorderable.
+ALTER TABLE product ALTER COLUMN stock DROP NOT NULL, ALTER COLUMN stock DROP DEFAULT;
+UPDATE product SET stock = NULL
+WHERE stock = 0 AND NOT EXISTS (
+    SELECT 1 FROM stock_adjustment WHERE stock_adjustment.product_id = product.id
+);
@@ -0,0 +1,6 @@
+-- Refunds are recorded before Stripe is asked to make them, and settled once
+-- it has, so that no transaction is held open while waiting on Stripe. The
+-- ID of a pending refund is its idempotency key, so retrying it is safe.
+-- Refunds recorded before this were all made while being recorded.
+ALTER TABLE order_refund ADD COLUMN settled_at TIMESTAMP;
+UPDATE order_refund SET settled_at = refunded_at;
@@ -0,0 +1,4 @@
+-- Whether the user has proven they can receive SMS messages at their phone
+-- number. SMS MFA is only offered once they have, so phone numbers stored
+-- before this start unverified.
+ALTER TABLE appuser ADD COLUMN phone_verified BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,5 @@
+-- The coupon redeemed when an order was placed, if any, and the percentage it
+-- takes off, so that the discount is kept when the order is repriced.
+ALTER TABLE apporder
+    ADD COLUMN coupon_code TEXT REFERENCES coupon (code),
+    ADD COLUMN percent_off SMALLINT NOT NULL DEFAULT 0 CHECK (percent_off >= 0 AND percent_off <= 100);
@@ -0,0 +1,14 @@
+-- The price of each ordered product and the address an order is shipped to,
+-- as they were when the order was placed, so that invoices keep showing what
+-- was charged and where it was sent after a price or the customer's address
+-- changes. Existing orders are given their products' current prices and
+-- their customers' current addresses. Addresses stay encrypted, as on appuser.
+ALTER TABLE order_item ADD COLUMN unit_price BIGINT CHECK (unit_price >= 0);
+UPDATE order_item SET unit_price = product.price
+    FROM product WHERE product.id = order_item.product_id;
+ALTER TABLE order_item ALTER COLUMN unit_price SET NOT NULL;
+
+ALTER TABLE apporder ADD COLUMN shipping_address BYTEA;
+UPDATE apporder SET shipping_address = appuser.address
+    FROM appuser WHERE appuser.id = apporder.user_id;
+ALTER TABLE apporder ALTER COLUMN shipping_address SET NOT NULL;
@@ -0,0 +1,17 @@
+-- A partial refund no longer changes an order's status, since that lost how
+-- far the order had been fulfilled and stopped the rest of it being shipped.
+-- How much has been refunded is kept in refunded_amount alone. Partially
+-- refunded orders are given back the status their items' fulfilment implies.
+-- PartiallyRefunded stays in the type, as enum values cannot be dropped.
+UPDATE apporder SET status = CASE
+    WHEN NOT EXISTS (
+        SELECT 1 FROM order_item
+        WHERE order_item.order_id = apporder.id AND order_item.fulfilled_count < order_item.count
+    ) THEN 'Fulfilled'::app_order_status
+    WHEN EXISTS (
+        SELECT 1 FROM order_item
+        WHERE order_item.order_id = apporder.id AND order_item.fulfilled_count > 0
+    ) THEN 'PartiallyFulfilled'::app_order_status
+    ELSE 'Confirmed'::app_order_status
+END
+WHERE status = 'PartiallyRefunded';
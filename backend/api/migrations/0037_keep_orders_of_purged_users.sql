@@ -0,0 +1,8 @@
+-- Purging a deleted user used to cascade to their orders, destroying the
+-- financial records of confirmed ones. Orders now keep their user from being
+-- deleted, and a purged user with confirmed orders is anonymized instead:
+-- their personal details and credentials are removed, and the row is marked
+-- so that it is neither purged again nor restored.
+ALTER TABLE apporder DROP CONSTRAINT fk_user,
+    ADD CONSTRAINT fk_user FOREIGN KEY (user_id) REFERENCES appuser(id) ON DELETE RESTRICT;
+ALTER TABLE appuser ADD COLUMN anonymized BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,4 @@
+-- Products which have been ordered can't be deleted without losing the
+-- order's items, so they are archived instead: kept, but never listed or
+-- retrieved again.
+ALTER TABLE product ADD COLUMN archived_at TIMESTAMP;
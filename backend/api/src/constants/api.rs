@@ -1,7 +1,22 @@
 //! Constants related to the general configuration of the entire API and its deployment.
-
-use std::{env::var, sync::LazyLock};
+use super::config::config;
+use std::sync::LazyLock;
 
 /// A prefix to prepend to any API paths to make them externally accessible.
-pub static API_URI_PREFIX: LazyLock<String> =
-    LazyLock::new(|| var("API_URI_PREFIX").unwrap_or_else(|_| String::from("/")));
+pub static API_URI_PREFIX: LazyLock<String> = LazyLock::new(|| config().api_uri_prefix.clone());
+
+/// The scheme and host the store is accessed at externally (e.g.
+/// `https://shop.example.com`), used to build absolute links in emails. Can be
+/// left blank, in which case links are relative, which most email clients
+/// will not follow.
+pub static PUBLIC_URI: LazyLock<String> = LazyLock::new(|| config().public_uri.clone());
+
+/// The most fields of a multipart form which are read looking for an upload,
+/// read from `MAX_MULTIPART_FIELDS`, so that a form padded with junk fields
+/// can't keep a request busy indefinitely. Defaults to 10.
+pub static MAX_MULTIPART_FIELDS: LazyLock<u32> = LazyLock::new(|| config().max_multipart_fields);
+
+/// Whether the API may seed the database with fixture data when started with
+/// `--seed`. Must never be set in production, since the fixtures include
+/// users with publically known credentials.
+pub static ALLOW_SEED: LazyLock<bool> = LazyLock::new(|| config().allow_seed);
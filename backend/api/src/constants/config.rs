@@ -0,0 +1,830 @@
+//! The API's configuration, read from environment variables (and Docker
+//! secrets) and validated all at once at startup, so that a missing or invalid
+//! setting stops the API from starting rather than failing a request later.
+//! The statics in the other constants modules read from this.
+use super::{address::COUNTRY_CODES, secrets::read_secret};
+#[cfg(test)]
+use crate::testing;
+use crate::{db::models::totp::TotpAlgorithm, utils::phone::PhoneNumber};
+use axum::http::{HeaderName, Method};
+use axum_extra::extract::cookie::SameSite;
+use core::{str::FromStr, time::Duration};
+use object_store::path::Path;
+use std::{env::var, sync::OnceLock};
+
+/// The configuration loaded at startup.
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The settings for delivering SMS messages through Twilio.
+pub struct TwilioSettings {
+    /// The SID of the Twilio account messages are sent through.
+    pub account_sid: String,
+    /// The auth token for the Twilio account.
+    pub auth_token: String,
+    /// The phone number messages are sent from, in E.164 format.
+    pub from_number: String,
+}
+
+/// The settings for delivering emails through Mailgun.
+pub struct MailgunSettings {
+    /// The base URI of the Mailgun API, which differs by region.
+    pub api_base: String,
+    /// The sending domain emails are sent through.
+    pub domain: String,
+    /// The API key to authenticate to Mailgun with.
+    pub api_key: String,
+    /// The sender emails are sent from, e.g. `SecureCart <noreply@example.com>`.
+    pub from: String,
+}
+
+/// Every setting the API reads from its environment. Named after the
+/// environment variables they are read from.
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "Each bool is an independent setting read from the environment"
+)]
+pub struct Config {
+    /// A prefix to prepend to any API paths to make them externally accessible.
+    pub api_uri_prefix: String,
+    /// The scheme and host the store is accessed at, for links in emails.
+    pub public_uri: String,
+    /// The most fields of a multipart form which are read looking for an upload.
+    pub max_multipart_fields: u32,
+    /// Whether the API may seed the database with fixture data.
+    pub allow_seed: bool,
+    /// The country assumed for addresses stored before addresses were structured.
+    pub legacy_address_country: String,
+    /// The name of the cookie carrying the session token.
+    pub session_cookie_name: String,
+    /// The name of the cookie carrying the session's CSRF token.
+    pub csrf_cookie_name: String,
+    /// The name of the request header which must carry the CSRF token.
+    pub csrf_header_name: HeaderName,
+    /// Whether cookies are only sent over HTTPS.
+    pub cookie_secure: bool,
+    /// The `SameSite` policy for cookies.
+    pub cookie_samesite: SameSite,
+    /// The domain cookies are scoped to, if any.
+    pub cookie_domain: Option<String>,
+    /// The hostname where the database server is accessible.
+    pub db_host: String,
+    /// The database to connect to on the database server.
+    pub db_database: String,
+    /// The username to authenticate to the database server with.
+    pub db_username: String,
+    /// The password to authenticate to the database with.
+    pub db_password: String,
+    /// A connection string for a read-only replica of the database, if any.
+    pub db_replica_url: Option<String>,
+    /// The maximum number of connections held open by each database pool.
+    pub db_max_connections: u32,
+    /// How long to wait for a pooled database connection.
+    pub db_acquire_timeout: Duration,
+    /// How long a pooled database connection may sit idle before being closed.
+    pub db_idle_timeout: Duration,
+    /// The key to encrypt sensitive data in the database with.
+    pub db_encryption_key: String,
+    /// Whether pending database migrations should be applied on startup.
+    pub run_migrations: bool,
+    /// Whether personally identifiable information is logged in full.
+    pub log_pii: bool,
+    /// The number of results returned by a list endpoint by default.
+    pub default_page_size: u32,
+    /// The most results a list endpoint will return at once.
+    pub max_page_size: u32,
+    /// The most registration and preauthentication sessions a single client
+    /// may have in progress at once.
+    pub max_pending_sessions_per_ip: u32,
+    /// The interval in seconds between reconciliations of the session store's indexes.
+    pub session_reconcile_interval_secs: u64,
+    /// The period in seconds during which a deleted user can be restored.
+    pub user_deletion_grace_period_secs: u32,
+    /// The request methods exempt from CSRF checks.
+    pub csrf_exempt_methods: Vec<Method>,
+    /// The most signups a single client may start within one rate limit window.
+    pub signup_rate_limit_attempts: u32,
+    /// The length in seconds of the window within which a client's signups are counted.
+    pub signup_rate_limit_window_secs: u32,
+    /// The most orders a single user may create within one rate limit window.
+    pub order_rate_limit_attempts: u32,
+    /// The length in seconds of the window within which a user's orders are counted.
+    pub order_rate_limit_window_secs: u32,
+    /// Whether product prices already include tax.
+    pub prices_include_tax: bool,
+    /// The tax rate applied to orders, in basis points (hundredths of a percent).
+    pub tax_rate_basis_points: u32,
+    /// The flat shipping cost of every order in pennies, if shipping is charged.
+    pub shipping_flat_rate: Option<u32>,
+    /// The flat cost of express shipping in pennies, if it is offered.
+    pub shipping_express_rate: Option<u32>,
+    /// The countries express shipping is offered to.
+    pub shipping_express_countries: Vec<String>,
+    /// The hostname where the Redis session store can be found.
+    pub redis_host: String,
+    /// The index of the Redis database to use.
+    pub redis_database: u32,
+    /// A prefix prepended to every key the session store uses.
+    pub redis_key_prefix: String,
+    /// The maximum number of attempts made for a failing session store operation.
+    pub redis_retry_attempts: u32,
+    /// The delay in milliseconds before the first retry of a session store operation.
+    pub redis_retry_backoff_ms: u64,
+    /// How long to wait when establishing the connection to Redis.
+    pub redis_connection_timeout: Duration,
+    /// How long to wait for a response to a single Redis command.
+    pub redis_response_timeout: Duration,
+    /// The hostname where the S3-compatible storage service can be accessed.
+    pub s3_host: String,
+    /// The port where the S3-compatible storage service can be accessed.
+    pub s3_port: u16,
+    /// The bucket where application media data is stored.
+    pub s3_bucket: String,
+    /// The access key (user) to authenticate to the store with.
+    pub s3_access_key: String,
+    /// The secret key (password) to authenticate to the store with.
+    pub s3_secret_key: String,
+    /// A URI where the store can be accessed from outside the internal network.
+    pub s3_external_uri: String,
+    /// The prefix within the bucket under which inline images are stored.
+    pub s3_image_prefix: String,
+    /// The prefix within the bucket under which downloadable images are stored.
+    pub s3_download_prefix: String,
+    /// The maximum number of attempts made for a failing object store operation.
+    pub s3_retry_attempts: u32,
+    /// The delay in milliseconds before the first retry of an object store operation.
+    pub s3_retry_backoff_ms: u64,
+    /// The maximum number of attempts made to connect to each dependency at startup.
+    pub startup_retry_attempts: u32,
+    /// The delay in milliseconds before the first retry of a connection at startup.
+    pub startup_retry_backoff_ms: u64,
+    #[cfg(feature = "stripe")]
+    /// The secret key to authenticate to Stripe with.
+    pub stripe_secret_key: String,
+    #[cfg(feature = "stripe")]
+    /// The secret used to verify Stripe webhook signatures.
+    pub stripe_webhook_secret: String,
+    #[cfg(feature = "stripe")]
+    /// The publishable key given to clients to take payments with.
+    pub stripe_publishable_key: String,
+    /// The length in bytes of generated TOTP secrets.
+    pub totp_secret_length: usize,
+    /// The number of digits in TOTP codes.
+    pub totp_digits: i16,
+    /// The number of seconds each TOTP code is valid for.
+    pub totp_step: i32,
+    /// The HMAC algorithm used to generate TOTP codes.
+    pub totp_algorithm: TotpAlgorithm,
+    /// The Twilio account SMS messages are sent through, if any. SMS features
+    /// are disabled without one.
+    pub twilio: Option<TwilioSettings>,
+    /// The Mailgun domain emails are sent through, if any. Email features are
+    /// disabled without one.
+    pub mailgun: Option<MailgunSettings>,
+}
+
+/// Read a variable which must be set.
+fn required<L: Fn(&str) -> Option<String>>(
+    lookup: &L,
+    name: &'static str,
+) -> Result<String, errors::ConfigError> {
+    lookup(name).ok_or(errors::ConfigError::Missing(name))
+}
+
+/// Read a secret which must either be set directly, or be the name of a
+/// Docker secret set in `<name>_DOCKER_SECRET`.
+fn secret<L: Fn(&str) -> Option<String>>(
+    lookup: &L,
+    name: &'static str,
+) -> Result<String, errors::ConfigError> {
+    if let Some(value) = lookup(name) {
+        return Ok(value);
+    }
+    let secret_path =
+        lookup(&format!("{name}_DOCKER_SECRET")).ok_or(errors::ConfigError::MissingSecret(name))?;
+    read_secret(&secret_path)
+        .map_err(|source| errors::ConfigError::UnreadableSecret { name, source })
+}
+
+/// Read a flag, which is only set if its value is exactly "true".
+fn flag<L: Fn(&str) -> Option<String>>(lookup: &L, name: &str) -> bool {
+    lookup(name).is_some_and(|value| value == "true")
+}
+
+/// Parse a variable, or use `default` if it is not set. Values which fail to
+/// parse, or which `valid` rejects, are errors.
+fn parsed<T: FromStr, L: Fn(&str) -> Option<String>>(
+    lookup: &L,
+    name: &'static str,
+    default: T,
+    valid: fn(&T) -> bool,
+    expected: &'static str,
+) -> Result<T, errors::ConfigError> {
+    lookup(name).map_or(Ok(default), |value| {
+        value
+            .parse()
+            .ok()
+            .filter(&valid)
+            .ok_or(errors::ConfigError::Invalid { name, expected })
+    })
+}
+
+/// Whether a name is safe to use as a cookie name, i.e. non-empty and made up
+/// only of ASCII letters, digits, '-' and '_'.
+fn valid_cookie_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || matches!(character, '-' | '_'))
+}
+
+/// Whether a prefix is safe to prepend to Redis keys, i.e. made up only of
+/// ASCII letters, digits, '-', '_', '.' and ':'. In particular, it must not
+/// contain glob characters, since keys are searched for by pattern.
+fn valid_redis_key_prefix(prefix: &str) -> bool {
+    prefix.chars().all(|character| {
+        character.is_ascii_alphanumeric() || matches!(character, '-' | '_' | '.' | ':')
+    })
+}
+
+/// Read a cookie name, or use `default` if it is not set.
+fn cookie_name<L: Fn(&str) -> Option<String>>(
+    lookup: &L,
+    name: &'static str,
+    default: &str,
+) -> Result<String, errors::ConfigError> {
+    lookup(name).map_or_else(
+        || Ok(default.to_owned()),
+        |value| {
+            Some(value)
+                .filter(|candidate| valid_cookie_name(candidate))
+                .ok_or(errors::ConfigError::Invalid {
+                    name,
+                    expected: "a valid cookie name",
+                })
+        },
+    )
+}
+
+/// Normalise a prefix within the bucket to have exactly one leading separator
+/// and none trailing. Returns None if it is empty or not a valid object store
+/// path (e.g. if it contains empty, "." or ".." segments).
+fn parse_prefix(prefix: &str) -> Option<String> {
+    Path::parse(prefix.trim_matches('/'))
+        .ok()
+        .filter(|path| path.parts().next().is_some())
+        .map(|path| format!("/{path}"))
+}
+
+/// Whether one prefix is the same as, or nested within, another.
+fn prefixes_overlap(first: &str, second: &str) -> bool {
+    let (shorter, longer) = if first.len() <= second.len() {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    longer
+        .strip_prefix(shorter)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+impl Config {
+    /// Load and validate the configuration from the process environment.
+    pub fn from_env() -> Result<Self, errors::ConfigError> {
+        Self::from_lookup(&env_setting)
+    }
+    /// Load and validate the configuration, reading each variable through
+    /// `lookup`, which returns None for unset variables.
+    #[expect(
+        clippy::too_many_lines,
+        reason = "One statement per setting, splitting this up would not make it clearer"
+    )]
+    pub fn from_lookup<L: Fn(&str) -> Option<String>>(
+        lookup: &L,
+    ) -> Result<Self, errors::ConfigError> {
+        let legacy_address_country = lookup("LEGACY_ADDRESS_COUNTRY").map_or_else(
+            || Ok(String::from("GB")),
+            |country| {
+                Some(country.trim().to_uppercase())
+                    .filter(|code| COUNTRY_CODES.contains(&code.as_str()))
+                    .ok_or(errors::ConfigError::Invalid {
+                        name: "LEGACY_ADDRESS_COUNTRY",
+                        expected: "an ISO 3166-1 alpha-2 country code",
+                    })
+            },
+        )?;
+        let shipping_express_countries = lookup("SHIPPING_EXPRESS_COUNTRIES").map_or_else(
+            || Ok(vec![String::from("GB")]),
+            |countries| {
+                countries
+                    .split(',')
+                    .map(|country| {
+                        Some(country.trim().to_uppercase())
+                            .filter(|code| COUNTRY_CODES.contains(&code.as_str()))
+                            .ok_or(errors::ConfigError::Invalid {
+                                name: "SHIPPING_EXPRESS_COUNTRIES",
+                                expected:
+                                    "a comma separated list of ISO 3166-1 alpha-2 country codes",
+                            })
+                    })
+                    .collect()
+            },
+        )?;
+        let csrf_exempt_methods = lookup("CSRF_EXEMPT_METHODS").map_or_else(
+            || Ok(vec![Method::GET, Method::HEAD, Method::OPTIONS]),
+            |methods| {
+                methods
+                    .split(',')
+                    .map(|method| {
+                        Method::from_str(&method.trim().to_uppercase())
+                            .ok()
+                            .filter(Method::is_safe)
+                            .ok_or(errors::ConfigError::Invalid {
+                                name: "CSRF_EXEMPT_METHODS",
+                                expected: "a comma separated list of safe request methods",
+                            })
+                    })
+                    .collect()
+            },
+        )?;
+        let csrf_header_name = lookup("CSRF_HEADER_NAME").map_or_else(
+            || Ok(HeaderName::from_static("x-csrf-token")),
+            |name| {
+                HeaderName::try_from(name)
+                    .ok()
+                    .ok_or(errors::ConfigError::Invalid {
+                        name: "CSRF_HEADER_NAME",
+                        expected: "a valid header name",
+                    })
+            },
+        )?;
+        let cookie_samesite =
+            lookup("COOKIE_SAMESITE").map_or(Ok(SameSite::Strict), |same_site| match same_site
+                .to_lowercase()
+                .as_str()
+            {
+                "strict" => Ok(SameSite::Strict),
+                "lax" => Ok(SameSite::Lax),
+                "none" => Ok(SameSite::None),
+                _ => Err(errors::ConfigError::Invalid {
+                    name: "COOKIE_SAMESITE",
+                    expected: "one of strict, lax or none",
+                }),
+            })?;
+        let s3_image_prefix =
+            parse_prefix(&lookup("S3_IMAGE_PREFIX").unwrap_or_else(|| String::from("/images")))
+                .ok_or(errors::ConfigError::Invalid {
+                    name: "S3_IMAGE_PREFIX",
+                    expected: "a valid object store path",
+                })?;
+        let s3_download_prefix = parse_prefix(
+            &lookup("S3_DOWNLOAD_PREFIX").unwrap_or_else(|| String::from("/downloads")),
+        )
+        .filter(|prefix| !prefixes_overlap(prefix, &s3_image_prefix))
+        .ok_or(errors::ConfigError::Invalid {
+            name: "S3_DOWNLOAD_PREFIX",
+            expected: "a valid object store path which does not overlap S3_IMAGE_PREFIX",
+        })?;
+        let totp_algorithm = lookup("TOTP_ALGORITHM").map_or(
+            Ok(TotpAlgorithm::Sha1),
+            |algorithm| match algorithm.to_uppercase().as_str() {
+                "SHA1" => Ok(TotpAlgorithm::Sha1),
+                "SHA256" => Ok(TotpAlgorithm::Sha256),
+                "SHA512" => Ok(TotpAlgorithm::Sha512),
+                _ => Err(errors::ConfigError::Invalid {
+                    name: "TOTP_ALGORITHM",
+                    expected: "one of SHA1, SHA256 or SHA512",
+                }),
+            },
+        )?;
+        let twilio = lookup("TWILIO_ACCOUNT_SID")
+            .map(|account_sid| {
+                Ok::<_, errors::ConfigError>(TwilioSettings {
+                    account_sid,
+                    auth_token: secret(lookup, "TWILIO_AUTH_TOKEN")?,
+                    from_number: Some(required(lookup, "TWILIO_FROM_NUMBER")?)
+                        .filter(|number| PhoneNumber::try_from(number.as_str()).is_ok())
+                        .ok_or(errors::ConfigError::Invalid {
+                            name: "TWILIO_FROM_NUMBER",
+                            expected: "a phone number in E.164 format",
+                        })?,
+                })
+            })
+            .transpose()?;
+        let mailgun = lookup("MAILGUN_DOMAIN")
+            .map(|domain| {
+                Ok::<_, errors::ConfigError>(MailgunSettings {
+                    api_base: lookup("MAILGUN_API_BASE")
+                        .unwrap_or_else(|| String::from("https://api.mailgun.net")),
+                    domain,
+                    api_key: secret(lookup, "MAILGUN_API_KEY")?,
+                    from: required(lookup, "MAIL_FROM")?,
+                })
+            })
+            .transpose()?;
+        Ok(Self {
+            api_uri_prefix: lookup("API_URI_PREFIX").unwrap_or_else(|| String::from("/")),
+            public_uri: lookup("PUBLIC_URI").unwrap_or_default(),
+            max_multipart_fields: parsed(
+                lookup,
+                "MAX_MULTIPART_FIELDS",
+                10,
+                |&count| count > 0,
+                "a valid positive number",
+            )?,
+            allow_seed: flag(lookup, "ALLOW_SEED"),
+            legacy_address_country,
+            session_cookie_name: cookie_name(lookup, "SESSION_COOKIE_NAME", "session")?,
+            csrf_cookie_name: cookie_name(lookup, "CSRF_COOKIE_NAME", "session_csrf")?,
+            csrf_header_name,
+            cookie_secure: parsed(lookup, "COOKIE_SECURE", true, |_| true, "a valid boolean")?,
+            cookie_samesite,
+            cookie_domain: lookup("COOKIE_DOMAIN"),
+            db_host: required(lookup, "DB_HOST")?,
+            db_database: required(lookup, "DB_DATABASE")?,
+            db_username: required(lookup, "DB_USERNAME")?,
+            db_password: secret(lookup, "DB_PASSWORD")?,
+            db_replica_url: lookup("DB_REPLICA_URL").filter(|url| !url.is_empty()),
+            db_max_connections: parsed(
+                lookup,
+                "DB_MAX_CONNECTIONS",
+                10,
+                |&count| count > 0,
+                "a valid positive number",
+            )?,
+            db_acquire_timeout: Duration::from_secs(parsed(
+                lookup,
+                "DB_ACQUIRE_TIMEOUT",
+                30,
+                |_| true,
+                "a valid number of seconds",
+            )?),
+            db_idle_timeout: Duration::from_secs(parsed(
+                lookup,
+                "DB_IDLE_TIMEOUT",
+                600,
+                |_| true,
+                "a valid number of seconds",
+            )?),
+            db_encryption_key: secret(lookup, "DB_ENCRYPTION_KEY")?,
+            run_migrations: flag(lookup, "RUN_MIGRATIONS"),
+            log_pii: cfg!(debug_assertions) && flag(lookup, "LOG_PII"),
+            default_page_size: parsed(
+                lookup,
+                "DEFAULT_PAGE_SIZE",
+                50,
+                |&count| count > 0,
+                "a valid positive number",
+            )?,
+            max_page_size: parsed(
+                lookup,
+                "MAX_PAGE_SIZE",
+                200,
+                |&count| count > 0,
+                "a valid positive number",
+            )?,
+            max_pending_sessions_per_ip: parsed(
+                lookup,
+                "MAX_PENDING_SESSIONS_PER_IP",
+                10,
+                |&count| count > 0,
+                "a valid positive number",
+            )?,
+            session_reconcile_interval_secs: parsed(
+                lookup,
+                "SESSION_RECONCILE_INTERVAL_SECS",
+                10 * 60,
+                |&seconds| seconds > 0,
+                "a valid positive number of seconds",
+            )?,
+            user_deletion_grace_period_secs: parsed(
+                lookup,
+                "USER_DELETION_GRACE_PERIOD_SECS",
+                30 * 24 * 60 * 60,
+                |_| true,
+                "a valid number of seconds",
+            )?,
+            csrf_exempt_methods,
+            signup_rate_limit_attempts: parsed(
+                lookup,
+                "SIGNUP_RATE_LIMIT_ATTEMPTS",
+                5,
+                |&count| count > 0,
+                "a valid positive number",
+            )?,
+            signup_rate_limit_window_secs: parsed(
+                lookup,
+                "SIGNUP_RATE_LIMIT_WINDOW_SECS",
+                60 * 60,
+                |&seconds| seconds > 0,
+                "a valid positive number of seconds",
+            )?,
+            order_rate_limit_attempts: parsed(
+                lookup,
+                "ORDER_RATE_LIMIT_ATTEMPTS",
+                5,
+                |&count| count > 0,
+                "a valid positive number",
+            )?,
+            order_rate_limit_window_secs: parsed(
+                lookup,
+                "ORDER_RATE_LIMIT_WINDOW_SECS",
+                60,
+                |&seconds| seconds > 0,
+                "a valid positive number of seconds",
+            )?,
+            prices_include_tax: flag(lookup, "PRICES_INCLUDE_TAX"),
+            tax_rate_basis_points: parsed(
+                lookup,
+                "TAX_RATE_BASIS_POINTS",
+                0,
+                |&rate| rate <= 10_000,
+                "a number of basis points from 0 to 10000",
+            )?,
+            shipping_flat_rate: lookup("SHIPPING_FLAT_RATE")
+                .map(|_| {
+                    parsed(
+                        lookup,
+                        "SHIPPING_FLAT_RATE",
+                        0,
+                        |_| true,
+                        "a number of pennies",
+                    )
+                })
+                .transpose()?,
+            shipping_express_rate: lookup("SHIPPING_EXPRESS_RATE")
+                .map(|_| {
+                    parsed(
+                        lookup,
+                        "SHIPPING_EXPRESS_RATE",
+                        0,
+                        |_| true,
+                        "a number of pennies",
+                    )
+                })
+                .transpose()?,
+            shipping_express_countries,
+            redis_host: required(lookup, "REDIS_HOST")?,
+            redis_database: parsed(
+                lookup,
+                "REDIS_DATABASE",
+                0,
+                |_| true,
+                "a valid database index",
+            )?,
+            redis_key_prefix: lookup("REDIS_KEY_PREFIX").map_or(Ok(String::new()), |prefix| {
+                Some(prefix)
+                    .filter(|candidate| valid_redis_key_prefix(candidate))
+                    .ok_or(errors::ConfigError::Invalid {
+                        name: "REDIS_KEY_PREFIX",
+                        expected: "made up of letters, digits, '-', '_', '.' and ':'",
+                    })
+            })?,
+            redis_retry_attempts: parsed(
+                lookup,
+                "REDIS_RETRY_ATTEMPTS",
+                3,
+                |_| true,
+                "a valid number",
+            )?,
+            redis_retry_backoff_ms: parsed(
+                lookup,
+                "REDIS_RETRY_BACKOFF_MS",
+                50,
+                |_| true,
+                "a valid number",
+            )?,
+            redis_connection_timeout: Duration::from_millis(parsed(
+                lookup,
+                "REDIS_CONNECTION_TIMEOUT_MS",
+                5000,
+                |_| true,
+                "a valid number",
+            )?),
+            redis_response_timeout: Duration::from_millis(parsed(
+                lookup,
+                "REDIS_RESPONSE_TIMEOUT_MS",
+                2000,
+                |_| true,
+                "a valid number",
+            )?),
+            s3_host: required(lookup, "S3_HOST")?,
+            s3_port: required(lookup, "S3_PORT")?.parse().ok().ok_or(
+                errors::ConfigError::Invalid {
+                    name: "S3_PORT",
+                    expected: "a valid port number",
+                },
+            )?,
+            s3_bucket: required(lookup, "S3_BUCKET")?,
+            s3_access_key: secret(lookup, "S3_ACCESS_KEY")?,
+            s3_secret_key: secret(lookup, "S3_SECRET_KEY")?,
+            s3_external_uri: lookup("S3_EXTERNAL_URI").unwrap_or_default(),
+            s3_image_prefix,
+            s3_download_prefix,
+            s3_retry_attempts: parsed(
+                lookup,
+                "S3_RETRY_ATTEMPTS",
+                3,
+                |&attempts| attempts > 0,
+                "a positive number",
+            )?,
+            s3_retry_backoff_ms: parsed(
+                lookup,
+                "S3_RETRY_BACKOFF_MS",
+                100,
+                |_| true,
+                "a valid number",
+            )?,
+            startup_retry_attempts: parsed(
+                lookup,
+                "STARTUP_RETRY_ATTEMPTS",
+                10,
+                |&attempts| attempts > 0,
+                "a positive number",
+            )?,
+            startup_retry_backoff_ms: parsed(
+                lookup,
+                "STARTUP_RETRY_BACKOFF_MS",
+                500,
+                |_| true,
+                "a valid number",
+            )?,
+            #[cfg(feature = "stripe")]
+            stripe_secret_key: secret(lookup, "STRIPE_SECRET_KEY")?,
+            #[cfg(feature = "stripe")]
+            stripe_webhook_secret: secret(lookup, "STRIPE_WEBHOOK_SECRET")?,
+            #[cfg(feature = "stripe")]
+            stripe_publishable_key: required(lookup, "STRIPE_PUBLISHABLE_KEY")?,
+            totp_secret_length: parsed(
+                lookup,
+                "TOTP_SECRET_LENGTH",
+                32,
+                |&bytes| bytes >= 16,
+                "a number of bytes of at least 16",
+            )?,
+            totp_digits: parsed(
+                lookup,
+                "TOTP_DIGITS",
+                6,
+                |digits| (6..=8).contains(digits),
+                "a number between 6 and 8",
+            )?,
+            totp_step: parsed(
+                lookup,
+                "TOTP_STEP",
+                30i32,
+                |&secs| secs > 0i32,
+                "a valid positive number of seconds",
+            )?,
+            totp_algorithm,
+            twilio,
+            mailgun,
+        })
+    }
+    /// Load the configuration from the process environment, to be used for
+    /// the rest of the API's lifetime. Should be called once at startup,
+    /// before anything reads the configuration.
+    pub fn init() -> Result<&'static Self, errors::ConfigError> {
+        Self::init_from(&env_setting)
+    }
+    /// Load the configuration, reading each variable through `lookup`, to be
+    /// used for the rest of the API's lifetime. If the configuration was
+    /// already loaded, that is kept instead.
+    pub fn init_from<L: Fn(&str) -> Option<String>>(
+        lookup: &L,
+    ) -> Result<&'static Self, errors::ConfigError> {
+        let config = Self::from_lookup(lookup)?;
+        Ok(CONFIG.get_or_init(|| config))
+    }
+}
+
+/// Read a setting from the process environment.
+fn env_setting(name: &str) -> Option<String> {
+    var(name).ok()
+}
+
+/// The configuration loaded at startup. If it was not loaded by
+/// `Config::init`, it is loaded from the process environment on first use,
+/// and panics if it is invalid. Tests load their own first, see
+/// `testing::configure`.
+pub fn config() -> &'static Config {
+    #[cfg(test)]
+    testing::configure();
+    CONFIG.get_or_init(|| Config::from_env().expect("Invalid configuration"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+
+    use super::{errors::ConfigError, Config};
+    use crate::{db::models::totp::TotpAlgorithm, testing::test_setting};
+
+    /// Load the configuration from the test settings, with `overrides`
+    /// replacing them, where an override of None unsets the variable.
+    fn load(overrides: &[(&str, Option<&str>)]) -> Result<Config, ConfigError> {
+        Config::from_lookup(&|name: &str| {
+            overrides
+                .iter()
+                .find(|&&(overridden, _)| overridden == name)
+                .map_or_else(
+                    || test_setting(name),
+                    |&(_, value)| value.map(str::to_owned),
+                )
+        })
+    }
+
+    /// A complete environment is loaded, with defaults for the settings which
+    /// are not set.
+    #[test]
+    fn complete_environment_is_loaded() {
+        let config = load(&[]).expect("Test configuration should be valid");
+        assert_eq!(config.db_host, "localhost");
+        assert_eq!(config.session_cookie_name, "test_session");
+        assert_eq!(config.csrf_exempt_methods, [Method::GET, Method::HEAD]);
+        assert_eq!(config.shipping_flat_rate, Some(300));
+        assert_eq!(config.max_page_size, 200);
+        assert!(!config.run_migrations);
+    }
+
+    /// A missing required setting or secret is reported by name.
+    #[test]
+    fn missing_settings_are_reported() {
+        assert!(matches!(
+            load(&[("DB_HOST", None)]).err(),
+            Some(ConfigError::Missing("DB_HOST"))
+        ));
+        assert!(matches!(
+            load(&[("DB_PASSWORD", None)]).err(),
+            Some(ConfigError::MissingSecret("DB_PASSWORD"))
+        ));
+    }
+
+    /// The parameters of newly enrolled TOTP secrets are configurable, with
+    /// the algorithm matched ignoring case.
+    #[test]
+    fn totp_settings_are_loaded() {
+        let config = load(&[
+            ("TOTP_ALGORITHM", Some("sha256")),
+            ("TOTP_DIGITS", Some("8")),
+        ])
+        .expect("Configuration should be valid");
+        assert!(matches!(config.totp_algorithm, TotpAlgorithm::Sha256));
+        assert_eq!(config.totp_digits, 8);
+        let defaults = load(&[]).expect("Test configuration should be valid");
+        assert!(matches!(defaults.totp_algorithm, TotpAlgorithm::Sha1));
+        assert_eq!((defaults.totp_digits, defaults.totp_step), (6i16, 30i32));
+    }
+
+    /// A setting with an invalid value is reported by name, rather than
+    /// replaced with its default.
+    #[test]
+    fn invalid_settings_are_reported() {
+        for (name, value) in [
+            ("MAX_PAGE_SIZE", "0"),
+            ("COOKIE_SAMESITE", "sometimes"),
+            ("CSRF_EXEMPT_METHODS", "GET,POST"),
+            ("TOTP_ALGORITHM", "MD5"),
+        ] {
+            assert!(
+                matches!(
+                    load(&[(name, Some(value))]).err(),
+                    Some(ConfigError::Invalid { name: invalid, .. }) if invalid == name
+                ),
+                "{name}={value}"
+            );
+        }
+    }
+}
+
+/// Errors which can be returned while loading the configuration.
+pub mod errors {
+    use std::io;
+    use thiserror::Error;
+
+    #[derive(Debug, Error)]
+    /// An error returned when a setting is missing or invalid.
+    pub enum ConfigError {
+        #[error("{0} not provided in environment variables")]
+        /// A required variable is not set.
+        Missing(&'static str),
+        #[error("Neither {0} nor {0}_DOCKER_SECRET provided in environment variables")]
+        /// A secret is neither set directly nor as a Docker secret.
+        MissingSecret(&'static str),
+        #[error("Failed to read {name} docker secret: {source}")]
+        /// A secret's Docker secret could not be read.
+        UnreadableSecret {
+            /// The name of the secret.
+            name: &'static str,
+            /// The error reading the secret.
+            source: io::Error,
+        },
+        #[error("{name} is not {expected}")]
+        /// A variable is set, but to an invalid value.
+        Invalid {
+            /// The name of the variable.
+            name: &'static str,
+            /// A description of the values which are valid.
+            expected: &'static str,
+        },
+    }
+}
@@ -0,0 +1,39 @@
+//! Constants controlling the attributes of cookies set by the API, which may
+//! need to differ between deployments (e.g. local HTTP development, or serving
+//! the frontend from a different subdomain).
+use super::config::config;
+use axum::http::HeaderName;
+use axum_extra::extract::cookie::SameSite;
+use std::sync::LazyLock;
+
+/// The name of the cookie carrying the session token. Defaults to `session`.
+/// Can be changed to namespace several applications served on one domain.
+pub static SESSION_COOKIE_NAME: LazyLock<String> =
+    LazyLock::new(|| config().session_cookie_name.clone());
+
+/// The name of the cookie carrying the session's CSRF token, which the
+/// frontend reads and echoes back in the `CSRF_HEADER_NAME` header. Defaults
+/// to `session_csrf`. The frontend (`csrf.ts`) must be changed to match.
+pub static CSRF_COOKIE_NAME: LazyLock<String> = LazyLock::new(|| config().csrf_cookie_name.clone());
+
+/// The name of the request header which must carry the session's CSRF token.
+/// Defaults to `X-CSRF-Token`. The frontend (`csrf.ts`) must be changed to match.
+pub static CSRF_HEADER_NAME: LazyLock<HeaderName> =
+    LazyLock::new(|| config().csrf_header_name.clone());
+
+/// Whether cookies are only sent over HTTPS. Defaults to true, and should
+/// only be disabled for local development over plain HTTP.
+pub static COOKIE_SECURE: LazyLock<bool> = LazyLock::new(|| config().cookie_secure);
+
+/// The `SameSite` policy for cookies, one of "strict", "lax" or "none".
+/// Defaults to strict. Browsers reject "none" unless cookies are also secure.
+pub static COOKIE_SAMESITE: LazyLock<SameSite> = LazyLock::new(|| config().cookie_samesite);
+
+/// The domain cookies are scoped to, if any. If not provided, cookies are
+/// only sent to the exact host which set them.
+pub static COOKIE_DOMAIN: LazyLock<Option<String>> =
+    LazyLock::new(|| config().cookie_domain.clone());
+
+/// The name of the cookie carrying the nonce of the magic link confirmation
+/// page last served to the client, which the page's form must submit back.
+pub const MAGIC_LINK_NONCE_COOKIE_NAME: &str = "magic_link_nonce";
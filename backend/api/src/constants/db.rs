@@ -1,31 +1,19 @@
 //! Database connection related constants.
-use super::secrets::read_secret;
-use std::env::var;
+use super::config::config;
+use core::time::Duration;
 use std::sync::LazyLock;
 
 /// The hostname where the database server is accessible.
-pub static DB_HOST: LazyLock<String> =
-    LazyLock::new(|| var("DB_HOST").expect("DB_HOST not provided in environment variables"));
+pub static DB_HOST: LazyLock<String> = LazyLock::new(|| config().db_host.clone());
 
 /// The database to connect to on the database server.
-pub static DB_DATABASE: LazyLock<String> = LazyLock::new(|| {
-    var("DB_DATABASE").expect("DB_DATABASE not provided in environment variables")
-});
+pub static DB_DATABASE: LazyLock<String> = LazyLock::new(|| config().db_database.clone());
 
 /// The username to authenticate to the database server with.
-pub static DB_USERNAME: LazyLock<String> = LazyLock::new(|| {
-    var("DB_USERNAME").expect("DB_USERNAME not provided in environment variables")
-});
+pub static DB_USERNAME: LazyLock<String> = LazyLock::new(|| config().db_username.clone());
 
 /// The password to authenticate to the database with.
-pub static DB_PASSWORD: LazyLock<String> = LazyLock::new(|| {
-    var("DB_PASSWORD").unwrap_or_else(|_| {
-        let secret_path = var("DB_PASSWORD_DOCKER_SECRET").expect(
-            "Neither DB_PASSWORD nor DB_PASSWORD_DOCKER_SECRET provided in environment variables",
-        );
-        read_secret(&secret_path).expect("Failed to read DB_PASSWORD docker secret")
-    })
-});
+pub static DB_PASSWORD: LazyLock<String> = LazyLock::new(|| config().db_password.clone());
 
 /// A URL-style database connection string, provided for convenience as it simply
 /// combines the other exposed constants in a defined manner.
@@ -36,12 +24,27 @@ pub static DB_URL: LazyLock<String> = LazyLock::new(|| {
     )
 });
 
+/// An optional URL-style connection string for a read-only replica of the
+/// database. If not set, read-only queries are made against the primary database.
+pub static DB_REPLICA_URL: LazyLock<Option<String>> =
+    LazyLock::new(|| config().db_replica_url.clone());
+
+/// The maximum number of connections held open by each database pool.
+/// Defaults to 10.
+pub static DB_MAX_CONNECTIONS: LazyLock<u32> = LazyLock::new(|| config().db_max_connections);
+
+/// How long to wait for a pooled database connection to become available
+/// before failing, read in seconds from `DB_ACQUIRE_TIMEOUT`. Defaults to 30s.
+pub static DB_ACQUIRE_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| config().db_acquire_timeout);
+
+/// How long a pooled database connection may sit idle before being closed,
+/// read in seconds from `DB_IDLE_TIMEOUT`. Defaults to 10 minutes.
+pub static DB_IDLE_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| config().db_idle_timeout);
+
 /// The key to encrypt sensitive data in the database with.
-pub static DB_ENCRYPTION_KEY: LazyLock<String> = LazyLock::new(|| {
-    var("DB_ENCRYPTION_KEY").unwrap_or_else(|_| {
-        let secret_path = var("DB_ENCRYPTION_KEY_DOCKER_SECRET").expect(
-            "Neither DB_ENCRYPTION_KEY nor DB_ENCRYPTION_KEY_DOCKER_SECRET provided in environment variables",
-        );
-        read_secret(&secret_path).expect("Failed to read DB_ENCRYPTION_KEY docker secret")
-    })
-});
+pub static DB_ENCRYPTION_KEY: LazyLock<String> =
+    LazyLock::new(|| config().db_encryption_key.clone());
+
+/// Whether pending database migrations should be applied on startup, before
+/// the API begins serving requests.
+pub static RUN_MIGRATIONS: LazyLock<bool> = LazyLock::new(|| config().run_migrations);
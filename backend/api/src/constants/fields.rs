@@ -0,0 +1,18 @@
+//! Constants limiting the length of free-text fields provided by users.
+
+/// The maximum length, in characters, of a user's forename or surname.
+pub const NAME_MAX_LENGTH: usize = 100;
+/// The maximum length, in characters, of each line, and the city, of a user's
+/// address.
+pub const ADDRESS_LINE_MAX_LENGTH: usize = 200;
+/// The maximum length, in characters, of the postcode of a user's address.
+pub const POSTCODE_MAX_LENGTH: usize = 16;
+/// The maximum length, in characters, of a product's name.
+pub const PRODUCT_NAME_MAX_LENGTH: usize = 200;
+/// The maximum length, in characters, of the text of a product review.
+pub const REVIEW_MAX_LENGTH: usize = 2000;
+/// The maximum length, in characters, of an email address (RFC 5321).
+pub const EMAIL_MAX_LENGTH: usize = 254;
+/// The maximum length, in characters, of the part of an email address before
+/// the `@` (RFC 5321).
+pub const EMAIL_LOCAL_PART_MAX_LENGTH: usize = 64;
@@ -0,0 +1,8 @@
+//! Constants controlling what is written to the logs.
+use super::config::config;
+use std::sync::LazyLock;
+
+/// Whether personally identifiable information, such as email addresses, is
+/// logged in full rather than masked. Only honoured in debug builds, so that
+/// it cannot be enabled in production. Set by `LOG_PII=true`.
+pub static LOG_PII: LazyLock<bool> = LazyLock::new(|| config().log_pii);
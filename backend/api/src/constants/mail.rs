@@ -0,0 +1,11 @@
+//! Constants for delivering emails.
+use super::config::{config, MailgunSettings};
+use std::sync::LazyLock;
+
+/// The Mailgun domain emails are sent through, read from `MAILGUN_DOMAIN`,
+/// `MAILGUN_API_KEY` (or its Docker secret), `MAIL_FROM` and optionally
+/// `MAILGUN_API_BASE` (defaults to the US region). If `MAILGUN_DOMAIN` is not
+/// set, no email provider is configured, so email MFA, magic links, guest
+/// account upgrades and back in stock notifications are unavailable.
+pub static MAILGUN: LazyLock<Option<&'static MailgunSettings>> =
+    LazyLock::new(|| config().mailgun.as_ref());
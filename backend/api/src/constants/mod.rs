@@ -1,10 +1,23 @@
 //! Constants (primary environment variables/secrets) used across the application.
+pub mod address;
 pub mod api;
+pub mod config;
+pub mod cookies;
 pub mod db;
+pub mod fields;
+pub mod logging;
+pub mod mail;
+pub mod orders;
+pub mod pagination;
 pub mod passwords;
+pub mod products;
 pub mod redis;
 pub mod s3;
 mod secrets;
 pub mod sessions;
+pub mod sms;
+pub mod startup;
 #[cfg(feature = "stripe")]
 pub mod stripe;
+pub mod totp;
+pub mod users;
@@ -0,0 +1,44 @@
+//! Constants limiting how quickly orders can be placed, and controlling how
+//! they are taxed and what they are charged for shipping.
+use super::config::config;
+use std::sync::LazyLock;
+
+/// The most orders a single user, or a single client (by IP) placing guest
+/// orders, may create within `ORDER_RATE_LIMIT_WINDOW`, read from
+/// `ORDER_RATE_LIMIT_ATTEMPTS`. This stops automated clients from buying up
+/// limited stock. Defaults to 5.
+pub static ORDER_RATE_LIMIT_ATTEMPTS: LazyLock<u32> =
+    LazyLock::new(|| config().order_rate_limit_attempts);
+/// The window in seconds within which a user's or client's orders are counted,
+/// starting from their first order in it. Read from
+/// `ORDER_RATE_LIMIT_WINDOW_SECS`. Defaults to 60.
+pub static ORDER_RATE_LIMIT_WINDOW: LazyLock<u32> =
+    LazyLock::new(|| config().order_rate_limit_window_secs);
+/// Whether product prices already include tax, read from `PRICES_INCLUDE_TAX`.
+/// If so, an order's total is the sum of its prices, and the tax is the part
+/// of that total which is tax. Otherwise, tax is added on top. Defaults to false.
+pub static PRICES_INCLUDE_TAX: LazyLock<bool> = LazyLock::new(|| config().prices_include_tax);
+/// The tax rate applied across the store, in basis points (e.g. 2000 for 20%),
+/// read from `TAX_RATE_BASIS_POINTS`. Defaults to 0, i.e. no tax.
+pub static TAX_RATE_BASIS_POINTS: LazyLock<u32> = LazyLock::new(|| config().tax_rate_basis_points);
+/// The shipping cost in pennies added to every order by the flat rate
+/// shipping calculator, read from `SHIPPING_FLAT_RATE`. If unset, no shipping
+/// calculator is configured and orders are not charged for shipping.
+pub static SHIPPING_FLAT_RATE: LazyLock<Option<u32>> =
+    LazyLock::new(|| config().shipping_flat_rate);
+/// The shipping cost in pennies of the express method offered by the flat
+/// rate shipping calculator, read from `SHIPPING_EXPRESS_RATE`. If unset,
+/// express shipping is not offered.
+pub static SHIPPING_EXPRESS_RATE: LazyLock<Option<u32>> =
+    LazyLock::new(|| config().shipping_express_rate);
+/// The ISO 3166-1 alpha-2 codes of the countries express shipping is offered
+/// to, read as a comma separated list from `SHIPPING_EXPRESS_COUNTRIES`.
+/// Defaults to GB only.
+pub static SHIPPING_EXPRESS_COUNTRIES: LazyLock<Vec<String>> =
+    LazyLock::new(|| config().shipping_express_countries.clone());
+/// The most working days an order sent by standard shipping should take to
+/// arrive, as shown to customers choosing a method.
+pub const STANDARD_SHIPPING_DAYS: u32 = 5;
+/// The most working days an order sent by express shipping should take to
+/// arrive, as shown to customers choosing a method.
+pub const EXPRESS_SHIPPING_DAYS: u32 = 1;
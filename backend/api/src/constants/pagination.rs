@@ -0,0 +1,11 @@
+//! Constants controlling the pagination of list endpoints.
+use super::config::config;
+use std::sync::LazyLock;
+
+/// The number of results returned by a list endpoint when the client does not
+/// specify a limit. Defaults to 50.
+pub static DEFAULT_PAGE_SIZE: LazyLock<u32> = LazyLock::new(|| config().default_page_size);
+
+/// The most results a list endpoint will return at once, whatever limit the
+/// client asks for. Defaults to 200.
+pub static MAX_PAGE_SIZE: LazyLock<u32> = LazyLock::new(|| config().max_page_size);
@@ -0,0 +1,18 @@
+//! Constants limiting the products which can be stored, and controlling how
+//! views of them are counted and how they are ranked by rating.
+
+/// The maximum number of images which can be attached to a single product.
+pub const MAX_IMAGES_PER_PRODUCT: u32 = 20;
+/// The maximum number of products which can be retrieved in a single batch.
+pub const MAX_PRODUCTS_PER_BATCH: usize = 100;
+/// The period in seconds during which repeated views of a product from the
+/// same session are only counted once.
+pub const PRODUCT_VIEW_DEBOUNCE: u32 = 10 * 60;
+/// The interval in seconds at which view counts held in the session store are
+/// added to the database.
+pub const PRODUCT_VIEW_FLUSH_INTERVAL: u64 = 60;
+/// The number of reviews a product needs before its average rating is
+/// trusted when sorting by rating. Products with fewer reviews are ranked
+/// after every product with at least this many, whatever their average, so
+/// that a single 5 star review can't outrank an established 4.8.
+pub const MIN_REVIEWS_FOR_RATING_RANK: i64 = 3;
@@ -1,10 +1,40 @@
 //! Redis connection related constants.
-use std::env::var;
+use super::config::config;
+use core::time::Duration;
 use std::sync::LazyLock;
 
 /// The hostname where the Redis session store can be found.
-pub static REDIS_HOST: LazyLock<String> =
-    LazyLock::new(|| var("REDIS_HOST").expect("REDIS_HOST not provided in environment variables"));
+pub static REDIS_HOST: LazyLock<String> = LazyLock::new(|| config().redis_host.clone());
+
+/// The index of the Redis database to use, read from `REDIS_DATABASE`.
+/// Defaults to 0, Redis's default database.
+pub static REDIS_DATABASE: LazyLock<u32> = LazyLock::new(|| config().redis_database);
 
 /// The formatted URL which can be used to connect to Redis.
-pub static REDIS_URL: LazyLock<String> = LazyLock::new(|| format!("redis://{}/", *REDIS_HOST));
+pub static REDIS_URL: LazyLock<String> =
+    LazyLock::new(|| format!("redis://{}/{}", *REDIS_HOST, *REDIS_DATABASE));
+
+/// A prefix prepended to every key in the session store, read from
+/// `REDIS_KEY_PREFIX` (e.g. `staging:`), so that several environments or
+/// applications can share a Redis database without their keys colliding.
+/// Defaults to empty, i.e. keys are unprefixed.
+pub static REDIS_KEY_PREFIX: LazyLock<String> = LazyLock::new(|| config().redis_key_prefix.clone());
+
+/// The maximum number of attempts made for a session store operation which
+/// fails with a transient (connection-level) error. Defaults to 3.
+pub static REDIS_RETRY_ATTEMPTS: LazyLock<u32> = LazyLock::new(|| config().redis_retry_attempts);
+
+/// The delay in milliseconds before the first retry of a failed session store
+/// operation. Doubles with each subsequent retry. Defaults to 50ms.
+pub static REDIS_RETRY_BACKOFF_MS: LazyLock<u64> =
+    LazyLock::new(|| config().redis_retry_backoff_ms);
+
+/// How long to wait when establishing the connection to Redis before failing,
+/// read in milliseconds from `REDIS_CONNECTION_TIMEOUT_MS`. Defaults to 5s.
+pub static REDIS_CONNECTION_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| config().redis_connection_timeout);
+
+/// How long to wait for a response to a single Redis command before failing,
+/// read in milliseconds from `REDIS_RESPONSE_TIMEOUT_MS`. Defaults to 2s.
+pub static REDIS_RESPONSE_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| config().redis_response_timeout);
@@ -1,46 +1,43 @@
 //! S3-compatible storage related constants.
-use std::{env::var, sync::LazyLock};
-
-use super::secrets::read_secret;
+use super::config::config;
+use std::sync::LazyLock;
 
 /// The hostname where the S3-compatible storage service can be accessed.
-pub static S3_HOST: LazyLock<String> =
-    LazyLock::new(|| var("S3_HOST").expect("S3_HOST not provided in environment variables"));
+pub static S3_HOST: LazyLock<String> = LazyLock::new(|| config().s3_host.clone());
 
 /// The port where the S3-compatible storage service can be accessed.
-pub static S3_PORT: LazyLock<u16> = LazyLock::new(|| {
-    var("S3_PORT")
-        .expect("S3_PORT not provided in environment variables")
-        .parse()
-        .expect("S3_PORT is not a valid port number")
-});
+pub static S3_PORT: LazyLock<u16> = LazyLock::new(|| config().s3_port);
 
 /// The bucket where application media data is stored.
-pub static S3_BUCKET: LazyLock<String> =
-    LazyLock::new(|| var("S3_BUCKET").expect("S3_BUCKET not provided in environment variables"));
+pub static S3_BUCKET: LazyLock<String> = LazyLock::new(|| config().s3_bucket.clone());
 
 /// The access key (user) to authenticate to the store with.
-pub static S3_ACCESS_KEY: LazyLock<String> = LazyLock::new(|| {
-    var("S3_ACCESS_KEY").unwrap_or_else(|_| {
-        let secret_path = var("S3_ACCESS_KEY_DOCKER_SECRET")
-            .expect("Neither S3_ACCESS_KEY nor S3_ACCESS_KEY_DOCKER_SECRET provided in environment variables");
-        read_secret(&secret_path).expect("Failed to read S3_ACCESS_KEY docker secret")
-    })
-});
+pub static S3_ACCESS_KEY: LazyLock<String> = LazyLock::new(|| config().s3_access_key.clone());
 
 /// The secret key (password) to authenticate to the store with.
-pub static S3_SECRET_KEY: LazyLock<String> = LazyLock::new(|| {
-    var("S3_SECRET_KEY").unwrap_or_else(|_| {
-        let secret_path = var("S3_SECRET_KEY_DOCKER_SECRET").expect(
-            "Neither S3_SECRET_KEY nor S3_SECRET_KEY_DOCKER_SECRET provided in environment variables",
-        );
-        read_secret(&secret_path).expect("Failed to read S3_SECRET_KEY docker secret")
-    })
-});
+pub static S3_SECRET_KEY: LazyLock<String> = LazyLock::new(|| config().s3_secret_key.clone());
 
 /// An optional URI where the S3 storage can be accessed from outside the
 /// inter-service internal network. Can be left blank, and the store will be
 /// assumed to be accessible via the same host as the API (true in the default
 /// docker compose configuration with NGINX).
-pub static S3_EXTERNAL_URI: LazyLock<String> =
-    LazyLock::new(|| var("S3_EXTERNAL_URI").unwrap_or_else(|_| String::new()));
+pub static S3_EXTERNAL_URI: LazyLock<String> = LazyLock::new(|| config().s3_external_uri.clone());
+
+/// The prefix within the bucket under which images to be displayed inline are
+/// stored. Defaults to "/images".
+pub static S3_IMAGE_PREFIX: LazyLock<String> = LazyLock::new(|| config().s3_image_prefix.clone());
+
+/// The prefix within the bucket under which images to be downloaded rather
+/// than displayed are stored. Defaults to "/downloads". Must not overlap
+/// `S3_IMAGE_PREFIX`, so that deduplication by hash can never flip the
+/// disposition of an image which is already being displayed.
+pub static S3_DOWNLOAD_PREFIX: LazyLock<String> =
+    LazyLock::new(|| config().s3_download_prefix.clone());
+
+/// The maximum number of attempts made for an object store operation which
+/// fails with a transient (connection-level) error. Defaults to 3.
+pub static S3_RETRY_ATTEMPTS: LazyLock<u32> = LazyLock::new(|| config().s3_retry_attempts);
+
+/// The delay in milliseconds before the first retry of a failed object store
+/// operation. Doubles with each subsequent retry. Defaults to 100ms.
+pub static S3_RETRY_BACKOFF_MS: LazyLock<u64> = LazyLock::new(|| config().s3_retry_backoff_ms);
@@ -1,13 +1,75 @@
 //! Constants related to authentication and session handling.
+use super::config::config;
+use axum::http::Method;
+use std::sync::LazyLock;
 
 /// Timeout for authenticated sessions in seconds.
 pub const SESSION_TIMEOUT: u32 = 7 * 24 * 60 * 60;
+/// Timeout for authenticated sessions created with "remember me" in seconds.
+pub const REMEMBER_ME_SESSION_TIMEOUT: u32 = 30 * 24 * 60 * 60;
+/// Whether refreshing a session restarts its timeout. If false, the refreshed
+/// session keeps the remaining lifetime of the session it replaces, so
+/// refreshing can't be used to keep a session alive indefinitely.
+pub const SESSION_REFRESH_RESETS_TIMEOUT: bool = false;
+/// Whether administrators must have TOTP enrolled to be given an
+/// administrative session. If true, an administrator without TOTP who logs in
+/// is given only a customer session, from which they can enrol.
+pub const REQUIRE_ADMIN_2FA: bool = false;
+/// Request methods exempt from CSRF checks, read as a comma separated list
+/// from `CSRF_EXEMPT_METHODS`. Only safe methods (e.g. GET) are accepted, and
+/// these must only ever be used for requests without side effects. Defaults
+/// to GET, HEAD and OPTIONS.
+pub static CSRF_EXEMPT_METHODS: LazyLock<Vec<Method>> =
+    LazyLock::new(|| config().csrf_exempt_methods.clone());
+/// Timeout for one-time MFA codes (e.g. sent by SMS or email) in seconds.
+pub const ONE_TIME_CODE_TIMEOUT: u32 = 5 * 60;
+/// Max attempts at entering one-time MFA codes of a kind, across every code of
+/// that kind issued to a session, before the session's code is invalidated.
+pub const ONE_TIME_CODE_MAX_ATTEMPTS: u32 = 5;
+/// Max one-time MFA codes of a kind which may be issued to a session.
+pub const ONE_TIME_CODE_MAX_ISSUED: u32 = 3;
+/// Time in seconds for which the one-time MFA codes issued to a session, and
+/// attempts at entering them, are counted, from when the first is issued.
+pub const ONE_TIME_CODE_COUNT_TIMEOUT: u32 = 24 * 60 * 60;
+/// Timeout for single-use login tokens sent by email as magic links, in seconds.
+pub const MAGIC_LINK_TIMEOUT: u32 = 15 * 60;
+/// Timeout for tokens granting a guest access to their order in seconds.
+pub const GUEST_ORDER_TOKEN_TIMEOUT: u32 = 30 * 24 * 60 * 60;
+/// Timeout for tokens emailed to guests to upgrade to a full account, in seconds.
+pub const GUEST_UPGRADE_TOKEN_TIMEOUT: u32 = 60 * 60;
 /// Timeout for pre-authentication sessions in seconds.
 pub const PREAUTH_SESSION_TIMEOUT: u32 = 5 * 60;
 /// Timeout for registration sessions in seconds;
 pub const REGISTRATION_SESSION_TIMEOUT: u32 = 10 * 60;
 /// Timeout for administrative sessions in seconds.
 pub const ADMIN_SESSION_TIMEOUT: u32 = 2 * 60 * 60;
+/// Timeout for sessions an administrator uses to impersonate a customer, in
+/// seconds. Never extended by refreshing the session.
+pub const IMPERSONATION_SESSION_TIMEOUT: u32 = 30 * 60;
+/// The most registration and preauthentication sessions a single client (by
+/// IP) may have in progress at once, read from `MAX_PENDING_SESSIONS_PER_IP`.
+/// Further attempts are rejected until one completes or expires. Defaults to 10.
+pub static MAX_PENDING_SESSIONS_PER_IP: LazyLock<u32> =
+    LazyLock::new(|| config().max_pending_sessions_per_ip);
+/// The most signups a single client (by IP) may start within
+/// `SIGNUP_RATE_LIMIT_WINDOW`, read from `SIGNUP_RATE_LIMIT_ATTEMPTS`. Unlike
+/// `MAX_PENDING_SESSIONS_PER_IP`, completing a signup does not free up an
+/// attempt, so accounts can't be created in bulk. Defaults to 5.
+pub static SIGNUP_RATE_LIMIT_ATTEMPTS: LazyLock<u32> =
+    LazyLock::new(|| config().signup_rate_limit_attempts);
+/// The window in seconds within which a client's signups are counted,
+/// starting from their first signup in it. Read from
+/// `SIGNUP_RATE_LIMIT_WINDOW_SECS`. Defaults to an hour.
+pub static SIGNUP_RATE_LIMIT_WINDOW: LazyLock<u32> =
+    LazyLock::new(|| config().signup_rate_limit_window_secs);
+/// The interval in seconds between reconciliations of the session store's
+/// indexes, which removes entries left referring to sessions which no longer
+/// exist. Read from `SESSION_RECONCILE_INTERVAL_SECS`. Defaults to 10 minutes.
+pub static SESSION_RECONCILE_INTERVAL: LazyLock<u64> =
+    LazyLock::new(|| config().session_reconcile_interval_secs);
+/// Delay in milliseconds before responding to an email availability check,
+/// which slows enumeration of registered emails.
+pub const EMAIL_CHECK_DELAY_MS: u64 = 500;
 /// Max authentication attempts before timeout;
 pub const AUTH_TIMEOUT_ATTEMPTS: u32 = 5;
 /// The timeout period within which bruteforce auth attempts will be counted;
@@ -0,0 +1,10 @@
+//! Constants for delivering SMS messages.
+use super::config::{config, TwilioSettings};
+use std::sync::LazyLock;
+
+/// The Twilio account SMS messages are sent through, read from
+/// `TWILIO_ACCOUNT_SID`, `TWILIO_AUTH_TOKEN` (or its Docker secret) and
+/// `TWILIO_FROM_NUMBER`. If `TWILIO_ACCOUNT_SID` is not set, no SMS provider is
+/// configured, so SMS MFA and phone number verification are unavailable.
+pub static TWILIO: LazyLock<Option<&'static TwilioSettings>> =
+    LazyLock::new(|| config().twilio.as_ref());
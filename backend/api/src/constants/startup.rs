@@ -0,0 +1,19 @@
+//! Constants controlling how the API waits for its dependencies (the database,
+//! session store and object storage) to become available at startup.
+use super::config::config;
+use core::time::Duration;
+use std::sync::LazyLock;
+
+/// The maximum number of attempts made to connect to each dependency at
+/// startup before giving up. Defaults to 10.
+pub static STARTUP_RETRY_ATTEMPTS: LazyLock<u32> =
+    LazyLock::new(|| config().startup_retry_attempts);
+
+/// The delay in milliseconds before the first retry of a failed connection at
+/// startup. Doubles with each subsequent retry, up to
+/// `STARTUP_RETRY_MAX_BACKOFF`. Defaults to 500ms.
+pub static STARTUP_RETRY_BACKOFF_MS: LazyLock<u64> =
+    LazyLock::new(|| config().startup_retry_backoff_ms);
+
+/// The longest delay between retries of a failed connection at startup.
+pub const STARTUP_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(10);
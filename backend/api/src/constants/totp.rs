@@ -0,0 +1,21 @@
+//! Constants controlling the TOTP secrets generated for users. Changing these
+//! affects only newly enrolled secrets, as each secret is stored with the
+//! parameters it was created with.
+use std::sync::LazyLock;
+
+use super::config::config;
+use crate::db::models::totp::TotpAlgorithm;
+
+/// The length in bytes of generated TOTP secrets. Must be at least 16, as
+/// required by RFC 4226. Defaults to 32.
+pub static TOTP_SECRET_LENGTH: LazyLock<usize> = LazyLock::new(|| config().totp_secret_length);
+
+/// The number of digits in TOTP codes, between 6 and 8. Defaults to 6.
+pub static TOTP_DIGITS: LazyLock<i16> = LazyLock::new(|| config().totp_digits);
+
+/// The number of seconds each TOTP code is valid for. Defaults to 30.
+pub static TOTP_STEP: LazyLock<i32> = LazyLock::new(|| config().totp_step);
+
+/// The HMAC algorithm used to generate TOTP codes, one of SHA1, SHA256 or
+/// SHA512. Defaults to SHA1, the only algorithm many authenticators support.
+pub static TOTP_ALGORITHM: LazyLock<TotpAlgorithm> = LazyLock::new(|| config().totp_algorithm);
@@ -0,0 +1,14 @@
+//! Constants controlling how deleted user accounts are kept and purged.
+use super::config::config;
+use std::sync::LazyLock;
+
+/// The period in seconds after a user's account is deleted during which an
+/// administrator can restore it, read from `USER_DELETION_GRACE_PERIOD_SECS`.
+/// Once it has passed, the account and everything belonging to it is purged,
+/// except that an account with confirmed orders is anonymized to keep them.
+/// Defaults to 30 days.
+pub static USER_DELETION_GRACE_PERIOD: LazyLock<u32> =
+    LazyLock::new(|| config().user_deletion_grace_period_secs);
+/// The interval in seconds between purges of accounts whose grace period has
+/// passed.
+pub const USER_PURGE_INTERVAL: u64 = 60 * 60;
@@ -1,21 +1,231 @@
 //! Contains database models and interaction code.
 pub mod models;
-use crate::constants::db as constants;
+use core::ops::Deref;
+use std::collections::HashSet;
+
+use sqlx::{
+    migrate::{Migrate as _, Migration, Migrator},
+    postgres::PgPoolOptions,
+    Postgres, QueryBuilder,
+};
+
+use crate::{constants::db as constants, utils::pagination::Pagination};
 
 /// An alias for the underlying DBMS specific pool type.
 pub type ConnectionPool = sqlx::PgPool;
 
+/// A pool for read-only queries, such as catalog browsing, connected to the
+/// read replica if one is configured (see `connect_read`). Functions which
+/// should read from the replica take this rather than a `ConnectionPool`, so
+/// that the choice is made once, by their signature, rather than by each
+/// caller. Dereferences to the underlying pool to run queries against it.
+#[derive(Clone)]
+pub struct ReadConnectionPool(ConnectionPool);
+
+impl From<ConnectionPool> for ReadConnectionPool {
+    fn from(pool: ConnectionPool) -> Self {
+        Self(pool)
+    }
+}
+
+impl Deref for ReadConnectionPool {
+    type Target = ConnectionPool;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Anything a query can be run against: a `&ConnectionPool`, or a connection
+/// borrowed from a `Transaction` (`&mut *tx`).
+pub use sqlx::postgres::PgExecutor as Executor;
+
+/// An alias for the underlying DBMS specific transaction type.
+pub type Transaction = sqlx::Transaction<'static, sqlx::Postgres>;
+
+/// Begin a new transaction on the given pool. Rolled back on drop unless it is
+/// committed.
+pub async fn begin(pool: &ConnectionPool) -> Result<Transaction, errors::DatabaseError> {
+    Ok(pool.begin().await?)
+}
+
+/// Commit a transaction begun with `begin`.
+pub async fn commit(transaction: Transaction) -> Result<(), errors::DatabaseError> {
+    Ok(transaction.commit().await?)
+}
+
+/// The options every database pool is created with, as configured by the
+/// `DB_MAX_CONNECTIONS`, `DB_ACQUIRE_TIMEOUT` and `DB_IDLE_TIMEOUT` constants.
+fn pool_options() -> PgPoolOptions {
+    PgPoolOptions::new()
+        .max_connections(*constants::DB_MAX_CONNECTIONS)
+        .acquire_timeout(*constants::DB_ACQUIRE_TIMEOUT)
+        .idle_timeout(*constants::DB_IDLE_TIMEOUT)
+}
+
 /// Initiate a pooled connection to the database.
 pub async fn connect() -> Result<ConnectionPool, errors::DatabaseError> {
-    Ok(sqlx::PgPool::connect(&constants::DB_URL).await?)
+    Ok(pool_options().connect(&constants::DB_URL).await?)
+}
+
+/// Append the LIMIT and OFFSET clauses selecting a page of results to a query.
+/// The query must already be ordered for pages to be consistent.
+pub fn push_page(query: &mut QueryBuilder<'_, Postgres>, page: Pagination) {
+    query.push(" LIMIT ");
+    query.push_bind(i64::from(page.limit));
+    query.push(" OFFSET ");
+    query.push_bind(i64::from(page.offset));
+}
+
+/// Append only the LIMIT clause of a page of results to a query, for queries
+/// which select their page by a cursor rather than an offset.
+pub fn push_limit(query: &mut QueryBuilder<'_, Postgres>, page: Pagination) {
+    query.push(" LIMIT ");
+    query.push_bind(i64::from(page.limit));
+}
+
+/// The migrations embedded from the `migrations` directory, which define the
+/// database schema.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Apply any of the embedded migrations which have not yet been applied to the
+/// database. Returns the migrations which were applied, in order.
+pub async fn migrate(
+    pool: &ConnectionPool,
+) -> Result<Vec<&'static Migration>, errors::MigrationError> {
+    let applied: HashSet<i64> = {
+        let mut conn = pool.acquire().await.map_err(errors::DatabaseError::from)?;
+        conn.ensure_migrations_table().await?;
+        conn.list_applied_migrations()
+            .await?
+            .into_iter()
+            .map(|migration| migration.version)
+            .collect()
+    };
+    MIGRATOR.run(pool).await?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect())
+}
+
+/// Initiate a pooled connection to be used for read-only queries. This will
+/// connect to the read replica if one is configured, and otherwise fall back
+/// to sharing the given primary pool.
+pub async fn connect_read(
+    primary: &ConnectionPool,
+) -> Result<ReadConnectionPool, errors::DatabaseError> {
+    connect_read_from(constants::DB_REPLICA_URL.as_deref(), primary).await
+}
+
+/// Initiate a pooled connection for read-only queries to the replica at
+/// `replica_url`, or share the primary pool if there is none.
+async fn connect_read_from(
+    replica_url: Option<&str>,
+    primary: &ConnectionPool,
+) -> Result<ReadConnectionPool, errors::DatabaseError> {
+    match replica_url {
+        Some(url) => Ok(ReadConnectionPool(pool_options().connect(url).await?)),
+        None => Ok(ReadConnectionPool(primary.clone())),
+    }
 }
 
 /// Errors returned by functions in this module.
 pub mod errors {
+    use sqlx::migrate::MigrateError;
     use thiserror::Error;
 
     /// An error returned by underlying database layer.
     #[derive(Error, Debug)]
     #[error(transparent)]
     pub struct DatabaseError(#[from] sqlx::Error);
+
+    impl DatabaseError {
+        /// Whether the error was caused by violating a unique constraint
+        /// (SQLSTATE 23505), e.g. inserting a duplicate of an existing record.
+        pub fn is_unique_violation(&self) -> bool {
+            matches!(&self.0, sqlx::Error::Database(err) if err.is_unique_violation())
+        }
+    }
+
+    /// Errors returned when updating a record which is guarded against
+    /// concurrent updates by its version.
+    #[derive(Error, Debug)]
+    pub enum UpdateError {
+        /// An error returned by the database.
+        #[error(transparent)]
+        Database(#[from] DatabaseError),
+        /// The record was updated (or deleted) since it was read, so the update
+        /// was not applied. The caller should re-read the record and retry.
+        #[error("The record was modified concurrently")]
+        ConcurrencyConflict,
+    }
+
+    /// Errors returned while migrating the database.
+    #[derive(Error, Debug)]
+    pub enum MigrationError {
+        /// An error connecting to the database.
+        #[error(transparent)]
+        Database(#[from] DatabaseError),
+        /// An error applying or inspecting the migrations.
+        #[error(transparent)]
+        Migrate(#[from] MigrateError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::var;
+
+    use super::{connect_read_from, migrate, ConnectionPool, MIGRATOR};
+
+    /// The name of the database a pool is connected to.
+    async fn database_name(pool: &ConnectionPool) -> String {
+        sqlx::query_scalar("SELECT current_database()")
+            .fetch_one(pool)
+            .await
+            .expect("Database name should be read")
+    }
+
+    /// Every migration applies in order to an empty database, and migrating
+    /// again applies nothing.
+    #[sqlx::test(migrations = false)]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn migrations_apply_once_in_order(db_conn: ConnectionPool) {
+        let applied = migrate(&db_conn).await.expect("Migrations should apply");
+        let applied_versions: Vec<i64> =
+            applied.iter().map(|migration| migration.version).collect();
+        let versions: Vec<i64> = MIGRATOR.iter().map(|migration| migration.version).collect();
+        assert_eq!(applied_versions, versions);
+        let reapplied = migrate(&db_conn)
+            .await
+            .expect("Migrating again should succeed");
+        assert!(reapplied.is_empty());
+    }
+
+    /// Reads go to the replica if one is configured. The test's own database
+    /// stands in for the primary, and the one at `DATABASE_URL` for the
+    /// replica.
+    #[sqlx::test(migrations = false)]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn reads_use_replica(db_conn: ConnectionPool) {
+        let replica_url = var("DATABASE_URL").expect("DATABASE_URL should be set");
+        let read_conn = connect_read_from(Some(&replica_url), &db_conn)
+            .await
+            .expect("Replica should be connected");
+        let primary_name = database_name(&db_conn).await;
+        assert_ne!(database_name(&read_conn).await, primary_name);
+    }
+
+    /// Without a replica, reads share the primary pool.
+    #[sqlx::test(migrations = false)]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn reads_fall_back_to_primary(db_conn: ConnectionPool) {
+        let read_conn = connect_read_from(None, &db_conn)
+            .await
+            .expect("Primary should be shared");
+        assert_eq!(
+            database_name(&read_conn).await,
+            database_name(&db_conn).await
+        );
+    }
 }
@@ -1,23 +1,41 @@
-#![expect(
-    clippy::pattern_type_mismatch,
-    reason = "This warning comes from sqlx::Type, not my fault"
-)]
 //! Models mapping to the apporder database table. Represents a user's order
 //! from the store.
-use crate::db::{errors::DatabaseError, ConnectionPool};
+use crate::{
+    constants::db::DB_ENCRYPTION_KEY,
+    db::{
+        self,
+        errors::{DatabaseError, UpdateError},
+        ConnectionPool, Executor,
+    },
+    utils::{
+        address::Address,
+        pagination::{self, Pagination},
+        pennies::{errors::PenniesOverflow, Pennies},
+    },
+};
+use futures_util::{Stream, TryStreamExt as _};
 use serde::{Deserialize, Serialize, Serializer};
-use sqlx::{prelude::FromRow, query, query_as, QueryBuilder};
-use time::{serde::iso8601, PrimitiveDateTime};
+use sqlx::{prelude::FromRow, query, query_as, query_scalar, Postgres, QueryBuilder};
+use time::{serde::iso8601, OffsetDateTime, PrimitiveDateTime, UtcOffset};
 use uuid::Uuid;
 
 /// INSERT model for an `AppOrder`. Used ONLY when creating a new order.
 pub struct AppOrderInsert {
-    /// The amount in pennies charged for this order.
-    pub amount_charged: i64,
+    /// The amount charged for this order, and how it is made up.
+    pub amounts: AmountBreakdown,
+    /// The method by which the order is to be shipped.
+    pub shipping_method: ShippingMethod,
     /// The time and date the order was placed.
     pub order_placed: PrimitiveDateTime,
     /// The ID of the user who placed the order.
     pub user_id: Uuid,
+    /// The code of the coupon redeemed for the order, if any.
+    pub coupon_code: Option<String>,
+    /// The percentage taken off the order by its coupon, or 0 without one.
+    pub percent_off: u8,
+    /// The address the order is to be shipped to, as it was when the order
+    /// was placed.
+    pub shipping_address: Address,
 }
 
 #[derive(Clone, Copy, sqlx::Type, Serialize, Deserialize, PartialEq, Eq)]
@@ -28,8 +46,96 @@ pub enum AppOrderStatus {
     Unconfirmed,
     /// TODO: add documentation
     Confirmed,
+    /// Some, but not all, of the order's items have been fulfilled.
+    PartiallyFulfilled,
     /// TODO: add documentation
     Fulfilled,
+    /// The whole amount charged has been refunded. Partial refunds leave the
+    /// status as it was, see `AppOrder::refunded_amount`.
+    Refunded,
+}
+
+impl AppOrderStatus {
+    /// The name of the status, as it is serialized.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Unconfirmed => "Unconfirmed",
+            Self::Confirmed => "Confirmed",
+            Self::PartiallyFulfilled => "PartiallyFulfilled",
+            Self::Fulfilled => "Fulfilled",
+            Self::Refunded => "Refunded",
+        }
+    }
+    /// Whether an order with this status has been paid for, and not yet
+    /// completely refunded, and so may be (further) refunded.
+    pub const fn is_refundable(self) -> bool {
+        matches!(
+            self,
+            Self::Confirmed | Self::PartiallyFulfilled | Self::Fulfilled
+        )
+    }
+}
+
+/// A method by which an order can be shipped. Which methods are offered, and
+/// what they cost, depends on the configured shipping calculator.
+#[derive(Clone, Copy, sqlx::Type, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[sqlx(type_name = "shipping_method")]
+pub enum ShippingMethod {
+    /// The default, cheapest method.
+    #[default]
+    Standard,
+    /// A faster method, at extra cost.
+    Express,
+}
+
+impl ShippingMethod {
+    /// Every shipping method, in the order they are offered.
+    pub const ALL: [Self; 2] = [Self::Standard, Self::Express];
+    /// The name of the method, as it is serialized.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Standard => "Standard",
+            Self::Express => "Express",
+        }
+    }
+}
+
+/// How the amount charged for an order is made up, in pennies. The amount
+/// charged is always the subtotal plus tax and shipping, which the database
+/// also enforces. The discount has already been taken off the subtotal, and
+/// is recorded so that invoices and refunds can show it.
+#[derive(Serialize, Clone, Copy)]
+pub struct AmountBreakdown {
+    /// The amount before tax, after the discount.
+    subtotal: i64,
+    /// The discount taken off the products' prices.
+    discount: i64,
+    /// The tax on the subtotal.
+    tax: i64,
+    /// The cost of shipping.
+    shipping: i64,
+    /// The amount charged.
+    total: i64,
+}
+
+impl AmountBreakdown {
+    /// Construct a breakdown from its components, failing if their total
+    /// would overflow.
+    pub fn new(
+        subtotal: Pennies,
+        discount: Pennies,
+        tax: Pennies,
+        shipping: Pennies,
+    ) -> Result<Self, PenniesOverflow> {
+        let total = subtotal.checked_add(tax)?.checked_add(shipping)?;
+        Ok(Self {
+            subtotal: subtotal.as_i64(),
+            discount: discount.as_i64(),
+            tax: tax.as_i64(),
+            shipping: shipping.as_i64(),
+            total: total.as_i64(),
+        })
+    }
 }
 
 /// An `AppOrder` which is stored in the database. Can only be constructed
@@ -38,8 +144,23 @@ pub enum AppOrderStatus {
 pub struct AppOrder {
     /// The `AppOrder`'s ID primary key. Private to restrict construction.
     id: Uuid,
-    /// The amount in pennies charged for this order.
+    /// The amount in pennies charged for this order, including tax and
+    /// shipping.
     pub amount_charged: i64,
+    /// The amount in pennies charged for this order before tax, after any
+    /// discount.
+    pub subtotal: i64,
+    /// The discount in pennies taken off this order before tax.
+    pub discount: i64,
+    /// The amount in pennies of tax charged for this order.
+    pub tax: i64,
+    /// The amount in pennies charged for shipping this order.
+    pub shipping: i64,
+    /// The method by which the order is to be shipped.
+    pub shipping_method: ShippingMethod,
+    /// The amount in pennies which has been refunded so far. The order is only
+    /// marked `Refunded` once this reaches the amount charged.
+    pub refunded_amount: i64,
     /// The time and date the order was placed.
     #[serde(serialize_with = "serialize_primitive_datetime")]
     pub order_placed: PrimitiveDateTime,
@@ -47,9 +168,12 @@ pub struct AppOrder {
     user_id: Uuid,
     /// The order's current status.
     status: AppOrderStatus,
+    /// Incremented on every update, to detect concurrent updates.
+    #[serde(skip)]
+    version: i64,
 }
 
-fn serialize_primitive_datetime<S>(
+pub(super) fn serialize_primitive_datetime<S>(
     time: &PrimitiveDateTime,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
@@ -62,23 +186,117 @@ where
 
 impl AppOrderInsert {
     /// Store this INSERT model in the database and return a complete `AppOrder` model.
-    pub async fn store(self, db_client: &ConnectionPool) -> Result<AppOrder, DatabaseError> {
+    pub async fn store<'c, E: Executor<'c>>(self, db_client: E) -> Result<AppOrder, DatabaseError> {
         #[expect(clippy::as_conversions, reason="As here is part of the query_as! macro")]
         Ok(query_as!(
             AppOrder,
-            r#"INSERT INTO apporder (user_id, order_placed, amount_charged, status) VALUES ($1, $2, $3, $4) RETURNING id, user_id, order_placed AS "order_placed", amount_charged, status AS "status!: AppOrderStatus""#,
-            &self.user_id, &self.order_placed, &self.amount_charged, AppOrderStatus::Unconfirmed as AppOrderStatus
+            r#"INSERT INTO apporder (user_id, order_placed, amount_charged, subtotal, discount, tax, shipping, shipping_method, status, coupon_code, percent_off, shipping_address)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, pgp_sym_encrypt($12, $13)) RETURNING id, user_id, order_placed AS "order_placed", amount_charged, subtotal, discount, tax, shipping, shipping_method AS "shipping_method!: ShippingMethod", refunded_amount, status AS "status!: AppOrderStatus", version"#,
+            &self.user_id, &self.order_placed, &self.amounts.total, &self.amounts.subtotal, &self.amounts.discount, &self.amounts.tax, &self.amounts.shipping, self.shipping_method as ShippingMethod, AppOrderStatus::Unconfirmed as AppOrderStatus, self.coupon_code, i16::from(self.percent_off), self.shipping_address.to_stored(), *DB_ENCRYPTION_KEY
         ).fetch_one(db_client).await?)
     }
 }
 
-#[derive(Deserialize)]
+/// The position of an order within search results, which are ordered by most
+/// recently placed, then ID. A search given a cursor continues from after
+/// that position.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct AppOrderCursor {
+    /// The time the last order returned was placed.
+    order_placed: PrimitiveDateTime,
+    /// The ID of the last order returned.
+    id: Uuid,
+}
+
+impl From<&AppOrder> for AppOrderCursor {
+    #[inline]
+    fn from(order: &AppOrder) -> Self {
+        Self {
+            order_placed: order.order_placed,
+            id: order.id,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
 /// TODO: add documentation
 pub struct AppOrderSearchParameters {
     /// TODO: add documentation
     pub user_id: Option<Uuid>,
     /// TODO: add documentation
     pub status: Option<AppOrderStatus>,
+    /// Match only orders placed at or after this time (ISO 8601).
+    #[serde(default, with = "iso8601::option")]
+    pub placed_from: Option<OffsetDateTime>,
+    /// Match only orders placed before this time (ISO 8601).
+    #[serde(default, with = "iso8601::option")]
+    pub placed_to: Option<OffsetDateTime>,
+    /// Only return orders after this position (see `pagination::cursor`). If
+    /// set, the page's offset is ignored.
+    #[serde(default, deserialize_with = "pagination::cursor")]
+    pub after: Option<AppOrderCursor>,
+}
+
+impl AppOrderSearchParameters {
+    /// Build a query selecting every `AppOrder` matching these parameters,
+    /// most recently placed first.
+    fn query(&self) -> QueryBuilder<'static, Postgres> {
+        // 1=1 is used to make adding additional criteria simpler, since they
+        // will always use AND.
+        let mut query = QueryBuilder::new(
+            "SELECT id, user_id, order_placed, amount_charged, subtotal, discount, tax, shipping, shipping_method, refunded_amount, status, version FROM apporder WHERE 1=1",
+        );
+        if let Some(user_id) = self.user_id {
+            query.push(" AND user_id = ");
+            query.push_bind(user_id);
+        }
+        if let Some(status) = self.status {
+            query.push(" AND status = ");
+            query.push_bind(status);
+        }
+        // Times are stored in UTC without an offset.
+        if let Some(from) = self.placed_from {
+            let utc = from.to_offset(UtcOffset::UTC);
+            query.push(" AND order_placed >= ");
+            query.push_bind(PrimitiveDateTime::new(utc.date(), utc.time()));
+        }
+        if let Some(to) = self.placed_to {
+            let utc = to.to_offset(UtcOffset::UTC);
+            query.push(" AND order_placed < ");
+            query.push_bind(PrimitiveDateTime::new(utc.date(), utc.time()));
+        }
+        // The order is mixed (placed descending, ID ascending), so a row
+        // comparison can't be used.
+        if let Some(after) = self.after {
+            query.push(" AND (order_placed < ");
+            query.push_bind(after.order_placed);
+            query.push(" OR (order_placed = ");
+            query.push_bind(after.order_placed);
+            query.push(" AND id > ");
+            query.push_bind(after.id);
+            query.push("))");
+        }
+        query.push(" ORDER BY order_placed DESC, id");
+        query
+    }
+}
+
+/// A search for `AppOrder`s which has been prepared to be streamed from the
+/// database (see `AppOrder::prepare_search`).
+pub struct PreparedAppOrderSearch(QueryBuilder<'static, Postgres>);
+
+impl PreparedAppOrderSearch {
+    /// Stream every matching `AppOrder`, without fetching them all into memory
+    /// at once.
+    pub fn stream<'a>(
+        &'a mut self,
+        db_client: &'a ConnectionPool,
+    ) -> impl Stream<Item = Result<AppOrder, DatabaseError>> + Send + 'a {
+        self.0
+            .build_query_as()
+            .fetch(db_client)
+            .map_err(DatabaseError::from)
+    }
 }
 
 impl AppOrder {
@@ -95,53 +313,201 @@ impl AppOrder {
         id: Uuid,
         db_client: &ConnectionPool,
     ) -> Result<Option<Self>, DatabaseError> {
-        Ok(query_as!(Self, r#"SELECT id, user_id, order_placed, amount_charged, status AS "status!: AppOrderStatus" FROM apporder WHERE id = $1"#, id)
+        Ok(query_as!(Self, r#"SELECT id, user_id, order_placed, amount_charged, subtotal, discount, tax, shipping, shipping_method AS "shipping_method!: ShippingMethod", refunded_amount, status AS "status!: AppOrderStatus", version FROM apporder WHERE id = $1"#, id)
             .fetch_optional(db_client)
             .await?)
     }
     /// Retrieve all `AppOrder` records in the database.
     pub async fn select_all(db_client: &ConnectionPool) -> Result<Vec<Self>, DatabaseError> {
-        Ok(query_as!(Self, r#"SELECT id, user_id, order_placed, amount_charged, status AS "status!: AppOrderStatus" FROM apporder"#)
+        Ok(query_as!(Self, r#"SELECT id, user_id, order_placed, amount_charged, subtotal, discount, tax, shipping, shipping_method AS "shipping_method!: ShippingMethod", refunded_amount, status AS "status!: AppOrderStatus", version FROM apporder"#)
             .fetch_all(db_client)
             .await?)
     }
-    /// TODO: add documentation
+    /// Prepare a search for every `AppOrder` matching a given set of search
+    /// parameters, most recently placed first, to be streamed (rather than
+    /// fetched all at once as by `search`).
+    pub fn prepare_search(params: &AppOrderSearchParameters) -> PreparedAppOrderSearch {
+        PreparedAppOrderSearch(params.query())
+    }
+    /// Return all `AppOrder`s matching a given set of search parameters, most
+    /// recently placed first, limited to `page` if set.
     pub async fn search(
         params: AppOrderSearchParameters,
+        page: Option<Pagination>,
         db_client: &ConnectionPool,
     ) -> Result<Vec<Self>, DatabaseError> {
-        let mut query = QueryBuilder::new(
-            "SELECT id, user_id, order_placed, amount_charged, status FROM apporder WHERE 1=1",
-        );
-        if let Some(user_id) = params.user_id {
-            query.push(" AND user_id = ");
-            query.push_bind(user_id);
-        }
-        if let Some(status) = params.status {
-            query.push(" AND status = ");
-            query.push_bind(status);
+        let mut query = params.query();
+        if let Some(selected) = page {
+            if params.after.is_some() {
+                db::push_limit(&mut query, selected);
+            } else {
+                db::push_page(&mut query, selected);
+            }
         }
         Ok(query.build_query_as().fetch_all(db_client).await?)
     }
 
-    /// Update the database record to match the model's current state.
-    pub async fn update(&self, db_client: &ConnectionPool) -> Result<(), DatabaseError> {
+    /// Update the database record to match the model's current state. Fails
+    /// with `ConcurrencyConflict` if the record was updated since this model
+    /// was read, in which case nothing is written.
+    pub async fn update<'c, E: Executor<'c>>(&mut self, db_client: E) -> Result<(), UpdateError> {
         #[expect(clippy::as_conversions, reason="As here is part of the query! macro, not an actual as cast")]
+        let updated = query!(
+            "UPDATE apporder SET user_id=$1, order_placed=$2, status=$3, version=version+1 WHERE id=$4 AND version=$5",
+            self.user_id, self.order_placed, self.status as AppOrderStatus, self.id, self.version
+        ).execute(db_client).await.map_err(DatabaseError::from)?.rows_affected();
+        if updated == 0 {
+            return Err(UpdateError::ConcurrencyConflict);
+        }
+        self.version = self.version.saturating_add(1);
+        Ok(())
+    }
+    /// Move every order placed by one of the users `from` to the user `to`.
+    pub async fn reassign_user<'c, E: Executor<'c>>(
+        from: &[Uuid],
+        to: Uuid,
+        db_client: E,
+    ) -> Result<(), DatabaseError> {
         query!(
-            "UPDATE apporder SET user_id=$1, order_placed=$2, amount_charged=$3, status=$4 WHERE id=$5",
-            self.user_id, self.order_placed, self.amount_charged, self.status as AppOrderStatus, self.id
-        ).execute(db_client).await?;
+            "UPDATE apporder SET user_id = $1, version = version + 1 WHERE user_id = ANY($2)",
+            to,
+            from
+        )
+        .execute(db_client)
+        .await?;
         Ok(())
     }
-    /// Delete the corresponding record from the database. Also consumes the
-    /// model itself for consistency.
-    pub async fn delete(self, db_client: &ConnectionPool) -> Result<(), DatabaseError> {
-        query!("DELETE FROM apporder WHERE id = $1", self.id)
-            .execute(db_client)
-            .await?;
+    /// Set the amount charged for the order with the given ID, along with how
+    /// it is made up, only if it is still unconfirmed. Returns whether the
+    /// order was updated.
+    pub async fn set_amounts_if_unconfirmed<'c, E: Executor<'c>>(
+        id: Uuid,
+        amounts: AmountBreakdown,
+        db_client: E,
+    ) -> Result<bool, DatabaseError> {
+        Ok(query!(
+            "UPDATE apporder SET amount_charged = $1, subtotal = $2, discount = $3, tax = $4,
+            shipping = $5, version = version + 1 WHERE id = $6 AND status = 'Unconfirmed'",
+            amounts.total,
+            amounts.subtotal,
+            amounts.discount,
+            amounts.tax,
+            amounts.shipping,
+            id
+        )
+        .execute(db_client)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+    /// The percentage taken off the order with the given ID by the coupon
+    /// redeemed when it was placed, or 0 if none was.
+    pub async fn select_percent_off<'c, E: Executor<'c>>(
+        id: Uuid,
+        db_client: E,
+    ) -> Result<u8, DatabaseError> {
+        let percent_off = query!("SELECT percent_off FROM apporder WHERE id = $1", id)
+            .fetch_one(db_client)
+            .await?
+            .percent_off;
+        Ok(u8::try_from(percent_off)
+            .expect("Database constraint on percent_off should prevent out of range values."))
+    }
+    /// The address the order with the given ID is shipped to, as it was when
+    /// the order was placed.
+    pub async fn select_shipping_address<'c, E: Executor<'c>>(
+        id: Uuid,
+        db_client: E,
+    ) -> Result<Address, DatabaseError> {
+        Ok(query_scalar!(
+            r#"SELECT pgp_sym_decrypt(shipping_address, $2) AS "shipping_address!: Address"
+            FROM apporder WHERE id = $1"#,
+            id,
+            *DB_ENCRYPTION_KEY
+        )
+        .fetch_one(db_client)
+        .await?)
+    }
+    /// Add a refund of `amount` to the order with the given ID, marking it
+    /// `Refunded` if the whole amount charged has now been refunded. A partial
+    /// refund leaves the status alone, so the rest of the order can still be
+    /// fulfilled. Only applied if the order is refundable and the total
+    /// refunded would not exceed the amount charged, returning the updated
+    /// order if so.
+    pub async fn record_refund<'c, E: Executor<'c>>(
+        id: Uuid,
+        amount: i64,
+        db_client: E,
+    ) -> Result<Option<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            r#"UPDATE apporder SET refunded_amount = refunded_amount + $2,
+            status = CASE WHEN refunded_amount + $2 = amount_charged
+                THEN 'Refunded'::app_order_status ELSE status END,
+            version = version + 1
+            WHERE id = $1 AND refunded_amount + $2 <= amount_charged
+            AND status IN ('Confirmed', 'PartiallyFulfilled', 'Fulfilled')
+            RETURNING id, user_id, order_placed, amount_charged, subtotal, discount, tax, shipping, shipping_method AS "shipping_method!: ShippingMethod", refunded_amount,
+            status AS "status!: AppOrderStatus", version"#,
+            id,
+            amount
+        )
+        .fetch_optional(db_client)
+        .await?)
+    }
+    /// Delete the corresponding record from the database, but only if the
+    /// order is still unconfirmed, since confirmed orders are financial
+    /// records. Returns whether it was deleted. Also consumes the model
+    /// itself for consistency.
+    pub async fn delete_unconfirmed(
+        self,
+        db_client: &ConnectionPool,
+    ) -> Result<bool, DatabaseError> {
+        Ok(query!(
+            "DELETE FROM apporder WHERE id = $1 AND status = 'Unconfirmed'",
+            self.id
+        )
+        .execute(db_client)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+    /// Delete every unconfirmed order placed by a user who was deleted before
+    /// `cutoff`, ahead of them being purged. Confirmed orders are kept.
+    pub async fn delete_unconfirmed_of_deleted_users<'c, E: Executor<'c>>(
+        cutoff: PrimitiveDateTime,
+        db_client: E,
+    ) -> Result<(), DatabaseError> {
+        query!(
+            "DELETE FROM apporder WHERE status = 'Unconfirmed' AND user_id IN (
+                SELECT id FROM appuser WHERE deleted_at < $1 AND NOT anonymized
+            )",
+            cutoff
+        )
+        .execute(db_client)
+        .await?;
         Ok(())
     }
 
+    /// The amount charged for the order, and how it is made up.
+    pub const fn amounts(&self) -> AmountBreakdown {
+        AmountBreakdown {
+            subtotal: self.subtotal,
+            discount: self.discount,
+            tax: self.tax,
+            shipping: self.shipping,
+            total: self.amount_charged,
+        }
+    }
+    /// Set the amount charged for the order, and how it is made up. Only
+    /// changes the model, see `set_amounts_if_unconfirmed`.
+    pub const fn set_amounts(&mut self, amounts: AmountBreakdown) {
+        self.amount_charged = amounts.total;
+        self.subtotal = amounts.subtotal;
+        self.discount = amounts.discount;
+        self.tax = amounts.tax;
+        self.shipping = amounts.shipping;
+    }
     /// TODO: add documentation
     pub const fn status(&self) -> AppOrderStatus {
         self.status
@@ -151,3 +517,55 @@ impl AppOrder {
         self.status = status;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AppOrder, AppOrderStatus, ShippingMethod};
+    use crate::{
+        db::{errors::UpdateError, models::product::ProductInsert, ConnectionPool},
+        services::orders::create_order,
+        testing::store_user,
+    };
+
+    /// Of two concurrent status changes to an order, the second to be written
+    /// detects the conflict rather than overwriting the first.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn concurrent_update_conflicts(db_conn: ConnectionPool) {
+        let customer = store_user("customer@example.com", &db_conn).await;
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(&db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        let id = create_order(
+            customer.id(),
+            vec![(product_id, 1)],
+            None,
+            ShippingMethod::Standard,
+            &db_conn,
+        )
+        .await
+        .expect("Order should be created")
+        .id();
+        let select = || async {
+            AppOrder::select_one(id, &db_conn)
+                .await
+                .expect("Order should be selected")
+                .expect("Order should exist")
+        };
+        let mut first = select().await;
+        let mut second = select().await;
+        first.set_status(AppOrderStatus::Confirmed);
+        first
+            .update(&db_conn)
+            .await
+            .expect("First update should succeed");
+        second.set_status(AppOrderStatus::Refunded);
+        assert!(matches!(
+            second.update(&db_conn).await,
+            Err(UpdateError::ConcurrencyConflict)
+        ));
+        assert!(select().await.status() == AppOrderStatus::Confirmed);
+    }
+}
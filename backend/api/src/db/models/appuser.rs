@@ -1,14 +1,17 @@
 //! Models mapping to the appuser database table. Represents a user and their
 //! associated information.
-#![expect(clippy::pattern_type_mismatch, reason = "SQLx enum bug")]
-
 use crate::{
     constants::db::DB_ENCRYPTION_KEY,
-    db::{errors::DatabaseError, ConnectionPool},
-    utils::email::EmailAddress,
+    db::{self, errors::DatabaseError, ConnectionPool, Executor},
+    utils::{
+        address::Address, email::EmailAddress, pagination::Pagination, phone::PhoneNumber,
+        redact::RedactedEmail, text,
+    },
 };
-use serde::{Deserialize, Serialize};
-use sqlx::{postgres::PgArguments, query, query_as, Arguments as _, QueryBuilder};
+use core::fmt;
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::{postgres::PgArguments, query, query_as, query_scalar, Arguments as _, QueryBuilder};
+use time::{serde::iso8601, OffsetDateTime, PrimitiveDateTime};
 use uuid::Uuid;
 
 /// INSERT model for an `AppUser`. Used ONLY when creating a new user.
@@ -17,11 +20,20 @@ pub struct AppUserInsert {
     /// The user's email address.
     pub email: EmailAddress,
     /// The user's forename.
+    #[serde(deserialize_with = "text::name")]
     pub forename: String,
     /// The user's surname.
+    #[serde(deserialize_with = "text::name")]
     pub surname: String,
     /// The user's address.
-    pub address: String,
+    pub address: Address,
+    /// The user's phone number, if they have provided one.
+    #[serde(default)]
+    pub phone: Option<PhoneNumber>,
+    /// Whether the user is a guest, created only to place an order and
+    /// without any credential to authenticate with.
+    #[serde(skip)]
+    pub guest: bool,
 }
 
 #[derive(sqlx::Type, Serialize, PartialEq, Eq, Deserialize)]
@@ -39,6 +51,23 @@ pub struct AppUserSearchParameters {
     pub email: Option<EmailAddress>,
     /// TODO: add documentation
     pub role: Option<AppUserRole>,
+    /// Match only users who have not logged in since this time, including
+    /// users who have never logged in at all.
+    #[serde(default, with = "iso8601::option")]
+    pub inactive_since: Option<OffsetDateTime>,
+    /// Match only users whose forename contains this, ignoring case.
+    pub forename: Option<String>,
+    /// Match only users whose surname contains this, ignoring case.
+    pub surname: Option<String>,
+}
+
+/// Escape the wildcard characters of a `LIKE` pattern, so that they match
+/// only themselves.
+fn escape_like(pattern: &str) -> String {
+    pattern
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
 }
 
 /// An `AppUser` which is stored in the database. Can only be constructed by
@@ -54,38 +83,113 @@ pub struct AppUser {
     /// The user's surname.
     pub surname: String,
     /// The user's address.
-    pub address: String,
+    pub address: Address,
+    /// The user's phone number, if they have provided one.
+    pub phone: Option<PhoneNumber>,
     /// The user's role (customer or admin).
     pub role: AppUserRole,
+    /// Whether the user has opted in to receiving MFA codes by email. Only
+    /// set once they have proven they can receive email at their address.
+    pub email_mfa_enabled: bool,
+    /// Whether the user has proven they can receive SMS messages at their
+    /// phone number. SMS MFA is only offered once they have.
+    pub phone_verified: bool,
+    /// Whether the user is a guest, who can only access the orders they were
+    /// given a guest order token for.
+    pub guest: bool,
+    /// When the user last fully authenticated (UTC), or None if they never have.
+    #[serde(serialize_with = "serialize_optional_primitive_datetime")]
+    pub last_login_at: Option<PrimitiveDateTime>,
+    /// When the user's account was deleted (UTC), or None if it has not been.
+    /// A deleted account can't be authenticated with, and is purged once
+    /// `USER_DELETION_GRACE_PERIOD` has passed.
+    #[serde(serialize_with = "serialize_optional_primitive_datetime")]
+    pub deleted_at: Option<PrimitiveDateTime>,
+}
+
+/// Serialize an optional UTC `PrimitiveDateTime` as an ISO8601 string (or null).
+#[expect(
+    clippy::ref_option,
+    reason = "Signature is dictated by serde's serialize_with"
+)]
+fn serialize_optional_primitive_datetime<S>(
+    time: &Option<PrimitiveDateTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    iso8601::option::serialize(&time.map(PrimitiveDateTime::assume_utc), serializer)
+}
+
+impl fmt::Debug for AppUserInsert {
+    /// Formats only what is needed to tell users apart in logs, so that
+    /// debugging output never contains anyone's PII in full.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AppUserInsert")
+            .field(
+                "email",
+                &format_args!("{}", RedactedEmail::from(&self.email)),
+            )
+            .field("forename", &"[REDACTED]")
+            .field("surname", &"[REDACTED]")
+            .field("address", &"[REDACTED]")
+            .field("phone", &self.phone.as_ref().map(|_| "[REDACTED]"))
+            .field("guest", &self.guest)
+            .finish()
+    }
 }
 
 impl AppUserInsert {
     /// Construct a new `AppUser` INSERT model.
-    pub fn new(email: EmailAddress, forename: &str, surname: &str, address: &str) -> Self {
+    pub fn new(
+        email: EmailAddress,
+        forename: &str,
+        surname: &str,
+        address: Address,
+        phone: Option<PhoneNumber>,
+    ) -> Self {
         Self {
             email,
             forename: forename.to_owned(),
             surname: surname.to_owned(),
-            address: address.to_owned(),
+            address,
+            phone,
+            guest: false,
+        }
+    }
+
+    /// Construct a new `AppUser` INSERT model for a guest user.
+    pub fn new_guest(email: EmailAddress, forename: &str, surname: &str, address: Address) -> Self {
+        Self {
+            guest: true,
+            ..Self::new(email, forename, surname, address, None)
         }
     }
 
     /// Store this INSERT model in the database and return a complete `AppUser` model.
-    pub async fn store(self, db_client: &ConnectionPool) -> Result<AppUser, DatabaseError> {
+    pub async fn store<'c, E: Executor<'c>>(self, db_client: E) -> Result<AppUser, DatabaseError> {
         Ok(query_as!(
             AppUser,
             r#"INSERT INTO appuser
-            (email, forename, surname, address, role)
-            VALUES ($1, pgp_sym_encrypt($2, $5), pgp_sym_encrypt($3, $5), pgp_sym_encrypt($4, $5), 'Customer')
+            (email, forename, surname, address, phone, role, guest)
+            VALUES ($1, pgp_sym_encrypt($2, $5), pgp_sym_encrypt($3, $5), pgp_sym_encrypt($4, $5),
+            pgp_sym_encrypt($6, $5), 'Customer', $7)
             RETURNING id, email AS "email: _", pgp_sym_decrypt(forename, $5) AS "forename!",
             pgp_sym_decrypt(surname, $5) AS "surname!",
-            pgp_sym_decrypt(address, $5) AS "address!", role AS "role!: AppUserRole""#,
+            pgp_sym_decrypt(address, $5) AS "address!: Address",
+            pgp_sym_decrypt(phone, $5) AS "phone: _",
+            role AS "role!: AppUserRole", email_mfa_enabled, phone_verified, guest, last_login_at, deleted_at"#,
             String::from(self.email),
             self.forename,
             self.surname,
-            self.address,
-            *DB_ENCRYPTION_KEY
-        ).fetch_one(db_client).await?)
+            self.address.to_stored(),
+            *DB_ENCRYPTION_KEY,
+            self.phone.map(String::from),
+            self.guest
+        )
+        .fetch_one(db_client)
+        .await?)
     }
 }
 
@@ -94,6 +198,10 @@ impl AppUser {
     pub const fn id(&self) -> Uuid {
         self.id
     }
+    /// Whether the user has the Administrator role.
+    pub fn is_admin(&self) -> bool {
+        self.role == AppUserRole::Administrator
+    }
     /// Select an `AppUser` from the database by ID.
     pub async fn select_one(
         id: Uuid,
@@ -103,8 +211,9 @@ impl AppUser {
             Self,
             r#"SELECT id, email AS "email: _", pgp_sym_decrypt(forename, $2) AS "forename!",
             pgp_sym_decrypt(surname, $2) AS "surname!",
-            pgp_sym_decrypt(address, $2) AS "address!",
-            role AS "role!: AppUserRole" FROM appuser WHERE id = $1"#,
+            pgp_sym_decrypt(address, $2) AS "address!: Address",
+            pgp_sym_decrypt(phone, $2) AS "phone: _",
+            role AS "role!: AppUserRole", email_mfa_enabled, phone_verified, guest, last_login_at, deleted_at FROM appuser WHERE id = $1"#,
             id,
             *DB_ENCRYPTION_KEY
         )
@@ -112,14 +221,37 @@ impl AppUser {
         .await?)
     }
 
+    /// Select a registered (non-guest) `AppUser` from the database by their
+    /// email address, ignoring case, as registered users' emails are unique
+    /// regardless of case. Guests are never selected, since several may share
+    /// an email (see `select_guest_ids_by_email`).
+    pub async fn select_by_email(
+        email: &EmailAddress,
+        db_client: &ConnectionPool,
+    ) -> Result<Option<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            r#"SELECT id, email AS "email: _", pgp_sym_decrypt(forename, $2) AS "forename!",
+            pgp_sym_decrypt(surname, $2) AS "surname!",
+            pgp_sym_decrypt(address, $2) AS "address!: Address",
+            pgp_sym_decrypt(phone, $2) AS "phone: _",
+            role AS "role!: AppUserRole", email_mfa_enabled, phone_verified, guest, last_login_at, deleted_at FROM appuser WHERE lower(email) = lower($1) AND NOT guest"#,
+            String::from(email.clone()),
+            *DB_ENCRYPTION_KEY
+        )
+        .fetch_optional(db_client)
+        .await?)
+    }
+
     /// Retrieve all `AppUser` records in the database.
     pub async fn select_all(db_client: &ConnectionPool) -> Result<Vec<Self>, DatabaseError> {
         Ok(query_as!(
             Self,
             r#"SELECT id, email AS "email: _", pgp_sym_decrypt(forename, $1) AS "forename!",
             pgp_sym_decrypt(surname, $1) AS "surname!",
-            pgp_sym_decrypt(address, $1) AS "address!",
-            role AS "role!: AppUserRole" FROM appuser"#,
+            pgp_sym_decrypt(address, $1) AS "address!: Address",
+            pgp_sym_decrypt(phone, $1) AS "phone: _",
+            role AS "role!: AppUserRole", email_mfa_enabled, phone_verified, guest, last_login_at, deleted_at FROM appuser"#,
             *DB_ENCRYPTION_KEY
         )
         .fetch_all(db_client)
@@ -127,34 +259,169 @@ impl AppUser {
     }
     /// Update the database record to match the model's current state.
     pub async fn update(&self, db_client: &ConnectionPool) -> Result<(), DatabaseError> {
+        #[expect(clippy::as_conversions, reason = "As here is part of the query! macro")]
         query!(
             "UPDATE appuser SET email = $1,
             forename = pgp_sym_encrypt($2, $6),
             surname = pgp_sym_encrypt($3, $6),
-            address = pgp_sym_encrypt($4, $6) WHERE id = $5",
+            address = pgp_sym_encrypt($4, $6),
+            phone = pgp_sym_encrypt($7, $6),
+            email_mfa_enabled = $8,
+            phone_verified = $10,
+            role = $9 WHERE id = $5",
             String::from(self.email.clone()),
             self.forename,
             self.surname,
-            self.address,
+            self.address.to_stored(),
             self.id,
-            *DB_ENCRYPTION_KEY
+            *DB_ENCRYPTION_KEY,
+            self.phone.clone().map(String::from),
+            self.email_mfa_enabled,
+            &self.role as &AppUserRole,
+            self.phone_verified
         )
         .execute(db_client)
         .await?;
         Ok(())
     }
-    /// Delete the corresponding record from the database. Also consumes the
-    /// model itself for consistency.
-    pub async fn delete(self, db_client: &ConnectionPool) -> Result<(), DatabaseError> {
-        query!("DELETE FROM appuser WHERE id = $1", self.id)
+    /// Select the IDs of every undeleted guest user with the given email (in
+    /// any case), locking them until the end of the transaction.
+    pub async fn select_guest_ids_by_email<'c, E: Executor<'c>>(
+        email: &str,
+        db_client: E,
+    ) -> Result<Vec<Uuid>, DatabaseError> {
+        Ok(query!(
+            "SELECT id FROM appuser WHERE lower(email) = lower($1) AND guest AND deleted_at IS NULL
+            ORDER BY id FOR UPDATE",
+            email
+        )
+        .fetch_all(db_client)
+        .await?
+        .into_iter()
+        .map(|record| record.id)
+        .collect())
+    }
+    /// Delete every guest user with one of the given IDs. Users who are not
+    /// guests are left untouched.
+    pub async fn delete_guests<'c, E: Executor<'c>>(
+        ids: &[Uuid],
+        db_client: E,
+    ) -> Result<(), DatabaseError> {
+        query!("DELETE FROM appuser WHERE id = ANY($1) AND guest", ids)
+            .execute(db_client)
+            .await?;
+        Ok(())
+    }
+    /// Mark a guest user as a full user, once they have a credential to
+    /// authenticate with.
+    pub async fn clear_guest<'c, E: Executor<'c>>(
+        user_id: Uuid,
+        db_client: E,
+    ) -> Result<(), DatabaseError> {
+        query!("UPDATE appuser SET guest = false WHERE id = $1", user_id)
             .execute(db_client)
             .await?;
         Ok(())
     }
+    /// Record that the user has just fully authenticated, updating both this
+    /// model and the database record.
+    pub async fn record_login(&mut self, db_client: &ConnectionPool) -> Result<(), DatabaseError> {
+        let current_time = OffsetDateTime::now_utc();
+        let now = PrimitiveDateTime::new(current_time.date(), current_time.time());
+        query!(
+            "UPDATE appuser SET last_login_at = $1 WHERE id = $2",
+            now,
+            self.id
+        )
+        .execute(db_client)
+        .await?;
+        self.last_login_at = Some(now);
+        Ok(())
+    }
+    /// Mark the user with the given ID as deleted as of `now`, unless they
+    /// already are. Returns whether they were marked.
+    pub async fn soft_delete(
+        id: Uuid,
+        now: PrimitiveDateTime,
+        db_client: &ConnectionPool,
+    ) -> Result<bool, DatabaseError> {
+        Ok(query!(
+            "UPDATE appuser SET deleted_at = $1 WHERE id = $2 AND deleted_at IS NULL",
+            now,
+            id
+        )
+        .execute(db_client)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+    /// Restore the deleted user with the given ID. Returns false if they are
+    /// not deleted, or have been anonymized by `anonymize_deleted`.
+    pub async fn restore(id: Uuid, db_client: &ConnectionPool) -> Result<bool, DatabaseError> {
+        Ok(query!(
+            "UPDATE appuser SET deleted_at = NULL
+            WHERE id = $1 AND deleted_at IS NOT NULL AND NOT anonymized",
+            id
+        )
+        .execute(db_client)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+    /// Permanently delete every user who was deleted before `cutoff` and has
+    /// no orders, along with everything belonging to them. Users with orders
+    /// are left, see `anonymize_deleted`. Returns the number purged.
+    pub async fn purge_deleted<'c, E: Executor<'c>>(
+        cutoff: PrimitiveDateTime,
+        db_client: E,
+    ) -> Result<u64, DatabaseError> {
+        Ok(query!(
+            "DELETE FROM appuser WHERE deleted_at < $1 AND NOT anonymized
+            AND NOT EXISTS (SELECT 1 FROM apporder WHERE apporder.user_id = appuser.id)",
+            cutoff
+        )
+        .execute(db_client)
+        .await?
+        .rows_affected())
+    }
+    /// Anonymize every user who was deleted before `cutoff` and has not
+    /// already been, keeping the row their orders belong to but removing
+    /// their personal details, credentials, reviews and stock notifications.
+    /// Anonymized users can't be restored. Returns the number anonymized.
+    pub async fn anonymize_deleted<'c, E: Executor<'c>>(
+        cutoff: PrimitiveDateTime,
+        db_client: E,
+    ) -> Result<u64, DatabaseError> {
+        let anonymized = query_scalar!(
+            r#"WITH anonymized AS (
+                UPDATE appuser SET email = id || '@deleted.invalid',
+                forename = pgp_sym_encrypt('Deleted', $2), surname = pgp_sym_encrypt('User', $2),
+                address = pgp_sym_encrypt('', $2), phone = NULL, email_mfa_enabled = false,
+                phone_verified = false, anonymized = true
+                WHERE deleted_at < $1 AND NOT anonymized RETURNING id
+            ), deleted_passwords AS (
+                DELETE FROM password WHERE user_id IN (SELECT id FROM anonymized)
+            ), deleted_totps AS (
+                DELETE FROM totp WHERE user_id IN (SELECT id FROM anonymized)
+            ), deleted_reviews AS (
+                DELETE FROM review WHERE user_id IN (SELECT id FROM anonymized)
+            ), deleted_notifications AS (
+                DELETE FROM stock_notification WHERE user_id IN (SELECT id FROM anonymized)
+            )
+            SELECT count(*) AS "count!" FROM anonymized"#,
+            cutoff,
+            *DB_ENCRYPTION_KEY
+        )
+        .fetch_one(db_client)
+        .await?;
+        Ok(u64::try_from(anonymized).expect("Count of rows should not be negative"))
+    }
 
-    /// TODO: add documentation
+    /// Return all `AppUser`s matching a given set of search parameters,
+    /// ordered by email, limited to `page` if set.
     pub async fn search(
         params: AppUserSearchParameters,
+        page: Option<Pagination>,
         db_client: &ConnectionPool,
     ) -> Result<Vec<Self>, DatabaseError> {
         let mut arguments = PgArguments::default();
@@ -165,23 +432,158 @@ impl AppUser {
             "SELECT id, email, pgp_sym_decrypt(forename, $1) AS forename,
             pgp_sym_decrypt(surname, $1) as surname,
             pgp_sym_decrypt(address, $1) as address,
-            role
+            pgp_sym_decrypt(phone, $1) as phone,
+            role, email_mfa_enabled, phone_verified, guest, last_login_at, deleted_at
             FROM appuser WHERE 1=1",
             arguments,
         );
 
         if let Some(email) = params.email {
-            let escaped_email = String::from(email)
-                .replace('\\', "\\\\")
-                .replace('%', "\\%")
-                .replace('_', "\\_");
             query.push(" AND email LIKE ");
-            query.push_bind(format!("%{escaped_email}%"));
+            query.push_bind(format!("%{}%", escape_like(email.as_str())));
+        }
+        // Names are encrypted at rest, so they can't be indexed, and every
+        // candidate row must be decrypted to be compared. This is a scan of
+        // every user matching the other criteria, however small the page.
+        // It is acceptable for the occasional search by support staff, but
+        // a blind index would be needed if searches by name became frequent.
+        if let Some(forename) = params.forename {
+            query.push(" AND pgp_sym_decrypt(forename, $1) ILIKE ");
+            query.push_bind(format!("%{}%", escape_like(&forename)));
+        }
+        if let Some(surname) = params.surname {
+            query.push(" AND pgp_sym_decrypt(surname, $1) ILIKE ");
+            query.push_bind(format!("%{}%", escape_like(&surname)));
         }
         if let Some(role) = params.role {
             query.push(" AND role = ");
             query.push_bind(role);
         }
+        if let Some(inactive_since) = params.inactive_since {
+            let cutoff = inactive_since.to_offset(time::UtcOffset::UTC);
+            // Users who have never logged in are deliberately counted as inactive.
+            query.push(" AND (last_login_at IS NULL OR last_login_at < ");
+            query.push_bind(PrimitiveDateTime::new(cutoff.date(), cutoff.time()));
+            query.push(")");
+        }
+        query.push(" ORDER BY email, id");
+        if let Some(selected) = page {
+            db::push_page(&mut query, selected);
+        }
         Ok(query.build_query_as().fetch_all(db_client).await?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AppUser, AppUserInsert};
+    use crate::{
+        db::ConnectionPool,
+        utils::{address::Address, email::EmailAddress, phone::PhoneNumber},
+    };
+
+    /// Parse an email address known to be valid.
+    fn email(address: &str) -> EmailAddress {
+        EmailAddress::try_from(address).expect("Email should be valid")
+    }
+
+    /// Store a registered user, or a guest if `guest` is set, with an email.
+    async fn store_user(address: &str, guest: bool, db_conn: &ConnectionPool) -> AppUser {
+        let address_of_user = Address::new("1 High Street", None, "London", "SW1A 1AA", "GB")
+            .expect("Address should be valid");
+        let user = if guest {
+            AppUserInsert::new_guest(email(address), "Alice", "Smith", address_of_user)
+        } else {
+            AppUserInsert::new(email(address), "Alice", "Smith", address_of_user, None)
+        };
+        user.store(db_conn).await.expect("User should be stored")
+    }
+
+    /// Users are found by their email in any case.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn select_by_email_ignores_case(db_conn: ConnectionPool) {
+        let user = store_user("Alice@Example.com", false, &db_conn).await;
+        let found = AppUser::select_by_email(&email("alice@example.COM"), &db_conn)
+            .await
+            .expect("Select should succeed")
+            .expect("User should be found");
+        assert_eq!(found.id(), user.id());
+    }
+
+    /// Only the whole email matches, not part of it.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn select_by_email_matches_whole_email(db_conn: ConnectionPool) {
+        store_user("malice@example.com", false, &db_conn).await;
+        let found = AppUser::select_by_email(&email("alice@example.com"), &db_conn)
+            .await
+            .expect("Select should succeed");
+        assert!(found.is_none());
+    }
+
+    /// Guests sharing a registered user's email are never selected in their
+    /// place.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn select_by_email_skips_guests(db_conn: ConnectionPool) {
+        store_user("alice@example.com", true, &db_conn).await;
+        let found = AppUser::select_by_email(&email("alice@example.com"), &db_conn)
+            .await
+            .expect("Select should succeed");
+        assert!(found.is_none());
+        let user = store_user("ALICE@example.com", false, &db_conn).await;
+        store_user("alice@example.com", true, &db_conn).await;
+        let registered = AppUser::select_by_email(&email("alice@example.com"), &db_conn)
+            .await
+            .expect("Select should succeed")
+            .expect("User should be found");
+        assert_eq!(registered.id(), user.id());
+    }
+
+    /// The database rejects a second registered user with the same email in
+    /// any case, even if nothing checked for one first.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn duplicate_email_is_unique_violation(db_conn: ConnectionPool) {
+        store_user("alice@example.com", false, &db_conn).await;
+        let err = AppUserInsert::new(
+            email("ALICE@example.com"),
+            "Alice",
+            "Smith",
+            Address::new("1 High Street", None, "London", "SW1A 1AA", "GB")
+                .expect("Address should be valid"),
+            None,
+        )
+        .store(&db_conn)
+        .await
+        .err()
+        .expect("Duplicate should be rejected");
+        assert!(err.is_unique_violation());
+    }
+
+    /// Debug output of a user to be inserted redacts their PII.
+    #[test]
+    fn insert_debug_output_is_redacted() {
+        let user = AppUserInsert::new(
+            email("alice.smith@example.com"),
+            "Alice",
+            "Smith",
+            Address::new("1 High Street", None, "London", "SW1A 1AA", "GB")
+                .expect("Address should be valid"),
+            Some(PhoneNumber::try_from("+447700900123").expect("Phone number should be valid")),
+        );
+        let debug = format!("{user:?}");
+        for pii in [
+            "alice.smith",
+            "Alice",
+            "Smith",
+            "High Street",
+            "SW1A",
+            "7700900123",
+        ] {
+            assert!(!debug.contains(pii), "{pii} in {debug}");
+        }
+        assert!(debug.contains("[REDACTED]"));
+    }
+}
@@ -0,0 +1,77 @@
+//! Models mapping to the `coupon` table. Represents a discount code which
+//! customers can apply to an order.
+use crate::db::{errors::DatabaseError, ConnectionPool, Executor};
+use sqlx::query_as;
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+/// A `Coupon` which is stored in the database. Can only be constructed by
+/// reading it from the database.
+pub struct Coupon {
+    /// The code customers enter to apply the coupon.
+    code: String,
+    /// The percentage taken off the total, from 1 to 100.
+    percent_off: i16,
+    /// The time (in UTC) after which the coupon can no longer be applied, if any.
+    expires_at: Option<PrimitiveDateTime>,
+    /// The number of times the coupon may be redeemed, if limited.
+    max_redemptions: Option<i32>,
+    /// The number of times the coupon has been redeemed.
+    redemptions: i32,
+}
+
+impl Coupon {
+    /// Select a coupon by its code.
+    pub async fn select(
+        code: &str,
+        db_client: &ConnectionPool,
+    ) -> Result<Option<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            "SELECT code, percent_off, expires_at, max_redemptions, redemptions
+            FROM coupon WHERE code = $1",
+            code
+        )
+        .fetch_optional(db_client)
+        .await?)
+    }
+    /// Redeem a coupon by its code, counting one more redemption, only if it
+    /// has not expired and has redemptions remaining. The check and the count
+    /// are a single statement, so concurrent orders cannot redeem the coupon
+    /// more times than it allows. Returns the redeemed coupon, or None if
+    /// nothing was redeemed.
+    pub async fn redeem<'c, E: Executor<'c>>(
+        code: &str,
+        db_client: E,
+    ) -> Result<Option<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            "UPDATE coupon SET redemptions = redemptions + 1
+            WHERE code = $1 AND (max_redemptions IS NULL OR redemptions < max_redemptions)
+            AND (expires_at IS NULL OR expires_at > (now() AT TIME ZONE 'UTC'))
+            RETURNING code, percent_off, expires_at, max_redemptions, redemptions",
+            code
+        )
+        .fetch_optional(db_client)
+        .await?)
+    }
+    /// The code customers enter to apply the coupon.
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+    /// The percentage taken off the total, from 1 to 100.
+    pub fn percent_off(&self) -> u8 {
+        u8::try_from(self.percent_off)
+            .expect("Database constraint on percent_off should prevent out of range values.")
+    }
+    /// Whether the coupon has passed its expiry time.
+    pub fn is_expired(&self) -> bool {
+        let now = OffsetDateTime::now_utc();
+        self.expires_at
+            .is_some_and(|expiry| expiry <= PrimitiveDateTime::new(now.date(), now.time()))
+    }
+    /// Whether the coupon has been redeemed as many times as it may be.
+    pub fn is_exhausted(&self) -> bool {
+        self.max_redemptions
+            .is_some_and(|max| self.redemptions >= max)
+    }
+}
@@ -1,8 +1,17 @@
 //! Defines data models (structs) which map directly to rows in the database.
 pub mod apporder;
 pub mod appuser;
+pub mod coupon;
+pub mod order_event;
 pub mod order_item;
+pub mod order_refund;
 pub mod password;
 pub mod product;
 pub mod product_image;
+pub mod product_stats;
+pub mod review;
+pub mod stock_adjustment;
+pub mod stock_notification;
+#[cfg(feature = "stripe")]
+pub mod stripe_event;
 pub mod totp;
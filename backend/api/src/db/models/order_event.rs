@@ -0,0 +1,82 @@
+//! Models for recording the status transitions of orders (the `order_event`
+//! table), which form each order's timeline.
+use super::apporder::{serialize_primitive_datetime, AppOrderStatus};
+use crate::db::{errors::DatabaseError, ConnectionPool, Executor};
+use serde::Serialize;
+use sqlx::{query, query_as};
+use time::PrimitiveDateTime;
+use uuid::Uuid;
+
+/// An INSERT model for an order event. Events are only ever recorded, never
+/// modified.
+pub struct OrderEventInsert {
+    /// The order which changed status.
+    pub order_id: Uuid,
+    /// The status the order changed to.
+    pub status: AppOrderStatus,
+    /// The user whose action caused the change, if any.
+    pub actor_id: Option<Uuid>,
+}
+
+impl OrderEventInsert {
+    /// Store this model as a record in the database.
+    pub async fn store<'c, E: Executor<'c>>(self, db_client: E) -> Result<(), DatabaseError> {
+        #[expect(
+            clippy::as_conversions,
+            reason = "As here is part of the query! macro, not an actual as cast"
+        )]
+        query!(
+            "INSERT INTO order_event (order_id, status, actor_id) VALUES ($1, $2, $3)",
+            self.order_id,
+            self.status as AppOrderStatus,
+            self.actor_id
+        )
+        .execute(db_client)
+        .await?;
+        Ok(())
+    }
+}
+
+/// A status transition of an order, as stored in the database.
+#[derive(Serialize)]
+pub struct OrderEvent {
+    /// The status the order changed to.
+    pub status: AppOrderStatus,
+    /// The user whose action caused the change, if any.
+    pub actor_id: Option<Uuid>,
+    /// When the change happened.
+    #[serde(serialize_with = "serialize_primitive_datetime")]
+    pub occurred_at: PrimitiveDateTime,
+}
+
+impl OrderEvent {
+    /// Attribute every event caused by one of the users `from` to the user `to`.
+    pub async fn reassign_actor<'c, E: Executor<'c>>(
+        from: &[Uuid],
+        to: Uuid,
+        db_client: E,
+    ) -> Result<(), DatabaseError> {
+        query!(
+            "UPDATE order_event SET actor_id = $1 WHERE actor_id = ANY($2)",
+            to,
+            from
+        )
+        .execute(db_client)
+        .await?;
+        Ok(())
+    }
+    /// Select every event of the order with the given ID, oldest first.
+    pub async fn select_for_order(
+        order_id: Uuid,
+        db_client: &ConnectionPool,
+    ) -> Result<Vec<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            r#"SELECT status AS "status!: AppOrderStatus", actor_id, occurred_at
+            FROM order_event WHERE order_id = $1 ORDER BY id"#,
+            order_id
+        )
+        .fetch_all(db_client)
+        .await?)
+    }
+}
@@ -1,8 +1,9 @@
 //! The database model for an item within an order. Corresponds to the `OrderItem` table.
-use sqlx::query_as;
+use sqlx::{query, query_as};
+use std::collections::HashMap;
 use uuid::Uuid;
 
-use crate::db::{errors::DatabaseError, ConnectionPool};
+use crate::db::{errors::DatabaseError, ConnectionPool, Executor};
 
 /// TODO: add documentation
 pub struct OrderItemInsert {
@@ -12,6 +13,10 @@ pub struct OrderItemInsert {
     order_id: Uuid,
     /// TODO: add documentation
     count: i64,
+    /// The product's name at the time the item was ordered.
+    product_name: String,
+    /// The product's price in pennies at the time the item was ordered.
+    unit_price: i64,
 }
 
 /// TODO: add documentation
@@ -22,25 +27,47 @@ pub struct OrderItem {
     order_id: Uuid,
     /// TODO: add documentation
     count: i64,
+    /// How many of this item have been fulfilled (shipped) so far.
+    fulfilled_count: i64,
+    /// The product's name at the time the item was ordered, kept so the
+    /// order still shows what was bought if the product is later renamed.
+    product_name: String,
+    /// The product's price in pennies at the time the item was ordered, kept
+    /// so the order's invoice still shows what was charged if the price
+    /// later changes.
+    unit_price: i64,
 }
 
 impl OrderItemInsert {
     /// TODO: add documentation
-    pub fn new(product_id: Uuid, order_id: Uuid, count: u32) -> Self {
+    pub fn new(
+        product_id: Uuid,
+        order_id: Uuid,
+        count: u32,
+        product_name: String,
+        unit_price: u32,
+    ) -> Self {
         Self {
             product_id,
             order_id,
             count: i64::from(count),
+            product_name,
+            unit_price: i64::from(unit_price),
         }
     }
     /// TODO: add documentation
-    pub async fn store(self, db_client: &ConnectionPool) -> Result<OrderItem, DatabaseError> {
+    pub async fn store<'c, E: Executor<'c>>(
+        self,
+        db_client: E,
+    ) -> Result<OrderItem, DatabaseError> {
         Ok(query_as!(
             OrderItem,
-            "INSERT INTO order_item (product_id, order_id, count) VALUES ($1, $2, $3) RETURNING *",
+            "INSERT INTO order_item (product_id, order_id, count, product_name, unit_price) VALUES ($1, $2, $3, $4, $5) RETURNING *",
             self.product_id,
             self.order_id,
-            self.count
+            self.count,
+            self.product_name,
+            self.unit_price
         )
         .fetch_one(db_client)
         .await?)
@@ -49,9 +76,9 @@ impl OrderItemInsert {
 
 impl OrderItem {
     /// TODO: add documentation
-    pub async fn select_all(
+    pub async fn select_all<'c, E: Executor<'c>>(
         order_id: Uuid,
-        db_client: &ConnectionPool,
+        db_client: E,
     ) -> Result<Vec<Self>, DatabaseError> {
         Ok(query_as!(
             Self,
@@ -61,6 +88,41 @@ impl OrderItem {
         .fetch_all(db_client)
         .await?)
     }
+    /// Count the total quantity of items in each of the given orders, in a
+    /// single query. Orders without any items are omitted.
+    pub async fn count_by_order(
+        order_ids: &[Uuid],
+        db_client: &ConnectionPool,
+    ) -> Result<HashMap<Uuid, i64>, DatabaseError> {
+        Ok(query!(
+            r#"SELECT order_id, SUM(count)::BIGINT AS "total!" FROM order_item
+            WHERE order_id = ANY($1) GROUP BY order_id"#,
+            order_ids
+        )
+        .fetch_all(db_client)
+        .await?
+        .into_iter()
+        .map(|row| (row.order_id, row.total))
+        .collect())
+    }
+    /// Sum the shipping weight in grams of every unit in an order, using each
+    /// product's current weight. Returns None if the order has no items, or if
+    /// any of its products has no weight set.
+    pub async fn total_weight_grams(
+        order_id: Uuid,
+        db_client: &ConnectionPool,
+    ) -> Result<Option<i64>, DatabaseError> {
+        Ok(query!(
+            "SELECT CASE WHEN bool_and(product.weight_grams IS NOT NULL)
+            THEN SUM(order_item.count * product.weight_grams)::BIGINT END AS total_weight_grams
+            FROM order_item JOIN product ON product.id = order_item.product_id
+            WHERE order_item.order_id = $1",
+            order_id
+        )
+        .fetch_one(db_client)
+        .await?
+        .total_weight_grams)
+    }
     /// TODO: add documentation
     pub const fn product_id(&self) -> Uuid {
         self.product_id
@@ -73,4 +135,65 @@ impl OrderItem {
     pub fn count(&self) -> u32 {
         u32::try_from(self.count).expect("Count in OrderItem exceeds u32 range.")
     }
+    /// Get the product's name as it was when the item was ordered.
+    pub fn product_name(&self) -> &str {
+        &self.product_name
+    }
+    /// Get the product's price in pennies as it was when the item was ordered.
+    pub fn unit_price(&self) -> u32 {
+        u32::try_from(self.unit_price).expect("Unit price in OrderItem exceeds u32 range.")
+    }
+    /// Get how many of this item have been fulfilled so far.
+    pub fn fulfilled_count(&self) -> u32 {
+        u32::try_from(self.fulfilled_count)
+            .expect("Fulfilled count in OrderItem exceeds u32 range.")
+    }
+    /// Whether every unit of this item has been fulfilled.
+    pub const fn is_fulfilled(&self) -> bool {
+        self.fulfilled_count >= self.count
+    }
+    /// Delete every item of the order with the given ID.
+    pub async fn delete_all<'c, E: Executor<'c>>(
+        order_id: Uuid,
+        db_client: E,
+    ) -> Result<(), DatabaseError> {
+        query!("DELETE FROM order_item WHERE order_id = $1", order_id)
+            .execute(db_client)
+            .await?;
+        Ok(())
+    }
+    /// Atomically mark `count` more units of the item for a product in an
+    /// order as fulfilled. Returns false, without changing anything, if the
+    /// item does not exist or fewer than `count` units are left unfulfilled.
+    pub async fn fulfil<'c, E: Executor<'c>>(
+        order_id: Uuid,
+        product_id: Uuid,
+        count: u32,
+        db_client: E,
+    ) -> Result<bool, DatabaseError> {
+        Ok(query!(
+            "UPDATE order_item SET fulfilled_count = fulfilled_count + $3
+            WHERE order_id = $1 AND product_id = $2 AND fulfilled_count + $3 <= count",
+            order_id,
+            product_id,
+            i64::from(count)
+        )
+        .execute(db_client)
+        .await?
+        .rows_affected()
+            == 1)
+    }
+    /// Mark every unit of every item in an order as fulfilled.
+    pub async fn fulfil_all<'c, E: Executor<'c>>(
+        order_id: Uuid,
+        db_client: E,
+    ) -> Result<(), DatabaseError> {
+        query!(
+            "UPDATE order_item SET fulfilled_count = count WHERE order_id = $1",
+            order_id
+        )
+        .execute(db_client)
+        .await?;
+        Ok(())
+    }
 }
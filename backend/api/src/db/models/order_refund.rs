@@ -0,0 +1,74 @@
+//! Models for recording refunds of orders (the `order_refund` table), which
+//! form an audit trail of money returned to customers.
+use crate::db::{errors::DatabaseError, Executor};
+use sqlx::{query, query_as};
+use uuid::Uuid;
+
+/// An INSERT model for a refund. Refunds are recorded as pending, before the
+/// payment is refunded, and are only modified to settle them.
+pub struct OrderRefundInsert {
+    /// The order which was refunded.
+    pub order_id: Uuid,
+    /// The administrator who issued the refund.
+    pub administrator_id: Uuid,
+    /// The amount in pennies which was refunded.
+    pub amount: i64,
+}
+
+/// A refund which has been recorded, but not yet settled, i.e. the payment
+/// may not have been refunded.
+pub struct PendingRefund {
+    /// The refund's ID primary key, also used as its idempotency key.
+    pub id: Uuid,
+    /// The amount in pennies to refund.
+    pub amount: i64,
+}
+
+impl OrderRefundInsert {
+    /// Store this model as a pending refund in the database, returning its ID.
+    pub async fn store<'c, E: Executor<'c>>(self, db_client: E) -> Result<Uuid, DatabaseError> {
+        Ok(query!(
+            "INSERT INTO order_refund (order_id, administrator_id, amount)
+            VALUES ($1, $2, $3) RETURNING id",
+            self.order_id,
+            self.administrator_id,
+            self.amount
+        )
+        .fetch_one(db_client)
+        .await?
+        .id)
+    }
+}
+
+/// Get every refund of an order which has not been settled, oldest first.
+pub async fn select_pending<'c, E: Executor<'c>>(
+    order_id: Uuid,
+    db_client: E,
+) -> Result<Vec<PendingRefund>, DatabaseError> {
+    Ok(query_as!(
+        PendingRefund,
+        "SELECT id, amount FROM order_refund WHERE order_id = $1 AND settled_at IS NULL
+        ORDER BY refunded_at",
+        order_id
+    )
+    .fetch_all(db_client)
+    .await?)
+}
+
+/// Mark a refund as settled, recording the ID of the refund in Stripe, if the
+/// payment was made through Stripe.
+pub async fn settle<'c, E: Executor<'c>>(
+    id: Uuid,
+    stripe_refund: Option<String>,
+    db_client: E,
+) -> Result<(), DatabaseError> {
+    query!(
+        "UPDATE order_refund SET settled_at = now() AT TIME ZONE 'utc', stripe_refund = $2
+        WHERE id = $1",
+        id,
+        stripe_refund
+    )
+    .execute(db_client)
+    .await?;
+    Ok(())
+}
@@ -1,6 +1,6 @@
 //! Models mapping to the password database table. Represents a password-based
 //! credential used by a user.
-use crate::db::{errors::DatabaseError, ConnectionPool};
+use crate::db::{errors::DatabaseError, ConnectionPool, Executor};
 use argon2::{
     password_hash::{
         rand_core::OsRng, PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString,
@@ -55,7 +55,7 @@ impl PasswordInsert {
         }
     }
     /// Store this INSERT model in the database and return a complete `Password` model.
-    pub async fn store(self, db_client: &ConnectionPool) -> Result<Password, DatabaseError> {
+    pub async fn store<'c, E: Executor<'c>>(self, db_client: E) -> Result<Password, DatabaseError> {
         Ok(query_as!(
             Password,
             "INSERT INTO password (user_id, password) VALUES ($1, $2) RETURNING *",
@@ -1,21 +1,51 @@
 //! Models mapping to the product database table. Represents a purchaseable
 //! product in the store.
-use crate::db::{errors::DatabaseError, ConnectionPool};
+use crate::{
+    constants::products::MIN_REVIEWS_FOR_RATING_RANK,
+    db::{
+        self,
+        errors::{DatabaseError, UpdateError},
+        ConnectionPool, Executor,
+    },
+    utils::{pagination::Pagination, text},
+};
+use futures_util::{Stream, TryStreamExt as _};
 use serde::{Deserialize, Serialize};
 use sqlx::{query, query_as, FromRow, QueryBuilder};
+use time::PrimitiveDateTime;
 use uuid::Uuid;
 
 /// INSERT model for a `product`. Used ONLY when adding a new product.
 #[derive(Deserialize)]
 pub struct ProductInsert {
     /// The name of the product.
+    #[serde(deserialize_with = "text::product_name")]
     pub name: String,
     /// A description of the product.
     pub description: String,
-    /// Whether the product is in stock (should be listed).
+    /// Whether the product is in stock (should be listed). Products are
+    /// created as unlisted drafts unless this is set.
+    #[serde(default)]
     listed: bool,
     /// The price of the product in pennies (GBP).
     price: i64,
+    /// The number of units of the product in stock when it is created. If
+    /// not set, the product's stock is unmanaged, and it can be ordered in
+    /// any quantity.
+    #[serde(default)]
+    stock: Option<u32>,
+    /// The shipping weight of the product in grams, if known.
+    #[serde(default)]
+    weight_grams: Option<u32>,
+    /// The packaged length of the product in millimetres, if known.
+    #[serde(default)]
+    length_mm: Option<u32>,
+    /// The packaged width of the product in millimetres, if known.
+    #[serde(default)]
+    width_mm: Option<u32>,
+    /// The packaged height of the product in millimetres, if known.
+    #[serde(default)]
+    height_mm: Option<u32>,
 }
 
 /// A `Product` which is stored in the database. Can only be constructed by
@@ -32,8 +62,29 @@ pub struct Product {
     listed: bool,
     /// The price of the product in pennies (GBP).
     price: i64,
-    /// A list of image paths associated with this product.
+    /// The number of units of the product currently in stock, or None if
+    /// the product's stock is unmanaged.
+    stock: Option<i64>,
+    /// Incremented on every update, to detect concurrent updates.
+    #[serde(skip)]
+    version: i64,
+    /// The shipping weight of the product in grams, if known.
+    weight_grams: Option<i64>,
+    /// The packaged length of the product in millimetres, if known.
+    length_mm: Option<i64>,
+    /// The packaged width of the product in millimetres, if known.
+    width_mm: Option<i64>,
+    /// The packaged height of the product in millimetres, if known.
+    height_mm: Option<i64>,
+    /// When the product was archived, if it has been. Archived products are
+    /// kept for the orders which refer to them, but are otherwise gone.
+    #[serde(skip)]
+    archived_at: Option<PrimitiveDateTime>,
+    /// A list of image paths associated with this product, in display order.
     pub images: Vec<String>,
+    /// The path of the product's primary image (the first in display order),
+    /// if it has any images.
+    pub primary_image: Option<String>,
 }
 
 impl ProductInsert {
@@ -44,18 +95,98 @@ impl ProductInsert {
             description: description.to_owned(),
             listed,
             price: i64::from(price),
+            stock: None,
+            weight_grams: None,
+            length_mm: None,
+            width_mm: None,
+            height_mm: None,
         }
     }
     /// Store this INSERT model in the database and return a complete `Product` model.
     pub async fn store(self, db_client: &ConnectionPool) -> Result<Product, DatabaseError> {
         Ok(query_as!(
             Product,
-            r#"INSERT INTO product (name, description, listed, price) VALUES ($1, $2, $3, $4) RETURNING id, name, description, listed, price, '{}'::text[] AS "images!""#,
-            self.name, self.description, self.listed, self.price
+            r#"INSERT INTO product (name, description, listed, price, weight_grams, length_mm, width_mm, height_mm, stock)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id, name, description, listed, price, stock, version,
+            weight_grams, length_mm, width_mm, height_mm, archived_at,
+            '{}'::text[] AS "images!", NULL::text AS primary_image"#,
+            self.name, self.description, self.listed, self.price,
+            self.weight_grams.map(i64::from), self.length_mm.map(i64::from),
+            self.width_mm.map(i64::from), self.height_mm.map(i64::from), self.stock.map(i64::from)
         ).fetch_one(db_client).await?)
     }
 }
 
+/// A `Product` returned by a search, along with the aggregate of its reviews.
+#[derive(Serialize, FromRow)]
+pub struct RatedProduct {
+    /// The product itself.
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub product: Product,
+    /// The mean rating of the product's reviews, if it has any.
+    average_rating: Option<f64>,
+    /// The number of reviews of the product.
+    review_count: i64,
+    /// The average rating in thousandths of a star (0 if there are no
+    /// reviews), which is exact, so can be compared against a cursor.
+    #[serde(skip)]
+    rating_key: i64,
+}
+
+/// How the results of a product search are ordered.
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum ProductSortOrder {
+    /// Alphabetically by name.
+    #[default]
+    Name,
+    /// Highest average rating first. Products with fewer than
+    /// `MIN_REVIEWS_FOR_RATING_RANK` reviews come after those with more, and
+    /// products with no reviews come last. Ties are broken by the number of
+    /// reviews, then by name.
+    RatingDesc,
+}
+
+/// The position of a product within search results, which are ordered by name
+/// then ID (after rating and review count if sorting by rating). A search given
+/// a cursor continues from after that position, so the cursor must be from a
+/// search with the same order.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProductCursor {
+    /// The name of the last product returned.
+    name: String,
+    /// The ID of the last product returned.
+    id: Uuid,
+    /// The `rating_key` of the last product returned.
+    #[serde(default)]
+    rating_key: i64,
+    /// The number of reviews of the last product returned.
+    #[serde(default)]
+    review_count: i64,
+}
+
+impl From<&RatedProduct> for ProductCursor {
+    #[inline]
+    fn from(rated: &RatedProduct) -> Self {
+        Self {
+            name: rated.product.name.clone(),
+            id: rated.product.id,
+            rating_key: rated.rating_key,
+            review_count: rated.review_count,
+        }
+    }
+}
+
+/// The SQL for the position of a product when sorting by rating, as a tuple
+/// ordered ascending. See `ProductSortOrder::RatingDesc`.
+fn rating_position() -> String {
+    format!(
+        "(COALESCE(ratings.review_count, 0) < {MIN_REVIEWS_FOR_RATING_RANK},
+        -COALESCE(ratings.rating_key, 0), -COALESCE(ratings.review_count, 0), name, id)"
+    )
+}
+
 #[derive(Default)]
 pub struct ProductSearchParameters {
     /// The name to search for. Will match any product starting with this.
@@ -66,6 +197,11 @@ pub struct ProductSearchParameters {
     pub price_max: Option<u32>,
     /// Whether the products are listed.
     pub listed: Option<bool>,
+    /// Only return products after this position. If set, the page's offset is
+    /// ignored.
+    pub after: Option<ProductCursor>,
+    /// The order to return products in.
+    pub sort_by: ProductSortOrder,
 }
 
 impl Product {
@@ -76,8 +212,10 @@ impl Product {
     ) -> Result<Option<Self>, DatabaseError> {
         Ok(query_as!(
             Self,
-            r#"SELECT id, name, description, listed, price,
-                array_remove(array_agg(path), NULL) AS "images!"
+            r#"SELECT id, name, description, listed, price, stock, version,
+                weight_grams, length_mm, width_mm, height_mm, archived_at,
+                array_remove(array_agg(path ORDER BY ordinal, path), NULL) AS "images!",
+                (array_remove(array_agg(path ORDER BY ordinal, path), NULL))[1] AS primary_image
                 FROM product LEFT JOIN product_image ON product.id = product_image.product_id
                 WHERE id = $1 GROUP BY id"#,
             id
@@ -85,32 +223,80 @@ impl Product {
         .fetch_optional(db_client)
         .await?)
     }
+    /// Select every `Product` whose ID is in `ids`, in no particular order.
+    /// IDs which do not match a product are ignored.
+    pub async fn select_many(
+        ids: &[Uuid],
+        db_client: &ConnectionPool,
+    ) -> Result<Vec<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            r#"SELECT id, name, description, listed, price, stock, version,
+                weight_grams, length_mm, width_mm, height_mm, archived_at,
+                array_remove(array_agg(path ORDER BY ordinal, path), NULL) AS "images!",
+                (array_remove(array_agg(path ORDER BY ordinal, path), NULL))[1] AS primary_image
+                FROM product LEFT JOIN product_image ON product.id = product_image.product_id
+                WHERE id = ANY($1) GROUP BY id"#,
+            ids
+        )
+        .fetch_all(db_client)
+        .await?)
+    }
     /// Retrieve all `Product`s stored in the database.
     pub async fn select_all(db_client: &ConnectionPool) -> Result<Vec<Self>, DatabaseError> {
         Ok(query_as!(
             Self,
-            r#"SELECT id, name, description, listed, price,
-                array_remove(array_agg(path), NULL) AS "images!"
+            r#"SELECT id, name, description, listed, price, stock, version,
+                weight_grams, length_mm, width_mm, height_mm, archived_at,
+                array_remove(array_agg(path ORDER BY ordinal, path), NULL) AS "images!",
+                (array_remove(array_agg(path ORDER BY ordinal, path), NULL))[1] AS primary_image
                 FROM product LEFT JOIN product_image ON product.id = product_image.product_id
                 GROUP BY id"#
         )
         .fetch_all(db_client)
         .await?)
     }
+    /// Stream every `Product` stored in the database, ordered by name, without
+    /// fetching them all into memory at once.
+    pub fn stream_all(
+        db_client: &ConnectionPool,
+    ) -> impl Stream<Item = Result<Self, DatabaseError>> + Send + '_ {
+        query_as!(
+            Self,
+            r#"SELECT id, name, description, listed, price, stock, version,
+                weight_grams, length_mm, width_mm, height_mm, archived_at,
+                array_remove(array_agg(path ORDER BY ordinal, path), NULL) AS "images!",
+                (array_remove(array_agg(path ORDER BY ordinal, path), NULL))[1] AS primary_image
+                FROM product LEFT JOIN product_image ON product.id = product_image.product_id
+                GROUP BY id ORDER BY name, id"#
+        )
+        .fetch(db_client)
+        .map_err(DatabaseError::from)
+    }
 
     /// Return all `Product`s matching a given set of search parameters (see
-    /// `ProductSearchParameters`). If all parameters are None, this is the
-    /// same as calling `select_all`.
+    /// `ProductSearchParameters`), with their ratings, in the order given by
+    /// `sort_by`, limited to `page` if set.
     pub async fn search(
         params: ProductSearchParameters,
+        page: Option<Pagination>,
         db_client: &ConnectionPool,
-    ) -> Result<Vec<Self>, DatabaseError> {
-        // 1=1 is used to make adding additional criteria simpler, since they will always
-        // use AND.
+    ) -> Result<Vec<RatedProduct>, DatabaseError> {
+        // Archived products are never searched for, and additional criteria
+        // are each added with AND.
         let mut query = QueryBuilder::new(
-            r#"SELECT id, name, description, listed, price,
-            array_remove(array_agg(path), NULL) AS "images"
-            FROM product LEFT JOIN product_image ON product.id = product_image.product_id WHERE 1=1"#,
+            r#"SELECT id, name, description, listed, price, stock, version,
+            weight_grams, length_mm, width_mm, height_mm, archived_at,
+            array_remove(array_agg(path ORDER BY ordinal, path), NULL) AS "images",
+            (array_remove(array_agg(path ORDER BY ordinal, path), NULL))[1] AS primary_image,
+            ratings.average_rating, COALESCE(ratings.review_count, 0) AS review_count,
+            COALESCE(ratings.rating_key, 0) AS rating_key
+            FROM product LEFT JOIN product_image ON product.id = product_image.product_id
+            LEFT JOIN (
+                SELECT product_id, AVG(rating)::float8 AS average_rating,
+                ROUND(AVG(rating) * 1000)::bigint AS rating_key, COUNT(*) AS review_count
+                FROM review GROUP BY product_id
+            ) AS ratings ON ratings.product_id = product.id WHERE archived_at IS NULL"#,
         );
         if let Some(ref name) = params.name {
             query.push(" AND name LIKE ");
@@ -137,9 +323,70 @@ impl Product {
             query.push(" AND listed = ");
             query.push_bind(listed);
         }
-        query.push(" GROUP BY id");
+        if let Some(ref after) = params.after {
+            match params.sort_by {
+                ProductSortOrder::Name => {
+                    query.push(" AND (name, id) > (");
+                }
+                ProductSortOrder::RatingDesc => {
+                    query.push(format!(" AND {} > (", rating_position()));
+                    query.push_bind(after.review_count < MIN_REVIEWS_FOR_RATING_RANK);
+                    query.push(", ");
+                    query.push_bind(after.rating_key.saturating_neg());
+                    query.push(", ");
+                    query.push_bind(after.review_count.saturating_neg());
+                    query.push(", ");
+                }
+            }
+            query.push_bind(after.name.clone());
+            query.push(", ");
+            query.push_bind(after.id);
+            query.push(")");
+        }
+        query
+            .push(" GROUP BY id, ratings.average_rating, ratings.rating_key, ratings.review_count");
+        match params.sort_by {
+            ProductSortOrder::Name => query.push(" ORDER BY name, id"),
+            ProductSortOrder::RatingDesc => query.push(format!(" ORDER BY {}", rating_position())),
+        };
+        if let Some(selected) = page {
+            if params.after.is_some() {
+                db::push_limit(&mut query, selected);
+            } else {
+                db::push_page(&mut query, selected);
+            }
+        }
         Ok(query.build_query_as().fetch_all(db_client).await?)
     }
+    /// Select a page of the listed products which a customer has purchased
+    /// in a fulfilled order but not yet reviewed, ordered by name.
+    pub async fn select_reviewable(
+        user_id: Uuid,
+        page: Pagination,
+        db_client: &ConnectionPool,
+    ) -> Result<Vec<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            r#"SELECT id, name, description, listed, price, stock, version,
+                weight_grams, length_mm, width_mm, height_mm, archived_at,
+                array_remove(array_agg(path ORDER BY ordinal, path), NULL) AS "images!",
+                (array_remove(array_agg(path ORDER BY ordinal, path), NULL))[1] AS primary_image
+                FROM product LEFT JOIN product_image ON product.id = product_image.product_id
+                WHERE listed AND id IN (
+                    SELECT order_item.product_id FROM order_item
+                    JOIN apporder ON apporder.id = order_item.order_id
+                    WHERE apporder.user_id = $1 AND apporder.status = 'Fulfilled'
+                ) AND NOT EXISTS (
+                    SELECT 1 FROM review WHERE review.user_id = $1 AND review.product_id = product.id
+                )
+                GROUP BY id ORDER BY name, id LIMIT $2 OFFSET $3"#,
+            user_id,
+            i64::from(page.limit),
+            i64::from(page.offset)
+        )
+        .fetch_all(db_client)
+        .await?)
+    }
     /// Set this product as listed.
     pub const fn list(&mut self) {
         self.listed = true;
@@ -152,6 +399,22 @@ impl Product {
     pub fn set_price(&mut self, price: u32) {
         self.price = i64::from(price);
     }
+    /// Set the product's shipping weight in grams.
+    pub fn set_weight_grams(&mut self, weight_grams: u32) {
+        self.weight_grams = Some(i64::from(weight_grams));
+    }
+    /// Set the product's packaged length in millimetres.
+    pub fn set_length_mm(&mut self, length_mm: u32) {
+        self.length_mm = Some(i64::from(length_mm));
+    }
+    /// Set the product's packaged width in millimetres.
+    pub fn set_width_mm(&mut self, width_mm: u32) {
+        self.width_mm = Some(i64::from(width_mm));
+    }
+    /// Set the product's packaged height in millimetres.
+    pub fn set_height_mm(&mut self, height_mm: u32) {
+        self.height_mm = Some(i64::from(height_mm));
+    }
     /// Set the product's description.
     pub fn set_description(&mut self, description: &str) {
         description.clone_into(&mut self.description);
@@ -160,27 +423,137 @@ impl Product {
     pub const fn is_listed(&self) -> bool {
         self.listed
     }
-    /// Get the price of this product in pennies (GBP).
-    pub fn price(&self) -> u32 {
-        u32::try_from(self.price).expect("Price value in database is out of allowed range")
+    /// Get whether this product has been archived.
+    pub const fn is_archived(&self) -> bool {
+        self.archived_at.is_some()
+    }
+    /// Get the number of units of this product currently in stock, or None if
+    /// the product's stock is unmanaged.
+    pub const fn stock(&self) -> Option<i64> {
+        self.stock
+    }
+    /// Get whether `count` units of this product are in stock. Always true if
+    /// the product's stock is unmanaged.
+    pub fn has_stock(&self, count: u32) -> bool {
+        self.stock.is_none_or(|stock| stock >= i64::from(count))
+    }
+    /// Get the shipping weight of this product in grams, if known.
+    pub const fn weight_grams(&self) -> Option<i64> {
+        self.weight_grams
+    }
+    /// Get the price of this product in pennies (GBP). Fails with a decode
+    /// error if the stored price is out of range (i.e. negative or too large),
+    /// which can only happen if the database was modified by something else.
+    pub fn try_price(&self) -> Result<u32, DatabaseError> {
+        u32::try_from(self.price)
+            .map_err(|err| DatabaseError::from(sqlx::Error::Decode(Box::new(err))))
     }
     /// Get this product's ID primary key.
     pub const fn id(&self) -> Uuid {
         self.id
     }
-    /// Update the corresponding database record to match this model's state.
-    pub async fn update(&self, db_client: &ConnectionPool) -> Result<(), DatabaseError> {
+    /// Atomically add `delta` (which may be negative) to the stock level of the
+    /// product with the given ID, returning the new stock level. An unmanaged
+    /// stock level is treated as 0, and becomes managed. Returns None, without
+    /// changing anything, if the product does not exist or the stock level
+    /// would become negative.
+    pub async fn adjust_stock<'c, E: Executor<'c>>(
+        id: Uuid,
+        delta: i64,
+        db_client: E,
+    ) -> Result<Option<i64>, DatabaseError> {
+        Ok(query!(
+            r#"UPDATE product SET stock = COALESCE(stock, 0) + $1
+            WHERE id = $2 AND COALESCE(stock, 0) + $1 >= 0 RETURNING stock AS "stock!""#,
+            delta,
+            id
+        )
+        .fetch_optional(db_client)
+        .await?
+        .map(|record| record.stock))
+    }
+    /// Atomically take `count` units of the product with the given ID out of
+    /// stock. Returns false, without changing anything, if the product does
+    /// not exist or fewer than `count` units are in stock. Unmanaged stock is
+    /// never exhausted, so is left unchanged.
+    pub async fn take_stock<'c, E: Executor<'c>>(
+        id: Uuid,
+        count: u32,
+        db_client: E,
+    ) -> Result<bool, DatabaseError> {
         Ok(query!(
-            "UPDATE product SET name = $1, description = $2, listed = $3, price = $4 WHERE id = $5",
+            "UPDATE product SET stock = stock - $1 WHERE id = $2 AND (stock IS NULL OR stock >= $1)",
+            i64::from(count),
+            id
+        )
+        .execute(db_client)
+        .await?
+        .rows_affected()
+            == 1)
+    }
+    /// Put `count` units of the product with the given ID back in stock, e.g.
+    /// after they were taken out of stock by an order which was refunded.
+    /// Unmanaged stock is left unchanged.
+    pub async fn return_stock<'c, E: Executor<'c>>(
+        id: Uuid,
+        count: u32,
+        db_client: E,
+    ) -> Result<(), DatabaseError> {
+        query!(
+            "UPDATE product SET stock = stock + $1 WHERE id = $2",
+            i64::from(count),
+            id
+        )
+        .execute(db_client)
+        .await?;
+        Ok(())
+    }
+    /// Update the corresponding database record to match this model's state.
+    /// Fails with `ConcurrencyConflict` if the record was updated since this
+    /// model was read, in which case nothing is written.
+    pub async fn update(&mut self, db_client: &ConnectionPool) -> Result<(), UpdateError> {
+        let updated = query!(
+            "UPDATE product SET name = $1, description = $2, listed = $3, price = $4,
+            weight_grams = $5, length_mm = $6, width_mm = $7, height_mm = $8,
+            version = version + 1 WHERE id = $9 AND version = $10",
             self.name,
             self.description,
             self.listed,
             self.price,
-            self.id
+            self.weight_grams,
+            self.length_mm,
+            self.width_mm,
+            self.height_mm,
+            self.id,
+            self.version
         )
         .execute(db_client)
         .await
-        .map(|_| ())?)
+        .map_err(DatabaseError::from)?
+        .rows_affected();
+        if updated == 0 {
+            return Err(UpdateError::ConcurrencyConflict);
+        }
+        self.version = self.version.saturating_add(1);
+        Ok(())
+    }
+    /// Archive the product with the given ID at `now`, unlisting it. Returns
+    /// false if the product does not exist or is already archived.
+    pub async fn archive(
+        id: Uuid,
+        now: PrimitiveDateTime,
+        db_client: &ConnectionPool,
+    ) -> Result<bool, DatabaseError> {
+        Ok(query!(
+            "UPDATE product SET archived_at = $1, listed = false, version = version + 1
+            WHERE id = $2 AND archived_at IS NULL",
+            now,
+            id
+        )
+        .execute(db_client)
+        .await?
+        .rows_affected()
+            > 0)
     }
     /// Delete the corresponding record from the database. Also consumes the
     /// model for the sake of consistency.
@@ -196,3 +569,68 @@ impl Product {
         name.clone_into(&mut self.name);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Product, ProductInsert};
+    use crate::db::{errors::UpdateError, ConnectionPool};
+
+    /// Of two concurrent updates to a product, the second to be written
+    /// detects the conflict rather than overwriting the first.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn concurrent_update_conflicts(db_conn: ConnectionPool) {
+        let id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(&db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        let select = || async {
+            Product::select_one(id, &db_conn)
+                .await
+                .expect("Product should be selected")
+                .expect("Product should exist")
+        };
+        let mut first = select().await;
+        let mut second = select().await;
+        first.name = "Gadget".to_owned();
+        first
+            .update(&db_conn)
+            .await
+            .expect("First update should succeed");
+        second.price = 2000;
+        assert!(matches!(
+            second.update(&db_conn).await,
+            Err(UpdateError::ConcurrencyConflict)
+        ));
+        let product = select().await;
+        assert_eq!(product.name, "Gadget");
+        assert_eq!(product.price, 1000);
+    }
+
+    /// A stored price which doesn't fit the API's price type is reported as
+    /// an error, rather than panicking.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn out_of_range_price_is_an_error(db_conn: ConnectionPool) {
+        let id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(&db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        sqlx::query("UPDATE product SET price = $1 WHERE id = $2")
+            .bind(i64::from(u32::MAX).saturating_add(1))
+            .bind(id)
+            .execute(&db_conn)
+            .await
+            .expect("Price should be updated");
+        let product = Product::select_one(id, &db_conn)
+            .await
+            .expect("Product should be selected")
+            .expect("Product should exist");
+        let err = product
+            .try_price()
+            .expect_err("Out of range price should be an error");
+        assert!(err.to_string().contains("out of range"));
+    }
+}
@@ -21,11 +21,14 @@ impl ProductImageInsert {
         }
     }
     /// Store this model as a record in the database, and return a full
-    /// ``ProductImage``.
+    /// ``ProductImage``. The image is placed after any existing images for
+    /// the product.
     pub async fn store(self, db_client: &ConnectionPool) -> Result<ProductImage, DatabaseError> {
         Ok(query_as!(
             ProductImage,
-            "INSERT INTO product_image (product_id, path) VALUES ($1, $2) RETURNING *",
+            "INSERT INTO product_image (product_id, path, ordinal)
+            VALUES ($1, $2, (SELECT COALESCE(MAX(ordinal) + 1, 0) FROM product_image WHERE product_id = $1))
+            RETURNING product_id, path",
             self.product_id,
             self.path
         )
@@ -53,7 +56,7 @@ impl ProductImage {
     ) -> Result<Option<Self>, DatabaseError> {
         Ok(query_as!(
             Self,
-            "SELECT * FROM product_image WHERE product_id = $1 AND path = $2",
+            "SELECT product_id, path FROM product_image WHERE product_id = $1 AND path = $2",
             product_id,
             path
         )
@@ -61,20 +64,56 @@ impl ProductImage {
         .await?)
     }
 
-    /// Retrieve all image paths associated with a given product.
+    /// Retrieve image paths associated with a given product in display order,
+    /// returning at most `limit` images if set.
     pub async fn select_all(
         product_id: Uuid,
+        limit: Option<u32>,
         db_client: &ConnectionPool,
     ) -> Result<Vec<Self>, DatabaseError> {
         Ok(query_as!(
             Self,
-            "SELECT * FROM product_image WHERE product_id = $1",
-            product_id
+            "SELECT product_id, path FROM product_image
+            WHERE product_id = $1 ORDER BY ordinal, path LIMIT $2",
+            product_id,
+            limit.map(i64::from)
         )
         .fetch_all(db_client)
         .await?)
     }
 
+    /// Count the images associated with a given product.
+    pub async fn count(product_id: Uuid, db_client: &ConnectionPool) -> Result<u32, DatabaseError> {
+        let count = query!(
+            r#"SELECT COUNT(*) AS "count!" FROM product_image WHERE product_id = $1"#,
+            product_id
+        )
+        .fetch_one(db_client)
+        .await?
+        .count;
+        Ok(u32::try_from(count).expect("Product image count exceeds u32 range."))
+    }
+
+    /// Set the display order of a product's images, where `paths` lists the
+    /// image paths in the desired order. The first path becomes ordinal 0,
+    /// i.e. the primary image. Paths not associated with the product are ignored.
+    pub async fn reorder(
+        product_id: Uuid,
+        paths: &[String],
+        db_client: &ConnectionPool,
+    ) -> Result<(), DatabaseError> {
+        Ok(query!(
+            "UPDATE product_image SET ordinal = new_order.position - 1
+            FROM unnest($2::text[]) WITH ORDINALITY AS new_order(path, position)
+            WHERE product_id = $1 AND product_image.path = new_order.path",
+            product_id,
+            paths
+        )
+        .execute(db_client)
+        .await
+        .map(|_| ())?)
+    }
+
     /// Delete the image from the associated product. DOES NOT delete the image from
     /// the media store, only the record in the database associating it with
     /// a given product.
@@ -89,3 +128,58 @@ impl ProductImage {
         .map(|_| ())?)
     }
 }
+
+/// A `product_image_size` record in the database, links a downsized variant
+/// of a product image to the original image's path.
+pub struct ProductImageSize {
+    /// The path of the original image within the media store.
+    pub path: String,
+    /// The width of this variant in pixels.
+    pub width: i32,
+    /// The path within the media store where this variant is stored.
+    pub size_path: String,
+}
+
+impl ProductImageSize {
+    /// Store the downsized variants of a product image, given as pairs of
+    /// width and storage path.
+    pub async fn store_all(
+        image: &ProductImage,
+        sizes: &[(u32, String)],
+        db_client: &ConnectionPool,
+    ) -> Result<(), DatabaseError> {
+        let (widths, size_paths): (Vec<i64>, Vec<String>) = sizes
+            .iter()
+            .map(|size| (i64::from(size.0), size.1.clone()))
+            .unzip();
+        Ok(query!(
+            "INSERT INTO product_image_size (product_id, path, width, size_path)
+            SELECT $1, $2, sizes.width, sizes.size_path
+            FROM unnest($3::bigint[], $4::text[]) AS sizes(width, size_path)
+            ON CONFLICT DO NOTHING",
+            image.product_id,
+            image.path,
+            &widths,
+            &size_paths
+        )
+        .execute(db_client)
+        .await
+        .map(|_| ())?)
+    }
+
+    /// Retrieve all downsized variants of all images associated with a given
+    /// product, ordered by width.
+    pub async fn select_all(
+        product_id: Uuid,
+        db_client: &ConnectionPool,
+    ) -> Result<Vec<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            "SELECT path, width, size_path FROM product_image_size
+            WHERE product_id = $1 ORDER BY width",
+            product_id
+        )
+        .fetch_all(db_client)
+        .await?)
+    }
+}
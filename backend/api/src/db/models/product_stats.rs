@@ -0,0 +1,79 @@
+//! Models mapping to the `product_stats` table, which holds aggregate
+//! statistics about each product for analytics.
+use crate::{
+    db::{errors::DatabaseError, ConnectionPool, Executor},
+    utils::pagination::Pagination,
+};
+use serde::Serialize;
+use sqlx::{query, query_as, FromRow};
+use uuid::Uuid;
+
+/// The number of times a product has been viewed.
+#[derive(Serialize, FromRow)]
+pub struct ProductViewCount {
+    /// The ID of the product.
+    pub product_id: Uuid,
+    /// The name of the product.
+    pub name: String,
+    /// The number of times the product has been viewed.
+    pub views: i64,
+}
+
+/// Add to the number of times a product has been viewed. Views of a product
+/// which has since been deleted are discarded.
+pub async fn add_views<'c, E: Executor<'c>>(
+    product_id: Uuid,
+    views: i64,
+    db_client: E,
+) -> Result<(), DatabaseError> {
+    query!(
+        "INSERT INTO product_stats (product_id, views) SELECT id, $2 FROM product WHERE id = $1
+        ON CONFLICT (product_id) DO UPDATE SET views = product_stats.views + EXCLUDED.views",
+        product_id,
+        views
+    )
+    .execute(db_client)
+    .await?;
+    Ok(())
+}
+
+/// Record that a batch of view counts has been added, so that it is never
+/// added again. Returns false if it already has been. Batches recorded more
+/// than 30 days ago are forgotten.
+pub async fn record_view_flush<'c, E: Executor<'c>>(
+    batch_id: Uuid,
+    db_client: E,
+) -> Result<bool, DatabaseError> {
+    Ok(query!(
+        "WITH pruned AS (
+            DELETE FROM product_view_flush
+            WHERE flushed_at < (now() AT TIME ZONE 'utc') - INTERVAL '30 days'
+        )
+        INSERT INTO product_view_flush (batch_id) VALUES ($1) ON CONFLICT (batch_id) DO NOTHING",
+        batch_id
+    )
+    .execute(db_client)
+    .await?
+    .rows_affected()
+        == 1)
+}
+
+impl ProductViewCount {
+    /// Select a page of products, most viewed first. Products which have never
+    /// been viewed are not included.
+    pub async fn select_top(
+        page: Pagination,
+        db_client: &ConnectionPool,
+    ) -> Result<Vec<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            "SELECT product.id AS product_id, name, views FROM product_stats
+            JOIN product ON product.id = product_stats.product_id
+            WHERE views > 0 ORDER BY views DESC, product.id LIMIT $1 OFFSET $2",
+            i64::from(page.limit),
+            i64::from(page.offset)
+        )
+        .fetch_all(db_client)
+        .await?)
+    }
+}
@@ -0,0 +1,99 @@
+//! Models mapping to the `review` table. Represents a customer's review of a
+//! product they have purchased.
+use super::apporder::serialize_primitive_datetime;
+use crate::{
+    db::{errors::DatabaseError, ConnectionPool},
+    utils::pagination::Pagination,
+};
+use serde::Serialize;
+use sqlx::{query, query_as, FromRow};
+use time::PrimitiveDateTime;
+use uuid::Uuid;
+
+/// INSERT model for a `Review`. Used ONLY when posting a new review.
+pub struct ReviewInsert {
+    /// The ID of the customer who wrote the review.
+    pub user_id: Uuid,
+    /// The ID of the product being reviewed.
+    pub product_id: Uuid,
+    /// The rating given to the product, from 1 to 5.
+    pub rating: i16,
+    /// The text of the review.
+    pub body: String,
+}
+
+/// A `Review` which is stored in the database. Can only be constructed by
+/// reading it from the database.
+#[derive(Serialize, FromRow)]
+pub struct Review {
+    /// The ID of the customer who wrote the review.
+    user_id: Uuid,
+    /// The ID of the product being reviewed.
+    product_id: Uuid,
+    /// The rating given to the product, from 1 to 5.
+    pub rating: i16,
+    /// The text of the review.
+    pub body: String,
+    /// The time and date the review was posted.
+    #[serde(serialize_with = "serialize_primitive_datetime")]
+    pub created_at: PrimitiveDateTime,
+}
+
+impl ReviewInsert {
+    /// Store this INSERT model in the database and return a complete `Review`
+    /// model, or None if the customer has already reviewed the product.
+    pub async fn store(self, db_client: &ConnectionPool) -> Result<Option<Review>, DatabaseError> {
+        Ok(query_as!(
+            Review,
+            "INSERT INTO review (user_id, product_id, rating, body) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, product_id) DO NOTHING
+            RETURNING user_id, product_id, rating, body, created_at",
+            self.user_id,
+            self.product_id,
+            self.rating,
+            self.body
+        )
+        .fetch_optional(db_client)
+        .await?)
+    }
+}
+
+impl Review {
+    /// Select a page of the reviews of a product, most recent first.
+    pub async fn select_for_product(
+        product_id: Uuid,
+        page: Pagination,
+        db_client: &ConnectionPool,
+    ) -> Result<Vec<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            "SELECT user_id, product_id, rating, body, created_at FROM review
+            WHERE product_id = $1 ORDER BY created_at DESC, user_id LIMIT $2 OFFSET $3",
+            product_id,
+            i64::from(page.limit),
+            i64::from(page.offset)
+        )
+        .fetch_all(db_client)
+        .await?)
+    }
+    /// Whether a customer has purchased a product in an order which has been
+    /// fulfilled, and so may review it.
+    pub async fn is_eligible(
+        user_id: Uuid,
+        product_id: Uuid,
+        db_client: &ConnectionPool,
+    ) -> Result<bool, DatabaseError> {
+        Ok(query!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM order_item JOIN apporder ON apporder.id = order_item.order_id
+                WHERE apporder.user_id = $1 AND order_item.product_id = $2
+                AND apporder.status = 'Fulfilled'
+            ) AS "eligible!""#,
+            user_id,
+            product_id
+        )
+        .fetch_one(db_client)
+        .await?
+        .eligible)
+    }
+}
@@ -0,0 +1,35 @@
+//! Models for recording adjustments to product stock levels (the
+//! `stock_adjustment` table), which form an audit trail of inventory changes.
+use crate::db::{errors::DatabaseError, Executor};
+use sqlx::query;
+use uuid::Uuid;
+
+/// An INSERT model for a stock adjustment. Adjustments are only ever recorded,
+/// never modified.
+pub struct StockAdjustmentInsert {
+    /// The product whose stock was adjusted.
+    pub product_id: Uuid,
+    /// The administrator who made the adjustment.
+    pub administrator_id: Uuid,
+    /// The change in stock level, negative for a reduction.
+    pub delta: i64,
+    /// The stock level after the adjustment was applied.
+    pub resulting_stock: i64,
+}
+
+impl StockAdjustmentInsert {
+    /// Store this model as a record in the database.
+    pub async fn store<'c, E: Executor<'c>>(self, db_client: E) -> Result<(), DatabaseError> {
+        query!(
+            "INSERT INTO stock_adjustment (product_id, administrator_id, delta, resulting_stock)
+            VALUES ($1, $2, $3, $4)",
+            self.product_id,
+            self.administrator_id,
+            self.delta,
+            self.resulting_stock
+        )
+        .execute(db_client)
+        .await?;
+        Ok(())
+    }
+}
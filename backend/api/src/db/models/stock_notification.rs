@@ -0,0 +1,65 @@
+//! Models mapping to the `stock_notification` table. Represents a customer's
+//! request to be emailed when an out of stock product is back in stock.
+use crate::{
+    db::{errors::DatabaseError, ConnectionPool, Executor},
+    utils::email::EmailAddress,
+};
+use sqlx::{query, query_as};
+use uuid::Uuid;
+
+/// INSERT model for a request to be notified when a product is back in stock.
+pub struct StockNotificationInsert {
+    /// The ID of the customer asking to be notified.
+    pub user_id: Uuid,
+    /// The ID of the out of stock product.
+    pub product_id: Uuid,
+}
+
+/// A notification due to a customer now that a product they asked about is
+/// back in stock.
+pub struct StockNotification {
+    /// The email address of the customer to notify.
+    pub email: EmailAddress,
+    /// The name of the product which is back in stock.
+    pub product_name: String,
+}
+
+impl StockNotificationInsert {
+    /// Store this model as a record in the database. Returns false, without
+    /// changing anything, if the customer has already asked to be notified
+    /// about the product.
+    pub async fn store(self, db_client: &ConnectionPool) -> Result<bool, DatabaseError> {
+        Ok(query!(
+            "INSERT INTO stock_notification (user_id, product_id) VALUES ($1, $2)
+            ON CONFLICT (user_id, product_id) DO NOTHING",
+            self.user_id,
+            self.product_id
+        )
+        .execute(db_client)
+        .await?
+        .rows_affected()
+            == 1)
+    }
+}
+
+/// Delete every request to be notified about a product, returning the
+/// notifications due for them. Requests from deleted users are removed without
+/// being returned.
+pub async fn take_for_product<'c, E: Executor<'c>>(
+    product_id: Uuid,
+    db_client: E,
+) -> Result<Vec<StockNotification>, DatabaseError> {
+    Ok(query_as!(
+        StockNotification,
+        r#"WITH taken AS (
+            DELETE FROM stock_notification WHERE product_id = $1 RETURNING user_id, product_id
+        )
+        SELECT appuser.email AS "email: _", product.name AS product_name FROM taken
+        JOIN appuser ON appuser.id = taken.user_id
+        JOIN product ON product.id = taken.product_id
+        WHERE appuser.deleted_at IS NULL"#,
+        product_id
+    )
+    .fetch_all(db_client)
+    .await?)
+}
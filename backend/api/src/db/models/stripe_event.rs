@@ -0,0 +1,142 @@
+//! Models mapping to the `stripe_event` table, an inbox of verified Stripe
+//! webhook events. Events are stored as soon as they are received, and
+//! processed separately, so that none are lost if processing fails.
+use crate::db::{errors::DatabaseError, ConnectionPool};
+use sqlx::{query, query_as};
+use uuid::Uuid;
+
+/// INSERT model for a `StripeEvent`. Used ONLY when receiving a new event.
+pub struct StripeEventInsert {
+    /// The ID Stripe assigned to the event.
+    pub id: String,
+    /// The ID of the order which the event confirms payment for.
+    pub order_id: Uuid,
+    /// The amount in pennies which the payment received.
+    pub amount: i64,
+    /// The ID of the `PaymentIntent` the payment was made through.
+    pub payment_intent: String,
+}
+
+/// INSERT model for a verified event which can never be processed (e.g.
+/// because its metadata is malformed), kept for investigation.
+pub struct StripeRejectedEventInsert {
+    /// The ID Stripe assigned to the event.
+    pub id: String,
+    /// The type of the event, e.g. `payment_intent.succeeded`.
+    pub event_type: String,
+    /// Why the event could not be processed.
+    pub reason: String,
+}
+
+/// A received `StripeEvent` which is stored in the database. Can only be
+/// constructed by reading it from the database.
+pub struct StripeEvent {
+    /// The ID Stripe assigned to the event.
+    id: String,
+    /// The ID of the order which the event confirms payment for.
+    order_id: Uuid,
+    /// The amount in pennies which the payment received, if it was recorded.
+    amount: Option<i64>,
+}
+
+impl StripeEventInsert {
+    /// Store this event in the database. Returns false without modifying
+    /// anything if the event was already stored, since Stripe may deliver the
+    /// same event more than once.
+    pub async fn store(self, db_client: &ConnectionPool) -> Result<bool, DatabaseError> {
+        Ok(query!(
+            "INSERT INTO stripe_event (id, order_id, amount, payment_intent) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (id) DO NOTHING",
+            self.id,
+            self.order_id,
+            self.amount,
+            self.payment_intent
+        )
+        .execute(db_client)
+        .await?
+        .rows_affected()
+            > 0)
+    }
+}
+
+impl StripeRejectedEventInsert {
+    /// Store this rejected event in the database. Redeliveries of an event
+    /// which was already rejected are ignored.
+    pub async fn store(self, db_client: &ConnectionPool) -> Result<(), DatabaseError> {
+        query!(
+            "INSERT INTO stripe_rejected_event (id, event_type, reason) VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO NOTHING",
+            self.id,
+            self.event_type,
+            self.reason
+        )
+        .execute(db_client)
+        .await?;
+        Ok(())
+    }
+}
+
+impl StripeEvent {
+    /// Select the ID of the `PaymentIntent` through which an order was most
+    /// recently paid, if any payment for it was recorded with one.
+    pub async fn select_payment_intent(
+        order_id: Uuid,
+        db_client: &ConnectionPool,
+    ) -> Result<Option<String>, DatabaseError> {
+        Ok(query!(
+            r#"SELECT payment_intent AS "payment_intent!" FROM stripe_event
+            WHERE order_id = $1 AND payment_intent IS NOT NULL
+            ORDER BY received_at DESC LIMIT 1"#,
+            order_id
+        )
+        .fetch_optional(db_client)
+        .await?
+        .map(|event| event.payment_intent))
+    }
+    /// Select all events which have not yet been successfully processed,
+    /// oldest first.
+    pub async fn select_unprocessed(
+        db_client: &ConnectionPool,
+    ) -> Result<Vec<Self>, DatabaseError> {
+        Ok(query_as!(
+            Self,
+            "SELECT id, order_id, amount FROM stripe_event WHERE processed_at IS NULL ORDER BY received_at"
+        )
+        .fetch_all(db_client)
+        .await?)
+    }
+    /// Mark this event as processed, so that it is never processed again.
+    pub async fn mark_processed(self, db_client: &ConnectionPool) -> Result<(), DatabaseError> {
+        query!(
+            "UPDATE stripe_event SET processed_at = (now() AT TIME ZONE 'utc') WHERE id = $1",
+            self.id
+        )
+        .execute(db_client)
+        .await?;
+        Ok(())
+    }
+    /// Record a failed attempt to process this event. It remains unprocessed,
+    /// and will be retried.
+    pub async fn record_failure(&self, db_client: &ConnectionPool) -> Result<(), DatabaseError> {
+        query!(
+            "UPDATE stripe_event SET attempts = attempts + 1 WHERE id = $1",
+            self.id
+        )
+        .execute(db_client)
+        .await?;
+        Ok(())
+    }
+    /// The ID Stripe assigned to the event.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    /// The ID of the order which the event confirms payment for.
+    pub const fn order_id(&self) -> Uuid {
+        self.order_id
+    }
+    /// The amount in pennies which the payment received. None for events
+    /// received before amounts were recorded.
+    pub const fn amount(&self) -> Option<i64> {
+        self.amount
+    }
+}
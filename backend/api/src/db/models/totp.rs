@@ -1,18 +1,76 @@
 //! Models mapping to the totp database table. Represents a Time-Based
 //! One-Time-Password secret used by the user.
 use crate::{
-    constants::db::DB_ENCRYPTION_KEY,
+    constants::{
+        db::DB_ENCRYPTION_KEY,
+        totp::{TOTP_ALGORITHM, TOTP_DIGITS, TOTP_STEP},
+    },
     db::{errors::DatabaseError, ConnectionPool},
 };
 use sqlx::{query, query_as};
+use totp_rs::{TotpUrlError, TOTP};
 use uuid::Uuid;
 
+/// The HMAC algorithm a TOTP secret generates codes with.
+#[derive(sqlx::Type, Clone, Copy)]
+#[sqlx(type_name = "totp_algorithm")]
+pub enum TotpAlgorithm {
+    /// HMAC-SHA1, the RFC 6238 default.
+    Sha1,
+    /// HMAC-SHA256.
+    Sha256,
+    /// HMAC-SHA512.
+    Sha512,
+}
+
+impl From<TotpAlgorithm> for totp_rs::Algorithm {
+    #[inline]
+    fn from(algorithm: TotpAlgorithm) -> Self {
+        match algorithm {
+            TotpAlgorithm::Sha1 => Self::SHA1,
+            TotpAlgorithm::Sha256 => Self::SHA256,
+            TotpAlgorithm::Sha512 => Self::SHA512,
+        }
+    }
+}
+
+/// Construct a TOTP validator for a secret with the given parameters.
+fn build_totp(
+    secret: Vec<u8>,
+    algorithm: TotpAlgorithm,
+    digits: i16,
+    step: i32,
+) -> Result<TOTP, TotpUrlError> {
+    TOTP::new(
+        algorithm.into(),
+        usize::try_from(digits).map_err(|_err| TotpUrlError::Digits(digits.to_string()))?,
+        1,
+        u64::try_from(step).map_err(|_err| TotpUrlError::Step(step.to_string()))?,
+        secret,
+        Some(String::new()),
+        String::new(),
+    )
+}
+
+/// Construct a TOTP validator for a secret with the configured parameters
+/// (`TOTP_ALGORITHM`, `TOTP_DIGITS` and `TOTP_STEP`), which every newly
+/// enrolled secret uses.
+pub fn configured_totp(secret: Vec<u8>) -> Result<TOTP, TotpUrlError> {
+    build_totp(secret, *TOTP_ALGORITHM, *TOTP_DIGITS, *TOTP_STEP)
+}
+
 /// INSERT model for a `Totp`. Used ONLY when adding a new secret.
 pub struct TotpInsert {
     /// The ID of the user who uses this credential.
     pub user_id: Uuid,
     /// The raw TOTP secret bytes.
     pub secret: Vec<u8>,
+    /// The HMAC algorithm codes are generated with.
+    pub algorithm: TotpAlgorithm,
+    /// The number of digits in each code.
+    pub digits: i16,
+    /// The number of seconds each code is valid for.
+    pub step: i32,
 }
 
 /// A `Totp` secret which is stored in the database. Can only be constructed
@@ -22,29 +80,56 @@ pub struct Totp {
     user_id: Uuid,
     /// The raw TOTP secret bytes.
     secret: Vec<u8>,
+    /// The HMAC algorithm codes are generated with.
+    algorithm: TotpAlgorithm,
+    /// The number of digits in each code.
+    digits: i16,
+    /// The number of seconds each code is valid for.
+    step: i32,
 }
 
 impl TotpInsert {
+    /// Construct a new `Totp` INSERT model using the configured parameters.
+    pub fn new(user_id: Uuid, secret: Vec<u8>) -> Self {
+        Self {
+            user_id,
+            secret,
+            algorithm: *TOTP_ALGORITHM,
+            digits: *TOTP_DIGITS,
+            step: *TOTP_STEP,
+        }
+    }
+
     /// Store this INSERT model in the database and return a complete `Totp` model.
     pub async fn store(&self, db_client: &ConnectionPool) -> Result<Totp, DatabaseError> {
+        #[expect(clippy::as_conversions, reason = "As here is part of the query! macro")]
         Ok(query_as!(
             Totp,
-            "INSERT INTO totp (user_id, secret) VALUES ($1, pgp_sym_encrypt_bytea($2, $3)) RETURNING *",
+            r#"INSERT INTO totp (user_id, secret, algorithm, digits, step)
+            VALUES ($1, pgp_sym_encrypt_bytea($2, $3), $4, $5, $6)
+            RETURNING user_id, secret, algorithm AS "algorithm!: TotpAlgorithm", digits, step"#,
             self.user_id,
             self.secret,
-            *DB_ENCRYPTION_KEY
+            *DB_ENCRYPTION_KEY,
+            &self.algorithm as &TotpAlgorithm,
+            self.digits,
+            self.step
         )
         .fetch_one(db_client)
         .await?)
     }
 
-    /// TODO: add documentation
+    /// Construct a TOTP validator for this secret, failing if the secret is
+    /// invalid (e.g. too short).
+    pub fn totp(&self) -> Result<TOTP, TotpUrlError> {
+        build_totp(self.secret.clone(), self.algorithm, self.digits, self.step)
+    }
+
+    /// Validate that a TOTP code is correct.
     pub fn validate(&self, code: &str) -> bool {
-        let totp = totp_rs::TOTP::from_rfc6238(
-            totp_rs::Rfc6238::with_defaults(self.secret.clone())
-                .expect("Non-Rfc6238-compliant secret in TOTP validation"),
-        )
-        .expect("Invalid URL in TOTP validation");
+        let totp = self
+            .totp()
+            .expect("Invalid TOTP secret or parameters in TOTP validation");
         totp.check_current(code)
             .expect("System time error while validating Totp code")
     }
@@ -57,7 +142,9 @@ impl Totp {
     ) -> Result<Option<Self>, DatabaseError> {
         Ok(query_as!(
             Self,
-            r#"SELECT user_id, pgp_sym_decrypt_bytea(secret, $2) AS "secret!" FROM totp WHERE user_id = $1"#,
+            r#"SELECT user_id, pgp_sym_decrypt_bytea(secret, $2) AS "secret!",
+            algorithm AS "algorithm!: TotpAlgorithm", digits, step
+            FROM totp WHERE user_id = $1"#,
             user_id,
             *DB_ENCRYPTION_KEY
         )
@@ -73,14 +160,70 @@ impl Totp {
             .map(|_| ())?)
     }
 
-    /// Validate that a TOTP code is correct.
+    /// Validate that a TOTP code is correct, using the parameters the secret
+    /// was enrolled with.
     pub fn validate(&self, code: &str) -> bool {
-        let totp = totp_rs::TOTP::from_rfc6238(
-            totp_rs::Rfc6238::with_defaults(self.secret.clone())
-                .expect("Non-Rfc6238-compliant secret in TOTP validation"),
-        )
-        .expect("Invalid URL in TOTP validation");
+        let totp = build_totp(self.secret.clone(), self.algorithm, self.digits, self.step)
+            .expect("Invalid TOTP secret or parameters in TOTP validation");
         totp.check_current(code)
             .expect("System time error while validating Totp code")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{Totp, TotpAlgorithm, TotpInsert};
+    use crate::{db::ConnectionPool, testing::store_user};
+
+    /// An 8-digit SHA-256 secret for a user.
+    fn sha256_insert(user_id: Uuid) -> TotpInsert {
+        TotpInsert {
+            user_id,
+            secret: vec![7; 32],
+            algorithm: TotpAlgorithm::Sha256,
+            digits: 8,
+            step: 30,
+        }
+    }
+
+    /// A code generated for a secret with non-default parameters is validated
+    /// with those parameters, rather than the RFC 6238 defaults.
+    #[test]
+    fn sha256_eight_digit_code_is_validated() {
+        let insert = sha256_insert(Uuid::new_v4());
+        let code = insert
+            .totp()
+            .expect("TOTP should be valid")
+            .generate_current()
+            .expect("Code should be generated");
+        assert_eq!(code.len(), 8);
+        assert!(insert.validate(&code));
+        let sha1 = TotpInsert {
+            algorithm: TotpAlgorithm::Sha1,
+            ..sha256_insert(insert.user_id)
+        };
+        assert!(!sha1.validate(&code));
+    }
+
+    /// A stored secret keeps the parameters it was enrolled with, so its codes
+    /// are still validated if the configured parameters differ.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn stored_secret_keeps_parameters(db_conn: ConnectionPool) {
+        let user = store_user("alice@example.com", &db_conn).await;
+        let insert = sha256_insert(user.id());
+        insert.store(&db_conn).await.expect("TOTP should be stored");
+        let code = insert
+            .totp()
+            .expect("TOTP should be valid")
+            .generate_current()
+            .expect("Code should be generated");
+        let stored = Totp::select(user.id(), &db_conn)
+            .await
+            .expect("TOTP should be selected")
+            .expect("User should have TOTP");
+        assert!(stored.validate(&code));
+    }
+}
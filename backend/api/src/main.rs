@@ -1,69 +1,182 @@
 //! This crate implements the backend API for the `SecureCart` ecommerce platform.
 
+extern crate alloc;
+
 mod constants;
 mod db;
 mod middleware;
 mod routes;
 mod services;
 mod state;
+#[cfg(test)]
+mod testing;
 mod utils;
 
-#[expect(
-    clippy::useless_attribute,
-    reason = "Lint is enabled only in clippy::restrictions"
-)]
-#[expect(
-    clippy::std_instead_of_alloc,
-    reason = "Does not work outside of no_std"
-)]
-use std::sync::Arc;
+use alloc::sync::Arc;
+use std::{env::args, io::stderr, pin::pin};
 
-use axum::{extract::Json, routing::get};
-use object_store::aws::AmazonS3Builder;
-use tokio::net::TcpListener;
+use axum::{extract::Json, middleware::from_fn, routing::get, Router};
+use futures_util::future::select;
+use object_store::{
+    aws::{AmazonS3, AmazonS3Builder},
+    path::Path,
+    ObjectStore as _,
+};
+use tokio::{
+    net::TcpListener,
+    signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    },
+};
+use utils::retry::connect_with_backoff;
 
 #[tokio::main]
 async fn main() {
-    let s3 = AmazonS3Builder::new()
-        .with_endpoint(format!(
-            "http://{}:{}",
-            &*constants::s3::S3_HOST,
-            &*constants::s3::S3_PORT
-        ))
-        .with_bucket_name(&*constants::s3::S3_BUCKET)
-        .with_access_key_id(&*constants::s3::S3_ACCESS_KEY)
-        .with_secret_access_key(&*constants::s3::S3_SECRET_KEY)
-        .with_allow_http(true)
-        .build()
-        .expect("Could not connect to S3-compatible object storage");
-    println!("CONNECTED TO S3: {s3}");
-    let db_conn = db::connect()
+    init_logging();
+    // Fail immediately on any missing or invalid configuration, rather than
+    // when a setting is first used.
+    constants::config::Config::init().expect("Invalid configuration");
+    let s3 = connect_media_store().await;
+    let db_conn = connect_with_backoff("primary database", db::connect)
         .await
         .expect("Could not connect to primary database");
-    let session_store_conn = services::sessions::store::Connection::connect()
+    if *constants::db::RUN_MIGRATIONS {
+        let applied = db::migrate(&db_conn)
+            .await
+            .expect("Failed to migrate database");
+        for migration in &applied {
+            tracing::info!(
+                "APPLIED MIGRATION: {} {}",
+                migration.version,
+                migration.description
+            );
+        }
+        tracing::info!("DATABASE SCHEMA UP TO DATE ({} applied)", applied.len());
+    }
+    let read_db_conn = connect_with_backoff("read replica database", || db::connect_read(&db_conn))
         .await
-        .expect("Could not connect to session store");
+        .expect("Could not connect to read replica database");
+    let session_store_conn = connect_with_backoff(
+        "session store",
+        services::sessions::store::Connection::connect,
+    )
+    .await
+    .expect("Could not connect to session store");
     let state = state::AppState {
         db: db_conn,
+        read_db: read_db_conn,
         session_store: session_store_conn,
         media_store: Arc::new(s3),
+        sms_sender: utils::sms::configured_sender(),
+        email_sender: utils::mailer::configured_sender(),
     };
-    let app = axum::Router::new()
+    if args().any(|arg| arg == "--seed") {
+        if *constants::api::ALLOW_SEED {
+            services::seed::seed(&state.db, Arc::clone(&state.media_store))
+                .await
+                .expect("Failed to seed database");
+        } else {
+            tracing::warn!(
+                "--seed was passed, but ALLOW_SEED is not set. Refusing to seed database."
+            );
+        }
+    }
+    #[cfg(feature = "stripe")]
+    tokio::spawn(services::stripe_events::run_processor(state.db.clone()));
+    tokio::spawn(services::products::run_view_flusher(
+        state.db.clone(),
+        state.session_conn(),
+    ));
+    tokio::spawn(services::sessions::run_reconciler(state.session_conn()));
+    tokio::spawn(services::users::run_user_purger(state.db.clone()));
+    let shutdown_db_conn = state.db.clone();
+    let mut shutdown_session_store_conn = state.session_conn();
+    let app = create_app(state);
+    let listener = TcpListener::bind("0.0.0.0:80")
+        .await
+        .expect("Failed to bind listener");
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .expect("Failed to init Axum service");
+    // Views counted since the last periodic flush would otherwise be left in
+    // the session store until the API is next started.
+    if let Err(err) =
+        services::products::flush_views(&shutdown_db_conn, &mut shutdown_session_store_conn).await
+    {
+        tracing::error!("Error flushing product view counts on shutdown: {err}");
+    }
+}
+
+/// Build the API's router, with every route and the middleware applied to all
+/// of them.
+fn create_app(state: state::AppState) -> Router {
+    Router::new()
         .route("/", get(root))
         .nest("/auth", routes::auth::create_router(&state))
+        .nest("/capabilities", routes::capabilities::create_router())
         .nest("/registration", routes::registration::create_router(&state))
         .nest("/products", routes::products::create_router(&state))
         .nest("/orders", routes::orders::create_router(&state))
         .nest("/webhook", routes::webhook::create_router(&state))
         .nest("/checkout", routes::checkout::create_router(&state))
+        .nest("/coupons", routes::coupons::create_router(&state))
         .nest("/users", routes::users::create_router(&state))
-        .with_state(state);
-    let listener = TcpListener::bind("0.0.0.0:80")
-        .await
-        .expect("Failed to bind listener");
-    axum::serve(listener, app)
-        .await
-        .expect("Failed to init Axum service");
+        .layer(from_fn(middleware::request_id::request_id_middleware))
+        .with_state(state)
+}
+
+/// Connect to the S3-compatible object storage media is kept in, waiting for
+/// it to become available.
+async fn connect_media_store() -> AmazonS3 {
+    let s3 = AmazonS3Builder::new()
+        .with_endpoint(format!(
+            "http://{}:{}",
+            &*constants::s3::S3_HOST,
+            &*constants::s3::S3_PORT
+        ))
+        .with_bucket_name(&*constants::s3::S3_BUCKET)
+        .with_access_key_id(&*constants::s3::S3_ACCESS_KEY)
+        .with_secret_access_key(&*constants::s3::S3_SECRET_KEY)
+        .with_allow_http(true)
+        .build()
+        .expect("Invalid S3-compatible object storage configuration");
+    // Building the client doesn't connect, so probe the store to wait for it
+    // to be available. A missing object shows the store is reachable.
+    connect_with_backoff("object storage", || async {
+        match s3.head(&Path::from("healthcheck")).await {
+            Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(err) => Err(err),
+        }
+    })
+    .await
+    .expect("Could not connect to S3-compatible object storage");
+    tracing::info!("CONNECTED TO S3: {s3}");
+    s3
+}
+
+/// Log to stderr, with each line tagged with the spans it was logged in, e.g.
+/// the ID of the request being handled.
+fn init_logging() {
+    tracing_subscriber::fmt()
+        .with_writer(stderr)
+        .with_target(false)
+        .init();
+}
+
+/// Resolves once the API is asked to stop, by SIGTERM (as sent by container
+/// runtimes) or Ctrl+C, so that in-flight requests can finish first.
+async fn shutdown_signal() {
+    let terminate = async {
+        signal(SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    let interrupt = async { ctrl_c().await.expect("Failed to listen for Ctrl+C") };
+    select(pin!(terminate), pin!(interrupt)).await;
+    tracing::info!("SHUTTING DOWN");
 }
 
 /// The / route is simply used as an availability check.
@@ -0,0 +1,28 @@
+//! Middleware hiding the routes of optional features which are unavailable in
+//! the runtime configuration.
+use crate::{state::AppState, utils::httperror::HttpError};
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+
+/// Middleware responding 404 to requests for routes which depend on sending
+/// email, as though the routes did not exist, when no email provider is
+/// configured. Otherwise the emails would be silently discarded.
+pub async fn require_email(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, HttpError> {
+    if !state.email_sender.is_configured() {
+        tracing::warn!(
+            "{} {} requires email, but no email provider is configured, rejected",
+            req.method(),
+            req.uri().path()
+        );
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    Ok(next.run(req).await)
+}
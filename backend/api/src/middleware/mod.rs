@@ -1,2 +1,4 @@
 //! Tower middleware used for performing pre/post handler functionality.
+pub mod features;
+pub mod request_id;
 pub mod session;
@@ -0,0 +1,132 @@
+//! Middleware which tags every request with an ID, so that a failure seen by a
+//! client can be correlated with the server's logs.
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{info_span, Instrument as _, Span};
+use uuid::Uuid;
+
+/// The header used to carry the request ID, both inbound and outbound.
+static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// The longest client-provided request ID which will be accepted.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    /// The ID of the request currently being handled by this task.
+    static REQUEST_ID: String;
+}
+
+/// Get the ID of the request currently being handled, if called from within
+/// a handler wrapped by `request_id_middleware`.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Whether a client-provided request ID is safe to adopt. Restricted to a
+/// short run of printable, non-space ASCII so it cannot be used to forge log
+/// lines or headers.
+fn is_acceptable_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// The span in which a request is handled, so that every line logged while
+/// handling it carries its ID.
+fn request_span(request_id: &str) -> Span {
+    info_span!("request", id = %request_id)
+}
+
+/// Middleware which adopts the request's `X-Request-Id` header (or generates a
+/// new ID if it is missing or unacceptable), makes it available to handlers via
+/// `current_request_id` and in every log line, and echoes it in the response's
+/// `X-Request-Id` header.
+pub async fn request_id_middleware(req: Request, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_acceptable_request_id(id))
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_owned);
+    let span = request_span(&request_id);
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(req))
+        .instrument(span.clone())
+        .await;
+    if response.status().is_server_error() || response.status().is_client_error() {
+        span.in_scope(|| tracing::warn!("{method} {uri} failed with {}", response.status()));
+    }
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER.clone(), value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::request_span;
+    use alloc::sync::Arc;
+    use std::{
+        io::{Error, Result, Write},
+        sync::Mutex,
+    };
+    use tracing::subscriber::with_default;
+
+    /// A log writer which appends to a shared buffer, so that what was logged
+    /// can be read back.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    #[expect(
+        clippy::missing_trait_methods,
+        reason = "The provided methods all write through write"
+    )]
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0
+                .lock()
+                .map_err(|_poisoned| Error::other("Log buffer was poisoned"))?
+                .extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Every line logged while handling a request carries its ID.
+    #[test]
+    fn logs_carry_request_id() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_target(false)
+            .finish();
+        with_default(subscriber, || {
+            request_span("abc-123").in_scope(|| {
+                tracing::warn!("Something was rejected");
+                tracing::error!("Something failed");
+            });
+        });
+        let logs = String::from_utf8(
+            captured
+                .0
+                .lock()
+                .expect("Log buffer should not be poisoned")
+                .clone(),
+        )
+        .expect("Logs should be UTF-8");
+        assert_eq!(logs.lines().count(), 2);
+        assert!(logs
+            .lines()
+            .all(|line| line.contains("request{id=abc-123}")));
+    }
+}
@@ -1,7 +1,24 @@
 //! Middleware used for checking user authentication/authorisation.
+//!
+//! Timing threat model: an attacker able to measure response times should not
+//! be able to distinguish a non-existent session token from an existing one, or
+//! learn how much of a guessed CSRF token is correct. To that end, the store
+//! performs the same reads whether or not a session exists (see
+//! `sessions::store`), a CSRF comparison is always performed (against an empty
+//! token if there is no session), and all comparisons are constant-time over
+//! digests of the tokens, so token length leaks nothing either. Error
+//! responses still differ by status code, which is not considered secret.
 use std::sync::LazyLock;
 
-use crate::{services::sessions::SessionTrait, state::AppState};
+use crate::{
+    constants::{
+        cookies::{CSRF_HEADER_NAME, SESSION_COOKIE_NAME},
+        sessions::CSRF_EXEMPT_METHODS,
+    },
+    services::sessions::{GenericAuthenticatedSession, SessionTrait},
+    state::AppState,
+    utils::httperror::HttpError,
+};
 use axum::{
     extract::{Request, State},
     http::StatusCode,
@@ -9,6 +26,8 @@ use axum::{
     response::Response,
 };
 use axum_extra::extract::CookieJar;
+use sha2::{Digest as _, Sha256};
+use subtle::ConstantTimeEq as _;
 
 /// The status code used for a CSRF failure. 419 is non-standard but
 ///  it's what Laravel does.
@@ -16,70 +35,373 @@ use axum_extra::extract::CookieJar;
 static STATUS_CODE_BAD_CSRF: LazyLock<StatusCode> =
     LazyLock::new(|| StatusCode::from_u16(419).unwrap());
 
+/// Compare two tokens in constant time with respect to both their contents
+/// and their lengths, by comparing fixed-length digests of each.
+fn tokens_match(provided: &str, expected: &str) -> bool {
+    Sha256::digest(provided)
+        .ct_eq(&Sha256::digest(expected))
+        .into()
+}
+
 /// Middleware to parse a session cookie and identify the associated user.
+/// Requests must also carry the session's CSRF token in the `CSRF_HEADER_NAME`
+/// header, unless their method is one of `CSRF_EXEMPT_METHODS`.
 pub async fn session_middleware<T: SessionTrait + 'static>(
     State(state): State<AppState>,
     cookie_jar: CookieJar,
     mut req: Request,
     next: Next,
-) -> Result<Response, StatusCode> {
+) -> Result<Response, HttpError> {
     let session_cookie = cookie_jar
-        .get("session")
+        .get(&SESSION_COOKIE_NAME)
         .ok_or(StatusCode::UNAUTHORIZED)?
         .value();
-    let session = T::get(session_cookie, &mut state.session_store.clone())
+    let maybe_session = T::get(session_cookie, &mut state.session_store.clone())
         .await
         .map_err(|err| {
-            eprintln!("Error loading session from store: {err}");
+            tracing::error!("Error loading session from store: {err}");
             StatusCode::INTERNAL_SERVER_ERROR
-        })?
-        .ok_or_else(|| {
-            eprintln!("Invalid session token.");
+        })?;
+    // `T::get` should only ever return the kinds of session `T` represents,
+    // but a route must never be given any other kind if it regresses.
+    if let Some(ref session) = maybe_session {
+        if !T::SESSION_KINDS.contains(&session.kind()) {
+            let accepted: Vec<&str> = T::SESSION_KINDS.iter().map(|kind| kind.name()).collect();
+            tracing::error!(
+                "Loaded a {} session for a route accepting only {}, rejecting it. This is a bug.",
+                session.kind().name(),
+                accepted.join(", ")
+            );
+            return Err(StatusCode::UNAUTHORIZED.into());
+        }
+    }
+    if CSRF_EXEMPT_METHODS.contains(req.method()) {
+        let session = maybe_session.ok_or_else(|| {
+            tracing::warn!("Invalid session token.");
             StatusCode::UNAUTHORIZED
         })?;
-    let csrf_token = req
+        req.extensions_mut().insert(session);
+        return Ok(next.run(req).await);
+    }
+    let maybe_csrf_token = req
         .headers()
-        .get("X-CSRF-Token")
+        .get(&*CSRF_HEADER_NAME)
+        .map(|header| header.to_str());
+    // Always compare, even without a session, so both cases take the same time.
+    let csrf_valid = tokens_match(
+        maybe_csrf_token
+            .as_ref()
+            .and_then(|token| token.as_ref().ok())
+            .copied()
+            .unwrap_or_default(),
+        &maybe_session
+            .as_ref()
+            .map(SessionTrait::csrf_token)
+            .unwrap_or_default(),
+    );
+    let session = maybe_session.ok_or_else(|| {
+        tracing::warn!("Invalid session token.");
+        StatusCode::UNAUTHORIZED
+    })?;
+    let provided_csrf_token = maybe_csrf_token
         .ok_or_else(|| {
-            eprintln!("Request is missing X-CSRF-Token");
+            tracing::warn!("Request is missing {}", *CSRF_HEADER_NAME);
             *STATUS_CODE_BAD_CSRF
         })?
-        .to_str()
         .map_err(|_err| {
-            eprintln!("CSRF token contains non-ASCII.");
+            tracing::warn!("CSRF token contains non-ASCII.");
             StatusCode::BAD_REQUEST
         })?;
-    if csrf_token != session.csrf_token() {
-        eprintln!("Incorrect X-CSRF-Token in request");
-        return Err(*STATUS_CODE_BAD_CSRF);
+    if !csrf_valid {
+        // A token from before the session was refreshed is most likely from
+        // a page loaded before then, rather than forged, so the client is told
+        // to reload instead of being given a generic failure.
+        if session
+            .previous_csrf_token()
+            .is_some_and(|previous| tokens_match(provided_csrf_token, &previous))
+        {
+            tracing::warn!(
+                "Stale {} in request, from before the session was refreshed",
+                *CSRF_HEADER_NAME
+            );
+            return Err(HttpError::new(
+                *STATUS_CODE_BAD_CSRF,
+                Some(String::from("CSRF token expired, please reload")),
+            ));
+        }
+        tracing::warn!("Incorrect {} in request", *CSRF_HEADER_NAME);
+        return Err(HttpError::new(
+            *STATUS_CODE_BAD_CSRF,
+            Some(String::from("Invalid CSRF token")),
+        ));
     }
     req.extensions_mut().insert(session);
     Ok(next.run(req).await)
 }
 
-/// Does the same thing as `session_middleware`, but skips the CSRF check. This
-/// potentially enables CSRF attacks against the endpoint in question, so
-/// ensure it does not have any dangerous effects.
-pub async fn session_middleware_no_csrf<T: SessionTrait + 'static>(
-    State(state): State<AppState>,
-    cookie_jar: CookieJar,
-    mut req: Request,
-    next: Next,
-) -> Result<Response, StatusCode> {
-    let session_cookie = cookie_jar
-        .get("session")
-        .ok_or(StatusCode::UNAUTHORIZED)?
-        .value();
-    let session = T::get(session_cookie, &mut state.session_store.clone())
-        .await
-        .map_err(|err| {
-            eprintln!("Error loading session from store: {err}");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?
-        .ok_or_else(|| {
-            eprintln!("Invalid session token.");
-            StatusCode::UNAUTHORIZED
-        })?;
-    req.extensions_mut().insert(session);
+/// Middleware rejecting requests made through a session an administrator is
+/// using to impersonate a customer, for routes which change credentials or
+/// contact details, revoke sessions or delete data. Must be layered inside
+/// `session_middleware`, so that the session has already been identified.
+pub async fn reject_impersonation(req: Request, next: Next) -> Result<Response, HttpError> {
+    if let Some(session) = req.extensions().get::<GenericAuthenticatedSession>() {
+        if let Some(administrator_id) = session.impersonated_by() {
+            tracing::warn!(
+                "Administrator {administrator_id} attempted {} {} while impersonating user {}, rejected",
+                req.method(),
+                req.uri().path(),
+                session.user_id()
+            );
+            return Err(HttpError::new(
+                StatusCode::FORBIDDEN,
+                Some(String::from("Not permitted while impersonating a user")),
+            ));
+        }
+    }
     Ok(next.run(req).await)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Method, Request, StatusCode},
+        middleware::from_fn_with_state,
+        routing::get,
+        Router,
+    };
+    use serde_json::Value;
+    use tower::ServiceExt as _;
+    use uuid::Uuid;
+
+    use super::session_middleware;
+    use crate::{
+        constants::{
+            cookies::{CSRF_HEADER_NAME, SESSION_COOKIE_NAME},
+            sessions::CSRF_EXEMPT_METHODS,
+        },
+        services::sessions::{
+            generate_token, CustomerSession, GenericAuthenticatedSession, PreAuthenticationSession,
+            SessionTrait as _,
+        },
+        testing::{TestApp, CLIENT_IP},
+    };
+
+    /// Create a customer session for a new user.
+    async fn customer_session(app: &TestApp) -> CustomerSession {
+        let mut session_store_conn = app.session_conn();
+        PreAuthenticationSession::create(
+            Uuid::new_v4(),
+            false,
+            false,
+            CLIENT_IP,
+            &mut session_store_conn,
+        )
+        .await
+        .expect("Session should be created")
+        .promote(&mut session_store_conn)
+        .await
+        .expect("Session should be promoted")
+    }
+
+    /// A router with a single route, accepting GETs and POSTs, guarded by
+    /// `session_middleware` for customer sessions.
+    fn customer_router(app: &TestApp) -> Router {
+        Router::new()
+            .route("/", get(|| async {}).post(|| async {}))
+            .layer(from_fn_with_state(
+                app.state.clone(),
+                session_middleware::<CustomerSession>,
+            ))
+            .with_state(app.state.clone())
+    }
+
+    /// A router like `customer_router`, guarded by `session_middleware` for
+    /// partially authenticated sessions instead.
+    fn pre_authentication_router(app: &TestApp) -> Router {
+        Router::new()
+            .route("/", get(|| async {}).post(|| async {}))
+            .layer(from_fn_with_state(
+                app.state.clone(),
+                session_middleware::<PreAuthenticationSession>,
+            ))
+            .with_state(app.state.clone())
+    }
+
+    /// Send a request to a router, with a session token and CSRF token if
+    /// given. Returns the response's status and error message, if any.
+    async fn send(
+        router: Router,
+        method: Method,
+        session_token: Option<&str>,
+        csrf_token: Option<&str>,
+    ) -> (StatusCode, Option<String>) {
+        let mut request = Request::builder().method(method).uri("/");
+        if let Some(token) = session_token {
+            request = request.header(header::COOKIE, format!("{}={token}", *SESSION_COOKIE_NAME));
+        }
+        if let Some(token) = csrf_token {
+            request = request.header(&*CSRF_HEADER_NAME, token);
+        }
+        let response = router
+            .oneshot(
+                request
+                    .body(Body::empty())
+                    .expect("Request should be valid"),
+            )
+            .await
+            .expect("Router should not fail");
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Response body should be readable");
+        let message = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|error| error.pointer("/message")?.as_str().map(str::to_owned));
+        (status, message)
+    }
+
+    /// Requests with an existing and a non-existent session token make the
+    /// same sequence of calls to the session store.
+    #[tokio::test]
+    async fn found_and_missing_sessions_make_same_store_calls() {
+        let app = TestApp::without_db();
+        let session = customer_session(&app).await;
+
+        let before_found = app.store.commands().len();
+        let (found_status, _) = send(
+            customer_router(&app),
+            Method::POST,
+            Some(&session.token()),
+            Some(&session.csrf_token()),
+        )
+        .await;
+        assert_eq!(found_status, StatusCode::OK);
+        let found = app.store.commands().split_off(before_found);
+
+        let before_missing = app.store.commands().len();
+        let (missing_status, _) = send(
+            customer_router(&app),
+            Method::POST,
+            Some(&generate_token()),
+            Some(&generate_token()),
+        )
+        .await;
+        assert_eq!(missing_status, StatusCode::UNAUTHORIZED);
+        let missing = app.store.commands().split_off(before_missing);
+
+        assert!(
+            !found.is_empty(),
+            "The session should be read from the store"
+        );
+        assert_eq!(found, missing);
+    }
+
+    /// After a session is refreshed, its CSRF token from before the refresh
+    /// is rejected as expired, and any other wrong token as invalid.
+    #[tokio::test]
+    async fn stale_csrf_token_is_distinguished_from_forged() {
+        let app = TestApp::without_db();
+        let session = customer_session(&app).await;
+        let stale_csrf = session.csrf_token();
+        let refreshed = GenericAuthenticatedSession::from(session)
+            .refresh(&mut app.session_conn())
+            .await
+            .expect("Session should be refreshed");
+        let token = refreshed.token();
+
+        let (stale_status, stale_message) = send(
+            customer_router(&app),
+            Method::POST,
+            Some(&token),
+            Some(&stale_csrf),
+        )
+        .await;
+        assert_eq!(stale_status.as_u16(), 419);
+        assert_eq!(
+            stale_message.as_deref(),
+            Some("CSRF token expired, please reload")
+        );
+
+        let (forged_status, forged_message) = send(
+            customer_router(&app),
+            Method::POST,
+            Some(&token),
+            Some(&generate_token()),
+        )
+        .await;
+        assert_eq!(forged_status.as_u16(), 419);
+        assert_eq!(forged_message.as_deref(), Some("Invalid CSRF token"));
+
+        let (status, _) = send(
+            customer_router(&app),
+            Method::POST,
+            Some(&token),
+            Some(&refreshed.csrf_token()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// The methods configured as exempt are let through without a CSRF token,
+    /// while others without one are rejected, even if safe.
+    #[tokio::test]
+    async fn only_exempt_methods_skip_csrf_token() {
+        let app = TestApp::without_db();
+        let session = customer_session(&app).await;
+        let token = session.token();
+        assert_eq!(*CSRF_EXEMPT_METHODS, [Method::GET, Method::HEAD]);
+        for method in [Method::GET, Method::HEAD] {
+            let (status, _) = send(customer_router(&app), method, Some(&token), None).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        for method in [Method::OPTIONS, Method::POST] {
+            let (status, _) = send(customer_router(&app), method, Some(&token), None).await;
+            assert_eq!(status.as_u16(), 419);
+        }
+    }
+
+    /// A partially authenticated session is rejected by routes for customers,
+    /// and a customer session by routes for partially authenticated sessions,
+    /// which do accept the partially authenticated one.
+    #[tokio::test]
+    async fn sessions_of_other_kinds_are_rejected() {
+        let app = TestApp::without_db();
+        let pre_authentication = PreAuthenticationSession::create(
+            Uuid::new_v4(),
+            false,
+            false,
+            CLIENT_IP,
+            &mut app.session_conn(),
+        )
+        .await
+        .expect("Session should be created");
+        let (status, _) = send(
+            customer_router(&app),
+            Method::POST,
+            Some(&pre_authentication.token()),
+            Some(&pre_authentication.csrf_token()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let customer = customer_session(&app).await;
+        let (customer_status, _) = send(
+            pre_authentication_router(&app),
+            Method::POST,
+            Some(&customer.token()),
+            Some(&customer.csrf_token()),
+        )
+        .await;
+        assert_eq!(customer_status, StatusCode::UNAUTHORIZED);
+
+        let (own_status, _) = send(
+            pre_authentication_router(&app),
+            Method::POST,
+            Some(&pre_authentication.token()),
+            Some(&pre_authentication.csrf_token()),
+        )
+        .await;
+        assert_eq!(own_status, StatusCode::OK);
+    }
+}
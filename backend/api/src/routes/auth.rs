@@ -1,63 +1,105 @@
 //! Routes under /auth handling authentication related mechanisms.
 use crate::{
-    middleware::session::{session_middleware, session_middleware_no_csrf},
+    constants::{
+        api::PUBLIC_URI,
+        cookies::{
+            CSRF_COOKIE_NAME, CSRF_HEADER_NAME, MAGIC_LINK_NONCE_COOKIE_NAME, SESSION_COOKIE_NAME,
+        },
+        sessions::REMEMBER_ME_SESSION_TIMEOUT,
+    },
+    middleware::{
+        features::require_email,
+        session::{reject_impersonation, session_middleware},
+    },
     services::{
         auth,
         sessions::{
-            self, AdministratorSession, CustomerSession, GenericAuthenticatedSession,
+            self, store, AdministratorSession, CustomerSession, GenericAuthenticatedSession,
             PreAuthenticationSession, SessionTrait as _,
         },
     },
-    state::AppState,
-    utils::{email::EmailAddress, httperror::HttpError},
+    state::{AppState, SessionConn},
+    utils::{
+        client_ip::client_ip,
+        cookies::{build_removal_cookie, build_session_cookie, remove_session_cookies},
+        email::EmailAddress,
+        httperror::HttpError,
+        json::ValidatedJson,
+        redact::RedactedEmail,
+    },
 };
 use axum::{
-    extract::{Extension, Json, State},
-    http::{HeaderMap, StatusCode},
-    middleware::from_fn_with_state,
+    extract::{Extension, Form, Json, Path, Query, State},
+    http::{
+        header::{CACHE_CONTROL, REFERRER_POLICY, RETRY_AFTER},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::{from_fn, from_fn_with_state},
+    response::{Html, Redirect},
     routing::{delete, get, post},
     Router,
 };
-use axum_extra::extract::{
-    cookie::{Cookie, SameSite},
-    CookieJar,
-};
+use axum_extra::extract::CookieJar;
 use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq as _;
+use time::Duration;
 
 /// Create a router for the /auth route.
 pub fn create_router(state: &AppState) -> Router<AppState> {
     let unauthenticated = Router::new()
         .route("/", get(list_methods))
-        .route("/", post(login));
+        .route("/", post(login))
+        .route("/csrf/config", get(get_csrf_config))
+        .route(
+            "/magic-link",
+            post(request_magic_link).layer(from_fn_with_state(state.clone(), require_email)),
+        )
+        .route(
+            "/magic-link/consume",
+            get(confirm_magic_link)
+                .post(consume_magic_link)
+                .layer(from_fn_with_state(state.clone(), require_email)),
+        );
     let authenticated = Router::new()
         .route("/", delete(logout))
+        .route("/refresh", post(refresh))
+        .route("/check", get(|| async {}))
+        .route("/csrf", get(get_csrf).post(rotate_csrf))
+        .route("/sessions", get(list_sessions))
         .layer(from_fn_with_state(
             state.clone(),
             session_middleware::<GenericAuthenticatedSession>,
         ));
-    let authenticated_no_csrf =
-        Router::new()
-            .route("/check", get(|| async {}))
-            .layer(from_fn_with_state(
-                state.clone(),
-                session_middleware_no_csrf::<GenericAuthenticatedSession>,
-            ));
-    let customer_authenticated_no_csrf = Router::new()
+    // An administrator impersonating a user must not be able to sign the user
+    // out of their own sessions.
+    let owner_authenticated = Router::new()
+        .route("/sessions/{session_ref}", delete(revoke_session))
+        .layer(from_fn(reject_impersonation))
+        .layer(from_fn_with_state(
+            state.clone(),
+            session_middleware::<GenericAuthenticatedSession>,
+        ));
+    let customer_authenticated = Router::new()
         .route("/check/customer", get(|| async {}))
         .layer(from_fn_with_state(
             state.clone(),
-            session_middleware_no_csrf::<CustomerSession>,
+            session_middleware::<CustomerSession>,
         ));
-    let admin_authenticated_no_csrf =
+    let admin_authenticated =
         Router::new()
             .route("/check/admin", get(|| async {}))
             .layer(from_fn_with_state(
                 state.clone(),
-                session_middleware_no_csrf::<AdministratorSession>,
+                session_middleware::<AdministratorSession>,
             ));
     let pre_authenticated = Router::new()
         .route("/2fa", get(get_mfa_methods))
         .route("/2fa", post(authenticate_2fa))
+        .route("/2fa/sms", post(send_sms_code))
+        .route(
+            "/2fa/email",
+            post(send_email_code).layer(from_fn_with_state(state.clone(), require_email)),
+        )
         .layer(from_fn_with_state(
             state.clone(),
             session_middleware::<PreAuthenticationSession>,
@@ -65,10 +107,10 @@ pub fn create_router(state: &AppState) -> Router<AppState> {
 
     unauthenticated
         .merge(pre_authenticated)
-        .merge(authenticated_no_csrf)
         .merge(authenticated)
-        .merge(customer_authenticated_no_csrf)
-        .merge(admin_authenticated_no_csrf)
+        .merge(owner_authenticated)
+        .merge(customer_authenticated)
+        .merge(admin_authenticated)
 }
 
 #[derive(Serialize)]
@@ -92,6 +134,9 @@ struct AuthenticateRequest {
     pub email: EmailAddress,
     /// The credential provided at login.
     pub credential: auth::PrimaryAuthenticationMethod,
+    /// Whether to create a longer-lived session. Ignored for administrators.
+    #[serde(default)]
+    pub remember_me: bool,
 }
 #[derive(Serialize)]
 /// A response to /auth/login
@@ -100,105 +145,516 @@ struct AuthenticateResponse {
     pub mfa_required: bool,
     /// Whether the session is administrative, None if MFA is required.
     pub is_admin: Option<bool>,
+    /// Whether the user is an administrator who must enrol TOTP before they
+    /// can be given an administrative session.
+    pub totp_enrolment_required: bool,
+}
+
+/// Add the session and CSRF cookies for a newly created session. If
+/// `remember_me` is set, the cookies persist across browser restarts for the
+/// lifetime of the session, rather than being discarded when the browser closes.
+fn add_session_cookies(
+    cookies: CookieJar,
+    token: String,
+    csrf: String,
+    remember_me: bool,
+) -> CookieJar {
+    let mut session_cookie = build_session_cookie(&SESSION_COOKIE_NAME, token, true);
+    let mut csrf_cookie = build_session_cookie(&CSRF_COOKIE_NAME, csrf, false);
+    if remember_me {
+        let max_age = Duration::seconds(i64::from(REMEMBER_ME_SESSION_TIMEOUT));
+        session_cookie.set_max_age(max_age);
+        csrf_cookie.set_max_age(max_age);
+    }
+    cookies.add(session_cookie).add(csrf_cookie)
+}
+
+/// Rotate the current session's token and CSRF token, invalidating the old ones.
+async fn refresh(
+    cookies: CookieJar,
+    SessionConn(mut session_store): SessionConn,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+) -> Result<CookieJar, HttpError> {
+    let user_id = session.user_id();
+    let new_session = session.refresh(&mut session_store).await?;
+    tracing::info!("Refreshed session token for user {user_id}");
+    Ok(add_session_cookies(
+        cookies,
+        new_session.token(),
+        new_session.csrf_token(),
+        new_session.remember_me(),
+    ))
+}
+
+#[derive(Serialize)]
+/// A response to /auth/csrf.
+struct CsrfResponse {
+    /// The session's CSRF token.
+    pub csrf_token: String,
+}
+
+#[derive(Serialize)]
+/// A response to GET /auth/csrf/config.
+struct CsrfConfigResponse {
+    /// The name of the cookie the CSRF token is set in.
+    pub cookie_name: String,
+    /// The name of the header the CSRF token must be echoed back in.
+    pub header_name: String,
+}
+
+/// Get the names of the CSRF cookie and header, which are configurable, so
+/// that the frontend can read and send the token without hard-coding them.
+/// Needs no session, since the frontend must send the token before logging in.
+async fn get_csrf_config() -> Json<CsrfConfigResponse> {
+    Json(CsrfConfigResponse {
+        cookie_name: CSRF_COOKIE_NAME.clone(),
+        header_name: CSRF_HEADER_NAME.to_string(),
+    })
+}
+
+/// Set a session's CSRF token in the CSRF cookie, and respond with it.
+fn csrf_response(
+    cookies: CookieJar,
+    session: &GenericAuthenticatedSession,
+    csrf_token: String,
+) -> (CookieJar, Json<CsrfResponse>) {
+    let mut csrf_cookie = build_session_cookie(&CSRF_COOKIE_NAME, csrf_token.clone(), false);
+    if session.remember_me() {
+        csrf_cookie.set_max_age(Duration::seconds(i64::from(REMEMBER_ME_SESSION_TIMEOUT)));
+    }
+    (cookies.add(csrf_cookie), Json(CsrfResponse { csrf_token }))
+}
+
+/// Get the current session's CSRF token, also setting it in the CSRF cookie.
+async fn get_csrf(
+    cookies: CookieJar,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+) -> (CookieJar, Json<CsrfResponse>) {
+    let csrf_token = session.csrf_token();
+    csrf_response(cookies, &session, csrf_token)
+}
+
+/// Replace the current session's CSRF token with a new one, setting it in the
+/// CSRF cookie. The session token and its expiry are left untouched. This
+/// changes the session, so unlike fetching the token, requires the current one.
+async fn rotate_csrf(
+    cookies: CookieJar,
+    SessionConn(mut session_store): SessionConn,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+) -> Result<(CookieJar, Json<CsrfResponse>), HttpError> {
+    let Some(csrf_token) = session.rotate_csrf(&mut session_store).await? else {
+        tracing::warn!("Session expired while rotating its CSRF token.");
+        return Err(HttpError::new(StatusCode::UNAUTHORIZED, None));
+    };
+    tracing::info!("Rotated CSRF token for user {}", session.user_id());
+    Ok(csrf_response(cookies, &session, csrf_token))
 }
 
 /// Logout the currently authenticated user.
 async fn logout(
     cookies: CookieJar,
-    State(state): State<AppState>,
+    SessionConn(mut session_store): SessionConn,
     Extension(session): Extension<GenericAuthenticatedSession>,
 ) -> Result<CookieJar, HttpError> {
-    session.delete(&mut state.session_store.clone()).await?;
-    Ok(cookies
-        .remove(Cookie::from("session"))
-        .remove(Cookie::from("session_csrf")))
+    session.delete(&mut session_store).await?;
+    Ok(remove_session_cookies(cookies))
 }
 
-/// Login using a credential method, and set a session cookie.
+#[derive(Serialize)]
+/// A single session in the response to GET /auth/sessions.
+struct SessionListing {
+    /// The short reference identifying the session.
+    reference: String,
+    /// The remaining lifetime of the session in seconds, if it has an expiry.
+    expires_in: Option<u32>,
+    /// Whether this is the session making the request.
+    current: bool,
+}
+
+#[derive(Serialize)]
+/// The response to GET /auth/sessions.
+struct ListSessionsResponse {
+    /// The current user's live sessions.
+    sessions: Vec<SessionListing>,
+}
+
+/// List the current user's live sessions.
+async fn list_sessions(
+    SessionConn(mut session_store): SessionConn,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+) -> Result<Json<ListSessionsResponse>, HttpError> {
+    let current = session.reference();
+    let sessions = sessions::list_user_sessions(session.user_id(), &mut session_store)
+        .await?
+        .into_iter()
+        .map(|summary| SessionListing {
+            current: summary.reference == current,
+            reference: summary.reference,
+            expires_in: summary.expires_in,
+        })
+        .collect();
+    Ok(Json(ListSessionsResponse { sessions }))
+}
+
+/// Revoke one of the current user's sessions by its reference, e.g. to log
+/// out a lost device. Revoking the current session also clears its cookies.
+async fn revoke_session(
+    cookies: CookieJar,
+    SessionConn(mut session_store): SessionConn,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+    Path(session_ref): Path<String>,
+) -> Result<CookieJar, HttpError> {
+    if !sessions::revoke_user_session(session.user_id(), &session_ref, &mut session_store).await? {
+        tracing::warn!(
+            "User {} attempted to revoke session {session_ref}, which is not theirs",
+            session.user_id()
+        );
+        return Err(HttpError::new(
+            StatusCode::NOT_FOUND,
+            Some(format!("Session {session_ref} not found")),
+        ));
+    }
+    tracing::info!("User {} revoked session {session_ref}", session.user_id());
+    if session_ref == session.reference() {
+        Ok(remove_session_cookies(cookies))
+    } else {
+        Ok(cookies)
+    }
+}
+
+/// Build the headers informing a client of their login rate limit state.
+fn rate_limit_headers(status: &store::BruteforceStatus) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "X-RateLimit-Limit",
+        HeaderValue::from(store::BruteforceStatus::LIMIT),
+    );
+    headers.insert(
+        "X-RateLimit-Remaining",
+        HeaderValue::from(status.remaining()),
+    );
+    if status.timed_out {
+        headers.insert(RETRY_AFTER, HeaderValue::from(status.reset_after));
+    }
+    headers
+}
+
+/// Login using a credential method, and set a session cookie. Every response
+/// after the rate limit check, successful or not, carries rate limit headers.
 async fn login(
     headers: HeaderMap,
     cookies: CookieJar,
     State(state): State<AppState>,
-    Json(body): Json<AuthenticateRequest>,
-) -> Result<(CookieJar, Json<AuthenticateResponse>), HttpError> {
-    let client_ip = headers
-        .get("x-real-ip")
-        .ok_or_else(|| {
-            eprintln!("X-Real-IP header not set, I should be running behind a reverse proxy.");
-            HttpError::new(
-                StatusCode::BAD_REQUEST,
-                Some(String::from("X-Real-IP not set")),
-            )
-        })?
-        .to_str()
-        .map_err(|err| {
-            eprintln!("Failed to parse X-Real-IP header value: {err}");
-            HttpError::new(
-                StatusCode::BAD_REQUEST,
-                Some(String::from("X-Real-IP value unparseable")),
-            )
-        })?;
-    if state
-        .session_store
-        .clone()
-        .bruteforce_timeout(client_ip)
-        .await?
-    {
-        eprintln!(
+    ValidatedJson(body): ValidatedJson<AuthenticateRequest>,
+) -> Result<
+    (
+        HeaderMap,
+        Result<(CookieJar, Json<AuthenticateResponse>), HttpError>,
+    ),
+    HttpError,
+> {
+    let client_ip = client_ip(&headers)?;
+    let rate_limit = state.session_conn().bruteforce_timeout(client_ip).await?;
+    if rate_limit.timed_out {
+        tracing::warn!(
             "Client {client_ip} is rate-limited for suspected bruteforce authentication attempt."
         );
-        return Err(HttpError::new(
-            StatusCode::TOO_MANY_REQUESTS,
-            Some(String::from("Too many authentication attempts.")),
+        return Ok((
+            rate_limit_headers(&rate_limit),
+            Err(HttpError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                Some(String::from("Too many authentication attempts.")),
+            )),
         ));
     }
-    let mut session_store = state.session_store.clone();
+    Ok((
+        rate_limit_headers(&rate_limit),
+        authenticate_credential(cookies, &state, client_ip, body).await,
+    ))
+}
+
+/// Authenticate with the primary credential in a login request, once it has
+/// passed the rate limit check.
+async fn authenticate_credential(
+    cookies: CookieJar,
+    state: &AppState,
+    client_ip: &str,
+    body: AuthenticateRequest,
+) -> Result<(CookieJar, Json<AuthenticateResponse>), HttpError> {
+    let mut session_store = state.session_conn();
     let outcome = auth::authenticate(
         body.email.clone(),
         body.credential,
-        &state.db,
+        body.remember_me,
+        client_ip,
+        state.db(),
         &mut session_store,
     )
     .await?;
-    let (mfa_required, is_admin, token, csrf) = match outcome {
+    if matches!(outcome, auth::AuthenticationOutcome::Failure) {
+        tracing::warn!(
+            "Failed authentication attempt as {}",
+            RedactedEmail::from(&body.email)
+        );
+    }
+    session_response(cookies, outcome)
+}
+
+/// Build the response to an authentication attempt, setting the session
+/// cookies for whichever session it created.
+fn session_response(
+    cookies: CookieJar,
+    outcome: auth::AuthenticationOutcome,
+) -> Result<(CookieJar, Json<AuthenticateResponse>), HttpError> {
+    let totp_enrolment_required = matches!(
+        outcome,
+        auth::AuthenticationOutcome::TotpEnrolmentRequired(_)
+    );
+    let (mfa_required, is_admin, token, csrf, remember_me) = match outcome {
         auth::AuthenticationOutcome::Failure => {
-            eprintln!("Failed authentication attempt as {}", body.email);
             return Err(HttpError::new(
                 StatusCode::UNAUTHORIZED,
                 Some(String::from("Authentication failed")),
             ));
         }
-        auth::AuthenticationOutcome::SuccessAdministrative(session) => {
-            (false, Some(true), session.token(), session.csrf_token())
-        }
-        auth::AuthenticationOutcome::Success(session) => {
-            (false, Some(false), session.token(), session.csrf_token())
-        }
+        auth::AuthenticationOutcome::SuccessAdministrative(session) => (
+            false,
+            Some(true),
+            session.token(),
+            session.csrf_token(),
+            false,
+        ),
+        auth::AuthenticationOutcome::Success(session) => (
+            false,
+            Some(false),
+            session.token(),
+            session.csrf_token(),
+            session.remember_me(),
+        ),
+        auth::AuthenticationOutcome::TotpEnrolmentRequired(session) => (
+            false,
+            Some(false),
+            session.token(),
+            session.csrf_token(),
+            false,
+        ),
         auth::AuthenticationOutcome::Partial(session) => {
-            (true, None, session.token(), session.csrf_token())
+            (true, None, session.token(), session.csrf_token(), false)
         }
     };
     Ok((
-        cookies
-            .add(
-                Cookie::build(("session", token))
-                    .http_only(true)
-                    .path("/")
-                    .secure(true)
-                    .same_site(SameSite::Strict),
-            )
-            .add(
-                Cookie::build(("session_csrf", csrf))
-                    .path("/")
-                    .same_site(SameSite::Strict),
-            ),
+        add_session_cookies(cookies, token, csrf, remember_me),
         Json(AuthenticateResponse {
             mfa_required,
             is_admin,
+            totp_enrolment_required,
         }),
     ))
 }
 
+#[derive(Deserialize)]
+/// A request POST to /auth/magic-link.
+struct MagicLinkRequest {
+    /// The email address to send the link to.
+    pub email: EmailAddress,
+}
+
+/// Email a magic link with which to log in to the user with the given address.
+/// Always succeeds (short of rate limiting or storage errors), whether or not
+/// such a user exists, so that it can't be used to discover accounts.
+async fn request_magic_link(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    ValidatedJson(body): ValidatedJson<MagicLinkRequest>,
+) -> Result<(), HttpError> {
+    let client_ip = client_ip(&headers)?;
+    let mut session_store = state.session_conn();
+    if session_store
+        .bruteforce_timeout(&format!("magic-link:{client_ip}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!("Client {client_ip} is rate-limited for excessive magic link requests.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many magic link requests.")),
+        ));
+    }
+    // Also limited per address, so that requests spread across many clients
+    // can't flood one user's inbox.
+    let email_subject = sessions::rate_limit_subject(&body.email.as_str().to_lowercase());
+    if session_store
+        .bruteforce_timeout(&format!("magic-link-email:{email_subject}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!(
+            "Magic links to {} are rate-limited for excessive requests.",
+            RedactedEmail::from(&body.email)
+        );
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many magic link requests.")),
+        ));
+    }
+    auth::request_magic_link(
+        body.email,
+        state.db(),
+        &mut session_store,
+        state.email_sender.as_ref(),
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+/// The query parameters of GET /auth/magic-link/consume.
+struct ConfirmMagicLinkQuery {
+    /// The token from the emailed link.
+    token: String,
+}
+
+/// Whether a magic link token could have been issued, i.e. is made up only
+/// of the hex digits tokens are generated from. Anything else is never
+/// reflected back into the confirmation page.
+fn is_well_formed_token(token: &str) -> bool {
+    !token.is_empty() && token.len() <= 48 && token.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Build the page asking the user to confirm that they want to sign in with a
+/// magic link token, which must be well formed (see `is_well_formed_token`).
+/// The form submits `nonce` back, proving that it was submitted from this page.
+fn magic_link_page(token: &str, nonce: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Sign in to SecureCart</title>
+</head>
+<body>
+<form method="post">
+<input type="hidden" name="token" value="{token}">
+<input type="hidden" name="nonce" value="{nonce}">
+<button type="submit">Sign in to SecureCart</button>
+</form>
+</body>
+</html>
+"#
+    )
+}
+
+/// Serve the page an emailed magic link opens, which asks the user to confirm
+/// the sign in. Following the link does not consume the token, so that mail
+/// scanners and link previews which fetch it can't use it up, or sign in on
+/// the user's behalf. The page is neither cached nor leaks the token in the
+/// Referer header. A fresh nonce is set in a cookie and embedded in the page,
+/// so that only a form submitted from the page can sign in.
+async fn confirm_magic_link(
+    cookies: CookieJar,
+    Query(query): Query<ConfirmMagicLinkQuery>,
+) -> Result<(CookieJar, HeaderMap, Html<String>), HttpError> {
+    if !is_well_formed_token(&query.token) {
+        return Err(HttpError::new(
+            StatusCode::BAD_REQUEST,
+            Some(String::from("Invalid sign in link.")),
+        ));
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+    let nonce = sessions::generate_token();
+    Ok((
+        cookies.add(build_session_cookie(
+            MAGIC_LINK_NONCE_COOKIE_NAME,
+            nonce.clone(),
+            true,
+        )),
+        headers,
+        Html(magic_link_page(&query.token, &nonce)),
+    ))
+}
+
+#[derive(Deserialize)]
+/// The form submitted by the page served by GET /auth/magic-link/consume.
+struct ConsumeMagicLinkForm {
+    /// The token from the emailed link.
+    token: String,
+    /// The nonce embedded in the confirmation page.
+    #[serde(default)]
+    nonce: String,
+}
+
+/// Log in with a magic link token submitted from its confirmation page, set a
+/// session cookie, and redirect to the page the user continues on. The token
+/// stands in for the primary credential only, so MFA is still required if
+/// the user has it enrolled. Counts towards the same rate limit as `login`,
+/// and attempts with each token are limited too. The form must carry the
+/// nonce set by the confirmation page, since the route needs no session and
+/// so has no CSRF token; otherwise another site could submit its own token,
+/// signing the user in to an account of its choosing.
+async fn consume_magic_link(
+    headers: HeaderMap,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Form(form): Form<ConsumeMagicLinkForm>,
+) -> Result<(CookieJar, Redirect), HttpError> {
+    let client_ip = client_ip(&headers)?;
+    let nonce_matches = cookies
+        .get(MAGIC_LINK_NONCE_COOKIE_NAME)
+        .is_some_and(|cookie| {
+            !form.nonce.is_empty()
+                && bool::from(cookie.value().as_bytes().ct_eq(form.nonce.as_bytes()))
+        });
+    if !nonce_matches {
+        tracing::warn!("Magic link submitted from {client_ip} without its page's nonce, rejected.");
+        return Err(HttpError::new(
+            StatusCode::FORBIDDEN,
+            Some(String::from(
+                "Sign in must be confirmed from the sign in link's page, please open the link again.",
+            )),
+        ));
+    }
+    let mut session_store = state.session_conn();
+    let token_subject = sessions::rate_limit_subject(&form.token);
+    if session_store.bruteforce_timeout(client_ip).await?.timed_out
+        || session_store
+            .bruteforce_timeout(&format!("magic-link-token:{token_subject}"))
+            .await?
+            .timed_out
+    {
+        tracing::warn!(
+            "Client {client_ip} is rate-limited for suspected bruteforce authentication attempt."
+        );
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many authentication attempts.")),
+        ));
+    }
+    let outcome =
+        auth::authenticate_magic_link(&form.token, client_ip, state.db(), &mut session_store)
+            .await?;
+    if matches!(outcome, auth::AuthenticationOutcome::Failure) {
+        tracing::warn!("Failed magic link authentication attempt from {client_ip}");
+    }
+    let (session_cookies, Json(response)) = session_response(
+        cookies.remove(build_removal_cookie(MAGIC_LINK_NONCE_COOKIE_NAME)),
+        outcome,
+    )?;
+    let page = if response.mfa_required {
+        "/2fa.html"
+    } else if response.totp_enrolment_required {
+        "/enroll2fa.html"
+    } else {
+        "/"
+    };
+    Ok((
+        session_cookies,
+        Redirect::to(&format!("{}{page}", *PUBLIC_URI)),
+    ))
+}
+
 #[derive(Serialize)]
 /// A response to /auth/2fa
 struct MfaMethodsResponse {
@@ -211,11 +667,72 @@ async fn get_mfa_methods(
     State(state): State<AppState>,
     Extension(session): Extension<PreAuthenticationSession>,
 ) -> Result<Json<MfaMethodsResponse>, HttpError> {
-    let db_conn = state.db;
-    let methods = auth::list_mfa_methods(session.user_id(), &db_conn).await?;
+    let methods = auth::list_mfa_methods(
+        &session,
+        state.sms_sender.is_configured(),
+        state.email_sender.is_configured(),
+        state.db(),
+    )
+    .await?;
     Ok(Json(MfaMethodsResponse { methods }))
 }
 
+/// Send a one-time MFA code by SMS to the partially authenticated user.
+async fn send_sms_code(
+    State(state): State<AppState>,
+    Extension(session): Extension<PreAuthenticationSession>,
+) -> Result<(), HttpError> {
+    let user_id = session.user_id();
+    let mut session_store = state.session_conn();
+    if session_store
+        .bruteforce_timeout(&format!("sms-code:{user_id}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!("User {user_id} is rate-limited for excessive SMS code requests.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many SMS code requests.")),
+        ));
+    }
+    auth::send_sms_code(
+        &session,
+        state.db(),
+        &mut session_store,
+        state.sms_sender.as_ref(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Send a one-time MFA code by email to the partially authenticated user.
+async fn send_email_code(
+    State(state): State<AppState>,
+    Extension(session): Extension<PreAuthenticationSession>,
+) -> Result<(), HttpError> {
+    let user_id = session.user_id();
+    let mut session_store = state.session_conn();
+    if session_store
+        .bruteforce_timeout(&format!("email-code:{user_id}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!("User {user_id} is rate-limited for excessive email code requests.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many email code requests.")),
+        ));
+    }
+    auth::send_email_code(
+        &session,
+        state.db(),
+        &mut session_store,
+        state.email_sender.as_ref(),
+    )
+    .await?;
+    Ok(())
+}
+
 #[derive(Deserialize)]
 /// A request POST to /auth/2fa.
 struct MfaAuthenticateRequest {
@@ -227,6 +744,9 @@ struct MfaAuthenticateRequest {
 struct MfaAuthenticateResponse {
     /// Whether the new session is administrative.
     is_admin: bool,
+    /// Whether the user is an administrator who must enrol TOTP before they
+    /// can be given an administrative session.
+    totp_enrolment_required: bool,
 }
 
 /// Authenticate using an MFA method.
@@ -234,44 +754,739 @@ async fn authenticate_2fa(
     cookies: CookieJar,
     State(state): State<AppState>,
     Extension(session): Extension<PreAuthenticationSession>,
-    Json(body): Json<MfaAuthenticateRequest>,
+    ValidatedJson(body): ValidatedJson<MfaAuthenticateRequest>,
 ) -> Result<(CookieJar, Json<MfaAuthenticateResponse>), HttpError> {
-    let mut session_store = state.session_store.clone();
+    let mut session_store = state.session_conn();
     let outcome =
-        auth::authenticate_2fa(session, body.credential, &state.db, &mut session_store).await?;
-    let (token, csrf, is_admin) = match outcome {
+        auth::authenticate_2fa(session, body.credential, state.db(), &mut session_store).await?;
+    let totp_enrolment_required = matches!(
+        outcome,
+        auth::AuthenticationOutcome2fa::TotpEnrolmentRequired(_)
+    );
+    let (token, csrf, is_admin, remember_me) = match outcome {
         auth::AuthenticationOutcome2fa::Failure => Err(HttpError::new(
             StatusCode::UNAUTHORIZED,
             Some(String::from("Two-factor authentication failed")),
         )),
-        auth::AuthenticationOutcome2fa::Success(new_session) => {
-            Ok((new_session.token(), new_session.csrf_token(), false))
-        }
+        auth::AuthenticationOutcome2fa::Success(new_session) => Ok((
+            new_session.token(),
+            new_session.csrf_token(),
+            false,
+            new_session.remember_me(),
+        )),
         auth::AuthenticationOutcome2fa::SuccessAdministrative(new_session) => {
-            Ok((new_session.token(), new_session.csrf_token(), true))
+            Ok((new_session.token(), new_session.csrf_token(), true, false))
+        }
+        auth::AuthenticationOutcome2fa::TotpEnrolmentRequired(new_session) => {
+            Ok((new_session.token(), new_session.csrf_token(), false, false))
         }
     }?;
     Ok((
-        cookies
-            .add(
-                Cookie::build(("session", token))
-                    .http_only(true)
-                    .path("/")
-                    .secure(true)
-                    .same_site(SameSite::Strict),
-            )
-            .add(
-                Cookie::build(("session_csrf", csrf))
-                    .path("/")
-                    .same_site(SameSite::Strict),
-            ),
-        Json(MfaAuthenticateResponse { is_admin }),
+        add_session_cookies(cookies, token, csrf, remember_me),
+        Json(MfaAuthenticateResponse {
+            is_admin,
+            totp_enrolment_required,
+        }),
     ))
 }
 
+impl From<auth::errors::AuthenticateError> for HttpError {
+    fn from(err: auth::errors::AuthenticateError) -> Self {
+        match err {
+            auth::errors::AuthenticateError::StorageError(storage_err) => storage_err.into(),
+            auth::errors::AuthenticateError::TooManySessions => {
+                tracing::warn!("Client has too many partially authenticated sessions in progress.");
+                Self::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some(String::from(
+                        "Too many authentication attempts in progress.",
+                    )),
+                )
+            }
+        }
+    }
+}
+
+impl From<auth::errors::SmsCodeError> for HttpError {
+    fn from(err: auth::errors::SmsCodeError) -> Self {
+        match err {
+            auth::errors::SmsCodeError::StorageError(storage_err) => storage_err.into(),
+            auth::errors::SmsCodeError::Unavailable => {
+                tracing::warn!("An SMS code was requested, but no SMS provider is configured.");
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(String::from("SMS codes are not available")),
+                )
+            }
+            auth::errors::SmsCodeError::NoPhoneNumber(user_id) => {
+                tracing::warn!(
+                    "User {user_id} requested an SMS code without a verified phone number."
+                );
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from(
+                        "No verified phone number is set for this account",
+                    )),
+                )
+            }
+            auth::errors::SmsCodeError::TooManyCodes(user_id) => {
+                tracing::warn!("User {user_id} requested too many SMS codes in one session.");
+                Self::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some(String::from("Too many SMS code requests.")),
+                )
+            }
+            auth::errors::SmsCodeError::DeliveryFailed(sms_err) => {
+                tracing::error!("Error sending SMS code: {sms_err}");
+                Self::new(
+                    StatusCode::BAD_GATEWAY,
+                    Some(String::from("Failed to send SMS code")),
+                )
+            }
+        }
+    }
+}
+
+impl From<auth::errors::EmailCodeError> for HttpError {
+    fn from(err: auth::errors::EmailCodeError) -> Self {
+        match err {
+            auth::errors::EmailCodeError::StorageError(storage_err) => storage_err.into(),
+            auth::errors::EmailCodeError::NotEnabled(user_id) => {
+                tracing::warn!(
+                    "User {user_id} requested an email code without enabling email MFA."
+                );
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from("Email MFA is not enabled for this account")),
+                )
+            }
+            auth::errors::EmailCodeError::TooManyCodes(user_id) => {
+                tracing::warn!("User {user_id} requested too many email codes in one session.");
+                Self::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some(String::from("Too many email code requests.")),
+                )
+            }
+            auth::errors::EmailCodeError::DeliveryFailed(mail_err) => {
+                tracing::error!("Error sending email code: {mail_err}");
+                Self::new(
+                    StatusCode::BAD_GATEWAY,
+                    Some(String::from("Failed to send email code")),
+                )
+            }
+        }
+    }
+}
+
 impl From<sessions::errors::SessionStorageError> for HttpError {
     fn from(err: sessions::errors::SessionStorageError) -> Self {
-        eprintln!("Storage error while accessing session store: {err}");
+        tracing::error!("Storage error while accessing session store: {err}");
         Self::new(StatusCode::INTERNAL_SERVER_ERROR, Some(err.to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Method, StatusCode},
+    };
+    use core::time::Duration;
+    use serde_json::{json, Value};
+    use tokio::time::advance;
+    use uuid::Uuid;
+
+    use super::{is_well_formed_token, magic_link_page};
+    use crate::{
+        constants::{
+            api::PUBLIC_URI,
+            cookies::{CSRF_COOKIE_NAME, CSRF_HEADER_NAME, SESSION_COOKIE_NAME},
+            sessions::{AUTH_PENALTY_PERIOD, MAGIC_LINK_TIMEOUT, ONE_TIME_CODE_MAX_ATTEMPTS},
+        },
+        db::{
+            models::{appuser::AppUser, totp::TotpInsert},
+            ConnectionPool,
+        },
+        services::sessions::{
+            self, store::BruteforceStatus, OneTimeCodeKind, PreAuthenticationSession,
+            SessionTrait as _,
+        },
+        testing::{store_user, TestApp, TestResponse},
+        utils::phone::PhoneNumber,
+    };
+
+    /// Store a customer who has opted in to email MFA, and has TOTP enrolled
+    /// if `totp` is set.
+    async fn store_email_mfa_user(email: &str, totp: bool, db_conn: &ConnectionPool) -> AppUser {
+        let mut user = store_user(email, db_conn).await;
+        user.email_mfa_enabled = true;
+        user.update(db_conn).await.expect("User should be updated");
+        if totp {
+            TotpInsert::new(user.id(), vec![7; 20])
+                .store(db_conn)
+                .await
+                .expect("TOTP should be stored");
+        }
+        user
+    }
+
+    /// The phone number of every user stored by `store_sms_mfa_user`.
+    const PHONE: &str = "+447700900123";
+
+    /// Store a customer with TOTP enrolled and a verified phone number, who
+    /// can use an SMS code for MFA instead.
+    async fn store_sms_mfa_user(email: &str, db_conn: &ConnectionPool) -> AppUser {
+        let mut user = store_user(email, db_conn).await;
+        user.phone = Some(PhoneNumber::try_from(PHONE).expect("Phone number should be valid"));
+        user.phone_verified = true;
+        user.update(db_conn).await.expect("User should be updated");
+        TotpInsert::new(user.id(), vec![7; 20])
+            .store(db_conn)
+            .await
+            .expect("TOTP should be stored");
+        user
+    }
+
+    /// Have an SMS code sent to the client's partially authenticated session,
+    /// returning the code from the message.
+    async fn send_sms_code(app: &mut TestApp) -> String {
+        let response = app.post("/auth/2fa/sms", &json!({})).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let sent = app.sms.sent();
+        let sms = sent.last().expect("An SMS should be sent");
+        assert_eq!(sms.recipient, PHONE);
+        sms.message
+            .trim_end_matches('.')
+            .rsplit(' ')
+            .next()
+            .expect("Message should contain a code")
+            .to_owned()
+    }
+
+    /// Attempt MFA with an SMS code.
+    async fn authenticate_sms(app: &mut TestApp, code: &str) -> StatusCode {
+        app.post(
+            "/auth/2fa",
+            &json!({ "credential": { "Sms": { "code": code } } }),
+        )
+        .await
+        .status
+    }
+
+    /// Submit a form to consume a magic link, as its confirmation page does.
+    async fn submit_magic_link_form(app: &mut TestApp, form: String) -> TestResponse {
+        let request = app
+            .request(Method::POST, "/auth/magic-link/consume")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(form))
+            .expect("Request should be valid");
+        app.send(request).await
+    }
+
+    /// Open a magic link's confirmation page and confirm the sign in.
+    async fn consume_magic_link(app: &mut TestApp, token: &str) -> TestResponse {
+        let page = app
+            .get(&format!("/auth/magic-link/consume?token={token}"))
+            .await;
+        assert_eq!(page.status, StatusCode::OK);
+        let nonce = String::from_utf8(page.body)
+            .expect("Page should be UTF-8")
+            .split_once(r#"name="nonce" value=""#)
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(nonce, _)| nonce.to_owned())
+            .expect("Page should embed a nonce");
+        submit_magic_link_form(app, format!("token={token}&nonce={nonce}")).await
+    }
+
+    /// Sign in to a user with a magic link, leaving the client with the
+    /// resulting session.
+    async fn sign_in_with_magic_link(app: &mut TestApp, user: &AppUser) -> TestResponse {
+        let token = sessions::create_magic_link_token(user.id(), &mut app.session_conn())
+            .await
+            .expect("Token should be stored");
+        consume_magic_link(app, &token).await
+    }
+
+    /// The names of the MFA methods offered to the client's session.
+    async fn mfa_method_names(app: &mut TestApp) -> Vec<String> {
+        let response = app.get("/auth/2fa").await;
+        assert_eq!(response.status, StatusCode::OK);
+        response
+            .json()
+            .pointer("/methods")
+            .and_then(Value::as_array)
+            .expect("Response should list methods")
+            .iter()
+            .filter_map(|method| method.as_object()?.keys().next().cloned())
+            .collect()
+    }
+
+    /// Issue an email code to the client's partially authenticated session,
+    /// as if it had been emailed to the user.
+    async fn issue_email_code(app: &TestApp) -> String {
+        let mut session_store_conn = app.session_conn();
+        let token = app
+            .cookie(&SESSION_COOKIE_NAME)
+            .expect("Client should have a session");
+        PreAuthenticationSession::get(&token, &mut session_store_conn)
+            .await
+            .expect("Session should be read")
+            .expect("Session should exist")
+            .issue_one_time_code(OneTimeCodeKind::Email, &mut session_store_conn)
+            .await
+            .expect("Code should be issued")
+            .expect("Code should not be rate limited")
+    }
+
+    /// A valid magic link signs the user in, and is redirected to the store.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn valid_magic_link_signs_in(db_conn: ConnectionPool) {
+        let user = store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        let response = sign_in_with_magic_link(&mut app, &user).await;
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+        let location = response
+            .headers
+            .get(header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .expect("Response should redirect");
+        assert_eq!(location, format!("{}/", *PUBLIC_URI));
+        assert_eq!(app.get("/auth/check/customer").await.status, StatusCode::OK);
+    }
+
+    /// A magic link can only be used once.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn used_magic_link_is_rejected(db_conn: ConnectionPool) {
+        let user = store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        let token = sessions::create_magic_link_token(user.id(), &mut app.session_conn())
+            .await
+            .expect("Token should be stored");
+        let first = consume_magic_link(&mut app, &token).await;
+        assert_eq!(first.status, StatusCode::SEE_OTHER);
+        let mut other_client = app.other_client();
+        let second = consume_magic_link(&mut other_client, &token).await;
+        assert_eq!(second.status, StatusCode::UNAUTHORIZED);
+        assert!(other_client.cookie(&SESSION_COOKIE_NAME).is_none());
+    }
+
+    /// A magic link can't be used once it has expired.
+    #[tokio::test(start_paused = true)]
+    async fn expired_magic_link_is_rejected() {
+        let mut app = TestApp::without_db();
+        let token = sessions::create_magic_link_token(Uuid::new_v4(), &mut app.session_conn())
+            .await
+            .expect("Token should be stored");
+        advance(Duration::from_secs(u64::from(MAGIC_LINK_TIMEOUT) + 1)).await;
+        let response = consume_magic_link(&mut app, &token).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert!(app.cookie(&SESSION_COOKIE_NAME).is_none());
+    }
+
+    /// A magic link stands in for only the primary credential, so a user with
+    /// MFA enrolled is left with a partially authenticated session.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn magic_link_still_requires_mfa(db_conn: ConnectionPool) {
+        let user = store_user("alice@example.com", &db_conn).await;
+        TotpInsert::new(user.id(), vec![7; 20])
+            .store(&db_conn)
+            .await
+            .expect("TOTP should be stored");
+        let mut app = TestApp::new(db_conn);
+        let response = sign_in_with_magic_link(&mut app, &user).await;
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+        let location = response
+            .headers
+            .get(header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .expect("Response should redirect");
+        assert!(location.ends_with("/2fa.html"));
+        assert_eq!(
+            app.get("/auth/check").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(mfa_method_names(&mut app).await, ["Totp"]);
+    }
+
+    /// A magic link form submitted without the nonce from its page, e.g. by
+    /// another site, is rejected without using up the token.
+    #[tokio::test]
+    async fn magic_link_without_page_nonce_is_rejected() {
+        let mut app = TestApp::without_db();
+        let token = sessions::create_magic_link_token(Uuid::new_v4(), &mut app.session_conn())
+            .await
+            .expect("Token should be stored");
+        let missing = submit_magic_link_form(&mut app, format!("token={token}")).await;
+        assert_eq!(missing.status, StatusCode::FORBIDDEN);
+        let page = app
+            .get(&format!("/auth/magic-link/consume?token={token}"))
+            .await;
+        assert_eq!(page.status, StatusCode::OK);
+        let forged = submit_magic_link_form(&mut app, format!("token={token}&nonce=0f3a9c")).await;
+        assert_eq!(forged.status, StatusCode::FORBIDDEN);
+        let user_id = sessions::take_magic_link_user(&token, &mut app.session_conn())
+            .await
+            .expect("Token should be read");
+        assert!(user_id.is_some());
+    }
+
+    /// After logging in with a password, an email code can be used for MFA.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn password_session_accepts_email_code(db_conn: ConnectionPool) {
+        store_email_mfa_user("alice@example.com", true, &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        let login = app.log_in("alice@example.com").await;
+        assert_eq!(login.status, StatusCode::OK);
+        assert!(mfa_method_names(&mut app)
+            .await
+            .contains(&"EmailOtp".to_owned()));
+        let code = issue_email_code(&app).await;
+        let response = app
+            .post(
+                "/auth/2fa",
+                &json!({ "credential": { "EmailOtp": { "code": code } } }),
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    /// Users with a verified phone number are offered SMS codes for MFA, and
+    /// the code sent to their phone completes authentication.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn sms_code_is_sent_and_accepted(db_conn: ConnectionPool) {
+        store_sms_mfa_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        assert_eq!(mfa_method_names(&mut app).await, ["Totp", "Sms"]);
+        let code = send_sms_code(&mut app).await;
+        assert_eq!(
+            authenticate_sms(&mut app, "not the code").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(authenticate_sms(&mut app, &code).await, StatusCode::OK);
+        assert_eq!(app.get("/auth/check").await.status, StatusCode::OK);
+    }
+
+    /// Once `ONE_TIME_CODE_MAX_ATTEMPTS` wrong SMS codes have been entered,
+    /// even the right code is refused.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn sms_code_attempts_are_limited(db_conn: ConnectionPool) {
+        store_sms_mfa_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let code = send_sms_code(&mut app).await;
+        for _ in 0..ONE_TIME_CODE_MAX_ATTEMPTS {
+            assert_eq!(
+                authenticate_sms(&mut app, "not the code").await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            authenticate_sms(&mut app, &code).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    /// Email codes are only offered to and sent to users who have opted in
+    /// to them, and the code emailed completes authentication.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn email_code_is_sent_only_when_opted_in(db_conn: ConnectionPool) {
+        let user = store_user("bob@example.com", &db_conn).await;
+        TotpInsert::new(user.id(), vec![7; 20])
+            .store(&db_conn)
+            .await
+            .expect("TOTP should be stored");
+        let mut app = TestApp::new(db_conn.clone());
+        assert_eq!(app.log_in("bob@example.com").await.status, StatusCode::OK);
+        assert_eq!(mfa_method_names(&mut app).await, ["Totp"]);
+        let refused = app.post("/auth/2fa/email", &json!({})).await;
+        assert!(!refused.status.is_success());
+        assert!(app.emails.sent().is_empty());
+
+        store_email_mfa_user("alice@example.com", true, &db_conn).await;
+        let mut opted_in_app = app.other_client();
+        assert_eq!(
+            opted_in_app.log_in("alice@example.com").await.status,
+            StatusCode::OK
+        );
+        assert_eq!(
+            mfa_method_names(&mut opted_in_app).await,
+            ["Totp", "EmailOtp"]
+        );
+        let sent = opted_in_app.post("/auth/2fa/email", &json!({})).await;
+        assert_eq!(sent.status, StatusCode::OK);
+        let emails = app.emails.sent();
+        let email = emails.first().expect("An email should be sent");
+        assert_eq!(email.recipient, "alice@example.com");
+        let code = email
+            .body
+            .trim_end_matches('.')
+            .rsplit(' ')
+            .next()
+            .expect("Email should contain a code");
+        let response = opted_in_app
+            .post(
+                "/auth/2fa",
+                &json!({ "credential": { "EmailOtp": { "code": code } } }),
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    /// After signing in with a magic link, email codes are neither offered,
+    /// sent nor accepted for MFA, since they come from the same inbox.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn magic_link_session_rejects_email_code(db_conn: ConnectionPool) {
+        let user = store_email_mfa_user("alice@example.com", true, &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        let response = sign_in_with_magic_link(&mut app, &user).await;
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+        assert_eq!(mfa_method_names(&mut app).await, ["Totp"]);
+        let send = app.post("/auth/2fa/email", &json!({})).await;
+        assert!(!send.status.is_success());
+        assert!(app.emails.sent().is_empty());
+        let code = issue_email_code(&app).await;
+        let attempt = app
+            .post(
+                "/auth/2fa",
+                &json!({ "credential": { "EmailOtp": { "code": code } } }),
+            )
+            .await;
+        assert_eq!(attempt.status, StatusCode::UNAUTHORIZED);
+    }
+
+    /// Users whose only MFA method is email can't sign in with a magic link,
+    /// and are told to use their password instead of being sent one.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn magic_link_refused_for_email_only_mfa(db_conn: ConnectionPool) {
+        let user = store_email_mfa_user("alice@example.com", false, &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        let request = app
+            .post("/auth/magic-link", &json!({ "email": "alice@example.com" }))
+            .await;
+        assert_eq!(request.status, StatusCode::OK);
+        let sent = app.emails.sent();
+        let email = sent.first().expect("An email should be sent");
+        assert!(!email.body.contains("token="));
+        let response = sign_in_with_magic_link(&mut app, &user).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert!(app.cookie(&SESSION_COOKIE_NAME).is_none());
+    }
+
+    /// Tokens made up of hex digits are accepted.
+    #[test]
+    fn accepts_hex_tokens() {
+        assert!(is_well_formed_token("0f3a9c"));
+        assert!(is_well_formed_token(&"f".repeat(48)));
+    }
+
+    /// Anything which could break out of the confirmation page's markup, or
+    /// could never have been issued, is rejected.
+    #[test]
+    fn rejects_malformed_tokens() {
+        assert!(!is_well_formed_token(""));
+        assert!(!is_well_formed_token(&"f".repeat(49)));
+        assert!(!is_well_formed_token("\"><script>"));
+        assert!(!is_well_formed_token("0f3a 9c"));
+    }
+
+    /// The confirmation page submits the token back, rather than using it.
+    #[test]
+    fn page_posts_token() {
+        let page = magic_link_page("0f3a9c", "4e0b7d");
+        assert!(page.contains(r#"<form method="post">"#));
+        assert!(page.contains(r#"name="token" value="0f3a9c""#));
+        assert!(page.contains(r#"name="nonce" value="4e0b7d""#));
+    }
+
+    /// The rate limit headers of a login response: the limit, the remaining
+    /// attempts and the `Retry-After` seconds, if given.
+    fn rate_limit(response: &TestResponse) -> (Option<&str>, Option<&str>, Option<&str>) {
+        let header = |name: &str| {
+            response
+                .headers
+                .get(name)
+                .map(|value| value.to_str().expect("Header should be text"))
+        };
+        (
+            header("X-RateLimit-Limit"),
+            header("X-RateLimit-Remaining"),
+            header(header::RETRY_AFTER.as_str()),
+        )
+    }
+
+    /// Login responses report the attempts remaining before the client is
+    /// locked out, whether or not they succeed, and when to retry once it is.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn login_reports_rate_limit(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        let limit = BruteforceStatus::LIMIT.to_string();
+        let success = app.log_in("alice@example.com").await;
+        assert_eq!(success.status, StatusCode::OK);
+        let after_success = (BruteforceStatus::LIMIT - 1).to_string();
+        assert_eq!(
+            rate_limit(&success),
+            (Some(limit.as_str()), Some(after_success.as_str()), None)
+        );
+        let body = json!({
+            "email": "alice@example.com",
+            "credential": { "Password": { "password": "wrong password" } },
+        });
+        for remaining_count in (0..BruteforceStatus::LIMIT - 1).rev() {
+            let failure = app.post("/auth", &body).await;
+            assert_eq!(failure.status, StatusCode::UNAUTHORIZED);
+            let remaining = remaining_count.to_string();
+            assert_eq!(
+                rate_limit(&failure),
+                (Some(limit.as_str()), Some(remaining.as_str()), None)
+            );
+        }
+        let locked_out = app.post("/auth", &body).await;
+        assert_eq!(locked_out.status, StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = AUTH_PENALTY_PERIOD.to_string();
+        assert_eq!(
+            rate_limit(&locked_out),
+            (Some(limit.as_str()), Some("0"), Some(retry_after.as_str()))
+        );
+    }
+
+    /// The configured names of the CSRF cookie and header are given to anyone.
+    #[tokio::test]
+    async fn csrf_config_gives_configured_names() {
+        let response = TestApp::without_db().get("/auth/csrf/config").await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.string_at("/cookie_name"), *CSRF_COOKIE_NAME);
+        assert_eq!(
+            response.string_at("/header_name"),
+            CSRF_HEADER_NAME.as_str()
+        );
+    }
+
+    /// Fetching the CSRF token returns the one in the CSRF cookie, without
+    /// replacing it.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn fetched_csrf_token_matches_cookie(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let original = app.cookie(&CSRF_COOKIE_NAME);
+        let response = app.get("/auth/csrf").await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(Some(response.string_at("/csrf_token")), original);
+        assert_eq!(app.cookie(&CSRF_COOKIE_NAME), original);
+    }
+
+    /// Rotating the CSRF token requires the current one, and returns a new
+    /// token matching the CSRF cookie, which the following request is
+    /// accepted with.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn rotated_csrf_token_matches_cookie(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let original = app.cookie(&CSRF_COOKIE_NAME);
+        let request = app
+            .request(Method::POST, "/auth/csrf")
+            .body(Body::empty())
+            .expect("Request should be valid");
+        assert_eq!(app.send(request).await.status.as_u16(), 419);
+        assert_eq!(app.cookie(&CSRF_COOKIE_NAME), original);
+        let response = app.post("/auth/csrf", &json!({})).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let csrf_token = response.string_at("/csrf_token");
+        assert_ne!(Some(&csrf_token), original.as_ref());
+        assert_eq!(app.cookie(&CSRF_COOKIE_NAME), Some(csrf_token));
+        assert_eq!(
+            app.post("/auth/refresh", &json!({})).await.status,
+            StatusCode::OK
+        );
+    }
+
+    /// Refreshing a session invalidates its old token, while the new session
+    /// token and CSRF token are accepted.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn refresh_replaces_session_token(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let old_token = app
+            .cookie(&SESSION_COOKIE_NAME)
+            .expect("Client should have a session");
+        let old_csrf = app.cookie(&CSRF_COOKIE_NAME);
+        assert_eq!(
+            app.post("/auth/refresh", &json!({})).await.status,
+            StatusCode::OK
+        );
+        let new_token = app
+            .cookie(&SESSION_COOKIE_NAME)
+            .expect("Client should have a session");
+        assert_ne!(new_token, old_token);
+        assert_ne!(app.cookie(&CSRF_COOKIE_NAME), old_csrf);
+
+        let mut old_client = app.other_client();
+        let request = old_client
+            .request(Method::GET, "/auth/check/customer")
+            .header(
+                header::COOKIE,
+                format!("{}={old_token}", *SESSION_COOKIE_NAME),
+            )
+            .body(Body::empty())
+            .expect("Request should be valid");
+        assert_eq!(
+            old_client.send(request).await.status,
+            StatusCode::UNAUTHORIZED
+        );
+
+        assert_eq!(app.get("/auth/check/customer").await.status, StatusCode::OK);
+        assert_eq!(
+            app.post("/auth/refresh", &json!({})).await.status,
+            StatusCode::OK
+        );
+    }
+
+    /// The configured cookie and header names are used in place of the
+    /// defaults, from logging in through to checking the CSRF token.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn configured_cookie_and_header_names_are_used(db_conn: ConnectionPool) {
+        assert_eq!(*SESSION_COOKIE_NAME, "test_session");
+        assert_eq!(*CSRF_COOKIE_NAME, "test_session_csrf");
+        assert_eq!(CSRF_HEADER_NAME.as_str(), "x-test-csrf-token");
+        store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        assert!(app.cookie("session").is_none());
+        assert!(app.cookie("session_csrf").is_none());
+        let csrf_token = app
+            .cookie(&CSRF_COOKIE_NAME)
+            .expect("Client should have a CSRF token");
+        assert_eq!(app.get("/auth/check/customer").await.status, StatusCode::OK);
+
+        let default_header = app
+            .request(Method::POST, "/auth/refresh")
+            .header("x-csrf-token", &csrf_token)
+            .body(Body::empty())
+            .expect("Request should be valid");
+        assert_eq!(app.send(default_header).await.status.as_u16(), 419);
+        let configured_header = app
+            .request(Method::POST, "/auth/refresh")
+            .header(&*CSRF_HEADER_NAME, &csrf_token)
+            .body(Body::empty())
+            .expect("Request should be valid");
+        assert_eq!(app.send(configured_header).await.status, StatusCode::OK);
+    }
+}
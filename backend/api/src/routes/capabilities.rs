@@ -0,0 +1,45 @@
+//! Routes under /capabilities, describing which optional features are
+//! available, so that frontends only offer what will work.
+use axum::{extract::State, routing::get, Json, Router};
+use serde::Serialize;
+
+use crate::state::AppState;
+
+/// Create a router for the /capabilities route.
+pub fn create_router() -> Router<AppState> {
+    Router::new().route("/", get(get_capabilities))
+}
+
+#[derive(Serialize)]
+/// The response model for GET /capabilities.
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "Each bool is an independent feature flag"
+)]
+struct CapabilitiesResponse {
+    /// Whether card payments through Stripe are compiled in.
+    stripe: bool,
+    /// Whether users can enrol TOTP as a second factor.
+    totp_mfa: bool,
+    /// Whether one-time codes can be sent by SMS as a second factor.
+    sms_mfa: bool,
+    /// Whether one-time codes can be sent by email as a second factor.
+    email_mfa: bool,
+    /// Whether customers can review products.
+    reviews: bool,
+    /// Whether coupon codes can be applied to orders.
+    coupons: bool,
+}
+
+/// List which optional features are enabled, either at compile time or by
+/// the runtime configuration. Available without a session.
+async fn get_capabilities(State(state): State<AppState>) -> Json<CapabilitiesResponse> {
+    Json(CapabilitiesResponse {
+        stripe: cfg!(feature = "stripe"),
+        totp_mfa: true,
+        sms_mfa: state.sms_sender.is_configured(),
+        email_mfa: state.email_sender.is_configured(),
+        reviews: true,
+        coupons: true,
+    })
+}
@@ -13,7 +13,7 @@ use crate::{
     middleware::session::session_middleware,
     services::{checkout, orders, sessions::CustomerSession},
     state::AppState,
-    utils::httperror::HttpError,
+    utils::{access::deny_or_not_found, httperror::HttpError, json::ValidatedJson},
 };
 
 #[cfg(feature = "stripe")]
@@ -27,7 +27,9 @@ pub fn create_router(state: &AppState) -> Router<AppState> {
             state.clone(),
             session_middleware::<CustomerSession>,
         ));
-    let unauthenticated = Router::new().route("/", get(get_status));
+    let unauthenticated = Router::new()
+        .route("/", get(get_status))
+        .route("/guest", post(do_guest_checkout));
     customer.merge(unauthenticated)
 }
 
@@ -78,16 +80,48 @@ struct CheckoutRequestResponse {
 async fn do_checkout(
     State(state): State<AppState>,
     Extension(session): Extension<CustomerSession>,
-    Json(body): Json<CheckoutRequestBody>,
+    ValidatedJson(body): ValidatedJson<CheckoutRequestBody>,
 ) -> Result<Json<CheckoutRequestResponse>, HttpError> {
-    let user_id = session.user_id();
-    let checkout_token = checkout::CheckoutToken::create(user_id, body.order_id, &state.db).await?;
+    checkout_order(session.user_id(), body.order_id, &state).await
+}
+
+#[derive(Deserialize)]
+/// A request to POST /checkout/guest.
+struct GuestCheckoutRequestBody {
+    /// The guest order token for the order being checked out.
+    token: String,
+}
+
+/// Checkout an order placed by a guest, authorised by its guest order token
+/// rather than a session.
+async fn do_guest_checkout(
+    State(state): State<AppState>,
+    ValidatedJson(body): ValidatedJson<GuestCheckoutRequestBody>,
+) -> Result<Json<CheckoutRequestResponse>, HttpError> {
+    let mut session_store_conn = state.session_conn();
+    let order = orders::get_guest_order(&body.token, state.db(), &mut session_store_conn)
+        .await?
+        .ok_or_else(|| {
+            tracing::warn!("Attempted to checkout a guest order with an invalid token.");
+            deny_or_not_found(false)
+        })?
+        .order;
+    checkout_order(order.user_id(), order.id(), &state).await
+}
+
+/// Checkout an order on behalf of the given user, confirming it immediately
+/// if Stripe is disabled.
+async fn checkout_order(
+    user_id: Uuid,
+    order_id: Uuid,
+    state: &AppState,
+) -> Result<Json<CheckoutRequestResponse>, HttpError> {
+    let checkout_token = checkout::CheckoutToken::create(user_id, order_id, &state.db).await?;
     if cfg!(not(feature = "stripe")) {
-        println!(
-            "Stripe is disabled, unconditionally confirming order {} without payment.",
-            body.order_id
+        tracing::info!(
+            "Stripe is disabled, unconditionally confirming order {order_id} without payment."
         );
-        orders::confirm_order(body.order_id, &state.db).await?;
+        orders::confirm_order(order_id, Some(user_id), &state.db).await?;
         Ok(Json(CheckoutRequestResponse {
             payment_required: false,
             payment_info: None,
@@ -115,12 +149,28 @@ impl From<orders::errors::OrderConfirmationError> for HttpError {
         match error {
             orders::errors::OrderConfirmationError::DatabaseError(err) => err.into(),
             orders::errors::OrderConfirmationError::OrderNonExistent(order_id) => {
-                eprintln!("Attempted to confirm order {order_id}, which does not exist");
+                tracing::warn!("Attempted to confirm order {order_id}, which does not exist");
                 Self::new(
                     StatusCode::NOT_FOUND,
                     Some(format!("Order {order_id} not found.")),
                 )
             }
+            orders::errors::OrderConfirmationError::ConcurrencyConflict => {
+                tracing::warn!("Order was modified concurrently while being confirmed.");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from("Order was modified concurrently, try again.")),
+                )
+            }
+            orders::errors::OrderConfirmationError::InsufficientStock(product_id) => {
+                tracing::warn!(
+                    "Attempted to confirm an order containing sold out product {product_id}"
+                );
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(format!("Product {product_id} is no longer available")),
+                )
+            }
         }
     }
 }
@@ -129,20 +179,115 @@ impl From<checkout::errors::CheckoutTokenCreateError> for HttpError {
         match error {
             checkout::errors::CheckoutTokenCreateError::DatabaseError(err) => err.into(),
             checkout::errors::CheckoutTokenCreateError::Unauthorized { user_id, order_id } => {
-                eprintln!(
+                tracing::warn!(
                     "User {user_id} made an unauthorized attempt to checkout for order {order_id}"
                 );
-                Self::from(StatusCode::FORBIDDEN)
+                deny_or_not_found(false)
             }
             checkout::errors::CheckoutTokenCreateError::OrderNonExistent { user_id, order_id } => {
-                eprintln!("User {user_id} attempted to checkout for non-existent order {order_id}");
-                Self::from(StatusCode::FORBIDDEN) // not 404 to prevent enumerating valid orders
+                tracing::warn!(
+                    "User {user_id} attempted to checkout for non-existent order {order_id}"
+                );
+                deny_or_not_found(false)
+            }
+            checkout::errors::CheckoutTokenCreateError::ItemNoLongerAvailable { product_id } => {
+                tracing::warn!(
+                    "Attempted to checkout an order containing unavailable product {product_id}"
+                );
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(format!("Product {product_id} is no longer available")),
+                )
             }
             #[cfg(feature = "stripe")]
             checkout::errors::CheckoutTokenCreateError::StripeError(err) => {
-                eprintln!("Stripe error when initialising checkout: {err}");
+                tracing::error!("Stripe error when initialising checkout: {err}");
                 Self::from(StatusCode::INTERNAL_SERVER_ERROR) // don't want to accidentally leak ANYTHING about stripe
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use crate::{
+        db::{
+            models::product::{Product, ProductInsert},
+            ConnectionPool,
+        },
+        testing::{store_user, TestApp},
+    };
+
+    /// Log a customer in, and place an order for one of a product, returning
+    /// the order's ID.
+    async fn place_order(app: &mut TestApp, product_id: Uuid) -> String {
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let body = json!({ "products": [{ "product": product_id, "count": 1u32 }] });
+        let response = app.post("/orders", &body).await;
+        assert_eq!(response.status, StatusCode::OK);
+        response.string_at("/id")
+    }
+
+    /// Checking out an order is a conflict if a product in it has been
+    /// unlisted since the order was placed.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn unlisted_item_blocks_checkout(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(&db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        let mut app = TestApp::new(db_conn.clone());
+        let order_id = place_order(&mut app, product_id).await;
+        sqlx::query!(
+            "UPDATE product SET listed = false WHERE id = $1",
+            product_id
+        )
+        .execute(&db_conn)
+        .await
+        .expect("Product should be unlisted");
+        let response = app
+            .post("/checkout", &json!({ "order_id": order_id }))
+            .await;
+        assert_eq!(response.status, StatusCode::CONFLICT);
+        assert_eq!(
+            response.string_at("/message"),
+            format!("Product {product_id} is no longer available")
+        );
+    }
+
+    /// Checking out an order is a conflict if a product in it has sold out
+    /// since the order was placed.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn out_of_stock_item_blocks_checkout(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(&db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        Product::adjust_stock(product_id, 1, &db_conn)
+            .await
+            .expect("Stock should be adjusted");
+        let mut app = TestApp::new(db_conn.clone());
+        let order_id = place_order(&mut app, product_id).await;
+        Product::adjust_stock(product_id, -1, &db_conn)
+            .await
+            .expect("Stock should be adjusted");
+        let response = app
+            .post("/checkout", &json!({ "order_id": order_id }))
+            .await;
+        assert_eq!(response.status, StatusCode::CONFLICT);
+        assert_eq!(
+            response.string_at("/message"),
+            format!("Product {product_id} is no longer available")
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! Routes for applying coupon codes, interacts with the coupon service.
+use axum::{
+    extract::State, http::StatusCode, middleware::from_fn_with_state, routing::post, Extension,
+    Json, Router,
+};
+use serde::Deserialize;
+
+use crate::{
+    middleware::session::session_middleware,
+    services::{
+        coupons::{self, CouponQuote, CouponTarget},
+        sessions::CustomerSession,
+    },
+    state::AppState,
+    utils::{access::deny_or_not_found, httperror::HttpError, json::ValidatedJson},
+};
+
+/// Create a router for routes under the coupon service.
+pub fn create_router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/validate", post(validate_coupon))
+        .layer(from_fn_with_state(
+            state.clone(),
+            session_middleware::<CustomerSession>,
+        ))
+}
+
+#[derive(Deserialize)]
+/// A request to POST /coupons/validate.
+struct ValidateCouponRequest {
+    /// The coupon code to apply.
+    code: String,
+    /// What the coupon is applied to, either `order_id` or a tentative `total`.
+    #[serde(flatten)]
+    target: CouponTarget,
+}
+
+/// Compute the discount a coupon code would give, so that it can be shown
+/// before checkout. Nothing is redeemed. Rate-limited per user, since
+/// otherwise it could be used to guess valid codes.
+async fn validate_coupon(
+    State(state): State<AppState>,
+    Extension(session): Extension<CustomerSession>,
+    ValidatedJson(body): ValidatedJson<ValidateCouponRequest>,
+) -> Result<Json<CouponQuote>, HttpError> {
+    let user_id = session.user_id();
+    if state
+        .session_conn()
+        .bruteforce_timeout(&format!("coupon-validate:{user_id}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!("User {user_id} is rate-limited for excessive coupon validation attempts.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many coupon attempts.")),
+        ));
+    }
+    Ok(Json(
+        coupons::validate_coupon(user_id, &body.code, body.target, state.db()).await?,
+    ))
+}
+
+impl From<coupons::errors::CouponValidationError> for HttpError {
+    #[inline]
+    fn from(error: coupons::errors::CouponValidationError) -> Self {
+        match error {
+            coupons::errors::CouponValidationError::DatabaseError(err) => err.into(),
+            coupons::errors::CouponValidationError::NonExistent => Self::new(
+                StatusCode::NOT_FOUND,
+                Some(String::from("Invalid coupon code")),
+            ),
+            coupons::errors::CouponValidationError::Expired => Self::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some(String::from("Coupon code has expired")),
+            ),
+            coupons::errors::CouponValidationError::Exhausted => Self::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some(String::from("Coupon code has no redemptions remaining")),
+            ),
+            coupons::errors::CouponValidationError::OrderNonExistent { .. } => {
+                tracing::warn!("{error}");
+                deny_or_not_found(false)
+            }
+            coupons::errors::CouponValidationError::Overflow(err) => {
+                tracing::error!("Overflow computing coupon discount: {err}");
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Some(String::from("Total is too large")),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    use crate::{
+        db::{models::product::ProductInsert, ConnectionPool},
+        testing::{store_user, TestApp, TestResponse},
+    };
+
+    /// Store a coupon, expiring `expires_in` seconds from now if given.
+    async fn store_coupon(
+        code: &str,
+        expires_in: Option<i32>,
+        max_redemptions: Option<i32>,
+        redemptions: i32,
+        db_conn: &ConnectionPool,
+    ) {
+        sqlx::query(
+            "INSERT INTO coupon (code, percent_off, expires_at, max_redemptions, redemptions)
+            VALUES ($1, 10, (now() AT TIME ZONE 'utc') + make_interval(secs => $2), $3, $4)",
+        )
+        .bind(code)
+        .bind(expires_in)
+        .bind(max_redemptions)
+        .bind(redemptions)
+        .execute(db_conn)
+        .await
+        .expect("Coupon should be stored");
+    }
+
+    /// Place an order for one of a new product as the user logged in to an
+    /// app, returning the order's ID and the amount charged for it.
+    async fn place_order(app: &mut TestApp, db_conn: &ConnectionPool) -> (String, i64) {
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        let response = app
+            .post(
+                "/orders",
+                &json!({ "products": [{ "product": product_id, "count": 1u32 }] }),
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::OK);
+        let charged = response
+            .json()
+            .pointer("/amount_charged")
+            .and_then(Value::as_i64)
+            .expect("Order should have an amount charged");
+        (response.string_at("/id"), charged)
+    }
+
+    /// An amount in pennies in a response.
+    fn pennies(response: &TestResponse, pointer: &str) -> Option<i64> {
+        response.json().pointer(pointer).and_then(Value::as_i64)
+    }
+
+    /// A valid coupon's discount is computed for a tentative total and for an
+    /// order of the customer's, rounded down, without redeeming the coupon.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn valid_coupon_is_quoted(db_conn: ConnectionPool) {
+        store_coupon("SAVE10", Some(60i32), Some(1i32), 0i32, &db_conn).await;
+        store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn.clone());
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+
+        let total_quote = app
+            .post(
+                "/coupons/validate",
+                &json!({ "code": "SAVE10", "total": 1999u32 }),
+            )
+            .await;
+        assert_eq!(total_quote.status, StatusCode::OK);
+        assert_eq!(pennies(&total_quote, "/subtotal"), Some(1999i64));
+        assert_eq!(pennies(&total_quote, "/discount"), Some(199i64));
+        assert_eq!(pennies(&total_quote, "/total"), Some(1800i64));
+
+        // Still redeemable, as validating it redeemed nothing.
+        let (order_id, charged) = place_order(&mut app, &db_conn).await;
+        let order_quote = app
+            .post(
+                "/coupons/validate",
+                &json!({ "code": "SAVE10", "order_id": order_id }),
+            )
+            .await;
+        assert_eq!(order_quote.status, StatusCode::OK);
+        assert_eq!(pennies(&order_quote, "/subtotal"), Some(charged));
+        assert_eq!(
+            pennies(&order_quote, "/discount"),
+            Some(charged.saturating_div(10i64))
+        );
+    }
+
+    /// Unknown, expired and exhausted codes are each rejected, as are other
+    /// customers' orders, indistinguishably from orders which don't exist.
+    /// Further attempts are rate limited.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn invalid_coupons_are_rejected(db_conn: ConnectionPool) {
+        store_coupon("SAVE10", None, None, 0i32, &db_conn).await;
+        store_coupon("EXPIRED", Some(-60i32), None, 0i32, &db_conn).await;
+        store_coupon("USED", None, Some(1i32), 1i32, &db_conn).await;
+        store_user("alice@example.com", &db_conn).await;
+        store_user("bob@example.com", &db_conn).await;
+        let mut bob = TestApp::new(db_conn.clone());
+        assert_eq!(bob.log_in("bob@example.com").await.status, StatusCode::OK);
+        let (bobs_order, _) = place_order(&mut bob, &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+
+        let attempts = [
+            (
+                json!({ "code": "NOPE", "total": 1000u32 }),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                json!({ "code": "EXPIRED", "total": 1000u32 }),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                json!({ "code": "USED", "total": 1000u32 }),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                json!({ "code": "SAVE10", "order_id": bobs_order }),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                json!({ "code": "SAVE10", "total": 1000u32 }),
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+        ];
+        for (body, status) in attempts {
+            let response = app.post("/coupons/validate", &body).await;
+            assert_eq!(response.status, status, "{body}");
+        }
+        let unknown_order = json!({ "code": "SAVE10", "order_id": Uuid::new_v4() });
+        assert_eq!(
+            bob.post("/coupons/validate", &unknown_order).await.status,
+            StatusCode::FORBIDDEN
+        );
+    }
+}
@@ -1,7 +1,9 @@
 //! API routes within the application. Mainly exposes sub-routers which should
 //! be nested with the main Axum router.
 pub mod auth;
+pub mod capabilities;
 pub mod checkout;
+pub mod coupons;
 pub mod orders;
 pub mod products;
 pub mod registration;
@@ -1,36 +1,63 @@
 //! Routes for handling order creation and access, interacts with the order service
+use alloc::sync::Arc;
+
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
-    middleware::from_fn_with_state,
-    routing::{delete, get, post},
+    body::Body,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        request::Parts,
+        HeaderMap, StatusCode,
+    },
+    middleware::{from_fn, from_fn_with_state},
+    response::IntoResponse,
+    routing::{delete, get, patch, post},
     Extension, Json, Router,
 };
+use futures_util::stream;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
     constants::api::API_URI_PREFIX,
-    db::models::apporder::{AppOrder, AppOrderSearchParameters},
-    middleware::session::session_middleware,
+    db::models::{
+        apporder::{
+            AppOrder, AppOrderCursor, AppOrderSearchParameters, AppOrderStatus, ShippingMethod,
+        },
+        appuser::AppUserInsert,
+        order_event::OrderEvent,
+    },
+    middleware::session::{reject_impersonation, session_middleware},
     services::{
-        orders::{self},
+        orders::{self, AppOrderWithItems, Invoice, OrderPreview, Reorder},
         sessions::{AdministratorSession, CustomerSession, GenericAuthenticatedSession},
     },
     state::AppState,
-    utils::httperror::HttpError,
+    utils::{
+        access::deny_or_not_found,
+        client_ip::client_ip,
+        httperror::HttpError,
+        json::ValidatedJson,
+        pagination::{self, Pagination},
+    },
 };
 
 /// TODO: add documentation
 pub fn create_router(state: &AppState) -> Router<AppState> {
     let customer = Router::new()
         .route("/", post(create_order))
+        .route("/preview", post(preview_order))
+        .route("/{order_id}", patch(update_order))
+        .route("/{order_id}/reorder", post(reorder))
         .layer(from_fn_with_state(
             state.clone(),
             session_middleware::<CustomerSession>,
         ));
     let administrator = Router::new()
+        .route("/export.csv", get(export_orders))
         .route("/{order_id}/fulfil", post(fulfil_order))
+        .route("/{order_id}/fulfil-items", post(fulfil_order_items))
+        .route("/{order_id}/refund", post(refund_order))
         .layer(from_fn_with_state(
             state.clone(),
             session_middleware::<AdministratorSession>,
@@ -38,12 +65,28 @@ pub fn create_router(state: &AppState) -> Router<AppState> {
     let authenticated = Router::new()
         .route("/", get(search_orders))
         .route("/{order_id}", get(retrieve_order))
+        .route("/{order_id}/invoice", get(retrieve_invoice))
+        .route("/{order_id}/timeline", get(retrieve_timeline))
+        .layer(from_fn_with_state(
+            state.clone(),
+            session_middleware::<GenericAuthenticatedSession>,
+        ));
+    // Nothing may be deleted while an administrator is impersonating a user.
+    let deletion = Router::new()
         .route("/{order_id}", delete(delete_order))
+        .layer(from_fn(reject_impersonation))
         .layer(from_fn_with_state(
             state.clone(),
             session_middleware::<GenericAuthenticatedSession>,
         ));
-    customer.merge(administrator).merge(authenticated)
+    let guest = Router::new()
+        .route("/guest", post(create_guest_order))
+        .route("/guest/{token}", get(retrieve_guest_order));
+    customer
+        .merge(administrator)
+        .merge(authenticated)
+        .merge(deletion)
+        .merge(guest)
 }
 
 #[derive(Deserialize)]
@@ -51,6 +94,11 @@ pub fn create_router(state: &AppState) -> Router<AppState> {
 struct CreateOrderRequest {
     /// TODO: add documentation
     products: Vec<CreateOrderRequestProductEntry>,
+    /// The code of a coupon to redeem for the order, if any.
+    coupon: Option<String>,
+    /// How the order is to be shipped. Defaults to standard shipping.
+    #[serde(default)]
+    shipping_method: ShippingMethod,
 }
 
 #[derive(Deserialize)]
@@ -66,9 +114,31 @@ struct CreateOrderRequestProductEntry {
 async fn create_order(
     State(state): State<AppState>,
     Extension(session): Extension<CustomerSession>,
-    Json(body): Json<CreateOrderRequest>,
+    ValidatedJson(body): ValidatedJson<CreateOrderRequest>,
 ) -> Result<Json<AppOrder>, HttpError> {
     let user_id = session.user_id();
+    if let Some(reset_after) = state.session_conn().order_rate_limit(user_id).await? {
+        tracing::warn!("User {user_id} is rate-limited for excessive order creation.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(format!(
+                "Too many orders placed. Try again in {reset_after} seconds."
+            )),
+        ));
+    }
+    if body.coupon.is_some()
+        && state
+            .session_conn()
+            .bruteforce_timeout(&format!("coupon-validate:{user_id}"))
+            .await?
+            .timed_out
+    {
+        tracing::warn!("User {user_id} is rate-limited for excessive coupon validation attempts.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many coupon attempts.")),
+        ));
+    }
     Ok(Json(
         orders::create_order(
             user_id,
@@ -76,152 +146,523 @@ async fn create_order(
                 .into_iter()
                 .map(|entry| (entry.product, entry.count))
                 .collect(),
+            body.coupon.as_deref(),
+            body.shipping_method,
             &state.db,
         )
         .await?,
     ))
 }
 
+#[derive(Deserialize)]
+/// A request to POST /orders/preview.
+struct PreviewOrderRequest {
+    /// The products to be ordered, as when creating an order.
+    products: Vec<CreateOrderRequestProductEntry>,
+    /// The code of a coupon to apply, if any.
+    coupon: Option<String>,
+    /// The shipping method to price the order with. Defaults to standard
+    /// shipping.
+    #[serde(default)]
+    shipping_method: ShippingMethod,
+}
+
+/// Show what an order of the given products would cost, including any coupon
+/// discount, tax and shipping, without placing it, along with the shipping
+/// methods which could be chosen for it. Rate-limited alongside
+/// coupon validation when a coupon is given, since otherwise it could be used
+/// to guess valid codes.
+async fn preview_order(
+    State(state): State<AppState>,
+    Extension(session): Extension<CustomerSession>,
+    ValidatedJson(body): ValidatedJson<PreviewOrderRequest>,
+) -> Result<Json<OrderPreview>, HttpError> {
+    let user_id = session.user_id();
+    if body.coupon.is_some()
+        && state
+            .session_conn()
+            .bruteforce_timeout(&format!("coupon-validate:{user_id}"))
+            .await?
+            .timed_out
+    {
+        tracing::warn!("User {user_id} is rate-limited for excessive coupon validation attempts.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many coupon attempts.")),
+        ));
+    }
+    let product_counts: Vec<(Uuid, u32)> = body
+        .products
+        .into_iter()
+        .map(|entry| (entry.product, entry.count))
+        .collect();
+    Ok(Json(
+        orders::preview_order(
+            user_id,
+            &product_counts,
+            body.coupon.as_deref(),
+            body.shipping_method,
+            state.db(),
+        )
+        .await?,
+    ))
+}
+
+#[derive(Deserialize)]
+/// A request to PATCH /orders/{id}.
+struct UpdateOrderRequest {
+    /// The new quantities of products in the order. A count of zero removes
+    /// the product from the order.
+    products: Vec<CreateOrderRequestProductEntry>,
+}
+
+/// Change the quantities of items in one of the customer's orders, which must
+/// not yet have been confirmed.
+async fn update_order(
+    State(state): State<AppState>,
+    OwnedOrder(order): OwnedOrder,
+    ValidatedJson(body): ValidatedJson<UpdateOrderRequest>,
+) -> Result<Json<RetrieveOrderResponse>, HttpError> {
+    let updated = orders::update_order_items(
+        order,
+        body.products
+            .into_iter()
+            .map(|entry| (entry.product, entry.count))
+            .collect(),
+        state.db(),
+    )
+    .await?;
+    Ok(Json(RetrieveOrderResponse::from(updated)))
+}
+
+/// Place a new order for the still-available items of one of the customer's
+/// previous orders.
+async fn reorder(
+    State(state): State<AppState>,
+    Extension(session): Extension<CustomerSession>,
+    Path(order_id): Path<Uuid>,
+) -> Result<Json<Reorder>, HttpError> {
+    Ok(Json(
+        orders::reorder(session.user_id(), order_id, state.db()).await?,
+    ))
+}
+
+#[derive(Deserialize)]
+/// A request to POST /orders/guest.
+struct CreateGuestOrderRequest {
+    /// The guest's details, including the address to ship the order to.
+    user_data: AppUserInsert,
+    /// The products being ordered, and how many of each.
+    products: Vec<CreateOrderRequestProductEntry>,
+    /// How the order is to be shipped. Defaults to standard shipping.
+    #[serde(default)]
+    shipping_method: ShippingMethod,
+}
+
+#[derive(Serialize)]
+/// The response to POST /orders/guest.
+struct CreateGuestOrderResponse {
+    /// The newly created order.
+    order: AppOrder,
+    /// A token granting access to only this order, used in place of a session
+    /// to view and pay for it.
+    token: String,
+}
+
+/// Create an order for a guest who does not have an account. Each client may
+/// only place `ORDER_RATE_LIMIT_ATTEMPTS` guest orders per window, since no
+/// login is needed to place one.
+async fn create_guest_order(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    ValidatedJson(body): ValidatedJson<CreateGuestOrderRequest>,
+) -> Result<Json<CreateGuestOrderResponse>, HttpError> {
+    let client_ip = client_ip(&headers)?;
+    let mut session_store_conn = state.session_conn();
+    if let Some(reset_after) = session_store_conn.guest_order_rate_limit(client_ip).await? {
+        tracing::warn!("Client {client_ip} is rate-limited for excessive guest order creation.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(format!(
+                "Too many orders placed. Try again in {reset_after} seconds."
+            )),
+        ));
+    }
+    let (order, token) = orders::create_guest_order(
+        body.user_data,
+        body.products
+            .into_iter()
+            .map(|entry| (entry.product, entry.count))
+            .collect(),
+        body.shipping_method,
+        state.db(),
+        &mut session_store_conn,
+        Arc::clone(&state.email_sender),
+    )
+    .await?;
+    Ok(Json(CreateGuestOrderResponse { order, token }))
+}
+
+/// Retrieve the order which a guest order token grants access to.
+async fn retrieve_guest_order(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Json<RetrieveOrderResponse>, HttpError> {
+    let mut session_store_conn = state.session_conn();
+    let order = orders::get_guest_order(&token, state.db(), &mut session_store_conn)
+        .await?
+        .ok_or_else(|| {
+            tracing::warn!("Attempted to view a guest order with an invalid token.");
+            deny_or_not_found(false)
+        })?;
+    Ok(Json(RetrieveOrderResponse::from(order)))
+}
+
+#[derive(Deserialize)]
+/// Options for GET /orders which control what is returned, rather than which
+/// orders match.
+struct OrderSearchOptions {
+    /// Include the total quantity of items in each order. Off by default,
+    /// since it needs an extra query.
+    #[serde(default)]
+    include_item_counts: bool,
+}
+
+#[derive(Serialize)]
+/// An order returned by a search, along with its total item quantity if
+/// requested.
+struct OrderSearchResult {
+    /// The order itself.
+    #[serde(flatten)]
+    order: AppOrder,
+    /// The total quantity of items in the order, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    item_count: Option<i64>,
+}
+
 #[derive(Serialize)]
 /// TODO: add documentation
 struct OrderSearchResponse {
     /// TODO: add documentation
-    orders: Vec<AppOrder>,
+    orders: Vec<OrderSearchResult>,
+    /// The page of results which was returned.
+    pagination: Pagination,
+    /// The cursor to pass as `after` to fetch the next page, if there may be one.
+    next_cursor: Option<String>,
 }
 
 async fn search_orders(
     State(state): State<AppState>,
     Extension(session): Extension<GenericAuthenticatedSession>,
     Query(params): Query<AppOrderSearchParameters>,
+    Query(options): Query<OrderSearchOptions>,
+    pagination: Pagination,
 ) -> Result<Json<OrderSearchResponse>, HttpError> {
+    let orders = match session {
+        GenericAuthenticatedSession::Customer(customer_session) => {
+            orders::search_orders(
+                AppOrderSearchParameters {
+                    user_id: Some(customer_session.user_id()),
+                    ..params
+                },
+                Some(pagination),
+                &state.db,
+            )
+            .await?
+        }
+        GenericAuthenticatedSession::Administrator(_) => {
+            orders::search_orders(params, Some(pagination), &state.db).await?
+        }
+    };
+    let next_cursor =
+        pagination::next_cursor(&orders, pagination, |order| AppOrderCursor::from(order));
+    let item_counts = if options.include_item_counts {
+        Some(orders::count_items(&orders, &state.db).await?)
+    } else {
+        None
+    };
+    let results = orders
+        .into_iter()
+        .map(|order| {
+            let item_count = item_counts
+                .as_ref()
+                .map(|counts| counts.get(&order.id()).copied().unwrap_or(0));
+            OrderSearchResult { order, item_count }
+        })
+        .collect();
     Ok(Json(OrderSearchResponse {
-        orders: match session {
-            GenericAuthenticatedSession::Customer(customer_session) => {
-                orders::search_orders(
-                    AppOrderSearchParameters {
-                        user_id: Some(customer_session.user_id()),
-                        status: params.status,
-                    },
-                    &state.db,
-                )
-                .await?
-            }
-            GenericAuthenticatedSession::Administrator(_) => {
-                orders::search_orders(params, &state.db).await?
-            }
-        },
+        orders: results,
+        pagination,
+        next_cursor,
     }))
 }
 
+/// Download every order matching a date range and status as CSV, e.g. for
+/// accounting. The response is streamed, so it is not buffered in memory
+/// however many orders match.
+async fn export_orders(
+    State(state): State<AppState>,
+    Query(params): Query<AppOrderSearchParameters>,
+) -> impl IntoResponse {
+    let receiver = orders::export_csv(params, state.read_db);
+    let body = Body::from_stream(stream::unfold(receiver, |mut rows| async move {
+        let row = rows.recv().await?.inspect_err(|err| {
+            tracing::error!("Aborting order export, database error: {err}");
+        });
+        Some((row, rows))
+    }));
+    (
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"orders.csv\""),
+        ],
+        body,
+    )
+}
+
 #[derive(Serialize)]
 /// TODO: add documentation
 struct RetrieveOrderResponse {
     /// TODO: add documentation
     order: AppOrder,
-    /// TODO: add documentation
-    items: Vec<(String, u32)>,
+    /// The URI of each product in the order, how many were ordered, and the
+    /// product's name when it was ordered.
+    items: Vec<(String, u32, String)>,
+    /// The total shipping weight of the order in grams, if every product's
+    /// weight is known.
+    total_weight_grams: Option<i64>,
 }
 
-/// TODO: add documentation
-async fn retrieve_order(
-    State(state): State<AppState>,
-    Extension(session): Extension<GenericAuthenticatedSession>,
-    Path(order_id): Path<Uuid>,
-) -> Result<Json<RetrieveOrderResponse>, HttpError> {
-    let maybe_order = orders::get_order_with_items(order_id, &state.db)
-        .await?
-        .map(|order| RetrieveOrderResponse {
+impl From<AppOrderWithItems> for RetrieveOrderResponse {
+    fn from(order: AppOrderWithItems) -> Self {
+        Self {
             order: order.order,
             items: order
                 .items
-                .iter()
-                .map(|&(product_id, count)| {
-                    (format!("{}/products/{product_id}", *API_URI_PREFIX), count)
+                .into_iter()
+                .map(|(product_id, count, name)| {
+                    (
+                        format!("{}/products/{product_id}", *API_URI_PREFIX),
+                        count,
+                        name,
+                    )
                 })
                 .collect(),
-        });
-    let order = match session {
-        GenericAuthenticatedSession::Administrator(_) => maybe_order.map_or_else(
-            || {
-                eprintln!("Administrator request to view order {order_id}, which does not exist.");
-                Err(HttpError::new(
-                    StatusCode::NOT_FOUND,
-                    Some(format!("Order {order_id} not found")),
-                ))
-            },
-            Ok,
-        ),
-        GenericAuthenticatedSession::Customer(customer) => {
-            match maybe_order {
-                None => {
-                    eprintln!("Customer with ID {} attempted to view order {order_id}, which does not exist.", customer.user_id());
-                    Err(StatusCode::FORBIDDEN.into()) // 401 not 404 to prevent enumerating valid order IDs.
-                }
-                Some(order) => {
-                    if order.order.user_id() == customer.user_id() {
-                        Ok(order)
-                    } else {
-                        eprintln!(
-                            "User {} attempted to view order {} owned by {}.",
-                            customer.user_id(),
-                            order_id,
-                            order.order.user_id()
+            total_weight_grams: order.total_weight_grams,
+        }
+    }
+}
+
+/// An order, loaded from the `order_id` path parameter, which the requesting
+/// session may access. Administrators may access any order, and customers
+/// only their own. Any other request is rejected following the
+/// `deny_or_not_found` policy, so handlers taking an `OwnedOrder` need no
+/// ownership checks of their own.
+struct OwnedOrder(AppOrder);
+
+impl FromRequestParts<AppState> for OwnedOrder {
+    type Rejection = HttpError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Path(order_id) = Path::<Uuid>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                tracing::warn!("Failed to extract order ID from path: {err}");
+                HttpError::new(err.status(), Some(err.body_text()))
+            })?;
+        let session = parts
+            .extensions
+            .get::<GenericAuthenticatedSession>()
+            .cloned()
+            .or_else(|| {
+                parts
+                    .extensions
+                    .get::<CustomerSession>()
+                    .cloned()
+                    .map(GenericAuthenticatedSession::from)
+            })
+            .ok_or_else(|| {
+                tracing::warn!(
+                    "Attempted to extract an owned order from a request without a session."
+                );
+                HttpError::from(StatusCode::INTERNAL_SERVER_ERROR)
+            })?;
+        let maybe_order = orders::get_order(order_id, state.db()).await?;
+        match session {
+            GenericAuthenticatedSession::Administrator(_) => maybe_order.ok_or_else(|| {
+                tracing::warn!("Administrator requested order {order_id}, which does not exist.");
+                deny_or_not_found(true)
+            }),
+            GenericAuthenticatedSession::Customer(customer) => {
+                let user_id = customer.user_id();
+                match maybe_order {
+                    None => {
+                        tracing::warn!("Customer with ID {user_id} requested order {order_id}, which does not exist.");
+                        Err(deny_or_not_found(false))
+                    }
+                    Some(order) if order.user_id() != user_id => {
+                        tracing::warn!(
+                            "User {user_id} requested order {order_id} owned by {}.",
+                            order.user_id()
                         );
-                        Err(StatusCode::FORBIDDEN.into())
+                        Err(deny_or_not_found(false))
                     }
+                    Some(order) => Ok(order),
                 }
             }
         }
-    }?;
-    Ok(Json(order))
+        .map(Self)
+    }
 }
 
 /// TODO: add documentation
+async fn retrieve_order(
+    State(state): State<AppState>,
+    OwnedOrder(order): OwnedOrder,
+) -> Result<Json<RetrieveOrderResponse>, HttpError> {
+    Ok(Json(RetrieveOrderResponse::from(
+        orders::with_items(order, &state.db).await?,
+    )))
+}
+
+/// Retrieve a structured invoice for an order. Customers may only retrieve
+/// invoices for their own orders.
+async fn retrieve_invoice(
+    State(state): State<AppState>,
+    OwnedOrder(order): OwnedOrder,
+) -> Result<Json<Invoice>, HttpError> {
+    let order_id = order.id();
+    orders::get_invoice(order_id, &state.db)
+        .await?
+        .map(Json)
+        .ok_or_else(|| {
+            tracing::warn!("Order {order_id} was deleted while its invoice was being produced.");
+            HttpError::new(
+                StatusCode::NOT_FOUND,
+                Some(format!("Order {order_id} not found")),
+            )
+        })
+}
+
+#[derive(Serialize)]
+/// The response to GET /orders/{id}/timeline.
+struct TimelineResponse {
+    /// Every status the order has passed through, oldest first.
+    events: Vec<OrderEvent>,
+}
+
+/// Retrieve the timeline of an order, i.e. every status it has passed through
+/// with when and by whom. Customers may only retrieve the timelines of their
+/// own orders.
+async fn retrieve_timeline(
+    State(state): State<AppState>,
+    OwnedOrder(order): OwnedOrder,
+) -> Result<Json<TimelineResponse>, HttpError> {
+    let events = orders::get_timeline(order.id(), &state.db).await?;
+    Ok(Json(TimelineResponse { events }))
+}
+
+/// Delete an order, which customers may only do to their own. Orders which
+/// have been confirmed are refused with a 409 for everyone.
 async fn delete_order(
     State(state): State<AppState>,
-    Extension(session): Extension<GenericAuthenticatedSession>,
-    Path(order_id): Path<Uuid>,
+    OwnedOrder(order): OwnedOrder,
 ) -> Result<(), HttpError> {
-    if let GenericAuthenticatedSession::Customer(customer_session) = session {
-        let user_id = customer_session.user_id();
-        let order = orders::get_order(order_id, &state.db)
-            .await?
-            .ok_or_else(|| {
-                eprintln!("Attempted to delete order which does not exist while authenticated as user {user_id}");
-                StatusCode::FORBIDDEN // 401 not 404 to obscure whether this order ID is valid or
-                                      // for another user or not.
-            })?;
-        let order_owner = order.user_id();
-        if user_id != order_owner {
-            eprintln!(
-                "User {user_id} attempted to delete order {order_id} owned by {order_owner}."
-            );
-            return Err(StatusCode::FORBIDDEN.into());
-        }
-    }
-    orders::delete_order(order_id, &state.db).await?;
+    orders::delete_order(order.id(), &state.db).await?;
     Ok(())
 }
 
 /// TODO: add documentation
 async fn fulfil_order(
     State(state): State<AppState>,
+    Extension(session): Extension<AdministratorSession>,
     Path(order_id): Path<Uuid>,
 ) -> Result<(), HttpError> {
-    orders::fulfil_order(order_id, &state.db).await?;
+    orders::fulfil_order(order_id, session.user_id(), &state.db).await?;
     Ok(())
 }
 
+#[derive(Deserialize)]
+/// A request to POST /orders/{id}/fulfil-items.
+struct FulfilItemsRequest {
+    /// The items being fulfilled, and how many of each.
+    items: Vec<FulfilItemsRequestEntry>,
+}
+
+#[derive(Deserialize)]
+/// A single item being fulfilled within a `FulfilItemsRequest`.
+struct FulfilItemsRequestEntry {
+    /// The ID of the product being fulfilled.
+    product: Uuid,
+    /// How many of the product are being fulfilled.
+    count: u32,
+}
+
+#[derive(Serialize)]
+/// The response to POST /orders/{id}/fulfil-items.
+struct FulfilItemsResponse {
+    /// The order's status after the items were fulfilled.
+    status: AppOrderStatus,
+}
+
+/// Fulfil some quantity of specific items within an order.
+async fn fulfil_order_items(
+    State(state): State<AppState>,
+    Extension(session): Extension<AdministratorSession>,
+    Path(order_id): Path<Uuid>,
+    ValidatedJson(body): ValidatedJson<FulfilItemsRequest>,
+) -> Result<Json<FulfilItemsResponse>, HttpError> {
+    let status = orders::fulfil_items(
+        order_id,
+        body.items
+            .into_iter()
+            .map(|entry| (entry.product, entry.count))
+            .collect(),
+        session.user_id(),
+        &state.db,
+    )
+    .await?;
+    Ok(Json(FulfilItemsResponse { status }))
+}
+
+#[derive(Deserialize)]
+/// A request to POST /orders/{id}/refund.
+struct RefundRequest {
+    /// The amount in pennies to refund. Everything not yet refunded if unset.
+    #[serde(default)]
+    amount: Option<u32>,
+}
+
+/// Refund some or all of an order's payment.
+async fn refund_order(
+    State(state): State<AppState>,
+    Extension(session): Extension<AdministratorSession>,
+    Path(order_id): Path<Uuid>,
+    ValidatedJson(body): ValidatedJson<RefundRequest>,
+) -> Result<Json<AppOrder>, HttpError> {
+    let order = orders::refund_order(order_id, body.amount, session.user_id(), &state.db).await?;
+    tracing::info!(
+        "Administrator {} refunded order {order_id}, {} of {} pennies now refunded",
+        session.user_id(),
+        order.refunded_amount,
+        order.amount_charged
+    );
+    Ok(Json(order))
+}
+
 impl From<orders::errors::OrderCreationError> for HttpError {
     fn from(error: orders::errors::OrderCreationError) -> Self {
         match error {
             orders::errors::OrderCreationError::DatabaseError(err) => err.into(),
             orders::errors::OrderCreationError::UserNonExistent(user_id) => {
-                eprintln!("Attempted to create an order while authenticated as user {user_id} who does not exist.");
+                tracing::warn!("Attempted to create an order while authenticated as user {user_id} who does not exist.");
                 Self::from(StatusCode::UNAUTHORIZED)
             }
             orders::errors::OrderCreationError::ProductNonExistent(product_id) => {
-                eprintln!(
+                tracing::warn!(
                     "Attempted to create an order containing product {product_id} which does not exist."
                 );
                 Self::new(
@@ -230,12 +671,76 @@ impl From<orders::errors::OrderCreationError> for HttpError {
                 )
             }
             orders::errors::OrderCreationError::CostTooLarge => {
-                eprintln!("Order total cost exceeded i64 max");
+                tracing::error!("Order total cost exceeded i64 max");
                 Self::new(
                     StatusCode::BAD_REQUEST,
                     Some(String::from("Order total exceeded max allowable value")),
                 )
             }
+            orders::errors::OrderCreationError::ShippingUnavailable(method) => Self::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some(format!(
+                    "{method} shipping is not available to the delivery address"
+                )),
+            ),
+            orders::errors::OrderCreationError::Coupon(err) => err.into(),
+        }
+    }
+}
+
+impl From<orders::errors::OrderPreviewError> for HttpError {
+    fn from(error: orders::errors::OrderPreviewError) -> Self {
+        match error {
+            orders::errors::OrderPreviewError::Pricing(err) => err.into(),
+            orders::errors::OrderPreviewError::Coupon(err) => err.into(),
+            orders::errors::OrderPreviewError::ItemUnavailable(product_id) => {
+                tracing::warn!("Attempted to preview an order of more of product {product_id} than is in stock.");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(format!(
+                        "Product {product_id} is not in stock in the quantity requested."
+                    )),
+                )
+            }
+        }
+    }
+}
+
+impl From<orders::errors::OrderUpdateError> for HttpError {
+    fn from(error: orders::errors::OrderUpdateError) -> Self {
+        match error {
+            orders::errors::OrderUpdateError::DatabaseError(err) => err.into(),
+            orders::errors::OrderUpdateError::OrderNotUnconfirmed(order_id) => {
+                tracing::warn!("Attempted to change order {order_id}, which is already confirmed.");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from(
+                        "Order is confirmed and can no longer be changed",
+                    )),
+                )
+            }
+            orders::errors::OrderUpdateError::ProductNonExistent(product_id) => {
+                tracing::warn!(
+                    "Attempted to change an order to contain product {product_id} which does not exist."
+                );
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(format!("Product {product_id} not found")),
+                )
+            }
+            orders::errors::OrderUpdateError::CostTooLarge => {
+                tracing::error!("Order total cost exceeded i64 max");
+                Self::new(
+                    StatusCode::BAD_REQUEST,
+                    Some(String::from("Order total exceeded max allowable value")),
+                )
+            }
+            orders::errors::OrderUpdateError::ShippingUnavailable(method) => Self::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some(format!(
+                    "{method} shipping is no longer available to the delivery address"
+                )),
+            ),
         }
     }
 }
@@ -245,12 +750,21 @@ impl From<orders::errors::OrderDeletionError> for HttpError {
         match error {
             orders::errors::OrderDeletionError::DatabaseError(err) => err.into(),
             orders::errors::OrderDeletionError::OrderNonExistent(order_id) => {
-                eprintln!("Attempted to delete order {order_id}, which does not exist.");
+                tracing::warn!("Attempted to delete order {order_id}, which does not exist.");
                 Self::new(
                     StatusCode::NOT_FOUND,
                     Some(format!("Order {order_id} not found")),
                 )
             }
+            orders::errors::OrderDeletionError::OrderConfirmed(order_id) => {
+                tracing::warn!("Refused to delete order {order_id}, which has been confirmed.");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(format!(
+                        "Order {order_id} has been confirmed, so cannot be deleted. Refund it instead."
+                    )),
+                )
+            }
         }
     }
 }
@@ -260,19 +774,769 @@ impl From<orders::errors::OrderFulfilmentError> for HttpError {
         match error {
             orders::errors::OrderFulfilmentError::DatabaseError(err) => err.into(),
             orders::errors::OrderFulfilmentError::OrderNonExistent(order_id) => {
-                eprintln!("Attempted to delete a non-existent order.");
+                tracing::warn!("Attempted to delete a non-existent order.");
                 Self::new(
                     StatusCode::NOT_FOUND,
                     Some(format!("Order {order_id} not found")),
                 )
             }
             orders::errors::OrderFulfilmentError::OrderNotConfirmed(order_id) => {
-                eprintln!("Attempted to fulfil order {order_id} which is not yet confirmed.");
+                tracing::warn!("Attempted to fulfil order {order_id} which is not yet confirmed.");
                 Self::new(
                     StatusCode::BAD_REQUEST,
                     Some(String::from("Order is not confirmed")),
                 )
             }
+            orders::errors::OrderFulfilmentError::ItemNonExistent {
+                order_id,
+                product_id,
+            } => {
+                tracing::warn!(
+                    "Attempted to fulfil product {product_id}, which is not in order {order_id}."
+                );
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(format!("Product {product_id} not found in order")),
+                )
+            }
+            orders::errors::OrderFulfilmentError::OverFulfilment {
+                order_id,
+                product_id,
+            } => {
+                tracing::warn!("Attempted to fulfil more of product {product_id} than were ordered in order {order_id}.");
+                Self::new(
+                    StatusCode::BAD_REQUEST,
+                    Some(format!(
+                        "Cannot fulfil more of product {product_id} than were ordered"
+                    )),
+                )
+            }
+            orders::errors::OrderFulfilmentError::ConcurrencyConflict => {
+                tracing::warn!("Order was modified concurrently while being fulfilled.");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from("Order was modified concurrently, try again")),
+                )
+            }
         }
     }
 }
+
+impl From<orders::errors::InvoiceError> for HttpError {
+    fn from(error: orders::errors::InvoiceError) -> Self {
+        match error {
+            orders::errors::InvoiceError::DatabaseError(err) => err.into(),
+            orders::errors::InvoiceError::UserNonExistent(user_id) => {
+                tracing::warn!("Attempted to produce an invoice for an order placed by user {user_id}, who does not exist.");
+                Self::from(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            orders::errors::InvoiceError::LineTotalTooLarge => {
+                tracing::error!("Invoice line total exceeded i64 max");
+                Self::from(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+impl From<orders::errors::GuestOrderCreationError> for HttpError {
+    fn from(error: orders::errors::GuestOrderCreationError) -> Self {
+        match error {
+            orders::errors::GuestOrderCreationError::StorageError(err) => err.into(),
+            orders::errors::GuestOrderCreationError::OrderCreationError(err) => err.into(),
+            orders::errors::GuestOrderCreationError::EmptyName => {
+                tracing::warn!("Attempted to place a guest order with an empty name.");
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Some(String::from("forename and surname cannot be empty")),
+                )
+            }
+        }
+    }
+}
+
+impl From<orders::errors::ReorderError> for HttpError {
+    fn from(error: orders::errors::ReorderError) -> Self {
+        match error {
+            orders::errors::ReorderError::DatabaseError(err) => err.into(),
+            orders::errors::ReorderError::OrderCreationError(err) => err.into(),
+            orders::errors::ReorderError::OrderNonExistent { user_id, order_id } => {
+                tracing::warn!(
+                    "User {user_id} attempted to reorder order {order_id}, which does not exist."
+                );
+                deny_or_not_found(false)
+            }
+            orders::errors::ReorderError::Unauthorized { user_id, order_id } => {
+                tracing::warn!(
+                    "User {user_id} attempted to reorder order {order_id} owned by another user."
+                );
+                deny_or_not_found(false)
+            }
+            orders::errors::ReorderError::NothingAvailable(order_id) => {
+                tracing::warn!(
+                    "Attempted to reorder order {order_id}, none of whose items are available."
+                );
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Some(String::from(
+                        "None of the order's items are still available",
+                    )),
+                )
+            }
+        }
+    }
+}
+
+impl From<orders::errors::OrderRefundError> for HttpError {
+    fn from(error: orders::errors::OrderRefundError) -> Self {
+        match error {
+            orders::errors::OrderRefundError::DatabaseError(err) => err.into(),
+            orders::errors::OrderRefundError::OrderNonExistent(order_id) => {
+                tracing::warn!("{error}");
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(format!("Order {order_id} not found")),
+                )
+            }
+            orders::errors::OrderRefundError::NotRefundable(_) => {
+                tracing::warn!("{error}");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from(
+                        "Order has not been paid for or is already fully refunded",
+                    )),
+                )
+            }
+            orders::errors::OrderRefundError::InvalidAmount { remaining, .. } => {
+                tracing::warn!("{error}");
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Some(format!("Refund amount must be between 1 and {remaining}")),
+                )
+            }
+            orders::errors::OrderRefundError::Payment(err) => {
+                // Details of the payment provider's errors are not exposed.
+                tracing::error!("Error refunding payment: {err}");
+                Self::new(
+                    StatusCode::BAD_GATEWAY,
+                    Some(String::from(
+                        "The refund was recorded, but the payment could not be refunded yet, it is retried on the next refund of the order",
+                    )),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    use crate::{
+        constants::{cookies::SESSION_COOKIE_NAME, orders::ORDER_RATE_LIMIT_ATTEMPTS},
+        db::{
+            models::{
+                apporder::AppOrder,
+                appuser::{AppUser, AppUserInsert, AppUserRole},
+                product::ProductInsert,
+            },
+            ConnectionPool,
+        },
+        services::orders,
+        testing::{store_user, TestApp, TestResponse},
+        utils::{address::Address, email::EmailAddress},
+    };
+
+    /// The body of a guest order for one of a product, placed with an email.
+    fn guest_order(email: &str, forename: &str, product_id: Uuid) -> Value {
+        json!({
+            "user_data": {
+                "email": email,
+                "forename": forename,
+                "surname": "Smith",
+                "address": {
+                    "line1": "1 High Street",
+                    "city": "London",
+                    "postcode": "SW1A 1AA",
+                    "country": "GB",
+                },
+            },
+            "products": [{ "product": product_id, "count": 1 }],
+        })
+    }
+
+    /// Store a product which is always in stock.
+    async fn store_product(db_conn: &ConnectionPool) -> Uuid {
+        ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(db_conn)
+            .await
+            .expect("Product should be stored")
+            .id()
+    }
+
+    /// Place a guest order for one of a product, returning the order's ID and
+    /// the token granting access to it.
+    async fn place_guest_order(
+        app: &mut TestApp,
+        email: &str,
+        product_id: Uuid,
+    ) -> (String, String) {
+        let response = app
+            .post("/orders/guest", &guest_order(email, "Bob", product_id))
+            .await;
+        assert_eq!(response.status, StatusCode::OK);
+        (
+            response.string_at("/order/id"),
+            response.string_at("/token"),
+        )
+    }
+
+    /// A guest order is placed for a new guest, with its items.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn guest_order_is_placed_for_new_guest(db_conn: ConnectionPool) {
+        let product_id = store_product(&db_conn).await;
+        let mut app = TestApp::new(db_conn.clone());
+        let (order_id, token) = place_guest_order(&mut app, "bob@example.com", product_id).await;
+        let guest_ids = AppUser::select_guest_ids_by_email("bob@example.com", &db_conn)
+            .await
+            .expect("Select should succeed");
+        assert_eq!(guest_ids.len(), 1);
+        let response = app.get(&format!("/orders/guest/{token}")).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.string_at("/order/id"), order_id);
+        let items = response
+            .json()
+            .pointer("/items")
+            .and_then(Value::as_array)
+            .map(Vec::len);
+        assert_eq!(items, Some(1));
+    }
+
+    /// A guest order which can't be placed leaves no guest behind.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn failed_guest_order_stores_no_guest(db_conn: ConnectionPool) {
+        let mut app = TestApp::new(db_conn.clone());
+        let response = app
+            .post(
+                "/orders/guest",
+                &guest_order("bob@example.com", "Bob", Uuid::new_v4()),
+            )
+            .await;
+        assert!(!response.status.is_success());
+        let guest_ids = AppUser::select_guest_ids_by_email("bob@example.com", &db_conn)
+            .await
+            .expect("Select should succeed");
+        assert!(guest_ids.is_empty());
+    }
+
+    /// A guest order token grants access to only its own order, and nothing
+    /// which needs a session.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn guest_order_token_grants_access_to_only_its_order(db_conn: ConnectionPool) {
+        let product_id = store_product(&db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        let (first_id, first_token) =
+            place_guest_order(&mut app, "bob@example.com", product_id).await;
+        let (second_id, second_token) =
+            place_guest_order(&mut app, "bob@example.com", product_id).await;
+        for (order_id, token) in [(&first_id, &first_token), (&second_id, &second_token)] {
+            let response = app.get(&format!("/orders/guest/{token}")).await;
+            assert_eq!(&response.string_at("/order/id"), order_id);
+        }
+        let invalid = app.get("/orders/guest/not-a-token").await;
+        assert_eq!(invalid.status, StatusCode::FORBIDDEN);
+        let unauthenticated = app.get(&format!("/orders/{first_id}")).await;
+        assert_eq!(unauthenticated.status, StatusCode::UNAUTHORIZED);
+        assert!(app.cookie(&SESSION_COOKIE_NAME).is_none());
+    }
+
+    /// Guests who placed orders with an email are upgraded to a single full
+    /// account with every one of their orders, through a link emailed to them.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn guest_is_upgraded_to_full_account(db_conn: ConnectionPool) {
+        let product_id = store_product(&db_conn).await;
+        let mut app = TestApp::new(db_conn.clone());
+        let (first_id, _) = place_guest_order(&mut app, "bob@example.com", product_id).await;
+        let (second_id, _) = place_guest_order(&mut app, "bob@example.com", product_id).await;
+        let link_response = app
+            .post(
+                "/registration/guest/link",
+                &json!({ "email": "bob@example.com" }),
+            )
+            .await;
+        assert_eq!(link_response.status, StatusCode::OK);
+        let sent = app.emails.sent();
+        let token = sent
+            .first()
+            .and_then(|email| email.body.split_once("token="))
+            .map(|(_, token)| token.trim().to_owned())
+            .expect("A guest upgrade link should be sent");
+        let upgrade_response = app
+            .post(
+                "/registration/guest",
+                &json!({
+                    "token": token,
+                    "credential": { "Password": { "password": "correct horse battery staple" } },
+                }),
+            )
+            .await;
+        assert_eq!(upgrade_response.status, StatusCode::OK);
+        let email = EmailAddress::try_from("bob@example.com").expect("Email should be valid");
+        let user = AppUser::select_by_email(&email, &db_conn)
+            .await
+            .expect("Select should succeed")
+            .expect("Guest should now have a full account");
+        assert!(
+            AppUser::select_guest_ids_by_email("bob@example.com", &db_conn)
+                .await
+                .expect("Select should succeed")
+                .is_empty()
+        );
+        for order_id in [first_id, second_id] {
+            let order = AppOrder::select_one(
+                Uuid::parse_str(&order_id).expect("Order ID should be a UUID"),
+                &db_conn,
+            )
+            .await
+            .expect("Select should succeed")
+            .expect("Order should still exist");
+            assert_eq!(order.user_id(), user.id());
+        }
+    }
+
+    /// A guest order placed with a registered email gets the same response as
+    /// one placed with any other email, and the account's owner is told of it.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn guest_order_does_not_reveal_registered_email(db_conn: ConnectionPool) {
+        AppUserInsert::new(
+            EmailAddress::try_from("alice@example.com").expect("Email should be valid"),
+            "Alice",
+            "Smith",
+            Address::new("1 High Street", None, "London", "SW1A 1AA", "GB")
+                .expect("Address should be valid"),
+            None,
+        )
+        .store(&db_conn)
+        .await
+        .expect("User should be stored");
+        let product_id = store_product(&db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        let unregistered = app
+            .post(
+                "/orders/guest",
+                &guest_order("bob@example.com", "Bob", product_id),
+            )
+            .await;
+        assert_eq!(unregistered.status, StatusCode::OK);
+        assert!(app.emails.sent().is_empty());
+        let registered = app
+            .post(
+                "/orders/guest",
+                &guest_order("alice@example.com", "Alice", product_id),
+            )
+            .await;
+        assert_eq!(registered.status, StatusCode::OK);
+        let (unregistered_body, registered_body) = (unregistered.json(), registered.json());
+        let keys = |body: &Value| -> Vec<String> {
+            body.as_object()
+                .expect("Body should be an object")
+                .keys()
+                .cloned()
+                .collect()
+        };
+        assert_eq!(keys(&unregistered_body), keys(&registered_body));
+        let sent = app.emails.wait_for(1).await;
+        assert_eq!(sent.len(), 1);
+        let notification = sent.first().expect("An email should be sent");
+        assert_eq!(notification.recipient, "alice@example.com");
+        assert!(notification.subject.contains("guest order"));
+        assert!(notification.body.contains("Log in"));
+    }
+
+    /// A client may only place so many guest orders per window, whether or
+    /// not they succeed.
+    #[tokio::test]
+    async fn guest_orders_are_rate_limited_by_client() {
+        let mut app = TestApp::without_db();
+        let order = guest_order("bob@example.com", "", Uuid::new_v4());
+        for _ in 0..*ORDER_RATE_LIMIT_ATTEMPTS {
+            let response = app.post("/orders/guest", &order).await;
+            assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        let response = app.post("/orders/guest", &order).await;
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    /// Move a user to an address in the US, which express shipping isn't
+    /// offered to.
+    async fn move_to_us(user: &mut AppUser, db_conn: &ConnectionPool) {
+        user.address = Address::new("1 Main Street", None, "Springfield", "12345", "US")
+            .expect("Address should be valid");
+        user.update(db_conn).await.expect("User should be updated");
+    }
+
+    /// Place an order for one of a product as the logged in customer.
+    async fn place_order(
+        app: &mut TestApp,
+        product_id: Uuid,
+        shipping_method: &str,
+    ) -> TestResponse {
+        app.post(
+            "/orders",
+            &json!({
+                "products": [{ "product": product_id, "count": 1u32 }],
+                "shipping_method": shipping_method,
+            }),
+        )
+        .await
+    }
+
+    /// The amount charged for shipping an order in a response.
+    fn shipping(response: &TestResponse, pointer: &str) -> Option<i64> {
+        response.json().pointer(pointer).and_then(Value::as_i64)
+    }
+
+    /// Each shipping method offered can be chosen at checkout, and is stored
+    /// on the order with its cost.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn shipping_method_is_chosen_at_checkout(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let product_id = store_product(&db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        for (method, cost) in [("Standard", 300), ("Express", 900)] {
+            let response = place_order(&mut app, product_id, method).await;
+            assert_eq!(response.status, StatusCode::OK);
+            assert_eq!(response.string_at("/shipping_method"), method);
+            assert_eq!(shipping(&response, "/shipping"), Some(cost));
+        }
+    }
+
+    /// A shipping method which isn't offered to the customer's address is
+    /// rejected.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn unavailable_shipping_method_is_rejected(db_conn: ConnectionPool) {
+        let mut user = store_user("alice@example.com", &db_conn).await;
+        move_to_us(&mut user, &db_conn).await;
+        let product_id = store_product(&db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let express = place_order(&mut app, product_id, "Express").await;
+        assert_eq!(express.status, StatusCode::UNPROCESSABLE_ENTITY);
+        let standard = place_order(&mut app, product_id, "Standard").await;
+        assert_eq!(standard.status, StatusCode::OK);
+    }
+
+    /// Changing an order after the customer has moved keeps its shipping
+    /// method and cost for the address it was placed with.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn changed_order_keeps_shipping_after_move(db_conn: ConnectionPool) {
+        let mut user = store_user("alice@example.com", &db_conn).await;
+        let product_id = store_product(&db_conn).await;
+        let mut app = TestApp::new(db_conn.clone());
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let placed = place_order(&mut app, product_id, "Express").await;
+        assert_eq!(placed.status, StatusCode::OK);
+        move_to_us(&mut user, &db_conn).await;
+        let changed = app
+            .send_json(
+                Method::PATCH,
+                &format!("/orders/{}", placed.string_at("/id")),
+                &json!({ "products": [{ "product": product_id, "count": 2u32 }] }),
+            )
+            .await;
+        assert_eq!(changed.status, StatusCode::OK);
+        assert_eq!(changed.string_at("/order/shipping_method"), "Express");
+        assert_eq!(shipping(&changed, "/order/shipping"), Some(900));
+    }
+
+    /// A customer may delete their order until it has been confirmed.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn unconfirmed_order_is_deleted(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let product_id = store_product(&db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let placed = place_order(&mut app, product_id, "Standard").await;
+        let uri = format!("/orders/{}", placed.string_at("/id"));
+        let deleted = app.send_json(Method::DELETE, &uri, &json!({})).await;
+        assert_eq!(deleted.status, StatusCode::OK);
+        // Customers can't tell a deleted order from another customer's.
+        assert_eq!(app.get(&uri).await.status, StatusCode::FORBIDDEN);
+    }
+
+    /// Once an order has been confirmed, neither its customer nor an
+    /// administrator may delete it.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn confirmed_order_is_not_deleted(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let mut administrator = store_user("admin@example.com", &db_conn).await;
+        administrator.role = AppUserRole::Administrator;
+        administrator
+            .update(&db_conn)
+            .await
+            .expect("User should be updated");
+        let product_id = store_product(&db_conn).await;
+        let mut customer_app = TestApp::new(db_conn.clone());
+        assert_eq!(
+            customer_app.log_in("alice@example.com").await.status,
+            StatusCode::OK
+        );
+        let placed = place_order(&mut customer_app, product_id, "Standard").await;
+        let order_id: Uuid = placed
+            .string_at("/id")
+            .parse()
+            .expect("Order ID should be a UUID");
+        orders::confirm_order(order_id, None, &db_conn)
+            .await
+            .expect("Order should be confirmed");
+        let uri = format!("/orders/{order_id}");
+        let mut admin_app = customer_app.other_client();
+        assert_eq!(
+            admin_app.log_in("admin@example.com").await.status,
+            StatusCode::OK
+        );
+        for app in [&mut customer_app, &mut admin_app] {
+            let refused = app.send_json(Method::DELETE, &uri, &json!({})).await;
+            assert_eq!(refused.status, StatusCode::CONFLICT);
+        }
+        assert_eq!(customer_app.get(&uri).await.status, StatusCode::OK);
+    }
+
+    /// An order is given to the customer who placed it and to administrators.
+    /// Other customers are refused whether or not the order exists, so they
+    /// can't tell which orders do, while administrators are told it doesn't.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn order_is_given_only_to_owner_and_administrator(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        store_user("bob@example.com", &db_conn).await;
+        let mut administrator = store_user("admin@example.com", &db_conn).await;
+        administrator.role = AppUserRole::Administrator;
+        administrator
+            .update(&db_conn)
+            .await
+            .expect("User should be updated");
+        let product_id = store_product(&db_conn).await;
+        let mut owner_app = TestApp::new(db_conn.clone());
+        let mut other_app = owner_app.other_client();
+        let mut admin_app = owner_app.other_client();
+        for (app, email) in [
+            (&mut owner_app, "alice@example.com"),
+            (&mut other_app, "bob@example.com"),
+            (&mut admin_app, "admin@example.com"),
+        ] {
+            assert_eq!(app.log_in(email).await.status, StatusCode::OK);
+        }
+        let placed = place_order(&mut owner_app, product_id, "Standard").await;
+        assert_eq!(placed.status, StatusCode::OK);
+        let uri = format!("/orders/{}", placed.string_at("/id"));
+        let missing = format!("/orders/{}", Uuid::new_v4());
+        for (app, order_status, missing_status) in [
+            (&mut owner_app, StatusCode::OK, StatusCode::FORBIDDEN),
+            (&mut other_app, StatusCode::FORBIDDEN, StatusCode::FORBIDDEN),
+            (&mut admin_app, StatusCode::OK, StatusCode::NOT_FOUND),
+        ] {
+            assert_eq!(app.get(&uri).await.status, order_status);
+            assert_eq!(app.get(&missing).await.status, missing_status);
+        }
+    }
+
+    /// An invoice lists the order's items at the prices and names they were
+    /// ordered at, and can be retrieved only by the customer who placed the
+    /// order or an administrator.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn invoice_is_given_to_owner_and_administrator(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        store_user("bob@example.com", &db_conn).await;
+        let mut administrator = store_user("admin@example.com", &db_conn).await;
+        administrator.role = AppUserRole::Administrator;
+        administrator
+            .update(&db_conn)
+            .await
+            .expect("User should be updated");
+        let product_id = store_product(&db_conn).await;
+        let mut customer_app = TestApp::new(db_conn.clone());
+        assert_eq!(
+            customer_app.log_in("alice@example.com").await.status,
+            StatusCode::OK
+        );
+        let placed = place_order(&mut customer_app, product_id, "Standard").await;
+        assert_eq!(placed.status, StatusCode::OK);
+        let order_id = placed.string_at("/id");
+        sqlx::query!(
+            "UPDATE product SET name = 'Gadget', price = 2000 WHERE id = $1",
+            product_id
+        )
+        .execute(&db_conn)
+        .await
+        .expect("Product should be updated");
+
+        let uri = format!("/orders/{order_id}/invoice");
+        let invoice = customer_app.get(&uri).await;
+        assert_eq!(invoice.status, StatusCode::OK);
+        let body = invoice.json();
+        assert_eq!(invoice.string_at("/order_id"), order_id);
+        assert_eq!(invoice.string_at("/customer_name"), "Alice Smith");
+        assert_eq!(
+            invoice.string_at("/shipping_address/line1"),
+            "1 High Street"
+        );
+        assert_eq!(
+            body.pointer("/items"),
+            Some(&json!([{
+                "product_id": product_id,
+                "name": "Widget",
+                "unit_price": 1000u32,
+                "count": 1u32,
+                "line_total": 1000u32,
+            }]))
+        );
+        assert_eq!(
+            body.pointer("/total"),
+            placed.json().pointer("/amount_charged")
+        );
+
+        let mut other_app = customer_app.other_client();
+        assert_eq!(
+            other_app.log_in("bob@example.com").await.status,
+            StatusCode::OK
+        );
+        assert_eq!(other_app.get(&uri).await.status, StatusCode::FORBIDDEN);
+        let mut admin_app = customer_app.other_client();
+        assert_eq!(
+            admin_app.log_in("admin@example.com").await.status,
+            StatusCode::OK
+        );
+        let administered = admin_app.get(&uri).await;
+        assert_eq!(administered.status, StatusCode::OK);
+        assert_eq!(administered.json(), body);
+    }
+
+    /// Unlist a product, as if an administrator had withdrawn it.
+    async fn unlist(product_id: Uuid, db_conn: &ConnectionPool) {
+        sqlx::query!(
+            "UPDATE product SET listed = false WHERE id = $1",
+            product_id
+        )
+        .execute(db_conn)
+        .await
+        .expect("Product should be unlisted");
+    }
+
+    /// The names of the products in an order and how many of each were
+    /// ordered, sorted by name.
+    async fn ordered_items(app: &mut TestApp, order_id: &str) -> Vec<(String, u64)> {
+        let response = app.get(&format!("/orders/{order_id}")).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let mut items: Vec<_> = response
+            .json()
+            .pointer("/items")
+            .and_then(Value::as_array)
+            .expect("Order should have items")
+            .iter()
+            .map(|item| {
+                (
+                    item.pointer("/2")
+                        .and_then(Value::as_str)
+                        .expect("Item should have a name")
+                        .to_owned(),
+                    item.pointer("/1")
+                        .and_then(Value::as_u64)
+                        .expect("Item should have a count"),
+                )
+            })
+            .collect();
+        items.sort_unstable();
+        items
+    }
+
+    /// Reordering places a new order for every item of the original which is
+    /// still available, reporting those which are skipped, and fails if none
+    /// are.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn reorder_skips_unavailable_items(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let widget_id = store_product(&db_conn).await;
+        let gadget_id = ProductInsert::new("Gadget", "A gadget.", true, 2000)
+            .store(&db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        let mut app = TestApp::new(db_conn.clone());
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let placed = app
+            .post(
+                "/orders",
+                &json!({
+                    "products": [
+                        { "product": widget_id, "count": 2u32 },
+                        { "product": gadget_id, "count": 1u32 },
+                    ],
+                }),
+            )
+            .await;
+        assert_eq!(placed.status, StatusCode::OK);
+        let order_id = placed.string_at("/id");
+        let uri = format!("/orders/{order_id}/reorder");
+
+        let full = app.post(&uri, &json!({})).await;
+        assert_eq!(full.status, StatusCode::OK);
+        assert_eq!(full.json().pointer("/skipped"), Some(&json!([])));
+        let full_id = full.string_at("/order/id");
+        assert_ne!(full_id, order_id);
+        assert_eq!(
+            ordered_items(&mut app, &full_id).await,
+            ordered_items(&mut app, &order_id).await
+        );
+        assert_eq!(full.string_at("/order/status"), "Unconfirmed");
+
+        unlist(gadget_id, &db_conn).await;
+        let partial = app.post(&uri, &json!({})).await;
+        assert_eq!(partial.status, StatusCode::OK);
+        assert_eq!(
+            partial.json().pointer("/skipped"),
+            Some(&json!([gadget_id]))
+        );
+        assert_eq!(
+            ordered_items(&mut app, &partial.string_at("/order/id")).await,
+            [(String::from("Widget"), 2u64)]
+        );
+
+        unlist(widget_id, &db_conn).await;
+        let nothing = app.post(&uri, &json!({})).await;
+        assert_eq!(nothing.status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    /// An order for a product whose stored price is out of range fails with
+    /// a server error, rather than crashing the request.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn out_of_range_price_is_server_error(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let product_id = store_product(&db_conn).await;
+        sqlx::query("UPDATE product SET price = $1 WHERE id = $2")
+            .bind(i64::from(u32::MAX).saturating_add(1))
+            .bind(product_id)
+            .execute(&db_conn)
+            .await
+            .expect("Price should be updated");
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let response = place_order(&mut app, product_id, "Standard").await;
+        assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
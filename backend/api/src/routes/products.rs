@@ -1,46 +1,116 @@
 //! Routes for CRUD operations on products.
+use alloc::sync::Arc;
+
 use axum::{
-    extract::{Multipart, Path, Query, State},
-    http::StatusCode,
-    middleware::from_fn_with_state,
+    body::Body,
+    extract::{Multipart, Path, Query, Request, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    middleware::{from_fn_with_state, Next},
+    response::{IntoResponse, Response},
     routing::{delete, get, post, put},
     Extension, Json, Router,
 };
-use serde::Serialize;
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
-    db::models::product::{Product, ProductInsert},
-    middleware::session::session_middleware,
+    constants::{
+        api::MAX_MULTIPART_FIELDS,
+        products::{MAX_IMAGES_PER_PRODUCT, MAX_PRODUCTS_PER_BATCH},
+    },
+    db::models::{
+        product::{Product, ProductCursor, ProductInsert, RatedProduct},
+        product_stats::ProductViewCount,
+        review::Review,
+    },
+    middleware::{features::require_email, session::session_middleware},
     services::{
-        products::{self, ProductSearchParameters, ProductUpdate, ProductVisibilityScope},
-        sessions::{AdministratorSession, GenericAuthenticatedSession},
+        products::{
+            self, ContentDisposition, ListImagesParameters, ProductSearchParameters, ProductUpdate,
+            ProductVisibilityScope, ResponsiveImage,
+        },
+        reviews,
+        sessions::{self, AdministratorSession, CustomerSession, GenericAuthenticatedSession},
     },
     state::AppState,
-    utils::httperror::HttpError,
+    utils::{
+        etag,
+        httperror::HttpError,
+        json::ValidatedJson,
+        pagination::{self, Pagination},
+        text,
+    },
 };
 
 /// Create a router for routes under the product service.
 pub fn create_router(state: &AppState) -> Router<AppState> {
     let authenticated = Router::new()
         .route("/", get(search_products))
+        .route("/batch", get(get_product_batch))
         .route("/{product_id}", get(get_product))
         .route("/{product_id}/images", get(list_product_images))
+        .route("/{product_id}/reviews", get(list_product_reviews))
         .layer(from_fn_with_state(
             state.clone(),
             session_middleware::<GenericAuthenticatedSession>,
         ));
+    let customer_authenticated = Router::new()
+        .route("/{product_id}/reviews", post(create_review))
+        .layer(from_fn_with_state(state.clone(), invalidate_product_lists))
+        .route(
+            "/{product_id}/notify-me",
+            post(request_stock_notification)
+                .layer(from_fn_with_state(state.clone(), require_email)),
+        )
+        .layer(from_fn_with_state(
+            state.clone(),
+            session_middleware::<CustomerSession>,
+        ));
     let admin_authenticated = Router::new()
         .route("/", post(create_product))
+        .route("/export.csv", get(export_products))
+        .route("/top-viewed", get(top_viewed_products))
         .route("/{product_id}", put(update_product))
         .route("/{product_id}", delete(delete_product))
+        .route("/{product_id}/list", post(list_product))
+        .route("/{product_id}/unlist", post(unlist_product))
+        .route("/{product_id}/archive", post(archive_product))
+        .route("/{product_id}/stock", post(adjust_product_stock))
         .route("/{product_id}/images", post(add_product_image))
+        .route("/{product_id}/images/order", put(reorder_product_images))
         .route("/{product_id}/images/{path}", delete(delete_product_image))
+        .layer(from_fn_with_state(state.clone(), invalidate_product_lists))
         .layer(from_fn_with_state(
             state.clone(),
             session_middleware::<AdministratorSession>,
         ));
-    authenticated.merge(admin_authenticated)
+    authenticated
+        .merge(customer_authenticated)
+        .merge(admin_authenticated)
+}
+
+/// Middleware advancing the product list version after every successful
+/// request it wraps which may have changed a product (i.e. which is not a
+/// safe method), so that cached listings are revalidated in full. A failure
+/// to do so is logged rather than failing the request, since the change has
+/// already been made.
+async fn invalidate_product_lists(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mutating = !req.method().is_safe();
+    let response = next.run(req).await;
+    if mutating && response.status().is_success() {
+        if let Err(err) = sessions::bump_product_list_version(&mut state.session_conn()).await {
+            tracing::error!("Error invalidating cached product listings: {err}");
+        }
+    }
+    response
 }
 
 /// The response to /products or /products/search.
@@ -50,25 +120,56 @@ struct ListProductsResponse {
     products: Vec<Product>,
 }
 
-/// Search for matching products.
+/// The response to a paginated search of /products.
+#[derive(Serialize)]
+struct SearchProductsResponse {
+    /// The page of products returned by the query, with their ratings.
+    products: Vec<RatedProduct>,
+    /// The page of results which was returned.
+    pagination: Pagination,
+    /// The cursor to pass as `after` to fetch the next page, if there may be one.
+    next_cursor: Option<String>,
+}
+
+/// Search for matching products. The `ETag` incorporates the product list
+/// version, so it changes whenever any product does.
 async fn search_products(
     State(state): State<AppState>,
     Extension(session): Extension<GenericAuthenticatedSession>,
     Query(params): Query<ProductSearchParameters>,
-) -> Result<Json<ListProductsResponse>, HttpError> {
+    pagination: Pagination,
+    headers: HeaderMap,
+) -> Result<Response, HttpError> {
     let products = match session {
         GenericAuthenticatedSession::Customer(_) => {
-            products::search_products::<{ ProductVisibilityScope::LISTED_ONLY }>(&state.db, &params)
-                .await?
+            products::search_products::<{ ProductVisibilityScope::LISTED_ONLY }>(
+                state.read_db(),
+                &params,
+                pagination,
+            )
+            .await?
         }
         GenericAuthenticatedSession::Administrator(_) => {
             products::search_products::<{ ProductVisibilityScope::INCLUDE_UNLISTED }>(
-                &state.db, &params,
+                state.read_db(),
+                &params,
+                pagination,
             )
             .await?
         }
     };
-    Ok(Json(ListProductsResponse { products }))
+    let version = sessions::product_list_version(&mut state.session_conn()).await?;
+    Ok(etag::conditional_json_versioned(
+        &headers,
+        &SearchProductsResponse {
+            next_cursor: pagination::next_cursor(&products, pagination, |product| {
+                ProductCursor::from(product)
+            }),
+            products,
+            pagination,
+        },
+        version,
+    ))
 }
 
 /// Get a product by its ID.
@@ -76,28 +177,144 @@ async fn get_product(
     State(state): State<AppState>,
     Extension(session): Extension<GenericAuthenticatedSession>,
     Path(product_id): Path<Uuid>,
-) -> Result<Json<Product>, HttpError> {
-    let product = match session {
+    headers: HeaderMap,
+) -> Result<Response, HttpError> {
+    let lookup = match session {
         GenericAuthenticatedSession::Customer(_) => {
             products::retrieve_product::<{ ProductVisibilityScope::LISTED_ONLY }>(
-                product_id, &state.db,
+                product_id,
+                state.read_db(),
             )
             .await?
         }
         GenericAuthenticatedSession::Administrator(_) => {
             products::retrieve_product::<{ ProductVisibilityScope::INCLUDE_UNLISTED }>(
-                product_id, &state.db,
+                product_id,
+                state.read_db(),
             )
             .await?
         }
     };
-    Ok(Json(product.ok_or(StatusCode::NOT_FOUND)?))
+    match lookup {
+        products::ProductLookup::Found(product) => {
+            // Analytics must never fail the request.
+            if let Err(err) =
+                products::record_view(product_id, &session, &mut state.session_conn()).await
+            {
+                tracing::error!("Error recording view of product {product_id}: {err}");
+            }
+            Ok(etag::conditional_json(&headers, &product))
+        }
+        products::ProductLookup::NonExistent => {
+            tracing::warn!("Attempted to retrieve non-existent product {product_id}.");
+            Err(StatusCode::NOT_FOUND.into())
+        }
+        products::ProductLookup::Archived => {
+            tracing::warn!("Attempted to retrieve archived product {product_id}.");
+            Err(HttpError::new(
+                StatusCode::GONE,
+                Some(format!("Product {product_id} has been archived")),
+            ))
+        }
+        products::ProductLookup::Unlisted => {
+            // Indistinguishable from a non-existent product, so that customers
+            // cannot discover unlisted products.
+            tracing::warn!("Customer attempted to retrieve unlisted product {product_id}.");
+            Err(StatusCode::NOT_FOUND.into())
+        }
+    }
+}
+
+/// The query parameters for /products/batch.
+#[derive(Deserialize)]
+struct ProductBatchParameters {
+    /// A comma-separated list of the product IDs to retrieve.
+    ids: String,
+}
+
+/// Get several products by their IDs in one request. Products are returned in
+/// the order requested, leaving out any which do not exist or are not visible.
+async fn get_product_batch(
+    State(state): State<AppState>,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+    Query(params): Query<ProductBatchParameters>,
+) -> Result<Json<ListProductsResponse>, HttpError> {
+    let ids = params
+        .ids
+        .split(',')
+        .filter(|id| !id.is_empty())
+        .map(Uuid::parse_str)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| {
+            tracing::warn!("Invalid product ID in batch request: {err}");
+            HttpError::new(
+                StatusCode::BAD_REQUEST,
+                Some(String::from("ids must be a comma-separated list of UUIDs")),
+            )
+        })?;
+    let products = match session {
+        GenericAuthenticatedSession::Customer(_) => {
+            products::retrieve_product_batch::<{ ProductVisibilityScope::LISTED_ONLY }>(
+                &ids,
+                state.read_db(),
+            )
+            .await?
+        }
+        GenericAuthenticatedSession::Administrator(_) => {
+            products::retrieve_product_batch::<{ ProductVisibilityScope::INCLUDE_UNLISTED }>(
+                &ids,
+                state.read_db(),
+            )
+            .await?
+        }
+    };
+    Ok(Json(ListProductsResponse { products }))
+}
+
+/// Download the entire product catalog as CSV. The response is streamed, so
+/// it is not buffered in memory however large the catalog is.
+async fn export_products(State(state): State<AppState>) -> impl IntoResponse {
+    let receiver = products::export_csv(state.read_db);
+    let body = Body::from_stream(stream::unfold(receiver, |mut rows| async move {
+        let row = rows.recv().await?.inspect_err(|err| {
+            tracing::error!("Aborting product export, database error: {err}");
+        });
+        Some((row, rows))
+    }));
+    (
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"products.csv\""),
+        ],
+        body,
+    )
+}
+
+/// The response to GET /products/top-viewed.
+#[derive(Serialize)]
+struct TopViewedProductsResponse {
+    /// The page of products returned, most viewed first.
+    products: Vec<ProductViewCount>,
+    /// The page of results which was returned.
+    pagination: Pagination,
+}
+
+/// List the products viewed most by customers.
+async fn top_viewed_products(
+    State(state): State<AppState>,
+    pagination: Pagination,
+) -> Result<Json<TopViewedProductsResponse>, HttpError> {
+    let products = products::top_viewed_products(pagination, state.read_db()).await?;
+    Ok(Json(TopViewedProductsResponse {
+        products,
+        pagination,
+    }))
 }
 
 /// Create a new product.
 async fn create_product(
     State(state): State<AppState>,
-    Json(body): Json<ProductInsert>,
+    ValidatedJson(body): ValidatedJson<ProductInsert>,
 ) -> Result<Json<Product>, HttpError> {
     Ok(Json(products::create_product(body, &state.db).await?))
 }
@@ -110,15 +327,78 @@ async fn delete_product(
     Ok(products::delete_product(product_id, &state.db).await?)
 }
 
+/// Archive a product, so that it is never listed or retrieved again, while
+/// keeping it for the orders which refer to it.
+async fn archive_product(
+    State(state): State<AppState>,
+    Path(product_id): Path<Uuid>,
+) -> Result<(), HttpError> {
+    Ok(products::archive_product(product_id, &state.db).await?)
+}
+
 /// Update a product.
 async fn update_product(
     State(state): State<AppState>,
     Path(product_id): Path<Uuid>,
-    Json(body): Json<ProductUpdate>,
+    ValidatedJson(body): ValidatedJson<ProductUpdate>,
 ) -> Result<(), HttpError> {
     Ok(products::update_product(product_id, body, &state.db).await?)
 }
 
+/// Publicly list a product, e.g. once a draft is ready to be published.
+async fn list_product(
+    State(state): State<AppState>,
+    Path(product_id): Path<Uuid>,
+) -> Result<(), HttpError> {
+    Ok(products::set_listed(product_id, true, &state.db).await?)
+}
+
+/// Unlist a product, hiding it from customers without deleting it.
+async fn unlist_product(
+    State(state): State<AppState>,
+    Path(product_id): Path<Uuid>,
+) -> Result<(), HttpError> {
+    Ok(products::set_listed(product_id, false, &state.db).await?)
+}
+
+/// A request to POST /products/{id}/stock.
+#[derive(Deserialize)]
+struct AdjustStockRequest {
+    /// The change in stock level, negative to reduce it.
+    delta: i64,
+}
+
+/// The response to POST /products/{id}/stock.
+#[derive(Serialize)]
+struct AdjustStockResponse {
+    /// The product's stock level after the adjustment.
+    stock: i64,
+}
+
+/// Adjust a product's stock level by a relative amount, e.g. when receiving
+/// inventory or correcting a miscount.
+async fn adjust_product_stock(
+    State(state): State<AppState>,
+    Extension(session): Extension<AdministratorSession>,
+    Path(product_id): Path<Uuid>,
+    ValidatedJson(body): ValidatedJson<AdjustStockRequest>,
+) -> Result<Json<AdjustStockResponse>, HttpError> {
+    let stock = products::adjust_stock(
+        product_id,
+        body.delta,
+        session.user_id(),
+        &state.db,
+        Arc::clone(&state.email_sender),
+    )
+    .await?;
+    tracing::info!(
+        "Administrator {} adjusted stock of product {product_id} by {}, now {stock}",
+        session.user_id(),
+        body.delta
+    );
+    Ok(Json(AdjustStockResponse { stock }))
+}
+
 /// The response to POST /products/{id}/images.
 #[derive(Serialize)]
 struct AddImageResponse {
@@ -126,28 +406,37 @@ struct AddImageResponse {
     path: String,
 }
 
+/// The query parameters accepted by POST /products/{id}/images.
+#[derive(Deserialize)]
+struct AddImageParameters {
+    /// How the image should be presented when requested. Defaults to inline.
+    #[serde(default)]
+    disposition: ContentDisposition,
+}
+
 /// Add an image to a given product. This, unlike most endpoints, accepts
 /// multipart form data instead of JSON. This is because that is the most
 /// natural way to do a file upload over HTTP.
 async fn add_product_image(
     State(state): State<AppState>,
     Path(product_id): Path<Uuid>,
+    Query(params): Query<AddImageParameters>,
     mut data: Multipart,
 ) -> Result<Json<AddImageResponse>, HttpError> {
-    loop {
+    for _ in 0..*MAX_MULTIPART_FIELDS {
         let Some(field) = data.next_field().await.map_err(|err| {
-            eprintln!("Error while processing multipart data: {err}");
+            tracing::warn!("Error while processing multipart data: {err}");
             StatusCode::UNPROCESSABLE_ENTITY
         })?
         else {
-            eprintln!("Image was not included in multipart form data.");
+            tracing::warn!("Image was not included in multipart form data.");
             return Err(HttpError::new(
                 StatusCode::UNPROCESSABLE_ENTITY,
                 Some(String::from("Image field is missing from form data")),
             ));
         };
         if field.name().ok_or_else(|| {
-            eprintln!("Multipart field missing name in request to add image");
+            tracing::warn!("Multipart field missing name in request to add image");
             HttpError::new(
                 StatusCode::UNPROCESSABLE_ENTITY,
                 Some(String::from("A multipart form field is missing a name")),
@@ -160,17 +449,29 @@ async fn add_product_image(
                     .bytes()
                     .await
                     .map_err(|err| {
-                        eprintln!("Multipart form image data unprocessable: {err}");
+                        tracing::warn!("Multipart form image data unprocessable: {err}");
                         HttpError::new(StatusCode::UNPROCESSABLE_ENTITY, Some(err.to_string()))
                     })?
                     .to_vec(),
+                params.disposition,
                 &state.db,
                 state.media_store,
             )
             .await?;
-            break Ok(Json(AddImageResponse { path: result }));
+            return Ok(Json(AddImageResponse { path: result }));
         }
     }
+    tracing::warn!(
+        "Image was not within the first {} fields of multipart form data.",
+        *MAX_MULTIPART_FIELDS
+    );
+    Err(HttpError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Some(format!(
+            "Image field must be within the first {} form fields",
+            *MAX_MULTIPART_FIELDS
+        )),
+    ))
 }
 
 /// Delete (disassociate) an image from a product.
@@ -181,31 +482,175 @@ async fn delete_product_image(
     Ok(products::delete_image(product_id, &path, &state.db).await?)
 }
 
+/// A request to PUT /products/{id}/images/order.
+#[derive(Deserialize)]
+struct ReorderImagesRequest {
+    /// Every image path (or URI) of the product, in the desired display order.
+    /// The first is the primary image.
+    paths: Vec<String>,
+}
+
+/// Set the display order of a product's images.
+async fn reorder_product_images(
+    State(state): State<AppState>,
+    Path(product_id): Path<Uuid>,
+    ValidatedJson(body): ValidatedJson<ReorderImagesRequest>,
+) -> Result<(), HttpError> {
+    Ok(products::reorder_images(product_id, &body.paths, &state.db).await?)
+}
+
 /// The response to /product/{id}/images
 #[derive(Serialize)]
 struct ListImagesResponse {
-    /// The list of images returned.
-    images: Vec<String>,
+    /// The list of images returned, each with its downsized variants.
+    images: Vec<ResponsiveImage>,
 }
 
-/// List URIs for all images associated with a product.
+/// List URIs for images associated with a product and their downsized
+/// variants, primary image first.
 async fn list_product_images(
     State(state): State<AppState>,
     Path(product_id): Path<Uuid>,
+    Query(params): Query<ListImagesParameters>,
 ) -> Result<Json<ListImagesResponse>, HttpError> {
     Ok(Json(
-        products::list_images(product_id, &state.db)
+        products::list_images(product_id, &params, state.read_db())
             .await
             .map(|images| ListImagesResponse { images })?,
     ))
 }
 
+/// The request body for POST /products/{id}/reviews.
+#[derive(Deserialize)]
+struct CreateReviewRequest {
+    /// The rating given to the product, from 1 to 5.
+    rating: u8,
+    /// The text of the review.
+    #[serde(deserialize_with = "text::review")]
+    body: String,
+}
+
+/// Review a product which the customer has received in a fulfilled order.
+async fn create_review(
+    State(state): State<AppState>,
+    Extension(session): Extension<CustomerSession>,
+    Path(product_id): Path<Uuid>,
+    ValidatedJson(body): ValidatedJson<CreateReviewRequest>,
+) -> Result<Json<Review>, HttpError> {
+    let review = reviews::create_review(
+        session.user_id(),
+        product_id,
+        body.rating,
+        body.body,
+        &state.db,
+    )
+    .await?;
+    tracing::info!(
+        "Customer {} reviewed product {product_id}.",
+        session.user_id()
+    );
+    Ok(Json(review))
+}
+
+/// Ask to be emailed when an out of stock product is back in stock.
+async fn request_stock_notification(
+    State(state): State<AppState>,
+    Extension(session): Extension<CustomerSession>,
+    Path(product_id): Path<Uuid>,
+) -> Result<(), HttpError> {
+    products::request_stock_notification(session.user_id(), product_id, &state.db).await?;
+    tracing::info!(
+        "Customer {} asked to be notified when product {product_id} is back in stock.",
+        session.user_id()
+    );
+    Ok(())
+}
+
+/// The response to GET /products/{id}/reviews.
+#[derive(Serialize)]
+struct ListReviewsResponse {
+    /// The page of reviews returned, most recent first.
+    reviews: Vec<Review>,
+    /// The page of results which was returned.
+    pagination: Pagination,
+}
+
+/// List the reviews of a product.
+async fn list_product_reviews(
+    State(state): State<AppState>,
+    Path(product_id): Path<Uuid>,
+    pagination: Pagination,
+) -> Result<Json<ListReviewsResponse>, HttpError> {
+    let reviews = reviews::list_product_reviews(product_id, pagination, state.read_db()).await?;
+    Ok(Json(ListReviewsResponse {
+        reviews,
+        pagination,
+    }))
+}
+
+impl From<reviews::errors::ReviewCreationError> for HttpError {
+    fn from(err: reviews::errors::ReviewCreationError) -> Self {
+        match err {
+            reviews::errors::ReviewCreationError::DatabaseError(error) => error.into(),
+            reviews::errors::ReviewCreationError::ProductNonExistent(product_id) => {
+                tracing::warn!("Attempted to review product {product_id}, which does not exist");
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(format!("Product {product_id} not found")),
+                )
+            }
+            reviews::errors::ReviewCreationError::NotPurchased(product_id) => {
+                tracing::warn!("Attempted to review product {product_id}, which was not purchased");
+                Self::new(
+                    StatusCode::FORBIDDEN,
+                    Some(String::from(
+                        "Only products received in a fulfilled order can be reviewed",
+                    )),
+                )
+            }
+            reviews::errors::ReviewCreationError::AlreadyReviewed(product_id) => {
+                tracing::warn!("Attempted to review product {product_id} a second time");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(format!("Product {product_id} has already been reviewed")),
+                )
+            }
+            reviews::errors::ReviewCreationError::InvalidRating(rating) => {
+                tracing::warn!("Attempted to review a product with out of range rating {rating}");
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Some(String::from("Rating must be between 1 and 5")),
+                )
+            }
+        }
+    }
+}
+
+impl From<products::errors::ProductBatchError> for HttpError {
+    fn from(err: products::errors::ProductBatchError) -> Self {
+        match err {
+            products::errors::ProductBatchError::DatabaseError(error) => error.into(),
+            products::errors::ProductBatchError::TooManyIds(count) => {
+                tracing::warn!(
+                    "Attempted to retrieve a batch of {count} products, exceeding the limit"
+                );
+                Self::new(
+                    StatusCode::BAD_REQUEST,
+                    Some(format!(
+                        "At most {MAX_PRODUCTS_PER_BATCH} products can be requested at once."
+                    )),
+                )
+            }
+        }
+    }
+}
+
 impl From<products::errors::ProductDeleteError> for HttpError {
     fn from(err: products::errors::ProductDeleteError) -> Self {
         match err {
             products::errors::ProductDeleteError::DatabaseError(error) => error.into(),
             products::errors::ProductDeleteError::NonExistent(product_id) => {
-                eprintln!("Attempted to delete product {product_id}, which does not exist");
+                tracing::warn!("Attempted to delete product {product_id}, which does not exist");
                 Self::new(
                     StatusCode::NOT_FOUND,
                     Some(format!("Product {product_id} not found")),
@@ -220,12 +665,50 @@ impl From<products::errors::ProductUpdateError> for HttpError {
         match err {
             products::errors::ProductUpdateError::DatabaseError(error) => error.into(),
             products::errors::ProductUpdateError::NonExistent(product_id) => {
-                eprintln!("Attempted to update product {product_id}, which does not exist");
+                tracing::warn!("Attempted to update product {product_id}, which does not exist");
                 Self::new(
                     StatusCode::NOT_FOUND,
                     Some(format!("Product {product_id} not found")),
                 )
             }
+            products::errors::ProductUpdateError::ConcurrencyConflict => {
+                tracing::warn!("Product was modified concurrently while being updated.");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from("Product was modified concurrently, try again")),
+                )
+            }
+            products::errors::ProductUpdateError::Archived(product_id) => {
+                tracing::warn!("Attempted to update product {product_id}, which has been archived");
+                Self::new(
+                    StatusCode::GONE,
+                    Some(format!("Product {product_id} has been archived")),
+                )
+            }
+        }
+    }
+}
+
+impl From<products::errors::ProductArchiveError> for HttpError {
+    fn from(err: products::errors::ProductArchiveError) -> Self {
+        match err {
+            products::errors::ProductArchiveError::DatabaseError(error) => error.into(),
+            products::errors::ProductArchiveError::NonExistent(product_id) => {
+                tracing::warn!("Attempted to archive product {product_id}, which does not exist");
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(format!("Product {product_id} not found")),
+                )
+            }
+            products::errors::ProductArchiveError::AlreadyArchived(product_id) => {
+                tracing::warn!(
+                    "Attempted to archive product {product_id}, which is already archived"
+                );
+                Self::new(
+                    StatusCode::GONE,
+                    Some(format!("Product {product_id} has been archived")),
+                )
+            }
         }
     }
 }
@@ -234,17 +717,52 @@ impl From<products::errors::AddImageError> for HttpError {
     fn from(err: products::errors::AddImageError) -> Self {
         match err {
             products::errors::AddImageError::DatabaseError(error) => error.into(),
-            products::errors::AddImageError::MediaStoreError(error) => {
-                eprintln!("Error in media object store while adding image: {error}");
-                Self::from(StatusCode::INTERNAL_SERVER_ERROR)
-            }
+            products::errors::AddImageError::MediaStoreError(error) => error.into(),
             products::errors::AddImageError::NonExistent(product_id) => {
-                eprintln!("Attempted to add an image to product {product_id} which does not exist");
+                tracing::warn!(
+                    "Attempted to add an image to product {product_id} which does not exist"
+                );
                 Self::new(
                     StatusCode::NOT_FOUND,
                     Some(format!("Product {product_id} not found.")),
                 )
             }
+            products::errors::AddImageError::TooManyImages(product_id) => {
+                tracing::warn!("Attempted to add an image to product {product_id} which has the maximum number of images");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(format!(
+                        "Product {product_id} already has the maximum of {MAX_IMAGES_PER_PRODUCT} images."
+                    )),
+                )
+            }
+        }
+    }
+}
+
+impl From<products::errors::StoreImageError> for HttpError {
+    fn from(err: products::errors::StoreImageError) -> Self {
+        tracing::error!("Error in media object store while adding image: {err}");
+        match err {
+            products::errors::StoreImageError::NotFound(_) => Self::new(
+                StatusCode::NOT_FOUND,
+                Some(String::from("The media store location was not found.")),
+            ),
+            products::errors::StoreImageError::AlreadyExists(_) => Self::new(
+                StatusCode::CONFLICT,
+                Some(String::from("The image already exists in the media store.")),
+            ),
+            products::errors::StoreImageError::Unavailable(_) => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                Some(String::from(
+                    "The media store is unavailable, try again later.",
+                )),
+            ),
+            products::errors::StoreImageError::InvalidFileType
+            | products::errors::StoreImageError::StorageError(_)
+            | products::errors::StoreImageError::ResizeError(_) => {
+                Self::from(StatusCode::INTERNAL_SERVER_ERROR)
+            }
         }
     }
 }
@@ -254,7 +772,7 @@ impl From<products::errors::ImageDeleteError> for HttpError {
         match err {
             products::errors::ImageDeleteError::DatabaseError(error) => error.into(),
             products::errors::ImageDeleteError::NonExistentImage(path, product_id) => {
-                eprintln!(
+                tracing::warn!(
                     "Attempted to delete non-existent image at {path} from product {product_id}"
                 );
                 Self::new(
@@ -265,3 +783,681 @@ impl From<products::errors::ImageDeleteError> for HttpError {
         }
     }
 }
+
+impl From<products::errors::ImageReorderError> for HttpError {
+    fn from(err: products::errors::ImageReorderError) -> Self {
+        match err {
+            products::errors::ImageReorderError::DatabaseError(error) => error.into(),
+            products::errors::ImageReorderError::NonExistent(product_id) => {
+                tracing::warn!(
+                    "Attempted to reorder images of product {product_id}, which does not exist"
+                );
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(format!("Product {product_id} not found")),
+                )
+            }
+            products::errors::ImageReorderError::ImageMismatch(product_id) => {
+                tracing::warn!(
+                    "Image order for product {product_id} did not match its current images"
+                );
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Some(String::from(
+                        "Image order must list each of the product's images exactly once",
+                    )),
+                )
+            }
+        }
+    }
+}
+
+impl From<products::errors::StockNotificationError> for HttpError {
+    fn from(err: products::errors::StockNotificationError) -> Self {
+        match err {
+            products::errors::StockNotificationError::DatabaseError(error) => error.into(),
+            products::errors::StockNotificationError::NonExistent(product_id) => {
+                tracing::warn!(
+                    "Attempted to request a stock notification for product {product_id}, which does not exist"
+                );
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(format!("Product {product_id} not found")),
+                )
+            }
+            products::errors::StockNotificationError::InStock(product_id) => {
+                tracing::warn!("Rejected stock notification request for product {product_id}, which is in stock");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from("Product is already in stock")),
+                )
+            }
+            products::errors::StockNotificationError::AlreadyRequested(product_id) => {
+                tracing::warn!(
+                    "Rejected duplicate stock notification request for product {product_id}"
+                );
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from("A notification has already been requested")),
+                )
+            }
+        }
+    }
+}
+
+impl From<products::errors::StockAdjustmentError> for HttpError {
+    fn from(err: products::errors::StockAdjustmentError) -> Self {
+        match err {
+            products::errors::StockAdjustmentError::DatabaseError(error) => error.into(),
+            products::errors::StockAdjustmentError::NonExistent(product_id) => {
+                tracing::warn!(
+                    "Attempted to adjust stock of product {product_id}, which does not exist"
+                );
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(format!("Product {product_id} not found")),
+                )
+            }
+            products::errors::StockAdjustmentError::InsufficientStock(product_id) => {
+                tracing::warn!("Rejected stock adjustment which would make product {product_id} stock negative");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from("Stock level cannot become negative")),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::iter::repeat_n;
+    use std::io::Cursor;
+
+    use axum::{
+        body::Body,
+        http::{
+            header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+            HeaderValue, Method, StatusCode,
+        },
+    };
+    use image::{ImageFormat, RgbImage};
+    use object_store::{path::Path, Attribute};
+    use serde_json::{json, Value};
+    use sqlx::postgres::PgPoolOptions;
+    use uuid::Uuid;
+
+    use crate::{
+        constants::{
+            api::MAX_MULTIPART_FIELDS,
+            products::MAX_IMAGES_PER_PRODUCT,
+            s3::{S3_BUCKET, S3_DOWNLOAD_PREFIX, S3_EXTERNAL_URI, S3_IMAGE_PREFIX},
+        },
+        db::{models::appuser::AppUserRole, ConnectionPool, ReadConnectionPool},
+        testing::{store_user, TestApp, TestResponse},
+    };
+
+    /// Store an administrator and a customer, and log each in as a client of
+    /// a new app.
+    async fn log_in_administrator_and_customer(db_conn: &ConnectionPool) -> (TestApp, TestApp) {
+        let mut administrator = store_user("admin@example.com", db_conn).await;
+        administrator.role = AppUserRole::Administrator;
+        administrator
+            .update(db_conn)
+            .await
+            .expect("User should be updated");
+        store_user("alice@example.com", db_conn).await;
+        let mut admin_app = TestApp::new(db_conn.clone());
+        assert_eq!(
+            admin_app.log_in("admin@example.com").await.status,
+            StatusCode::OK
+        );
+        let mut customer_app = admin_app.other_client();
+        assert_eq!(
+            customer_app.log_in("alice@example.com").await.status,
+            StatusCode::OK
+        );
+        (admin_app, customer_app)
+    }
+
+    /// Create a product as the administrator logged in to an app, returning
+    /// its ID.
+    async fn create_product(app: &mut TestApp, body: &Value) -> String {
+        let response = app.post("/products", body).await;
+        assert_eq!(response.status, StatusCode::OK);
+        response.string_at("/id")
+    }
+
+    /// A product created without `listed` is an unlisted draft, hidden from
+    /// customers until it is listed, and hidden again once unlisted, while an
+    /// explicit `listed` is honoured.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn products_are_drafts_until_listed(db_conn: ConnectionPool) {
+        let (mut admin_app, mut customer_app) = log_in_administrator_and_customer(&db_conn).await;
+        let draft = json!({ "name": "Widget", "description": "A widget.", "price": 1000u32 });
+        let product_id = create_product(&mut admin_app, &draft).await;
+        let uri = format!("/products/{product_id}");
+        let created = admin_app.get(&uri).await;
+        assert_eq!(created.json().pointer("/listed"), Some(&Value::Bool(false)));
+        assert_eq!(customer_app.get(&uri).await.status, StatusCode::NOT_FOUND);
+
+        for (action, status) in [("list", StatusCode::OK), ("unlist", StatusCode::NOT_FOUND)] {
+            let toggled = admin_app.post(&format!("{uri}/{action}"), &json!({})).await;
+            assert_eq!(toggled.status, StatusCode::OK, "{action}");
+            assert_eq!(customer_app.get(&uri).await.status, status, "{action}");
+        }
+
+        let listed = json!({
+            "name": "Gadget",
+            "description": "A gadget.",
+            "price": 1000u32,
+            "listed": true,
+        });
+        let listed_id = create_product(&mut admin_app, &listed).await;
+        assert_eq!(
+            customer_app
+                .get(&format!("/products/{listed_id}"))
+                .await
+                .status,
+            StatusCode::OK
+        );
+    }
+
+    /// Send a GET request with an If-None-Match header.
+    async fn get_if_none_match(app: &mut TestApp, uri: &str, etag: &HeaderValue) -> TestResponse {
+        let request = app
+            .request(Method::GET, uri)
+            .header(IF_NONE_MATCH, etag)
+            .body(Body::empty())
+            .expect("Request should be valid");
+        app.send(request).await
+    }
+
+    /// A product is returned with an `ETag`, and a request which already has
+    /// it is answered with an empty 304 Not Modified, until the product
+    /// changes.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn unchanged_product_is_not_modified(db_conn: ConnectionPool) {
+        let (mut admin_app, mut customer_app) = log_in_administrator_and_customer(&db_conn).await;
+        let body = json!({
+            "name": "Widget",
+            "description": "A widget.",
+            "price": 1000u32,
+            "listed": true,
+        });
+        let uri = format!("/products/{}", create_product(&mut admin_app, &body).await);
+        let first = customer_app.get(&uri).await;
+        assert_eq!(first.status, StatusCode::OK);
+        let etag = first
+            .headers
+            .get(ETAG)
+            .expect("Response should have an ETag")
+            .clone();
+
+        let revalidated = get_if_none_match(&mut customer_app, &uri, &etag).await;
+        assert_eq!(revalidated.status, StatusCode::NOT_MODIFIED);
+        assert!(revalidated.body.is_empty());
+        assert_eq!(revalidated.headers.get(ETAG), Some(&etag));
+
+        let update = json!({ "price": 1200u32 });
+        assert_eq!(
+            admin_app.send_json(Method::PUT, &uri, &update).await.status,
+            StatusCode::OK
+        );
+        let changed = get_if_none_match(&mut customer_app, &uri, &etag).await;
+        assert_eq!(changed.status, StatusCode::OK);
+        assert_ne!(changed.headers.get(ETAG), Some(&etag));
+    }
+
+    /// The product listing is also answered with 304 Not Modified while no
+    /// product has changed, and in full once one has.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn unchanged_product_list_is_not_modified(db_conn: ConnectionPool) {
+        let (mut admin_app, mut customer_app) = log_in_administrator_and_customer(&db_conn).await;
+        let first = customer_app.get("/products").await;
+        assert_eq!(first.status, StatusCode::OK);
+        let etag = first
+            .headers
+            .get(ETAG)
+            .expect("Response should have an ETag")
+            .clone();
+        assert_eq!(
+            get_if_none_match(&mut customer_app, "/products", &etag)
+                .await
+                .status,
+            StatusCode::NOT_MODIFIED
+        );
+        let body = json!({ "name": "Widget", "description": "A widget.", "price": 1000u32 });
+        create_product(&mut admin_app, &body).await;
+        assert_eq!(
+            get_if_none_match(&mut customer_app, "/products", &etag)
+                .await
+                .status,
+            StatusCode::OK
+        );
+    }
+
+    /// A small PNG image of the given width, which can be uploaded as a
+    /// product image. Images of different widths are stored separately.
+    fn png(width: u32) -> Vec<u8> {
+        let mut encoded = Cursor::new(Vec::new());
+        RgbImage::new(width, 1)
+            .write_to(&mut encoded, ImageFormat::Png)
+            .expect("Image should be encoded");
+        encoded.into_inner()
+    }
+
+    /// Upload `count` distinct images to a product, returning their URIs in
+    /// upload order.
+    async fn upload_images(app: &mut TestApp, product_id: &str, count: u32) -> Vec<String> {
+        let mut uris = Vec::new();
+        for width in 1..=count {
+            let response = app
+                .post_multipart(
+                    &format!("/products/{product_id}/images"),
+                    &[("image", &png(width))],
+                )
+                .await;
+            assert_eq!(response.status, StatusCode::OK);
+            uris.push(response.string_at("/path"));
+        }
+        uris
+    }
+
+    /// List the original URIs of a product's images.
+    async fn list_images(app: &mut TestApp, uri: &str) -> Vec<String> {
+        let response = app.get(uri).await;
+        assert_eq!(response.status, StatusCode::OK);
+        response
+            .json()
+            .pointer("/images")
+            .and_then(Value::as_array)
+            .expect("Images should be listed")
+            .iter()
+            .map(|image| {
+                image
+                    .pointer("/original")
+                    .and_then(Value::as_str)
+                    .expect("Image should have an original")
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    /// The catalog is exported to administrators as CSV, with fields
+    /// containing commas, quotes or line breaks escaped.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn catalog_is_exported_as_escaped_csv(db_conn: ConnectionPool) {
+        let (mut admin_app, mut customer_app) = log_in_administrator_and_customer(&db_conn).await;
+        let body = json!({
+            "name": "Widget",
+            "description": "A small, \"blue\"\nwidget.",
+            "price": 1000u32,
+        });
+        let product_id = create_product(&mut admin_app, &body).await;
+        assert_eq!(
+            customer_app.get("/products/export.csv").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        let exported = admin_app.get("/products/export.csv").await;
+        assert_eq!(exported.status, StatusCode::OK);
+        assert_eq!(
+            exported
+                .headers
+                .get(CONTENT_TYPE)
+                .map(HeaderValue::as_bytes),
+            Some(b"text/csv; charset=utf-8".as_slice())
+        );
+        assert_eq!(
+            String::from_utf8(exported.body).expect("Export should be UTF-8"),
+            format!(
+                "id,name,price,listed,stock,description\r\n\
+                {product_id},Widget,1000,false,,\"A small, \"\"blue\"\"\nwidget.\"\r\n"
+            )
+        );
+    }
+
+    /// Images can be added to a product up to `MAX_IMAGES_PER_PRODUCT`, after
+    /// which any more are refused.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn images_are_limited_per_product(db_conn: ConnectionPool) {
+        let (mut admin_app, mut customer_app) = log_in_administrator_and_customer(&db_conn).await;
+        let body = json!({ "name": "Widget", "description": "A widget.", "price": 1000u32 });
+        let product_id = create_product(&mut admin_app, &body).await;
+        let uris = upload_images(&mut admin_app, &product_id, MAX_IMAGES_PER_PRODUCT).await;
+        let uri = format!("/products/{product_id}/images");
+        let refused = admin_app
+            .post_multipart(&uri, &[("image", &png(MAX_IMAGES_PER_PRODUCT + 1))])
+            .await;
+        assert_eq!(refused.status, StatusCode::CONFLICT);
+        assert_eq!(list_images(&mut customer_app, &uri).await, uris);
+    }
+
+    /// Images are listed in upload order, optionally limited, and can be
+    /// filtered to just the primary (first) image.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn image_listing_is_ordered_and_filtered(db_conn: ConnectionPool) {
+        let (mut admin_app, mut customer_app) = log_in_administrator_and_customer(&db_conn).await;
+        let body = json!({ "name": "Widget", "description": "A widget.", "price": 1000u32 });
+        let product_id = create_product(&mut admin_app, &body).await;
+        let uris = upload_images(&mut admin_app, &product_id, 3).await;
+        let uri = format!("/products/{product_id}/images");
+        assert_eq!(list_images(&mut customer_app, &uri).await, uris);
+        assert_eq!(
+            list_images(&mut customer_app, &format!("{uri}?limit=2")).await,
+            uris.get(..2).expect("Three images should be uploaded")
+        );
+        for query in ["?primary_only=true", "?primary_only=true&limit=3"] {
+            assert_eq!(
+                list_images(&mut customer_app, &format!("{uri}{query}")).await,
+                uris.get(..1).expect("Three images should be uploaded"),
+                "{query}"
+            );
+        }
+    }
+
+    /// Images can be reordered, making the first the product's primary
+    /// image, but only by listing exactly the product's current images.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn images_are_reordered(db_conn: ConnectionPool) {
+        let (mut admin_app, mut customer_app) = log_in_administrator_and_customer(&db_conn).await;
+        let body = json!({
+            "name": "Widget",
+            "description": "A widget.",
+            "price": 1000u32,
+            "listed": true,
+        });
+        let product_id = create_product(&mut admin_app, &body).await;
+        let mut uris = upload_images(&mut admin_app, &product_id, 3).await;
+        uris.reverse();
+        let order_uri = format!("/products/{product_id}/images/order");
+        let reordered = admin_app
+            .send_json(Method::PUT, &order_uri, &json!({ "paths": uris }))
+            .await;
+        assert_eq!(reordered.status, StatusCode::OK);
+        let images_uri = format!("/products/{product_id}/images");
+        assert_eq!(list_images(&mut customer_app, &images_uri).await, uris);
+        let product = customer_app.get(&format!("/products/{product_id}")).await;
+        assert_eq!(
+            product.string_at("/primary_image"),
+            *uris.first().expect("Three images should be uploaded")
+        );
+
+        let mut missing = uris.clone();
+        missing.push(String::from("images/missing.png"));
+        let mut incomplete = uris.clone();
+        incomplete.pop();
+        for paths in [missing, incomplete] {
+            let refused = admin_app
+                .send_json(Method::PUT, &order_uri, &json!({ "paths": paths }))
+                .await;
+            assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        assert_eq!(list_images(&mut customer_app, &images_uri).await, uris);
+    }
+
+    /// Only as many multipart fields as `MAX_MULTIPART_FIELDS` are read when
+    /// looking for an uploaded image, so an image after too many junk fields
+    /// is refused.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn image_upload_reads_limited_fields(db_conn: ConnectionPool) {
+        let (mut admin_app, _) = log_in_administrator_and_customer(&db_conn).await;
+        let body = json!({ "name": "Widget", "description": "A widget.", "price": 1000u32 });
+        let uri = format!(
+            "/products/{}/images",
+            create_product(&mut admin_app, &body).await
+        );
+        let image = png(1);
+        let junk_fields = |count: usize| {
+            repeat_n(("junk", b"junk".as_slice()), count)
+                .chain([("image", image.as_slice())])
+                .collect::<Vec<_>>()
+        };
+        let limit = usize::try_from(*MAX_MULTIPART_FIELDS).expect("Field limit should fit");
+        let refused = admin_app.post_multipart(&uri, &junk_fields(limit)).await;
+        assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            refused.string_at("/message"),
+            format!("Image field must be within the first {limit} form fields")
+        );
+        let accepted = admin_app
+            .post_multipart(&uri, &junk_fields(limit.saturating_sub(1)))
+            .await;
+        assert_eq!(accepted.status, StatusCode::OK);
+    }
+
+    /// The `ETag` a GET request is answered with.
+    async fn etag(app: &mut TestApp, uri: &str) -> HeaderValue {
+        let response = app.get(uri).await;
+        assert_eq!(response.status, StatusCode::OK);
+        response
+            .headers
+            .get(ETAG)
+            .expect("Response should have an ETag")
+            .clone()
+    }
+
+    /// Creating, updating, or adjusting the stock of a product changes the
+    /// product listing's `ETag`, and updating it changes the product's own.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn product_list_etag_changes_on_mutation(db_conn: ConnectionPool) {
+        let (mut admin_app, mut customer_app) = log_in_administrator_and_customer(&db_conn).await;
+        let initial = etag(&mut customer_app, "/products").await;
+        let body = json!({
+            "name": "Widget",
+            "description": "A widget.",
+            "price": 1000u32,
+            "listed": true,
+        });
+        let uri = format!("/products/{}", create_product(&mut admin_app, &body).await);
+        let created = etag(&mut customer_app, "/products").await;
+        assert_ne!(created, initial);
+        let product = etag(&mut customer_app, &uri).await;
+
+        let update = json!({ "price": 2000u32 });
+        let updated = admin_app.send_json(Method::PUT, &uri, &update).await;
+        assert_eq!(updated.status, StatusCode::OK);
+        let after_update = etag(&mut customer_app, "/products").await;
+        assert_ne!(after_update, created);
+        assert_ne!(etag(&mut customer_app, &uri).await, product);
+
+        let stock = json!({ "delta": 5i64 });
+        let adjusted = admin_app.post(&format!("{uri}/stock"), &stock).await;
+        assert_eq!(adjusted.status, StatusCode::OK);
+        assert_ne!(etag(&mut customer_app, "/products").await, after_update);
+    }
+
+    /// A product which never existed is not found, an unlisted one is not
+    /// found by customers only, and an archived one is gone for everyone and
+    /// can't be changed.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn archived_product_is_gone(db_conn: ConnectionPool) {
+        let (mut admin_app, mut customer_app) = log_in_administrator_and_customer(&db_conn).await;
+        let body = json!({ "name": "Widget", "description": "A widget.", "price": 1000u32 });
+        let unlisted_uri = format!("/products/{}", create_product(&mut admin_app, &body).await);
+        let archived_uri = format!("/products/{}", create_product(&mut admin_app, &body).await);
+        let archived = admin_app
+            .post(&format!("{archived_uri}/archive"), &json!({}))
+            .await;
+        assert_eq!(archived.status, StatusCode::OK);
+        let missing_uri = format!("/products/{}", Uuid::new_v4());
+        for (uri, customer_status, admin_status) in [
+            (&missing_uri, StatusCode::NOT_FOUND, StatusCode::NOT_FOUND),
+            (&unlisted_uri, StatusCode::NOT_FOUND, StatusCode::OK),
+            (&archived_uri, StatusCode::GONE, StatusCode::GONE),
+        ] {
+            assert_eq!(customer_app.get(uri).await.status, customer_status, "{uri}");
+            assert_eq!(admin_app.get(uri).await.status, admin_status, "{uri}");
+        }
+
+        for action in ["archive", "list"] {
+            let refused = admin_app
+                .post(&format!("{archived_uri}/{action}"), &json!({}))
+                .await;
+            assert_eq!(refused.status, StatusCode::GONE, "{action}");
+        }
+        let listing = admin_app.get("/products").await;
+        assert_eq!(
+            listing
+                .json()
+                .pointer("/products")
+                .and_then(Value::as_array)
+                .map(Vec::len),
+            Some(1)
+        );
+    }
+
+    /// An image is stored with the disposition asked for when it is uploaded,
+    /// under that disposition's prefix, and is displayed inline by default.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn image_is_stored_with_requested_disposition(db_conn: ConnectionPool) {
+        let (mut admin_app, _) = log_in_administrator_and_customer(&db_conn).await;
+        let body = json!({ "name": "Widget", "description": "A widget.", "price": 1000u32 });
+        let image = png(1);
+        let uri_prefix = format!("{}/{}", &*S3_EXTERNAL_URI, &*S3_BUCKET);
+        for (query, disposition, prefix) in [
+            ("", "inline", &*S3_IMAGE_PREFIX),
+            ("?disposition=inline", "inline", &*S3_IMAGE_PREFIX),
+            (
+                "?disposition=attachment",
+                "attachment",
+                &*S3_DOWNLOAD_PREFIX,
+            ),
+        ] {
+            let product_id = create_product(&mut admin_app, &body).await;
+            let uploaded = admin_app
+                .post_multipart(
+                    &format!("/products/{product_id}/images{query}"),
+                    &[("image", image.as_slice())],
+                )
+                .await;
+            assert_eq!(uploaded.status, StatusCode::OK, "{query}");
+            let uri = uploaded.string_at("/path");
+            let path = uri
+                .strip_prefix(&uri_prefix)
+                .expect("Image should be in the bucket");
+            assert!(path.starts_with(prefix.as_str()), "{query}");
+            let stored = admin_app
+                .state
+                .media_store
+                .get(&Path::from(path))
+                .await
+                .expect("Image should be stored");
+            assert_eq!(
+                stored
+                    .attributes
+                    .get(&Attribute::ContentDisposition)
+                    .map(AsRef::as_ref),
+                Some(disposition),
+                "{query}"
+            );
+        }
+    }
+
+    /// Catalog reads are made against the read replica, while writes go to
+    /// the primary. The replica is a separate pool on the same database, so
+    /// whether it was used is told by whether it opened any connections.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn catalog_reads_use_replica(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let mut administrator = store_user("admin@example.com", &db_conn).await;
+        administrator.role = AppUserRole::Administrator;
+        administrator
+            .update(&db_conn)
+            .await
+            .expect("User should be updated");
+        let replica = PgPoolOptions::new().connect_lazy_with((*db_conn.connect_options()).clone());
+        let mut admin_app =
+            TestApp::with_read_db(db_conn, ReadConnectionPool::from(replica.clone()));
+        assert_eq!(
+            admin_app.log_in("admin@example.com").await.status,
+            StatusCode::OK
+        );
+        let body = json!({
+            "name": "Widget",
+            "description": "A widget.",
+            "price": 1000u32,
+            "listed": true,
+        });
+        let uri = format!("/products/{}", create_product(&mut admin_app, &body).await);
+        assert_eq!(replica.size(), 0);
+
+        let mut customer_app = admin_app.other_client();
+        assert_eq!(
+            customer_app.log_in("alice@example.com").await.status,
+            StatusCode::OK
+        );
+        for read_uri in [uri.as_str(), "/products"] {
+            assert_eq!(customer_app.get(read_uri).await.status, StatusCode::OK);
+        }
+        assert!(replica.size() > 0);
+    }
+
+    /// Adjust the stock of a product as the administrator logged in to an app.
+    async fn adjust_stock(app: &mut TestApp, product_uri: &str, delta: i64) {
+        let adjusted = app
+            .post(&format!("{product_uri}/stock"), &json!({ "delta": delta }))
+            .await;
+        assert_eq!(adjusted.status, StatusCode::OK, "{delta}");
+    }
+
+    /// A customer can ask once to be told when an out of stock product is
+    /// back in stock, and is emailed on the next restock only.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn customer_is_notified_once_on_restock(db_conn: ConnectionPool) {
+        let (mut admin_app, mut customer_app) = log_in_administrator_and_customer(&db_conn).await;
+        let body = json!({
+            "name": "Widget",
+            "description": "A widget.",
+            "price": 1000u32,
+            "listed": true,
+        });
+        let uri = format!("/products/{}", create_product(&mut admin_app, &body).await);
+        let notify_uri = format!("{uri}/notify-me");
+        adjust_stock(&mut admin_app, &uri, 1i64).await;
+        assert_eq!(
+            customer_app.post(&notify_uri, &json!({})).await.status,
+            StatusCode::CONFLICT
+        );
+        adjust_stock(&mut admin_app, &uri, -1i64).await;
+        assert_eq!(
+            customer_app.post(&notify_uri, &json!({})).await.status,
+            StatusCode::OK
+        );
+        assert_eq!(
+            customer_app.post(&notify_uri, &json!({})).await.status,
+            StatusCode::CONFLICT
+        );
+
+        adjust_stock(&mut admin_app, &uri, 5i64).await;
+        let sent = customer_app.emails.wait_for(1).await;
+        assert!(matches!(
+            sent.as_slice(),
+            [email] if email.recipient == "alice@example.com"
+                && email.subject == "Widget is back in stock at SecureCart"
+        ));
+        // The request was cleared, so this restock notifies no one.
+        adjust_stock(&mut admin_app, &uri, -5i64).await;
+        adjust_stock(&mut admin_app, &uri, 5i64).await;
+        adjust_stock(&mut admin_app, &uri, -5i64).await;
+        assert_eq!(
+            customer_app.post(&notify_uri, &json!({})).await.status,
+            StatusCode::OK
+        );
+        adjust_stock(&mut admin_app, &uri, 5i64).await;
+        assert_eq!(customer_app.emails.wait_for(2).await.len(), 2);
+    }
+}
@@ -1,27 +1,30 @@
 //! Routes for onboarding and user registration.
 use crate::{
-    constants::passwords::{PASSWORD_MAX_LENGTH, PASSWORD_MIN_LENGTH},
+    constants::{
+        cookies::{CSRF_COOKIE_NAME, SESSION_COOKIE_NAME},
+        passwords::{PASSWORD_MAX_LENGTH, PASSWORD_MIN_LENGTH},
+    },
     db::models::appuser::AppUserInsert,
-    middleware::session::session_middleware,
+    middleware::{features::require_email, session::session_middleware},
     services::{
         registration::{self, PrimaryAuthenticationMethod},
         sessions::{RegistrationSession, SessionTrait as _},
     },
     state::AppState,
-    utils::httperror::HttpError,
+    utils::{
+        client_ip::client_ip, cookies::build_session_cookie, email::EmailAddress,
+        httperror::HttpError, json::ValidatedJson, redact::RedactedEmail,
+    },
 };
 use axum::{
     extract::{Extension, Json, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     middleware::from_fn_with_state,
     routing::{get, post},
     Router,
 };
-use axum_extra::extract::{
-    cookie::{Cookie, SameSite},
-    CookieJar,
-};
-use serde::Deserialize;
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
 
 /// Create a router for the /onboarding route.
 pub fn create_router(state: &AppState) -> Router<AppState> {
@@ -33,6 +36,12 @@ pub fn create_router(state: &AppState) -> Router<AppState> {
         ))
         .route("/", get(root))
         .route("/", post(signup_init))
+        .route("/check-email", post(check_email))
+        .route("/guest", post(upgrade_guest))
+        .route(
+            "/guest/link",
+            post(request_guest_upgrade).layer(from_fn_with_state(state.clone(), require_email)),
+        )
 }
 
 /// The root route for /onboarding, which does nothing.
@@ -50,29 +59,82 @@ struct SignUpInitRequest {
 /// This route initialises the onboarding process by creating a temporary
 /// registration session with the user's data associated with it. The database
 /// will not be modified until the signup process is fully complete, and the
-/// data will be deleted after the registration timeout period expires.
+/// data will be deleted after the registration timeout period expires. Each
+/// client may only start `SIGNUP_RATE_LIMIT_ATTEMPTS` signups per window.
 async fn signup_init(
+    headers: HeaderMap,
     cookies: CookieJar,
     State(state): State<AppState>,
-    Json(body): Json<SignUpInitRequest>,
+    ValidatedJson(body): ValidatedJson<SignUpInitRequest>,
 ) -> Result<CookieJar, HttpError> {
-    let mut session_store_conn = state.session_store.clone();
-    let db_conn = &state.db;
+    let client_ip = client_ip(&headers)?;
+    let mut session_store_conn = state.session_conn();
+    if let Some(reset_after) = session_store_conn.signup_rate_limit(client_ip).await? {
+        tracing::warn!("Client {client_ip} is rate-limited for excessive signups.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(format!(
+                "Too many signups. Try again in {reset_after} seconds."
+            )),
+        ));
+    }
+    let db_conn = state.db();
     let session =
-        registration::signup_init(body.user_data, &mut session_store_conn, db_conn).await?;
+        registration::signup_init(body.user_data, client_ip, &mut session_store_conn, db_conn)
+            .await?;
     Ok(cookies
-        .add(
-            Cookie::build(("session", session.token()))
-                .http_only(true)
-                .path("/")
-                .secure(true)
-                .same_site(SameSite::Strict),
-        )
-        .add(
-            Cookie::build(("session_csrf", session.csrf_token()))
-                .path("/")
-                .same_site(SameSite::Strict),
+        .add(build_session_cookie(
+            &SESSION_COOKIE_NAME,
+            session.token(),
+            true,
         ))
+        .add(build_session_cookie(
+            &CSRF_COOKIE_NAME,
+            session.csrf_token(),
+            false,
+        )))
+}
+
+/// Request body for /registration/check-email.
+#[derive(Deserialize)]
+struct CheckEmailRequest {
+    /// The email to check the availability of.
+    email: EmailAddress,
+}
+
+/// Response body for /registration/check-email.
+#[derive(Serialize)]
+struct CheckEmailResponse {
+    /// Whether the email can be used to sign up.
+    available: bool,
+}
+
+/// Check whether an email is already registered, so signup forms can warn
+/// the user early. This allows enumerating registered emails, which is
+/// mitigated by a fixed response delay and a strict per-client rate limit,
+/// making enumeration no faster than repeatedly attempting signups.
+async fn check_email(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    ValidatedJson(body): ValidatedJson<CheckEmailRequest>,
+) -> Result<Json<CheckEmailResponse>, HttpError> {
+    let client_ip = client_ip(&headers)?;
+    if state
+        .session_conn()
+        .bruteforce_timeout(&format!("check-email:{client_ip}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!(
+            "Client {client_ip} is rate-limited for excessive email availability checks."
+        );
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many email checks.")),
+        ));
+    }
+    let available = registration::check_email_available(body.email, state.db()).await?;
+    Ok(Json(CheckEmailResponse { available }))
 }
 
 /// Request body for /onboard/credential.
@@ -88,51 +150,133 @@ struct SignUpAddCredentialRequest {
 async fn signup_add_credential(
     State(state): State<AppState>,
     Extension(session): Extension<RegistrationSession>,
-    Json(body): Json<SignUpAddCredentialRequest>,
+    ValidatedJson(body): ValidatedJson<SignUpAddCredentialRequest>,
 ) -> Result<(), HttpError> {
-    let mut session_store_conn = state.session_store.clone();
+    let mut session_store_conn = state.session_conn();
     registration::add_credential_and_commit(
         session,
         body.credential,
-        &state.db,
+        state.db(),
+        &mut session_store_conn,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Request body for /registration/guest/link.
+#[derive(Deserialize)]
+struct GuestUpgradeLinkRequest {
+    /// The email address the guest placed their orders with.
+    pub email: EmailAddress,
+}
+
+/// Email a link with which the guests who placed orders with the given address
+/// can be upgraded to a full account. Always succeeds (short of rate limiting
+/// or storage errors), whether or not any such guest exists, so that it can't
+/// be used to discover who has placed orders.
+async fn request_guest_upgrade(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    ValidatedJson(body): ValidatedJson<GuestUpgradeLinkRequest>,
+) -> Result<(), HttpError> {
+    let client_ip = client_ip(&headers)?;
+    let mut session_store_conn = state.session_conn();
+    if session_store_conn
+        .bruteforce_timeout(&format!("guest-upgrade:{client_ip}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!("Client {client_ip} is rate-limited for excessive guest upgrade requests.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many guest upgrade requests.")),
+        ));
+    }
+    registration::request_guest_upgrade(
+        body.email,
+        state.db(),
         &mut session_store_conn,
+        state.email_sender.as_ref(),
     )
     .await?;
     Ok(())
 }
 
+/// Request body for /registration/guest.
+#[derive(Deserialize)]
+struct UpgradeGuestRequest {
+    /// The token from a link sent by /registration/guest/link.
+    pub token: String,
+    /// The credential to give the guest.
+    pub credential: PrimaryAuthenticationMethod,
+}
+
+/// Upgrade a guest user to a full account by giving them a credential. The
+/// guest proves that they own the email their orders were placed with by a
+/// token sent to it, since guests have no other way to authenticate.
+async fn upgrade_guest(
+    State(state): State<AppState>,
+    ValidatedJson(body): ValidatedJson<UpgradeGuestRequest>,
+) -> Result<(), HttpError> {
+    let mut session_store_conn = state.session_conn();
+    registration::upgrade_guest(
+        &body.token,
+        body.credential,
+        state.db(),
+        &mut session_store_conn,
+    )
+    .await?;
+    Ok(())
+}
+
+impl From<registration::errors::GuestUpgradeError> for HttpError {
+    fn from(value: registration::errors::GuestUpgradeError) -> Self {
+        match value {
+            registration::errors::GuestUpgradeError::StorageError(err) => err.into(),
+            registration::errors::GuestUpgradeError::Credential(err) => err.into(),
+            registration::errors::GuestUpgradeError::InvalidToken => {
+                tracing::warn!("Attempt to upgrade a guest with an invalid guest upgrade token.");
+                Self::from(StatusCode::FORBIDDEN)
+            }
+        }
+    }
+}
+
 impl From<registration::errors::SignupInitError> for HttpError {
     fn from(value: registration::errors::SignupInitError) -> Self {
         match value {
             registration::errors::SignupInitError::StorageError(err) => err.into(),
             registration::errors::SignupInitError::DuplicateEmail(email) => {
-                eprintln!("Attempt to sign up with duplicate email {email}.");
+                tracing::warn!(
+                    "Attempt to sign up with duplicate email {}.",
+                    RedactedEmail::new(&email)
+                );
                 Self::new(
                     StatusCode::CONFLICT,
                     Some(format!("Email {email} is already in use.")),
                 )
             }
-            registration::errors::SignupInitError::EmptyAddress => {
-                eprintln!("Attempt to sign up with empty address");
-                Self::new(
-                    StatusCode::UNPROCESSABLE_ENTITY,
-                    Some(String::from("Address cannot be empty")),
-                )
-            }
             registration::errors::SignupInitError::EmptySurname => {
-                eprintln!("Attempt to sign up with empty surname");
+                tracing::warn!("Attempt to sign up with empty surname");
                 Self::new(
                     StatusCode::UNPROCESSABLE_ENTITY,
                     Some(String::from("surname cannot be empty")),
                 )
             }
             registration::errors::SignupInitError::EmptyForename => {
-                eprintln!("Attempt to sign up with empty forename");
+                tracing::warn!("Attempt to sign up with empty forename");
                 Self::new(
                     StatusCode::UNPROCESSABLE_ENTITY,
                     Some(String::from("forename cannot be empty")),
                 )
             }
+            registration::errors::SignupInitError::TooManySessions => {
+                tracing::warn!("Client has too many signups in progress.");
+                Self::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some(String::from("Too many signups in progress.")),
+                )
+            }
         }
     }
 }
@@ -142,7 +286,7 @@ impl From<registration::errors::AddCredentialError> for HttpError {
         match value {
             registration::errors::AddCredentialError::StorageError(err) => err.into(),
             registration::errors::AddCredentialError::PasswordTooShort => {
-                eprintln!("Signup attempt with password below minimum length.");
+                tracing::warn!("Signup attempt with password below minimum length.");
                 Self::new(
                     StatusCode::UNPROCESSABLE_ENTITY,
                     Some(format!(
@@ -151,7 +295,7 @@ impl From<registration::errors::AddCredentialError> for HttpError {
                 )
             }
             registration::errors::AddCredentialError::PasswordTooLong => {
-                eprintln!("Signup attempt with password above maximum length.");
+                tracing::warn!("Signup attempt with password above maximum length.");
                 Self::new(
                     StatusCode::UNPROCESSABLE_ENTITY,
                     Some(format!(
@@ -159,6 +303,105 @@ impl From<registration::errors::AddCredentialError> for HttpError {
                     )),
                 )
             }
+            registration::errors::AddCredentialError::AlreadyRegistered(email) => {
+                tracing::warn!(
+                    "Attempt to complete signup for already registered email {}.",
+                    RedactedEmail::new(&email)
+                );
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(format!("Email {email} is already in use.")),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use crate::{
+        constants::sessions::SIGNUP_RATE_LIMIT_ATTEMPTS,
+        db::ConnectionPool,
+        testing::{store_user, TestApp},
+    };
+
+    /// The body of a signup with an email.
+    fn signup(email: &str) -> Value {
+        json!({
+            "user_data": {
+                "email": email,
+                "forename": "Bob",
+                "surname": "Smith",
+                "address": {
+                    "line1": "1 High Street",
+                    "city": "London",
+                    "postcode": "SW1A 1AA",
+                    "country": "GB",
+                },
+            },
+        })
+    }
+
+    /// A signup whose email is malformed is rejected with the path to the
+    /// email within the body, before anything is stored.
+    #[tokio::test]
+    async fn invalid_email_is_reported_by_field() {
+        let response = TestApp::without_db()
+            .post("/registration", &signup("bob@"))
+            .await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.string_at("/error_code"), "INVALID_FIELD");
+        assert_eq!(response.string_at("/errors/0/field"), "user_data.email");
+        assert!(response
+            .string_at("/errors/0/reason")
+            .starts_with("malformed email address"));
+    }
+
+    /// A signup missing a required field is rejected with the path to the
+    /// object the field is missing from, and the field's name.
+    #[tokio::test]
+    async fn missing_field_is_reported_by_field() {
+        let mut body = signup("bob@example.com");
+        body.pointer_mut("/user_data")
+            .and_then(Value::as_object_mut)
+            .expect("Signup should have user data")
+            .remove("surname");
+        let response = TestApp::without_db().post("/registration", &body).await;
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.string_at("/errors/0/field"), "user_data");
+        assert!(response
+            .string_at("/errors/0/reason")
+            .starts_with("missing field `surname`"));
+    }
+
+    /// A client may only start so many signups, even from fresh sessions,
+    /// and being rate limited for signups doesn't stop it logging in.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn signups_are_rate_limited_by_client(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let app = TestApp::new(db_conn);
+        for attempt in 0..*SIGNUP_RATE_LIMIT_ATTEMPTS {
+            let response = app
+                .other_client()
+                .post(
+                    "/registration",
+                    &signup(&format!("bob{attempt}@example.com")),
+                )
+                .await;
+            assert_eq!(response.status, StatusCode::OK, "signup {attempt}");
         }
+        let limited = app
+            .other_client()
+            .post("/registration", &signup("carol@example.com"))
+            .await;
+        assert_eq!(limited.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            app.other_client().log_in("alice@example.com").await.status,
+            StatusCode::OK
+        );
     }
 }
@@ -2,52 +2,106 @@
 use axum::{
     extract::{Path, Query, State},
     http::StatusCode,
-    middleware::from_fn_with_state,
+    middleware::{from_fn, from_fn_with_state},
     routing::{delete, get, post, put},
     Extension, Json, Router,
 };
-use axum_extra::extract::{cookie::Cookie, CookieJar};
+use axum_extra::extract::CookieJar;
 use base64::{prelude::BASE64_STANDARD, Engine as _};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
-    constants::passwords::{PASSWORD_MAX_LENGTH, PASSWORD_MIN_LENGTH},
-    db::models::appuser::{AppUser, AppUserRole, AppUserSearchParameters},
-    middleware::session::session_middleware,
+    constants::{
+        cookies::{CSRF_COOKIE_NAME, SESSION_COOKIE_NAME},
+        passwords::{PASSWORD_MAX_LENGTH, PASSWORD_MIN_LENGTH},
+    },
+    db::models::{
+        appuser::{AppUser, AppUserRole, AppUserSearchParameters},
+        product::Product,
+    },
+    middleware::{
+        features::require_email,
+        session::{reject_impersonation, session_middleware},
+    },
     services::{
-        registration,
-        sessions::{AdministratorSession, GenericAuthenticatedSession},
-        users,
+        auth, registration, reviews,
+        sessions::{
+            self, AdministratorSession, CustomerSession, GenericAuthenticatedSession,
+            SessionTrait as _,
+        },
+        users::{self, UserAction},
     },
     state::AppState,
-    utils::httperror::HttpError,
+    utils::{
+        cookies::{build_session_cookie, remove_session_cookies},
+        httperror::HttpError,
+        json::ValidatedJson,
+        pagination::Pagination,
+    },
 };
 
 /// TODO: add documentation
 pub fn create_router(state: &AppState) -> Router<AppState> {
     let authenticated = Router::new()
         .route("/self", get(retrieve_self))
+        .route("/self/2fa/new", get(generate_2fa))
+        .route("/self/2fa/verify", post(verify_2fa))
+        .layer(from_fn_with_state(
+            state.clone(),
+            session_middleware::<GenericAuthenticatedSession>,
+        ));
+    // Changing credentials or contact details (which can be used to recover
+    // the account) or deleting the account is never allowed while an
+    // administrator is impersonating the user.
+    let credentials = Router::new()
         .route("/self", put(update_self))
         .route("/self/credential", put(update_credential))
-        .route("/self/2fa/new", get(generate_2fa))
         .route("/self/2fa", post(set_2fa))
+        .route(
+            "/self/2fa/email",
+            post(request_email_mfa).layer(from_fn_with_state(state.clone(), require_email)),
+        )
+        .route(
+            "/self/2fa/email/confirm",
+            post(enable_email_mfa).layer(from_fn_with_state(state.clone(), require_email)),
+        )
+        .route("/self/2fa/email", delete(disable_email_mfa))
+        .route("/self/phone/verify", post(request_phone_verification))
+        .route("/self/phone/verify/confirm", post(verify_phone))
         .route("/self", delete(delete_self))
+        .layer(from_fn(reject_impersonation))
         .layer(from_fn_with_state(
             state.clone(),
             session_middleware::<GenericAuthenticatedSession>,
         ));
+    let customer = Router::new()
+        .route("/self/reviewable", get(list_reviewable_products))
+        .layer(from_fn_with_state(
+            state.clone(),
+            session_middleware::<CustomerSession>,
+        ));
     let administrator = Router::new()
         .route("/", get(search_users))
         .route("/{user_id}", get(retrieve_user))
         .route("/{user_id}", put(update_user))
         .route("/{user_id}", delete(delete_user))
         .route("/{user_id}/promote", post(promote_user))
+        .route("/{user_id}/restore", post(restore_user))
+        .route("/{user_id}/revoke-sessions", post(revoke_user_sessions))
+        .route("/{user_id}/impersonate", post(impersonate_user))
+        .route(
+            "/{user_id}/sessions/{session_ref}",
+            delete(revoke_user_session),
+        )
         .layer(from_fn_with_state(
             state.clone(),
             session_middleware::<AdministratorSession>,
         ));
-    authenticated.merge(administrator)
+    authenticated
+        .merge(credentials)
+        .merge(customer)
+        .merge(administrator)
 }
 
 /// TODO: add documentation
@@ -56,10 +110,10 @@ async fn retrieve_user(
     Extension(session): Extension<AdministratorSession>,
     Path(user_id): Path<Uuid>,
 ) -> Result<Json<AppUser>, HttpError> {
-    let user = users::retrieve_user(user_id, &state.db)
+    let user = users::retrieve_user(user_id, state.db())
         .await?
         .ok_or_else(|| {
-            eprintln!(
+            tracing::warn!(
                 "Administrator {} attempted to retrieve data of user {}, who does not exist",
                 session.user_id(),
                 user_id
@@ -69,17 +123,30 @@ async fn retrieve_user(
     Ok(Json(user))
 }
 
+#[derive(Serialize)]
+/// The response to GET /users/self.
+struct RetrieveSelfResponse {
+    /// The authenticated user.
+    #[serde(flatten)]
+    user: AppUser,
+    /// The administrator impersonating the user, if this session is being
+    /// used for impersonation, so that the frontend can make it obvious.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    impersonated_by: Option<Uuid>,
+}
+
 /// TODO: add documentation
 async fn retrieve_self(
     State(state): State<AppState>,
     Extension(session): Extension<GenericAuthenticatedSession>,
-) -> Result<Json<AppUser>, HttpError> {
-    Ok(Json(
-        users::retrieve_user(session.user_id(), &state.db).await?.ok_or_else(|| {
-            eprintln!("User {} was not found while requesting their own data. Something is critically wrong.", session.user_id());
+) -> Result<Json<RetrieveSelfResponse>, HttpError> {
+    Ok(Json(RetrieveSelfResponse {
+        user: users::retrieve_user(session.user_id(), state.db()).await?.ok_or_else(|| {
+            tracing::error!("User {} was not found while requesting their own data. Something is critically wrong.", session.user_id());
             StatusCode::INTERNAL_SERVER_ERROR
         })?,
-    ))
+        impersonated_by: session.impersonated_by(),
+    }))
 }
 
 #[derive(Serialize)]
@@ -94,7 +161,7 @@ struct Generate2faResponse {
 async fn generate_2fa() -> Result<Json<Generate2faResponse>, HttpError> {
     let totp = users::generate_2fa()?;
     let qr = totp.get_qr_base64().map_err(|err| {
-        eprintln!("Error generating 2fa QR code: {err}");
+        tracing::error!("Error generating 2fa QR code: {err}");
         HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, Some(err))
     })?;
     let secret = BASE64_STANDARD.encode(totp.secret);
@@ -114,30 +181,77 @@ struct Set2faRequest {
 async fn set_2fa(
     State(state): State<AppState>,
     Extension(session): Extension<GenericAuthenticatedSession>,
-    Json(body): Json<Set2faRequest>,
+    ValidatedJson(body): ValidatedJson<Set2faRequest>,
 ) -> Result<(), HttpError> {
     let secret_raw = BASE64_STANDARD.decode(body.secret).map_err(|_err| {
-        eprintln!("Invalid base64 in 2fa secret");
+        tracing::error!("Invalid base64 in 2fa secret");
         HttpError::new(
             StatusCode::UNPROCESSABLE_ENTITY,
             Some(String::from("Invalid base64 encoding in 2FA secret")),
         )
     })?;
     Ok(
-        users::set_2fa(session.user_id(), secret_raw, &body.code, &state.db)
+        users::set_2fa(session.user_id(), secret_raw, &body.code, state.db())
             .await
             .map(|_| ())?,
     )
 }
+#[derive(Deserialize)]
+/// A request to POST /users/self/2fa/verify.
+struct Verify2faRequest {
+    /// The base64-encoded candidate TOTP secret.
+    secret: String,
+    /// A code generated by the authenticator from that secret.
+    code: String,
+}
+
+#[derive(Serialize)]
+/// The response to POST /users/self/2fa/verify.
+struct Verify2faResponse {
+    /// Whether the code is currently valid for the secret.
+    valid: bool,
+}
+
+/// Check a TOTP code against a candidate secret without persisting anything.
+/// Rate-limited per user, since otherwise it could be used as a code oracle.
+async fn verify_2fa(
+    State(state): State<AppState>,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+    ValidatedJson(body): ValidatedJson<Verify2faRequest>,
+) -> Result<Json<Verify2faResponse>, HttpError> {
+    let user_id = session.user_id();
+    if state
+        .session_conn()
+        .bruteforce_timeout(&format!("totp-verify:{user_id}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!("User {user_id} is rate-limited for excessive 2fa verification attempts.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many verification attempts.")),
+        ));
+    }
+    let secret_raw = BASE64_STANDARD.decode(body.secret).map_err(|_err| {
+        tracing::error!("Invalid base64 in 2fa secret");
+        HttpError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Some(String::from("Invalid base64 encoding in 2FA secret")),
+        )
+    })?;
+    let valid = users::verify_2fa(user_id, secret_raw, &body.code)?;
+    Ok(Json(Verify2faResponse { valid }))
+}
+
 /// TODO: add documentation
 async fn update_self(
     State(state): State<AppState>,
     Extension(session): Extension<GenericAuthenticatedSession>,
-    Json(body): Json<users::AppUserUpdate>,
+    ValidatedJson(body): ValidatedJson<users::AppUserUpdate>,
 ) -> Result<Json<AppUser>, HttpError> {
-    eprintln!("User {} updated their data: {}", session.user_id(), body);
+    tracing::info!("User {} updated their data: {}", session.user_id(), body);
     Ok(Json(
-        users::update_user(session.user_id(), body, &state.db).await?,
+        users::update_user(session.user_id(), body, state.db()).await?,
     ))
 }
 
@@ -146,12 +260,12 @@ async fn update_user(
     State(state): State<AppState>,
     Extension(session): Extension<AdministratorSession>,
     Path(user_id): Path<Uuid>,
-    Json(body): Json<users::AppUserUpdate>,
+    ValidatedJson(body): ValidatedJson<users::AppUserUpdate>,
 ) -> Result<Json<AppUser>, HttpError> {
-    let user = AppUser::select_one(user_id, &state.db)
+    let user = AppUser::select_one(user_id, state.db())
         .await?
         .ok_or_else(|| {
-            eprintln!(
+            tracing::warn!(
                 "Administrator {} attempted to update data for user {}, who does not exist.",
                 session.user_id(),
                 user_id
@@ -161,24 +275,14 @@ async fn update_user(
                 Some(format!("User {} not found", session.user_id())),
             )
         })?;
-    if user_id != session.user_id() && user.role == AppUserRole::Administrator {
-        eprintln!(
-            "Administrator {} made an unauthorised attempt to update data for administrator {}",
-            session.user_id(),
-            user_id
-        );
-        return Err(HttpError::new(
-            StatusCode::FORBIDDEN,
-            Some(String::from("Cannot update data for another administrator")),
-        ));
-    }
-    eprintln!(
+    users::authorize_user_action(&session.clone().into(), &user, UserAction::Update)?;
+    tracing::info!(
         "Administrator {} updated data for customer {}: {}",
         session.user_id(),
         user_id,
         body
     );
-    Ok(Json(users::update_user(user_id, body, &state.db).await?))
+    Ok(Json(users::update_user(user_id, body, state.db()).await?))
 }
 
 #[derive(Serialize)]
@@ -186,15 +290,19 @@ async fn update_user(
 struct UserSearchResponse {
     /// TODO: add documentation
     users: Vec<AppUser>,
+    /// The page of results which was returned.
+    pagination: Pagination,
 }
 
 /// TODO: add documentation
 async fn search_users(
     State(state): State<AppState>,
     Query(params): Query<AppUserSearchParameters>,
+    pagination: Pagination,
 ) -> Result<Json<UserSearchResponse>, HttpError> {
     Ok(Json(UserSearchResponse {
-        users: users::search_users(params, &state.db).await?,
+        users: users::search_users(params, pagination, state.db()).await?,
+        pagination,
     }))
 }
 
@@ -203,8 +311,8 @@ async fn promote_user(
     State(state): State<AppState>,
     Path(user_id): Path<Uuid>,
 ) -> Result<Json<AppUser>, HttpError> {
-    eprintln!("User {user_id} is being promoted to Administrator");
-    Ok(Json(users::promote_user(user_id, &state.db).await?))
+    tracing::info!("User {user_id} is being promoted to Administrator");
+    Ok(Json(users::promote_user(user_id, state.db()).await?))
 }
 
 /// TODO: add documentation
@@ -219,14 +327,20 @@ async fn delete_user(
             AppUserSearchParameters {
                 role: Some(AppUserRole::Administrator),
                 email: None,
+                inactive_since: None,
+                forename: None,
+                surname: None,
             },
-            &state.db,
+            None,
+            state.db(),
         )
         .await?
-        .len()
+        .iter()
+        .filter(|admin| admin.deleted_at.is_none())
+        .count()
             == 1
     {
-        eprintln!("Sole administrator {user_id} attempted to delete their account. Denied until another administrator is promoted.");
+        tracing::warn!("Sole administrator {user_id} attempted to delete their account. Denied until another administrator is promoted.");
         return Err(HttpError::new(
             StatusCode::FORBIDDEN,
             Some(String::from(
@@ -234,10 +348,10 @@ async fn delete_user(
             )),
         ));
     }
-    if AppUser::select_one(user_id, &state.db)
+    let user = AppUser::select_one(user_id, state.db())
         .await?
         .ok_or_else(|| {
-            eprintln!(
+            tracing::warn!(
                 "Administrator {} attempted to delete user {}, who does not exist",
                 session.user_id(),
                 user_id
@@ -246,35 +360,325 @@ async fn delete_user(
                 StatusCode::NOT_FOUND,
                 Some(format!("User {user_id} not found")),
             )
-        })?
-        .role
-        == AppUserRole::Administrator
+        })?;
+    users::authorize_user_action(&session.clone().into(), &user, UserAction::Delete)?;
+    users::delete_user(user_id, state.db(), &mut state.session_conn()).await?;
+    if user_id == session.user_id() {
+        Ok(remove_session_cookies(cookies))
+    } else {
+        tracing::info!(
+            "Customer {} account deleted by administrator {}",
+            user_id,
+            session.user_id()
+        );
+        Ok(cookies)
+    }
+}
+
+/// Restore a deleted user whose account has not yet been purged, allowing
+/// them to log in again.
+async fn restore_user(
+    State(state): State<AppState>,
+    Extension(session): Extension<AdministratorSession>,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<AppUser>, HttpError> {
+    let user = users::restore_user(user_id, state.db()).await?;
+    tracing::info!(
+        "User {user_id} account restored by administrator {}",
+        session.user_id()
+    );
+    Ok(Json(user))
+}
+
+/// Begin opting in to email MFA by sending a confirmation code to the user's
+/// email address.
+async fn request_email_mfa(
+    State(state): State<AppState>,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+) -> Result<(), HttpError> {
+    let user_id = session.user_id();
+    let mut session_store = state.session_conn();
+    if session_store
+        .bruteforce_timeout(&format!("email-mfa-enrol:{user_id}"))
+        .await?
+        .timed_out
     {
-        eprintln!(
-            "Administrator {} attempted to delete account of administrator {}",
-            session.user_id(),
-            user_id
+        tracing::warn!("User {user_id} is rate-limited for excessive email MFA requests.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many email MFA requests.")),
+        ));
+    }
+    users::request_email_mfa(
+        &session,
+        state.db(),
+        &mut session_store,
+        state.email_sender.as_ref(),
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+/// A request to POST /users/self/2fa/email/confirm.
+struct EnableEmailMfaRequest {
+    /// The code which was sent to the user's email address.
+    code: String,
+}
+
+/// Opt in to email MFA, confirming the code sent to the user's email address.
+async fn enable_email_mfa(
+    State(state): State<AppState>,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+    ValidatedJson(body): ValidatedJson<EnableEmailMfaRequest>,
+) -> Result<(), HttpError> {
+    users::enable_email_mfa(&session, &body.code, state.db(), &mut state.session_conn()).await?;
+    tracing::info!("User {} enabled email MFA.", session.user_id());
+    Ok(())
+}
+
+/// Begin verifying the user's phone number by sending a confirmation code to
+/// it by SMS.
+async fn request_phone_verification(
+    State(state): State<AppState>,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+) -> Result<(), HttpError> {
+    let user_id = session.user_id();
+    let mut session_store = state.session_conn();
+    if session_store
+        .bruteforce_timeout(&format!("phone-verify:{user_id}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!("User {user_id} is rate-limited for excessive phone verification requests.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many phone verification requests.")),
+        ));
+    }
+    users::request_phone_verification(
+        &session,
+        state.db(),
+        &mut session_store,
+        state.sms_sender.as_ref(),
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+/// A request to POST /users/self/phone/verify/confirm.
+struct VerifyPhoneRequest {
+    /// The code which was sent to the user's phone number.
+    code: String,
+}
+
+/// Verify the user's phone number, confirming the code sent to it by SMS.
+async fn verify_phone(
+    State(state): State<AppState>,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+    ValidatedJson(body): ValidatedJson<VerifyPhoneRequest>,
+) -> Result<(), HttpError> {
+    users::verify_phone(&session, &body.code, state.db(), &mut state.session_conn()).await?;
+    tracing::info!("User {} verified their phone number.", session.user_id());
+    Ok(())
+}
+
+#[derive(Deserialize)]
+/// A request to DELETE /users/self/2fa/email.
+struct DisableEmailMfaRequest {
+    /// The user's primary credential, to re-authenticate with.
+    credential: auth::PrimaryAuthenticationMethod,
+}
+
+/// Opt out of email MFA, re-authenticating with the user's primary credential.
+/// Counts towards a per-user rate limit, since it is a chance to guess it.
+async fn disable_email_mfa(
+    State(state): State<AppState>,
+    Extension(session): Extension<GenericAuthenticatedSession>,
+    ValidatedJson(body): ValidatedJson<DisableEmailMfaRequest>,
+) -> Result<(), HttpError> {
+    let user_id = session.user_id();
+    if state
+        .session_conn()
+        .bruteforce_timeout(&format!("reauthenticate:{user_id}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!("User {user_id} is rate-limited for excessive re-authentication attempts.");
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many re-authentication attempts.")),
+        ));
+    }
+    users::disable_email_mfa(user_id, body.credential, state.db()).await?;
+    tracing::info!("User {} disabled email MFA.", session.user_id());
+    Ok(())
+}
+
+#[derive(Serialize)]
+/// The response to POST /users/{id}/revoke-sessions.
+struct RevokeSessionsResponse {
+    /// The number of the user's sessions which were revoked.
+    revoked: u32,
+}
+
+/// Log in as a customer, to see exactly what they see, e.g. when helping
+/// them with a problem. The administrator's session cookies are replaced with
+/// those of a short-lived session for the customer, which cannot be used to
+/// change the customer's credentials or delete anything.
+async fn impersonate_user(
+    State(state): State<AppState>,
+    Extension(session): Extension<AdministratorSession>,
+    Path(user_id): Path<Uuid>,
+    cookies: CookieJar,
+) -> Result<CookieJar, HttpError> {
+    let administrator_id = session.user_id();
+    let mut session_store = state.session_conn();
+    if session_store
+        .bruteforce_timeout(&format!("impersonate:{administrator_id}"))
+        .await?
+        .timed_out
+    {
+        tracing::warn!(
+            "Administrator {administrator_id} is rate-limited for excessive impersonation."
+        );
+        return Err(HttpError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(String::from("Too many impersonation requests.")),
+        ));
+    }
+    let user = users::retrieve_user(user_id, state.db())
+        .await?
+        .ok_or_else(|| {
+            tracing::warn!(
+                "Administrator {administrator_id} attempted to impersonate user {user_id}, who does not exist"
+            );
+            HttpError::new(
+                StatusCode::NOT_FOUND,
+                Some(format!("User {user_id} not found")),
+            )
+        })?;
+    if user.role == AppUserRole::Administrator {
+        tracing::warn!(
+            "Administrator {administrator_id} attempted to impersonate administrator {user_id}, rejected"
         );
         return Err(HttpError::new(
             StatusCode::FORBIDDEN,
+            Some(String::from("Administrators cannot be impersonated")),
+        ));
+    }
+    if user.deleted_at.is_some() {
+        tracing::warn!(
+            "Administrator {administrator_id} attempted to impersonate deleted user {user_id}, rejected"
+        );
+        return Err(HttpError::new(
+            StatusCode::CONFLICT,
             Some(String::from(
-                "Cannot delete account of another administrator",
+                "Deleted users cannot be impersonated until restored",
             )),
         ));
     }
-    users::delete_user(user_id, &state.db).await?;
-    if user_id == session.user_id() {
-        Ok(cookies
-            .remove(Cookie::from("session"))
-            .remove(Cookie::from("session_csrf")))
+    let impersonation =
+        CustomerSession::impersonate(user_id, administrator_id, &mut session_store).await?;
+    tracing::info!(
+        "IMPERSONATION: Administrator {administrator_id} began impersonating user {user_id}, session {}",
+        GenericAuthenticatedSession::from(impersonation.clone()).reference()
+    );
+    Ok(cookies
+        .add(build_session_cookie(
+            &SESSION_COOKIE_NAME,
+            impersonation.token(),
+            true,
+        ))
+        .add(build_session_cookie(
+            &CSRF_COOKIE_NAME,
+            impersonation.csrf_token(),
+            false,
+        )))
+}
+
+/// Forcibly log a user out of every session, e.g. if their account has been
+/// compromised.
+async fn revoke_user_sessions(
+    State(state): State<AppState>,
+    Extension(session): Extension<AdministratorSession>,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<RevokeSessionsResponse>, HttpError> {
+    if users::retrieve_user(user_id, state.db()).await?.is_none() {
+        tracing::warn!(
+            "Administrator {} attempted to revoke sessions of user {}, who does not exist",
+            session.user_id(),
+            user_id
+        );
+        return Err(HttpError::new(
+            StatusCode::NOT_FOUND,
+            Some(format!("User {user_id} not found")),
+        ));
+    }
+    let revoked = sessions::revoke_user_sessions(user_id, &mut state.session_conn()).await?;
+    if revoked == 0 {
+        tracing::info!(
+            "Administrator {} revoked sessions of user {}, who had no active sessions",
+            session.user_id(),
+            user_id
+        );
     } else {
-        eprintln!(
-            "Customer {} account deleted by administrator {}",
-            user_id,
+        tracing::info!(
+            "Administrator {} revoked {} session(s) of user {}",
+            session.user_id(),
+            revoked,
+            user_id
+        );
+    }
+    Ok(Json(RevokeSessionsResponse { revoked }))
+}
+
+/// Forcibly revoke a single one of a user's sessions by its reference.
+async fn revoke_user_session(
+    State(state): State<AppState>,
+    Extension(session): Extension<AdministratorSession>,
+    Path((user_id, session_ref)): Path<(Uuid, String)>,
+) -> Result<(), HttpError> {
+    if !sessions::revoke_user_session(user_id, &session_ref, &mut state.session_conn()).await? {
+        tracing::warn!(
+            "Administrator {} attempted to revoke session {session_ref} of user {user_id}, which does not exist",
             session.user_id()
         );
-        Ok(cookies)
+        return Err(HttpError::new(
+            StatusCode::NOT_FOUND,
+            Some(format!("Session {session_ref} not found")),
+        ));
     }
+    tracing::info!(
+        "Administrator {} revoked session {session_ref} of user {user_id}",
+        session.user_id()
+    );
+    Ok(())
+}
+
+/// The response to GET /users/self/reviewable.
+#[derive(Serialize)]
+struct ReviewableProductsResponse {
+    /// The page of products which the customer may review.
+    products: Vec<Product>,
+    /// The page of results which was returned.
+    pagination: Pagination,
+}
+
+/// List the products which the current customer has received in a fulfilled
+/// order but not yet reviewed.
+async fn list_reviewable_products(
+    State(state): State<AppState>,
+    Extension(session): Extension<CustomerSession>,
+    pagination: Pagination,
+) -> Result<Json<ReviewableProductsResponse>, HttpError> {
+    let products =
+        reviews::list_reviewable_products(session.user_id(), pagination, state.read_db()).await?;
+    Ok(Json(ReviewableProductsResponse {
+        products,
+        pagination,
+    }))
 }
 
 /// TODO: add documentation
@@ -288,14 +692,20 @@ async fn delete_self(
             AppUserSearchParameters {
                 role: Some(AppUserRole::Administrator),
                 email: None,
+                inactive_since: None,
+                forename: None,
+                surname: None,
             },
-            &state.db,
+            None,
+            state.db(),
         )
         .await?
-        .len()
+        .iter()
+        .filter(|admin| admin.deleted_at.is_none())
+        .count()
             == 1
         {
-            eprintln!("Sole administrator {} attempted to delete their account. Denied until another administrator is promoted.", session.user_id());
+            tracing::warn!("Sole administrator {} attempted to delete their account. Denied until another administrator is promoted.", session.user_id());
             return Err(HttpError::new(
                 StatusCode::FORBIDDEN,
                 Some(String::from(
@@ -304,21 +714,19 @@ async fn delete_self(
             ));
         }
     }
-    users::delete_user(session.user_id(), &state.db).await?;
-    eprintln!("User {} deleted their account", session.user_id());
-    Ok(cookies
-        .remove(Cookie::from("session"))
-        .remove(Cookie::from("session_csrf")))
+    users::delete_user(session.user_id(), state.db(), &mut state.session_conn()).await?;
+    tracing::info!("User {} deleted their account", session.user_id());
+    Ok(remove_session_cookies(cookies))
 }
 
 /// TODO: add documentation
 async fn update_credential(
     State(state): State<AppState>,
     Extension(session): Extension<GenericAuthenticatedSession>,
-    Json(body): Json<registration::PrimaryAuthenticationMethod>,
+    ValidatedJson(body): ValidatedJson<registration::PrimaryAuthenticationMethod>,
 ) -> Result<(), HttpError> {
-    users::update_credential(session.user_id(), body, &state.db).await?;
-    eprintln!(
+    users::update_credential(session.user_id(), body, state.db()).await?;
+    tracing::info!(
         "User {} has updated their primary authentication mechanism.",
         session.user_id()
     );
@@ -330,7 +738,7 @@ impl From<users::errors::CredentialUpdateError> for HttpError {
         match error {
             users::errors::CredentialUpdateError::DatabaseError(err) => err.into(),
             users::errors::CredentialUpdateError::PasswordTooShort(user_id) => {
-                eprintln!("User {user_id} attempted to update their password to below the minimum length.");
+                tracing::warn!("User {user_id} attempted to update their password to below the minimum length.");
                 Self::new(
                     StatusCode::UNPROCESSABLE_ENTITY,
                     Some(format!(
@@ -339,7 +747,7 @@ impl From<users::errors::CredentialUpdateError> for HttpError {
                 )
             }
             users::errors::CredentialUpdateError::PasswordTooLong(user_id) => {
-                eprintln!("User {user_id} attempted to update their password to above the maximum length.");
+                tracing::warn!("User {user_id} attempted to update their password to above the maximum length.");
                 Self::new(
                     StatusCode::UNPROCESSABLE_ENTITY,
                     Some(format!(
@@ -351,6 +759,97 @@ impl From<users::errors::CredentialUpdateError> for HttpError {
     }
 }
 
+impl From<users::errors::EmailMfaError> for HttpError {
+    fn from(error: users::errors::EmailMfaError) -> Self {
+        match error {
+            users::errors::EmailMfaError::StorageError(err) => err.into(),
+            users::errors::EmailMfaError::UserNonExistent(user_id) => {
+                tracing::error!("User {user_id} was not found while updating email MFA. Something is critically wrong.");
+                StatusCode::INTERNAL_SERVER_ERROR.into()
+            }
+            users::errors::EmailMfaError::IncorrectCode(user_id) => {
+                tracing::warn!("User {user_id} entered an incorrect email MFA confirmation code.");
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Some(String::from("Incorrect verification code")),
+                )
+            }
+            users::errors::EmailMfaError::IncorrectCredential(user_id) => {
+                tracing::warn!("User {user_id} failed to re-authenticate to disable email MFA.");
+                Self::new(
+                    StatusCode::FORBIDDEN,
+                    Some(String::from("Incorrect credential")),
+                )
+            }
+            users::errors::EmailMfaError::TooManyCodes(user_id) => {
+                tracing::warn!("User {user_id} requested too many email MFA confirmation codes.");
+                Self::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some(String::from("Too many verification code requests.")),
+                )
+            }
+            users::errors::EmailMfaError::DeliveryFailed(err) => {
+                tracing::error!("Error sending email MFA confirmation code: {err}");
+                Self::new(
+                    StatusCode::BAD_GATEWAY,
+                    Some(String::from("Failed to send verification code")),
+                )
+            }
+        }
+    }
+}
+
+impl From<users::errors::PhoneVerificationError> for HttpError {
+    fn from(error: users::errors::PhoneVerificationError) -> Self {
+        match error {
+            users::errors::PhoneVerificationError::StorageError(err) => err.into(),
+            users::errors::PhoneVerificationError::Unavailable => {
+                tracing::warn!(
+                    "Phone verification was requested, but no SMS provider is configured."
+                );
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(String::from("Phone verification is not available")),
+                )
+            }
+            users::errors::PhoneVerificationError::UserNonExistent(user_id) => {
+                tracing::error!("User {user_id} was not found while verifying their phone number. Something is critically wrong.");
+                StatusCode::INTERNAL_SERVER_ERROR.into()
+            }
+            users::errors::PhoneVerificationError::NoPhoneNumber(user_id) => {
+                tracing::warn!(
+                    "User {user_id} requested phone verification without a phone number."
+                );
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from("No phone number is set for this account")),
+                )
+            }
+            users::errors::PhoneVerificationError::IncorrectCode(user_id) => {
+                tracing::warn!("User {user_id} entered an incorrect phone verification code.");
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Some(String::from("Incorrect verification code")),
+                )
+            }
+            users::errors::PhoneVerificationError::TooManyCodes(user_id) => {
+                tracing::warn!("User {user_id} requested too many phone verification codes.");
+                Self::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some(String::from("Too many verification code requests.")),
+                )
+            }
+            users::errors::PhoneVerificationError::DeliveryFailed(err) => {
+                tracing::error!("Error sending phone verification code: {err}");
+                Self::new(
+                    StatusCode::BAD_GATEWAY,
+                    Some(String::from("Failed to send verification code")),
+                )
+            }
+        }
+    }
+}
+
 impl From<users::errors::UserRetrievalError> for HttpError {
     fn from(error: users::errors::UserRetrievalError) -> Self {
         match error {
@@ -372,14 +871,16 @@ impl From<users::errors::UserPromotionError> for HttpError {
         match error {
             users::errors::UserPromotionError::DatabaseError(err) => err.into(),
             users::errors::UserPromotionError::UserNonExistent(user_id) => {
-                eprintln!("Attempted to promote non-existent user {user_id}");
+                tracing::warn!("Attempted to promote non-existent user {user_id}");
                 Self::new(
                     StatusCode::NOT_FOUND,
                     Some(format!("User {user_id} not found")),
                 )
             }
             users::errors::UserPromotionError::AlreadyAdministrator(user_id) => {
-                eprintln!("Attempted to promote user {user_id}, who is already an administrator");
+                tracing::warn!(
+                    "Attempted to promote user {user_id}, who is already an administrator"
+                );
                 Self::new(
                     StatusCode::CONFLICT,
                     Some(String::from("User is already an administrator")),
@@ -393,13 +894,45 @@ impl From<users::errors::UserDeletionError> for HttpError {
     fn from(error: users::errors::UserDeletionError) -> Self {
         match error {
             users::errors::UserDeletionError::UserNonExistent(user_id) => {
-                eprintln!("Attempted to delete non-existent user {user_id}");
+                tracing::warn!("Attempted to delete non-existent user {user_id}");
                 Self::new(
                     StatusCode::NOT_FOUND,
                     Some(format!("User {user_id} not found")),
                 )
             }
             users::errors::UserDeletionError::DatabaseError(err) => err.into(),
+            users::errors::UserDeletionError::SessionStorageError(err) => err.into(),
+        }
+    }
+}
+
+impl From<users::errors::UserRestorationError> for HttpError {
+    fn from(error: users::errors::UserRestorationError) -> Self {
+        match error {
+            users::errors::UserRestorationError::UserNonExistent(user_id) => {
+                tracing::warn!("Attempted to restore non-existent user {user_id}");
+                Self::new(
+                    StatusCode::NOT_FOUND,
+                    Some(format!("User {user_id} not found")),
+                )
+            }
+            users::errors::UserRestorationError::NotDeleted(user_id) => {
+                tracing::warn!("Attempted to restore user {user_id}, who is not deleted");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from("User is not deleted")),
+                )
+            }
+            users::errors::UserRestorationError::Purged(user_id) => {
+                tracing::warn!("Attempted to restore user {user_id}, who has been purged");
+                Self::new(
+                    StatusCode::GONE,
+                    Some(String::from(
+                        "User has been purged and can no longer be restored",
+                    )),
+                )
+            }
+            users::errors::UserRestorationError::DatabaseError(err) => err.into(),
         }
     }
 }
@@ -408,7 +941,7 @@ impl From<users::errors::UserUpdateError> for HttpError {
     fn from(error: users::errors::UserUpdateError) -> Self {
         match error {
             users::errors::UserUpdateError::UserNonExistent(user_id) => {
-                eprintln!("Attempted to update non-existent user {user_id}");
+                tracing::warn!("Attempted to update non-existent user {user_id}");
                 Self::new(
                     StatusCode::NOT_FOUND,
                     Some(format!("User {user_id} not found")),
@@ -424,12 +957,33 @@ impl From<users::errors::SetTotpError> for HttpError {
         match error {
             users::errors::SetTotpError::DatabaseError(err) => err.into(),
             users::errors::SetTotpError::IncorrectCode(user_id) => {
-                eprintln!("User {user_id} supplied incorrect code during 2fa setup");
+                tracing::warn!("User {user_id} supplied incorrect code during 2fa setup");
                 Self::new(
                     StatusCode::FORBIDDEN,
                     Some(String::from("2FA verification code incorrect")),
                 )
             }
+            users::errors::SetTotpError::AlreadyEnrolled(user_id) => {
+                tracing::warn!("User {user_id} attempted to enroll 2fa while already enrolled");
+                Self::new(
+                    StatusCode::CONFLICT,
+                    Some(String::from("2FA is already enabled for this account")),
+                )
+            }
+        }
+    }
+}
+
+impl From<users::errors::VerifyTotpError> for HttpError {
+    fn from(error: users::errors::VerifyTotpError) -> Self {
+        match error {
+            users::errors::VerifyTotpError::InvalidSecret(err) => {
+                tracing::warn!("Invalid secret supplied for 2fa verification: {err}");
+                Self::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Some(String::from("Invalid 2FA secret")),
+                )
+            }
         }
     }
 }
@@ -437,10 +991,489 @@ impl From<users::errors::SetTotpError> for HttpError {
 impl From<users::errors::GenerateTotpError> for HttpError {
     fn from(error: users::errors::GenerateTotpError) -> Self {
         match error {
-            users::errors::GenerateTotpError::Rfc6238Error(err) => {
-                eprintln!("Non-RFC6238-compliant parameters in 2fa generation: {err}");
+            users::errors::GenerateTotpError::InvalidParameters(err) => {
+                tracing::error!("Invalid TOTP parameters in 2fa generation: {err}");
                 StatusCode::INTERNAL_SERVER_ERROR.into()
             }
         }
     }
 }
+
+impl From<users::errors::UserActionError> for HttpError {
+    fn from(err: users::errors::UserActionError) -> Self {
+        match err {
+            users::errors::UserActionError::Forbidden {
+                actor,
+                target,
+                action,
+            } => {
+                tracing::warn!(
+                    "User {actor} made an unauthorised attempt to {action} user {target}"
+                );
+                Self::new(
+                    StatusCode::FORBIDDEN,
+                    Some(format!("Not permitted to {action} user {target}")),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use base64::{prelude::BASE64_STANDARD, Engine as _};
+    use serde_json::{json, Value};
+    use time::{Duration, OffsetDateTime};
+    use uuid::Uuid;
+
+    use crate::{
+        constants::{
+            fields::NAME_MAX_LENGTH,
+            pagination::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},
+            sessions::{AUTH_TIMEOUT_ATTEMPTS, IMPERSONATION_SESSION_TIMEOUT},
+            users::USER_DELETION_GRACE_PERIOD,
+        },
+        db::{
+            models::{
+                appuser::{AppUser, AppUserRole},
+                totp::{Totp, TotpInsert},
+            },
+            ConnectionPool,
+        },
+        services::users,
+        testing::{store_user, TestApp},
+    };
+
+    /// Store an administrator, and log them in to a new app.
+    async fn log_in_administrator(db_conn: &ConnectionPool) -> (TestApp, AppUser) {
+        let mut administrator = store_user("admin@example.com", db_conn).await;
+        administrator.role = AppUserRole::Administrator;
+        administrator
+            .update(db_conn)
+            .await
+            .expect("User should be updated");
+        let mut app = TestApp::new(db_conn.clone());
+        assert_eq!(app.log_in("admin@example.com").await.status, StatusCode::OK);
+        (app, administrator)
+    }
+
+    /// Impersonate a user as the administrator logged in to the app.
+    async fn impersonate(app: &mut TestApp, user: &AppUser) -> StatusCode {
+        app.post(&format!("/users/{}/impersonate", user.id()), &json!({}))
+            .await
+            .status
+    }
+
+    /// Impersonation gives the administrator a customer session for the
+    /// user, which is marked as impersonated and has no administrative access.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn impersonation_session_is_scoped(db_conn: ConnectionPool) {
+        let customer = store_user("alice@example.com", &db_conn).await;
+        let (mut app, administrator) = log_in_administrator(&db_conn).await;
+        assert_eq!(app.get("/users").await.status, StatusCode::OK);
+
+        assert_eq!(impersonate(&mut app, &customer).await, StatusCode::OK);
+        let own_data = app.get("/users/self").await;
+        assert_eq!(own_data.status, StatusCode::OK);
+        assert_eq!(own_data.string_at("/email"), "alice@example.com");
+        assert_eq!(
+            own_data.string_at("/impersonated_by"),
+            administrator.id().to_string()
+        );
+        assert_eq!(app.get("/auth/check/customer").await.status, StatusCode::OK);
+        assert_eq!(app.get("/users").await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    /// An impersonation session expires within the impersonation timeout,
+    /// and administrators who impersonate too often are rate limited.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn impersonation_is_time_boxed_and_rate_limited(db_conn: ConnectionPool) {
+        let customer = store_user("alice@example.com", &db_conn).await;
+        let (mut app, administrator) = log_in_administrator(&db_conn).await;
+        assert_eq!(impersonate(&mut app, &customer).await, StatusCode::OK);
+        let sessions = app.get("/auth/sessions").await;
+        let expires_in = sessions
+            .json()
+            .pointer("/sessions")
+            .and_then(Value::as_array)
+            .and_then(|listed| {
+                listed
+                    .iter()
+                    .find(|session| session.get("current") == Some(&Value::Bool(true)))
+            })
+            .and_then(|current| current.get("expires_in"))
+            .and_then(Value::as_u64)
+            .expect("Current session should expire");
+        assert!(expires_in <= u64::from(IMPERSONATION_SESSION_TIMEOUT));
+
+        // As if the administrator had impersonated users up to the limit. Only
+        // counted, as each impersonation replaces the administrator's session,
+        // and logging in again is itself rate limited.
+        let mut session_store = app.session_conn();
+        for _ in 2..AUTH_TIMEOUT_ATTEMPTS {
+            session_store
+                .bruteforce_timeout(&format!("impersonate:{}", administrator.id()))
+                .await
+                .expect("Attempt should be counted");
+        }
+        assert_eq!(app.log_in("admin@example.com").await.status, StatusCode::OK);
+        assert_eq!(
+            impersonate(&mut app, &customer).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    /// Credentials, MFA, contact details and the account itself can't be
+    /// changed while impersonating, and the user's password still works.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn credential_changes_refused_while_impersonating(db_conn: ConnectionPool) {
+        let customer = store_user("alice@example.com", &db_conn).await;
+        let (mut app, _) = log_in_administrator(&db_conn).await;
+        assert_eq!(impersonate(&mut app, &customer).await, StatusCode::OK);
+
+        let requests: [(Method, &str, Value); 6] = [
+            (
+                Method::PUT,
+                "/users/self/credential",
+                json!({ "Password": { "password": "a new password for alice" } }),
+            ),
+            (
+                Method::POST,
+                "/users/self/2fa",
+                json!({ "secret": "", "code": "" }),
+            ),
+            (Method::POST, "/users/self/2fa/email", json!({})),
+            (Method::DELETE, "/users/self/2fa/email", json!({})),
+            (
+                Method::PUT,
+                "/users/self",
+                json!({ "email": "mallory@example.com" }),
+            ),
+            (Method::DELETE, "/users/self", json!({})),
+        ];
+        for (method, uri, body) in requests {
+            let response = app.send_json(method.clone(), uri, &body).await;
+            assert_eq!(response.status, StatusCode::FORBIDDEN, "{method} {uri}");
+            assert_eq!(
+                response.string_at("/message"),
+                "Not permitted while impersonating a user"
+            );
+        }
+
+        let own_data = app.get("/users/self").await;
+        assert_eq!(own_data.string_at("/email"), "alice@example.com");
+        assert_eq!(
+            app.other_client().log_in("alice@example.com").await.status,
+            StatusCode::OK,
+            "Password should be unchanged"
+        );
+    }
+
+    /// Administrators can't be impersonated.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn administrators_cannot_be_impersonated(db_conn: ConnectionPool) {
+        let (mut app, administrator) = log_in_administrator(&db_conn).await;
+        assert_eq!(
+            impersonate(&mut app, &administrator).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(app.get("/users").await.status, StatusCode::OK);
+    }
+
+    /// Names given in a profile update are stored with their whitespace
+    /// normalised, and over-long names or names containing control
+    /// characters are rejected.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn profile_names_are_sanitized(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let updated = app
+            .send_json(
+                Method::PUT,
+                "/users/self",
+                &json!({ "forename": "  Mary   Jane ", "surname": "Smith\n" }),
+            )
+            .await;
+        assert_eq!(updated.status, StatusCode::OK);
+        let retrieved = app.get("/users/self").await;
+        assert_eq!(retrieved.string_at("/forename"), "Mary Jane");
+        assert_eq!(retrieved.string_at("/surname"), "Smith");
+        for forename in ["A".repeat(NAME_MAX_LENGTH + 1), String::from("Mary\u{0}")] {
+            let refused = app
+                .send_json(Method::PUT, "/users/self", &json!({ "forename": forename }))
+                .await;
+            assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        assert_eq!(
+            app.get("/users/self").await.string_at("/forename"),
+            "Mary Jane"
+        );
+    }
+
+    /// User searches report the page applied, with the limit defaulted when
+    /// missing and clamped when over the maximum.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn user_search_reports_applied_page(db_conn: ConnectionPool) {
+        let (mut app, _) = log_in_administrator(&db_conn).await;
+        for (query, limit, offset) in [
+            (String::new(), *DEFAULT_PAGE_SIZE, 0u32),
+            (
+                format!("?limit={}&offset=1", MAX_PAGE_SIZE.saturating_add(1)),
+                *MAX_PAGE_SIZE,
+                1u32,
+            ),
+        ] {
+            let response = app.get(&format!("/users{query}")).await;
+            assert_eq!(response.status, StatusCode::OK);
+            assert_eq!(
+                response.json().pointer("/pagination"),
+                Some(&json!({ "limit": limit, "offset": offset })),
+                "{query}"
+            );
+        }
+        assert_eq!(
+            app.get("/users?limit=-1").await.status,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    /// Enrolling TOTP a second time is a conflict, and leaves the first
+    /// authenticator enrolled.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn enrolling_totp_twice_is_conflict(db_conn: ConnectionPool) {
+        let user = store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn.clone());
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let mut codes = Vec::new();
+        for (secret, status) in [
+            (vec![7; 20], StatusCode::OK),
+            (vec![8; 20], StatusCode::CONFLICT),
+        ] {
+            let code = TotpInsert::new(user.id(), secret.clone())
+                .totp()
+                .expect("TOTP should be valid")
+                .generate_current()
+                .expect("Code should be generated");
+            let body = json!({ "secret": BASE64_STANDARD.encode(secret), "code": code });
+            assert_eq!(app.post("/users/self/2fa", &body).await.status, status);
+            codes.push(code);
+        }
+        let totp = Totp::select(user.id(), &db_conn)
+            .await
+            .expect("TOTP should be selected")
+            .expect("User should have TOTP");
+        assert_eq!(
+            codes
+                .iter()
+                .map(|code| totp.validate(code))
+                .collect::<Vec<_>>(),
+            [true, false]
+        );
+    }
+
+    /// Verifying a TOTP code reports whether it is valid for the secret
+    /// without enrolling it, and is rate-limited.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn totp_code_is_verified_without_enrolling(db_conn: ConnectionPool) {
+        let user = store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn.clone());
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+        let secret = vec![7; 20];
+        let code = TotpInsert::new(user.id(), secret.clone())
+            .totp()
+            .expect("TOTP should be valid")
+            .generate_current()
+            .expect("Code should be generated");
+        let wrong_code = if code == "000000" { "111111" } else { "000000" };
+        for (candidate, valid) in [(code.as_str(), true), (wrong_code, false)] {
+            let body = json!({ "secret": BASE64_STANDARD.encode(&secret), "code": candidate });
+            let response = app.post("/users/self/2fa/verify", &body).await;
+            assert_eq!(response.status, StatusCode::OK);
+            assert_eq!(response.json().pointer("/valid"), Some(&json!(valid)));
+        }
+        assert!(Totp::select(user.id(), &db_conn)
+            .await
+            .expect("TOTP should be selected")
+            .is_none());
+        // The two verifications above count towards the limit.
+        let body = json!({ "secret": BASE64_STANDARD.encode(&secret), "code": code });
+        let mut statuses = Vec::new();
+        for _ in 2..AUTH_TIMEOUT_ATTEMPTS {
+            statuses.push(app.post("/users/self/2fa/verify", &body).await.status);
+        }
+        assert_eq!(statuses.pop(), Some(StatusCode::TOO_MANY_REQUESTS));
+        assert!(statuses.iter().all(|status| *status == StatusCode::OK));
+    }
+
+    /// Logging in records when the user last did so, and administrators can
+    /// search for users who haven't logged in since a given time, including
+    /// those who never have.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn inactive_users_are_searched_by_last_login(db_conn: ConnectionPool) {
+        let never = store_user("never@example.com", &db_conn).await;
+        let dormant = store_user("dormant@example.com", &db_conn).await;
+        let (mut admin_app, administrator) = log_in_administrator(&db_conn).await;
+        let mut dormant_app = TestApp::new(db_conn.clone());
+        assert_eq!(
+            dormant_app.log_in("dormant@example.com").await.status,
+            StatusCode::OK
+        );
+        let retrieved = dormant_app.get("/users/self").await.json();
+        assert!(retrieved
+            .pointer("/last_login_at")
+            .is_some_and(Value::is_string));
+        for user in [&never, &dormant, &administrator] {
+            let selected = AppUser::select_one(user.id(), &db_conn)
+                .await
+                .expect("User should be selected")
+                .expect("User should exist");
+            assert_eq!(
+                selected.last_login_at.is_some(),
+                user.id() != never.id(),
+                "{}",
+                selected.email
+            );
+        }
+        sqlx::query!(
+            "UPDATE appuser SET last_login_at = now() - interval '30 days' WHERE id = $1",
+            dormant.id()
+        )
+        .execute(&db_conn)
+        .await
+        .expect("Last login should be backdated");
+
+        let cutoff = OffsetDateTime::now_utc() - Duration::days(1);
+        let found = admin_app
+            .get(&format!(
+                "/users?inactive_since={}T00:00:00Z",
+                cutoff.date()
+            ))
+            .await;
+        assert_eq!(found.status, StatusCode::OK);
+        let emails: Vec<_> = found
+            .json()
+            .pointer("/users")
+            .and_then(Value::as_array)
+            .expect("Users should be listed")
+            .iter()
+            .filter_map(|user| {
+                user.pointer("/email")
+                    .and_then(Value::as_str)
+                    .map(str::to_owned)
+            })
+            .collect();
+        assert_eq!(emails, ["dormant@example.com", "never@example.com"]);
+    }
+
+    /// An administrator revoking a user's sessions logs out every one of that
+    /// user's sessions, and only theirs. Every client shares the
+    /// administrator's session store.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn administrator_revokes_only_target_sessions(db_conn: ConnectionPool) {
+        let target = store_user("alice@example.com", &db_conn).await;
+        store_user("bob@example.com", &db_conn).await;
+        let (mut admin_app, _) = log_in_administrator(&db_conn).await;
+        let mut target_apps = Vec::new();
+        for _ in 0..2u8 {
+            let mut app = admin_app.other_client();
+            assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+            target_apps.push(app);
+        }
+        let mut other_app = admin_app.other_client();
+        assert_eq!(
+            other_app.log_in("bob@example.com").await.status,
+            StatusCode::OK
+        );
+
+        let uri = format!("/users/{}/revoke-sessions", target.id());
+        let revoked = admin_app.post(&uri, &json!({})).await;
+        assert_eq!(revoked.status, StatusCode::OK);
+        assert_eq!(revoked.json().pointer("/revoked"), Some(&json!(2u8)));
+        for app in &mut target_apps {
+            assert_eq!(
+                app.get("/users/self").await.status,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        for app in [&mut other_app, &mut admin_app] {
+            assert_eq!(app.get("/users/self").await.status, StatusCode::OK);
+        }
+
+        let repeated = admin_app.post(&uri, &json!({})).await;
+        assert_eq!(repeated.json().pointer("/revoked"), Some(&json!(0u8)));
+        let missing = admin_app
+            .post(
+                &format!("/users/{}/revoke-sessions", Uuid::new_v4()),
+                &json!({}),
+            )
+            .await;
+        assert_eq!(missing.status, StatusCode::NOT_FOUND);
+    }
+
+    /// A user who deletes their account can't log in until an administrator
+    /// restores it, which is only possible while the account is deleted and
+    /// hasn't yet been purged.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn deleted_user_is_restored_by_administrator(db_conn: ConnectionPool) {
+        let (mut admin_app, _) = log_in_administrator(&db_conn).await;
+        let user = store_user("alice@example.com", &db_conn).await;
+        let mut customer_app = admin_app.other_client();
+        assert_eq!(
+            customer_app.log_in("alice@example.com").await.status,
+            StatusCode::OK
+        );
+        let deleted = customer_app
+            .send_json(Method::DELETE, "/users/self", &json!({}))
+            .await;
+        assert_eq!(deleted.status, StatusCode::OK);
+        assert_eq!(
+            customer_app.log_in("alice@example.com").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let uri = format!("/users/{}/restore", user.id());
+        assert_eq!(
+            admin_app.post(&uri, &json!({})).await.status,
+            StatusCode::OK
+        );
+        assert_eq!(
+            customer_app.log_in("alice@example.com").await.status,
+            StatusCode::OK
+        );
+        assert_eq!(
+            admin_app.post(&uri, &json!({})).await.status,
+            StatusCode::CONFLICT
+        );
+
+        let deleted_again = admin_app
+            .send_json(Method::DELETE, &format!("/users/{}", user.id()), &json!({}))
+            .await;
+        assert_eq!(deleted_again.status, StatusCode::OK);
+        sqlx::query("UPDATE appuser SET deleted_at = deleted_at - $1 WHERE id = $2")
+            .bind(Duration::seconds(i64::from(*USER_DELETION_GRACE_PERIOD)))
+            .bind(user.id())
+            .execute(&db_conn)
+            .await
+            .expect("Deletion should be backdated");
+        users::purge_deleted_users(&db_conn)
+            .await
+            .expect("Purge should succeed");
+        assert_eq!(
+            admin_app.post(&uri, &json!({})).await.status,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -6,14 +6,12 @@ use axum::{
     routing::post,
     Router,
 };
+use core::{future::Future, pin::Pin};
+use std::{collections::HashMap, sync::LazyLock};
 use stripe::{Event, EventObject, EventType};
 use uuid::Uuid;
 
-use crate::{
-    constants::stripe::STRIPE_WEBHOOK_SECRET,
-    services::orders::{self, errors::OrderConfirmationError},
-    state::AppState,
-};
+use crate::{constants::stripe::STRIPE_WEBHOOK_SECRET, services::stripe_events, state::AppState};
 
 pub fn create_router() -> Router<AppState> {
     Router::new().route("/", post(stripe_webhook_event))
@@ -47,49 +45,172 @@ where
                 &STRIPE_WEBHOOK_SECRET,
             )
             .map_err(|_err| {
-                eprintln!("Invalid/Unauthenticated stripe webhook event");
+                tracing::warn!("Invalid/Unauthenticated stripe webhook event");
                 StatusCode::BAD_REQUEST.into_response()
             })?,
         ))
     }
 }
 
+/// The future returned by a webhook event handler.
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<(), StatusCode>> + Send>>;
+
+/// An async handler for a single type of verified Stripe webhook event.
+type EventHandler = fn(Event, AppState) -> HandlerFuture;
+
+/// The handler for each type of Stripe webhook event which is acted upon. To
+/// handle a new type of event, register its handler here. Events of any other
+/// type are acknowledged and ignored.
+static EVENT_HANDLERS: LazyLock<HashMap<EventType, EventHandler>> = LazyLock::new(|| {
+    let mut handlers: HashMap<EventType, EventHandler> = HashMap::new();
+    handlers.insert(EventType::PaymentIntentSucceeded, |event, state| {
+        Box::pin(payment_intent_succeeded(event, state))
+    });
+    handlers
+});
+
+/// Route a verified event to the handler registered for its type in
+/// `handlers`, if any.
+fn dispatch(
+    handlers: &HashMap<EventType, EventHandler>,
+    event: Event,
+    state: AppState,
+) -> HandlerFuture {
+    match handlers.get(&event.type_) {
+        Some(handler) => handler(event, state),
+        None => Box::pin(async { Ok(()) }),
+    }
+}
+
 pub async fn stripe_webhook_event(
     State(state): State<AppState>,
     StripeEvent(event): StripeEvent,
 ) -> Result<(), StatusCode> {
-    #[expect(
-        clippy::wildcard_enum_match_arm,
-        reason = "There are over 400 possible stripe webhook events. I refuse to list them all."
-    )]
-    match event.type_ {
-        EventType::PaymentIntentSucceeded => {
-            if let EventObject::PaymentIntent(data) = event.data.object {
-                let order_id: Uuid = data.metadata.get("order_id").ok_or_else(|| {
-                    eprintln!("Stripe webhook paymentintent.succeeded did not contain order_id metadata");
-                    StatusCode::BAD_REQUEST
-                })?
-                .parse().map_err(|_parse| {
-                    eprintln!("Stripe webhook paymentintent order_id not an integer");
-                    StatusCode::UNPROCESSABLE_ENTITY
-                })?;
-                orders::confirm_order(order_id, &state.db)
-                    .await
-                    .map_err(|error| match error {
-                        OrderConfirmationError::DatabaseError(err) => {
-                            eprintln!("Error raised by database while confirming order: {err}");
-                            StatusCode::INTERNAL_SERVER_ERROR
-                        }
-                        OrderConfirmationError::OrderNonExistent(order_id) => {
-                            eprintln!(
-                                "Stripe attempted to confirm order {order_id}, which does not exist."
-                            );
-                            StatusCode::NOT_FOUND
-                        }
-                    })?;
+    dispatch(&EVENT_HANDLERS, event, state).await
+}
+
+/// The reason an event's `order_id` metadata could not be used.
+enum OrderIdError {
+    /// The metadata has no `order_id`.
+    Missing,
+    /// The `order_id` is not a valid UUID.
+    Malformed(String),
+}
+
+/// Read the ID of the order a payment is for from its `order_id` metadata,
+/// which must be the order's UUID.
+fn metadata_order_id(metadata: &HashMap<String, String>) -> Result<Uuid, OrderIdError> {
+    let raw = metadata.get("order_id").ok_or(OrderIdError::Missing)?;
+    Uuid::parse_str(raw).map_err(|_parse| OrderIdError::Malformed(raw.clone()))
+}
+
+/// Persist a successful payment for its order to be confirmed. Redelivered
+/// events are ignored when stored. An event without a valid `order_id` can
+/// never be processed, so is recorded for investigation and acknowledged,
+/// since Stripe would otherwise redeliver it indefinitely. Only failures which
+/// may be transient are returned as errors, so that Stripe retries them.
+async fn payment_intent_succeeded(event: Event, state: AppState) -> Result<(), StatusCode> {
+    if let EventObject::PaymentIntent(data) = event.data.object {
+        let order_id = match metadata_order_id(&data.metadata) {
+            Ok(order_id) => order_id,
+            Err(err) => {
+                let reason = match err {
+                    OrderIdError::Missing => String::from("No order_id metadata"),
+                    OrderIdError::Malformed(raw) => {
+                        format!("order_id metadata '{raw}' is not a UUID")
+                    }
+                };
+                return stripe_events::reject_event(
+                    event.id.to_string(),
+                    String::from("payment_intent.succeeded"),
+                    reason,
+                    &state.db,
+                )
+                .await
+                .map_err(|db_err| {
+                    tracing::error!(
+                        "Error raised by database while rejecting Stripe event: {db_err}"
+                    );
+                    StatusCode::INTERNAL_SERVER_ERROR
+                });
             }
-            Ok(())
-        }
-        _ => Ok(()),
+        };
+        // Only persisted here, so Stripe is acknowledged immediately.
+        // The order is confirmed by the background event processor.
+        stripe_events::receive_payment_succeeded(
+            event.id.to_string(),
+            order_id,
+            data.amount_received,
+            data.id.to_string(),
+            &state.db,
+        )
+        .await
+        .map_err(|err| {
+            tracing::error!("Error raised by database while storing Stripe event: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use stripe::{Event, EventType};
+
+    use super::{dispatch, EventHandler};
+    use crate::testing::TestApp;
+
+    /// A verified event of a given type, e.g. `charge.refunded`.
+    fn event(type_: &str) -> Event {
+        serde_json::from_value(json!({
+            "id": "evt_test",
+            "api_version": null,
+            "created": 0,
+            "data": { "object": { "object": "application", "id": "ca_test", "name": null } },
+            "livemode": false,
+            "pending_webhooks": 0,
+            "request": null,
+            "type": type_,
+        }))
+        .expect("Event should be valid")
+    }
+
+    /// Handlers for two types of event, each failing with its own status so
+    /// that tests can tell which was run.
+    fn handlers() -> HashMap<EventType, EventHandler> {
+        let mut handlers: HashMap<EventType, EventHandler> = HashMap::new();
+        handlers.insert(EventType::PaymentIntentSucceeded, |_event, _state| {
+            Box::pin(async { Err(StatusCode::IM_A_TEAPOT) })
+        });
+        handlers.insert(EventType::ChargeRefunded, |_event, _state| {
+            Box::pin(async { Err(StatusCode::CONFLICT) })
+        });
+        handlers
+    }
+
+    /// Each event is given to the handler registered for its type.
+    #[tokio::test]
+    async fn events_are_routed_by_type() {
+        let state = TestApp::without_db().state;
+        let succeeded = dispatch(
+            &handlers(),
+            event("payment_intent.succeeded"),
+            state.clone(),
+        )
+        .await;
+        assert_eq!(succeeded, Err(StatusCode::IM_A_TEAPOT));
+        let refunded = dispatch(&handlers(), event("charge.refunded"), state).await;
+        assert_eq!(refunded, Err(StatusCode::CONFLICT));
+    }
+
+    /// Events of a type without a handler are acknowledged and ignored.
+    #[tokio::test]
+    async fn unhandled_events_are_ignored() {
+        let state = TestApp::without_db().state;
+        let result = dispatch(&handlers(), event("customer.created"), state).await;
+        assert_eq!(result, Ok(()));
     }
 }
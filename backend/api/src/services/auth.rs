@@ -1,15 +1,19 @@
 //! Controllers which manage authentication.
 use crate::{
+    constants::{
+        api::{API_URI_PREFIX, PUBLIC_URI},
+        sessions::{MAGIC_LINK_TIMEOUT, REQUIRE_ADMIN_2FA},
+    },
     db::{
         self,
         models::{
-            appuser::{AppUser, AppUserRole, AppUserSearchParameters},
+            appuser::{AppUser, AppUserRole},
             password::Password,
             totp::Totp,
         },
     },
-    services::sessions::{self, CustomerSession, PreAuthenticationSession},
-    utils::email::EmailAddress,
+    services::sessions::{self, CustomerSession, OneTimeCodeKind, PreAuthenticationSession},
+    utils::{email::EmailAddress, mailer::EmailSender, sms::SmsSender},
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -38,7 +42,7 @@ async fn do_password_authentication(
 
 impl PrimaryAuthenticationMethod {
     /// Authenticate using this authentication method.
-    async fn authenticate(
+    pub async fn authenticate(
         self,
         user_id: Uuid,
         db_conn: &db::ConnectionPool,
@@ -59,6 +63,16 @@ pub enum MfaAuthenticationMethod {
         /// The generated TOTP code.
         code: String,
     },
+    /// One-time code sent by SMS, requested with `send_sms_code`.
+    Sms {
+        /// The code received by SMS.
+        code: String,
+    },
+    /// One-time code sent by email, requested with `send_email_code`.
+    EmailOtp {
+        /// The code received by email.
+        code: String,
+    },
 }
 
 /// List all supported authentication methods.
@@ -79,53 +93,223 @@ pub enum AuthenticationOutcome {
     Failure,
     /// The authentication was successful, and an ``AdministrativeSession`` was created.
     SuccessAdministrative(AdministratorSession),
+    /// The authentication was successful, but the user is an administrator
+    /// without TOTP enrolled while `REQUIRE_ADMIN_2FA` is set. Only a
+    /// ``CustomerSession`` was created, with which they must enrol.
+    TotpEnrolmentRequired(CustomerSession),
 }
+
+impl From<AuthenticationOutcome2fa> for AuthenticationOutcome {
+    fn from(outcome: AuthenticationOutcome2fa) -> Self {
+        match outcome {
+            AuthenticationOutcome2fa::Success(session) => Self::Success(session),
+            AuthenticationOutcome2fa::SuccessAdministrative(session) => {
+                Self::SuccessAdministrative(session)
+            }
+            AuthenticationOutcome2fa::TotpEnrolmentRequired(session) => {
+                Self::TotpEnrolmentRequired(session)
+            }
+            AuthenticationOutcome2fa::Failure => Self::Failure,
+        }
+    }
+}
+
 /// Authenticate with a primary authentication method, and return a session
 /// if successful. The session is not guaranteed to be fully authenticated,
 /// and checking that `AuthenticatedSession::try_from_session` is successful
 /// is recommended. If the session is not authenticated, then further action
-/// (most likely MFA) is required.
+/// (most likely MFA) is required. If `remember_me` is set, the resulting
+/// customer session will be long-lived (administrative sessions never are).
+/// `client` identifies where the attempt came from, to limit how many
+/// partially authenticated sessions a single client can have at once.
 pub async fn authenticate(
     email: EmailAddress,
     credential: PrimaryAuthenticationMethod,
+    remember_me: bool,
+    client: &str,
     db_conn: &db::ConnectionPool,
     session_store_conn: &mut sessions::store::Connection,
-) -> Result<AuthenticationOutcome, super::errors::StorageError> {
-    let mut res = AppUser::search(
-        AppUserSearchParameters {
-            email: Some(email),
-            role: None,
-        },
-        db_conn,
-    )
-    .await?;
-    let Some(user) = res.pop() else {
+) -> Result<AuthenticationOutcome, errors::AuthenticateError> {
+    let Some(user) = AppUser::select_by_email(&email, db_conn)
+        .await
+        .map_err(super::errors::StorageError::from)?
+    else {
         return Ok(AuthenticationOutcome::Failure);
     };
-    if !credential.authenticate(user.id(), db_conn).await? {
+    if !credential
+        .authenticate(user.id(), db_conn)
+        .await
+        .map_err(super::errors::StorageError::from)?
+    {
+        return Ok(AuthenticationOutcome::Failure);
+    }
+    begin_session(
+        user,
+        remember_me,
+        false,
+        client,
+        db_conn,
+        session_store_conn,
+    )
+    .await
+}
+
+/// Start a session for a user who has passed primary authentication, fully
+/// authenticating it straight away if they have no MFA method enrolled.
+/// `email_first_factor` is set if the primary authentication only proved that
+/// the user can read their email. Deleted users fail authentication, without
+/// revealing that they exist.
+async fn begin_session(
+    mut user: AppUser,
+    remember_me: bool,
+    email_first_factor: bool,
+    client: &str,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+) -> Result<AuthenticationOutcome, errors::AuthenticateError> {
+    if user.deleted_at.is_some() {
         return Ok(AuthenticationOutcome::Failure);
     }
     let user_id = user.id();
-    let session = PreAuthenticationSession::create(user_id, session_store_conn).await?;
-    if Totp::select(user_id, db_conn).await?.is_none() {
-        match user.role {
-            AppUserRole::Customer => Ok(AuthenticationOutcome::Success(
-                session.promote(session_store_conn).await?,
-            )),
-            AppUserRole::Administrator => Ok(AuthenticationOutcome::SuccessAdministrative(
-                session.promote_to_admin(session_store_conn).await?,
-            )),
-        }
+    let session = PreAuthenticationSession::create(
+        user_id,
+        remember_me,
+        email_first_factor,
+        client,
+        session_store_conn,
+    )
+    .await?;
+    if !user.email_mfa_enabled
+        && Totp::select(user_id, db_conn)
+            .await
+            .map_err(super::errors::StorageError::from)?
+            .is_none()
+    {
+        user.record_login(db_conn)
+            .await
+            .map_err(super::errors::StorageError::from)?;
+        Ok(promote_session(
+            &user,
+            session,
+            REQUIRE_ADMIN_2FA,
+            db_conn,
+            session_store_conn,
+        )
+        .await?
+        .into())
     } else {
         Ok(AuthenticationOutcome::Partial(session))
     }
 }
 
-/// List 2fa methods available for a user
+/// Whether a user's only MFA method is an emailed code. Such users can't log
+/// in with a magic link, since both factors would then come from their inbox.
+async fn has_only_email_mfa(
+    user: &AppUser,
+    db_conn: &db::ConnectionPool,
+) -> Result<bool, db::errors::DatabaseError> {
+    Ok(user.email_mfa_enabled && Totp::select(user.id(), db_conn).await?.is_none())
+}
+
+/// Email a magic link with which a user can log in without their password.
+/// Nothing is sent if no (non-guest, undeleted) user has the address, but
+/// callers must not reveal whether that was the case, so failing to send the
+/// email is only logged. Users whose only MFA method is email are told to log
+/// in with their password instead.
+pub async fn request_magic_link(
+    email: EmailAddress,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+    email_sender: &dyn EmailSender,
+) -> Result<(), super::errors::StorageError> {
+    let Some(user) = AppUser::select_by_email(&email, db_conn)
+        .await?
+        .filter(|found| !found.guest && found.deleted_at.is_none())
+    else {
+        return Ok(());
+    };
+    if has_only_email_mfa(&user, db_conn).await? {
+        if let Err(err) = email_sender
+            .send(
+                &user.email,
+                "Your SecureCart sign-in link",
+                "A sign-in link was requested for your SecureCart account, but your account uses codes sent to this email address for two-factor authentication, so it can't also be signed in to with a link sent here. Please sign in with your password instead.",
+            )
+            .await
+        {
+            tracing::error!("Failed to send magic link refusal to user {}: {err}", user.id());
+        }
+        return Ok(());
+    }
+    let token = sessions::create_magic_link_token(user.id(), session_store_conn).await?;
+    let link = format!(
+        "{}{}/auth/magic-link/consume?token={token}",
+        *PUBLIC_URI,
+        API_URI_PREFIX.trim_end_matches('/')
+    );
+    if let Err(err) = email_sender
+        .send(
+            &user.email,
+            "Your SecureCart sign-in link",
+            &format!(
+                "Follow this link to sign in to SecureCart. It expires in {} minutes and can only be used once.\n\n{link}",
+                MAGIC_LINK_TIMEOUT.div_euclid(60)
+            ),
+        )
+        .await
+    {
+        tracing::error!("Failed to send magic link to user {}: {err}", user.id());
+    }
+    Ok(())
+}
+
+/// Authenticate with a magic link token in place of a primary credential.
+/// The token is consumed whatever the outcome. As with `authenticate`, the
+/// resulting session still requires MFA if the user has it enrolled, but the
+/// session records that the first factor was email, so an email code is not
+/// accepted as the second. Fails for users whose only MFA method is email.
+pub async fn authenticate_magic_link(
+    token: &str,
+    client: &str,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+) -> Result<AuthenticationOutcome, errors::AuthenticateError> {
+    let Some(user_id) = sessions::take_magic_link_user(token, session_store_conn)
+        .await
+        .map_err(super::errors::StorageError::from)?
+    else {
+        return Ok(AuthenticationOutcome::Failure);
+    };
+    let Some(user) = AppUser::select_one(user_id, db_conn)
+        .await
+        .map_err(super::errors::StorageError::from)?
+    else {
+        return Ok(AuthenticationOutcome::Failure);
+    };
+    if has_only_email_mfa(&user, db_conn)
+        .await
+        .map_err(super::errors::StorageError::from)?
+    {
+        tracing::warn!(
+            "User {user_id} used a magic link, but their only MFA method is email, rejected."
+        );
+        return Ok(AuthenticationOutcome::Failure);
+    }
+    begin_session(user, false, true, client, db_conn, session_store_conn).await
+}
+
+/// List 2fa methods available to complete a session. SMS is offered as an
+/// alternative to TOTP for users who have verified their phone number, if
+/// `sms_available` (i.e. an SMS provider is configured), and email codes for
+/// users who have opted in to them, if `email_available`, unless the session's
+/// first factor was email.
 pub async fn list_mfa_methods(
-    user_id: Uuid,
+    session: &PreAuthenticationSession,
+    sms_available: bool,
+    email_available: bool,
     db_conn: &db::ConnectionPool,
 ) -> Result<Vec<MfaAuthenticationMethod>, super::errors::StorageError> {
+    let user_id = session.user_id();
     let mut methods = vec![];
     let totp_enabled = Totp::select(user_id, db_conn).await?.is_some();
     if totp_enabled {
@@ -133,20 +317,135 @@ pub async fn list_mfa_methods(
             code: "string".to_owned(),
         });
     }
+    let user = AppUser::select_one(user_id, db_conn).await?;
+    if sms_available && user.as_ref().is_some_and(has_verified_phone) {
+        methods.push(MfaAuthenticationMethod::Sms {
+            code: "string".to_owned(),
+        });
+    }
+    if email_available
+        && !session.email_first_factor()
+        && user.is_some_and(|found| found.email_mfa_enabled)
+    {
+        methods.push(MfaAuthenticationMethod::EmailOtp {
+            code: "string".to_owned(),
+        });
+    }
     Ok(methods)
 }
 
-/// Validate a 2fa credential for a user.
+/// Whether a user has a phone number which they have proven they can receive
+/// SMS messages at.
+const fn has_verified_phone(user: &AppUser) -> bool {
+    user.phone.is_some() && user.phone_verified
+}
+
+/// Send a one-time code by SMS to the verified phone number of a partially
+/// authenticated user, to be entered with `MfaAuthenticationMethod::Sms`.
+pub async fn send_sms_code(
+    session: &PreAuthenticationSession,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+    sms_sender: &dyn SmsSender,
+) -> Result<(), errors::SmsCodeError> {
+    if !sms_sender.is_configured() {
+        return Err(errors::SmsCodeError::Unavailable);
+    }
+    let phone = AppUser::select_one(session.user_id(), db_conn)
+        .await
+        .map_err(super::errors::StorageError::from)?
+        .filter(has_verified_phone)
+        .and_then(|user| user.phone)
+        .ok_or(errors::SmsCodeError::NoPhoneNumber(session.user_id()))?;
+    let code = session
+        .issue_one_time_code(OneTimeCodeKind::Sms, session_store_conn)
+        .await
+        .map_err(super::errors::StorageError::from)?
+        .ok_or(errors::SmsCodeError::TooManyCodes(session.user_id()))?;
+    sms_sender
+        .send(
+            &phone,
+            &format!("Your SecureCart verification code is {code}."),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Send a one-time code to the email address of a partially authenticated
+/// user who has opted in to email MFA, to be entered with
+/// `MfaAuthenticationMethod::EmailOtp`. Never sent for a session whose first
+/// factor was email.
+pub async fn send_email_code(
+    session: &PreAuthenticationSession,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+    email_sender: &dyn EmailSender,
+) -> Result<(), errors::EmailCodeError> {
+    if session.email_first_factor() {
+        return Err(errors::EmailCodeError::NotEnabled(session.user_id()));
+    }
+    let email = AppUser::select_one(session.user_id(), db_conn)
+        .await
+        .map_err(super::errors::StorageError::from)?
+        .filter(|user| user.email_mfa_enabled)
+        .map(|user| user.email)
+        .ok_or(errors::EmailCodeError::NotEnabled(session.user_id()))?;
+    let code = session
+        .issue_one_time_code(OneTimeCodeKind::Email, session_store_conn)
+        .await
+        .map_err(super::errors::StorageError::from)?
+        .ok_or(errors::EmailCodeError::TooManyCodes(session.user_id()))?;
+    email_sender
+        .send(
+            &email,
+            "Your SecureCart sign-in code",
+            &format!("Your SecureCart verification code is {code}."),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Validate a 2fa credential for a user. Email codes are rejected for
+/// sessions whose first factor was email, since both would come from the
+/// same inbox.
 async fn validate_2fa(
-    user_id: Uuid,
+    session: &PreAuthenticationSession,
     method: MfaAuthenticationMethod,
     db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
 ) -> Result<bool, super::errors::StorageError> {
     match method {
         MfaAuthenticationMethod::Totp { code } => {
-            let totp_secret = Totp::select(user_id, db_conn).await?;
+            let totp_secret = Totp::select(session.user_id(), db_conn).await?;
             Ok(totp_secret.is_some_and(|secret| secret.validate(&code)))
         }
+        MfaAuthenticationMethod::Sms { code } => {
+            // Changing the phone number invalidates any code which was sent.
+            let verified = AppUser::select_one(session.user_id(), db_conn)
+                .await?
+                .is_some_and(|user| has_verified_phone(&user));
+            Ok(verified
+                && session
+                    .verify_one_time_code(OneTimeCodeKind::Sms, &code, session_store_conn)
+                    .await?)
+        }
+        MfaAuthenticationMethod::EmailOtp { .. } if session.email_first_factor() => {
+            tracing::warn!(
+                "User {} attempted to use an email code after a magic link, rejected.",
+                session.user_id()
+            );
+            Ok(false)
+        }
+        MfaAuthenticationMethod::EmailOtp { code } => {
+            // Opting out invalidates any code which was already sent.
+            let enabled = AppUser::select_one(session.user_id(), db_conn)
+                .await?
+                .is_some_and(|user| user.email_mfa_enabled);
+            Ok(enabled
+                && session
+                    .verify_one_time_code(OneTimeCodeKind::Email, &code, session_store_conn)
+                    .await?)
+        }
     }
 }
 
@@ -156,10 +455,46 @@ pub enum AuthenticationOutcome2fa {
     Success(CustomerSession),
     /// The authentication was successful, an `AdministrativeSession` was created.
     SuccessAdministrative(AdministratorSession),
+    /// The authentication was successful, but the user is an administrator
+    /// without TOTP enrolled while `REQUIRE_ADMIN_2FA` is set. Only a
+    /// `CustomerSession` was created, with which they must enrol.
+    TotpEnrolmentRequired(CustomerSession),
     /// The authentication was unsuccessful.
     Failure,
 }
 
+/// Promote a fully authenticated session according to the user's role. This
+/// is the only place administrative sessions are granted on login, and so
+/// where `REQUIRE_ADMIN_2FA` is enforced, given as `require_admin_totp`.
+async fn promote_session(
+    user: &AppUser,
+    session: PreAuthenticationSession,
+    require_admin_totp: bool,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+) -> Result<AuthenticationOutcome2fa, super::errors::StorageError> {
+    match user.role {
+        AppUserRole::Customer => Ok(AuthenticationOutcome2fa::Success(
+            session.promote(session_store_conn).await?,
+        )),
+        AppUserRole::Administrator => {
+            if require_admin_totp && Totp::select(user.id(), db_conn).await?.is_none() {
+                tracing::info!(
+                    "Administrator {} has not enrolled TOTP, granting only a customer session.",
+                    user.id()
+                );
+                Ok(AuthenticationOutcome2fa::TotpEnrolmentRequired(
+                    session.promote(session_store_conn).await?,
+                ))
+            } else {
+                Ok(AuthenticationOutcome2fa::SuccessAdministrative(
+                    session.promote_to_admin(session_store_conn).await?,
+                ))
+            }
+        }
+    }
+}
+
 /// Authenticate a partially authenticated user using an MFA method.
 pub async fn authenticate_2fa(
     session: PreAuthenticationSession,
@@ -167,22 +502,202 @@ pub async fn authenticate_2fa(
     db_conn: &db::ConnectionPool,
     session_store_conn: &mut sessions::store::Connection,
 ) -> Result<AuthenticationOutcome2fa, super::errors::StorageError> {
-    let user = AppUser::select_one(session.user_id(), db_conn)
+    let mut user = AppUser::select_one(session.user_id(), db_conn)
         .await?
         .expect("User was deleting while authenticating session. Bailing.");
-    if validate_2fa(session.user_id(), method, db_conn).await? {
-        match user.role {
-            AppUserRole::Customer => Ok(AuthenticationOutcome2fa::Success(
-                session.promote(session_store_conn).await?,
-            )),
-            AppUserRole::Administrator => Ok(AuthenticationOutcome2fa::SuccessAdministrative(
-                session.promote_to_admin(session_store_conn).await?,
-            )),
-        }
+    // The user may have been deleted since the session was started.
+    if user.deleted_at.is_some() {
+        return Ok(AuthenticationOutcome2fa::Failure);
+    }
+    if validate_2fa(&session, method, db_conn, session_store_conn).await? {
+        user.record_login(db_conn).await?;
+        promote_session(
+            &user,
+            session,
+            REQUIRE_ADMIN_2FA,
+            db_conn,
+            session_store_conn,
+        )
+        .await
     } else {
         Ok(AuthenticationOutcome2fa::Failure)
     }
 }
 
 /// Errors returned by functions within this module.
-pub mod errors {}
+pub mod errors {
+    pub use super::super::errors::StorageError;
+    use crate::{
+        services::sessions::errors::PendingSessionError,
+        utils::{mailer::MailError, sms::SmsError},
+    };
+    use thiserror::Error;
+    use uuid::Uuid;
+
+    /// Errors returned while authenticating with a primary credential.
+    #[derive(Error, Debug)]
+    pub enum AuthenticateError {
+        /// An error in the underlying storage.
+        #[error(transparent)]
+        StorageError(#[from] StorageError),
+        /// The client already has the maximum number of partially
+        /// authenticated sessions in progress.
+        #[error("Too many authentication attempts are in progress from this client")]
+        TooManySessions,
+    }
+
+    impl From<PendingSessionError> for AuthenticateError {
+        fn from(err: PendingSessionError) -> Self {
+            match err {
+                PendingSessionError::LimitReached => Self::TooManySessions,
+                PendingSessionError::StorageError(storage_err) => {
+                    Self::StorageError(storage_err.into())
+                }
+            }
+        }
+    }
+
+    /// Errors returned while sending an MFA code by SMS.
+    #[derive(Error, Debug)]
+    pub enum SmsCodeError {
+        /// An error in the underlying storage.
+        #[error(transparent)]
+        StorageError(#[from] StorageError),
+        /// No SMS provider is configured, so SMS codes can't be sent.
+        #[error("No SMS provider is configured")]
+        Unavailable,
+        /// The user has no verified phone number to send the code to.
+        #[error("User {0} has no verified phone number")]
+        NoPhoneNumber(Uuid),
+        /// The session has already been sent `ONE_TIME_CODE_MAX_ISSUED` codes.
+        #[error("Too many SMS codes were requested for user {0}")]
+        TooManyCodes(Uuid),
+        /// The SMS provider failed to deliver the code.
+        #[error(transparent)]
+        DeliveryFailed(#[from] SmsError),
+    }
+
+    /// Errors returned while sending an MFA code by email.
+    #[derive(Error, Debug)]
+    pub enum EmailCodeError {
+        /// An error in the underlying storage.
+        #[error(transparent)]
+        StorageError(#[from] StorageError),
+        /// The user has not opted in to email MFA.
+        #[error("User {0} has not enabled email MFA")]
+        NotEnabled(Uuid),
+        /// The session has already been sent `ONE_TIME_CODE_MAX_ISSUED` codes.
+        #[error("Too many email codes were requested for user {0}")]
+        TooManyCodes(Uuid),
+        /// The email provider failed to deliver the code.
+        #[error(transparent)]
+        DeliveryFailed(#[from] MailError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{promote_session, AuthenticationOutcome2fa};
+    use crate::{
+        db::{
+            models::{
+                appuser::{AppUser, AppUserRole},
+                totp::TotpInsert,
+            },
+            ConnectionPool,
+        },
+        services::sessions::{fake_store::FakeStore, store, PreAuthenticationSession},
+        testing::{store_user, CLIENT_IP},
+    };
+
+    /// Store an administrator, with TOTP enrolled if `totp` is set.
+    async fn store_administrator(totp: bool, db_conn: &ConnectionPool) -> AppUser {
+        let mut user = store_user("admin@example.com", db_conn).await;
+        user.role = AppUserRole::Administrator;
+        user.update(db_conn).await.expect("User should be updated");
+        if totp {
+            TotpInsert::new(user.id(), vec![7; 20])
+                .store(db_conn)
+                .await
+                .expect("TOTP should be stored");
+        }
+        user
+    }
+
+    /// Promote a new session for a user who has fully authenticated.
+    async fn promote(
+        user: &AppUser,
+        require_admin_totp: bool,
+        db_conn: &ConnectionPool,
+    ) -> AuthenticationOutcome2fa {
+        let mut session_store_conn = store::Connection::fake(&FakeStore::default());
+        let session = PreAuthenticationSession::create(
+            user.id(),
+            false,
+            false,
+            CLIENT_IP,
+            &mut session_store_conn,
+        )
+        .await
+        .expect("Session should be created");
+        promote_session(
+            user,
+            session,
+            require_admin_totp,
+            db_conn,
+            &mut session_store_conn,
+        )
+        .await
+        .expect("Session should be promoted")
+    }
+
+    /// An administrator without TOTP is only given a customer session, with
+    /// which to enrol, while the policy is on.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn admin_without_totp_blocked_when_required(db_conn: ConnectionPool) {
+        let admin = store_administrator(false, &db_conn).await;
+        assert!(matches!(
+            promote(&admin, true, &db_conn).await,
+            AuthenticationOutcome2fa::TotpEnrolmentRequired(_)
+        ));
+    }
+
+    /// An administrator without TOTP is given an administrative session while
+    /// the policy is off.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn admin_without_totp_allowed_when_not_required(db_conn: ConnectionPool) {
+        let admin = store_administrator(false, &db_conn).await;
+        assert!(matches!(
+            promote(&admin, false, &db_conn).await,
+            AuthenticationOutcome2fa::SuccessAdministrative(_)
+        ));
+    }
+
+    /// An administrator with TOTP is given an administrative session while
+    /// the policy is on.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn admin_with_totp_allowed_when_required(db_conn: ConnectionPool) {
+        let admin = store_administrator(true, &db_conn).await;
+        assert!(matches!(
+            promote(&admin, true, &db_conn).await,
+            AuthenticationOutcome2fa::SuccessAdministrative(_)
+        ));
+    }
+
+    /// Customers without TOTP are given a customer session whether or not
+    /// the policy is on.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn customers_unaffected_by_policy(db_conn: ConnectionPool) {
+        let customer = store_user("customer@example.com", &db_conn).await;
+        for require_admin_totp in [true, false] {
+            assert!(matches!(
+                promote(&customer, require_admin_totp, &db_conn).await,
+                AuthenticationOutcome2fa::Success(_)
+            ));
+        }
+    }
+}
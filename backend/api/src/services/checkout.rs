@@ -1,11 +1,35 @@
 //! Logic for handling checkouts, with or without Stripe integrated.
 #[cfg(feature = "stripe")]
 use crate::constants::stripe::STRIPE_SECRET_KEY;
-use crate::db::{self, models::apporder::AppOrder};
+#[cfg(feature = "stripe")]
+use crate::db::models::stripe_event::StripeEvent;
+use crate::db::{
+    self,
+    models::{apporder::AppOrder, order_item::OrderItem, product::Product},
+};
 #[cfg(feature = "stripe")]
 use stripe;
 use uuid::Uuid;
 
+/// Check that every item in an order can still be ordered in the quantity
+/// requested, since products may have been unlisted or sold out since the
+/// order was created.
+async fn ensure_items_available(
+    order_id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<(), errors::CheckoutTokenCreateError> {
+    for item in OrderItem::select_all(order_id, db_conn).await? {
+        let product_id = item.product_id();
+        let available = Product::select_one(product_id, db_conn)
+            .await?
+            .is_some_and(|product| product.is_listed() && product.has_stock(item.count()));
+        if !available {
+            return Err(errors::CheckoutTokenCreateError::ItemNoLongerAvailable { product_id });
+        }
+    }
+    Ok(())
+}
+
 #[cfg(feature = "stripe")]
 /// A live checkout token containing a stripe PaymentIntent.
 pub struct CheckoutToken(stripe::PaymentIntent);
@@ -28,6 +52,7 @@ impl CheckoutToken {
         if order.user_id() != user_id {
             return Err(errors::CheckoutTokenCreateError::Unauthorized { user_id, order_id });
         }
+        ensure_items_available(order_id, db_conn).await?;
         let stripe_client = stripe::Client::new(&*STRIPE_SECRET_KEY);
         let mut create_intent =
             stripe::CreatePaymentIntent::new(order.amount_charged, stripe::Currency::GBP);
@@ -63,11 +88,11 @@ impl CheckoutToken {
         let order = AppOrder::select_one(order_id, db_conn)
             .await?
             .ok_or(errors::CheckoutTokenCreateError::OrderNonExistent { user_id, order_id })?;
-        if order.user_id() == user_id {
-            Ok(Self)
-        } else {
-            Err(errors::CheckoutTokenCreateError::Unauthorized { user_id, order_id })
+        if order.user_id() != user_id {
+            return Err(errors::CheckoutTokenCreateError::Unauthorized { user_id, order_id });
         }
+        ensure_items_available(order_id, db_conn).await?;
+        Ok(Self)
     }
     #[cfg(not(feature = "stripe"))]
     #[expect(
@@ -80,6 +105,50 @@ impl CheckoutToken {
     }
 }
 
+/// Refund `amount` pennies of the Stripe payment made for an order, returning
+/// the ID of the refund in Stripe. The ID of the recorded refund is used as the
+/// idempotency key, so Stripe makes the refund at most once however many times
+/// it is requested.
+#[cfg(feature = "stripe")]
+pub async fn refund_payment(
+    order_id: Uuid,
+    refund_id: Uuid,
+    amount: i64,
+    db_conn: &db::ConnectionPool,
+) -> Result<Option<String>, errors::RefundPaymentError> {
+    let payment_intent = StripeEvent::select_payment_intent(order_id, db_conn)
+        .await?
+        .ok_or(errors::RefundPaymentError::NoPayment(order_id))?;
+    let stripe_client = stripe::Client::new(&*STRIPE_SECRET_KEY)
+        .with_strategy(stripe::RequestStrategy::Idempotent(refund_id.to_string()));
+    let mut create_refund = stripe::CreateRefund::new();
+    create_refund.payment_intent = Some(payment_intent.parse()?);
+    create_refund.amount = Some(amount);
+    Ok(Some(
+        stripe::Refund::create(&stripe_client, create_refund)
+            .await?
+            .id
+            .to_string(),
+    ))
+}
+
+/// Payments are not taken when Stripe is disabled, so there is nothing to
+/// refund. Always returns None, would return the Stripe refund ID if stripe
+/// were enabled.
+#[cfg(not(feature = "stripe"))]
+#[expect(
+    clippy::unused_async,
+    reason = "This is a mock function, must match the real signature"
+)]
+pub async fn refund_payment(
+    _order_id: Uuid,
+    _refund_id: Uuid,
+    _amount: i64,
+    _db_conn: &db::ConnectionPool,
+) -> Result<Option<String>, errors::RefundPaymentError> {
+    Ok(None)
+}
+
 /// TODO: add documentation
 pub mod errors {
     use crate::db::errors::DatabaseError;
@@ -108,8 +177,34 @@ pub mod errors {
             /// TODO: add documentation
             order_id: Uuid,
         },
+        #[error("An item in the order is no longer available in the quantity ordered")]
+        /// A product in the order has been unlisted, or has too little stock.
+        ItemNoLongerAvailable {
+            /// The ID of the unavailable product.
+            product_id: Uuid,
+        },
+        #[cfg(feature = "stripe")]
+        #[error(transparent)]
+        StripeError(#[from] stripe::StripeError),
+    }
+
+    #[derive(Debug, Error)]
+    /// An error returned while refunding the payment for an order.
+    pub enum RefundPaymentError {
+        #[error(transparent)]
+        /// An error returned up from the database.
+        DatabaseError(#[from] DatabaseError),
+        #[cfg(feature = "stripe")]
+        #[error("No Stripe payment was recorded for order {0}")]
+        /// The order has no recorded payment to refund.
+        NoPayment(Uuid),
+        #[cfg(feature = "stripe")]
+        #[error(transparent)]
+        /// The recorded `PaymentIntent` ID is malformed.
+        InvalidPaymentIntent(#[from] stripe::ParseIdError),
         #[cfg(feature = "stripe")]
         #[error(transparent)]
+        /// An error returned by Stripe while creating the refund.
         StripeError(#[from] stripe::StripeError),
     }
 }
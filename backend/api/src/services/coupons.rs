@@ -0,0 +1,138 @@
+//! Functions for applying coupon codes, which take a percentage off the total
+//! of an order.
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    db::{
+        self,
+        models::{apporder::AppOrder, coupon::Coupon},
+    },
+    utils::pennies::Pennies,
+};
+
+/// The amount a coupon is being applied to.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CouponTarget {
+    /// An existing order, which must belong to the customer.
+    OrderId(Uuid),
+    /// A tentative total in pennies, e.g. of a basket which has not yet been
+    /// placed as an order.
+    Total(u32),
+}
+
+/// The effect of applying a coupon to an amount.
+#[derive(Serialize)]
+pub struct CouponQuote {
+    /// The coupon code which was applied.
+    code: String,
+    /// The percentage taken off the total.
+    percent_off: u8,
+    /// The total in pennies before the discount.
+    subtotal: i64,
+    /// The discount in pennies, rounded down to the nearest penny.
+    discount: i64,
+    /// The total in pennies after the discount.
+    total: i64,
+}
+
+/// Look up a coupon by its code, failing unless it exists, has not expired
+/// and has redemptions remaining. Nothing is redeemed.
+pub async fn redeemable_coupon(
+    code: &str,
+    db_conn: &db::ConnectionPool,
+) -> Result<Coupon, errors::CouponValidationError> {
+    let coupon = Coupon::select(code, db_conn)
+        .await?
+        .ok_or(errors::CouponValidationError::NonExistent)?;
+    if coupon.is_expired() {
+        return Err(errors::CouponValidationError::Expired);
+    }
+    if coupon.is_exhausted() {
+        return Err(errors::CouponValidationError::Exhausted);
+    }
+    Ok(coupon)
+}
+
+/// Redeem a coupon by its code as part of `transaction`, so that the
+/// redemption is undone if the transaction is not committed. Fails with the
+/// same errors as `redeemable_coupon` if the coupon cannot be redeemed.
+pub async fn redeem_coupon(
+    code: &str,
+    transaction: &mut db::Transaction,
+    db_conn: &db::ConnectionPool,
+) -> Result<Coupon, errors::CouponValidationError> {
+    if let Some(coupon) = Coupon::redeem(code, &mut **transaction).await? {
+        return Ok(coupon);
+    }
+    // Only reached if the coupon cannot be redeemed, to find out why. If it
+    // appears redeemable now, its last redemption was taken concurrently.
+    redeemable_coupon(code, db_conn).await?;
+    Err(errors::CouponValidationError::Exhausted)
+}
+
+/// Compute the discount a coupon would give if applied to an order (or a
+/// tentative total), without redeeming it.
+pub async fn validate_coupon(
+    user_id: Uuid,
+    code: &str,
+    target: CouponTarget,
+    db_conn: &db::ConnectionPool,
+) -> Result<CouponQuote, errors::CouponValidationError> {
+    let coupon = redeemable_coupon(code, db_conn).await?;
+    let subtotal = match target {
+        CouponTarget::OrderId(order_id) => {
+            let order = AppOrder::select_one(order_id, db_conn)
+                .await?
+                .filter(|order| order.user_id() == user_id)
+                .ok_or(errors::CouponValidationError::OrderNonExistent { user_id, order_id })?;
+            Pennies::from_stored(order.amount_charged)
+                .expect("Amount charged in database is negative")
+        }
+        CouponTarget::Total(total) => Pennies::from(total),
+    };
+    let discount = subtotal.checked_percentage(coupon.percent_off())?;
+    Ok(CouponQuote {
+        code: coupon.code().to_owned(),
+        percent_off: coupon.percent_off(),
+        subtotal: subtotal.as_i64(),
+        discount: discount.as_i64(),
+        total: subtotal.saturating_sub(discount).as_i64(),
+    })
+}
+
+/// Errors returned by functions within this module.
+pub mod errors {
+    use crate::{db::errors::DatabaseError, utils::pennies::errors::PenniesOverflow};
+    use thiserror::Error;
+    use uuid::Uuid;
+
+    /// Errors returned when validating a coupon code.
+    #[derive(Error, Debug)]
+    pub enum CouponValidationError {
+        /// Error passed up from the database storage layer.
+        #[error(transparent)]
+        DatabaseError(#[from] DatabaseError),
+        /// Raised when no coupon has the given code.
+        #[error("The coupon code does not exist.")]
+        NonExistent,
+        /// Raised when the coupon has passed its expiry time.
+        #[error("The coupon has expired.")]
+        Expired,
+        /// Raised when the coupon has been redeemed as many times as it may be.
+        #[error("The coupon has no redemptions remaining.")]
+        Exhausted,
+        /// Raised when the order does not exist or belongs to another user.
+        #[error("User {user_id} attempted to apply a coupon to non-existent order {order_id}.")]
+        OrderNonExistent {
+            /// The ID of the user applying the coupon.
+            user_id: Uuid,
+            /// The ID of the order.
+            order_id: Uuid,
+        },
+        /// Raised when computing the discount overflows.
+        #[error(transparent)]
+        Overflow(#[from] PenniesOverflow),
+    }
+}
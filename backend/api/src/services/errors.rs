@@ -19,7 +19,7 @@ pub enum StorageError {
 
 impl From<StorageError> for HttpError {
     fn from(value: StorageError) -> Self {
-        eprintln!("Storage error in route handler: {value}");
+        tracing::error!("Storage error in route handler: {value}");
         Self::new(StatusCode::INTERNAL_SERVER_ERROR, Some(value.to_string()))
     }
 }
@@ -1,17 +1,62 @@
 //! Logic for storing and operating on stored media objects, such as images.
-use std::path::PathBuf;
-#[expect(clippy::useless_attribute, reason = "This is from clippy::restricted")]
-#[expect(
-    clippy::std_instead_of_alloc,
-    reason = "Alloc is not available outside of no_std"
-)]
-use std::sync::Arc;
+use alloc::sync::Arc;
+use std::{io::Cursor, path::PathBuf};
 
+use core::time::Duration;
+use image::{imageops::FilterType, GenericImageView as _, ImageFormat};
 use object_store::{path::Path, Attribute, Attributes, ObjectStore, PutOptions, PutPayload};
+use serde::Deserialize;
 use sha2::{Digest as _, Sha256};
+use tokio::{task::spawn_blocking, time::sleep};
 
-/// The prefix within the storage bucket under which images will be stored.
-const IMAGE_PREFIX: &str = "/images";
+use crate::constants::s3::{
+    S3_DOWNLOAD_PREFIX, S3_IMAGE_PREFIX, S3_RETRY_ATTEMPTS, S3_RETRY_BACKOFF_MS,
+};
+
+/// The widths (in pixels) of the downsized variants generated for each
+/// uploaded image, used to build a responsive `srcset`.
+const RESPONSIVE_WIDTHS: [u32; 3] = [256, 512, 1024];
+
+/// How a browser should present a stored object when it is requested.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentDisposition {
+    /// Display the object in the page (the default, used for product display).
+    #[default]
+    Inline,
+    /// Force the object to be downloaded as a file.
+    Attachment,
+}
+
+impl ContentDisposition {
+    /// Get the value of the Content-Disposition header for this disposition.
+    const fn header_value(self) -> &'static str {
+        match self {
+            Self::Inline => "inline",
+            Self::Attachment => "attachment",
+        }
+    }
+    /// Get the prefix within the storage bucket used for this disposition.
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::Inline => &S3_IMAGE_PREFIX,
+            Self::Attachment => &S3_DOWNLOAD_PREFIX,
+        }
+    }
+}
+
+/// Whether a path (relative to the bucket root, with or without a leading
+/// separator) lies under one of the prefixes media is stored under.
+pub fn is_media_path(path: &str) -> bool {
+    let relative = path.trim_start_matches('/');
+    [ContentDisposition::Inline, ContentDisposition::Attachment]
+        .into_iter()
+        .any(|disposition| {
+            relative
+                .strip_prefix(disposition.prefix().trim_start_matches('/'))
+                .is_some_and(|rest| rest.starts_with('/'))
+        })
+}
 
 /// Supported image file types.
 enum ImageFileType {
@@ -55,10 +100,12 @@ impl ImageFileType {
 
 /// Store an image in the media store. Will return the path under the storage bucket
 /// at which the image has been stored, and will error if the image file is of an
-/// unsupported type or the networked storage access fails.
+/// unsupported type or the networked storage access fails. The stored object
+/// will be served with the given `ContentDisposition`.
 pub async fn store_image(
     store: Arc<dyn ObjectStore>,
     image: Vec<u8>,
+    disposition: ContentDisposition,
 ) -> Result<String, errors::StoreImageError> {
     let mut hasher = Sha256::new();
     hasher.update(&image);
@@ -67,7 +114,7 @@ pub async fn store_image(
         ImageFileType::from_bytes(&image).ok_or(errors::StoreImageError::InvalidFileType)?;
     let object_name = format!("{hash:x}");
     let object_path = PathBuf::new()
-        .join(IMAGE_PREFIX)
+        .join(disposition.prefix())
         .join(object_name)
         .with_extension(file_type.extension())
         .to_string_lossy()
@@ -79,24 +126,252 @@ pub async fn store_image(
         Attribute::ContentType,
         file_type.mimetype().to_owned().into(),
     );
-    object_attributes.insert(Attribute::ContentDisposition, "inline".into());
+    object_attributes.insert(
+        Attribute::ContentDisposition,
+        disposition.header_value().into(),
+    );
     let put_opts = PutOptions {
         attributes: object_attributes,
         ..Default::default()
     };
-    store
-        .put_opts(
-            &Path::from(object_path.as_str()),
-            PutPayload::from(image),
-            put_opts,
-        )
+    let location = Path::from(object_path.as_str());
+    let payload = PutPayload::from(image);
+    let mut attempt = 1;
+    let mut backoff = Duration::from_millis(*S3_RETRY_BACKOFF_MS);
+    loop {
+        // Payloads are reference counted, so cloning one doesn't copy the image.
+        match store
+            .put_opts(&location, payload.clone(), put_opts.clone())
+            .await
+        {
+            Ok(_) => return Ok(object_path),
+            Err(err) if errors::is_transient(&err) && attempt < *S3_RETRY_ATTEMPTS => {
+                tracing::warn!(
+                    "Transient object store error on attempt {attempt}, retrying: {err}"
+                );
+                sleep(backoff).await;
+                backoff = backoff.saturating_mul(2);
+                attempt = attempt.saturating_add(1);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Generate and store downsized variants of an image at each of the
+/// `RESPONSIVE_WIDTHS` narrower than the original, returning the width and
+/// storage path of each variant. Images are never upscaled, so a small original
+/// may produce no variants at all. Only PNG images are currently decoded;
+/// other supported types are stored without variants.
+pub async fn store_image_sizes(
+    store: Arc<dyn ObjectStore>,
+    image: Vec<u8>,
+    disposition: ContentDisposition,
+) -> Result<Vec<(u32, String)>, errors::StoreImageError> {
+    if !matches!(ImageFileType::from_bytes(&image), Some(ImageFileType::Png)) {
+        return Ok(Vec::new());
+    }
+    let variants = spawn_blocking(move || resize_png(&image))
         .await
-        .map_err(errors::StorageError::from)?;
-    Ok(object_path)
+        .expect("Image resizing task panicked")?;
+    let mut sizes = Vec::with_capacity(variants.len());
+    for (width, variant) in variants {
+        let path = store_image(Arc::clone(&store), variant, disposition).await?;
+        sizes.push((width, path));
+    }
+    Ok(sizes)
+}
+
+/// Decode a PNG image and re-encode it at each of the `RESPONSIVE_WIDTHS`
+/// narrower than the original, preserving its aspect ratio.
+fn resize_png(image: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, errors::StoreImageError> {
+    let decoded = image::load_from_memory_with_format(image, ImageFormat::Png)?;
+    let (original_width, original_height) = decoded.dimensions();
+    RESPONSIVE_WIDTHS
+        .into_iter()
+        .filter(|&width| width < original_width)
+        .map(|width| {
+            let resized = decoded.resize(width, original_height, FilterType::Lanczos3);
+            let mut encoded = Cursor::new(Vec::new());
+            resized.write_to(&mut encoded, ImageFormat::Png)?;
+            Ok((width, encoded.into_inner()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::VecDeque;
+    use alloc::sync::Arc;
+    use core::{
+        fmt::{self, Display, Formatter},
+        iter::repeat_with,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+    use std::{
+        io::{self, ErrorKind},
+        sync::Mutex,
+    };
+
+    use async_trait::async_trait;
+    use futures_util::stream::BoxStream;
+    use object_store::{
+        memory::InMemory, path::Path, GetOptions, GetResult, ListResult, MultipartUpload,
+        ObjectMeta, ObjectStore, PutMultipartOpts, PutOptions, PutPayload, PutResult,
+    };
+
+    use super::{errors::StoreImageError, store_image, ContentDisposition};
+    use crate::constants::s3::S3_RETRY_ATTEMPTS;
+
+    /// The magic bytes of a PNG image, enough for it to be stored.
+    const PNG: &[u8] = &[0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0];
+
+    /// An in-memory object store whose writes fail with queued errors, in
+    /// order, before succeeding.
+    #[derive(Debug, Default)]
+    struct FailingStore {
+        /// Where writes are stored once the queued failures run out.
+        inner: InMemory,
+        /// The errors returned by the next writes.
+        failures: Mutex<VecDeque<object_store::Error>>,
+        /// The number of writes attempted.
+        puts: AtomicU32,
+    }
+
+    impl FailingStore {
+        /// Create a store whose next writes fail with each of `failures`.
+        fn new(failures: impl IntoIterator<Item = object_store::Error>) -> Self {
+            Self {
+                failures: Mutex::new(failures.into_iter().collect()),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Display for FailingStore {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "FailingStore")
+        }
+    }
+
+    #[expect(
+        clippy::missing_trait_methods,
+        reason = "The provided methods all go through the required ones"
+    )]
+    #[async_trait]
+    impl ObjectStore for FailingStore {
+        async fn put_opts(
+            &self,
+            location: &Path,
+            payload: PutPayload,
+            opts: PutOptions,
+        ) -> object_store::Result<PutResult> {
+            self.puts.fetch_add(1, Ordering::SeqCst);
+            let failure = self
+                .failures
+                .lock()
+                .expect("Failure queue poisoned")
+                .pop_front();
+            match failure {
+                Some(err) => Err(err),
+                None => self.inner.put_opts(location, payload, opts).await,
+            }
+        }
+        async fn put_multipart_opts(
+            &self,
+            location: &Path,
+            opts: PutMultipartOpts,
+        ) -> object_store::Result<Box<dyn MultipartUpload>> {
+            self.inner.put_multipart_opts(location, opts).await
+        }
+        async fn get_opts(
+            &self,
+            location: &Path,
+            options: GetOptions,
+        ) -> object_store::Result<GetResult> {
+            self.inner.get_opts(location, options).await
+        }
+        async fn delete(&self, location: &Path) -> object_store::Result<()> {
+            self.inner.delete(location).await
+        }
+        fn list(&self, prefix: Option<&Path>) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+        async fn list_with_delimiter(
+            &self,
+            prefix: Option<&Path>,
+        ) -> object_store::Result<ListResult> {
+            self.inner.list_with_delimiter(prefix).await
+        }
+        async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+            self.inner.copy(from, to).await
+        }
+        async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    /// An error as the object store reports a dropped connection.
+    fn connection_reset() -> object_store::Error {
+        object_store::Error::Generic {
+            store: "S3",
+            source: Box::new(io::Error::from(ErrorKind::ConnectionReset)),
+        }
+    }
+
+    /// Store the test image in a store, returning its result and the number
+    /// of writes the store saw.
+    async fn store(failing_store: FailingStore) -> (Result<String, StoreImageError>, u32) {
+        let shared = Arc::new(failing_store);
+        let object_store: Arc<dyn ObjectStore> = Arc::<FailingStore>::clone(&shared);
+        let result = store_image(object_store, PNG.to_vec(), ContentDisposition::Inline).await;
+        (result, shared.puts.load(Ordering::SeqCst))
+    }
+
+    /// A missing bucket is reported as not found, without retrying.
+    #[tokio::test(start_paused = true)]
+    async fn not_found_is_classified() {
+        let (result, puts) = store(FailingStore::new([object_store::Error::NotFound {
+            path: "securecart".to_owned(),
+            source: "NoSuchBucket".into(),
+        }]))
+        .await;
+        assert!(matches!(result, Err(StoreImageError::NotFound(path)) if path == "securecart"));
+        assert_eq!(puts, 1);
+    }
+
+    /// A transient failure is retried, and the image stored once it passes.
+    #[tokio::test(start_paused = true)]
+    async fn transient_error_is_retried() {
+        let (result, puts) = store(FailingStore::new([connection_reset()])).await;
+        assert!(result.is_ok_and(|path| Path::from(path).extension() == Some("png")));
+        assert_eq!(puts, 2);
+    }
+
+    /// A transient failure which outlasts every retry is reported as the
+    /// store being unavailable.
+    #[tokio::test(start_paused = true)]
+    async fn persistent_transient_error_is_unavailable() {
+        let failures = repeat_with(connection_reset)
+            .take(usize::try_from(*S3_RETRY_ATTEMPTS).expect("Too many attempts"));
+        let (result, puts) = store(FailingStore::new(failures)).await;
+        assert!(matches!(result, Err(StoreImageError::Unavailable(_))));
+        assert_eq!(puts, *S3_RETRY_ATTEMPTS);
+    }
+
+    /// Other errors are neither retried nor reported as transient.
+    #[tokio::test(start_paused = true)]
+    async fn logical_error_is_not_retried() {
+        let (result, puts) = store(FailingStore::new([object_store::Error::NotImplemented])).await;
+        assert!(matches!(result, Err(StoreImageError::StorageError(_))));
+        assert_eq!(puts, 1);
+    }
 }
 
 /// Errors returned from this module.
 pub mod errors {
+    use core::{error::Error as StdError, iter::successors};
+    use std::io::{self, ErrorKind};
     use thiserror::Error;
     /// Errors returned when storing an image.
     #[derive(Debug, Error)]
@@ -105,9 +380,64 @@ pub mod errors {
         /// (see ``ImageFileType``).
         #[error("Image is of invalid file type")]
         InvalidFileType,
-        /// An error occurred during the actual storage operation.
+        /// The object store reported that an object (or the bucket) does not
+        /// exist.
+        #[error("Object at {0} not found")]
+        NotFound(String),
+        /// The object store refused to overwrite an existing object.
+        #[error("Object at {0} already exists")]
+        AlreadyExists(String),
+        /// The object store could not be reached, even after retrying. The
+        /// operation may succeed if attempted again later.
+        #[error("Object store unavailable: {0}")]
+        Unavailable(object_store::Error),
+        /// Any other error during the actual storage operation.
         #[error(transparent)]
         StorageError(#[from] StorageError),
+        /// The image could not be decoded or resized.
+        #[error(transparent)]
+        ResizeError(#[from] image::ImageError),
+    }
+
+    impl From<object_store::Error> for StoreImageError {
+        fn from(err: object_store::Error) -> Self {
+            #[expect(
+                clippy::wildcard_enum_match_arm,
+                reason = "Every other kind of object store error is handled the same way"
+            )]
+            match err {
+                object_store::Error::NotFound { path, .. } => Self::NotFound(path),
+                object_store::Error::AlreadyExists { path, .. } => Self::AlreadyExists(path),
+                other if is_transient(&other) => Self::Unavailable(other),
+                other => Self::StorageError(StorageError::from(other)),
+            }
+        }
+    }
+
+    /// Whether an object store error is a transient, connection-level failure
+    /// (as opposed to a logical one), so the operation may succeed if retried.
+    /// The HTTP client's own errors are private to `object_store`, so this is
+    /// recognised by an I/O error of a connection-level kind in its causes.
+    pub(super) fn is_transient(err: &object_store::Error) -> bool {
+        let object_store::Error::Generic { ref source, .. } = *err else {
+            return false;
+        };
+        let root: &(dyn StdError + 'static) = source.as_ref();
+        successors(Some(root), |&cause| cause.source())
+            .filter_map(|cause| cause.downcast_ref::<io::Error>())
+            .any(|io_err| {
+                matches!(
+                    io_err.kind(),
+                    ErrorKind::ConnectionRefused
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::NotConnected
+                        | ErrorKind::BrokenPipe
+                        | ErrorKind::TimedOut
+                        | ErrorKind::UnexpectedEof
+                        | ErrorKind::Interrupted
+                )
+            })
     }
 
     /// An error passed up from the underlying object store.
@@ -1,10 +1,16 @@
 //! Controllers which correspond to routes and define core business logic.
 pub mod auth;
 pub mod checkout;
+pub mod coupons;
 pub mod errors;
 mod media;
 pub mod orders;
 pub mod products;
 pub mod registration;
+pub mod reviews;
+pub mod seed;
 pub mod sessions;
+pub mod shipping;
+#[cfg(feature = "stripe")]
+pub mod stripe_events;
 pub mod users;
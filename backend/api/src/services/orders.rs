@@ -1,85 +1,727 @@
 //! Logic for handling orders, interacts with the `AppOrder` model.
+use alloc::sync::Arc;
+use futures_util::StreamExt as _;
 use serde::Serialize;
-use time::{OffsetDateTime, PrimitiveDateTime};
+use std::collections::HashMap;
+use time::{
+    format_description::well_known::Iso8601, serde::iso8601, OffsetDateTime, PrimitiveDateTime,
+};
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
-use crate::db::{
-    self,
-    models::{
-        apporder::{AppOrder, AppOrderInsert, AppOrderSearchParameters, AppOrderStatus},
-        appuser::AppUser,
-        order_item::{OrderItem, OrderItemInsert},
-        product::Product,
+use crate::{
+    constants::orders::{PRICES_INCLUDE_TAX, TAX_RATE_BASIS_POINTS},
+    db::{
+        self,
+        errors::UpdateError,
+        models::{
+            apporder::{
+                AmountBreakdown, AppOrder, AppOrderInsert, AppOrderSearchParameters,
+                AppOrderStatus, ShippingMethod,
+            },
+            appuser::{AppUser, AppUserInsert},
+            coupon::Coupon,
+            order_event::{OrderEvent, OrderEventInsert},
+            order_item::{OrderItem, OrderItemInsert},
+            order_refund::{self, OrderRefundInsert},
+            product::Product,
+        },
+    },
+    services::{
+        checkout, coupons,
+        errors::StorageError,
+        sessions,
+        shipping::{self, ShippingOption},
+    },
+    utils::{
+        address::Address,
+        csv,
+        email::EmailAddress,
+        mailer::EmailSender,
+        pagination::Pagination,
+        pennies::{errors::PenniesOverflow, Pennies},
+        redact::RedactedEmail,
     },
 };
 
-/// TODO: add documentation
+/// The total of an order split into the discount, the amount before tax, the
+/// tax on it, and the cost of shipping.
+#[derive(Clone, Copy)]
+struct TaxBreakdown {
+    /// The total of the products' prices before any discount.
+    prices: Pennies,
+    /// The discount taken off the prices.
+    discount: Pennies,
+    /// The amount before tax, after any discount.
+    subtotal: Pennies,
+    /// The tax on the subtotal.
+    tax: Pennies,
+    /// The cost of shipping, which is not taxed.
+    shipping: Pennies,
+    /// The amount charged, i.e. the subtotal plus tax and shipping.
+    total: Pennies,
+}
+
+impl TaxBreakdown {
+    /// The amount charged and how it is made up, as stored on an order.
+    fn amounts(&self) -> Result<AmountBreakdown, PenniesOverflow> {
+        AmountBreakdown::new(self.subtotal, self.discount, self.tax, self.shipping)
+    }
+}
+
+/// Compute the tax breakdown of an order whose product prices add up to
+/// `prices`, less `percent_off` percent, at the store's tax rate, and which
+/// costs `shipping` to ship.
+fn tax_breakdown(
+    prices: Pennies,
+    percent_off: u8,
+    shipping: Pennies,
+) -> Result<TaxBreakdown, PenniesOverflow> {
+    tax_breakdown_at(
+        prices,
+        percent_off,
+        shipping,
+        *TAX_RATE_BASIS_POINTS,
+        *PRICES_INCLUDE_TAX,
+    )
+}
+
+/// Compute a tax breakdown as `tax_breakdown` does, at a tax rate of `rate`
+/// basis points. If `prices_include_tax`, the tax is taken out of the
+/// discounted prices, otherwise it is added on top of them.
+fn tax_breakdown_at(
+    prices: Pennies,
+    percent_off: u8,
+    shipping: Pennies,
+    rate: u32,
+    prices_include_tax: bool,
+) -> Result<TaxBreakdown, PenniesOverflow> {
+    const WHOLE: u32 = 10_000;
+    let discount = prices.checked_percentage(percent_off)?;
+    let discounted = prices.saturating_sub(discount);
+    if prices_include_tax {
+        let subtotal = discounted.checked_ratio(WHOLE, WHOLE.saturating_add(rate))?;
+        Ok(TaxBreakdown {
+            prices,
+            discount,
+            subtotal,
+            tax: discounted.saturating_sub(subtotal),
+            shipping,
+            total: discounted.checked_add(shipping)?,
+        })
+    } else {
+        let tax = discounted.checked_ratio(rate, WHOLE)?;
+        Ok(TaxBreakdown {
+            prices,
+            discount,
+            subtotal: discounted,
+            tax,
+            shipping,
+            total: discounted.checked_add(tax)?.checked_add(shipping)?,
+        })
+    }
+}
+
+/// Add `count` units of `product` to the running total weight of an order in
+/// grams. The total becomes unknown (None) if the product's weight is
+/// unknown, or if it would overflow.
+fn add_weight(total: Option<i64>, product: &Product, count: u32) -> Option<i64> {
+    let line_weight = product.weight_grams()?.checked_mul(i64::from(count))?;
+    total?.checked_add(line_weight)
+}
+
+/// Set an order's status and save it as part of `transaction`, recording the
+/// transition in the order's timeline if the status changed. `actor_id` is
+/// the user whose action caused the change, if any.
+async fn transition(
+    order: &mut AppOrder,
+    status: AppOrderStatus,
+    actor_id: Option<Uuid>,
+    transaction: &mut db::Transaction,
+) -> Result<(), UpdateError> {
+    let changed = order.status() != status;
+    order.set_status(status);
+    order.update(&mut **transaction).await?;
+    if changed {
+        OrderEventInsert {
+            order_id: order.id(),
+            status,
+            actor_id,
+        }
+        .store(&mut **transaction)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Mark an order as confirmed (paid for), taking its items out of stock in
+/// the same transaction. Fails, changing nothing, if any product has too
+/// little stock left. Orders which have already been confirmed are left as
+/// they are, so confirming twice never takes items out of stock twice.
+/// `actor_id` is the user who confirmed it, or None if it was confirmed by a
+/// payment notification.
 pub async fn confirm_order(
     order_id: Uuid,
+    actor_id: Option<Uuid>,
     db_conn: &db::ConnectionPool,
 ) -> Result<(), errors::OrderConfirmationError> {
     let mut order = AppOrder::select_one(order_id, db_conn)
         .await?
         .ok_or(errors::OrderConfirmationError::OrderNonExistent(order_id))?;
-    order.set_status(AppOrderStatus::Confirmed);
-    order.update(db_conn).await?;
+    if order.status() != AppOrderStatus::Unconfirmed {
+        return Ok(());
+    }
+    let mut transaction = db::begin(db_conn).await?;
+    for item in OrderItem::select_all(order_id, db_conn).await? {
+        let product_id = item.product_id();
+        if !Product::take_stock(product_id, item.count(), &mut *transaction).await? {
+            return Err(errors::OrderConfirmationError::InsufficientStock(
+                product_id,
+            ));
+        }
+    }
+    // The order's version is checked, so if it was confirmed concurrently,
+    // this fails and its items are not taken out of stock again.
+    transition(
+        &mut order,
+        AppOrderStatus::Confirmed,
+        actor_id,
+        &mut transaction,
+    )
+    .await?;
+    db::commit(transaction).await?;
     Ok(())
 }
 
+/// Retrieve the timeline of an order, i.e. every status it has passed
+/// through, oldest first.
+pub async fn get_timeline(
+    order_id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<Vec<OrderEvent>, db::errors::DatabaseError> {
+    OrderEvent::select_for_order(order_id, db_conn).await
+}
+
 #[derive(Serialize)]
 /// TODO: add documentation
 pub struct AppOrderWithItems {
     /// TODO: add documentation
     pub order: AppOrder,
     /// TODO: add documentation
-    pub items: Vec<(Uuid, u32)>, // id, count
+    pub items: Vec<(Uuid, u32, String)>, // id, count, name when ordered
+    /// The total shipping weight of the order in grams, from the products'
+    /// current weights, or None if any product's weight is unknown.
+    pub total_weight_grams: Option<i64>,
 }
 
-/// TODO: add documentation
-pub async fn create_order(
+/// A new order priced by `price_order`.
+struct PricedOrder {
+    /// The breakdown of the order's total.
+    breakdown: TaxBreakdown,
+    /// Each product ordered, in the order given.
+    products: Vec<Product>,
+    /// Every shipping method offered for the order, with their costs.
+    shipping_options: Vec<ShippingOption>,
+    /// The user's address, which the order was priced to ship to.
+    shipping_address: Address,
+}
+
+/// Get the address of `user_id`, which orders placed by them are shipped to.
+/// Fails if the user does not exist.
+async fn shipping_address(
     user_id: Uuid,
-    product_counts: Vec<(Uuid, u32)>,
     db_conn: &db::ConnectionPool,
-) -> Result<AppOrder, errors::OrderCreationError> {
-    AppUser::select_one(user_id, db_conn)
+) -> Result<Address, errors::OrderCreationError> {
+    Ok(AppUser::select_one(user_id, db_conn)
         .await?
-        .ok_or(errors::OrderCreationError::UserNonExistent(user_id))?;
-    let current_time = OffsetDateTime::now_utc();
-    let mut total_cost: u64 = 0;
-    for &(product_id, count) in &product_counts {
+        .ok_or(errors::OrderCreationError::UserNonExistent(user_id))?
+        .address)
+}
+
+/// Price a new order of the given products to ship to `address`, less
+/// `percent_off` percent, shipped by `shipping_method`. Fails if any product
+/// does not exist or is not listed, or the shipping method is not offered to
+/// the address. Shared by `create_order` and `preview_order`, so that a
+/// preview always matches the order which would be placed.
+async fn price_order(
+    address: Address,
+    product_counts: &[(Uuid, u32)],
+    percent_off: u8,
+    shipping_method: ShippingMethod,
+    db_conn: &db::ConnectionPool,
+) -> Result<PricedOrder, errors::OrderCreationError> {
+    let mut total_cost = Pennies::ZERO;
+    let mut total_weight = Some(0);
+    let mut products = Vec::with_capacity(product_counts.len());
+    for &(product_id, count) in product_counts {
         let product = Product::select_one(product_id, db_conn)
             .await?
             .filter(Product::is_listed)
             .ok_or(errors::OrderCreationError::ProductNonExistent(product_id))?;
-        total_cost = total_cost
-            .checked_add(
-                u64::from(product.price())
-                    .checked_mul(u64::from(count))
-                    .ok_or(errors::OrderCreationError::CostTooLarge)?,
-            )
-            .ok_or(errors::OrderCreationError::CostTooLarge)?;
+        total_cost =
+            total_cost.checked_add(Pennies::from(product.try_price()?).checked_mul(count)?)?;
+        total_weight = add_weight(total_weight, &product, count);
+        products.push(product);
     }
+    let shipping = shipping::cost(shipping_method, total_weight, &address).ok_or(
+        errors::OrderCreationError::ShippingUnavailable(shipping_method.name()),
+    )?;
+    Ok(PricedOrder {
+        breakdown: tax_breakdown(total_cost, percent_off, shipping)?,
+        products,
+        shipping_options: shipping::options(total_weight, &address),
+        shipping_address: address,
+    })
+}
+
+/// The price of an order which has not been placed, as shown by
+/// `preview_order`. Amounts are in pennies.
+#[derive(Serialize)]
+pub struct OrderPreview {
+    /// The total of the products' prices before any discount.
+    prices: i64,
+    /// The discount given by the coupon, if one was applied.
+    discount: i64,
+    /// The amount before tax, after any discount.
+    subtotal: i64,
+    /// The tax on the subtotal.
+    tax: i64,
+    /// The cost of shipping the order by the chosen method.
+    shipping: i64,
+    /// The total which would be charged.
+    total: i64,
+    /// The shipping method the order was priced with.
+    shipping_method: ShippingMethod,
+    /// Every shipping method which could be chosen for the order.
+    shipping_methods: Vec<ShippingOption>,
+}
+
+/// Price an order as `create_order` would, optionally applying a coupon,
+/// without storing anything or redeeming the coupon. Unlike `create_order`,
+/// also fails if any product has too little stock for the quantity requested,
+/// since the order could not then be checked out. The preview lists every
+/// shipping method offered, so that one can be chosen at checkout.
+pub async fn preview_order(
+    user_id: Uuid,
+    product_counts: &[(Uuid, u32)],
+    coupon_code: Option<&str>,
+    shipping_method: ShippingMethod,
+    db_conn: &db::ConnectionPool,
+) -> Result<OrderPreview, errors::OrderPreviewError> {
+    let percent_off = match coupon_code {
+        Some(code) => coupons::redeemable_coupon(code, db_conn)
+            .await?
+            .percent_off(),
+        None => 0,
+    };
+    let PricedOrder {
+        breakdown,
+        products,
+        shipping_options,
+        ..
+    } = price_order(
+        shipping_address(user_id, db_conn).await?,
+        product_counts,
+        percent_off,
+        shipping_method,
+        db_conn,
+    )
+    .await?;
+    for (product, &(product_id, count)) in products.iter().zip(product_counts) {
+        if !product.has_stock(count) {
+            return Err(errors::OrderPreviewError::ItemUnavailable(product_id));
+        }
+    }
+    Ok(OrderPreview {
+        prices: breakdown.prices.as_i64(),
+        discount: breakdown.discount.as_i64(),
+        subtotal: breakdown.subtotal.as_i64(),
+        tax: breakdown.tax.as_i64(),
+        shipping: breakdown.shipping.as_i64(),
+        total: breakdown.total.as_i64(),
+        shipping_method,
+        shipping_methods: shipping_options,
+    })
+}
+
+/// Create an unconfirmed order for `user_id` of the given products, to be
+/// shipped by `shipping_method`, which must be offered to the user's address.
+/// If a coupon code is given, the coupon is redeemed and its discount applied,
+/// in the same transaction as the order is stored, so it is only counted as
+/// redeemed if the order is placed.
+pub async fn create_order(
+    user_id: Uuid,
+    product_counts: Vec<(Uuid, u32)>,
+    coupon_code: Option<&str>,
+    shipping_method: ShippingMethod,
+    db_conn: &db::ConnectionPool,
+) -> Result<AppOrder, errors::OrderCreationError> {
+    let address = shipping_address(user_id, db_conn).await?;
+    let mut transaction = db::begin(db_conn).await?;
+    let order = store_order(
+        user_id,
+        address,
+        &product_counts,
+        coupon_code,
+        shipping_method,
+        &mut transaction,
+        db_conn,
+    )
+    .await?;
+    db::commit(transaction).await?;
+    Ok(order)
+}
+
+/// Store an unconfirmed order for `user_id` as `create_order` does, shipped
+/// to `address`, within `transaction`. Nothing is committed, so the caller can
+/// store other rows in the same transaction.
+async fn store_order(
+    user_id: Uuid,
+    address: Address,
+    product_counts: &[(Uuid, u32)],
+    coupon_code: Option<&str>,
+    shipping_method: ShippingMethod,
+    transaction: &mut db::Transaction,
+    db_conn: &db::ConnectionPool,
+) -> Result<AppOrder, errors::OrderCreationError> {
+    let current_time = OffsetDateTime::now_utc();
+    let coupon = match coupon_code {
+        Some(code) => Some(coupons::redeem_coupon(code, transaction, db_conn).await?),
+        None => None,
+    };
+    let percent_off = coupon.as_ref().map_or(0, Coupon::percent_off);
+    let PricedOrder {
+        breakdown,
+        products,
+        shipping_address,
+        ..
+    } = price_order(
+        address,
+        product_counts,
+        percent_off,
+        shipping_method,
+        db_conn,
+    )
+    .await?;
     let order_insert = AppOrderInsert {
-        amount_charged: i64::try_from(total_cost)
-            .map_err(|_overflow| errors::OrderCreationError::CostTooLarge)?,
+        amounts: breakdown.amounts()?,
+        shipping_method,
         order_placed: PrimitiveDateTime::new(current_time.date(), current_time.time()),
         user_id,
+        coupon_code: coupon.map(|redeemed| redeemed.code().to_owned()),
+        percent_off,
+        shipping_address,
     };
-    let order = order_insert.store(db_conn).await?;
+    let order = order_insert.store(&mut **transaction).await?;
     let order_id = order.id();
-    for &(product_id, count) in &product_counts {
-        let order_item_insert = OrderItemInsert::new(product_id, order_id, count);
-        order_item_insert.store(db_conn).await?;
+    OrderEventInsert {
+        order_id,
+        status: AppOrderStatus::Unconfirmed,
+        actor_id: Some(user_id),
+    }
+    .store(&mut **transaction)
+    .await?;
+    for (&(product_id, count), product) in product_counts.iter().zip(products) {
+        let unit_price = product.try_price()?;
+        let order_item_insert =
+            OrderItemInsert::new(product_id, order_id, count, product.name, unit_price);
+        order_item_insert.store(&mut **transaction).await?;
     }
     Ok(order)
 }
 
-/// TODO: add documentation
+/// Change the quantities of items in an order which has not yet been
+/// confirmed. Each change sets the quantity of a product in the order, adding
+/// it if it is not already in the order, or removing it if the quantity is
+/// zero. Products are validated as in `create_order`, and the amount charged
+/// is recalculated from their current prices, keeping the order's shipping
+/// method, delivery address and any coupon discount.
+pub async fn update_order_items(
+    mut order: AppOrder,
+    changes: Vec<(Uuid, u32)>,
+    db_conn: &db::ConnectionPool,
+) -> Result<AppOrderWithItems, errors::OrderUpdateError> {
+    let order_id = order.id();
+    if order.status() != AppOrderStatus::Unconfirmed {
+        return Err(errors::OrderUpdateError::OrderNotUnconfirmed(order_id));
+    }
+    let mut items: Vec<(Uuid, u32)> = OrderItem::select_all(order_id, db_conn)
+        .await?
+        .into_iter()
+        .map(|item| (item.product_id(), item.count()))
+        .collect();
+    for (product_id, count) in changes {
+        items.retain(|&(existing_id, _)| existing_id != product_id);
+        if count != 0 {
+            items.push((product_id, count));
+        }
+    }
+    items.sort_unstable();
+    // Shipped to the address the order was placed with, wherever the user
+    // has moved since.
+    let destination = AppOrder::select_shipping_address(order_id, db_conn).await?;
+    // As the order is priced afresh, item names are also taken afresh.
+    let mut total_cost = Pennies::ZERO;
+    let mut total_weight = Some(0);
+    let mut named_items = Vec::with_capacity(items.len());
+    let mut unit_prices = Vec::with_capacity(items.len());
+    for (product_id, count) in items {
+        let product = Product::select_one(product_id, db_conn)
+            .await?
+            .filter(Product::is_listed)
+            .ok_or(errors::OrderUpdateError::ProductNonExistent(product_id))?;
+        let unit_price = product.try_price()?;
+        total_cost = total_cost.checked_add(Pennies::from(unit_price).checked_mul(count)?)?;
+        total_weight = add_weight(total_weight, &product, count);
+        named_items.push((product_id, count, product.name));
+        unit_prices.push(unit_price);
+    }
+    let shipping = shipping::cost(order.shipping_method, total_weight, &destination).ok_or(
+        errors::OrderUpdateError::ShippingUnavailable(order.shipping_method.name()),
+    )?;
+    let percent_off = AppOrder::select_percent_off(order_id, db_conn).await?;
+    let amounts = tax_breakdown(total_cost, percent_off, shipping)?.amounts()?;
+    let mut transaction = db::begin(db_conn).await?;
+    // Checked again while updating, in case the order was confirmed meanwhile.
+    if !AppOrder::set_amounts_if_unconfirmed(order_id, amounts, &mut *transaction).await? {
+        return Err(errors::OrderUpdateError::OrderNotUnconfirmed(order_id));
+    }
+    OrderItem::delete_all(order_id, &mut *transaction).await?;
+    for (&(product_id, count, ref product_name), &unit_price) in
+        named_items.iter().zip(&unit_prices)
+    {
+        OrderItemInsert::new(
+            product_id,
+            order_id,
+            count,
+            product_name.clone(),
+            unit_price,
+        )
+        .store(&mut *transaction)
+        .await?;
+    }
+    db::commit(transaction).await?;
+    order.set_amounts(amounts);
+    let total_weight_grams = OrderItem::total_weight_grams(order_id, db_conn).await?;
+    Ok(AppOrderWithItems {
+        order,
+        items: named_items,
+        total_weight_grams,
+    })
+}
+
+/// The result of reordering a previous order.
+#[derive(Serialize)]
+pub struct Reorder {
+    /// The newly created order.
+    pub order: AppOrder,
+    /// The IDs of products from the original order which were left out of the
+    /// new order, since they are no longer available in the quantity ordered.
+    pub skipped: Vec<Uuid>,
+}
+
+/// Place a new order for the items of a previous order placed by the given
+/// user, shipped by the same method. Items which are no longer listed, or no
+/// longer in stock in the quantity originally ordered, are skipped.
+pub async fn reorder(
+    user_id: Uuid,
+    order_id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<Reorder, errors::ReorderError> {
+    let original = get_order_with_items(order_id, db_conn)
+        .await?
+        .ok_or(errors::ReorderError::OrderNonExistent { user_id, order_id })?;
+    if original.order.user_id() != user_id {
+        return Err(errors::ReorderError::Unauthorized { user_id, order_id });
+    }
+    let mut product_counts = Vec::with_capacity(original.items.len());
+    let mut skipped = Vec::new();
+    for (product_id, count, _) in original.items {
+        let available = Product::select_one(product_id, db_conn)
+            .await?
+            .is_some_and(|product| product.is_listed() && product.has_stock(count));
+        if available {
+            product_counts.push((product_id, count));
+        } else {
+            skipped.push(product_id);
+        }
+    }
+    if product_counts.is_empty() {
+        return Err(errors::ReorderError::NothingAvailable(order_id));
+    }
+    let order = create_order(
+        user_id,
+        product_counts,
+        None,
+        original.order.shipping_method,
+        db_conn,
+    )
+    .await?;
+    Ok(Reorder { order, skipped })
+}
+
+/// Create an order on behalf of a guest, who does not have an account. A new
+/// guest user is created for the order, even if previous guest orders were
+/// placed with the same email. Returns the order and a token granting access
+/// to only that order.
+///
+/// If the email is registered to a full account, the order is still placed
+/// exactly as it otherwise would be, and the account's owner is emailed about
+/// it in the background, so that the response does not reveal which emails
+/// are registered.
+pub async fn create_guest_order(
+    user_data: AppUserInsert,
+    product_counts: Vec<(Uuid, u32)>,
+    shipping_method: ShippingMethod,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+    email_sender: Arc<dyn EmailSender>,
+) -> Result<(AppOrder, String), errors::GuestOrderCreationError> {
+    if user_data.forename.is_empty() || user_data.surname.is_empty() {
+        return Err(errors::GuestOrderCreationError::EmptyName);
+    }
+    let registered = AppUser::select_by_email(&user_data.email, db_conn)
+        .await?
+        .is_some();
+    let email = user_data.email.clone();
+    // A new guest is created for every order, since nothing proves that the
+    // client placing it owns the email. Guests can be merged into a full
+    // account once they do (see `registration::upgrade_guest`). The guest is
+    // stored in the same transaction as their order, so that no guest is left
+    // behind without one if the order can't be placed.
+    let mut transaction = db::begin(db_conn).await?;
+    let guest = AppUserInsert::new_guest(
+        user_data.email,
+        &user_data.forename,
+        &user_data.surname,
+        user_data.address,
+    )
+    .store(&mut *transaction)
+    .await?;
+    let order = store_order(
+        guest.id(),
+        guest.address,
+        &product_counts,
+        None,
+        shipping_method,
+        &mut transaction,
+        db_conn,
+    )
+    .await?;
+    db::commit(transaction).await?;
+    let token = sessions::create_guest_order_token(order.id(), session_store_conn)
+        .await
+        .map_err(StorageError::from)?;
+    if registered {
+        tokio::spawn(notify_registered_guest_order(email, email_sender));
+    }
+    Ok((order, token))
+}
+
+/// Tell the owner of an account that a guest order was placed with their
+/// account's email, since they should log in to place orders. Failures are
+/// logged rather than returned, since the order has already been placed.
+async fn notify_registered_guest_order(email: EmailAddress, email_sender: Arc<dyn EmailSender>) {
+    if let Err(err) = email_sender
+        .send(
+            &email,
+            "A guest order was placed with your email at SecureCart",
+            "An order was just placed at SecureCart as a guest, using the email address of your account. Log in to place future orders, so they appear with the rest of your orders. If you did not place this order, you can ignore this email.",
+        )
+        .await
+    {
+        tracing::error!(
+            "Error notifying {} of a guest order placed with their email: {err}",
+            RedactedEmail::from(&email)
+        );
+    }
+}
+
+/// Retrieve the order, with its items, which a guest order token grants
+/// access to. Returns None if the token is invalid or has expired.
+pub async fn get_guest_order(
+    token: &str,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+) -> Result<Option<AppOrderWithItems>, StorageError> {
+    match sessions::get_guest_order_id(token, session_store_conn).await? {
+        Some(order_id) => Ok(get_order_with_items(order_id, db_conn).await?),
+        None => Ok(None),
+    }
+}
+
+/// Search for orders matching a given set of search parameters, most recently
+/// placed first. Returns only the given page of results if one is set.
 pub async fn search_orders(
     params: AppOrderSearchParameters,
+    page: Option<Pagination>,
     db_conn: &db::ConnectionPool,
 ) -> Result<Vec<AppOrder>, db::errors::DatabaseError> {
-    AppOrder::search(params, db_conn).await
+    AppOrder::search(params, page, db_conn).await
+}
+
+/// Count the total quantity of items in each of the given orders, with a
+/// single query however many orders there are.
+pub async fn count_items(
+    orders: &[AppOrder],
+    db_conn: &db::ConnectionPool,
+) -> Result<HashMap<Uuid, i64>, db::errors::DatabaseError> {
+    let order_ids: Vec<Uuid> = orders.iter().map(AppOrder::id).collect();
+    OrderItem::count_by_order(&order_ids, db_conn).await
+}
+
+/// The columns of an order export, in order.
+const EXPORT_COLUMNS: [&str; 5] = ["id", "user_id", "order_placed", "amount_charged", "status"];
+
+/// The number of encoded rows which may be buffered ahead of the consumer of
+/// an order export.
+const EXPORT_BUFFER_ROWS: usize = 64;
+
+/// Export every order matching a set of search parameters as CSV, most
+/// recently placed first, beginning with a header row. Each received item is
+/// one encoded row, with amounts in pounds (e.g. "12.34") and times in ISO
+/// 8601. Orders are read from the database only as the rows are consumed, so
+/// they are never held in memory in full. The export ends early, after
+/// yielding the error, if the database fails or holds an order whose amount
+/// charged is negative.
+pub fn export_csv(
+    params: AppOrderSearchParameters,
+    db_conn: db::ReadConnectionPool,
+) -> mpsc::Receiver<Result<String, db::errors::DatabaseError>> {
+    let (sender, receiver) = mpsc::channel(EXPORT_BUFFER_ROWS);
+    tokio::spawn(async move {
+        if sender.send(Ok(csv::record(&EXPORT_COLUMNS))).await.is_err() {
+            return;
+        }
+        let mut search = AppOrder::prepare_search(&params);
+        let mut orders = search.stream(&db_conn);
+        while let Some(result) = orders.next().await {
+            let row = result.and_then(|order| {
+                let amount = Pennies::from_stored(order.amount_charged).ok_or_else(|| {
+                    db::errors::DatabaseError::from(sqlx::Error::Decode(
+                        format!("Amount charged for order {} is negative", order.id()).into(),
+                    ))
+                })?;
+                let placed = order
+                    .order_placed
+                    .assume_utc()
+                    .format(&Iso8601::DEFAULT)
+                    .expect("Formatting a valid date and time as ISO 8601 cannot fail.");
+                Ok(csv::record(&[
+                    &order.id().to_string(),
+                    &order.user_id().to_string(),
+                    &placed,
+                    &amount.to_string(),
+                    order.status().name(),
+                ]))
+            });
+            let failed = row.is_err();
+            if sender.send(row).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
 }
 
 /// TODO: add documentation
@@ -89,14 +731,21 @@ pub async fn list_orders(
     AppOrder::select_all(db_conn).await
 }
 
-/// TODO: add documentation
+/// Delete an order. Only unconfirmed orders can be deleted, by anyone, since
+/// an order which has been paid for is a financial record. Those must be
+/// refunded instead.
 pub async fn delete_order(
     order_id: Uuid,
     db_conn: &db::ConnectionPool,
 ) -> Result<(), errors::OrderDeletionError> {
-    match AppOrder::select_one(order_id, db_conn).await? {
-        Some(order) => Ok(order.delete(db_conn).await?),
-        None => Err(errors::OrderDeletionError::OrderNonExistent(order_id)),
+    let order = AppOrder::select_one(order_id, db_conn)
+        .await?
+        .ok_or(errors::OrderDeletionError::OrderNonExistent(order_id))?;
+    // Checked again when deleting, since the order may be confirmed meanwhile.
+    if order.status() == AppOrderStatus::Unconfirmed && order.delete_unconfirmed(db_conn).await? {
+        Ok(())
+    } else {
+        Err(errors::OrderDeletionError::OrderConfirmed(order_id))
     }
 }
 
@@ -117,35 +766,278 @@ pub async fn get_order_with_items(
     let Some(order) = maybe_order else {
         return Ok(None);
     };
-    let order_items = OrderItem::select_all(order_id, db_conn).await?;
-    Ok(Some(AppOrderWithItems {
+    Ok(Some(with_items(order, db_conn).await?))
+}
+
+/// Retrieve the items of an order which has already been loaded.
+pub async fn with_items(
+    order: AppOrder,
+    db_conn: &db::ConnectionPool,
+) -> Result<AppOrderWithItems, db::errors::DatabaseError> {
+    let order_items = OrderItem::select_all(order.id(), db_conn).await?;
+    let total_weight_grams = OrderItem::total_weight_grams(order.id(), db_conn).await?;
+    Ok(AppOrderWithItems {
         order,
         items: order_items
             .into_iter()
-            .map(|item| (item.product_id(), item.count()))
+            .map(|item| {
+                (
+                    item.product_id(),
+                    item.count(),
+                    item.product_name().to_owned(),
+                )
+            })
             .collect(),
+        total_weight_grams,
+    })
+}
+
+/// A single line of an `Invoice`, covering every unit of one product.
+#[derive(Serialize)]
+pub struct InvoiceLine {
+    /// The ID of the product.
+    product_id: Uuid,
+    /// The name of the product when it was ordered.
+    name: String,
+    /// The price in pennies of a single unit of the product.
+    unit_price: u32,
+    /// How many of the product were ordered.
+    count: u32,
+    /// The price in pennies of every unit of the product ordered.
+    line_total: i64,
+}
+
+/// A structured invoice (receipt) for an order.
+#[derive(Serialize)]
+pub struct Invoice {
+    /// The ID of the order being invoiced.
+    order_id: Uuid,
+    /// The time and date the order was placed.
+    #[serde(with = "iso8601")]
+    order_placed: OffsetDateTime,
+    /// The name of the customer who placed the order.
+    customer_name: String,
+    /// The address the order is being shipped to.
+    shipping_address: Address,
+    /// The line items within the order.
+    items: Vec<InvoiceLine>,
+    /// The total amount charged for the order, and how it is made up.
+    #[serde(flatten)]
+    amounts: AmountBreakdown,
+}
+
+/// Produce an invoice for the given order, returning None if the order does
+/// not exist. Line prices and the shipping address are those recorded when
+/// the order was placed, as are the subtotal, discount, tax, shipping and
+/// total, so the invoice is unaffected by later price or address changes.
+pub async fn get_invoice(
+    order_id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<Option<Invoice>, errors::InvoiceError> {
+    let Some(order) = AppOrder::select_one(order_id, db_conn).await? else {
+        return Ok(None);
+    };
+    let user_id = order.user_id();
+    let customer = AppUser::select_one(user_id, db_conn)
+        .await?
+        .ok_or(errors::InvoiceError::UserNonExistent(user_id))?;
+    let order_items = OrderItem::select_all(order_id, db_conn).await?;
+    let mut items = Vec::with_capacity(order_items.len());
+    for item in order_items {
+        let unit_price = item.unit_price();
+        let count = item.count();
+        items.push(InvoiceLine {
+            product_id: item.product_id(),
+            name: item.product_name().to_owned(),
+            unit_price,
+            count,
+            line_total: Pennies::from(unit_price).checked_mul(count)?.as_i64(),
+        });
+    }
+    Ok(Some(Invoice {
+        order_id,
+        order_placed: order.order_placed.assume_utc(),
+        customer_name: format!("{} {}", customer.forename, customer.surname),
+        shipping_address: AppOrder::select_shipping_address(order_id, db_conn).await?,
+        items,
+        amounts: order.amounts(),
     }))
 }
 
-/// TODO: add documentation
+/// Retrieve an order which is able to have items fulfilled, i.e. one which has
+/// been confirmed but not yet completely fulfilled.
+async fn get_fulfillable_order(
+    order_id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<AppOrder, errors::OrderFulfilmentError> {
+    let order = AppOrder::select_one(order_id, db_conn)
+        .await?
+        .ok_or(errors::OrderFulfilmentError::OrderNonExistent(order_id))?;
+    match order.status() {
+        AppOrderStatus::Confirmed | AppOrderStatus::PartiallyFulfilled => Ok(order),
+        AppOrderStatus::Unconfirmed | AppOrderStatus::Fulfilled | AppOrderStatus::Refunded => {
+            Err(errors::OrderFulfilmentError::OrderNotConfirmed(order_id))
+        }
+    }
+}
+
+/// Mark an entire order, and every item within it, as fulfilled, on behalf
+/// of the administrator `actor_id`.
 pub async fn fulfil_order(
     order_id: Uuid,
+    actor_id: Uuid,
     db_conn: &db::ConnectionPool,
 ) -> Result<(), errors::OrderFulfilmentError> {
-    let mut order = AppOrder::select_one(order_id, db_conn)
+    let mut order = get_fulfillable_order(order_id, db_conn).await?;
+    let mut transaction = db::begin(db_conn).await?;
+    OrderItem::fulfil_all(order_id, &mut *transaction).await?;
+    transition(
+        &mut order,
+        AppOrderStatus::Fulfilled,
+        Some(actor_id),
+        &mut transaction,
+    )
+    .await?;
+    db::commit(transaction).await?;
+    Ok(())
+}
+
+/// Fulfil some quantity of specific items within an order, given as pairs of
+/// product ID and the number of that product being fulfilled. The order is
+/// marked as `Fulfilled` once every item has been completely fulfilled, and
+/// `PartiallyFulfilled` otherwise. Nothing is written unless every requested
+/// quantity can be fulfilled. Returns the order's new status. `actor_id` is
+/// the administrator fulfilling the items.
+pub async fn fulfil_items(
+    order_id: Uuid,
+    product_counts: Vec<(Uuid, u32)>,
+    actor_id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<AppOrderStatus, errors::OrderFulfilmentError> {
+    let mut order = get_fulfillable_order(order_id, db_conn).await?;
+    // The transaction is rolled back if it is dropped early, so either every
+    // quantity is fulfilled or none are.
+    let mut transaction = db::begin(db_conn).await?;
+    let items = OrderItem::select_all(order_id, &mut *transaction).await?;
+    for (product_id, count) in product_counts {
+        if !items.iter().any(|item| item.product_id() == product_id) {
+            return Err(errors::OrderFulfilmentError::ItemNonExistent {
+                order_id,
+                product_id,
+            });
+        }
+        if !OrderItem::fulfil(order_id, product_id, count, &mut *transaction).await? {
+            return Err(errors::OrderFulfilmentError::OverFulfilment {
+                order_id,
+                product_id,
+            });
+        }
+    }
+    let status = if OrderItem::select_all(order_id, &mut *transaction)
         .await?
-        .ok_or(errors::OrderFulfilmentError::OrderNonExistent(order_id))?;
-    if order.status() != AppOrderStatus::Confirmed {
-        return Err(errors::OrderFulfilmentError::OrderNotConfirmed(order_id));
+        .iter()
+        .all(OrderItem::is_fulfilled)
+    {
+        AppOrderStatus::Fulfilled
+    } else {
+        AppOrderStatus::PartiallyFulfilled
+    };
+    transition(&mut order, status, Some(actor_id), &mut transaction).await?;
+    db::commit(transaction).await?;
+    Ok(status)
+}
+
+/// Refund the payment for every refund of an order which was recorded but
+/// never settled, e.g. because Stripe could not be reached, and settle them.
+/// Each is requested under the same idempotency key as before, so none can be
+/// paid out twice.
+async fn settle_pending_refunds(
+    order_id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<(), errors::OrderRefundError> {
+    for pending in order_refund::select_pending(order_id, db_conn).await? {
+        let stripe_refund =
+            checkout::refund_payment(order_id, pending.id, pending.amount, db_conn).await?;
+        order_refund::settle(pending.id, stripe_refund, db_conn).await?;
     }
-    order.set_status(AppOrderStatus::Fulfilled);
-    order.update(db_conn).await?;
     Ok(())
 }
 
+/// Refund `amount` pennies of an order on behalf of an administrator, or
+/// everything not yet refunded if no amount is given. The payment is refunded
+/// through Stripe if it is enabled, and the refund is recorded for auditing.
+/// Any earlier refunds of the order left pending are settled first. Returns
+/// the updated order.
+pub async fn refund_order(
+    order_id: Uuid,
+    amount: Option<u32>,
+    administrator_id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<AppOrder, errors::OrderRefundError> {
+    settle_pending_refunds(order_id, db_conn).await?;
+    let order = AppOrder::select_one(order_id, db_conn)
+        .await?
+        .ok_or(errors::OrderRefundError::OrderNonExistent(order_id))?;
+    if !order.status().is_refundable() {
+        return Err(errors::OrderRefundError::NotRefundable(order_id));
+    }
+    let remaining = order.amount_charged.saturating_sub(order.refunded_amount);
+    let refund = amount.map_or(remaining, i64::from);
+    if refund <= 0 || refund > remaining {
+        return Err(errors::OrderRefundError::InvalidAmount {
+            order_id,
+            remaining,
+        });
+    }
+    // The refund is recorded as pending before Stripe is asked to make it, so
+    // concurrent refunds cannot exceed the amount charged, and a refund
+    // Stripe made is never lost if the request fails part way.
+    let mut transaction = db::begin(db_conn).await?;
+    let refunded = AppOrder::record_refund(order_id, refund, &mut *transaction)
+        .await?
+        .ok_or(errors::OrderRefundError::NotRefundable(order_id))?;
+    let refund_id = OrderRefundInsert {
+        order_id,
+        administrator_id,
+        amount: refund,
+    }
+    .store(&mut *transaction)
+    .await?;
+    if refunded.status() != order.status() {
+        OrderEventInsert {
+            order_id,
+            status: refunded.status(),
+            actor_id: Some(administrator_id),
+        }
+        .store(&mut *transaction)
+        .await?;
+    }
+    // Once the whole order is refunded, whatever was never sent is back in
+    // stock. Partial refunds are of an amount, not of items, so leave it.
+    if refunded.status() == AppOrderStatus::Refunded {
+        for item in OrderItem::select_all(order_id, db_conn).await? {
+            let unsent = item.count().saturating_sub(item.fulfilled_count());
+            if unsent > 0 {
+                Product::return_stock(item.product_id(), unsent, &mut *transaction).await?;
+            }
+        }
+    }
+    db::commit(transaction).await?;
+    // If this fails, the refund stays pending, and is retried before the
+    // order is next refunded.
+    let stripe_refund = checkout::refund_payment(order_id, refund_id, refund, db_conn).await?;
+    order_refund::settle(refund_id, stripe_refund, db_conn).await?;
+    Ok(refunded)
+}
+
 /// Errors which can be returned by the orders service
 pub mod errors {
-    use crate::db::errors::DatabaseError;
+    use crate::db::errors::{DatabaseError, UpdateError};
+    use crate::services::{
+        checkout::errors::RefundPaymentError, coupons::errors::CouponValidationError,
+        errors::StorageError,
+    };
+    use crate::utils::pennies::errors::PenniesOverflow;
     use thiserror::Error;
     use uuid::Uuid;
 
@@ -158,7 +1050,23 @@ pub mod errors {
         #[error("Order does not exist")]
         /// TODO: add documentation
         OrderNonExistent(Uuid),
+        #[error("Order was modified concurrently")]
+        /// The order was updated by another request while being confirmed.
+        ConcurrencyConflict,
+        #[error("Product is out of stock")]
+        /// A product in the order has too little stock left to confirm it.
+        InsufficientStock(Uuid),
     }
+
+    impl From<UpdateError> for OrderConfirmationError {
+        fn from(err: UpdateError) -> Self {
+            match err {
+                UpdateError::Database(db_err) => Self::DatabaseError(db_err),
+                UpdateError::ConcurrencyConflict => Self::ConcurrencyConflict,
+            }
+        }
+    }
+
     #[derive(Error, Debug)]
     /// TODO: add documentation
     pub enum OrderCreationError {
@@ -174,6 +1082,33 @@ pub mod errors {
         #[error("Total cost exceeds 64-bit max")]
         /// TODO: add documentation
         CostTooLarge,
+        #[error("Shipping method is not offered to the delivery address")]
+        /// The chosen shipping method, named, is not offered to the user's
+        /// address.
+        ShippingUnavailable(&'static str),
+        #[error(transparent)]
+        /// The coupon given cannot be redeemed.
+        Coupon(#[from] CouponValidationError),
+    }
+
+    impl From<PenniesOverflow> for OrderCreationError {
+        fn from(_overflow: PenniesOverflow) -> Self {
+            Self::CostTooLarge
+        }
+    }
+
+    #[derive(Error, Debug)]
+    /// Errors returned when previewing the price of an order.
+    pub enum OrderPreviewError {
+        #[error(transparent)]
+        /// The order could not be priced, as it could not be created.
+        Pricing(#[from] OrderCreationError),
+        #[error(transparent)]
+        /// The coupon cannot be applied.
+        Coupon(#[from] CouponValidationError),
+        #[error("Product is not in stock in the quantity requested")]
+        /// A product has too little stock for the quantity requested.
+        ItemUnavailable(Uuid),
     }
 
     #[derive(Error, Debug)]
@@ -188,6 +1123,131 @@ pub mod errors {
         #[error("Order is not yet confirmed")]
         /// TODO: add documentation
         OrderNotConfirmed(Uuid),
+        #[error("Product is not part of the order")]
+        /// A product being fulfilled is not one of the order's items.
+        ItemNonExistent {
+            /// The ID of the order being fulfilled.
+            order_id: Uuid,
+            /// The ID of the product which is not in the order.
+            product_id: Uuid,
+        },
+        #[error("More items would be fulfilled than were ordered")]
+        /// Fulfilling the requested quantity would exceed the quantity ordered.
+        OverFulfilment {
+            /// The ID of the order being fulfilled.
+            order_id: Uuid,
+            /// The ID of the product which would be over-fulfilled.
+            product_id: Uuid,
+        },
+        #[error("Order was modified concurrently")]
+        /// The order was updated by another request while being fulfilled.
+        ConcurrencyConflict,
+    }
+
+    impl From<UpdateError> for OrderFulfilmentError {
+        fn from(err: UpdateError) -> Self {
+            match err {
+                UpdateError::Database(db_err) => Self::DatabaseError(db_err),
+                UpdateError::ConcurrencyConflict => Self::ConcurrencyConflict,
+            }
+        }
+    }
+
+    #[derive(Error, Debug)]
+    /// Errors returned when changing the items of an existing order.
+    pub enum OrderUpdateError {
+        #[error(transparent)]
+        /// An error from the underlying database.
+        DatabaseError(#[from] DatabaseError),
+        #[error("Order is no longer unconfirmed")]
+        /// The order has been confirmed, so can no longer be changed.
+        OrderNotUnconfirmed(Uuid),
+        #[error("Product does not exist")]
+        /// A product in the order does not exist or is not listed.
+        ProductNonExistent(Uuid),
+        #[error("Total cost exceeds 64-bit max")]
+        /// The order's new total cost would overflow.
+        CostTooLarge,
+        #[error("Shipping method is not offered to the delivery address")]
+        /// The order's shipping method, named, is no longer offered to the
+        /// order's delivery address.
+        ShippingUnavailable(&'static str),
+    }
+
+    impl From<PenniesOverflow> for OrderUpdateError {
+        fn from(_overflow: PenniesOverflow) -> Self {
+            Self::CostTooLarge
+        }
+    }
+
+    #[derive(Error, Debug)]
+    /// Errors returned when reordering a previous order.
+    pub enum ReorderError {
+        #[error(transparent)]
+        /// An error from the underlying database.
+        DatabaseError(#[from] DatabaseError),
+        #[error(transparent)]
+        /// The new order could not be created.
+        OrderCreationError(#[from] OrderCreationError),
+        #[error("Order does not exist")]
+        /// The order being reordered does not exist.
+        OrderNonExistent {
+            /// The ID of the user reordering.
+            user_id: Uuid,
+            /// The ID of the order being reordered.
+            order_id: Uuid,
+        },
+        #[error("Order belongs to another user")]
+        /// The order being reordered was placed by another user.
+        Unauthorized {
+            /// The ID of the user reordering.
+            user_id: Uuid,
+            /// The ID of the order being reordered.
+            order_id: Uuid,
+        },
+        #[error("No items in the order are still available")]
+        /// None of the order's items can be ordered again.
+        NothingAvailable(Uuid),
+    }
+
+    #[derive(Error, Debug)]
+    /// Errors returned when creating an order on behalf of a guest.
+    pub enum GuestOrderCreationError {
+        #[error(transparent)]
+        /// An error from the underlying storage.
+        StorageError(#[from] StorageError),
+        #[error(transparent)]
+        /// The order itself could not be created.
+        OrderCreationError(#[from] OrderCreationError),
+        #[error("The guest's name is empty")]
+        /// The guest did not provide their full name.
+        EmptyName,
+    }
+
+    impl From<DatabaseError> for GuestOrderCreationError {
+        fn from(err: DatabaseError) -> Self {
+            Self::StorageError(err.into())
+        }
+    }
+
+    #[derive(Error, Debug)]
+    /// Errors returned when producing an invoice for an order.
+    pub enum InvoiceError {
+        #[error(transparent)]
+        /// An error from the underlying database.
+        DatabaseError(#[from] DatabaseError),
+        #[error("User who placed the order does not exist")]
+        /// The user who placed the order no longer exists.
+        UserNonExistent(Uuid),
+        #[error("Line total exceeds 64-bit max")]
+        /// The total for a single line of the invoice overflowed.
+        LineTotalTooLarge,
+    }
+
+    impl From<PenniesOverflow> for InvoiceError {
+        fn from(_overflow: PenniesOverflow) -> Self {
+            Self::LineTotalTooLarge
+        }
     }
 
     #[derive(Error, Debug)]
@@ -199,5 +1259,434 @@ pub mod errors {
         #[error("Order does not exist")]
         /// TODO: add documentation
         OrderNonExistent(Uuid),
+        #[error("Order has been confirmed, so cannot be deleted")]
+        /// The order is no longer unconfirmed, so is a financial record which
+        /// must be kept.
+        OrderConfirmed(Uuid),
+    }
+
+    #[derive(Error, Debug)]
+    /// Errors returned when refunding an order.
+    pub enum OrderRefundError {
+        #[error(transparent)]
+        /// An error returned up from the database.
+        DatabaseError(#[from] DatabaseError),
+        #[error("Attempted to refund non-existent order {0}")]
+        /// The order does not exist.
+        OrderNonExistent(Uuid),
+        #[error("Attempted to refund order {0}, which has not been paid for or is already fully refunded")]
+        /// The order has not been paid for, or is already fully refunded.
+        NotRefundable(Uuid),
+        #[error("Invalid refund amount for order {order_id}, at most {remaining} pennies may be refunded")]
+        /// The amount is zero, or more than has not yet been refunded.
+        InvalidAmount {
+            /// The ID of the order.
+            order_id: Uuid,
+            /// The amount in pennies which has not yet been refunded.
+            remaining: i64,
+        },
+        #[error(transparent)]
+        /// An error refunding the payment itself.
+        Payment(#[from] RefundPaymentError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        confirm_order, create_order, errors::OrderFulfilmentError, export_csv, fulfil_items,
+        tax_breakdown_at, update_order_items,
+    };
+    #[cfg(not(feature = "stripe"))]
+    use super::{errors::OrderRefundError, refund_order};
+    use crate::{
+        db::{
+            errors::DatabaseError,
+            models::{
+                apporder::{AppOrder, AppOrderSearchParameters, AppOrderStatus, ShippingMethod},
+                order_item::OrderItem,
+                product::ProductInsert,
+            },
+            ConnectionPool,
+        },
+        testing::store_user,
+        utils::{address::Address, pennies::Pennies},
+    };
+    use time::{Date, Month, PrimitiveDateTime, Time};
+    use uuid::Uuid;
+
+    /// Without tax, the total is the discounted prices plus shipping.
+    #[test]
+    fn no_tax() {
+        let breakdown =
+            tax_breakdown_at(Pennies::from(1000u32), 0, Pennies::from(300u32), 0, false)
+                .expect("Breakdown should not overflow");
+        assert_eq!(breakdown.discount, Pennies::ZERO);
+        assert_eq!(breakdown.subtotal, Pennies::from(1000u32));
+        assert_eq!(breakdown.tax, Pennies::ZERO);
+        assert_eq!(breakdown.total, Pennies::from(1300u32));
+    }
+
+    /// Tax is added on top of the discounted prices, but not on shipping.
+    #[test]
+    fn tax_added_after_discount() {
+        let breakdown = tax_breakdown_at(
+            Pennies::from(1000u32),
+            10,
+            Pennies::from(300u32),
+            2000,
+            false,
+        )
+        .expect("Breakdown should not overflow");
+        assert_eq!(breakdown.prices, Pennies::from(1000u32));
+        assert_eq!(breakdown.discount, Pennies::from(100u32));
+        assert_eq!(breakdown.subtotal, Pennies::from(900u32));
+        assert_eq!(breakdown.tax, Pennies::from(180u32));
+        assert_eq!(breakdown.shipping, Pennies::from(300u32));
+        assert_eq!(breakdown.total, Pennies::from(1380u32));
+    }
+
+    /// When prices include tax, the tax is taken out of the discounted
+    /// prices, so the total is unchanged by the tax rate.
+    #[test]
+    fn tax_included_in_prices() {
+        let breakdown = tax_breakdown_at(Pennies::from(1200u32), 0, Pennies::ZERO, 2000, true)
+            .expect("Breakdown should not overflow");
+        assert_eq!(breakdown.subtotal, Pennies::from(1000u32));
+        assert_eq!(breakdown.tax, Pennies::from(200u32));
+        assert_eq!(breakdown.total, Pennies::from(1200u32));
+    }
+
+    /// Included tax is rounded to the nearest penny, and the subtotal and tax
+    /// always add up to the discounted prices.
+    #[test]
+    fn included_tax_rounds() {
+        let breakdown = tax_breakdown_at(Pennies::from(1000u32), 50, Pennies::ZERO, 2000, true)
+            .expect("Breakdown should not overflow");
+        assert_eq!(breakdown.subtotal, Pennies::from(417u32));
+        assert_eq!(breakdown.tax, Pennies::from(83u32));
+        assert_eq!(breakdown.total, Pennies::from(500u32));
+    }
+
+    /// A total too large to represent fails rather than wrapping.
+    #[test]
+    fn overflow_fails() {
+        let max = Pennies::from_stored(i64::MAX).expect("Maximum should be non-negative");
+        assert!(tax_breakdown_at(max, 0, Pennies::from(1u32), 0, false).is_err());
+    }
+
+    /// Store a customer's confirmed order for two of a product, and an
+    /// administrator to fulfil or refund it. Returns the order, the product's
+    /// ID and the administrator's ID.
+    async fn confirmed_order(db_conn: &ConnectionPool) -> (AppOrder, Uuid, Uuid) {
+        let customer = store_user("customer@example.com", db_conn).await;
+        let administrator = store_user("administrator@example.com", db_conn).await;
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        let order = create_order(
+            customer.id(),
+            vec![(product_id, 2)],
+            None,
+            ShippingMethod::Standard,
+            db_conn,
+        )
+        .await
+        .expect("Order should be created");
+        confirm_order(order.id(), None, db_conn)
+            .await
+            .expect("Order should be confirmed");
+        (order, product_id, administrator.id())
+    }
+
+    /// The number of an order's items which have been fulfilled.
+    async fn fulfilled_count(order_id: Uuid, db_conn: &ConnectionPool) -> u32 {
+        OrderItem::select_all(order_id, db_conn)
+            .await
+            .expect("Items should be selected")
+            .iter()
+            .map(OrderItem::fulfilled_count)
+            .sum()
+    }
+
+    /// Fulfilling some of an order's items marks it partially fulfilled.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn partial_fulfilment(db_conn: ConnectionPool) {
+        let (order, product_id, administrator_id) = confirmed_order(&db_conn).await;
+        let status = fulfil_items(
+            order.id(),
+            vec![(product_id, 1)],
+            administrator_id,
+            &db_conn,
+        )
+        .await
+        .expect("Item should be fulfilled");
+        assert!(status == AppOrderStatus::PartiallyFulfilled);
+        assert_eq!(fulfilled_count(order.id(), &db_conn).await, 1);
+    }
+
+    /// Fulfilling the rest of an order's items marks it fulfilled.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn fulfilment_completes(db_conn: ConnectionPool) {
+        let (order, product_id, administrator_id) = confirmed_order(&db_conn).await;
+        for _ in 0..2u8 {
+            fulfil_items(
+                order.id(),
+                vec![(product_id, 1)],
+                administrator_id,
+                &db_conn,
+            )
+            .await
+            .expect("Item should be fulfilled");
+        }
+        let fulfilled = AppOrder::select_one(order.id(), &db_conn)
+            .await
+            .expect("Order should be selected")
+            .expect("Order should exist");
+        assert!(fulfilled.status() == AppOrderStatus::Fulfilled);
+        assert_eq!(fulfilled_count(order.id(), &db_conn).await, 2);
+    }
+
+    /// More of an item than was ordered cannot be fulfilled, and nothing is
+    /// recorded.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn over_fulfilment_is_rejected(db_conn: ConnectionPool) {
+        let (order, product_id, administrator_id) = confirmed_order(&db_conn).await;
+        assert!(matches!(
+            fulfil_items(
+                order.id(),
+                vec![(product_id, 3)],
+                administrator_id,
+                &db_conn
+            )
+            .await,
+            Err(OrderFulfilmentError::OverFulfilment { .. })
+        ));
+        let unchanged = AppOrder::select_one(order.id(), &db_conn)
+            .await
+            .expect("Order should be selected")
+            .expect("Order should exist");
+        assert!(unchanged.status() == AppOrderStatus::Confirmed);
+        assert_eq!(fulfilled_count(order.id(), &db_conn).await, 0);
+    }
+
+    /// Changing an order's items after its customer has moved still prices
+    /// shipping to the address the order was placed with.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn changed_order_ships_to_original_address(db_conn: ConnectionPool) {
+        let mut customer = store_user("customer@example.com", &db_conn).await;
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(&db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        let order = create_order(
+            customer.id(),
+            vec![(product_id, 1)],
+            None,
+            ShippingMethod::Express,
+            &db_conn,
+        )
+        .await
+        .expect("Order should be created");
+        let shipping = order.shipping;
+        // Express shipping is only offered to the original address.
+        customer.address = Address::new("1 Main Street", None, "Springfield", "12345", "US")
+            .expect("Address should be valid");
+        customer
+            .update(&db_conn)
+            .await
+            .expect("User should be updated");
+        let changed = update_order_items(order, vec![(product_id, 2)], &db_conn)
+            .await
+            .expect("Order should be changed");
+        assert_eq!(changed.order.shipping, shipping);
+        assert_eq!(changed.items.first().map(|item| item.1), Some(2));
+    }
+
+    /// Refunding without an amount refunds everything, marking the order
+    /// refunded, after which it cannot be refunded again.
+    #[cfg(not(feature = "stripe"))]
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn full_refund(db_conn: ConnectionPool) {
+        let (order, _, administrator_id) = confirmed_order(&db_conn).await;
+        let refunded = refund_order(order.id(), None, administrator_id, &db_conn)
+            .await
+            .expect("Order should be refunded");
+        assert!(refunded.status() == AppOrderStatus::Refunded);
+        assert_eq!(refunded.refunded_amount, order.amount_charged);
+        assert!(matches!(
+            refund_order(order.id(), Some(1), administrator_id, &db_conn).await,
+            Err(OrderRefundError::NotRefundable(_))
+        ));
+    }
+
+    /// A partial refund records the amount but leaves the status alone, so
+    /// the order can still be fulfilled, and refunding the rest later marks
+    /// it refunded.
+    #[cfg(not(feature = "stripe"))]
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn partial_refund_keeps_fulfilment(db_conn: ConnectionPool) {
+        let (order, product_id, administrator_id) = confirmed_order(&db_conn).await;
+        let partially_refunded = refund_order(order.id(), Some(100), administrator_id, &db_conn)
+            .await
+            .expect("Order should be partially refunded");
+        assert!(partially_refunded.status() == AppOrderStatus::Confirmed);
+        assert_eq!(partially_refunded.refunded_amount, 100);
+
+        let status = fulfil_items(
+            order.id(),
+            vec![(product_id, 1)],
+            administrator_id,
+            &db_conn,
+        )
+        .await
+        .expect("Partially refunded order should be fulfillable");
+        assert!(status == AppOrderStatus::PartiallyFulfilled);
+
+        let refunded = refund_order(order.id(), None, administrator_id, &db_conn)
+            .await
+            .expect("Rest of the order should be refunded");
+        assert!(refunded.status() == AppOrderStatus::Refunded);
+        assert_eq!(refunded.refunded_amount, order.amount_charged);
+    }
+
+    /// More than has not yet been refunded cannot be refunded, and nothing is
+    /// recorded.
+    #[cfg(not(feature = "stripe"))]
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn over_refund_is_rejected(db_conn: ConnectionPool) {
+        let (order, _, administrator_id) = confirmed_order(&db_conn).await;
+        let too_much = u32::try_from(order.amount_charged)
+            .expect("Amount should fit")
+            .saturating_add(1);
+        assert!(matches!(
+            refund_order(order.id(), Some(too_much), administrator_id, &db_conn).await,
+            Err(OrderRefundError::InvalidAmount { remaining, .. }) if remaining == order.amount_charged
+        ));
+        let unchanged = AppOrder::select_one(order.id(), &db_conn)
+            .await
+            .expect("Order should be selected")
+            .expect("Order should exist");
+        assert_eq!(unchanged.refunded_amount, 0);
+        assert!(unchanged.status() == AppOrderStatus::Confirmed);
+    }
+
+    /// The given day and hour of 2024.
+    fn in_2024(month: Month, day: u8, hour: u8) -> PrimitiveDateTime {
+        PrimitiveDateTime::new(
+            Date::from_calendar_date(2024, month, day).expect("Date should be valid"),
+            Time::from_hms(hour, 0, 0).expect("Time should be valid"),
+        )
+    }
+
+    /// Store an unconfirmed order for a new user, placed at a given time and
+    /// charged `amount_charged` pennies. Returns the order's and user's IDs.
+    async fn store_placed_order(
+        placed: PrimitiveDateTime,
+        amount_charged: i64,
+        db_conn: &ConnectionPool,
+    ) -> (Uuid, Uuid) {
+        let email = format!("{}@example.com", Uuid::new_v4());
+        let user_id = store_user(&email, db_conn).await.id();
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        let order_id = create_order(
+            user_id,
+            vec![(product_id, 1)],
+            None,
+            ShippingMethod::Standard,
+            db_conn,
+        )
+        .await
+        .expect("Order should be created")
+        .id();
+        sqlx::query(
+            "UPDATE apporder SET order_placed = $1, amount_charged = $2, subtotal = $2,
+            discount = 0, tax = 0, shipping = 0 WHERE id = $3",
+        )
+        .bind(placed)
+        .bind(amount_charged)
+        .bind(order_id)
+        .execute(db_conn)
+        .await
+        .expect("Order should be updated");
+        (order_id, user_id)
+    }
+
+    /// Every item an export yields, until it ends.
+    async fn export_items(
+        params: AppOrderSearchParameters,
+        db_conn: ConnectionPool,
+    ) -> Vec<Result<String, DatabaseError>> {
+        let mut receiver = export_csv(params, db_conn.into());
+        let mut items = Vec::new();
+        while let Some(item) = receiver.recv().await {
+            items.push(item);
+        }
+        items
+    }
+
+    /// Only orders placed within the date range are exported, after the
+    /// header, with amounts in pounds.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn export_streams_orders_in_range(db_conn: ConnectionPool) {
+        store_placed_order(in_2024(Month::January, 1, 0), 1205, &db_conn).await;
+        let (order_id, user_id) =
+            store_placed_order(in_2024(Month::February, 1, 12), 1205, &db_conn).await;
+        store_placed_order(in_2024(Month::March, 1, 0), 1205, &db_conn).await;
+        let rows: Vec<String> = export_items(
+            AppOrderSearchParameters {
+                placed_from: Some(in_2024(Month::January, 15, 0).assume_utc()),
+                placed_to: Some(in_2024(Month::February, 15, 0).assume_utc()),
+                ..AppOrderSearchParameters::default()
+            },
+            db_conn,
+        )
+        .await
+        .into_iter()
+        .map(|row| row.expect("Row should be exported"))
+        .collect();
+        assert_eq!(
+            rows,
+            [
+                String::from("id,user_id,order_placed,amount_charged,status\r\n"),
+                format!(
+                    "{order_id},{user_id},2024-02-01T12:00:00.000000000Z,12.05,{}\r\n",
+                    AppOrderStatus::Unconfirmed.name()
+                ),
+            ]
+        );
+    }
+
+    /// An order with a negative amount charged ends the export with an
+    /// error, rather than panicking and silently truncating it.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn export_ends_with_error_at_negative_amount(db_conn: ConnectionPool) {
+        // Only possible if the database was modified by something else.
+        sqlx::query("ALTER TABLE apporder DROP CONSTRAINT apporder_subtotal_check")
+            .execute(&db_conn)
+            .await
+            .expect("Constraint should be dropped");
+        store_placed_order(in_2024(Month::January, 1, 0), 1205, &db_conn).await;
+        store_placed_order(in_2024(Month::February, 1, 0), -1, &db_conn).await;
+        let items = export_items(AppOrderSearchParameters::default(), db_conn).await;
+        assert_eq!(items.len(), 2, "Export should end after the error");
+        assert!(items.first().is_some_and(Result::is_ok));
+        assert!(items.last().is_some_and(Result::is_err));
     }
 }
@@ -1,30 +1,46 @@
 //! Functions for dealing with/storing/querying products.
-#[expect(
-    clippy::useless_attribute,
-    reason = "Lint is enabled only in clippy::restrictions"
-)]
-#[expect(
-    clippy::std_instead_of_alloc,
-    reason = "Does not work outside of no_std"
-)]
-use std::sync::Arc;
+use alloc::sync::Arc;
+use std::collections::HashMap;
 
+use core::time::Duration;
+
+use futures_util::StreamExt as _;
 use object_store::ObjectStore;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, PrimitiveDateTime};
+use tokio::{sync::mpsc, time::sleep};
 use uuid::Uuid;
 
 use crate::{
-    constants::s3::{S3_BUCKET, S3_EXTERNAL_URI},
+    constants::{
+        products::{MAX_IMAGES_PER_PRODUCT, MAX_PRODUCTS_PER_BATCH, PRODUCT_VIEW_FLUSH_INTERVAL},
+        s3::{S3_BUCKET, S3_EXTERNAL_URI},
+    },
     db::{
         self,
         models::{
-            product::{Product, ProductInsert},
-            product_image::{ProductImage, ProductImageInsert},
+            product::{Product, ProductCursor, ProductInsert, ProductSortOrder, RatedProduct},
+            product_image::{ProductImage, ProductImageInsert, ProductImageSize},
+            product_stats::{self, ProductViewCount},
+            stock_adjustment::StockAdjustmentInsert,
+            stock_notification::{self, StockNotification, StockNotificationInsert},
         },
     },
+    utils::{
+        csv,
+        mailer::EmailSender,
+        pagination::{self, Pagination},
+        redact::RedactedEmail,
+        text,
+    },
 };
 
-use super::media;
+pub use super::media::ContentDisposition;
+use super::{
+    errors::StorageError,
+    media,
+    sessions::{self, errors::SessionStorageError, GenericAuthenticatedSession},
+};
 
 // This is a little weird and unpleasant (implementing an enum manually),
 // but it is necessary since enums are non-const and not allowed as const
@@ -47,48 +63,120 @@ pub mod ProductVisibilityScope {
 /// contain the full URI (including S3 host and bucket).
 fn with_image_uris(product: Product) -> Product {
     let mut new_product = product;
-    new_product.images.iter_mut().for_each(|path| {
-        *path = format!(
-            "{}/{}/{}",
-            &*S3_EXTERNAL_URI,
-            &*S3_BUCKET,
-            path.trim_start_matches('/')
-        );
-    });
+    new_product
+        .images
+        .iter_mut()
+        .chain(new_product.primary_image.as_mut())
+        .for_each(|path| {
+            *path = format!(
+                "{}/{}/{}",
+                &*S3_EXTERNAL_URI,
+                &*S3_BUCKET,
+                path.trim_start_matches('/')
+            );
+        });
     new_product
 }
 
-/// Retrieve a specific product. Generically parameterised over the visibility
-/// scope to retrieve from. `VISIBILITY_SCOPE` must *ONLY* be set to a value from
-/// `ProductVisibilityScope`, or the function's behaviour is undefined.
+/// Normalise an image path or URI as provided by a client to the form stored in
+/// the database. This removes the S3 URI and bucket if present, and ensures that
+/// the path starts with exactly one leading separator (as if relative to the
+/// bucket root). The bucket is only removed if the path is not already under
+/// one of the configured media prefixes, so that a prefix which begins with
+/// the bucket's name is left intact.
+fn normalise_image_path(path: &str) -> String {
+    let without_uri = path
+        .strip_prefix(&*S3_EXTERNAL_URI)
+        .unwrap_or(path)
+        .trim_start_matches('/');
+    let relative = if media::is_media_path(without_uri) {
+        without_uri
+    } else {
+        without_uri
+            .strip_prefix(&*S3_BUCKET)
+            .unwrap_or(without_uri)
+            .trim_start_matches('/')
+    };
+    format!("/{relative}")
+}
+
+/// The result of looking up a single product within a visibility scope.
+pub enum ProductLookup {
+    /// The product exists and is visible in the scope.
+    Found(Box<Product>),
+    /// No product with the ID exists, including products which have been
+    /// deleted outright.
+    NonExistent,
+    /// The product has been archived, in any scope.
+    Archived,
+    /// The product exists, but is unlisted and the scope excludes unlisted
+    /// products.
+    Unlisted,
+}
+
+/// Retrieve a specific product, distinguishing a product which does not exist
+/// from one which is not visible in the scope. Generically parameterised over
+/// the visibility scope to retrieve from. `VISIBILITY_SCOPE` must *ONLY* be set
+/// to a value from `ProductVisibilityScope`, or the function's behaviour is
+/// undefined.
 pub async fn retrieve_product<const VISIBILITY_SCOPE: ProductVisibilityScopeT>(
     id: Uuid,
-    db_conn: &db::ConnectionPool,
-) -> Result<Option<Product>, db::errors::DatabaseError> {
-    Ok(Product::select_one(id, db_conn)
+    db_conn: &db::ReadConnectionPool,
+) -> Result<ProductLookup, db::errors::DatabaseError> {
+    Ok(match Product::select_one(id, db_conn).await? {
+        None => ProductLookup::NonExistent,
+        Some(prod) if prod.is_archived() => ProductLookup::Archived,
+        Some(prod)
+            if VISIBILITY_SCOPE == ProductVisibilityScope::LISTED_ONLY && !prod.is_listed() =>
+        {
+            ProductLookup::Unlisted
+        }
+        Some(prod) => ProductLookup::Found(Box::new(with_image_uris(prod))),
+    })
+}
+
+/// Retrieve a batch of products by ID, in the order requested. IDs which do
+/// not match a product in the visibility scope are left out. Generically
+/// parameterised over the visibility scope to retrieve from. `VISIBILITY_SCOPE`
+/// must *ONLY* be set to a value from `ProductVisibilityScope`, or the
+/// function's behaviour is undefined.
+pub async fn retrieve_product_batch<const VISIBILITY_SCOPE: ProductVisibilityScopeT>(
+    ids: &[Uuid],
+    db_conn: &db::ReadConnectionPool,
+) -> Result<Vec<Product>, errors::ProductBatchError> {
+    if ids.len() > MAX_PRODUCTS_PER_BATCH {
+        return Err(errors::ProductBatchError::TooManyIds(ids.len()));
+    }
+    let mut found: HashMap<Uuid, Product> = Product::select_many(ids, db_conn)
         .await?
+        .into_iter()
         .filter(|prod| {
-            VISIBILITY_SCOPE == ProductVisibilityScope::INCLUDE_UNLISTED || prod.is_listed()
+            !prod.is_archived()
+                && (VISIBILITY_SCOPE == ProductVisibilityScope::INCLUDE_UNLISTED
+                    || prod.is_listed())
         })
-        .map(with_image_uris))
+        .map(|prod| (prod.id(), with_image_uris(prod)))
+        .collect();
+    Ok(ids.iter().filter_map(|id| found.remove(id)).collect())
 }
 
 /// List all products in the database. Generically parameterised over the visibility
 /// scope to retrieve from. `VISIBILITY_SCOPE` must *ONLY* be set to a value from
 /// `ProductVisibilityScope`, or the function's behaviour is undefined.
 pub async fn retrieve_products<const VISIBILITY_SCOPE: ProductVisibilityScopeT>(
-    db_conn: &db::ConnectionPool,
+    db_conn: &db::ReadConnectionPool,
 ) -> Result<Vec<Product>, db::errors::DatabaseError> {
     Ok(Product::search(
         db::models::product::ProductSearchParameters {
             listed: (VISIBILITY_SCOPE == ProductVisibilityScope::LISTED_ONLY).then_some(true),
             ..Default::default()
         },
+        None,
         db_conn,
     )
     .await?
     .into_iter()
-    .map(with_image_uris)
+    .map(|rated| with_image_uris(rated.product))
     .collect())
 }
 
@@ -104,27 +192,41 @@ pub struct ProductSearchParameters {
     price_min: Option<u32>,
     /// The maximum price bound. Will match only products which cost less than this.
     price_max: Option<u32>,
+    /// Only return products after this position (see `pagination::cursor`).
+    #[serde(default, deserialize_with = "pagination::cursor")]
+    after: Option<ProductCursor>,
+    /// The order to return products in, by name unless set.
+    #[serde(default)]
+    sort_by: ProductSortOrder,
 }
 
-/// Search products stored in the database. Generically parameterised over the visibility
+/// Search products stored in the database, returning only the given page of
+/// results. Generically parameterised over the visibility
 /// scope to retrieve from. `VISIBILITY_SCOPE` must *ONLY* be set to a value from
 /// `ProductVisibilityScope`, or the function's behaviour is undefined.
 pub async fn search_products<const VISIBILITY_SCOPE: ProductVisibilityScopeT>(
-    db_conn: &db::ConnectionPool,
+    db_conn: &db::ReadConnectionPool,
     params: &ProductSearchParameters,
-) -> Result<Vec<Product>, db::errors::DatabaseError> {
+    page: Pagination,
+) -> Result<Vec<RatedProduct>, db::errors::DatabaseError> {
     Ok(Product::search(
         db::models::product::ProductSearchParameters {
             name: params.name.clone(),
             price_min: params.price_min,
             price_max: params.price_max,
             listed: (VISIBILITY_SCOPE == ProductVisibilityScope::LISTED_ONLY).then_some(true),
+            after: params.after.clone(),
+            sort_by: params.sort_by,
         },
+        Some(page),
         db_conn,
     )
     .await?
     .into_iter()
-    .map(with_image_uris)
+    .map(|mut rated| {
+        rated.product = with_image_uris(rated.product);
+        rated
+    })
     .collect())
 }
 
@@ -134,6 +236,7 @@ pub async fn search_products<const VISIBILITY_SCOPE: ProductVisibilityScopeT>(
 #[derive(Deserialize)]
 pub struct ProductUpdate {
     /// The product's new name.
+    #[serde(default, deserialize_with = "text::optional_product_name")]
     name: Option<String>,
     /// The product's new price.
     price: Option<u32>,
@@ -141,6 +244,14 @@ pub struct ProductUpdate {
     listed: Option<bool>,
     /// The product's new description.
     description: Option<String>,
+    /// The product's new shipping weight in grams.
+    weight_grams: Option<u32>,
+    /// The product's new packaged length in millimetres.
+    length_mm: Option<u32>,
+    /// The product's new packaged width in millimetres.
+    width_mm: Option<u32>,
+    /// The product's new packaged height in millimetres.
+    height_mm: Option<u32>,
 }
 
 /// Update an an existing stored product.
@@ -152,6 +263,9 @@ pub async fn update_product(
     let mut product = Product::select_one(id, db_conn)
         .await?
         .ok_or(errors::ProductUpdateError::NonExistent(id))?;
+    if product.is_archived() {
+        return Err(errors::ProductUpdateError::Archived(id));
+    }
     if let Some(name) = product_info.name {
         product.set_name(&name);
     }
@@ -168,23 +282,68 @@ pub async fn update_product(
     if let Some(description) = product_info.description {
         product.set_description(&description);
     }
+    if let Some(weight_grams) = product_info.weight_grams {
+        product.set_weight_grams(weight_grams);
+    }
+    if let Some(length_mm) = product_info.length_mm {
+        product.set_length_mm(length_mm);
+    }
+    if let Some(width_mm) = product_info.width_mm {
+        product.set_width_mm(width_mm);
+    }
+    if let Some(height_mm) = product_info.height_mm {
+        product.set_height_mm(height_mm);
+    }
     Ok(product.update(db_conn).await?)
 }
 
+/// List or unlist an existing stored product, leaving everything else
+/// unchanged.
+pub async fn set_listed(
+    id: Uuid,
+    listed: bool,
+    db_conn: &db::ConnectionPool,
+) -> Result<(), errors::ProductUpdateError> {
+    update_product(
+        id,
+        ProductUpdate {
+            name: None,
+            price: None,
+            listed: Some(listed),
+            description: None,
+            weight_grams: None,
+            length_mm: None,
+            width_mm: None,
+            height_mm: None,
+        },
+        db_conn,
+    )
+    .await
+}
+
 /// Add an image to a product, returning the path (URI) at which the image can be
-/// found.
+/// found. The image will be served with the given `ContentDisposition`.
+/// Downsized variants are generated and stored alongside the original. Fails
+/// if the product already has `MAX_IMAGES_PER_PRODUCT` images.
 pub async fn add_image(
     product_id: Uuid,
     image: Vec<u8>,
+    disposition: ContentDisposition,
     db_conn: &db::ConnectionPool,
     media_store: Arc<dyn ObjectStore>,
 ) -> Result<String, errors::AddImageError> {
     let _: Product = Product::select_one(product_id, db_conn)
         .await?
         .ok_or(errors::AddImageError::NonExistent(product_id))?;
-    let image_path = media::store_image(media_store, image).await?;
+    if ProductImage::count(product_id, db_conn).await? >= MAX_IMAGES_PER_PRODUCT {
+        return Err(errors::AddImageError::TooManyImages(product_id));
+    }
+    let image_path =
+        media::store_image(Arc::clone(&media_store), image.clone(), disposition).await?;
+    let sizes = media::store_image_sizes(media_store, image, disposition).await?;
     let image_insert = ProductImageInsert::new(product_id, &image_path);
-    let _: ProductImage = image_insert.store(db_conn).await?;
+    let product_image = image_insert.store(db_conn).await?;
+    ProductImageSize::store_all(&product_image, &sizes, db_conn).await?;
     Ok(format!(
         "{}/{}/{}",
         &*S3_EXTERNAL_URI,
@@ -193,21 +352,70 @@ pub async fn add_image(
     ))
 }
 
-/// List the paths (URIs) of all images associated with the given product.
+/// The parameters for listing a product's images.
+#[derive(Deserialize, Default)]
+pub struct ListImagesParameters {
+    /// The maximum number of images to return.
+    limit: Option<u32>,
+    /// Return only the primary (first) image. Overrides `limit`.
+    #[serde(default)]
+    primary_only: bool,
+}
+
+/// A downsized variant of a product image, for use in a responsive `srcset`.
+#[derive(Serialize)]
+pub struct ImageSize {
+    /// The width of the variant in pixels.
+    width: i32,
+    /// The URI at which the variant can be found.
+    uri: String,
+}
+
+/// A product image along with its available downsized variants.
+#[derive(Serialize)]
+pub struct ResponsiveImage {
+    /// The URI at which the original image can be found.
+    original: String,
+    /// The downsized variants of the image, narrowest first.
+    sizes: Vec<ImageSize>,
+}
+
+/// List the images associated with the given product, in display order (so
+/// the first is the primary image), along with the URIs of their downsized
+/// variants.
 pub async fn list_images(
     product_id: Uuid,
-    db_conn: &db::ConnectionPool,
-) -> Result<Vec<String>, db::errors::DatabaseError> {
-    Ok(ProductImage::select_all(product_id, db_conn)
-        .await?
+    params: &ListImagesParameters,
+    db_conn: &db::ReadConnectionPool,
+) -> Result<Vec<ResponsiveImage>, db::errors::DatabaseError> {
+    let limit = if params.primary_only {
+        Some(1)
+    } else {
+        params.limit
+    };
+    let images = ProductImage::select_all(product_id, limit, db_conn).await?;
+    let mut sizes: HashMap<String, Vec<ImageSize>> = HashMap::new();
+    for size in ProductImageSize::select_all(product_id, db_conn).await? {
+        sizes.entry(size.path).or_default().push(ImageSize {
+            width: size.width,
+            uri: format!(
+                "{}/{}/{}",
+                &*S3_EXTERNAL_URI,
+                &*S3_BUCKET,
+                size.size_path.trim_start_matches('/')
+            ),
+        });
+    }
+    Ok(images
         .into_iter()
-        .map(|img| {
-            format!(
+        .map(|img| ResponsiveImage {
+            original: format!(
                 "{}/{}/{}",
                 &*S3_EXTERNAL_URI,
                 &*S3_BUCKET,
                 img.path.trim_start_matches('/')
-            )
+            ),
+            sizes: sizes.remove(&img.path).unwrap_or_default(),
         })
         .collect())
 }
@@ -218,15 +426,7 @@ pub async fn delete_image(
     path: &str,
     db_conn: &db::ConnectionPool,
 ) -> Result<(), errors::ImageDeleteError> {
-    // This removes the S3 URI and bucket if present, and ensures that the path
-    // starts with exactly one leading separator (as if relative to the bucket root).
-    let mut normalised_path = String::from("/");
-    normalised_path.push_str(
-        path.trim_start_matches(&*S3_EXTERNAL_URI)
-            .trim_start_matches('/')
-            .trim_start_matches(&*S3_BUCKET)
-            .trim_start_matches('/'),
-    );
+    let normalised_path = normalise_image_path(path);
     let product = ProductImage::select(product_id, &normalised_path, db_conn)
         .await?
         .ok_or(errors::ImageDeleteError::NonExistentImage(
@@ -237,6 +437,137 @@ pub async fn delete_image(
     Ok(())
 }
 
+/// Set the display order of a product's images. `paths` must contain exactly
+/// the product's current images, each once, in the desired order. The first
+/// becomes the product's primary image.
+pub async fn reorder_images(
+    product_id: Uuid,
+    paths: &[String],
+    db_conn: &db::ConnectionPool,
+) -> Result<(), errors::ImageReorderError> {
+    let _: Product = Product::select_one(product_id, db_conn)
+        .await?
+        .ok_or(errors::ImageReorderError::NonExistent(product_id))?;
+    let new_order: Vec<String> = paths
+        .iter()
+        .map(|path| normalise_image_path(path))
+        .collect();
+    let mut requested: Vec<&String> = new_order.iter().collect();
+    requested.sort_unstable();
+    let mut current: Vec<String> = ProductImage::select_all(product_id, None, db_conn)
+        .await?
+        .into_iter()
+        .map(|img| img.path)
+        .collect();
+    current.sort_unstable();
+    if !requested.into_iter().eq(current.iter()) {
+        return Err(errors::ImageReorderError::ImageMismatch(product_id));
+    }
+    Ok(ProductImage::reorder(product_id, &new_order, db_conn).await?)
+}
+
+/// Add `delta` (which may be negative) to a product's stock level on behalf of
+/// an administrator, recording the adjustment for auditing. Returns the new
+/// stock level. If the product was out of stock, every customer who asked to
+/// be notified when it is back in stock is emailed in the background, and
+/// their requests are cleared.
+pub async fn adjust_stock(
+    product_id: Uuid,
+    delta: i64,
+    administrator_id: Uuid,
+    db_conn: &db::ConnectionPool,
+    email_sender: Arc<dyn EmailSender>,
+) -> Result<i64, errors::StockAdjustmentError> {
+    let mut transaction = db::begin(db_conn).await?;
+    let Some(resulting_stock) = Product::adjust_stock(product_id, delta, &mut *transaction).await?
+    else {
+        return Err(match Product::select_one(product_id, db_conn).await? {
+            Some(_) => errors::StockAdjustmentError::InsufficientStock(product_id),
+            None => errors::StockAdjustmentError::NonExistent(product_id),
+        });
+    };
+    StockAdjustmentInsert {
+        product_id,
+        administrator_id,
+        delta,
+        resulting_stock,
+    }
+    .store(&mut *transaction)
+    .await?;
+    // Stock can never be negative, so the product was out of stock before
+    // this adjustment exactly when the adjustment makes up all of it.
+    let notifications = if resulting_stock > 0 && resulting_stock == delta {
+        stock_notification::take_for_product(product_id, &mut *transaction).await?
+    } else {
+        Vec::new()
+    };
+    db::commit(transaction).await?;
+    if !notifications.is_empty() {
+        tracing::info!(
+            "Product {product_id} is back in stock, notifying {} customers",
+            notifications.len()
+        );
+        tokio::spawn(send_stock_notifications(notifications, email_sender));
+    }
+    Ok(resulting_stock)
+}
+
+/// Email each customer in `notifications` that the product they asked about
+/// is back in stock. Failures are logged rather than retried, since the
+/// requests have already been cleared.
+async fn send_stock_notifications(
+    notifications: Vec<StockNotification>,
+    email_sender: Arc<dyn EmailSender>,
+) {
+    for StockNotification {
+        email,
+        product_name,
+    } in notifications
+    {
+        if let Err(err) = email_sender
+            .send(
+                &email,
+                &format!("{product_name} is back in stock at SecureCart"),
+                &format!(
+                    "{product_name}, which you asked to be told about, is back in stock at SecureCart."
+                ),
+            )
+            .await
+        {
+            tracing::error!(
+                "Error notifying {} that a product is back in stock: {err}",
+                RedactedEmail::from(&email)
+            );
+        }
+    }
+}
+
+/// Ask for a customer to be emailed when an out of stock product is back in
+/// stock. The product must be listed.
+pub async fn request_stock_notification(
+    user_id: Uuid,
+    product_id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<(), errors::StockNotificationError> {
+    let product = Product::select_one(product_id, db_conn)
+        .await?
+        .filter(Product::is_listed)
+        .ok_or(errors::StockNotificationError::NonExistent(product_id))?;
+    if product.has_stock(1) {
+        return Err(errors::StockNotificationError::InStock(product_id));
+    }
+    let requested = StockNotificationInsert {
+        user_id,
+        product_id,
+    }
+    .store(db_conn)
+    .await?;
+    if !requested {
+        return Err(errors::StockNotificationError::AlreadyRequested(product_id));
+    }
+    Ok(())
+}
+
 /// Create a new product in the database.
 pub async fn create_product(
     data: ProductInsert,
@@ -245,6 +576,107 @@ pub async fn create_product(
     data.store(db_conn).await
 }
 
+/// The columns of a product catalog export, in order.
+const EXPORT_COLUMNS: [&str; 6] = ["id", "name", "price", "listed", "stock", "description"];
+
+/// The number of encoded rows which may be buffered ahead of the consumer of
+/// a product catalog export.
+const EXPORT_BUFFER_ROWS: usize = 64;
+
+/// Export every product as CSV, beginning with a header row. Each received
+/// item is one encoded row. Products are read from the database only as the
+/// rows are consumed, so the catalog is never held in memory in full. The
+/// export ends early, after yielding the error, if the database fails.
+pub fn export_csv(
+    db_conn: db::ReadConnectionPool,
+) -> mpsc::Receiver<Result<String, db::errors::DatabaseError>> {
+    let (sender, receiver) = mpsc::channel(EXPORT_BUFFER_ROWS);
+    tokio::spawn(async move {
+        if sender.send(Ok(csv::record(&EXPORT_COLUMNS))).await.is_err() {
+            return;
+        }
+        let mut products = Product::stream_all(&db_conn);
+        while let Some(result) = products.next().await {
+            let row = result.and_then(|product| {
+                Ok(csv::record(&[
+                    &product.id().to_string(),
+                    &product.name,
+                    &product.try_price()?.to_string(),
+                    &product.is_listed().to_string(),
+                    &product
+                        .stock()
+                        .map_or_else(String::new, |stock| stock.to_string()),
+                    &product.description,
+                ]))
+            });
+            let failed = row.is_err();
+            if sender.send(row).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Count a view of a product for analytics. Views by administrators are not
+/// counted, and nor are repeated views from the same session within
+/// `PRODUCT_VIEW_DEBOUNCE` seconds.
+pub async fn record_view(
+    product_id: Uuid,
+    session: &GenericAuthenticatedSession,
+    session_store_conn: &mut sessions::store::Connection,
+) -> Result<(), SessionStorageError> {
+    if let GenericAuthenticatedSession::Customer(_) = *session {
+        sessions::record_product_view(product_id, session, session_store_conn).await?;
+    }
+    Ok(())
+}
+
+/// Add the view counts held in the session store to the database. Counts are
+/// staged in batches, and each batch is added in a transaction which records
+/// its ID, so a batch left staged by a flush which stopped part way through
+/// is added by the next flush exactly once. If the database fails, the
+/// batches which were not added stay staged to be added later.
+pub async fn flush_views(
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+) -> Result<(), StorageError> {
+    for (batch_id, counts) in sessions::stage_product_views(session_store_conn).await? {
+        let mut transaction = db::begin(db_conn).await?;
+        if product_stats::record_view_flush(batch_id, &mut *transaction).await? {
+            for (product_id, views) in counts {
+                product_stats::add_views(product_id, views, &mut *transaction).await?;
+            }
+            db::commit(transaction).await?;
+        }
+        sessions::discard_product_views(batch_id, session_store_conn).await?;
+    }
+    Ok(())
+}
+
+/// Flush view counts to the database every `PRODUCT_VIEW_FLUSH_INTERVAL`
+/// seconds, forever. Intended to be spawned as a background task at startup.
+/// Views are also flushed once more when the API shuts down gracefully.
+pub async fn run_view_flusher(
+    db_conn: db::ConnectionPool,
+    mut session_store_conn: sessions::store::Connection,
+) -> ! {
+    loop {
+        sleep(Duration::from_secs(PRODUCT_VIEW_FLUSH_INTERVAL)).await;
+        if let Err(err) = flush_views(&db_conn, &mut session_store_conn).await {
+            tracing::error!("Error flushing product view counts, will retry: {err}");
+        }
+    }
+}
+
+/// List a page of the most viewed products, most viewed first.
+pub async fn top_viewed_products(
+    page: Pagination,
+    db_conn: &db::ReadConnectionPool,
+) -> Result<Vec<ProductViewCount>, db::errors::DatabaseError> {
+    ProductViewCount::select_top(page, db_conn).await
+}
+
 /// Delete a given product from the database.
 pub async fn delete_product(
     id: Uuid,
@@ -256,10 +688,27 @@ pub async fn delete_product(
     Ok(product.delete(db_conn).await?)
 }
 
+/// Archive a given product, unlisting it for good while keeping it for the
+/// orders which refer to it.
+pub async fn archive_product(
+    id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<(), errors::ProductArchiveError> {
+    let current_time = OffsetDateTime::now_utc();
+    let now = PrimitiveDateTime::new(current_time.date(), current_time.time());
+    if Product::archive(id, now, db_conn).await? {
+        return Ok(());
+    }
+    Err(match Product::select_one(id, db_conn).await? {
+        Some(_) => errors::ProductArchiveError::AlreadyArchived(id),
+        None => errors::ProductArchiveError::NonExistent(id),
+    })
+}
+
 /// Errors which can be returned by functions in this service.
 pub mod errors {
-    use crate::db::errors::DatabaseError;
-    use crate::services::media::errors::StoreImageError;
+    use crate::db::errors::{DatabaseError, UpdateError};
+    pub use crate::services::media::errors::StoreImageError;
     use thiserror::Error;
     use uuid::Uuid;
 
@@ -272,6 +721,21 @@ pub mod errors {
         /// Raised when the product being updated does not exist.
         #[error("The product being updated does not exist.")]
         NonExistent(Uuid),
+        /// Raised when the product was updated by another request meanwhile.
+        #[error("The product was modified concurrently.")]
+        ConcurrencyConflict,
+        /// Raised when the product being updated has been archived.
+        #[error("The product being updated has been archived.")]
+        Archived(Uuid),
+    }
+
+    impl From<UpdateError> for ProductUpdateError {
+        fn from(err: UpdateError) -> Self {
+            match err {
+                UpdateError::Database(db_err) => Self::DatabaseError(db_err),
+                UpdateError::ConcurrencyConflict => Self::ConcurrencyConflict,
+            }
+        }
     }
     /// Errors returned when deleting products.
     #[derive(Error, Debug)]
@@ -283,6 +747,19 @@ pub mod errors {
         #[error("The product being deleted does not exist.")]
         NonExistent(Uuid),
     }
+    /// Errors returned when archiving products.
+    #[derive(Error, Debug)]
+    pub enum ProductArchiveError {
+        /// Error passed up from the database storage layer.
+        #[error(transparent)]
+        DatabaseError(#[from] DatabaseError),
+        /// Raised when the product being archived does not exist.
+        #[error("The product being archived does not exist.")]
+        NonExistent(Uuid),
+        /// Raised when the product has already been archived.
+        #[error("The product being archived has already been archived.")]
+        AlreadyArchived(Uuid),
+    }
     /// Errors returned when adding images to products.
     #[derive(Error, Debug)]
     pub enum AddImageError {
@@ -295,6 +772,19 @@ pub mod errors {
         /// Raised when the product in question does not exist.
         #[error("The product being added to does not exist.")]
         NonExistent(Uuid),
+        /// Raised when the product already has `MAX_IMAGES_PER_PRODUCT` images.
+        #[error("The product being added to has too many images.")]
+        TooManyImages(Uuid),
+    }
+    /// Errors returned when retrieving a batch of products.
+    #[derive(Error, Debug)]
+    pub enum ProductBatchError {
+        /// Error passed up from the database storage layer.
+        #[error(transparent)]
+        DatabaseError(#[from] DatabaseError),
+        /// Raised when more than `MAX_PRODUCTS_PER_BATCH` IDs are requested.
+        #[error("Too many products were requested in one batch.")]
+        TooManyIds(usize),
     }
     /// Errors returned when deleting images from products.
     #[derive(Error, Debug)]
@@ -306,4 +796,145 @@ pub mod errors {
         #[error("The image being deleted does not exist")]
         NonExistentImage(String, Uuid),
     }
+    /// Errors returned when adjusting a product's stock level.
+    #[derive(Error, Debug)]
+    pub enum StockAdjustmentError {
+        /// Error passed up from the database storage layer.
+        #[error(transparent)]
+        DatabaseError(#[from] DatabaseError),
+        /// Raised when the product being adjusted does not exist.
+        #[error("The product being adjusted does not exist.")]
+        NonExistent(Uuid),
+        /// Raised when the adjustment would take the stock level below zero.
+        #[error("The adjustment would make the stock level negative.")]
+        InsufficientStock(Uuid),
+    }
+    /// Errors returned when asking to be notified that a product is back in
+    /// stock.
+    #[derive(Error, Debug)]
+    pub enum StockNotificationError {
+        /// Error passed up from the database storage layer.
+        #[error(transparent)]
+        DatabaseError(#[from] DatabaseError),
+        /// Raised when the product does not exist or is not listed.
+        #[error("The product does not exist.")]
+        NonExistent(Uuid),
+        /// Raised when the product is not out of stock.
+        #[error("The product is in stock.")]
+        InStock(Uuid),
+        /// Raised when the customer has already asked to be notified about
+        /// the product.
+        #[error("A notification has already been requested for the product.")]
+        AlreadyRequested(Uuid),
+    }
+    /// Errors returned when reordering a product's images.
+    #[derive(Error, Debug)]
+    pub enum ImageReorderError {
+        /// Error passed up from the database storage layer.
+        #[error(transparent)]
+        DatabaseError(#[from] DatabaseError),
+        /// Raised when the product being reordered does not exist.
+        #[error("The product being reordered does not exist.")]
+        NonExistent(Uuid),
+        /// Raised when the provided order does not list exactly the product's
+        /// current images, each once.
+        #[error("The provided image order does not match the product's images.")]
+        ImageMismatch(Uuid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use uuid::Uuid;
+
+    use super::{adjust_stock, errors::StockAdjustmentError};
+    use crate::{
+        db::{
+            models::product::{Product, ProductInsert},
+            ConnectionPool,
+        },
+        testing::{store_user, RecordingEmailSender},
+    };
+
+    /// Adjust a product's stock on behalf of an administrator.
+    async fn adjust(
+        product_id: Uuid,
+        delta: i64,
+        administrator_id: Uuid,
+        db_conn: &ConnectionPool,
+    ) -> Result<i64, StockAdjustmentError> {
+        adjust_stock(
+            product_id,
+            delta,
+            administrator_id,
+            db_conn,
+            Arc::new(RecordingEmailSender::default()),
+        )
+        .await
+    }
+
+    /// The recorded adjustments to a product's stock, oldest first, as each
+    /// change and the stock level it resulted in.
+    async fn recorded_adjustments(product_id: Uuid, db_conn: &ConnectionPool) -> Vec<(i64, i64)> {
+        sqlx::query_as(
+            "SELECT delta, resulting_stock FROM stock_adjustment
+            WHERE product_id = $1 ORDER BY adjusted_at",
+        )
+        .bind(product_id)
+        .fetch_all(db_conn)
+        .await
+        .expect("Adjustments should be selected")
+    }
+
+    /// Stock can be added and taken away, each adjustment returning the new
+    /// stock level and being recorded.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn stock_is_adjusted_and_recorded(db_conn: ConnectionPool) {
+        let administrator = store_user("administrator@example.com", &db_conn).await;
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(&db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        let added = adjust(product_id, 5, administrator.id(), &db_conn)
+            .await
+            .expect("Stock should be added");
+        assert_eq!(added, 5);
+        let removed = adjust(product_id, -2, administrator.id(), &db_conn)
+            .await
+            .expect("Stock should be removed");
+        assert_eq!(removed, 3);
+        assert_eq!(
+            recorded_adjustments(product_id, &db_conn).await,
+            [(5, 5), (-2, 3)]
+        );
+    }
+
+    /// An adjustment which would make the stock level negative is rejected,
+    /// leaving the stock level unchanged and recording nothing.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn stock_underflow_is_rejected(db_conn: ConnectionPool) {
+        let administrator = store_user("administrator@example.com", &db_conn).await;
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(&db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        adjust(product_id, 1, administrator.id(), &db_conn)
+            .await
+            .expect("Stock should be added");
+        assert!(matches!(
+            adjust(product_id, -2, administrator.id(), &db_conn).await,
+            Err(StockAdjustmentError::InsufficientStock(id)) if id == product_id
+        ));
+        let product = Product::select_one(product_id, &db_conn)
+            .await
+            .expect("Product should be selected")
+            .expect("Product should exist");
+        assert_eq!(product.stock(), Some(1));
+        assert_eq!(recorded_adjustments(product_id, &db_conn).await, [(1, 1)]);
+    }
 }
@@ -1,50 +1,65 @@
 //! Logic for onboarding and user registration.
 use super::sessions::{self, SessionTrait as _};
-use crate::db::models::appuser::AppUserSearchParameters;
 use crate::{
-    constants::passwords::{PASSWORD_MAX_LENGTH, PASSWORD_MIN_LENGTH},
+    constants::{
+        api::PUBLIC_URI,
+        passwords::{PASSWORD_MAX_LENGTH, PASSWORD_MIN_LENGTH},
+        sessions::{EMAIL_CHECK_DELAY_MS, GUEST_UPGRADE_TOKEN_TIMEOUT},
+    },
     db::{
         self,
+        errors::DatabaseError,
         models::{
+            apporder::AppOrder,
             appuser::{AppUser, AppUserInsert},
-            password::PasswordInsert,
+            order_event::OrderEvent,
+            password::{Password, PasswordInsert},
         },
     },
     services::sessions::RegistrationSession,
+    utils::{email::EmailAddress, mailer::EmailSender, redact::RedactedEmail},
 };
+use core::time::Duration;
+use errors::StorageError;
 use serde::Deserialize;
+use tokio::time::sleep;
+
+/// Check whether an email is available to sign up with, i.e. not already
+/// registered to a user. This necessarily reveals which emails are registered,
+/// so every check is delayed by `EMAIL_CHECK_DELAY_MS`, and callers must rate
+/// limit it per client. `signup_init` still rejects duplicates regardless.
+pub async fn check_email_available(
+    email: EmailAddress,
+    db_conn: &db::ConnectionPool,
+) -> Result<bool, StorageError> {
+    sleep(Duration::from_millis(EMAIL_CHECK_DELAY_MS)).await;
+    Ok(AppUser::select_by_email(&email, db_conn).await?.is_none())
+}
 
-/// Begin a signup session, setting the initial user information.
+/// Begin a signup session, setting the initial user information. `client`
+/// identifies where the request came from, to limit how many signups a single
+/// client can have in progress at once.
 pub async fn signup_init(
     user_data: AppUserInsert,
+    client: &str,
     session_store_conn: &mut sessions::store::Connection,
     db_conn: &db::ConnectionPool,
 ) -> Result<RegistrationSession, errors::SignupInitError> {
-    if !AppUser::search(
-        AppUserSearchParameters {
-            email: Some(user_data.email.clone()),
-            role: None,
-        },
-        db_conn,
-    )
-    .await
-    .map_err(errors::StorageError::from)?
-    .is_empty()
+    if AppUser::select_by_email(&user_data.email, db_conn)
+        .await
+        .map_err(errors::StorageError::from)?
+        .is_some()
     {
         return Err(errors::SignupInitError::DuplicateEmail(
             user_data.email.to_string(),
         ));
     }
-    if user_data.address.is_empty() {
-        Err(errors::SignupInitError::EmptyAddress)
-    } else if user_data.surname.is_empty() {
+    if user_data.surname.is_empty() {
         Err(errors::SignupInitError::EmptySurname)
     } else if user_data.forename.is_empty() {
         Err(errors::SignupInitError::EmptyForename)
     } else {
-        Ok(RegistrationSession::create(user_data, session_store_conn)
-            .await
-            .map_err(errors::StorageError::from)?)
+        Ok(RegistrationSession::create(user_data, client, session_store_conn).await?)
     }
 }
 
@@ -60,6 +75,23 @@ pub enum PrimaryAuthenticationMethod {
     },
 }
 
+/// Check that a credential being registered is acceptable.
+const fn check_credential(
+    credential: &PrimaryAuthenticationMethod,
+) -> Result<(), errors::AddCredentialError> {
+    match *credential {
+        PrimaryAuthenticationMethod::Password { ref password } => {
+            if password.len() < PASSWORD_MIN_LENGTH {
+                return Err(errors::AddCredentialError::PasswordTooShort);
+            }
+            if password.len() > PASSWORD_MAX_LENGTH {
+                return Err(errors::AddCredentialError::PasswordTooLong);
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Add credentials to a user during a onboarding session and save the user data
 /// to the database.
 pub async fn add_credential_and_commit(
@@ -68,29 +100,51 @@ pub async fn add_credential_and_commit(
     db_conn: &db::ConnectionPool,
     session_store_conn: &mut sessions::store::Connection,
 ) -> Result<(), errors::AddCredentialError> {
+    check_credential(&credential)?;
     let user_data = registration_session.user_data();
-    let stored_user = user_data
-        .store(db_conn)
+    // A retry after a failure part way through a previous commit may find the
+    // user already created. If they have no credential yet, it is attached to
+    // them rather than attempting to create them again.
+    let existing_user = AppUser::select_by_email(&user_data.email, db_conn)
         .await
-        .map_err(|err| errors::AddCredentialError::StorageError(err.into()))?;
+        .map_err(StorageError::from)?;
+    if let Some(ref user) = existing_user {
+        if Password::select(user.id(), db_conn)
+            .await
+            .map_err(StorageError::from)?
+            .is_some()
+        {
+            registration_session
+                .delete(session_store_conn)
+                .await
+                .map_err(|err| errors::AddCredentialError::StorageError(err.into()))?;
+            return Err(errors::AddCredentialError::AlreadyRegistered(
+                user_data.email.to_string(),
+            ));
+        }
+    }
+    // The user and their credential are written in one transaction, so a
+    // failure part way through never leaves a user who cannot log in.
+    let email = user_data.email.clone();
+    let mut transaction = db::begin(db_conn).await.map_err(StorageError::from)?;
+    let user_id = match existing_user {
+        Some(user) => user.id(),
+        None => user_data
+            .store(&mut *transaction)
+            .await
+            .map_err(|err| commit_error(err, &email))?
+            .id(),
+    };
     match credential {
         PrimaryAuthenticationMethod::Password { password } => {
-            if password.len() < PASSWORD_MIN_LENGTH {
-                return Err(errors::AddCredentialError::PasswordTooShort);
-            }
-            if password.len() > PASSWORD_MAX_LENGTH {
-                return Err(errors::AddCredentialError::PasswordTooLong);
-            }
-            let password_model = PasswordInsert::new(stored_user.id(), &password);
-            if let Err(error) = password_model.store(db_conn).await {
-                stored_user
-                    .delete(db_conn)
-                    .await
-                    .map_err(|err| errors::AddCredentialError::StorageError(err.into()))?;
-                return Err(errors::AddCredentialError::StorageError(error.into()));
-            }
+            let password_model = PasswordInsert::new(user_id, &password);
+            password_model
+                .store(&mut *transaction)
+                .await
+                .map_err(|err| commit_error(err, &email))?;
         }
     }
+    db::commit(transaction).await.map_err(StorageError::from)?;
     registration_session
         .delete(session_store_conn)
         .await
@@ -98,9 +152,119 @@ pub async fn add_credential_and_commit(
     Ok(())
 }
 
+/// Convert an error storing a new user or their credential into an
+/// `AddCredentialError`. The check for an existing registration isn't atomic
+/// with the insert, so a concurrent registration with the same email (in any
+/// case) may commit first, which the database reports as a unique violation.
+fn commit_error(err: DatabaseError, email: &EmailAddress) -> errors::AddCredentialError {
+    if err.is_unique_violation() {
+        tracing::warn!(
+            "Registration as {} lost a race with a concurrent registration.",
+            RedactedEmail::from(email)
+        );
+        errors::AddCredentialError::AlreadyRegistered(email.to_string())
+    } else {
+        StorageError::from(err).into()
+    }
+}
+
+/// Email a link with which the guests who placed orders with `email` can be
+/// upgraded to a full account, proving that whoever upgrades them can receive
+/// email there. Nothing is sent if no undeleted guest has the address, or it
+/// is already registered, but callers must not reveal whether that was the
+/// case, so failing to send the email is only logged.
+pub async fn request_guest_upgrade(
+    email: EmailAddress,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+    email_sender: &dyn EmailSender,
+) -> Result<(), StorageError> {
+    if AppUser::select_by_email(&email, db_conn).await?.is_some()
+        || AppUser::select_guest_ids_by_email(email.as_str(), db_conn)
+            .await?
+            .is_empty()
+    {
+        return Ok(());
+    }
+    let token = sessions::create_guest_upgrade_token(email.as_str(), session_store_conn).await?;
+    let link = format!("{}/register/guest?token={token}", *PUBLIC_URI);
+    if let Err(err) = email_sender
+        .send(
+            &email,
+            "Create your SecureCart account",
+            &format!(
+                "Follow this link to create a SecureCart account with your previous orders. It expires in {} minutes and can only be used once.\n\n{link}",
+                GUEST_UPGRADE_TOKEN_TIMEOUT.div_euclid(60)
+            ),
+        )
+        .await
+    {
+        tracing::error!(
+            "Failed to send guest upgrade link to {}: {err}",
+            RedactedEmail::from(&email)
+        );
+    }
+    Ok(())
+}
+
+/// Upgrade the guests who placed orders with an email to a single full
+/// account with a credential, given a token sent by `request_guest_upgrade`
+/// to that email. Every order placed by those guests is moved to the account.
+/// The token is consumed whatever the outcome.
+pub async fn upgrade_guest(
+    token: &str,
+    credential: PrimaryAuthenticationMethod,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+) -> Result<(), errors::GuestUpgradeError> {
+    check_credential(&credential)?;
+    let email = sessions::take_guest_upgrade_email(token, session_store_conn)
+        .await
+        .map_err(StorageError::from)?
+        .ok_or(errors::GuestUpgradeError::InvalidToken)?;
+    let mut transaction = db::begin(db_conn).await.map_err(StorageError::from)?;
+    let guest_ids = AppUser::select_guest_ids_by_email(&email, &mut *transaction)
+        .await
+        .map_err(StorageError::from)?;
+    let Some((&user_id, merged_ids)) = guest_ids.split_first() else {
+        return Err(errors::GuestUpgradeError::InvalidToken);
+    };
+    AppOrder::reassign_user(merged_ids, user_id, &mut *transaction)
+        .await
+        .map_err(StorageError::from)?;
+    OrderEvent::reassign_actor(merged_ids, user_id, &mut *transaction)
+        .await
+        .map_err(StorageError::from)?;
+    AppUser::delete_guests(merged_ids, &mut *transaction)
+        .await
+        .map_err(StorageError::from)?;
+    match credential {
+        PrimaryAuthenticationMethod::Password { password } => {
+            let password_model = PasswordInsert::new(user_id, &password);
+            password_model
+                .store(&mut *transaction)
+                .await
+                .map_err(StorageError::from)?;
+        }
+    }
+    // The email may have been registered since the token was issued.
+    AppUser::clear_guest(user_id, &mut *transaction)
+        .await
+        .map_err(|err| {
+            if err.is_unique_violation() {
+                errors::AddCredentialError::AlreadyRegistered(email.clone()).into()
+            } else {
+                errors::GuestUpgradeError::from(StorageError::from(err))
+            }
+        })?;
+    db::commit(transaction).await.map_err(StorageError::from)?;
+    Ok(())
+}
+
 /// Erors returned by this service.
 pub mod errors {
     pub use super::super::errors::StorageError;
+    use crate::services::sessions::errors::PendingSessionError;
     use thiserror::Error;
 
     /// Errors returned while initiating an onboarding session.
@@ -112,15 +276,26 @@ pub mod errors {
         #[error("Email is already is use")]
         /// The signup attempt uses an email which is already registered.
         DuplicateEmail(String),
-        #[error("The signup address field is empty")]
-        /// TODO: add documentation
-        EmptyAddress,
         #[error("The signup surname field is empty")]
         /// TODO: add documentation
         EmptySurname,
         #[error("The signup forename field is empty")]
         /// TODO: add documentation
         EmptyForename,
+        #[error("Too many signups are in progress from this client")]
+        /// The client already has the maximum number of signups in progress.
+        TooManySessions,
+    }
+
+    impl From<PendingSessionError> for SignupInitError {
+        fn from(err: PendingSessionError) -> Self {
+            match err {
+                PendingSessionError::LimitReached => Self::TooManySessions,
+                PendingSessionError::StorageError(storage_err) => {
+                    Self::StorageError(storage_err.into())
+                }
+            }
+        }
     }
 
     #[derive(Error, Debug)]
@@ -134,5 +309,156 @@ pub mod errors {
         /// The provided password was too long
         #[error("The password was above the maximum length")]
         PasswordTooLong,
+        /// A user with the session's email has already completed registration.
+        #[error("Email is already registered")]
+        AlreadyRegistered(String),
+    }
+
+    /// Errors returned while upgrading a guest user to a full account.
+    #[derive(Error, Debug)]
+    pub enum GuestUpgradeError {
+        /// An error in the underlying storage.
+        #[error(transparent)]
+        StorageError(#[from] StorageError),
+        /// The guest upgrade token is invalid or has expired, or the guests
+        /// it was issued for no longer exist.
+        #[error("The guest upgrade token is invalid")]
+        InvalidToken,
+        /// The credential could not be added.
+        #[error(transparent)]
+        Credential(#[from] AddCredentialError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_credential_and_commit, errors::AddCredentialError, PrimaryAuthenticationMethod,
+    };
+    use crate::{
+        db::{
+            models::{
+                appuser::{AppUser, AppUserInsert},
+                password::Password,
+            },
+            ConnectionPool,
+        },
+        services::sessions::{
+            fake_store::FakeStore, store::Connection, RegistrationSession, SessionTrait as _,
+        },
+        testing::{store_user, CLIENT_IP, PASSWORD},
+        utils::{address::Address, email::EmailAddress},
+    };
+
+    /// The details Alice signs up with.
+    fn alice() -> AppUserInsert {
+        AppUserInsert::new(
+            EmailAddress::try_from("alice@example.com").expect("Email should be valid"),
+            "Alice",
+            "Smith",
+            Address::new("1 High Street", None, "London", "SW1A 1AA", "GB")
+                .expect("Address should be valid"),
+            None,
+        )
+    }
+
+    /// Complete Alice's registration with a password, through a new
+    /// registration session. Returns the session's token with the outcome.
+    async fn register_alice(
+        db_conn: &ConnectionPool,
+        session_store_conn: &mut Connection,
+    ) -> (String, Result<(), AddCredentialError>) {
+        let session = RegistrationSession::create(alice(), CLIENT_IP, session_store_conn)
+            .await
+            .expect("Session should be created");
+        let token = session.token();
+        let result = add_credential_and_commit(
+            session,
+            PrimaryAuthenticationMethod::Password {
+                password: PASSWORD.to_owned(),
+            },
+            db_conn,
+            session_store_conn,
+        )
+        .await;
+        (token, result)
+    }
+
+    /// A retry after a commit which stored the user but not their credential
+    /// attaches the credential to that user, rather than storing them again.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn retry_attaches_credential_to_existing_user(db_conn: ConnectionPool) {
+        let user = alice()
+            .store(&db_conn)
+            .await
+            .expect("User should be stored");
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        let (token, result) = register_alice(&db_conn, &mut conn).await;
+        result.expect("Registration should be committed");
+        assert!(Password::select(user.id(), &db_conn)
+            .await
+            .expect("Password should be read")
+            .is_some());
+        let registered = AppUser::select_by_email(&alice().email, &db_conn)
+            .await
+            .expect("User should be read")
+            .expect("User should exist");
+        assert_eq!(registered.id(), user.id());
+        assert!(RegistrationSession::get(&token, &mut conn)
+            .await
+            .expect("Session should be read")
+            .is_none());
+    }
+
+    /// A user who already has a credential is not registered again.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn registered_user_is_rejected(db_conn: ConnectionPool) {
+        store_user("alice@example.com", &db_conn).await;
+        let store = FakeStore::default();
+        let (_, result) = register_alice(&db_conn, &mut Connection::fake(&store)).await;
+        assert!(matches!(
+            result,
+            Err(AddCredentialError::AlreadyRegistered(_))
+        ));
+    }
+
+    /// If the credential can't be stored, the user stored before it is rolled
+    /// back with it.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn failed_credential_rolls_back_user(db_conn: ConnectionPool) {
+        sqlx::query("ALTER TABLE password ADD CONSTRAINT reject_all CHECK (false) NOT VALID")
+            .execute(&db_conn)
+            .await
+            .expect("Constraint should be added");
+        let store = FakeStore::default();
+        let (_, result) = register_alice(&db_conn, &mut Connection::fake(&store)).await;
+        assert!(matches!(result, Err(AddCredentialError::StorageError(_))));
+        assert!(AppUser::select_by_email(&alice().email, &db_conn)
+            .await
+            .expect("User should be read")
+            .is_none());
+    }
+
+    /// Of two concurrent registrations with the same email, exactly one is
+    /// committed, and the other is rejected as already registered.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn concurrent_registrations_commit_once(db_conn: ConnectionPool) {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        let mut other_conn = Connection::fake(&store);
+        let ((_, first), (_, second)) = tokio::join!(
+            register_alice(&db_conn, &mut conn),
+            register_alice(&db_conn, &mut other_conn),
+        );
+        let outcomes = [first, second];
+        assert_eq!(outcomes.iter().filter(|outcome| outcome.is_ok()).count(), 1);
+        assert!(outcomes
+            .iter()
+            .any(|outcome| matches!(outcome, Err(AddCredentialError::AlreadyRegistered(_)))));
     }
 }
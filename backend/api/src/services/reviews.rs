@@ -0,0 +1,91 @@
+//! Functions for posting and querying reviews of products. Only customers who
+//! have received a product in a fulfilled order may review it.
+use uuid::Uuid;
+
+use crate::{
+    db::{
+        self,
+        errors::DatabaseError,
+        models::{
+            product::Product,
+            review::{Review, ReviewInsert},
+        },
+    },
+    utils::pagination::Pagination,
+};
+
+/// Post a customer's review of a product, rating it from 1 to 5.
+pub async fn create_review(
+    user_id: Uuid,
+    product_id: Uuid,
+    rating: u8,
+    body: String,
+    db_conn: &db::ConnectionPool,
+) -> Result<Review, errors::ReviewCreationError> {
+    if !(1..=5).contains(&rating) {
+        return Err(errors::ReviewCreationError::InvalidRating(rating));
+    }
+    Product::select_one(product_id, db_conn)
+        .await?
+        .filter(Product::is_listed)
+        .ok_or(errors::ReviewCreationError::ProductNonExistent(product_id))?;
+    if !Review::is_eligible(user_id, product_id, db_conn).await? {
+        return Err(errors::ReviewCreationError::NotPurchased(product_id));
+    }
+    ReviewInsert {
+        user_id,
+        product_id,
+        rating: i16::from(rating),
+        body,
+    }
+    .store(db_conn)
+    .await?
+    .ok_or(errors::ReviewCreationError::AlreadyReviewed(product_id))
+}
+
+/// List a page of the reviews of a product, most recent first.
+pub async fn list_product_reviews(
+    product_id: Uuid,
+    page: Pagination,
+    db_conn: &db::ReadConnectionPool,
+) -> Result<Vec<Review>, DatabaseError> {
+    Review::select_for_product(product_id, page, db_conn).await
+}
+
+/// List a page of the products which a customer may review but has not yet
+/// reviewed.
+pub async fn list_reviewable_products(
+    user_id: Uuid,
+    page: Pagination,
+    db_conn: &db::ReadConnectionPool,
+) -> Result<Vec<Product>, DatabaseError> {
+    Product::select_reviewable(user_id, page, db_conn).await
+}
+
+/// Errors returned by functions within this module.
+pub mod errors {
+    use crate::db::errors::DatabaseError;
+    use thiserror::Error;
+    use uuid::Uuid;
+
+    /// Errors returned when posting a review.
+    #[derive(Error, Debug)]
+    pub enum ReviewCreationError {
+        /// Error passed up from the database storage layer.
+        #[error(transparent)]
+        DatabaseError(#[from] DatabaseError),
+        /// Raised when the product being reviewed does not exist or is not listed.
+        #[error("The product being reviewed does not exist.")]
+        ProductNonExistent(Uuid),
+        /// Raised when the customer has not received the product in a
+        /// fulfilled order.
+        #[error("The product has not been purchased by this customer.")]
+        NotPurchased(Uuid),
+        /// Raised when the customer has already reviewed the product.
+        #[error("The product has already been reviewed by this customer.")]
+        AlreadyReviewed(Uuid),
+        /// Raised when the rating is outside of the range 1 to 5.
+        #[error("The rating must be between 1 and 5.")]
+        InvalidRating(u8),
+    }
+}
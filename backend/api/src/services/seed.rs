@@ -0,0 +1,292 @@
+//! Seeding of the database with a fixed set of fixture data, for local
+//! development and testing. Seeding is idempotent, so running it against an
+//! already seeded database changes nothing.
+use alloc::sync::Arc;
+use std::io::Cursor;
+
+use image::{ImageFormat, Rgb, RgbImage};
+use object_store::ObjectStore;
+use uuid::Uuid;
+
+use crate::{
+    db::{
+        self,
+        models::{
+            apporder::{AppOrderSearchParameters, ShippingMethod},
+            appuser::{AppUser, AppUserInsert, AppUserRole},
+            password::PasswordInsert,
+            product::{Product, ProductInsert, ProductSearchParameters},
+        },
+    },
+    services::{orders, products},
+    utils::{
+        address::Address, email::EmailAddress, mailer::NoopEmailSender, redact::RedactedEmail,
+    },
+};
+
+/// A user created by the seed.
+struct SeedUser {
+    /// The user's email address.
+    email: &'static str,
+    /// The user's password.
+    password: &'static str,
+    /// The user's forename.
+    forename: &'static str,
+    /// The user's surname.
+    surname: &'static str,
+    /// Whether the user is an administrator.
+    admin: bool,
+}
+
+/// A product created by the seed.
+struct SeedProduct {
+    /// The product's name.
+    name: &'static str,
+    /// The product's description.
+    description: &'static str,
+    /// The product's price in pennies.
+    price: u32,
+    /// The product's initial stock.
+    stock: i64,
+    /// The colour of the product's generated image.
+    colour: [u8; 3],
+}
+
+/// The seeded administrator.
+const ADMIN: SeedUser = SeedUser {
+    email: "admin@securecart.local",
+    password: "seed-admin-password",
+    forename: "Ada",
+    surname: "Admin",
+    admin: true,
+};
+
+/// The seeded customer.
+const CUSTOMER: SeedUser = SeedUser {
+    email: "customer@securecart.local",
+    password: "seed-customer-password",
+    forename: "Carl",
+    surname: "Customer",
+    admin: false,
+};
+
+/// The seeded products.
+const PRODUCTS: [SeedProduct; 4] = [
+    SeedProduct {
+        name: "Red Mug",
+        description: "A sturdy red ceramic mug.",
+        price: 899,
+        stock: 50,
+        colour: [200, 30, 30],
+    },
+    SeedProduct {
+        name: "Green Notebook",
+        description: "A ruled A5 notebook with a green cover.",
+        price: 450,
+        stock: 120,
+        colour: [30, 160, 60],
+    },
+    SeedProduct {
+        name: "Blue Water Bottle",
+        description: "A 750ml insulated water bottle.",
+        price: 1599,
+        stock: 30,
+        colour: [30, 80, 200],
+    },
+    SeedProduct {
+        name: "Grey Hoodie",
+        description: "A heavyweight cotton hoodie.",
+        price: 3499,
+        stock: 15,
+        colour: [120, 120, 120],
+    },
+];
+
+/// The width and height in pixels of generated product images. Large enough
+/// that every responsive size is generated.
+const IMAGE_SIZE: u32 = 1200;
+
+/// Seed the database with a known administrator, customer, products (with
+/// images) and orders. Anything which already exists is left untouched.
+pub async fn seed(
+    db_conn: &db::ConnectionPool,
+    media_store: Arc<dyn ObjectStore>,
+) -> Result<(), errors::SeedError> {
+    let admin = ensure_user(&ADMIN, db_conn).await?;
+    let customer = ensure_user(&CUSTOMER, db_conn).await?;
+    let mut product_ids = Vec::with_capacity(PRODUCTS.len());
+    for product in &PRODUCTS {
+        product_ids
+            .push(ensure_product(product, admin.id(), db_conn, Arc::clone(&media_store)).await?);
+    }
+    ensure_orders(customer.id(), &product_ids, db_conn).await?;
+    Ok(())
+}
+
+/// Create a seed user unless a user with their email already exists.
+async fn ensure_user(
+    seed_user: &SeedUser,
+    db_conn: &db::ConnectionPool,
+) -> Result<AppUser, errors::SeedError> {
+    let email = EmailAddress::try_from(seed_user.email).expect("Seed email address invalid.");
+    if let Some(user) = AppUser::select_by_email(&email, db_conn).await? {
+        return Ok(user);
+    }
+    let mut transaction = db::begin(db_conn).await?;
+    let user = AppUserInsert::new(
+        email,
+        seed_user.forename,
+        seed_user.surname,
+        Address::new("1 Seed Street", None, "Testville", "TE1 1ST", "GB")
+            .expect("Seed address invalid."),
+        None,
+    )
+    .store(&mut *transaction)
+    .await?;
+    PasswordInsert::new(user.id(), seed_user.password)
+        .store(&mut *transaction)
+        .await?;
+    db::commit(transaction).await?;
+    if seed_user.admin {
+        let mut admin = user;
+        admin.role = AppUserRole::Administrator;
+        admin.update(db_conn).await?;
+        tracing::info!(
+            "Seeded administrator {}",
+            RedactedEmail::new(seed_user.email)
+        );
+        Ok(admin)
+    } else {
+        tracing::info!("Seeded customer {}", RedactedEmail::new(seed_user.email));
+        Ok(user)
+    }
+}
+
+/// Create a seed product, with its stock and a generated image, unless a
+/// product with its name already exists. Returns the product's ID.
+async fn ensure_product(
+    seed_product: &SeedProduct,
+    administrator_id: Uuid,
+    db_conn: &db::ConnectionPool,
+    media_store: Arc<dyn ObjectStore>,
+) -> Result<Uuid, errors::SeedError> {
+    let existing = Product::search(
+        ProductSearchParameters {
+            name: Some(seed_product.name.to_owned()),
+            ..Default::default()
+        },
+        None,
+        db_conn,
+    )
+    .await?
+    .into_iter()
+    .find(|rated| rated.product.name == seed_product.name);
+    if let Some(rated) = existing {
+        return Ok(rated.product.id());
+    }
+    let product = ProductInsert::new(
+        seed_product.name,
+        seed_product.description,
+        true,
+        seed_product.price,
+    )
+    .store(db_conn)
+    .await?;
+    let product_id = product.id();
+    // Nobody can have asked to be notified about a product created just now.
+    products::adjust_stock(
+        product_id,
+        seed_product.stock,
+        administrator_id,
+        db_conn,
+        Arc::new(NoopEmailSender),
+    )
+    .await?;
+    let mut image = Cursor::new(Vec::new());
+    RgbImage::from_pixel(IMAGE_SIZE, IMAGE_SIZE, Rgb(seed_product.colour))
+        .write_to(&mut image, ImageFormat::Png)?;
+    products::add_image(
+        product_id,
+        image.into_inner(),
+        products::ContentDisposition::Inline,
+        db_conn,
+        media_store,
+    )
+    .await?;
+    tracing::info!("Seeded product {}", seed_product.name);
+    Ok(product_id)
+}
+
+/// Place sample orders for the seeded customer, one of which is confirmed,
+/// unless they already have orders.
+async fn ensure_orders(
+    customer_id: Uuid,
+    product_ids: &[Uuid],
+    db_conn: &db::ConnectionPool,
+) -> Result<(), errors::SeedError> {
+    let existing = orders::search_orders(
+        AppOrderSearchParameters {
+            user_id: Some(customer_id),
+            ..AppOrderSearchParameters::default()
+        },
+        None,
+        db_conn,
+    )
+    .await?;
+    if !existing.is_empty() {
+        return Ok(());
+    }
+    let mut counts = product_ids.iter().copied().zip(1..);
+    let confirmed = orders::create_order(
+        customer_id,
+        counts.by_ref().take(2).collect(),
+        None,
+        ShippingMethod::Standard,
+        db_conn,
+    )
+    .await?;
+    orders::confirm_order(confirmed.id(), None, db_conn).await?;
+    orders::create_order(
+        customer_id,
+        counts.collect(),
+        None,
+        ShippingMethod::Standard,
+        db_conn,
+    )
+    .await?;
+    tracing::info!("Seeded orders for customer {customer_id}");
+    Ok(())
+}
+
+/// Errors returned from this module.
+pub mod errors {
+    use thiserror::Error;
+
+    use crate::{
+        db::errors::DatabaseError,
+        services::{orders::errors as orders, products::errors as products},
+    };
+
+    /// Errors returned while seeding the database.
+    #[derive(Debug, Error)]
+    pub enum SeedError {
+        /// An error returned by the database.
+        #[error(transparent)]
+        Database(#[from] DatabaseError),
+        /// An error generating a product image.
+        #[error(transparent)]
+        Image(#[from] image::ImageError),
+        /// An error adding a product's image.
+        #[error(transparent)]
+        AddImage(#[from] products::AddImageError),
+        /// An error setting a product's stock.
+        #[error(transparent)]
+        StockAdjustment(#[from] products::StockAdjustmentError),
+        /// An error creating an order.
+        #[error(transparent)]
+        OrderCreation(#[from] orders::OrderCreationError),
+        /// An error confirming an order.
+        #[error(transparent)]
+        OrderConfirmation(#[from] orders::OrderConfirmationError),
+    }
+}
@@ -0,0 +1,619 @@
+//! An in-memory stand-in for the Redis server behind the session store, used
+//! in tests. Interprets only the commands which the store sends, and can be
+//! made to fail commands as a flaky connection to Redis would.
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+use core::{future, str::from_utf8};
+use redis::{
+    aio::ConnectionLike, Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, Value,
+};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Mutex, MutexGuard},
+};
+use tokio::time::{Duration, Instant};
+
+use super::store::ROTATE_CSRF_SCRIPT;
+
+/// A value held under a key.
+enum Data {
+    /// A string, including integers stored as strings.
+    String(Vec<u8>),
+    /// A hash of fields to values.
+    Hash(BTreeMap<Vec<u8>, Vec<u8>>),
+    /// A set of members.
+    Set(BTreeSet<Vec<u8>>),
+    /// A sorted set of members, with their scores.
+    SortedSet(BTreeMap<Vec<u8>, f64>),
+}
+
+impl Data {
+    /// Whether this is a collection with nothing left in it, which Redis
+    /// deletes.
+    fn is_empty(&self) -> bool {
+        match *self {
+            Self::String(_) => false,
+            Self::Hash(ref hash) => hash.is_empty(),
+            Self::Set(ref set) => set.is_empty(),
+            Self::SortedSet(ref sorted_set) => sorted_set.is_empty(),
+        }
+    }
+}
+
+/// The value held under a key, and when the key expires (if ever).
+struct Entry {
+    /// The value.
+    data: Data,
+    /// When the key expires, or None if it never does.
+    expires_at: Option<Instant>,
+}
+
+/// How a command made to fail with `FakeStore::fail` fails. Either way the
+/// caller sees a transient, connection-level error.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The command never reaches the store.
+    Dropped,
+    /// The command is applied, but its reply is lost.
+    ReplyLost,
+}
+
+/// The data held by the store, and the failures waiting to happen.
+#[derive(Default)]
+struct State {
+    /// Every key with its value.
+    entries: HashMap<Vec<u8>, Entry>,
+    /// The number of upcoming commands, of any kind, which are dropped.
+    dropped: u32,
+    /// The upcoming failures of commands by name, each of which happens
+    /// once, the next time a command with the name is sent.
+    failures: Vec<(String, Failure)>,
+    /// The name of every command sent, in order, including failed commands.
+    sent: Vec<String>,
+}
+
+/// An in-memory session store. Clones share the same data, as clones of a
+/// connection to the same Redis server would.
+#[derive(Clone, Default)]
+pub struct FakeStore(Arc<Mutex<State>>);
+
+impl FakeStore {
+    /// Drop the next `commands` commands sent to the store, of any kind.
+    pub fn fail_next(&self, commands: u32) {
+        self.state().dropped = commands;
+    }
+    /// Fail the next command sent with the given name (e.g. "EXPIRE").
+    pub fn fail(&self, command: &str, failure: Failure) {
+        self.state()
+            .failures
+            .push((command.to_uppercase(), failure));
+    }
+    /// The name of every command sent to the store so far, in order.
+    pub fn commands(&self) -> Vec<String> {
+        self.state().sent.clone()
+    }
+    /// Lock the state of the store.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0.lock().expect("Fake store should not be poisoned")
+    }
+}
+
+impl ConnectionLike for FakeStore {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let args: Vec<&[u8]> = cmd
+            .args_iter()
+            .filter_map(|arg| match arg {
+                Arg::Simple(bytes) => Some(bytes),
+                // Only SCAN takes a cursor, and the fake returns every key
+                // matching at once.
+                Arg::Cursor => None,
+            })
+            .collect();
+        let reply = self.state().send(&args);
+        Box::pin(future::ready(reply))
+    }
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        _cmd: &'a Pipeline,
+        _offset: usize,
+        _count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(future::ready(Err(unsupported("pipelines"))))
+    }
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+/// The error seen by the caller of a command which failed.
+fn transient_error() -> RedisError {
+    RedisError::from(io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "Connection to fake store reset",
+    ))
+}
+
+/// The error returned for a command which the fake does not interpret.
+fn unsupported(command: &str) -> RedisError {
+    RedisError::from((
+        ErrorKind::ClientError,
+        "Not supported by the fake store",
+        command.to_owned(),
+    ))
+}
+
+/// The error returned for a command against a key holding the wrong type of
+/// value.
+fn wrong_type() -> RedisError {
+    RedisError::from((
+        ErrorKind::TypeError,
+        "WRONGTYPE Operation against a key holding the wrong kind of value",
+    ))
+}
+
+/// The error returned for a command with invalid arguments.
+fn invalid_argument() -> RedisError {
+    RedisError::from((ErrorKind::ResponseError, "ERR syntax error"))
+}
+
+/// Parse an integer argument.
+fn integer(arg: &[u8]) -> RedisResult<i64> {
+    from_utf8(arg)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(invalid_argument)
+}
+
+/// Parse a floating point argument, including "-inf" and "+inf".
+fn float(arg: &[u8]) -> RedisResult<f64> {
+    from_utf8(arg)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(invalid_argument)
+}
+
+/// The reply counting `count` things, e.g. keys deleted.
+fn count(count: usize) -> Value {
+    Value::Int(i64::try_from(count).unwrap_or(i64::MAX))
+}
+
+/// The reply holding a string.
+fn bulk(bytes: &[u8]) -> Value {
+    Value::BulkString(bytes.to_vec())
+}
+
+/// Add `delta` (an integer argument) to the integer stored in `value`,
+/// returning the reply with the result.
+fn increment(value: &mut Vec<u8>, delta: &[u8]) -> RedisResult<Value> {
+    let current = if value.is_empty() { 0 } else { integer(value)? };
+    let result = current
+        .checked_add(integer(delta)?)
+        .ok_or_else(invalid_argument)?;
+    *value = result.to_string().into_bytes();
+    Ok(Value::Int(result))
+}
+
+/// Whether `key` matches a SCAN pattern, in which "*" matches any sequence of
+/// bytes and "?" any single byte.
+fn glob_matches(pattern: &[u8], key: &[u8]) -> bool {
+    match (pattern.split_first(), key.split_first()) {
+        (None, None) => true,
+        (Some((&b'*', rest)), _) => {
+            glob_matches(rest, key)
+                || key
+                    .split_first()
+                    .is_some_and(|(_, key_rest)| glob_matches(pattern, key_rest))
+        }
+        (Some((&b'?', rest)), Some((_, key_rest))) => glob_matches(rest, key_rest),
+        (Some((expected, rest)), Some((actual, key_rest))) => {
+            expected == actual && glob_matches(rest, key_rest)
+        }
+        (Some(_), None) | (None, Some(_)) => false,
+    }
+}
+
+/// The position in a sequence of `len` items given by a ZRANGE index, which
+/// counts from the end if negative.
+fn position(index: i64, len: usize) -> usize {
+    let offset = usize::try_from(index.unsigned_abs()).unwrap_or(usize::MAX);
+    if index < 0 {
+        len.saturating_sub(offset)
+    } else {
+        offset
+    }
+}
+
+impl State {
+    /// Send a command, given its name and arguments, returning its reply.
+    fn send(&mut self, command: &[&[u8]]) -> RedisResult<Value> {
+        let Some((raw_name, args)) = command.split_first() else {
+            return Err(invalid_argument());
+        };
+        let name = String::from_utf8_lossy(raw_name).to_uppercase();
+        self.sent.push(name.clone());
+        if self.dropped > 0 {
+            self.dropped = self.dropped.saturating_sub(1);
+            return Err(transient_error());
+        }
+        let failure = self
+            .failures
+            .iter()
+            .position(|failure| failure.0 == name)
+            .map(|index| self.failures.remove(index).1);
+        if failure == Some(Failure::Dropped) {
+            return Err(transient_error());
+        }
+        let now = Instant::now();
+        self.entries
+            .retain(|_, entry| entry.expires_at.is_none_or(|expires_at| expires_at > now));
+        let reply = self.execute(&name, args);
+        self.entries.retain(|_, entry| !entry.data.is_empty());
+        if failure == Some(Failure::ReplyLost) {
+            return Err(transient_error());
+        }
+        reply
+    }
+
+    /// Apply a command to the store.
+    #[expect(
+        clippy::too_many_lines,
+        reason = "One arm per command, splitting this up would not make it clearer"
+    )]
+    fn execute(&mut self, name: &str, args: &[&[u8]]) -> RedisResult<Value> {
+        match (name, args) {
+            ("GET", &[key]) => Ok(self.string(key)?.map_or(Value::Nil, bulk)),
+            ("GETDEL", &[key]) => {
+                let value = self.string(key)?.map(bulk);
+                self.entries.remove(key);
+                Ok(value.unwrap_or(Value::Nil))
+            }
+            ("SET", &[key, value, ref options @ ..]) => self.set(key, value, options),
+            ("SETEX", &[key, seconds, value]) => {
+                let expires_at = Self::expiry(seconds)?;
+                self.insert(key, Data::String(value.to_vec()), Some(expires_at));
+                Ok(Value::Okay)
+            }
+            ("DEL", keys) => Ok(count(
+                keys.iter()
+                    .filter(|&&key| self.entries.remove(key).is_some())
+                    .count(),
+            )),
+            ("EXISTS", keys) => Ok(count(
+                keys.iter()
+                    .filter(|&&key| self.entries.contains_key(key))
+                    .count(),
+            )),
+            ("EXPIRE", &[key, seconds, ref options @ ..]) => self.expire(key, seconds, options),
+            ("TTL", &[key]) => Ok(Value::Int(self.ttl(key))),
+            ("INCRBY", &[key, delta]) => {
+                let entry = self.entries.entry(key.to_vec()).or_insert_with(|| Entry {
+                    data: Data::String(Vec::new()),
+                    expires_at: None,
+                });
+                match entry.data {
+                    Data::String(ref mut value) => increment(value, delta),
+                    Data::Hash(_) | Data::Set(_) | Data::SortedSet(_) => Err(wrong_type()),
+                }
+            }
+            ("RENAME", &[key, new_key]) => {
+                let entry = self.entries.remove(key).ok_or_else(|| {
+                    RedisError::from((ErrorKind::ResponseError, "ERR no such key"))
+                })?;
+                self.entries.insert(new_key.to_vec(), entry);
+                Ok(Value::Okay)
+            }
+            ("HSET" | "HMSET", &[key, ref field_values @ ..]) => {
+                let pairs = field_values.chunks_exact(2);
+                let fields = pairs.len();
+                if !pairs.remainder().is_empty() || fields == 0 {
+                    return Err(invalid_argument());
+                }
+                let hash = self.hash_mut(key)?;
+                let replaced = pairs
+                    .filter_map(|pair| match *pair {
+                        [field, value] => hash.insert(field.to_vec(), value.to_vec()),
+                        _ => None,
+                    })
+                    .count();
+                Ok(if name == "HMSET" {
+                    Value::Okay
+                } else {
+                    count(fields.saturating_sub(replaced))
+                })
+            }
+            ("HSETNX", &[key, field, value]) => {
+                let hash = self.hash_mut(key)?;
+                if hash.contains_key(field) {
+                    return Ok(Value::Int(0));
+                }
+                hash.insert(field.to_vec(), value.to_vec());
+                Ok(Value::Int(1))
+            }
+            ("HGET", &[key, field]) => Ok(self
+                .hash(key)?
+                .and_then(|hash| hash.get(field))
+                .map_or(Value::Nil, |value| bulk(value))),
+            ("HMGET", &[key, ref fields @ ..]) => {
+                let hash = self.hash(key)?;
+                Ok(Value::Array(
+                    fields
+                        .iter()
+                        .map(|&field| {
+                            hash.and_then(|fields_values| fields_values.get(field))
+                                .map_or(Value::Nil, |value| bulk(value))
+                        })
+                        .collect(),
+                ))
+            }
+            ("HGETALL", &[key]) => Ok(Value::Array(
+                self.hash(key)?
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|(field, value)| [bulk(field), bulk(value)])
+                    .collect(),
+            )),
+            ("HINCRBY", &[key, field, delta]) => {
+                let value = self.hash_mut(key)?.entry(field.to_vec()).or_default();
+                increment(value, delta)
+            }
+            ("SADD", &[key, ref members @ ..]) => {
+                let set = self.set_mut(key)?;
+                Ok(count(
+                    members
+                        .iter()
+                        .filter(|&&member| set.insert(member.to_vec()))
+                        .count(),
+                ))
+            }
+            ("SREM", &[key, ref members @ ..]) => {
+                let set = self.set_mut(key)?;
+                Ok(count(
+                    members.iter().filter(|&&member| set.remove(member)).count(),
+                ))
+            }
+            ("SMEMBERS", &[key]) => Ok(Value::Array(
+                self.members(key)?
+                    .into_iter()
+                    .flatten()
+                    .map(|member| bulk(member))
+                    .collect(),
+            )),
+            ("ZADD", &[key, score, member]) => {
+                let added = self
+                    .sorted_set_mut(key)?
+                    .insert(member.to_vec(), float(score)?)
+                    .is_none();
+                Ok(count(usize::from(added)))
+            }
+            ("ZREM", &[key, ref members @ ..]) => {
+                let sorted_set = self.sorted_set_mut(key)?;
+                Ok(count(
+                    members
+                        .iter()
+                        .filter(|&&member| sorted_set.remove(member).is_some())
+                        .count(),
+                ))
+            }
+            ("ZCARD", &[key]) => Ok(count(self.sorted_set(key)?.map_or(0, BTreeMap::len))),
+            ("ZRANGE", &[key, start_index, stop_index]) => {
+                let mut members: Vec<(&Vec<u8>, f64)> = self
+                    .sorted_set(key)?
+                    .into_iter()
+                    .flatten()
+                    .map(|(member, &score)| (member, score))
+                    .collect();
+                members.sort_by(|&(first, first_score), &(second, second_score)| {
+                    first_score
+                        .total_cmp(&second_score)
+                        .then_with(|| first.cmp(second))
+                });
+                let start = position(integer(start_index)?, members.len());
+                let stop = position(integer(stop_index)?, members.len());
+                Ok(Value::Array(
+                    members
+                        .into_iter()
+                        .skip(start)
+                        .take(stop.saturating_add(1).saturating_sub(start))
+                        .map(|(member, _)| bulk(member))
+                        .collect(),
+                ))
+            }
+            ("ZREMRANGEBYSCORE", &[key, min_score, max_score]) => {
+                let (min, max) = (float(min_score)?, float(max_score)?);
+                let sorted_set = self.sorted_set_mut(key)?;
+                let before = sorted_set.len();
+                sorted_set.retain(|_, &mut score| score < min || score > max);
+                Ok(count(before.saturating_sub(sorted_set.len())))
+            }
+            ("SCAN", options) => {
+                let pattern = options
+                    .iter()
+                    .skip_while(|&&arg| !arg.eq_ignore_ascii_case(b"MATCH"))
+                    .nth(1)
+                    .copied()
+                    .unwrap_or(b"*");
+                let mut keys: Vec<&Vec<u8>> = self
+                    .entries
+                    .keys()
+                    .filter(|key| glob_matches(pattern, key))
+                    .collect();
+                keys.sort();
+                Ok(Value::Array(vec![
+                    bulk(b"0"),
+                    Value::Array(keys.into_iter().map(|key| bulk(key)).collect()),
+                ]))
+            }
+            // The only script the store runs, see `ROTATE_CSRF_SCRIPT`.
+            ("EVAL", &[script, b"1", key, current_csrf, new_csrf])
+                if script == ROTATE_CSRF_SCRIPT.as_bytes() =>
+            {
+                let current = self
+                    .hash(key)?
+                    .and_then(|hash| hash.get(b"csrf".as_slice()))
+                    .cloned();
+                if current.as_deref() != Some(current_csrf) {
+                    return Ok(current.map_or(Value::Nil, |csrf| bulk(&csrf)));
+                }
+                let hash = self.hash_mut(key)?;
+                hash.insert(b"csrf".to_vec(), new_csrf.to_vec());
+                hash.insert(b"previous_csrf".to_vec(), current_csrf.to_vec());
+                Ok(bulk(new_csrf))
+            }
+            _ => Err(unsupported(name)),
+        }
+    }
+
+    /// When a key given an expiry of `seconds` (an integer argument) from now
+    /// expires.
+    fn expiry(seconds: &[u8]) -> RedisResult<Instant> {
+        let secs = u64::try_from(integer(seconds)?).map_err(|_err| invalid_argument())?;
+        Instant::now()
+            .checked_add(Duration::from_secs(secs))
+            .ok_or_else(invalid_argument)
+    }
+
+    /// Store a value under a key, replacing any existing value and expiry.
+    fn insert(&mut self, key: &[u8], data: Data, expires_at: Option<Instant>) {
+        self.entries
+            .insert(key.to_vec(), Entry { data, expires_at });
+    }
+
+    /// Apply SET, with any of its NX, XX and EX options.
+    fn set(&mut self, key: &[u8], value: &[u8], options: &[&[u8]]) -> RedisResult<Value> {
+        let (mut only_new, mut only_existing, mut expires_at) = (false, false, None);
+        let mut remaining = options.iter();
+        while let Some(option) = remaining.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"NX" => only_new = true,
+                b"XX" => only_existing = true,
+                b"EX" => {
+                    let seconds = remaining.next().ok_or_else(invalid_argument)?;
+                    expires_at = Some(Self::expiry(seconds)?);
+                }
+                _ => return Err(unsupported("SET option")),
+            }
+        }
+        let exists = self.entries.contains_key(key);
+        if (only_new && exists) || (only_existing && !exists) {
+            return Ok(Value::Nil);
+        }
+        self.insert(key, Data::String(value.to_vec()), expires_at);
+        Ok(Value::Okay)
+    }
+
+    /// Apply EXPIRE, with either of its NX and XX options.
+    fn expire(&mut self, key: &[u8], seconds: &[u8], options: &[&[u8]]) -> RedisResult<Value> {
+        let expires_at = Self::expiry(seconds)?;
+        let Some(entry) = self.entries.get_mut(key) else {
+            return Ok(Value::Int(0));
+        };
+        let applies = match *options {
+            [] => true,
+            [option] if option.eq_ignore_ascii_case(b"NX") => entry.expires_at.is_none(),
+            [option] if option.eq_ignore_ascii_case(b"XX") => entry.expires_at.is_some(),
+            _ => return Err(unsupported("EXPIRE option")),
+        };
+        if applies {
+            entry.expires_at = Some(expires_at);
+        }
+        Ok(count(usize::from(applies)))
+    }
+
+    /// The reply to TTL: the seconds until a key expires (rounded, as Redis
+    /// does), -1 if it never expires, or -2 if it does not exist.
+    fn ttl(&self, key: &[u8]) -> i64 {
+        let Some(entry) = self.entries.get(key) else {
+            return -2;
+        };
+        let Some(expires_at) = entry.expires_at else {
+            return -1;
+        };
+        let remaining = expires_at.saturating_duration_since(Instant::now());
+        let seconds = if remaining.subsec_millis() >= 500 {
+            remaining.as_secs().saturating_add(1)
+        } else {
+            remaining.as_secs()
+        };
+        i64::try_from(seconds).unwrap_or(i64::MAX)
+    }
+
+    /// The string stored under a key, if any.
+    fn string(&self, key: &[u8]) -> RedisResult<Option<&[u8]>> {
+        let Some(entry) = self.entries.get(key) else {
+            return Ok(None);
+        };
+        match entry.data {
+            Data::String(ref value) => Ok(Some(value)),
+            Data::Hash(_) | Data::Set(_) | Data::SortedSet(_) => Err(wrong_type()),
+        }
+    }
+
+    /// The hash stored under a key, if any.
+    fn hash(&self, key: &[u8]) -> RedisResult<Option<&BTreeMap<Vec<u8>, Vec<u8>>>> {
+        let Some(entry) = self.entries.get(key) else {
+            return Ok(None);
+        };
+        match entry.data {
+            Data::Hash(ref hash) => Ok(Some(hash)),
+            Data::String(_) | Data::Set(_) | Data::SortedSet(_) => Err(wrong_type()),
+        }
+    }
+
+    /// The hash stored under a key, created empty if there is none.
+    fn hash_mut(&mut self, key: &[u8]) -> RedisResult<&mut BTreeMap<Vec<u8>, Vec<u8>>> {
+        match self.entry(key, || Data::Hash(BTreeMap::new())).data {
+            Data::Hash(ref mut hash) => Ok(hash),
+            Data::String(_) | Data::Set(_) | Data::SortedSet(_) => Err(wrong_type()),
+        }
+    }
+
+    /// The members of the set stored under a key, if any.
+    fn members(&self, key: &[u8]) -> RedisResult<Option<&BTreeSet<Vec<u8>>>> {
+        let Some(entry) = self.entries.get(key) else {
+            return Ok(None);
+        };
+        match entry.data {
+            Data::Set(ref set) => Ok(Some(set)),
+            Data::String(_) | Data::Hash(_) | Data::SortedSet(_) => Err(wrong_type()),
+        }
+    }
+
+    /// The set stored under a key, created empty if there is none.
+    fn set_mut(&mut self, key: &[u8]) -> RedisResult<&mut BTreeSet<Vec<u8>>> {
+        match self.entry(key, || Data::Set(BTreeSet::new())).data {
+            Data::Set(ref mut set) => Ok(set),
+            Data::String(_) | Data::Hash(_) | Data::SortedSet(_) => Err(wrong_type()),
+        }
+    }
+
+    /// The sorted set stored under a key, if any.
+    fn sorted_set(&self, key: &[u8]) -> RedisResult<Option<&BTreeMap<Vec<u8>, f64>>> {
+        let Some(entry) = self.entries.get(key) else {
+            return Ok(None);
+        };
+        match entry.data {
+            Data::SortedSet(ref sorted_set) => Ok(Some(sorted_set)),
+            Data::String(_) | Data::Hash(_) | Data::Set(_) => Err(wrong_type()),
+        }
+    }
+
+    /// The sorted set stored under a key, created empty if there is none.
+    fn sorted_set_mut(&mut self, key: &[u8]) -> RedisResult<&mut BTreeMap<Vec<u8>, f64>> {
+        match self.entry(key, || Data::SortedSet(BTreeMap::new())).data {
+            Data::SortedSet(ref mut sorted_set) => Ok(sorted_set),
+            Data::String(_) | Data::Hash(_) | Data::Set(_) => Err(wrong_type()),
+        }
+    }
+
+    /// The entry under a key, created with the value from `empty` and no
+    /// expiry if there is none.
+    fn entry(&mut self, key: &[u8], empty: impl FnOnce() -> Data) -> &mut Entry {
+        self.entries.entry(key.to_vec()).or_insert_with(|| Entry {
+            data: empty(),
+            expires_at: None,
+        })
+    }
+}
@@ -1,28 +1,109 @@
 //! Logic for session handling. Creating, managing and revoking session tokens.
 use crate::{
     constants::sessions::{
-        ADMIN_SESSION_TIMEOUT, PREAUTH_SESSION_TIMEOUT, REGISTRATION_SESSION_TIMEOUT,
-        SESSION_TIMEOUT,
+        PREAUTH_SESSION_TIMEOUT, REGISTRATION_SESSION_TIMEOUT, SESSION_RECONCILE_INTERVAL,
+        SESSION_REFRESH_RESETS_TIMEOUT,
     },
     db::models::appuser::AppUserInsert,
 };
+#[cfg(test)]
+pub mod fake_store;
+mod seal;
 pub mod store;
-use core::fmt::Write as _;
+use core::{fmt::Write as _, time::Duration};
+use sha2::{Digest as _, Sha256};
 use store::{AuthenticatedSessionData, Connection, SessionInfo};
+use subtle::ConstantTimeEq as _;
+use tokio::time::sleep;
 use uuid::Uuid;
 
 /// Generates a new 24-byte token using a CSPRNG.
-fn generate_token() -> String {
+pub fn generate_token() -> String {
     let mut token_buf: [u8; 24] = [0; 24];
     getrandom::fill(&mut token_buf).expect("Error getting OS random. Critical, aborting.");
     token_buf
         .into_iter()
         .fold(String::new(), |mut acc: String, x: u8| {
-            write!(acc, "{x:x}").unwrap();
+            write!(acc, "{x:x}").expect("Writing to a String cannot fail.");
             acc
         })
 }
 
+/// Generates a new 6-digit numeric code using a CSPRNG.
+#[expect(
+    clippy::little_endian_bytes,
+    reason = "Random bytes are equally random in any byte order."
+)]
+fn generate_numeric_code() -> String {
+    // The largest multiple of 1,000,000 representable in a u32. Values at or
+    // above this are rejected, so that every code is equally likely.
+    const LIMIT: u32 = 4_294_000_000;
+    loop {
+        let mut buf: [u8; 4] = [0; 4];
+        getrandom::fill(&mut buf).expect("Error getting OS random. Critical, aborting.");
+        let value = u32::from_le_bytes(buf);
+        if value < LIMIT {
+            let code = value
+                .checked_rem(1_000_000)
+                .expect("Remainder by a non-zero constant cannot fail.");
+            return format!("{code:06}");
+        }
+    }
+}
+
+/// Hash a one-time code for storage, so that it can't be read back out of
+/// the session store.
+fn hash_one_time_code(code: &str) -> String {
+    Sha256::digest(code)
+        .into_iter()
+        .fold(String::new(), |mut acc: String, byte: u8| {
+            write!(acc, "{byte:02x}").expect("Writing to a String cannot fail.");
+            acc
+        })
+}
+
+/// Derive the name under which attempts involving a sensitive identifier
+/// (e.g. an email address or a token) are rate limited, so that the
+/// identifier itself is never held in the session store.
+pub fn rate_limit_subject(identifier: &str) -> String {
+    hash_one_time_code(identifier)
+}
+
+/// Derive the short, non-reversible reference used to identify a session to
+/// clients without exposing its token.
+fn session_reference(token: &str) -> String {
+    Sha256::digest(token)
+        .into_iter()
+        .take(8)
+        .fold(String::new(), |mut acc: String, byte: u8| {
+            write!(acc, "{byte:02x}").expect("Writing to a String cannot fail.");
+            acc
+        })
+}
+
+#[derive(Clone, Copy)]
+/// The channels through which a one-time MFA code can be delivered. A
+/// session may have one outstanding code of each kind.
+pub enum OneTimeCodeKind {
+    /// A code sent by SMS to the user's phone.
+    Sms,
+    /// A code sent to the user's email address.
+    Email,
+    /// A code sent by SMS to verify the user's phone number.
+    PhoneVerification,
+}
+
+impl OneTimeCodeKind {
+    /// The name used to store codes of this kind.
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Sms => "sms",
+            Self::Email => "email",
+            Self::PhoneVerification => "phone",
+        }
+    }
+}
+
 #[derive(Clone)]
 /// A session, associating a session token with a given user. *NOT* guaranteed
 /// to be fully authenticated. Look at `AuthenticatedSession` for that.
@@ -35,7 +116,37 @@ pub struct BaseSession {
                                       // with the underlying store much easier
 }
 
+/// The kinds of session a token can identify, according to what is recorded
+/// for it in the session store.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SessionKind {
+    /// A session for a user who has not yet completed MFA.
+    PreAuthentication,
+    /// A session for a user part way through signing up.
+    Registration,
+    /// A fully authenticated customer session.
+    Customer,
+    /// A fully authenticated administrative session.
+    Administrator,
+}
+
+impl SessionKind {
+    /// A name for the kind of session, for logging.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::PreAuthentication => "preauthentication",
+            Self::Registration => "registration",
+            Self::Customer => "customer",
+            Self::Administrator => "administrator",
+        }
+    }
+}
+
 pub trait SessionTrait: Send + Sync + Clone + Sized {
+    /// The kinds of session which this type may represent. A route guarded by
+    /// `session_middleware` for this type accepts only these kinds, which is
+    /// checked against every session loaded, independently of `get`.
+    const SESSION_KINDS: &'static [SessionKind];
     /// Get an instance of this session type given the corresponding session token.
     async fn get(
         token: &str,
@@ -50,6 +161,10 @@ pub trait SessionTrait: Send + Sync + Clone + Sized {
     ) -> Result<(), errors::SessionStorageError>;
     /// Get this session's CSRF token.
     fn csrf_token(&self) -> String;
+    /// Get the CSRF token this session replaced when it was refreshed, if any.
+    fn previous_csrf_token(&self) -> Option<String>;
+    /// Get the kind of this session, from the data stored for it.
+    fn kind(&self) -> SessionKind;
 }
 
 /// A session which is guaranteed to have been fully authenticated. Can be
@@ -98,7 +213,21 @@ pub enum GenericAuthenticatedSession {
     Administrator(AdministratorSession),
 }
 
+impl From<CustomerSession> for GenericAuthenticatedSession {
+    fn from(session: CustomerSession) -> Self {
+        Self::Customer(session)
+    }
+}
+
+impl From<AdministratorSession> for GenericAuthenticatedSession {
+    fn from(session: AdministratorSession) -> Self {
+        Self::Administrator(session)
+    }
+}
+
 impl SessionTrait for GenericAuthenticatedSession {
+    const SESSION_KINDS: &'static [SessionKind] =
+        &[SessionKind::Customer, SessionKind::Administrator];
     async fn get(
         token: &str,
         session_store_conn: &mut store::Connection,
@@ -143,6 +272,223 @@ impl SessionTrait for GenericAuthenticatedSession {
         })) = *self;
         session_info.csrf_token()
     }
+    fn previous_csrf_token(&self) -> Option<String> {
+        self.base().info().previous_csrf_token()
+    }
+    fn kind(&self) -> SessionKind {
+        self.base().kind()
+    }
+}
+
+/// Revoke every authenticated session belonging to a user, logging them out
+/// everywhere. Returns the number of sessions revoked.
+pub async fn revoke_user_sessions(
+    user_id: Uuid,
+    session_store_conn: &mut store::Connection,
+) -> Result<u32, errors::SessionStorageError> {
+    session_store_conn.delete_user_sessions(user_id).await
+}
+
+/// A live authenticated session, as presented to its owner.
+pub struct SessionSummary {
+    /// The session's reference (see `GenericAuthenticatedSession::reference`).
+    pub reference: String,
+    /// The remaining lifetime of the session in seconds, if it has an expiry.
+    pub expires_in: Option<u32>,
+}
+
+/// List every live authenticated session belonging to a user.
+pub async fn list_user_sessions(
+    user_id: Uuid,
+    session_store_conn: &mut store::Connection,
+) -> Result<Vec<SessionSummary>, errors::SessionStorageError> {
+    let tokens = session_store_conn.user_session_tokens(user_id).await?;
+    let mut summaries = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let expires_in = session_store_conn
+            .ttl(&token, store::SessionType::Authenticated)
+            .await?;
+        summaries.push(SessionSummary {
+            reference: session_reference(&token),
+            expires_in,
+        });
+    }
+    Ok(summaries)
+}
+
+/// Revoke a single authenticated session belonging to a user, identified by
+/// its reference. Returns false if the user has no session with that reference.
+pub async fn revoke_user_session(
+    user_id: Uuid,
+    reference: &str,
+    session_store_conn: &mut store::Connection,
+) -> Result<bool, errors::SessionStorageError> {
+    let tokens = session_store_conn.user_session_tokens(user_id).await?;
+    let maybe_token = tokens.into_iter().find(|token| {
+        bool::from(
+            session_reference(token)
+                .as_bytes()
+                .ct_eq(reference.as_bytes()),
+        )
+    });
+    match maybe_token {
+        Some(token) => {
+            session_store_conn
+                .delete(&token, store::SessionType::Authenticated)
+                .await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Remove the entries from the store's indexes of sessions (by user, and by
+/// client for pending sessions) which refer to sessions which have expired or
+/// were deleted without being removed from their indexes, e.g. because the
+/// API stopped part way through. Returns the number of entries removed.
+///
+/// Only the session store is reconciled. Stock is taken out when an order is
+/// confirmed, in the transaction which confirms it, so there is no
+/// reservation to release if the order is deleted. The Stripe events and
+/// pending refunds which carry idempotency keys are kept, since refunds are
+/// issued against the recorded payments.
+pub async fn reconcile_indexes(
+    session_store_conn: &mut store::Connection,
+) -> Result<u32, errors::SessionStorageError> {
+    let users = session_store_conn.reconcile_user_indexes().await?;
+    let pending = session_store_conn.reconcile_pending_indexes().await?;
+    Ok(users.saturating_add(pending))
+}
+
+/// Reconcile the session store's indexes (see `reconcile_indexes`) every
+/// `SESSION_RECONCILE_INTERVAL` seconds, forever. Intended to be spawned as a
+/// background task at startup. Every instance of the API may run this, but a
+/// lock in the store ensures only one of them reconciles in each interval.
+pub async fn run_reconciler(mut session_store_conn: store::Connection) -> ! {
+    loop {
+        sleep(Duration::from_secs(*SESSION_RECONCILE_INTERVAL)).await;
+        match session_store_conn
+            .try_lock("reconcile-indexes", *SESSION_RECONCILE_INTERVAL)
+            .await
+        {
+            Ok(true) => match reconcile_indexes(&mut session_store_conn).await {
+                Ok(0) => {}
+                Ok(removed) => tracing::info!("Removed {removed} stale session index entries."),
+                Err(err) => tracing::error!("Error reconciling session indexes, will retry: {err}"),
+            },
+            Ok(false) => {}
+            Err(err) => {
+                tracing::error!("Error taking session reconciliation lock, will retry: {err}");
+            }
+        }
+    }
+}
+
+/// Count a view of a product by a session, unless the session viewed it
+/// recently. Returns whether the view was counted.
+pub async fn record_product_view(
+    product_id: Uuid,
+    session: &GenericAuthenticatedSession,
+    session_store_conn: &mut store::Connection,
+) -> Result<bool, errors::SessionStorageError> {
+    session_store_conn
+        .record_product_view(product_id, &session.reference())
+        .await
+}
+
+/// Stage the view counts of every product viewed since they were last staged
+/// as a new batch, and return every staged batch, by its ID, which has not
+/// yet been discarded with `discard_product_views`.
+pub async fn stage_product_views(
+    session_store_conn: &mut store::Connection,
+) -> Result<Vec<(Uuid, Vec<(Uuid, i64)>)>, errors::SessionStorageError> {
+    session_store_conn.stage_product_views().await?;
+    session_store_conn.staged_product_views().await
+}
+
+/// Discard a staged batch of view counts which has been persisted.
+pub async fn discard_product_views(
+    batch_id: Uuid,
+    session_store_conn: &mut store::Connection,
+) -> Result<(), errors::SessionStorageError> {
+    session_store_conn.discard_product_views(batch_id).await
+}
+
+/// Get the current version of the product listings, which is advanced by
+/// `bump_product_list_version` whenever a product changes.
+pub async fn product_list_version(
+    session_store_conn: &mut store::Connection,
+) -> Result<u64, errors::SessionStorageError> {
+    session_store_conn.product_list_version().await
+}
+
+/// Advance the version of the product listings, so that anything cached
+/// against the previous version is treated as stale.
+pub async fn bump_product_list_version(
+    session_store_conn: &mut store::Connection,
+) -> Result<u64, errors::SessionStorageError> {
+    session_store_conn.bump_product_list_version().await
+}
+
+/// Issue a token granting a guest access to a single order, without a full
+/// session. Returns the token.
+pub async fn create_guest_order_token(
+    order_id: Uuid,
+    session_store_conn: &mut store::Connection,
+) -> Result<String, errors::SessionStorageError> {
+    let token = generate_token();
+    session_store_conn.set_guest_order(&token, order_id).await?;
+    Ok(token)
+}
+
+/// Get the ID of the order which a guest order token grants access to, or
+/// None if the token is invalid or has expired.
+pub async fn get_guest_order_id(
+    token: &str,
+    session_store_conn: &mut store::Connection,
+) -> Result<Option<Uuid>, errors::SessionStorageError> {
+    session_store_conn.get_guest_order(token).await
+}
+
+/// Issue a single-use token with which a user can log in without their
+/// primary credential, to be sent to them as a magic link. Returns the token.
+pub async fn create_magic_link_token(
+    user_id: Uuid,
+    session_store_conn: &mut store::Connection,
+) -> Result<String, errors::SessionStorageError> {
+    let token = generate_token();
+    session_store_conn.set_magic_link(&token, user_id).await?;
+    Ok(token)
+}
+
+/// Consume a magic link token, returning the ID of the user it was issued to,
+/// or None if the token is invalid, expired or has already been used.
+pub async fn take_magic_link_user(
+    token: &str,
+    session_store_conn: &mut store::Connection,
+) -> Result<Option<Uuid>, errors::SessionStorageError> {
+    session_store_conn.take_magic_link(token).await
+}
+
+/// Issue a single-use token proving access to `email`, to be sent to it so
+/// that the guests who placed orders with it can be upgraded to a full
+/// account. Returns the token.
+pub async fn create_guest_upgrade_token(
+    email: &str,
+    session_store_conn: &mut store::Connection,
+) -> Result<String, errors::SessionStorageError> {
+    let token = generate_token();
+    session_store_conn.set_guest_upgrade(&token, email).await?;
+    Ok(token)
+}
+
+/// Consume a guest upgrade token, returning the email it was issued for, or
+/// None if the token is invalid, expired or has already been used.
+pub async fn take_guest_upgrade_email(
+    token: &str,
+    session_store_conn: &mut store::Connection,
+) -> Result<Option<String>, errors::SessionStorageError> {
+    session_store_conn.take_guest_upgrade(token).await
 }
 
 impl GenericAuthenticatedSession {
@@ -153,9 +499,93 @@ impl GenericAuthenticatedSession {
             Self::Administrator(ref admin) => admin.user_id(),
         }
     }
+    /// The short reference identifying this session in session listings,
+    /// which can't be used to recover the session token.
+    pub fn reference(&self) -> String {
+        session_reference(&self.base().token)
+    }
+    /// The underlying session, whatever the role.
+    const fn base(&self) -> &BaseSession {
+        match *self {
+            Self::Customer(CustomerSession { ref session })
+            | Self::Administrator(AdministratorSession { ref session }) => session,
+        }
+    }
+    /// Generate a new one-time code of a given kind for this session (see
+    /// `BaseSession::issue_one_time_code`).
+    pub async fn issue_one_time_code(
+        &self,
+        kind: OneTimeCodeKind,
+        session_store_conn: &mut store::Connection,
+    ) -> Result<Option<String>, errors::SessionStorageError> {
+        self.base()
+            .issue_one_time_code(kind, session_store_conn)
+            .await
+    }
+    /// Check a code against this session's one-time code of a given kind (see
+    /// `BaseSession::verify_one_time_code`).
+    pub async fn verify_one_time_code(
+        &self,
+        kind: OneTimeCodeKind,
+        code: &str,
+        session_store_conn: &mut store::Connection,
+    ) -> Result<bool, errors::SessionStorageError> {
+        self.base()
+            .verify_one_time_code(kind, code, session_store_conn)
+            .await
+    }
+    /// The administrator impersonating the user through this session, if any.
+    /// Always None for administrative sessions.
+    pub fn impersonated_by(&self) -> Option<Uuid> {
+        match *self {
+            Self::Customer(ref customer) => customer.impersonated_by(),
+            Self::Administrator(_) => None,
+        }
+    }
+    /// Whether this session was created with "remember me". Always false for
+    /// administrative sessions.
+    pub fn remember_me(&self) -> bool {
+        match *self {
+            Self::Customer(ref customer) => customer.remember_me(),
+            Self::Administrator(_) => false,
+        }
+    }
+    /// Rotate this session's token and CSRF token, for the same user and role.
+    /// Consumes the original session, whose token will no longer be valid. The
+    /// new session's lifetime is determined by `SESSION_REFRESH_RESETS_TIMEOUT`.
+    pub async fn refresh(
+        self,
+        session_store_conn: &mut store::Connection,
+    ) -> Result<Self, errors::SessionStorageError> {
+        Ok(match self {
+            Self::Customer(CustomerSession { session }) => Self::Customer(CustomerSession {
+                session: session.rotate(session_store_conn).await?,
+            }),
+            Self::Administrator(AdministratorSession { session }) => {
+                Self::Administrator(AdministratorSession {
+                    session: session.rotate(session_store_conn).await?,
+                })
+            }
+        })
+    }
+    /// Replace this session's CSRF token with a new one, leaving its token and
+    /// expiry unchanged. If another request has already replaced the CSRF token
+    /// this session was read with, that replacement is kept instead, so
+    /// concurrent rotations agree on a single token. Returns the session's CSRF
+    /// token afterwards, or None if the session no longer exists.
+    pub async fn rotate_csrf(
+        &self,
+        session_store_conn: &mut store::Connection,
+    ) -> Result<Option<String>, errors::SessionStorageError> {
+        let base = self.base();
+        session_store_conn
+            .rotate_csrf(&base.token, &base.info().csrf_token(), &generate_token())
+            .await
+    }
 }
 
 impl SessionTrait for AdministratorSession {
+    const SESSION_KINDS: &'static [SessionKind] = &[SessionKind::Administrator];
     async fn get(
         token: &str,
         session_store_conn: &mut store::Connection,
@@ -186,6 +616,12 @@ impl SessionTrait for AdministratorSession {
     fn csrf_token(&self) -> String {
         self.session.info().csrf_token()
     }
+    fn previous_csrf_token(&self) -> Option<String> {
+        self.session.info().previous_csrf_token()
+    }
+    fn kind(&self) -> SessionKind {
+        self.session.kind()
+    }
 }
 
 impl AdministratorSession {
@@ -200,6 +636,7 @@ impl AdministratorSession {
 }
 
 impl SessionTrait for CustomerSession {
+    const SESSION_KINDS: &'static [SessionKind] = &[SessionKind::Customer];
     async fn get(
         token: &str,
         session_store_conn: &mut store::Connection,
@@ -234,6 +671,12 @@ impl SessionTrait for CustomerSession {
     fn csrf_token(&self) -> String {
         self.session.info().csrf_token()
     }
+    fn previous_csrf_token(&self) -> Option<String> {
+        self.session.info().previous_csrf_token()
+    }
+    fn kind(&self) -> SessionKind {
+        self.session.kind()
+    }
 }
 
 impl CustomerSession {
@@ -245,19 +688,71 @@ impl CustomerSession {
             .expect("Attempted to convert a registration session to an authentication session.")
             .user_id
     }
+    /// Whether this session was created with "remember me", and so is long-lived.
+    pub fn remember_me(&self) -> bool {
+        self.session
+            .info()
+            .as_auth()
+            .expect("Attempted to convert a registration session to an authentication session.")
+            .remember_me
+    }
+    /// The administrator impersonating the user through this session, if it
+    /// was created with `CustomerSession::impersonate`.
+    pub fn impersonated_by(&self) -> Option<Uuid> {
+        self.session.info().as_auth()?.impersonated_by
+    }
+    /// Create a session through which an administrator can act as a customer,
+    /// to see exactly what they see. The session is marked with the
+    /// administrator's ID, and lasts only `IMPERSONATION_SESSION_TIMEOUT`.
+    /// Should ONLY be done if the target user has been checked not to be an
+    /// administrator.
+    pub async fn impersonate(
+        user_id: Uuid,
+        administrator_id: Uuid,
+        session_store_conn: &mut store::Connection,
+    ) -> Result<Self, errors::SessionStorageError> {
+        let data = AuthenticatedSessionData {
+            user_id,
+            admin: false,
+            remember_me: false,
+            previous_csrf: None,
+            impersonated_by: Some(administrator_id),
+        };
+        let timeout = data.timeout();
+        let session = BaseSession::create(
+            SessionInfo::Authenticated {
+                csrf: generate_token(),
+                data,
+            },
+            session_store_conn,
+        )
+        .await?;
+        session.set_expiry(timeout, session_store_conn).await?;
+        Ok(Self { session })
+    }
 }
 
 impl PreAuthenticationSession {
-    /// Create a new preauthentication session given a user ID.
+    /// Create a new preauthentication session given a user ID, whether the
+    /// user asked to be remembered once fully authenticated, and whether their
+    /// first factor proved only that they can read their email. Fails if the
+    /// client already has too many sessions in progress.
     pub async fn create(
         user_id: Uuid,
+        remember_me: bool,
+        email_first_factor: bool,
+        client: &str,
         session_store_conn: &mut store::Connection,
-    ) -> Result<Self, errors::SessionStorageError> {
+    ) -> Result<Self, errors::PendingSessionError> {
         let csrf = generate_token();
         let session = BaseSession::create(
             SessionInfo::PreAuthentication {
                 csrf,
-                data: store::PreAuthenticationSessionData { user_id },
+                data: store::PreAuthenticationSessionData {
+                    user_id,
+                    remember_me,
+                    email_first_factor,
+                },
             },
             session_store_conn,
         )
@@ -265,10 +760,13 @@ impl PreAuthenticationSession {
         session
             .set_expiry(PREAUTH_SESSION_TIMEOUT, session_store_conn)
             .await?;
+        session
+            .track_pending(client, PREAUTH_SESSION_TIMEOUT, session_store_conn)
+            .await?;
         Ok(Self { session })
     }
     /// Promote this preauthentication session to a fully authenticated one.
-    /// Consumes the original session, who's token will no longer be valid,
+    /// Consumes the original session, whose token will no longer be valid,
     /// and generates a completely new session.
     pub async fn promote(
         self,
@@ -278,24 +776,24 @@ impl PreAuthenticationSession {
             .delete(&self.session.token, store::SessionType::PreAuthentication)
             .await?;
         let csrf = generate_token();
+        let session_info = self.session.info();
+        let pre_auth_data = session_info
+            .as_pre_auth()
+            .expect("Attempted to promote a non-preauthentication session to an authenticated one");
+        let data = AuthenticatedSessionData {
+            user_id: pre_auth_data.user_id,
+            admin: false,
+            remember_me: pre_auth_data.remember_me,
+            previous_csrf: None,
+            impersonated_by: None,
+        };
+        let timeout = data.timeout();
         let session = BaseSession::create(
-            SessionInfo::Authenticated {
-                csrf,
-                data: AuthenticatedSessionData {
-                    user_id: self.session
-                        .info()
-                        .as_pre_auth()
-                        .expect("Attempted to promote a non-preauthentication session to an authenticated one")
-                        .user_id,
-                    admin: false
-                }
-            },
+            SessionInfo::Authenticated { csrf, data },
             session_store_conn,
         )
         .await?;
-        session
-            .set_expiry(SESSION_TIMEOUT, session_store_conn)
-            .await?;
+        session.set_expiry(timeout, session_store_conn).await?;
         Ok(CustomerSession { session })
     }
 
@@ -309,24 +807,49 @@ impl PreAuthenticationSession {
             .delete(&self.session.token, store::SessionType::PreAuthentication)
             .await?;
         let csrf = generate_token();
+        // Administrative sessions always use the shorter admin timeout, so
+        // "remember me" is deliberately ignored here.
+        let data = AuthenticatedSessionData {
+            user_id: self.session.info().as_pre_auth().expect(
+                "Attempted to promote non-preauthentication registration session to an administrative session.",
+            ).user_id,
+            admin: true,
+            remember_me: false,
+            previous_csrf: None,
+            impersonated_by: None,
+        };
+        let timeout = data.timeout();
         let session = BaseSession::create(
-            SessionInfo::Authenticated {
-                csrf,
-                data: AuthenticatedSessionData {
-                    user_id: self.session.info().as_pre_auth().expect(
-                        "Attempted to promote non-preauthentication registration session to an administrative session.",
-                    ).user_id,
-                    admin: true
-                }
-            },
+            SessionInfo::Authenticated { csrf, data },
             session_store_conn,
         )
         .await?;
-        session
-            .set_expiry(ADMIN_SESSION_TIMEOUT, session_store_conn)
-            .await?;
+        session.set_expiry(timeout, session_store_conn).await?;
         Ok(AdministratorSession { session })
     }
+    /// Generate a new one-time code of a given kind for this session (see
+    /// `BaseSession::issue_one_time_code`).
+    pub async fn issue_one_time_code(
+        &self,
+        kind: OneTimeCodeKind,
+        session_store_conn: &mut store::Connection,
+    ) -> Result<Option<String>, errors::SessionStorageError> {
+        self.session
+            .issue_one_time_code(kind, session_store_conn)
+            .await
+    }
+    /// Check a code against this session's one-time code of a given kind (see
+    /// `BaseSession::verify_one_time_code`).
+    pub async fn verify_one_time_code(
+        &self,
+        kind: OneTimeCodeKind,
+        code: &str,
+        session_store_conn: &mut store::Connection,
+    ) -> Result<bool, errors::SessionStorageError> {
+        self.session
+            .verify_one_time_code(kind, code, session_store_conn)
+            .await
+    }
     /// Get the user ID associated with this session.
     pub fn user_id(&self) -> Uuid {
         self.session
@@ -335,9 +858,20 @@ impl PreAuthenticationSession {
             .expect("Attempted to convert a registration session to a preauth session.")
             .user_id
     }
+    /// Whether the user's first factor was proof that they can read their
+    /// email (i.e. a magic link), in which case an email code can't be used
+    /// as their second factor.
+    pub fn email_first_factor(&self) -> bool {
+        self.session
+            .info()
+            .as_pre_auth()
+            .expect("Attempted to convert a registration session to a preauth session.")
+            .email_first_factor
+    }
 }
 
 impl SessionTrait for PreAuthenticationSession {
+    const SESSION_KINDS: &'static [SessionKind] = &[SessionKind::PreAuthentication];
     async fn get(
         token: &str,
         session_store_conn: &mut store::Connection,
@@ -366,9 +900,16 @@ impl SessionTrait for PreAuthenticationSession {
     fn csrf_token(&self) -> String {
         self.session.info().csrf_token()
     }
+    fn previous_csrf_token(&self) -> Option<String> {
+        self.session.info().previous_csrf_token()
+    }
+    fn kind(&self) -> SessionKind {
+        self.session.kind()
+    }
 }
 
 impl SessionTrait for RegistrationSession {
+    const SESSION_KINDS: &'static [SessionKind] = &[SessionKind::Registration];
     async fn get(
         token: &str,
         session_store_conn: &mut store::Connection,
@@ -393,19 +934,29 @@ impl SessionTrait for RegistrationSession {
     fn csrf_token(&self) -> String {
         self.session.info().csrf_token()
     }
+    fn previous_csrf_token(&self) -> Option<String> {
+        self.session.info().previous_csrf_token()
+    }
+    fn kind(&self) -> SessionKind {
+        self.session.kind()
+    }
 }
 
 impl RegistrationSession {
-    /// Create a registration session from a set of user data.
+    /// Create a registration session from a set of user data. Fails if the
+    /// client already has too many sessions in progress.
     pub async fn create(
         user_data: AppUserInsert,
+        client: &str,
         session_store_conn: &mut store::Connection,
-    ) -> Result<Self, errors::SessionStorageError> {
+    ) -> Result<Self, errors::PendingSessionError> {
         let csrf = generate_token();
         let session = BaseSession::create(
             store::SessionInfo::Registration {
                 csrf,
-                data: store::RegistrationSessionData { user_data },
+                data: store::RegistrationSessionData {
+                    user_data: Box::new(user_data),
+                },
             },
             session_store_conn,
         )
@@ -413,6 +964,9 @@ impl RegistrationSession {
         session
             .set_expiry(REGISTRATION_SESSION_TIMEOUT, session_store_conn)
             .await?;
+        session
+            .track_pending(client, REGISTRATION_SESSION_TIMEOUT, session_store_conn)
+            .await?;
         Ok(Self { session })
     }
     /// Return the user data associated with this registration session.
@@ -422,6 +976,7 @@ impl RegistrationSession {
             .as_registration()
             .expect("Attempted to convert an authentication session to a registration session.")
             .user_data
+            .as_ref()
             .clone()
     }
 }
@@ -467,6 +1022,84 @@ impl BaseSession {
             }))
     }
 
+    /// Generate a new one-time code of a given kind for this session, replacing
+    /// any existing one. Only a hash of the code is stored, so the returned code
+    /// must be delivered to the user immediately. Returns None if the session
+    /// has already been issued `ONE_TIME_CODE_MAX_ISSUED` codes of the kind.
+    async fn issue_one_time_code(
+        &self,
+        kind: OneTimeCodeKind,
+        session_store_conn: &mut Connection,
+    ) -> Result<Option<String>, errors::SessionStorageError> {
+        let code = generate_numeric_code();
+        Ok(session_store_conn
+            .set_one_time_code(&self.token, kind.name(), &hash_one_time_code(&code))
+            .await?
+            .then_some(code))
+    }
+    /// Check a code against this session's one-time code of a given kind. A
+    /// correct code is consumed, and each incorrect attempt counts towards
+    /// `ONE_TIME_CODE_MAX_ATTEMPTS`.
+    async fn verify_one_time_code(
+        &self,
+        kind: OneTimeCodeKind,
+        code: &str,
+        session_store_conn: &mut Connection,
+    ) -> Result<bool, errors::SessionStorageError> {
+        let Some(expected) = session_store_conn
+            .attempt_one_time_code(&self.token, kind.name())
+            .await?
+        else {
+            return Ok(false);
+        };
+        let valid: bool = hash_one_time_code(code)
+            .as_bytes()
+            .ct_eq(expected.as_bytes())
+            .into();
+        if valid {
+            session_store_conn
+                .delete_one_time_code(&self.token, kind.name())
+                .await?;
+        }
+        Ok(valid)
+    }
+
+    /// Replace this authenticated session with a new one holding the same data
+    /// under a new token and CSRF token, then delete this one.
+    async fn rotate(
+        self,
+        session_store_conn: &mut Connection,
+    ) -> Result<Self, errors::SessionStorageError> {
+        let data = AuthenticatedSessionData {
+            previous_csrf: Some(self.session_info.csrf_token()),
+            ..self
+                .session_info
+                .as_auth()
+                .expect("Attempted to rotate a non-authenticated session.")
+                .clone()
+        };
+        let remaining = session_store_conn
+            .ttl(&self.token, store::SessionType::Authenticated)
+            .await?;
+        let timeout = match remaining {
+            Some(ttl) if !SESSION_REFRESH_RESETS_TIMEOUT || data.impersonated_by.is_some() => ttl,
+            _ => data.timeout(),
+        };
+        let session = Self::create(
+            SessionInfo::Authenticated {
+                csrf: generate_token(),
+                data,
+            },
+            session_store_conn,
+        )
+        .await?;
+        session.set_expiry(timeout, session_store_conn).await?;
+        session_store_conn
+            .delete(&self.token, store::SessionType::Authenticated)
+            .await?;
+        Ok(session)
+    }
+
     /// Set the expiry time (in seconds) for this session.
     async fn set_expiry(
         &self,
@@ -477,13 +1110,218 @@ impl BaseSession {
             .set_expiry(&self.token, seconds, self.session_info.clone().into())
             .await
     }
+    /// Count this (registration or preauthentication) session as in progress
+    /// from a client until it is deleted or `timeout` seconds pass. Deletes
+    /// it again if the client already has too many sessions in progress.
+    async fn track_pending(
+        &self,
+        client: &str,
+        timeout: u32,
+        session_store_conn: &mut Connection,
+    ) -> Result<(), errors::PendingSessionError> {
+        if session_store_conn
+            .track_pending_session(
+                client,
+                &self.token,
+                self.session_info.clone().into(),
+                timeout,
+            )
+            .await?
+        {
+            Ok(())
+        } else {
+            Err(errors::PendingSessionError::LimitReached)
+        }
+    }
     /// Get this session's associated information.
     pub fn info(&self) -> SessionInfo {
         self.session_info.clone()
     }
+    /// Get the kind of this session, from its associated information.
+    const fn kind(&self) -> SessionKind {
+        match self.session_info {
+            SessionInfo::PreAuthentication { .. } => SessionKind::PreAuthentication,
+            SessionInfo::Registration { .. } => SessionKind::Registration,
+            SessionInfo::Authenticated { ref data, .. } => {
+                if data.admin {
+                    SessionKind::Administrator
+                } else {
+                    SessionKind::Customer
+                }
+            }
+        }
+    }
 }
 
 /// Errors returned by function within this module.
 pub mod errors {
     pub use super::store::errors::SessionStorageError;
+    use thiserror::Error;
+
+    /// Errors returned while creating a registration or preauthentication session.
+    #[derive(Error, Debug)]
+    pub enum PendingSessionError {
+        /// The client already has `MAX_PENDING_SESSIONS_PER_IP` sessions in
+        /// progress.
+        #[error("Too many sessions are in progress from this client")]
+        LimitReached,
+        /// There was an error while writing to/reading from the store.
+        #[error(transparent)]
+        StorageError(#[from] SessionStorageError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+    use tokio::time::advance;
+    use uuid::Uuid;
+
+    use super::{
+        errors::PendingSessionError, fake_store::FakeStore, store, OneTimeCodeKind,
+        PreAuthenticationSession, SessionTrait as _,
+    };
+    use crate::{
+        constants::sessions::{
+            ADMIN_SESSION_TIMEOUT, MAX_PENDING_SESSIONS_PER_IP, ONE_TIME_CODE_TIMEOUT,
+            REMEMBER_ME_SESSION_TIMEOUT, SESSION_TIMEOUT,
+        },
+        testing::CLIENT_IP,
+    };
+
+    /// Create a preauthentication session for a new user.
+    async fn pre_authentication_session(
+        remember_me: bool,
+        session_store_conn: &mut store::Connection,
+    ) -> PreAuthenticationSession {
+        PreAuthenticationSession::create(
+            Uuid::new_v4(),
+            remember_me,
+            false,
+            CLIENT_IP,
+            session_store_conn,
+        )
+        .await
+        .expect("Session should be created")
+    }
+
+    /// The remaining lifetime of an authenticated session.
+    async fn ttl(token: &str, session_store_conn: &mut store::Connection) -> Option<u32> {
+        session_store_conn
+            .ttl(token, store::SessionType::Authenticated)
+            .await
+            .expect("TTL should be read")
+    }
+
+    /// A customer who asked to be remembered is given a longer session.
+    #[tokio::test]
+    async fn remember_me_extends_customer_session() {
+        let fake_store = FakeStore::default();
+        let mut conn = store::Connection::fake(&fake_store);
+        let session = pre_authentication_session(false, &mut conn)
+            .await
+            .promote(&mut conn)
+            .await
+            .expect("Session should be promoted");
+        assert_eq!(
+            ttl(&session.token(), &mut conn).await,
+            Some(SESSION_TIMEOUT)
+        );
+        let remembered = pre_authentication_session(true, &mut conn)
+            .await
+            .promote(&mut conn)
+            .await
+            .expect("Session should be promoted");
+        assert!(remembered.remember_me());
+        assert_eq!(
+            ttl(&remembered.token(), &mut conn).await,
+            Some(REMEMBER_ME_SESSION_TIMEOUT)
+        );
+    }
+
+    /// Administrative sessions keep their shorter timeout, even if the user
+    /// asked to be remembered.
+    #[tokio::test]
+    async fn remember_me_ignored_for_administrators() {
+        let fake_store = FakeStore::default();
+        let mut conn = store::Connection::fake(&fake_store);
+        let session = pre_authentication_session(true, &mut conn)
+            .await
+            .promote_to_admin(&mut conn)
+            .await
+            .expect("Session should be promoted");
+        assert_eq!(
+            ttl(&session.token(), &mut conn).await,
+            Some(ADMIN_SESSION_TIMEOUT)
+        );
+    }
+
+    /// A client can only have `MAX_PENDING_SESSIONS_PER_IP` sessions in
+    /// progress at once, other clients are unaffected, and completing a
+    /// session frees capacity for another.
+    #[tokio::test]
+    async fn pending_sessions_are_capped_per_client() {
+        let fake_store = FakeStore::default();
+        let mut conn = store::Connection::fake(&fake_store);
+        let mut sessions = Vec::new();
+        for _ in 0..*MAX_PENDING_SESSIONS_PER_IP {
+            sessions.push(pre_authentication_session(false, &mut conn).await);
+        }
+        let over_limit =
+            PreAuthenticationSession::create(Uuid::new_v4(), false, false, CLIENT_IP, &mut conn)
+                .await;
+        assert!(matches!(
+            over_limit.err(),
+            Some(PendingSessionError::LimitReached)
+        ));
+        PreAuthenticationSession::create(Uuid::new_v4(), false, false, "192.0.2.2", &mut conn)
+            .await
+            .expect("Another client should not be limited");
+
+        sessions
+            .pop()
+            .expect("Sessions should have been created")
+            .promote(&mut conn)
+            .await
+            .expect("Session should be promoted");
+        pre_authentication_session(false, &mut conn).await;
+    }
+
+    /// Check that a one-time code of a kind is refused once it has expired,
+    /// while a newly issued one is accepted.
+    async fn assert_one_time_code_expires(kind: OneTimeCodeKind) {
+        let fake_store = FakeStore::default();
+        let mut conn = store::Connection::fake(&fake_store);
+        let session = pre_authentication_session(false, &mut conn).await;
+        let issue = async |session_store_conn: &mut store::Connection| {
+            session
+                .issue_one_time_code(kind, session_store_conn)
+                .await
+                .expect("Code should be issued")
+                .expect("Code should not be rate limited")
+        };
+        let expired = issue(&mut conn).await;
+        advance(Duration::from_secs(u64::from(ONE_TIME_CODE_TIMEOUT) + 1)).await;
+        assert!(!session
+            .verify_one_time_code(kind, &expired, &mut conn)
+            .await
+            .expect("Code should be verified"));
+        let current = issue(&mut conn).await;
+        assert!(session
+            .verify_one_time_code(kind, &current, &mut conn)
+            .await
+            .expect("Code should be verified"));
+    }
+
+    /// An SMS code can't be used once it has expired.
+    #[tokio::test(start_paused = true)]
+    async fn sms_code_expires() {
+        assert_one_time_code_expires(OneTimeCodeKind::Sms).await;
+    }
+
+    /// An email code can't be used once it has expired.
+    #[tokio::test(start_paused = true)]
+    async fn email_code_expires() {
+        assert_one_time_code_expires(OneTimeCodeKind::Email).await;
+    }
 }
@@ -0,0 +1,56 @@
+//! Encryption of sensitive session data (e.g. the PII of a user who is part
+//! way through registering), so that it is never held in the session store in
+//! plaintext.
+use crate::constants::db::DB_ENCRYPTION_KEY;
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use sha2::{Digest as _, Sha256};
+use std::sync::LazyLock;
+
+/// The key session data is encrypted with. Derived from the database
+/// encryption key, domain separated so that the same key is never used
+/// directly for two purposes.
+static SESSION_KEY: LazyLock<LessSafeKey> = LazyLock::new(|| {
+    let key = Sha256::new()
+        .chain_update(b"securecart session store:")
+        .chain_update(DB_ENCRYPTION_KEY.as_bytes())
+        .finalize();
+    LessSafeKey::new(
+        UnboundKey::new(&CHACHA20_POLY1305, &key)
+            .expect("A SHA-256 digest is always a valid ChaCha20-Poly1305 key"),
+    )
+});
+
+/// Encrypt a value for storage, returning the base64 encoded nonce followed
+/// by the ciphertext. Each call uses a fresh random nonce, so sealing the same
+/// value twice gives different results.
+pub fn seal(plaintext: &str) -> String {
+    let mut nonce: [u8; NONCE_LEN] = [0; NONCE_LEN];
+    getrandom::fill(&mut nonce).expect("Error getting OS random. Critical, aborting.");
+    let mut sealed = plaintext.as_bytes().to_vec();
+    SESSION_KEY
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut sealed,
+        )
+        .expect("Session data is far too small to exceed the ChaCha20-Poly1305 limit");
+    let mut stored = nonce.to_vec();
+    stored.append(&mut sealed);
+    BASE64_STANDARD.encode(stored)
+}
+
+/// Decrypt a value sealed with `seal`. Returns None if it was not sealed with
+/// the current key, or has been tampered with.
+pub fn open(sealed: &str) -> Option<String> {
+    let mut stored = BASE64_STANDARD.decode(sealed).ok()?;
+    if stored.len() < NONCE_LEN {
+        return None;
+    }
+    let mut ciphertext = stored.split_off(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&stored).ok()?;
+    let plaintext = SESSION_KEY
+        .open_in_place(nonce, Aad::empty(), &mut ciphertext)
+        .ok()?;
+    String::from_utf8(plaintext.to_vec()).ok()
+}
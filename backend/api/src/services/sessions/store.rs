@@ -1,20 +1,92 @@
 //! Provides an abstracted interface to the underlying session store. Accessible only
 //! within the session service, since no other part of the code should ever access
 //! the session store.
+#[cfg(test)]
+use super::fake_store::FakeStore;
+use super::seal;
 use crate::{
     constants::{
+        orders::{ORDER_RATE_LIMIT_ATTEMPTS, ORDER_RATE_LIMIT_WINDOW},
+        products::PRODUCT_VIEW_DEBOUNCE,
         redis as constants,
-        sessions::{AUTH_PENALTY_PERIOD, AUTH_TIMEOUT_ATTEMPTS, AUTH_TIMEOUT_PERIOD},
+        sessions::{
+            ADMIN_SESSION_TIMEOUT, AUTH_PENALTY_PERIOD, AUTH_TIMEOUT_ATTEMPTS, AUTH_TIMEOUT_PERIOD,
+            GUEST_ORDER_TOKEN_TIMEOUT, GUEST_UPGRADE_TOKEN_TIMEOUT, IMPERSONATION_SESSION_TIMEOUT,
+            MAGIC_LINK_TIMEOUT, MAX_PENDING_SESSIONS_PER_IP, ONE_TIME_CODE_COUNT_TIMEOUT,
+            ONE_TIME_CODE_MAX_ATTEMPTS, ONE_TIME_CODE_MAX_ISSUED, ONE_TIME_CODE_TIMEOUT,
+            PREAUTH_SESSION_TIMEOUT, REGISTRATION_SESSION_TIMEOUT, REMEMBER_ME_SESSION_TIMEOUT,
+            SESSION_TIMEOUT, SIGNUP_RATE_LIMIT_ATTEMPTS, SIGNUP_RATE_LIMIT_WINDOW,
+        },
     },
     db::models::appuser::AppUserInsert,
+    utils::address::Address,
 };
-use redis::{aio::MultiplexedConnection, AsyncCommands as _};
+use core::{fmt::Display, time::Duration};
+use redis::{
+    aio::{ConnectionLike, MultiplexedConnection},
+    AsyncCommands as _, AsyncConnectionConfig, AsyncIter, Cmd, ExistenceCheck, Pipeline,
+    RedisFuture, SetExpiry, SetOptions, Value,
+};
+use std::collections::HashMap;
+use time::OffsetDateTime;
+use tokio::time::sleep;
 use uuid::Uuid;
 
+/// The script `Connection::rotate_csrf` runs to replace a session's CSRF
+/// token atomically, given the session's key, the token it was read with and
+/// the replacement.
+pub(super) const ROTATE_CSRF_SCRIPT: &str = "
+    local current = redis.call('HGET', KEYS[1], 'csrf')
+    if current == ARGV[1] then
+        redis.call('HSET', KEYS[1], 'csrf', ARGV[2], 'previous_csrf', ARGV[1])
+        return ARGV[2]
+    end
+    return current
+";
+
 #[derive(Clone)]
 /// A connection to the session store. Guaranteed to be safe to clone and share
 /// between threads.
-pub struct Connection(MultiplexedConnection);
+pub struct Connection(Backend);
+
+/// What a `Connection` is connected to: Redis, or in tests an in-memory fake.
+#[derive(Clone)]
+enum Backend {
+    /// A (multiplexed) connection to Redis.
+    Redis(MultiplexedConnection),
+    /// An in-memory fake of Redis.
+    #[cfg(test)]
+    Fake(FakeStore),
+}
+
+impl ConnectionLike for Backend {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match *self {
+            Self::Redis(ref mut conn) => conn.req_packed_command(cmd),
+            #[cfg(test)]
+            Self::Fake(ref mut fake) => fake.req_packed_command(cmd),
+        }
+    }
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match *self {
+            Self::Redis(ref mut conn) => conn.req_packed_commands(cmd, offset, count),
+            #[cfg(test)]
+            Self::Fake(ref mut fake) => fake.req_packed_commands(cmd, offset, count),
+        }
+    }
+    fn get_db(&self) -> i64 {
+        match *self {
+            Self::Redis(ref conn) => conn.get_db(),
+            #[cfg(test)]
+            Self::Fake(ref fake) => fake.get_db(),
+        }
+    }
+}
 
 #[derive(Copy, Clone)]
 /// The type of session represented by a `SessionInfo`. Corresponds directly to
@@ -33,6 +105,11 @@ pub enum SessionType {
 pub struct PreAuthenticationSessionData {
     /// The ID of the user in the process of authenticating with this token.
     pub user_id: Uuid,
+    /// Whether the user asked for the resulting session to be long-lived.
+    pub remember_me: bool,
+    /// Whether the user's first factor was proof that they can read their
+    /// email, i.e. a magic link, so that an email code can't be their second.
+    pub email_first_factor: bool,
 }
 
 #[derive(Clone)]
@@ -42,14 +119,63 @@ pub struct AuthenticatedSessionData {
     pub user_id: Uuid,
     /// TODO: add documentation
     pub admin: bool,
+    /// Whether the session was created with "remember me", and so should use
+    /// the longer `REMEMBER_ME_SESSION_TIMEOUT`. Never set for admin sessions.
+    pub remember_me: bool,
+    /// The CSRF token of the session this one was refreshed from, if any, so
+    /// that a stale token from before the refresh can be told apart from a
+    /// forged one.
+    pub previous_csrf: Option<String>,
+    /// The administrator impersonating the user through this session, if it
+    /// was created for impersonation rather than by the user logging in.
+    pub impersonated_by: Option<Uuid>,
+}
+
+impl AuthenticatedSessionData {
+    /// The idle timeout (in seconds) which applies to this session.
+    pub const fn timeout(&self) -> u32 {
+        if self.impersonated_by.is_some() {
+            IMPERSONATION_SESSION_TIMEOUT
+        } else if self.admin {
+            ADMIN_SESSION_TIMEOUT
+        } else if self.remember_me {
+            REMEMBER_ME_SESSION_TIMEOUT
+        } else {
+            SESSION_TIMEOUT
+        }
+    }
 }
 
 /// Information stored with a Registration session token.
 #[derive(Clone)]
 pub struct RegistrationSessionData {
-    /// TODO: add documentation
-    pub user_data: AppUserInsert,
+    /// The details of the user being registered. Boxed to keep `SessionInfo`
+    /// small, as a structured address makes them much larger than any other
+    /// session's data.
+    pub user_data: Box<AppUserInsert>,
 }
+
+/// The raw fields of a registration session as read from the store, in the
+/// order email, forename, surname, address, CSRF token, phone number.
+type RegistrationFields = (
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+/// The raw fields of an authenticated session as read from the store, in the
+/// order user ID, admin, remember me, CSRF token, previous CSRF token,
+/// impersonating administrator.
+type AuthenticatedFields = (
+    Option<Uuid>,
+    Option<bool>,
+    Option<bool>,
+    Option<String>,
+    Option<String>,
+    Option<Uuid>,
+);
 /// Information stored alongside a session token.
 #[derive(Clone)]
 pub enum SessionInfo {
@@ -76,18 +202,36 @@ pub enum SessionInfo {
     },
 }
 
+/// Prepend `REDIS_KEY_PREFIX` to the name of a key. Every key in the store is
+/// built with this, so that stores sharing a Redis database never collide.
+fn key(name: impl Display) -> String {
+    format!("{}{name}", *constants::REDIS_KEY_PREFIX)
+}
+
 impl SessionType {
     /// Convert this enum to a string representing its Redis parent key name.
     /// Session data is stored under "{`SessionType::to_parent_key_name()}:{token`}"."
     fn to_parent_key_name(self) -> String {
         match self {
-            Self::PreAuthentication => String::from("sessions:preauthentication"),
-            Self::Authenticated => String::from("sessions:authenticated"),
-            Self::Registration => String::from("sessions:registration"),
+            Self::PreAuthentication => key("sessions:preauthentication"),
+            Self::Authenticated => key("sessions:authenticated"),
+            Self::Registration => key("sessions:registration"),
         }
     }
 }
 
+/// The key of the set indexing all authenticated session tokens of a user,
+/// which allows revoking every session belonging to that user.
+fn user_index_key(user_id: Uuid) -> String {
+    key(format!("sessions:users:{user_id}"))
+}
+
+/// The key of the sorted set of registration and preauthentication sessions in
+/// progress from a client, scored by when each expires.
+fn pending_index_key(client: &str) -> String {
+    key(format!("sessions:pending:{client}"))
+}
+
 impl SessionInfo {
     /// TODO: add documentation
     pub fn csrf_token(&self) -> String {
@@ -114,6 +258,11 @@ impl SessionInfo {
         }
     }
 
+    /// The CSRF token this session replaced when it was refreshed, if any.
+    pub fn previous_csrf_token(&self) -> Option<String> {
+        self.as_auth()?.previous_csrf.clone()
+    }
+
     /// Extract user data from this, and return None if it is not a `RegistrationSession`.
     pub const fn as_registration(&self) -> Option<&RegistrationSessionData> {
         match *self {
@@ -133,30 +282,519 @@ impl From<SessionInfo> for SessionType {
     }
 }
 
+/// A client's brute-force rate limit state, after recording an attempt.
+pub struct BruteforceStatus {
+    /// The number of attempts recorded in the current window, including this one.
+    pub attempts: u32,
+    /// Whether the client is timed out, and so this attempt must be rejected.
+    pub timed_out: bool,
+    /// The number of seconds until the attempt count (or timeout) expires.
+    pub reset_after: u32,
+}
+
+impl BruteforceStatus {
+    /// The number of attempts permitted within a window before timing out.
+    pub const LIMIT: u32 = AUTH_TIMEOUT_ATTEMPTS.saturating_sub(1);
+    /// The number of further attempts permitted in the current window.
+    pub const fn remaining(&self) -> u32 {
+        Self::LIMIT.saturating_sub(self.attempts)
+    }
+}
+
+/// Tracks retries of a session store operation which failed with a transient
+/// error, with exponential backoff, up to `REDIS_RETRY_ATTEMPTS` attempts in
+/// total. Logical failures (e.g. a duplicate token) are never retried.
+struct Retry {
+    /// The number of attempts made so far.
+    attempt: u32,
+    /// The delay before the next retry.
+    backoff: Duration,
+}
+
+impl Retry {
+    /// Start tracking a new operation, before its first attempt.
+    fn new() -> Self {
+        Self {
+            attempt: 1,
+            backoff: Duration::from_millis(*constants::REDIS_RETRY_BACKOFF_MS),
+        }
+    }
+    /// Given the error from the latest attempt, return whether the operation
+    /// should be retried, waiting out the backoff period first if so.
+    async fn should_retry<E: errors::Retryable + Display + Sync>(&mut self, err: &E) -> bool {
+        if !err.is_transient() || self.attempt >= *constants::REDIS_RETRY_ATTEMPTS {
+            return false;
+        }
+        tracing::warn!(
+            "Transient session store error on attempt {}, retrying: {err}",
+            self.attempt
+        );
+        sleep(self.backoff).await;
+        self.backoff = self.backoff.saturating_mul(2);
+        self.attempt = self.attempt.saturating_add(1);
+        true
+    }
+}
+
 impl Connection {
     /// Initiate a new (multiplexed) connection to the session store.
     /// This connection can be cloned and is safe share between threads.
     pub async fn connect() -> Result<Self, errors::SessionStorageError> {
-        Ok(Self(
+        Ok(Self(Backend::Redis(
             redis::Client::open(constants::REDIS_URL.to_owned())?
-                .get_multiplexed_async_connection()
+                .get_multiplexed_async_connection_with_config(
+                    &AsyncConnectionConfig::new()
+                        .set_connection_timeout(*constants::REDIS_CONNECTION_TIMEOUT)
+                        .set_response_timeout(*constants::REDIS_RESPONSE_TIMEOUT),
+                )
                 .await?,
-        ))
+        )))
+    }
+    /// A connection to an in-memory fake of the session store, for tests.
+    #[cfg(test)]
+    pub fn fake(store: &FakeStore) -> Self {
+        Self(Backend::Fake(store.clone()))
     }
-    /// Increments an internal counter to indicate an authentication attempt, and returns whether the user is timed out or now
+    /// Increments an internal counter to indicate an authentication attempt, and returns
+    /// the client's resulting rate limit state, including whether they are now timed out.
     pub async fn bruteforce_timeout(
         &mut self,
         client: &str,
-    ) -> Result<bool, errors::SessionStorageError> {
-        let key = format!("bruteforce:{client}");
+    ) -> Result<BruteforceStatus, errors::SessionStorageError> {
+        let key = key(format!("bruteforce:{client}"));
         let attempts: u32 = self.0.incr(&key, 1u32).await?;
-        if attempts < AUTH_TIMEOUT_ATTEMPTS {
-            let _: () = self.0.expire(&key, i64::from(AUTH_TIMEOUT_PERIOD)).await?;
-            Ok(false)
+        let timed_out = attempts >= AUTH_TIMEOUT_ATTEMPTS;
+        let reset_after = if timed_out {
+            AUTH_PENALTY_PERIOD
         } else {
-            let _: () = self.0.expire(&key, i64::from(AUTH_PENALTY_PERIOD)).await?;
-            Ok(true)
+            AUTH_TIMEOUT_PERIOD
+        };
+        let _: () = self.0.expire(&key, i64::from(reset_after)).await?;
+        Ok(BruteforceStatus {
+            attempts,
+            timed_out,
+            reset_after,
+        })
+    }
+    /// Count an order creation by a user towards their order rate limit,
+    /// independently of any per-client limits. Returns None if the order may
+    /// proceed, or the number of seconds until the user's window resets if
+    /// they have exceeded `ORDER_RATE_LIMIT_ATTEMPTS`.
+    pub async fn order_rate_limit(
+        &mut self,
+        user_id: Uuid,
+    ) -> Result<Option<u32>, errors::SessionStorageError> {
+        self.rate_limit(
+            &key(format!("order_rate:{user_id}")),
+            *ORDER_RATE_LIMIT_ATTEMPTS,
+            *ORDER_RATE_LIMIT_WINDOW,
+        )
+        .await
+    }
+    /// Count a guest order placed by a client towards their guest order rate
+    /// limit. Guests need no account, so they are limited by client rather
+    /// than by user like `order_rate_limit`. Returns None if the order may
+    /// proceed, or the number of seconds until the client's window resets if
+    /// they have exceeded `ORDER_RATE_LIMIT_ATTEMPTS`.
+    pub async fn guest_order_rate_limit(
+        &mut self,
+        client: &str,
+    ) -> Result<Option<u32>, errors::SessionStorageError> {
+        self.rate_limit(
+            &key(format!("guest_order_rate:{client}")),
+            *ORDER_RATE_LIMIT_ATTEMPTS,
+            *ORDER_RATE_LIMIT_WINDOW,
+        )
+        .await
+    }
+    /// Count a signup started by a client towards their signup rate limit,
+    /// which is kept apart from the brute force limits on logging in. Returns
+    /// None if the signup may proceed, or the number of seconds until the
+    /// client's window resets if they have exceeded
+    /// `SIGNUP_RATE_LIMIT_ATTEMPTS`.
+    pub async fn signup_rate_limit(
+        &mut self,
+        client: &str,
+    ) -> Result<Option<u32>, errors::SessionStorageError> {
+        self.rate_limit(
+            &key(format!("signup_rate:{client}")),
+            *SIGNUP_RATE_LIMIT_ATTEMPTS,
+            *SIGNUP_RATE_LIMIT_WINDOW,
+        )
+        .await
+    }
+    /// Count an attempt against the rate limit counted under `key`, allowing
+    /// `attempts` within a window of `window` seconds from the first attempt
+    /// in it. Returns None if the attempt is within the limit, or the number
+    /// of seconds until the window resets if not.
+    async fn rate_limit(
+        &mut self,
+        key: &str,
+        attempts: u32,
+        window: u32,
+    ) -> Result<Option<u32>, errors::SessionStorageError> {
+        let count: u32 = self.0.incr(key, 1u32).await?;
+        if count == 1 {
+            let _: () = self.0.expire(key, i64::from(window)).await?;
+        }
+        if count <= attempts {
+            return Ok(None);
+        }
+        let ttl: i64 = self.0.ttl(key).await?;
+        Ok(Some(u32::try_from(ttl).unwrap_or(window)))
+    }
+    /// Record a registration or preauthentication session as in progress from
+    /// a client, so that it counts towards `MAX_PENDING_SESSIONS_PER_IP` until
+    /// it is deleted or its timeout passes. If this takes the client over the
+    /// limit, the session is deleted again and false is returned.
+    pub(super) async fn track_pending_session(
+        &mut self,
+        client: &str,
+        token: &str,
+        session_type: SessionType,
+        timeout: u32,
+    ) -> Result<bool, errors::SessionStorageError> {
+        let key = format!("{}:{token}", session_type.to_parent_key_name());
+        let index_key = pending_index_key(client);
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        // Sessions which have expired were never deleted, so are pruned here.
+        let _: () = self.0.zrembyscore(&index_key, "-inf", now).await?;
+        let _: () = self
+            .0
+            .zadd(&index_key, &key, now.saturating_add(i64::from(timeout)))
+            .await?;
+        let _: () = self.0.hset(&key, "client", client).await?;
+        let index_timeout = PREAUTH_SESSION_TIMEOUT.max(REGISTRATION_SESSION_TIMEOUT);
+        let _: () = self.0.expire(&index_key, i64::from(index_timeout)).await?;
+        // Counted after adding, so concurrent attempts can't all slip in
+        // under the limit. At worst they are all rejected.
+        let in_progress: u32 = self.0.zcard(&index_key).await?;
+        if in_progress > *MAX_PENDING_SESSIONS_PER_IP {
+            self.delete(token, session_type).await?;
+            return Ok(false);
         }
+        Ok(true)
+    }
+    /// Take the lock with the given name for `seconds`, unless it is already
+    /// held. Returns whether it was taken. Locks are never released early, so
+    /// also limit the work they guard to once per period, across every
+    /// instance of the API sharing the store.
+    pub(super) async fn try_lock(
+        &mut self,
+        name: &str,
+        seconds: u64,
+    ) -> Result<bool, errors::SessionStorageError> {
+        let taken: Option<String> = self
+            .0
+            .set_options(
+                key(format!("locks:{name}")),
+                true,
+                SetOptions::default()
+                    .conditional_set(ExistenceCheck::NX)
+                    .with_expiration(SetExpiry::EX(seconds)),
+            )
+            .await?;
+        Ok(taken.is_some())
+    }
+    /// List every key matching a pattern. Uses SCAN rather than KEYS, so that
+    /// the store isn't blocked while searching.
+    async fn scan_keys(
+        &mut self,
+        pattern: &str,
+    ) -> Result<Vec<String>, errors::SessionStorageError> {
+        let mut iter: AsyncIter<'_, String> = self.0.scan_match(pattern).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+    /// Remove the tokens from every user's session index which refer to
+    /// sessions which no longer exist, returning the number removed. These
+    /// are otherwise only removed when the user's sessions are next listed.
+    pub(super) async fn reconcile_user_indexes(
+        &mut self,
+    ) -> Result<u32, errors::SessionStorageError> {
+        let mut removed: u32 = 0;
+        for index_key in self.scan_keys(&key("sessions:users:*")).await? {
+            let tokens: Vec<String> = self.0.smembers(&index_key).await?;
+            for token in tokens {
+                let key = format!(
+                    "{}:{token}",
+                    SessionType::Authenticated.to_parent_key_name()
+                );
+                let exists: bool = self.0.exists(key).await?;
+                if !exists {
+                    let count: u32 = self.0.srem(&index_key, token).await?;
+                    removed = removed.saturating_add(count);
+                }
+            }
+        }
+        Ok(removed)
+    }
+    /// Remove the entries from every client's pending session index which
+    /// have expired, or refer to sessions which no longer exist (e.g. if the
+    /// API stopped part way through deleting one), returning the number
+    /// removed. Expired entries are otherwise only removed when the client
+    /// next starts a session.
+    pub(super) async fn reconcile_pending_indexes(
+        &mut self,
+    ) -> Result<u32, errors::SessionStorageError> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut removed: u32 = 0;
+        for index_key in self.scan_keys(&key("sessions:pending:*")).await? {
+            let expired: u32 = self.0.zrembyscore(&index_key, "-inf", now).await?;
+            removed = removed.saturating_add(expired);
+            let keys: Vec<String> = self.0.zrange(&index_key, 0, -1).await?;
+            for key in keys {
+                let exists: bool = self.0.exists(&key).await?;
+                if !exists {
+                    let count: u32 = self.0.zrem(&index_key, key).await?;
+                    removed = removed.saturating_add(count);
+                }
+            }
+        }
+        Ok(removed)
+    }
+    /// Count a view of a product, unless the same viewer viewed it within the
+    /// last `PRODUCT_VIEW_DEBOUNCE` seconds. Returns whether it was counted.
+    pub(super) async fn record_product_view(
+        &mut self,
+        product_id: Uuid,
+        viewer: &str,
+    ) -> Result<bool, errors::SessionStorageError> {
+        let first: Option<String> = self
+            .0
+            .set_options(
+                key(format!("product_views:seen:{product_id}:{viewer}")),
+                true,
+                SetOptions::default()
+                    .conditional_set(ExistenceCheck::NX)
+                    .with_expiration(SetExpiry::EX(u64::from(PRODUCT_VIEW_DEBOUNCE))),
+            )
+            .await?;
+        if first.is_none() {
+            return Ok(false);
+        }
+        let _: i64 = self
+            .0
+            .hincr(key("product_views:counts"), product_id, 1i64)
+            .await?;
+        Ok(true)
+    }
+    /// Move every product's count of views recorded since the last batch was
+    /// staged into a new batch, with a new ID, to be added to the database.
+    /// Does nothing if no views have been recorded. The move is a single
+    /// rename, so views are never lost or counted in two batches.
+    pub(super) async fn stage_product_views(&mut self) -> Result<(), errors::SessionStorageError> {
+        let counts_key = key("product_views:counts");
+        let exists: bool = self.0.exists(&counts_key).await?;
+        if exists {
+            let _: () = self
+                .0
+                .rename(
+                    counts_key,
+                    key(format!("product_views:batches:{}", Uuid::new_v4())),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+    /// Get every staged batch of view counts which has not yet been
+    /// discarded, by its ID, including any left by a flush which stopped part
+    /// way through.
+    pub(super) async fn staged_product_views(
+        &mut self,
+    ) -> Result<Vec<(Uuid, Vec<(Uuid, i64)>)>, errors::SessionStorageError> {
+        let mut batches = Vec::new();
+        for batch_key in self.scan_keys(&key("product_views:batches:*")).await? {
+            let Some(batch_id) = batch_key
+                .rsplit(':')
+                .next()
+                .and_then(|id| Uuid::parse_str(id).ok())
+            else {
+                continue;
+            };
+            let counts: HashMap<Uuid, i64> = self.0.hgetall(&batch_key).await?;
+            batches.push((batch_id, counts.into_iter().collect()));
+        }
+        Ok(batches)
+    }
+    /// Discard a staged batch of view counts, once it has been added to the
+    /// database.
+    pub(super) async fn discard_product_views(
+        &mut self,
+        batch_id: Uuid,
+    ) -> Result<(), errors::SessionStorageError> {
+        let _: () = self
+            .0
+            .del(key(format!("product_views:batches:{batch_id}")))
+            .await?;
+        Ok(())
+    }
+    /// Get the current version of the product listings, which changes
+    /// whenever any product is changed. 0 if no product has been changed
+    /// since the store was emptied.
+    pub(super) async fn product_list_version(
+        &mut self,
+    ) -> Result<u64, errors::SessionStorageError> {
+        let version: Option<u64> = self.0.get(key("product_lists:version")).await?;
+        Ok(version.unwrap_or(0))
+    }
+    /// Advance the version of the product listings, returning the new version.
+    pub(super) async fn bump_product_list_version(
+        &mut self,
+    ) -> Result<u64, errors::SessionStorageError> {
+        Ok(self.0.incr(key("product_lists:version"), 1u64).await?)
+    }
+    /// Store the hash of a one-time code of a given kind (e.g. "sms") for a
+    /// session, replacing any previous code of that kind. The code expires
+    /// after `ONE_TIME_CODE_TIMEOUT`. Returns false, without storing the code,
+    /// if `ONE_TIME_CODE_MAX_ISSUED` codes of the kind have already been
+    /// issued to the session within `ONE_TIME_CODE_COUNT_TIMEOUT`.
+    pub(super) async fn set_one_time_code(
+        &mut self,
+        token: &str,
+        kind: &str,
+        code_hash: &str,
+    ) -> Result<bool, errors::SessionStorageError> {
+        let counts_key = key(format!("one_time_code_counts:{kind}:{token}"));
+        let issued: u32 = self.0.hincr(&counts_key, "issued", 1u32).await?;
+        if issued == 1 {
+            let _: () = self
+                .0
+                .expire(&counts_key, i64::from(ONE_TIME_CODE_COUNT_TIMEOUT))
+                .await?;
+        }
+        if issued > ONE_TIME_CODE_MAX_ISSUED {
+            return Ok(false);
+        }
+        let _: () = self
+            .0
+            .set_ex(
+                key(format!("one_time_codes:{kind}:{token}")),
+                code_hash,
+                u64::from(ONE_TIME_CODE_TIMEOUT),
+            )
+            .await?;
+        Ok(true)
+    }
+    /// Get the hash of a session's one-time code of a given kind, counting
+    /// this as an attempt at entering it. Attempts are counted across every
+    /// code of the kind issued to the session, so issuing a new code does not
+    /// allow any more guesses. Returns None if there is no code, it has
+    /// expired, or `ONE_TIME_CODE_MAX_ATTEMPTS` has been exceeded, in which
+    /// case it is also deleted.
+    pub(super) async fn attempt_one_time_code(
+        &mut self,
+        token: &str,
+        kind: &str,
+    ) -> Result<Option<String>, errors::SessionStorageError> {
+        let code_key = key(format!("one_time_codes:{kind}:{token}"));
+        let maybe_hash: Option<String> = self.0.get(&code_key).await?;
+        let Some(code_hash) = maybe_hash else {
+            return Ok(None);
+        };
+        let counts_key = key(format!("one_time_code_counts:{kind}:{token}"));
+        let attempts: u32 = self.0.hincr(&counts_key, "attempts", 1u32).await?;
+        if attempts > ONE_TIME_CODE_MAX_ATTEMPTS {
+            let _: () = self.0.del(&code_key).await?;
+            return Ok(None);
+        }
+        Ok(Some(code_hash))
+    }
+    /// Delete a session's one-time code of a given kind, e.g. once it is used.
+    /// The codes issued to the session, and attempts at them, are still counted.
+    pub(super) async fn delete_one_time_code(
+        &mut self,
+        token: &str,
+        kind: &str,
+    ) -> Result<(), errors::SessionStorageError> {
+        let _: () = self
+            .0
+            .del(key(format!("one_time_codes:{kind}:{token}")))
+            .await?;
+        Ok(())
+    }
+    /// Associate a guest order token with the order it grants access to. The
+    /// token expires after `GUEST_ORDER_TOKEN_TIMEOUT`.
+    pub(super) async fn set_guest_order(
+        &mut self,
+        token: &str,
+        order_id: Uuid,
+    ) -> Result<(), errors::SessionStorageError> {
+        let _: () = self
+            .0
+            .set_ex(
+                key(format!("guest_orders:{token}")),
+                order_id,
+                u64::from(GUEST_ORDER_TOKEN_TIMEOUT),
+            )
+            .await?;
+        Ok(())
+    }
+    /// Store a magic link login token for a user, valid for `MAGIC_LINK_TIMEOUT`.
+    pub(super) async fn set_magic_link(
+        &mut self,
+        token: &str,
+        user_id: Uuid,
+    ) -> Result<(), errors::SessionStorageError> {
+        let _: () = self
+            .0
+            .set_ex(
+                key(format!("magic_links:{token}")),
+                user_id,
+                u64::from(MAGIC_LINK_TIMEOUT),
+            )
+            .await?;
+        Ok(())
+    }
+    /// Take the user a magic link login token was issued to, deleting the
+    /// token in the same step so it can only ever be used once. Returns None
+    /// if the token does not exist or has expired.
+    pub(super) async fn take_magic_link(
+        &mut self,
+        token: &str,
+    ) -> Result<Option<Uuid>, errors::SessionStorageError> {
+        Ok(self.0.get_del(key(format!("magic_links:{token}"))).await?)
+    }
+    /// Store a token proving access to an email address, with which the guests
+    /// placing orders with it can be upgraded to a full account. Valid for
+    /// `GUEST_UPGRADE_TOKEN_TIMEOUT`.
+    pub(super) async fn set_guest_upgrade(
+        &mut self,
+        token: &str,
+        email: &str,
+    ) -> Result<(), errors::SessionStorageError> {
+        let _: () = self
+            .0
+            .set_ex(
+                key(format!("guest_upgrades:{token}")),
+                email,
+                u64::from(GUEST_UPGRADE_TOKEN_TIMEOUT),
+            )
+            .await?;
+        Ok(())
+    }
+    /// Take the email a guest upgrade token was issued for, deleting the token
+    /// in the same step so it can only ever be used once. Returns None if the
+    /// token does not exist or has expired.
+    pub(super) async fn take_guest_upgrade(
+        &mut self,
+        token: &str,
+    ) -> Result<Option<String>, errors::SessionStorageError> {
+        Ok(self
+            .0
+            .get_del(key(format!("guest_upgrades:{token}")))
+            .await?)
+    }
+    /// Get the order which a guest order token grants access to, or None if
+    /// the token does not exist or has expired.
+    pub(super) async fn get_guest_order(
+        &mut self,
+        token: &str,
+    ) -> Result<Option<Uuid>, errors::SessionStorageError> {
+        Ok(self.0.get(key(format!("guest_orders:{token}"))).await?)
     }
     /// Store user data for a registration session in the store.
     async fn store_registration_data(
@@ -165,12 +803,11 @@ impl Connection {
         csrf: &str,
         RegistrationSessionData { user_data }: RegistrationSessionData,
     ) -> Result<(), errors::SessionCreationError> {
-        let _: () = self
-            .0
-            .hset_nx(key, "email", user_data.email.to_string())
-            .await?;
+        // Everything but the CSRF token is PII, so is only stored encrypted.
+        let email = String::from(user_data.email);
+        let _: () = self.0.hset_nx(key, "email", seal::seal(&email)).await?;
         let set_email: String = self.0.hget(key, "email").await?;
-        if set_email != String::from(user_data.email) {
+        if seal::open(&set_email).as_deref() != Some(email.as_str()) {
             return Err(errors::SessionCreationError::Duplicate);
         }
         let _: () = self
@@ -178,13 +815,19 @@ impl Connection {
             .hset_multiple(
                 key,
                 &[
-                    ("forename", &user_data.forename),
-                    ("surname", &user_data.surname),
-                    ("address", &user_data.address),
-                    ("csrf", &csrf.to_owned()),
+                    ("forename", seal::seal(&user_data.forename)),
+                    ("surname", seal::seal(&user_data.surname)),
+                    ("address", seal::seal(&user_data.address.to_stored())),
+                    ("csrf", csrf.to_owned()),
                 ],
             )
             .await?;
+        if let Some(phone) = user_data.phone {
+            let _: () = self
+                .0
+                .hset(key, "phone", seal::seal(&String::from(phone)))
+                .await?;
+        }
         Ok(())
     }
     /// Store data for a regular (authenticated/preauthentication) session
@@ -192,14 +835,39 @@ impl Connection {
     async fn store_authenticated_data(
         &mut self,
         key: &str,
+        token: &str,
         csrf: &str,
-        AuthenticatedSessionData { user_id, admin }: AuthenticatedSessionData,
+        AuthenticatedSessionData {
+            user_id,
+            admin,
+            remember_me,
+            previous_csrf,
+            impersonated_by,
+        }: AuthenticatedSessionData,
     ) -> Result<(), errors::SessionCreationError> {
         let _: () = self.0.hset_nx(key, "user_id", user_id).await?;
         let set_user_id: Uuid = self.0.hget(key, "user_id").await?;
         if set_user_id == user_id {
             let _: () = self.0.hset(key, "admin", admin).await?;
+            let _: () = self.0.hset(key, "remember_me", remember_me).await?;
             let _: () = self.0.hset(key, "csrf", csrf).await?;
+            if let Some(previous) = previous_csrf {
+                let _: () = self.0.hset(key, "previous_csrf", previous).await?;
+            }
+            if let Some(administrator_id) = impersonated_by {
+                let _: () = self
+                    .0
+                    .hset(key, "impersonated_by", administrator_id)
+                    .await?;
+            }
+            let index_key = user_index_key(user_id);
+            let _: () = self.0.sadd(&index_key, token).await?;
+            // The index must outlive every session it refers to. Expired
+            // tokens left in it are harmless, and are skipped on revocation.
+            let index_timeout = SESSION_TIMEOUT
+                .max(REMEMBER_ME_SESSION_TIMEOUT)
+                .max(ADMIN_SESSION_TIMEOUT);
+            let _: () = self.0.expire(&index_key, i64::from(index_timeout)).await?;
             Ok(())
         } else {
             Err(errors::SessionCreationError::Duplicate)
@@ -211,11 +879,20 @@ impl Connection {
         &mut self,
         key: &str,
         csrf: &str,
-        PreAuthenticationSessionData { user_id }: PreAuthenticationSessionData,
+        PreAuthenticationSessionData {
+            user_id,
+            remember_me,
+            email_first_factor,
+        }: PreAuthenticationSessionData,
     ) -> Result<(), errors::SessionCreationError> {
         let _: () = self.0.hset_nx(key, "user_id", user_id).await?;
         let set_user_id: Uuid = self.0.hget(key, "user_id").await?;
         if set_user_id == user_id {
+            let _: () = self.0.hset(key, "remember_me", remember_me).await?;
+            let _: () = self
+                .0
+                .hset(key, "email_first_factor", email_first_factor)
+                .await?;
             let _: () = self.0.hset(key, "csrf", csrf).await?;
             Ok(())
         } else {
@@ -223,30 +900,69 @@ impl Connection {
         }
     }
 
+    // The getters below each read every field of their session type with a
+    // single HMGET, whether or not the session exists. This keeps the store
+    // round trips identical for valid and invalid tokens, so that response
+    // timing does not reveal whether a guessed token exists.
+
     /// Get registration user data stored in the session store for a given
     /// session.
     async fn get_registration_session_data(
         &mut self,
         key: &str,
     ) -> Result<Option<SessionInfo>, errors::SessionStorageError> {
-        let email_opt: Option<String> = self.0.hget(key, "email").await?;
-        let Some(email) = email_opt else {
+        let fields: RegistrationFields = self
+            .0
+            .hget(
+                key,
+                &["email", "forename", "surname", "address", "csrf", "phone"],
+            )
+            .await?;
+        let (
+            Some(sealed_email),
+            Some(sealed_forename),
+            Some(sealed_surname),
+            Some(sealed_address),
+            Some(csrf),
+            sealed_phone,
+        ) = fields
+        else {
+            return Ok(None);
+        };
+        let (
+            Some(email),
+            Some(forename),
+            Some(surname),
+            Some(address),
+            phone @ (None | Some(Some(_))),
+        ) = (
+            seal::open(&sealed_email),
+            seal::open(&sealed_forename),
+            seal::open(&sealed_surname),
+            seal::open(&sealed_address),
+            sealed_phone.as_deref().map(seal::open),
+        )
+        else {
+            // e.g. sealed with a previous key, in which case the session
+            // cannot be used and the user must restart registration.
+            tracing::error!("Failed to decrypt data of registration session.");
             return Ok(None);
         };
-        let forename: String = self.0.hget(key, "forename").await?;
-        let surname: String = self.0.hget(key, "surname").await?;
-        let address: String = self.0.hget(key, "address").await?;
-        let csrf: String = self.0.hget(key, "csrf").await?;
         Ok(Some(SessionInfo::Registration {
             data: RegistrationSessionData {
-                user_data: AppUserInsert::new(
+                user_data: Box::new(AppUserInsert::new(
                     email
                         .try_into()
                         .expect("Solar bit flip or act of God made email address invalid."),
                     &forename,
                     &surname,
-                    &address,
-                ),
+                    Address::from_stored(&address),
+                    phone.flatten().map(|number| {
+                        number
+                            .try_into()
+                            .expect("Solar bit flip or act of God made phone number invalid.")
+                    }),
+                )),
             },
             csrf,
         }))
@@ -257,13 +973,37 @@ impl Connection {
         &mut self,
         key: &str,
     ) -> Result<Option<SessionInfo>, errors::SessionStorageError> {
-        let maybe_user_id: Option<Uuid> = self.0.hget(key, "user_id").await?;
-        let maybe_admin: Option<bool> = self.0.hget(key, "admin").await?;
-        let maybe_csrf_token: Option<String> = self.0.hget(key, "csrf").await?;
+        let (
+            maybe_user_id,
+            maybe_admin,
+            maybe_remember_me,
+            maybe_csrf_token,
+            previous_csrf,
+            impersonated_by,
+        ): AuthenticatedFields = self
+            .0
+            .hget(
+                key,
+                &[
+                    "user_id",
+                    "admin",
+                    "remember_me",
+                    "csrf",
+                    "previous_csrf",
+                    "impersonated_by",
+                ],
+            )
+            .await?;
         Ok(maybe_user_id.and_then(|user_id| {
             let admin = maybe_admin?;
             maybe_csrf_token.map(|csrf| SessionInfo::Authenticated {
-                data: AuthenticatedSessionData { user_id, admin },
+                data: AuthenticatedSessionData {
+                    user_id,
+                    admin,
+                    remember_me: maybe_remember_me.unwrap_or(false),
+                    previous_csrf,
+                    impersonated_by,
+                },
                 csrf,
             })
         }))
@@ -274,27 +1014,62 @@ impl Connection {
         &mut self,
         key: &str,
     ) -> Result<Option<SessionInfo>, errors::SessionStorageError> {
-        let maybe_user_id: Option<Uuid> = self.0.hget(key, "user_id").await?;
-        let maybe_csrf_token: Option<String> = self.0.hget(key, "csrf").await?;
+        let (maybe_user_id, maybe_remember_me, maybe_email_first_factor, maybe_csrf_token): (
+            Option<Uuid>,
+            Option<bool>,
+            Option<bool>,
+            Option<String>,
+        ) = self
+            .0
+            .hget(
+                key,
+                &["user_id", "remember_me", "email_first_factor", "csrf"],
+            )
+            .await?;
         Ok(maybe_user_id.and_then(|user_id| {
             maybe_csrf_token.map(|csrf| SessionInfo::PreAuthentication {
-                data: PreAuthenticationSessionData { user_id },
+                data: PreAuthenticationSessionData {
+                    user_id,
+                    remember_me: maybe_remember_me.unwrap_or(false),
+                    email_first_factor: maybe_email_first_factor.unwrap_or(false),
+                },
                 csrf,
             })
         }))
     }
 
     /// Create a new session with a given token token in the session store.
+    /// Transient store errors are retried (see `Retry`).
     pub(super) async fn create(
         &mut self,
         token: &str,
         session_info: SessionInfo,
+    ) -> Result<(), errors::SessionCreationError> {
+        let mut retry = Retry::new();
+        let mut retrying = false;
+        loop {
+            match self.try_create(token, session_info.clone(), retrying).await {
+                Err(err) if retry.should_retry(&err).await => retrying = true,
+                result => return result,
+            }
+        }
+    }
+
+    /// Make a single attempt at creating a new session in the session store.
+    /// A retry may find the session already (partly) written by an earlier
+    /// attempt whose reply was lost, so when `retrying` the session is only a
+    /// duplicate if the one stored has different data.
+    async fn try_create(
+        &mut self,
+        token: &str,
+        session_info: SessionInfo,
+        retrying: bool,
     ) -> Result<(), errors::SessionCreationError> {
         let key = format!(
             "{}:{token}",
             SessionType::from(session_info.clone()).to_parent_key_name()
         );
-        if self.0.exists(&key).await? {
+        if !retrying && self.0.exists(&key).await? {
             return Err(errors::SessionCreationError::Duplicate);
         }
         match session_info {
@@ -307,8 +1082,13 @@ impl Connection {
                     .await
             }
             SessionInfo::Authenticated { ref data, .. } => {
-                self.store_authenticated_data(&key, &session_info.csrf_token(), data.to_owned())
-                    .await
+                self.store_authenticated_data(
+                    &key,
+                    token,
+                    &session_info.csrf_token(),
+                    data.to_owned(),
+                )
+                .await
             }
         }
     }
@@ -320,11 +1100,73 @@ impl Connection {
         session_type: SessionType,
     ) -> Result<(), errors::SessionStorageError> {
         let key = format!("{}:{token}", session_type.to_parent_key_name());
+        if matches!(session_type, SessionType::Authenticated) {
+            let maybe_user_id: Option<Uuid> = self.0.hget(&key, "user_id").await?;
+            if let Some(user_id) = maybe_user_id {
+                let _: () = self.0.srem(user_index_key(user_id), token).await?;
+            }
+        } else {
+            let maybe_client: Option<String> = self.0.hget(&key, "client").await?;
+            if let Some(client) = maybe_client {
+                let _: () = self.0.zrem(pending_index_key(&client), &key).await?;
+            }
+        }
         let _: () = self.0.del(key).await?;
         Ok(())
     }
 
-    /// Set a token's expiry in seconds.
+    /// Delete every authenticated session belonging to a user, returning the
+    /// number of sessions which were deleted.
+    pub(super) async fn delete_user_sessions(
+        &mut self,
+        user_id: Uuid,
+    ) -> Result<u32, errors::SessionStorageError> {
+        let index_key = user_index_key(user_id);
+        let tokens: Vec<String> = self.0.smembers(&index_key).await?;
+        let keys: Vec<String> = tokens
+            .iter()
+            .map(|token| {
+                format!(
+                    "{}:{token}",
+                    SessionType::Authenticated.to_parent_key_name()
+                )
+            })
+            .collect();
+        let deleted: u32 = if keys.is_empty() {
+            0
+        } else {
+            self.0.del(keys).await?
+        };
+        let _: () = self.0.del(index_key).await?;
+        Ok(deleted)
+    }
+
+    /// Get the tokens of every live authenticated session belonging to a user.
+    /// Index entries whose session has since expired are pruned.
+    pub(super) async fn user_session_tokens(
+        &mut self,
+        user_id: Uuid,
+    ) -> Result<Vec<String>, errors::SessionStorageError> {
+        let index_key = user_index_key(user_id);
+        let tokens: Vec<String> = self.0.smembers(&index_key).await?;
+        let mut live = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            let key = format!(
+                "{}:{token}",
+                SessionType::Authenticated.to_parent_key_name()
+            );
+            let exists: bool = self.0.exists(key).await?;
+            if exists {
+                live.push(token);
+            } else {
+                let _: () = self.0.srem(&index_key, token).await?;
+            }
+        }
+        Ok(live)
+    }
+
+    /// Set a token's expiry in seconds. Transient store errors are retried
+    /// (see `Retry`).
     pub(super) async fn set_expiry(
         &mut self,
         token: &str,
@@ -332,13 +1174,77 @@ impl Connection {
         session_type: SessionType,
     ) -> Result<(), errors::SessionStorageError> {
         let key = format!("{}:{token}", session_type.to_parent_key_name());
-        Ok(self.0.expire(key, i64::from(seconds)).await?)
+        let mut retry = Retry::new();
+        loop {
+            match self.0.expire(&key, i64::from(seconds)).await {
+                Ok(()) => return Ok(()),
+                Err(redis_err) => {
+                    let err = errors::SessionStorageError::from(redis_err);
+                    if !retry.should_retry(&err).await {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+    /// Replace the CSRF token of the authenticated session `token` with
+    /// `new_csrf`, provided it is still `current_csrf`, keeping the replaced
+    /// token as the session's previous CSRF token. The check and replacement
+    /// are made atomically, so of concurrent rotations from the same token only
+    /// one takes effect, and the rest see its result. Returns the session's
+    /// CSRF token afterwards, or None if the session no longer exists. The
+    /// session's expiry is left unchanged.
+    pub(super) async fn rotate_csrf(
+        &mut self,
+        token: &str,
+        current_csrf: &str,
+        new_csrf: &str,
+    ) -> Result<Option<String>, errors::SessionStorageError> {
+        let key = format!(
+            "{}:{token}",
+            SessionType::Authenticated.to_parent_key_name()
+        );
+        Ok(redis::cmd("EVAL")
+            .arg(ROTATE_CSRF_SCRIPT)
+            .arg(1u8)
+            .arg(key)
+            .arg(current_csrf)
+            .arg(new_csrf)
+            .query_async(&mut self.0)
+            .await?)
     }
-    /// Get stored session info associated with a given token.
+    /// Get the remaining lifetime of a token in seconds, or None if it does not
+    /// exist or has no expiry.
+    pub(super) async fn ttl(
+        &mut self,
+        token: &str,
+        session_type: SessionType,
+    ) -> Result<Option<u32>, errors::SessionStorageError> {
+        let key = format!("{}:{token}", session_type.to_parent_key_name());
+        // TTL returns negative values for missing keys or keys without expiry.
+        let ttl: i64 = self.0.ttl(key).await?;
+        Ok(u32::try_from(ttl).ok())
+    }
+    /// Get stored session info associated with a given token. Transient store
+    /// errors are retried (see `Retry`).
     pub(super) async fn get_info(
         &mut self,
         token: &str,
         session_type: SessionType,
+    ) -> Result<Option<SessionInfo>, errors::SessionStorageError> {
+        let mut retry = Retry::new();
+        loop {
+            match self.try_get_info(token, session_type).await {
+                Err(err) if retry.should_retry(&err).await => {}
+                result => return result,
+            }
+        }
+    }
+    /// Make a single attempt at reading the session info for a given token.
+    async fn try_get_info(
+        &mut self,
+        token: &str,
+        session_type: SessionType,
     ) -> Result<Option<SessionInfo>, errors::SessionStorageError> {
         let key = format!("{}:{token}", session_type.to_parent_key_name());
         Ok(match session_type {
@@ -349,6 +1255,311 @@ impl Connection {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{
+        errors::SessionCreationError, pending_index_key, user_index_key, AuthenticatedSessionData,
+        Connection, PreAuthenticationSessionData, RegistrationSessionData, SessionInfo,
+        SessionType,
+    };
+    use crate::{
+        constants::redis::REDIS_RETRY_ATTEMPTS,
+        db::models::appuser::AppUserInsert,
+        services::sessions::fake_store::{Failure, FakeStore},
+        utils::{address::Address, email::EmailAddress, phone::PhoneNumber},
+    };
+    use core::time::Duration;
+    use redis::AsyncCommands as _;
+    use std::collections::HashMap;
+    use time::OffsetDateTime;
+    use tokio::time::{advance, Instant};
+    use uuid::Uuid;
+
+    /// An authenticated session for a user.
+    fn authenticated(user_id: Uuid) -> SessionInfo {
+        SessionInfo::Authenticated {
+            csrf: "csrf".to_owned(),
+            data: AuthenticatedSessionData {
+                user_id,
+                admin: false,
+                remember_me: false,
+                previous_csrf: None,
+                impersonated_by: None,
+            },
+        }
+    }
+
+    /// The user of the authenticated session with a token, if it exists.
+    async fn session_user(conn: &mut Connection, token: &str) -> Option<Uuid> {
+        conn.get_info(token, SessionType::Authenticated)
+            .await
+            .expect("Session should be read")
+            .and_then(|info| info.as_auth().map(|data| data.user_id))
+    }
+
+    /// Reading a session succeeds once the store recovers.
+    #[tokio::test(start_paused = true)]
+    async fn get_info_retries_transient_errors() {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        let user_id = Uuid::new_v4();
+        conn.create("token", authenticated(user_id))
+            .await
+            .expect("Session should be created");
+        store.fail_next(1);
+        assert_eq!(session_user(&mut conn, "token").await, Some(user_id));
+    }
+
+    /// Creating a session succeeds once the store recovers.
+    #[tokio::test(start_paused = true)]
+    async fn create_retries_transient_errors() {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        let user_id = Uuid::new_v4();
+        store.fail_next(1);
+        conn.create("token", authenticated(user_id))
+            .await
+            .expect("Session should be created");
+        assert_eq!(session_user(&mut conn, "token").await, Some(user_id));
+    }
+
+    /// Setting a session's expiry succeeds once the store recovers.
+    #[tokio::test(start_paused = true)]
+    async fn set_expiry_retries_transient_errors() {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        conn.create("token", authenticated(Uuid::new_v4()))
+            .await
+            .expect("Session should be created");
+        store.fail_next(1);
+        conn.set_expiry("token", 60u32, SessionType::Authenticated)
+            .await
+            .expect("Expiry should be set");
+        let ttl = conn
+            .ttl("token", SessionType::Authenticated)
+            .await
+            .expect("TTL should be read");
+        assert_eq!(ttl, Some(60u32));
+    }
+
+    /// The error is returned once every attempt has failed.
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_retry_attempts() {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        store.fail_next(*REDIS_RETRY_ATTEMPTS);
+        assert!(
+            conn.get_info("token", SessionType::Authenticated)
+                .await
+                .is_err(),
+            "Every attempt should fail"
+        );
+    }
+
+    /// A duplicate token is rejected at once, without backing off to retry.
+    #[tokio::test(start_paused = true)]
+    async fn duplicate_is_not_retried() {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        conn.create("token", authenticated(Uuid::new_v4()))
+            .await
+            .expect("Session should be created");
+        let started = Instant::now();
+        let err = conn
+            .create("token", authenticated(Uuid::new_v4()))
+            .await
+            .expect_err("Token should be a duplicate");
+        assert!(matches!(err, SessionCreationError::Duplicate));
+        assert_eq!(started.elapsed().as_secs(), 0);
+    }
+
+    /// A retry which finds the session written by an attempt whose reply was
+    /// lost succeeds.
+    #[tokio::test(start_paused = true)]
+    async fn create_retry_accepts_own_write() {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        let user_id = Uuid::new_v4();
+        store.fail("HSET", Failure::ReplyLost);
+        conn.create("token", authenticated(user_id))
+            .await
+            .expect("Session should be created");
+        assert_eq!(session_user(&mut conn, "token").await, Some(user_id));
+    }
+
+    /// A retry which finds another session under the token still rejects it
+    /// as a duplicate, leaving the other session unchanged.
+    #[tokio::test(start_paused = true)]
+    async fn create_retry_rejects_other_session() {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        let user_id = Uuid::new_v4();
+        conn.create("token", authenticated(user_id))
+            .await
+            .expect("Session should be created");
+        store.fail("EXISTS", Failure::Dropped);
+        let err = conn
+            .create("token", authenticated(Uuid::new_v4()))
+            .await
+            .expect_err("Token should be a duplicate");
+        assert!(matches!(err, SessionCreationError::Duplicate));
+        assert_eq!(session_user(&mut conn, "token").await, Some(user_id));
+    }
+
+    /// Concurrent rotations from the same CSRF token agree on a single new
+    /// token, with the original kept as the previous one.
+    #[tokio::test]
+    async fn concurrent_csrf_rotations_agree() {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        let mut other_conn = Connection::fake(&store);
+        conn.create("token", authenticated(Uuid::new_v4()))
+            .await
+            .expect("Session should be created");
+        let (first, second) = tokio::join!(
+            conn.rotate_csrf("token", "csrf", "first"),
+            other_conn.rotate_csrf("token", "csrf", "second"),
+        );
+        let first_csrf = first.expect("CSRF token should be rotated");
+        assert_eq!(first_csrf, second.expect("CSRF token should be rotated"));
+        assert_ne!(first_csrf.as_deref(), Some("csrf"));
+        let info = conn
+            .get_info("token", SessionType::Authenticated)
+            .await
+            .expect("Session should be read")
+            .expect("Session should exist");
+        assert_eq!(Some(info.csrf_token()), first_csrf);
+        assert_eq!(info.previous_csrf_token().as_deref(), Some("csrf"));
+    }
+
+    /// Reconciling user indexes removes the tokens of expired sessions and
+    /// keeps those of live ones.
+    #[tokio::test(start_paused = true)]
+    async fn reconcile_user_indexes_removes_expired_sessions() {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        let user_id = Uuid::new_v4();
+        for token in ["live", "stale"] {
+            conn.create(token, authenticated(user_id))
+                .await
+                .expect("Session should be created");
+        }
+        conn.set_expiry("stale", 1, SessionType::Authenticated)
+            .await
+            .expect("Expiry should be set");
+        advance(Duration::from_secs(2)).await;
+        let removed = conn
+            .reconcile_user_indexes()
+            .await
+            .expect("Indexes should be reconciled");
+        assert_eq!(removed, 1);
+        let tokens: Vec<String> = conn
+            .0
+            .smembers(user_index_key(user_id))
+            .await
+            .expect("Index should be read");
+        assert_eq!(tokens, ["live"]);
+    }
+
+    /// Reconciling pending indexes removes expired entries and those whose
+    /// session was deleted without them, and keeps those of live sessions.
+    #[tokio::test]
+    async fn reconcile_pending_indexes_removes_stale_entries() {
+        let store = FakeStore::default();
+        let mut conn = Connection::fake(&store);
+        for token in ["live", "deleted"] {
+            let info = SessionInfo::PreAuthentication {
+                csrf: "csrf".to_owned(),
+                data: PreAuthenticationSessionData {
+                    user_id: Uuid::new_v4(),
+                    remember_me: false,
+                    email_first_factor: false,
+                },
+            };
+            conn.create(token, info)
+                .await
+                .expect("Session should be created");
+            let tracked = conn
+                .track_pending_session("client", token, SessionType::PreAuthentication, 600)
+                .await
+                .expect("Session should be tracked");
+            assert!(tracked);
+        }
+        let parent = SessionType::PreAuthentication.to_parent_key_name();
+        let index_key = pending_index_key("client");
+        // As if the API stopped part way through deleting the session.
+        let _: () = conn
+            .0
+            .del(format!("{parent}:deleted"))
+            .await
+            .expect("Session should be deleted");
+        let expired_at = OffsetDateTime::now_utc().unix_timestamp().saturating_sub(1);
+        let _: () = conn
+            .0
+            .zadd(&index_key, format!("{parent}:expired"), expired_at)
+            .await
+            .expect("Entry should be added");
+        let removed = conn
+            .reconcile_pending_indexes()
+            .await
+            .expect("Indexes should be reconciled");
+        assert_eq!(removed, 2);
+        let keys: Vec<String> = conn
+            .0
+            .zrange(&index_key, 0, -1)
+            .await
+            .expect("Index should be read");
+        assert_eq!(keys, [format!("{parent}:live")]);
+    }
+
+    /// The PII of a user part way through registering is stored encrypted,
+    /// and read back intact.
+    #[tokio::test]
+    async fn registration_data_is_sealed() {
+        let store = FakeStore::default();
+        let address = Address::new("1 High Street", None, "London", "SW1A 1AA", "GB")
+            .expect("Address should be valid");
+        let mut conn = Connection::fake(&store);
+        let user_data = AppUserInsert::new(
+            EmailAddress::try_from("alice.smith@example.com").expect("Email should be valid"),
+            "Alice",
+            "Smith",
+            address.clone(),
+            Some(PhoneNumber::try_from("+447700900123").expect("Phone number should be valid")),
+        );
+        let info = SessionInfo::Registration {
+            csrf: "csrf".to_owned(),
+            data: RegistrationSessionData {
+                user_data: Box::new(user_data),
+            },
+        };
+        conn.create("token", info)
+            .await
+            .expect("Session should be created");
+        let key = format!("{}:token", SessionType::Registration.to_parent_key_name());
+        let stored: HashMap<String, String> =
+            conn.0.hgetall(&key).await.expect("Session should be read");
+        for pii in ["alice.smith", "Alice", "Smith", "High Street", "7700900123"] {
+            assert!(
+                stored.values().all(|value| !value.contains(pii)),
+                "{pii} stored in plaintext"
+            );
+        }
+        let read = conn
+            .get_info("token", SessionType::Registration)
+            .await
+            .expect("Session should be read")
+            .expect("Session should exist");
+        let user = &read
+            .as_registration()
+            .expect("Session should be a registration")
+            .user_data;
+        assert_eq!(String::from(user.email.clone()), "alice.smith@example.com");
+        assert_eq!(user.forename, "Alice");
+        assert_eq!(user.address.to_stored(), address.to_stored());
+    }
+}
+
 /// Errors returned by functions in this module.
 pub mod errors {
     use redis::RedisError;
@@ -375,4 +1586,30 @@ pub mod errors {
             Self::from(SessionStorageError::from(err))
         }
     }
+
+    /// Errors which may be worth retrying.
+    pub(super) trait Retryable {
+        /// Whether the error is a transient, connection-level failure (as
+        /// opposed to a logical one), so the operation may succeed if retried.
+        fn is_transient(&self) -> bool;
+    }
+
+    impl Retryable for SessionStorageError {
+        fn is_transient(&self) -> bool {
+            let Self(ref err) = *self;
+            err.is_io_error()
+                || err.is_timeout()
+                || err.is_connection_dropped()
+                || err.is_connection_refusal()
+        }
+    }
+
+    impl Retryable for SessionCreationError {
+        fn is_transient(&self) -> bool {
+            match *self {
+                Self::Duplicate => false,
+                Self::StorageError(ref err) => err.is_transient(),
+            }
+        }
+    }
 }
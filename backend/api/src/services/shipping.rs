@@ -0,0 +1,130 @@
+//! Calculation of the cost of shipping an order by each shipping method. The
+//! calculator used is chosen by configuration. If none is configured, only
+//! standard shipping is offered, and orders are not charged for it.
+use crate::{
+    constants::orders::{
+        EXPRESS_SHIPPING_DAYS, SHIPPING_EXPRESS_COUNTRIES, SHIPPING_EXPRESS_RATE,
+        SHIPPING_FLAT_RATE, STANDARD_SHIPPING_DAYS,
+    },
+    db::models::apporder::ShippingMethod,
+    utils::{address::Address, pennies::Pennies},
+};
+use serde::Serialize;
+use std::sync::LazyLock;
+
+/// A way of pricing the shipping of an order.
+pub trait ShippingCalculator: Send + Sync {
+    /// The cost of shipping an order weighing `order_weight` grams (None if
+    /// the weight of any of its products is unknown) to `destination` by
+    /// `method`, or None if the method is not offered to the destination.
+    fn cost(
+        &self,
+        method: ShippingMethod,
+        order_weight: Option<i64>,
+        destination: &Address,
+    ) -> Option<Pennies>;
+}
+
+/// Charges the same amount for shipping every order by each method, whatever
+/// its weight. Standard shipping is offered everywhere, and express shipping
+/// (if it has a rate) only to the countries listed for it.
+pub struct FlatRate {
+    /// The amount charged for standard shipping of each order.
+    standard: Pennies,
+    /// The amount charged for express shipping of each order, if offered.
+    express: Option<Pennies>,
+    /// The country codes of the destinations express shipping is offered to.
+    express_countries: Vec<String>,
+}
+
+impl FlatRate {
+    /// Construct a flat rate calculator charging `standard` pennies per order
+    /// for standard shipping, and `express` pennies per order for express
+    /// shipping to `express_countries`.
+    pub fn new(standard: u32, express: Option<u32>, express_countries: Vec<String>) -> Self {
+        Self {
+            standard: Pennies::from(standard),
+            express: express.map(Pennies::from),
+            express_countries,
+        }
+    }
+}
+
+impl ShippingCalculator for FlatRate {
+    fn cost(
+        &self,
+        method: ShippingMethod,
+        _order_weight: Option<i64>,
+        destination: &Address,
+    ) -> Option<Pennies> {
+        match method {
+            ShippingMethod::Standard => Some(self.standard),
+            ShippingMethod::Express => self.express.filter(|_| {
+                self.express_countries
+                    .iter()
+                    .any(|country| country == destination.country())
+            }),
+        }
+    }
+}
+
+/// The configured shipping calculator, if any. A flat rate calculator is used
+/// if `SHIPPING_FLAT_RATE` is set.
+static CALCULATOR: LazyLock<Option<Box<dyn ShippingCalculator>>> = LazyLock::new(|| {
+    SHIPPING_FLAT_RATE.map(|rate| -> Box<dyn ShippingCalculator> {
+        Box::new(FlatRate::new(
+            rate,
+            *SHIPPING_EXPRESS_RATE,
+            SHIPPING_EXPRESS_COUNTRIES.clone(),
+        ))
+    })
+});
+
+/// The cost of shipping an order by `method` using the configured
+/// calculator, or None if the method is not offered to the destination. If
+/// no calculator is configured, standard shipping is free and nothing else
+/// is offered.
+pub fn cost(
+    method: ShippingMethod,
+    order_weight: Option<i64>,
+    destination: &Address,
+) -> Option<Pennies> {
+    CALCULATOR.as_ref().map_or_else(
+        || (method == ShippingMethod::Standard).then_some(Pennies::ZERO),
+        |calculator| calculator.cost(method, order_weight, destination),
+    )
+}
+
+/// The most working days an order sent by `method` should take to arrive.
+const fn estimated_days(method: ShippingMethod) -> u32 {
+    match method {
+        ShippingMethod::Standard => STANDARD_SHIPPING_DAYS,
+        ShippingMethod::Express => EXPRESS_SHIPPING_DAYS,
+    }
+}
+
+/// A shipping method offered for an order, with what it costs.
+#[derive(Serialize)]
+pub struct ShippingOption {
+    /// The shipping method.
+    method: ShippingMethod,
+    /// The cost in pennies of shipping the order by this method.
+    cost: i64,
+    /// The most working days the order should take to arrive.
+    estimated_days: u32,
+}
+
+/// Every shipping method offered for an order weighing `order_weight` grams
+/// to `destination`, with their costs.
+pub fn options(order_weight: Option<i64>, destination: &Address) -> Vec<ShippingOption> {
+    ShippingMethod::ALL
+        .into_iter()
+        .filter_map(|method| {
+            cost(method, order_weight, destination).map(|price| ShippingOption {
+                method,
+                cost: price.as_i64(),
+                estimated_days: estimated_days(method),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,253 @@
+//! Processing of Stripe webhook events through a transactional inbox. Verified
+//! events are persisted before Stripe is acknowledged, then processed by a
+//! background task which retries them until they succeed, so that an order
+//! confirmation is never lost to a crash or transient failure.
+use core::time::Duration;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use super::orders::{self, errors::OrderConfirmationError};
+use crate::{
+    constants::stripe::STRIPE_EVENT_POLL_INTERVAL,
+    db::{
+        self,
+        errors::DatabaseError,
+        models::stripe_event::{StripeEvent, StripeEventInsert, StripeRejectedEventInsert},
+    },
+};
+
+/// Persist a successful payment event, the amount it received and the
+/// `PaymentIntent` it was made through (for refunds), for later processing.
+/// Events which were already received are ignored.
+pub async fn receive_payment_succeeded(
+    event_id: String,
+    order_id: Uuid,
+    amount: i64,
+    payment_intent: String,
+    db_conn: &db::ConnectionPool,
+) -> Result<(), DatabaseError> {
+    let inserted = StripeEventInsert {
+        id: event_id.clone(),
+        order_id,
+        amount,
+        payment_intent,
+    }
+    .store(db_conn)
+    .await?;
+    if !inserted {
+        tracing::warn!("Ignoring duplicate delivery of Stripe event {event_id}.");
+    }
+    Ok(())
+}
+
+/// Record a verified event which can never be processed, so that it can be
+/// acknowledged (stopping Stripe from redelivering it) without being lost.
+pub async fn reject_event(
+    event_id: String,
+    event_type: String,
+    reason: String,
+    db_conn: &db::ConnectionPool,
+) -> Result<(), DatabaseError> {
+    tracing::warn!("Rejecting unprocessable Stripe event {event_id}: {reason}");
+    StripeRejectedEventInsert {
+        id: event_id,
+        event_type,
+        reason,
+    }
+    .store(db_conn)
+    .await
+}
+
+/// Whether the amount an event's payment received covers the amount charged
+/// for its order. An unconfirmed order may be changed after its payment began,
+/// in which case it must not be confirmed for the original amount. Events
+/// whose amount was not recorded are trusted.
+async fn payment_covers_order(
+    event: &StripeEvent,
+    db_conn: &db::ConnectionPool,
+) -> Result<bool, DatabaseError> {
+    let Some(paid) = event.amount() else {
+        return Ok(true);
+    };
+    Ok(orders::get_order(event.order_id(), db_conn)
+        .await?
+        .is_none_or(|order| paid >= order.amount_charged))
+}
+
+/// Process every stored event which has not yet been processed, confirming
+/// the orders they refer to. Events which fail due to a database error are
+/// left unprocessed to be retried later.
+pub async fn process_pending(db_conn: &db::ConnectionPool) -> Result<(), DatabaseError> {
+    for event in StripeEvent::select_unprocessed(db_conn).await? {
+        match payment_covers_order(&event, db_conn).await {
+            Ok(true) => {}
+            Ok(false) => {
+                // Retrying can never succeed, so the payment must be resolved
+                // manually.
+                tracing::warn!(
+                    "Stripe event {} paid less than is charged for order {}. Not confirming.",
+                    event.id(),
+                    event.order_id()
+                );
+                event.mark_processed(db_conn).await?;
+                continue;
+            }
+            Err(err) => {
+                tracing::error!(
+                    "Error raised by database while processing Stripe event {}, will retry: {err}",
+                    event.id()
+                );
+                event.record_failure(db_conn).await?;
+                continue;
+            }
+        }
+        match orders::confirm_order(event.order_id(), None, db_conn).await {
+            Ok(()) => event.mark_processed(db_conn).await?,
+            Err(OrderConfirmationError::OrderNonExistent(order_id)) => {
+                // Retrying can never succeed, so the event is discarded.
+                tracing::warn!(
+                    "Stripe event {} confirmed order {order_id}, which does not exist.",
+                    event.id()
+                );
+                event.mark_processed(db_conn).await?;
+            }
+            Err(OrderConfirmationError::InsufficientStock(product_id)) => {
+                // The payment has been taken, but retrying can't succeed
+                // unless the product is restocked, so it must be resolved
+                // manually.
+                tracing::warn!(
+                    "Stripe event {} paid for order {}, but product {product_id} is out of stock. Not confirming.",
+                    event.id(),
+                    event.order_id()
+                );
+                event.mark_processed(db_conn).await?;
+            }
+            Err(OrderConfirmationError::ConcurrencyConflict) => {
+                tracing::warn!(
+                    "Order {} was modified concurrently while processing Stripe event {}, will retry.",
+                    event.order_id(),
+                    event.id()
+                );
+                event.record_failure(db_conn).await?;
+            }
+            Err(OrderConfirmationError::DatabaseError(err)) => {
+                tracing::error!(
+                    "Error raised by database while processing Stripe event {}, will retry: {err}",
+                    event.id()
+                );
+                event.record_failure(db_conn).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Process pending events every `STRIPE_EVENT_POLL_INTERVAL` seconds, forever.
+/// Intended to be spawned as a background task at startup, where it also picks
+/// up any events left unprocessed by a previous run.
+pub async fn run_processor(db_conn: db::ConnectionPool) -> ! {
+    loop {
+        if let Err(err) = process_pending(&db_conn).await {
+            tracing::error!("Error raised by database while processing Stripe events: {err}");
+        }
+        sleep(Duration::from_secs(STRIPE_EVENT_POLL_INTERVAL)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{process_pending, receive_payment_succeeded};
+    use crate::{
+        db::{
+            models::{
+                apporder::{AppOrder, AppOrderStatus, ShippingMethod},
+                product::ProductInsert,
+                stripe_event::StripeEvent,
+            },
+            ConnectionPool,
+        },
+        services::orders::create_order,
+        testing::store_user,
+    };
+
+    /// Store a customer's order for a product, as yet unpaid.
+    async fn unpaid_order(db_conn: &ConnectionPool) -> AppOrder {
+        let customer = store_user("customer@example.com", db_conn).await;
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        create_order(
+            customer.id(),
+            vec![(product_id, 1)],
+            None,
+            ShippingMethod::Standard,
+            db_conn,
+        )
+        .await
+        .expect("Order should be created")
+    }
+
+    /// The current status of an order.
+    async fn status(order: &AppOrder, db_conn: &ConnectionPool) -> AppOrderStatus {
+        AppOrder::select_one(order.id(), db_conn)
+            .await
+            .expect("Order should be selected")
+            .expect("Order should exist")
+            .status()
+    }
+
+    /// An event persisted before a crash, and so never processed, confirms
+    /// its order once the processor runs again.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn persisted_event_confirms_order_after_crash(db_conn: ConnectionPool) {
+        let order = unpaid_order(&db_conn).await;
+        receive_payment_succeeded(
+            "evt_test".to_owned(),
+            order.id(),
+            order.amount_charged,
+            "pi_test".to_owned(),
+            &db_conn,
+        )
+        .await
+        .expect("Event should be stored");
+        assert!(status(&order, &db_conn).await != AppOrderStatus::Confirmed);
+
+        process_pending(&db_conn)
+            .await
+            .expect("Events should be processed");
+        assert!(status(&order, &db_conn).await == AppOrderStatus::Confirmed);
+        assert!(StripeEvent::select_unprocessed(&db_conn)
+            .await
+            .expect("Events should be selected")
+            .is_empty());
+    }
+
+    /// An event delivered again after it was processed is ignored.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn redelivered_event_is_ignored(db_conn: ConnectionPool) {
+        let order = unpaid_order(&db_conn).await;
+        for _ in 0..2u8 {
+            receive_payment_succeeded(
+                "evt_test".to_owned(),
+                order.id(),
+                order.amount_charged,
+                "pi_test".to_owned(),
+                &db_conn,
+            )
+            .await
+            .expect("Event should be stored");
+            process_pending(&db_conn)
+                .await
+                .expect("Events should be processed");
+        }
+        assert!(status(&order, &db_conn).await == AppOrderStatus::Confirmed);
+        assert!(StripeEvent::select_unprocessed(&db_conn)
+            .await
+            .expect("Events should be selected")
+            .is_empty());
+    }
+}
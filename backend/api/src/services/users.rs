@@ -1,23 +1,38 @@
 //! Logic for working with application users, interacts with the `AppUser` model.
-use core::fmt;
+use core::{fmt, time::Duration};
 
 use serde::Deserialize;
+use time::{OffsetDateTime, PrimitiveDateTime};
+use tokio::time::sleep;
 use uuid::Uuid;
 
 use crate::{
-    constants::passwords::{PASSWORD_MAX_LENGTH, PASSWORD_MIN_LENGTH},
+    constants::{
+        passwords::{PASSWORD_MAX_LENGTH, PASSWORD_MIN_LENGTH},
+        totp::TOTP_SECRET_LENGTH,
+        users::{USER_DELETION_GRACE_PERIOD, USER_PURGE_INTERVAL},
+    },
     db::{
         self,
         models::{
+            apporder::AppOrder,
             appuser::{AppUser, AppUserRole, AppUserSearchParameters},
             password::Password,
-            totp::{Totp, TotpInsert},
+            totp::{self, Totp, TotpInsert},
         },
     },
-    utils::email::EmailAddress,
+    utils::{
+        address::Address, email::EmailAddress, mailer::EmailSender, pagination::Pagination,
+        phone::PhoneNumber, redact::RedactedEmail, sms::SmsSender, text,
+    },
 };
 
-use super::registration;
+use super::{
+    auth,
+    errors::StorageError,
+    registration,
+    sessions::{self, GenericAuthenticatedSession, OneTimeCodeKind},
+};
 
 /// Set a user's 2FA token. Requires an example code generated by the authenticator
 /// to assure correctness.
@@ -27,19 +42,170 @@ pub async fn set_2fa(
     code: &str,
     db_conn: &db::ConnectionPool,
 ) -> Result<Totp, errors::SetTotpError> {
-    let totp = TotpInsert { user_id, secret };
+    let totp = TotpInsert::new(user_id, secret);
     if !totp.validate(code) {
         return Err(errors::SetTotpError::IncorrectCode(user_id));
     }
-    Ok(totp.store(db_conn).await?)
+    totp.store(db_conn).await.map_err(|err| {
+        if err.is_unique_violation() {
+            errors::SetTotpError::AlreadyEnrolled(user_id)
+        } else {
+            err.into()
+        }
+    })
+}
+
+/// Check whether a code is valid for a candidate 2FA secret, without storing
+/// anything. Used to confirm an authenticator is set up before committing it.
+pub fn verify_2fa(
+    user_id: Uuid,
+    secret: Vec<u8>,
+    code: &str,
+) -> Result<bool, errors::VerifyTotpError> {
+    let totp = TotpInsert::new(user_id, secret);
+    totp.totp()?;
+    Ok(totp.validate(code))
+}
+
+/// Begin opting a user in to email MFA by sending a one-time code to their
+/// email address, to be confirmed with `enable_email_mfa`. This proves that
+/// they can receive email at that address before it is relied upon.
+pub async fn request_email_mfa(
+    session: &GenericAuthenticatedSession,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+    email_sender: &dyn EmailSender,
+) -> Result<(), errors::EmailMfaError> {
+    let user = AppUser::select_one(session.user_id(), db_conn)
+        .await
+        .map_err(StorageError::from)?
+        .ok_or(errors::EmailMfaError::UserNonExistent(session.user_id()))?;
+    let code = session
+        .issue_one_time_code(OneTimeCodeKind::Email, session_store_conn)
+        .await
+        .map_err(StorageError::from)?
+        .ok_or(errors::EmailMfaError::TooManyCodes(session.user_id()))?;
+    email_sender
+        .send(
+            &user.email,
+            "Confirm your SecureCart email for two-factor authentication",
+            &format!("Your SecureCart verification code is {code}."),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Opt a user in to email MFA, given the code sent by `request_email_mfa`.
+pub async fn enable_email_mfa(
+    session: &GenericAuthenticatedSession,
+    code: &str,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+) -> Result<(), errors::EmailMfaError> {
+    let user_id = session.user_id();
+    if !session
+        .verify_one_time_code(OneTimeCodeKind::Email, code, session_store_conn)
+        .await
+        .map_err(StorageError::from)?
+    {
+        return Err(errors::EmailMfaError::IncorrectCode(user_id));
+    }
+    let mut user = AppUser::select_one(user_id, db_conn)
+        .await
+        .map_err(StorageError::from)?
+        .ok_or(errors::EmailMfaError::UserNonExistent(user_id))?;
+    user.email_mfa_enabled = true;
+    user.update(db_conn).await.map_err(StorageError::from)?;
+    Ok(())
+}
+
+/// Opt a user out of email MFA. The user must re-authenticate with their
+/// primary credential, so that a session alone (e.g. one left signed in on a
+/// shared device) can't be used to weaken their account's protection.
+pub async fn disable_email_mfa(
+    user_id: Uuid,
+    credential: auth::PrimaryAuthenticationMethod,
+    db_conn: &db::ConnectionPool,
+) -> Result<(), errors::EmailMfaError> {
+    if !credential
+        .authenticate(user_id, db_conn)
+        .await
+        .map_err(StorageError::from)?
+    {
+        return Err(errors::EmailMfaError::IncorrectCredential(user_id));
+    }
+    let mut user = AppUser::select_one(user_id, db_conn)
+        .await
+        .map_err(StorageError::from)?
+        .ok_or(errors::EmailMfaError::UserNonExistent(user_id))?;
+    user.email_mfa_enabled = false;
+    user.update(db_conn).await.map_err(StorageError::from)?;
+    Ok(())
+}
+
+/// Begin verifying a user's phone number by sending a one-time code to it by
+/// SMS, to be confirmed with `verify_phone`. SMS MFA is only offered once the
+/// user has proven they can receive SMS messages at the number.
+pub async fn request_phone_verification(
+    session: &GenericAuthenticatedSession,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+    sms_sender: &dyn SmsSender,
+) -> Result<(), errors::PhoneVerificationError> {
+    let user_id = session.user_id();
+    if !sms_sender.is_configured() {
+        return Err(errors::PhoneVerificationError::Unavailable);
+    }
+    let phone = AppUser::select_one(user_id, db_conn)
+        .await
+        .map_err(StorageError::from)?
+        .ok_or(errors::PhoneVerificationError::UserNonExistent(user_id))?
+        .phone
+        .ok_or(errors::PhoneVerificationError::NoPhoneNumber(user_id))?;
+    let code = session
+        .issue_one_time_code(OneTimeCodeKind::PhoneVerification, session_store_conn)
+        .await
+        .map_err(StorageError::from)?
+        .ok_or(errors::PhoneVerificationError::TooManyCodes(user_id))?;
+    sms_sender
+        .send(
+            &phone,
+            &format!("Your SecureCart phone verification code is {code}."),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Mark a user's phone number as verified, given the code sent by
+/// `request_phone_verification`.
+pub async fn verify_phone(
+    session: &GenericAuthenticatedSession,
+    code: &str,
+    db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
+) -> Result<(), errors::PhoneVerificationError> {
+    let user_id = session.user_id();
+    if !session
+        .verify_one_time_code(OneTimeCodeKind::PhoneVerification, code, session_store_conn)
+        .await
+        .map_err(StorageError::from)?
+    {
+        return Err(errors::PhoneVerificationError::IncorrectCode(user_id));
+    }
+    let mut user = AppUser::select_one(user_id, db_conn)
+        .await
+        .map_err(StorageError::from)?
+        .ok_or(errors::PhoneVerificationError::UserNonExistent(user_id))?;
+    user.phone_verified = true;
+    user.update(db_conn).await.map_err(StorageError::from)?;
+    Ok(())
 }
 
 /// Generate a new 2FA token and associated validator.
 pub fn generate_2fa() -> Result<totp_rs::TOTP, errors::GenerateTotpError> {
-    let mut secret_buf: [u8; 32] = [0; 32];
+    let mut secret_buf = vec![0; *TOTP_SECRET_LENGTH];
     getrandom::fill(&mut secret_buf).expect("Error getting OS random while generating 2fa token.");
-    let rfc6238 = totp_rs::Rfc6238::with_defaults(secret_buf.to_vec())?;
-    Ok(totp_rs::TOTP::from_rfc6238(rfc6238).expect("Invalid URL in TOTP initialisation"))
+    Ok(totp::configured_totp(secret_buf)?)
 }
 
 /// Retrieve a user's information from the database.
@@ -50,24 +216,86 @@ pub async fn retrieve_user(
     Ok(AppUser::select_one(user_id, db_conn).await?)
 }
 
-/// Search for a user matching a given set of search parameters (email/role).
+/// Search for a user matching a given set of search parameters (email/role),
+/// returning only the given page of results.
 pub async fn search_users(
     params: AppUserSearchParameters,
+    page: Pagination,
     db_conn: &db::ConnectionPool,
 ) -> Result<Vec<AppUser>, errors::UserSearchError> {
-    Ok(AppUser::search(params, db_conn).await?)
+    Ok(AppUser::search(params, Some(page), db_conn).await?)
 }
 
-/// Delete a user from the database.
+/// Delete a user, logging them out of every session. The account is only
+/// marked as deleted, so that an administrator can restore it with
+/// `restore_user` until `USER_DELETION_GRACE_PERIOD` has passed, after which
+/// it is purged. Deleting a user who is already deleted does nothing.
 pub async fn delete_user(
     user_id: Uuid,
     db_conn: &db::ConnectionPool,
+    session_store_conn: &mut sessions::store::Connection,
 ) -> Result<(), errors::UserDeletionError> {
-    Ok(AppUser::select_one(user_id, db_conn)
+    if AppUser::select_one(user_id, db_conn).await?.is_none() {
+        return Err(errors::UserDeletionError::UserNonExistent(user_id));
+    }
+    let current_time = OffsetDateTime::now_utc();
+    let now = PrimitiveDateTime::new(current_time.date(), current_time.time());
+    AppUser::soft_delete(user_id, now, db_conn).await?;
+    sessions::revoke_user_sessions(user_id, session_store_conn).await?;
+    Ok(())
+}
+
+/// Restore a deleted user whose account has not yet been purged, so that
+/// they can log in again.
+pub async fn restore_user(
+    user_id: Uuid,
+    db_conn: &db::ConnectionPool,
+) -> Result<AppUser, errors::UserRestorationError> {
+    if !AppUser::restore(user_id, db_conn).await? {
+        return Err(match AppUser::select_one(user_id, db_conn).await? {
+            Some(user) if user.deleted_at.is_some() => {
+                errors::UserRestorationError::Purged(user_id)
+            }
+            Some(_) => errors::UserRestorationError::NotDeleted(user_id),
+            None => errors::UserRestorationError::UserNonExistent(user_id),
+        });
+    }
+    AppUser::select_one(user_id, db_conn)
         .await?
-        .ok_or(errors::UserDeletionError::UserNonExistent(user_id))?
-        .delete(db_conn)
-        .await?)
+        .ok_or(errors::UserRestorationError::UserNonExistent(user_id))
+}
+
+/// Purge every user who was deleted more than `USER_DELETION_GRACE_PERIOD`
+/// seconds ago. Their unconfirmed orders are deleted, and then so are they,
+/// unless they have confirmed orders, which are financial records that must
+/// be kept. Those users are anonymized instead. Returns the number purged.
+pub async fn purge_deleted_users(
+    db_conn: &db::ConnectionPool,
+) -> Result<u64, db::errors::DatabaseError> {
+    let cutoff = OffsetDateTime::now_utc().saturating_sub(time::Duration::seconds(i64::from(
+        *USER_DELETION_GRACE_PERIOD,
+    )));
+    let cutoff_time = PrimitiveDateTime::new(cutoff.date(), cutoff.time());
+    let mut transaction = db::begin(db_conn).await?;
+    AppOrder::delete_unconfirmed_of_deleted_users(cutoff_time, &mut *transaction).await?;
+    let deleted = AppUser::purge_deleted(cutoff_time, &mut *transaction).await?;
+    let anonymized = AppUser::anonymize_deleted(cutoff_time, &mut *transaction).await?;
+    db::commit(transaction).await?;
+    Ok(deleted.saturating_add(anonymized))
+}
+
+/// Purge deleted users whose grace period has passed every
+/// `USER_PURGE_INTERVAL` seconds, forever. Intended to be spawned as a
+/// background task at startup.
+pub async fn run_user_purger(db_conn: db::ConnectionPool) -> ! {
+    loop {
+        sleep(Duration::from_secs(USER_PURGE_INTERVAL)).await;
+        match purge_deleted_users(&db_conn).await {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!("Purged {purged} deleted users"),
+            Err(err) => tracing::error!("Error purging deleted users, will retry: {err}"),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -76,21 +304,21 @@ pub struct AppUserUpdate {
     /// The new email address if present
     email: Option<EmailAddress>,
     /// The new forename if present
+    #[serde(default, deserialize_with = "text::optional_name")]
     forename: Option<String>,
     /// The new surname if present
+    #[serde(default, deserialize_with = "text::optional_name")]
     surname: Option<String>,
     /// The new address if present
-    address: Option<String>,
+    address: Option<Address>,
+    /// The new phone number if present.
+    phone: Option<PhoneNumber>,
 }
 
 impl fmt::Display for AppUserUpdate {
-    #[expect(
-        clippy::min_ident_chars,
-        reason = "f is the trait defined parameter name"
-    )]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(ref email) = self.email {
-            write!(f, "email={email} ")?;
+            write!(f, "email={} ", RedactedEmail::from(email))?;
         }
         if self.forename.is_some() {
             write!(f, "forename=[REDACTED] ")?;
@@ -101,6 +329,9 @@ impl fmt::Display for AppUserUpdate {
         if self.address.is_some() {
             write!(f, "address=[REDACTED] ")?;
         }
+        if self.phone.is_some() {
+            write!(f, "phone=[REDACTED] ")?;
+        }
         Ok(())
     }
 }
@@ -115,6 +346,10 @@ pub async fn update_user(
         .await?
         .ok_or(errors::UserUpdateError::UserNonExistent(user_id))?;
     if let Some(email) = data.email {
+        if email.to_string() != user.email.to_string() {
+            // The new address has not been proven to receive email.
+            user.email_mfa_enabled = false;
+        }
         email.clone_into(&mut user.email);
     }
     if let Some(forename) = data.forename {
@@ -124,7 +359,18 @@ pub async fn update_user(
         surname.clone_into(&mut user.surname);
     }
     if let Some(address) = data.address {
-        address.clone_into(&mut user.address);
+        user.address = address;
+    }
+    if let Some(phone) = data.phone {
+        if user
+            .phone
+            .as_ref()
+            .is_none_or(|old| old.to_string() != phone.to_string())
+        {
+            // The new number has not been proven to receive SMS messages.
+            user.phone_verified = false;
+        }
+        user.phone = Some(phone);
     }
     user.update(db_conn).await?;
     Ok(user)
@@ -154,6 +400,47 @@ pub async fn update_credential(
     Ok(())
 }
 
+/// An action which one user can take on another user's account.
+#[derive(Clone, Copy, Debug)]
+pub enum UserAction {
+    /// Updating the user's data.
+    Update,
+    /// Deleting the user's account.
+    Delete,
+}
+
+impl fmt::Display for UserAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Update => write!(f, "update"),
+            Self::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+/// Decide whether the user authenticated with `actor` may take an action on
+/// the `target` user's account. Users may act on their own accounts, and
+/// administrators may act on customers' accounts, but never on those of other
+/// administrators.
+pub fn authorize_user_action(
+    actor: &GenericAuthenticatedSession,
+    target: &AppUser,
+    action: UserAction,
+) -> Result<(), errors::UserActionError> {
+    let actor_id = actor.user_id();
+    let permitted = actor_id == target.id()
+        || (matches!(*actor, GenericAuthenticatedSession::Administrator(_)) && !target.is_admin());
+    if permitted {
+        Ok(())
+    } else {
+        Err(errors::UserActionError::Forbidden {
+            actor: actor_id,
+            target: target.id(),
+            action,
+        })
+    }
+}
+
 /// Promote a user to have the Administrator role
 pub async fn promote_user(
     user_id: Uuid,
@@ -162,7 +449,7 @@ pub async fn promote_user(
     let mut user = AppUser::select_one(user_id, db_conn)
         .await?
         .ok_or(errors::UserPromotionError::UserNonExistent(user_id))?;
-    if user.role == AppUserRole::Administrator {
+    if user.is_admin() {
         Err(errors::UserPromotionError::AlreadyAdministrator(user_id))
     } else {
         user.role = AppUserRole::Administrator;
@@ -176,7 +463,61 @@ pub mod errors {
     use thiserror::Error;
     use uuid::Uuid;
 
-    use crate::db::errors::DatabaseError;
+    pub use super::super::errors::StorageError;
+    use crate::{
+        db::errors::DatabaseError,
+        services::sessions::errors::SessionStorageError,
+        utils::{mailer::MailError, sms::SmsError},
+    };
+
+    #[derive(Debug, Error)]
+    /// An error returned while opting a user in or out of email MFA.
+    pub enum EmailMfaError {
+        #[error(transparent)]
+        /// An error in the underlying storage.
+        StorageError(#[from] StorageError),
+        #[error("The user does not exist")]
+        /// The user does not exist, includes the attempted UUID.
+        UserNonExistent(Uuid),
+        #[error("Incorrect email verification code")]
+        /// The code provided did not match the one sent by email.
+        IncorrectCode(Uuid),
+        #[error("Too many email verification codes were requested for user {0}")]
+        /// The session has already been sent `ONE_TIME_CODE_MAX_ISSUED` codes.
+        TooManyCodes(Uuid),
+        #[error("Incorrect credential given to re-authenticate user {0}")]
+        /// The primary credential given to re-authenticate was incorrect.
+        IncorrectCredential(Uuid),
+        #[error(transparent)]
+        /// The email provider failed to deliver the code.
+        DeliveryFailed(#[from] MailError),
+    }
+
+    #[derive(Debug, Error)]
+    /// An error returned while verifying a user's phone number.
+    pub enum PhoneVerificationError {
+        #[error(transparent)]
+        /// An error in the underlying storage.
+        StorageError(#[from] StorageError),
+        #[error("No SMS provider is configured")]
+        /// No SMS provider is configured, so phone numbers can't be verified.
+        Unavailable,
+        #[error("The user does not exist")]
+        /// The user does not exist, includes the attempted UUID.
+        UserNonExistent(Uuid),
+        #[error("User {0} has no phone number")]
+        /// The user has no phone number to verify.
+        NoPhoneNumber(Uuid),
+        #[error("Incorrect phone verification code")]
+        /// The code provided did not match the one sent by SMS.
+        IncorrectCode(Uuid),
+        #[error("Too many phone verification codes were requested for user {0}")]
+        /// The session has already been sent `ONE_TIME_CODE_MAX_ISSUED` codes.
+        TooManyCodes(Uuid),
+        #[error(transparent)]
+        /// The SMS provider failed to deliver the code.
+        DeliveryFailed(#[from] SmsError),
+    }
 
     #[derive(Debug, Error)]
     /// An error returned while retrieving a user from the database
@@ -202,6 +543,25 @@ pub mod errors {
         #[error("The user being deleted does not exist")]
         /// The user being deleted does not exist, includes the attempted UUID
         UserNonExistent(Uuid),
+        #[error(transparent)]
+        /// The user's sessions could not be revoked.
+        SessionStorageError(#[from] SessionStorageError),
+    }
+    #[derive(Debug, Error)]
+    /// An error returned while restoring a deleted user.
+    pub enum UserRestorationError {
+        #[error(transparent)]
+        /// An error returned up from the database
+        DatabaseError(#[from] DatabaseError),
+        #[error("The user being restored does not exist")]
+        /// The user does not exist, or has already been purged.
+        UserNonExistent(Uuid),
+        #[error("The user being restored is not deleted")]
+        /// The user has not been deleted, so there is nothing to restore.
+        NotDeleted(Uuid),
+        #[error("The user being restored has been purged")]
+        /// The user was purged, but kept anonymized for their orders.
+        Purged(Uuid),
     }
     #[derive(Debug, Error)]
     /// An error returned while updating a user in the database.
@@ -227,6 +587,20 @@ pub mod errors {
         PasswordTooLong(Uuid),
     }
     #[derive(Debug, Error)]
+    /// An error returned when a user may not take an action on another user.
+    pub enum UserActionError {
+        #[error("User {actor} may not {action} user {target}")]
+        /// The actor is not permitted to take the action on the target user.
+        Forbidden {
+            /// The ID of the user attempting the action.
+            actor: Uuid,
+            /// The ID of the user the action would be taken on.
+            target: Uuid,
+            /// The action being attempted.
+            action: super::UserAction,
+        },
+    }
+    #[derive(Debug, Error)]
     /// An error returned while promoting a user to an Administrator
     pub enum UserPromotionError {
         #[error(transparent)]
@@ -243,8 +617,15 @@ pub mod errors {
     /// An error returned while generating a new TOTP validator
     pub enum GenerateTotpError {
         #[error(transparent)]
-        /// An error returned from the TOTP RFC6238 subsystem
-        Rfc6238Error(#[from] totp_rs::Rfc6238Error),
+        /// The configured TOTP parameters are invalid.
+        InvalidParameters(#[from] totp_rs::TotpUrlError),
+    }
+    #[derive(Debug, Error)]
+    /// An error returned while verifying a code against a candidate TOTP secret.
+    pub enum VerifyTotpError {
+        #[error(transparent)]
+        /// The candidate secret is not valid for RFC6238 (e.g. too short).
+        InvalidSecret(#[from] totp_rs::TotpUrlError),
     }
     #[derive(Debug, Error)]
     /// An error returned while setting the active TOTP token for a user
@@ -255,5 +636,167 @@ pub mod errors {
         #[error("The verification TOTP code was incorrect")]
         /// The example verification code provided was incorrect
         IncorrectCode(Uuid),
+        #[error("The user already has a TOTP secret enrolled")]
+        /// The user already has a TOTP secret, which must be removed before
+        /// enrolling a new one.
+        AlreadyEnrolled(Uuid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+    use uuid::Uuid;
+
+    use super::{delete_user, errors::UserRestorationError, purge_deleted_users, restore_user};
+    use crate::{
+        constants::users::USER_DELETION_GRACE_PERIOD,
+        db::{
+            models::{
+                apporder::{AppOrder, ShippingMethod},
+                appuser::AppUser,
+                product::ProductInsert,
+            },
+            ConnectionPool,
+        },
+        services::orders::{confirm_order, create_order},
+        testing::{store_user, TestApp},
+    };
+
+    /// Mark a user as deleted `seconds` before the grace period ended.
+    async fn delete_past_grace_period(user_id: Uuid, seconds: i64, db_conn: &ConnectionPool) {
+        let deleted = OffsetDateTime::now_utc().saturating_sub(Duration::seconds(
+            i64::from(*USER_DELETION_GRACE_PERIOD).saturating_add(seconds),
+        ));
+        AppUser::soft_delete(
+            user_id,
+            PrimitiveDateTime::new(deleted.date(), deleted.time()),
+            db_conn,
+        )
+        .await
+        .expect("User should be deleted");
+    }
+
+    /// Place an order for a product as a user, confirming it if `confirmed`
+    /// is set. Returns the order's ID.
+    async fn place_order(user_id: Uuid, confirmed: bool, db_conn: &ConnectionPool) -> Uuid {
+        let product_id = ProductInsert::new("Widget", "A widget.", true, 1000)
+            .store(db_conn)
+            .await
+            .expect("Product should be stored")
+            .id();
+        let order_id = create_order(
+            user_id,
+            vec![(product_id, 1)],
+            None,
+            ShippingMethod::Standard,
+            db_conn,
+        )
+        .await
+        .expect("Order should be created")
+        .id();
+        if confirmed {
+            confirm_order(order_id, None, db_conn)
+                .await
+                .expect("Order should be confirmed");
+        }
+        order_id
+    }
+
+    /// A deleted user can't log in until they are restored.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn deleted_user_can_log_in_once_restored(db_conn: ConnectionPool) {
+        let user = store_user("alice@example.com", &db_conn).await;
+        let mut app = TestApp::new(db_conn.clone());
+        delete_user(user.id(), &db_conn, &mut app.session_conn())
+            .await
+            .expect("User should be deleted");
+        assert_eq!(
+            app.log_in("alice@example.com").await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        restore_user(user.id(), &db_conn)
+            .await
+            .expect("User should be restored");
+        assert_eq!(app.log_in("alice@example.com").await.status, StatusCode::OK);
+    }
+
+    /// Users deleted before the grace period are purged along with their
+    /// unconfirmed orders, and those deleted within it are left alone.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn purge_waits_for_grace_period(db_conn: ConnectionPool) {
+        let expired = store_user("expired@example.com", &db_conn).await;
+        let unconfirmed_order = place_order(expired.id(), false, &db_conn).await;
+        delete_past_grace_period(expired.id(), 60, &db_conn).await;
+        let recent = store_user("recent@example.com", &db_conn).await;
+        delete_past_grace_period(recent.id(), -60, &db_conn).await;
+
+        assert_eq!(
+            purge_deleted_users(&db_conn)
+                .await
+                .expect("Purge should succeed"),
+            1
+        );
+        assert!(AppUser::select_one(expired.id(), &db_conn)
+            .await
+            .expect("Select should succeed")
+            .is_none());
+        assert!(AppOrder::select_one(unconfirmed_order, &db_conn)
+            .await
+            .expect("Select should succeed")
+            .is_none());
+        restore_user(recent.id(), &db_conn)
+            .await
+            .expect("User within the grace period should be restorable");
+    }
+
+    /// Purging a user with a confirmed order keeps the order, anonymizing
+    /// the user instead of deleting them, and only does so once.
+    #[sqlx::test]
+    #[ignore = "Requires a database at DATABASE_URL"]
+    async fn purge_keeps_confirmed_orders(db_conn: ConnectionPool) {
+        let user = store_user("alice@example.com", &db_conn).await;
+        let order_id = place_order(user.id(), true, &db_conn).await;
+        delete_past_grace_period(user.id(), 60, &db_conn).await;
+
+        assert_eq!(
+            purge_deleted_users(&db_conn)
+                .await
+                .expect("Purge should succeed"),
+            1
+        );
+        let order = AppOrder::select_one(order_id, &db_conn)
+            .await
+            .expect("Select should succeed")
+            .expect("Confirmed order should be kept");
+        assert_eq!(order.user_id(), user.id());
+        let anonymized = AppUser::select_one(user.id(), &db_conn)
+            .await
+            .expect("Select should succeed")
+            .expect("User should be kept for their order");
+        assert_eq!(
+            anonymized.email.as_str(),
+            format!("{}@deleted.invalid", user.id())
+        );
+        assert_eq!(anonymized.forename, "Deleted");
+        assert!(anonymized.phone.is_none());
+        assert!(matches!(
+            restore_user(user.id(), &db_conn).await,
+            Err(UserRestorationError::Purged(_))
+        ));
+        assert_eq!(
+            purge_deleted_users(&db_conn)
+                .await
+                .expect("Purge should succeed"),
+            0
+        );
+        let mut app = TestApp::new(db_conn);
+        assert_eq!(
+            app.log_in("alice@example.com").await.status,
+            StatusCode::UNAUTHORIZED
+        );
     }
 }
@@ -1,24 +1,63 @@
 //! Defines the state shared across the Axum application.
-#[expect(
-    clippy::useless_attribute,
-    reason = "Lint is enabled only in clippy::restrictions"
-)]
-#[expect(
-    clippy::std_instead_of_alloc,
-    reason = "Does not work outside of no_std"
-)]
-use std::sync::Arc;
+use alloc::sync::Arc;
 
-use crate::{db, services::sessions};
+use core::convert::Infallible;
+
+use crate::{
+    db,
+    services::sessions,
+    utils::{mailer::EmailSender, sms::SmsSender},
+};
+use axum::{extract::FromRequestParts, http::request::Parts};
 use object_store::ObjectStore;
 
 #[derive(Clone)]
 /// The state struct shared across routers.
 pub struct AppState {
-    /// A database connection pool for getting new database connections.
+    /// A database connection pool for getting new database connections. Used
+    /// for writes and anything which must see its own writes.
     pub db: db::ConnectionPool,
+    /// A database connection pool used only for read-only catalog queries.
+    /// Points at the read replica if configured, otherwise the same as `db`.
+    pub read_db: db::ReadConnectionPool,
     /// A multiplexed connection for getting new session store connections.
     pub session_store: sessions::store::Connection,
     /// A shared connection for adding to the media store.
     pub media_store: Arc<dyn ObjectStore>,
+    /// The sender used to deliver SMS messages, e.g. MFA codes.
+    pub sms_sender: Arc<dyn SmsSender>,
+    /// The sender used to deliver emails, e.g. MFA codes.
+    pub email_sender: Arc<dyn EmailSender>,
+}
+
+impl AppState {
+    /// Get a connection to the session store. Cheap, since every clone shares
+    /// the same underlying multiplexed connection.
+    pub fn session_conn(&self) -> sessions::store::Connection {
+        self.session_store.clone()
+    }
+    /// Get the primary database connection pool.
+    pub const fn db(&self) -> &db::ConnectionPool {
+        &self.db
+    }
+    /// Get the database connection pool for read-only catalog queries, which
+    /// the functions making them require by type.
+    pub const fn read_db(&self) -> &db::ReadConnectionPool {
+        &self.read_db
+    }
+}
+
+/// Extracts an owned session store connection, for handlers which need
+/// nothing else from the `AppState`.
+pub struct SessionConn(pub sessions::store::Connection);
+
+impl FromRequestParts<AppState> for SessionConn {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        _parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(state.session_conn()))
+    }
 }
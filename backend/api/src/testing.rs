@@ -0,0 +1,427 @@
+//! Helpers for tests which exercise the API through its router, as a client
+//! would, with the session store, media store and message senders replaced by
+//! in-memory fakes.
+use alloc::sync::Arc;
+use core::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, Once},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, request::Builder, HeaderMap, Method, Request, StatusCode},
+};
+use object_store::memory::InMemory;
+use serde_json::{json, Value};
+use sqlx::postgres::PgPoolOptions;
+use tokio::{task::yield_now, time::timeout};
+use tower::ServiceExt as _;
+
+use crate::{
+    constants::{
+        config::Config,
+        cookies::{CSRF_COOKIE_NAME, CSRF_HEADER_NAME},
+    },
+    db::{
+        self,
+        models::{
+            appuser::{AppUser, AppUserInsert},
+            password::PasswordInsert,
+        },
+    },
+    services::sessions::{fake_store::FakeStore, store},
+    state::AppState,
+    utils::{
+        address::Address,
+        email::EmailAddress,
+        mailer::{EmailSender, SendFuture},
+        phone::PhoneNumber,
+        sms::{self, SmsSender},
+    },
+};
+
+/// Placeholder values for the settings which have no default, so that tests
+/// have a valid configuration without depending on their environment, and
+/// any optional behaviour the tests exercise.
+const TEST_SETTINGS: &[(&str, &str)] = &[
+    ("DB_HOST", "localhost"),
+    ("DB_DATABASE", "securecart"),
+    ("DB_USERNAME", "securecart"),
+    ("DB_PASSWORD", "password"),
+    ("DB_ENCRYPTION_KEY", "test encryption key"),
+    ("REDIS_HOST", "localhost"),
+    ("S3_HOST", "localhost"),
+    ("S3_PORT", "9000"),
+    ("S3_BUCKET", "securecart"),
+    ("S3_ACCESS_KEY", "access"),
+    ("S3_SECRET_KEY", "secret"),
+    ("STRIPE_SECRET_KEY", "sk_test"),
+    ("STRIPE_WEBHOOK_SECRET", "whsec_test"),
+    ("STRIPE_PUBLISHABLE_KEY", "pk_test"),
+    ("SHIPPING_FLAT_RATE", "300"),
+    ("SHIPPING_EXPRESS_RATE", "900"),
+    ("SHIPPING_EXPRESS_COUNTRIES", "GB"),
+    ("SESSION_COOKIE_NAME", "test_session"),
+    ("CSRF_COOKIE_NAME", "test_session_csrf"),
+    ("CSRF_HEADER_NAME", "x-test-csrf-token"),
+    ("CSRF_EXEMPT_METHODS", "GET,HEAD"),
+];
+
+/// Look up a setting in `TEST_SETTINGS`.
+pub fn test_setting(name: &str) -> Option<String> {
+    TEST_SETTINGS
+        .iter()
+        .find(|&&(setting, _)| setting == name)
+        .map(|&(_, value)| value.to_owned())
+}
+
+/// Configure the API for tests, with every setting's default other than
+/// those in `TEST_SETTINGS`, rather than settings from their environment.
+/// Called by `config` whenever tests read the configuration.
+pub fn configure() {
+    static CONFIGURED: Once = Once::new();
+    CONFIGURED.call_once(|| {
+        Config::init_from(&test_setting).expect("Invalid test configuration");
+    });
+}
+
+/// The address every request made through a `TestApp` comes from.
+pub const CLIENT_IP: &str = "192.0.2.1";
+
+/// The password of every user stored by `store_user`.
+pub const PASSWORD: &str = "correct horse battery staple";
+
+/// Store a customer with an email, who logs in with `PASSWORD`.
+pub async fn store_user(email: &str, db_conn: &db::ConnectionPool) -> AppUser {
+    let user = AppUserInsert::new(
+        EmailAddress::try_from(email).expect("Email should be valid"),
+        "Alice",
+        "Smith",
+        Address::new("1 High Street", None, "London", "SW1A 1AA", "GB")
+            .expect("Address should be valid"),
+        None,
+    )
+    .store(db_conn)
+    .await
+    .expect("User should be stored");
+    PasswordInsert::new(user.id(), PASSWORD)
+        .store(db_conn)
+        .await
+        .expect("Password should be stored");
+    user
+}
+
+/// An email sent through a `RecordingEmailSender`.
+#[derive(Clone, Debug)]
+pub struct SentEmail {
+    /// The address the email was sent to.
+    pub recipient: String,
+    /// The email's subject.
+    pub subject: String,
+    /// The email's plain text body.
+    pub body: String,
+}
+
+/// An `EmailSender` which keeps every email sent through it, so tests can
+/// check what would have been delivered.
+#[derive(Default)]
+pub struct RecordingEmailSender(Mutex<Vec<SentEmail>>);
+
+impl RecordingEmailSender {
+    /// Every email sent so far.
+    pub fn sent(&self) -> Vec<SentEmail> {
+        self.0
+            .lock()
+            .expect("Sent emails should not be poisoned")
+            .clone()
+    }
+    /// Wait for at least `count` emails to have been sent, e.g. by a task the
+    /// handler spawned, and return every email sent. Panics if they are not
+    /// sent within a second.
+    pub async fn wait_for(&self, count: usize) -> Vec<SentEmail> {
+        timeout(Duration::from_secs(1), async {
+            loop {
+                let sent = self.sent();
+                if sent.len() >= count {
+                    return sent;
+                }
+                yield_now().await;
+            }
+        })
+        .await
+        .expect("Expected emails were not sent")
+    }
+}
+
+impl EmailSender for RecordingEmailSender {
+    fn send<'a>(
+        &'a self,
+        recipient: &'a EmailAddress,
+        subject: &'a str,
+        body: &'a str,
+    ) -> SendFuture<'a> {
+        self.0
+            .lock()
+            .expect("Sent emails should not be poisoned")
+            .push(SentEmail {
+                recipient: recipient.as_str().to_owned(),
+                subject: subject.to_owned(),
+                body: body.to_owned(),
+            });
+        Box::pin(async { Ok(()) })
+    }
+    fn is_configured(&self) -> bool {
+        true
+    }
+}
+
+/// An SMS message sent through a `RecordingSmsSender`.
+#[derive(Clone, Debug)]
+pub struct SentSms {
+    /// The phone number the message was sent to.
+    pub recipient: String,
+    /// The message's text.
+    pub message: String,
+}
+
+/// An `SmsSender` which keeps every message sent through it, so tests can
+/// check what would have been delivered.
+#[derive(Default)]
+pub struct RecordingSmsSender(Mutex<Vec<SentSms>>);
+
+impl RecordingSmsSender {
+    /// Every message sent so far.
+    pub fn sent(&self) -> Vec<SentSms> {
+        self.0
+            .lock()
+            .expect("Sent messages should not be poisoned")
+            .clone()
+    }
+}
+
+impl SmsSender for RecordingSmsSender {
+    fn send<'a>(&'a self, recipient: &'a PhoneNumber, message: &'a str) -> sms::SendFuture<'a> {
+        self.0
+            .lock()
+            .expect("Sent messages should not be poisoned")
+            .push(SentSms {
+                recipient: recipient.to_string(),
+                message: message.to_owned(),
+            });
+        Box::pin(async { Ok(()) })
+    }
+    fn is_configured(&self) -> bool {
+        true
+    }
+}
+
+/// A response to a request made through a `TestApp`, with its body read.
+pub struct TestResponse {
+    /// The response's status.
+    pub status: StatusCode,
+    /// The response's headers.
+    pub headers: HeaderMap,
+    /// The response's body.
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    /// The response's body, parsed as JSON.
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).expect("Response body should be JSON")
+    }
+    /// The string at a JSON pointer (e.g. `/order/id`) in the response's body.
+    pub fn string_at(&self, pointer: &str) -> String {
+        self.json()
+            .pointer(pointer)
+            .and_then(Value::as_str)
+            .expect("Response body should have a string at the pointer")
+            .to_owned()
+    }
+}
+
+/// The API, as seen by a single client which keeps the cookies it is given
+/// and echoes its CSRF token back, as the frontend does.
+pub struct TestApp {
+    /// The state the API's handlers are given.
+    pub state: AppState,
+    /// The sender behind `state`, holding every email the API sent.
+    pub emails: Arc<RecordingEmailSender>,
+    /// The sender behind `state`, holding every SMS message the API sent.
+    pub sms: Arc<RecordingSmsSender>,
+    /// The session store behind `state`.
+    pub store: FakeStore,
+    /// The cookies the client holds, by name.
+    cookies: HashMap<String, String>,
+}
+
+impl TestApp {
+    /// An app backed by the given database.
+    pub fn new(db_conn: db::ConnectionPool) -> Self {
+        let read_db = db::ReadConnectionPool::from(db_conn.clone());
+        Self::with_read_db(db_conn, read_db)
+    }
+    /// An app backed by the given database, with read-only catalog queries
+    /// made against `read_db` instead, as if it were a read replica.
+    pub fn with_read_db(db_conn: db::ConnectionPool, read_db: db::ReadConnectionPool) -> Self {
+        let store = FakeStore::default();
+        let emails = Arc::new(RecordingEmailSender::default());
+        let sms = Arc::new(RecordingSmsSender::default());
+        Self {
+            state: AppState {
+                read_db,
+                db: db_conn,
+                session_store: store::Connection::fake(&store),
+                media_store: Arc::new(InMemory::new()),
+                sms_sender: Arc::<RecordingSmsSender>::clone(&sms),
+                email_sender: Arc::<RecordingEmailSender>::clone(&emails),
+            },
+            emails,
+            sms,
+            store,
+            cookies: HashMap::new(),
+        }
+    }
+    /// An app for tests which never reach the database. Any query fails.
+    pub fn without_db() -> Self {
+        Self::new(
+            PgPoolOptions::new()
+                .acquire_timeout(Duration::from_millis(1))
+                .connect_lazy("postgres://localhost/unused")
+                .expect("Connection string should be valid"),
+        )
+    }
+    /// Another client of the same app, holding no cookies.
+    pub fn other_client(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            emails: Arc::clone(&self.emails),
+            sms: Arc::clone(&self.sms),
+            store: self.store.clone(),
+            cookies: HashMap::new(),
+        }
+    }
+    /// A connection to the app's session store.
+    pub fn session_conn(&self) -> store::Connection {
+        self.state.session_conn()
+    }
+    /// The value of a cookie the client holds.
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies.get(name).cloned()
+    }
+    /// Start building a request carrying the client's cookies and address,
+    /// but no CSRF token.
+    pub fn request(&self, method: Method, uri: &str) -> Builder {
+        let builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("x-real-ip", CLIENT_IP);
+        if self.cookies.is_empty() {
+            return builder;
+        }
+        let cookies: Vec<String> = self
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        builder.header(header::COOKIE, cookies.join("; "))
+    }
+    /// Send a request to the API, keeping any cookies the response sets.
+    pub async fn send(&mut self, request: Request<Body>) -> TestResponse {
+        let (parts, body) = crate::create_app(self.state.clone())
+            .oneshot(request)
+            .await
+            .expect("Router should not fail")
+            .into_parts();
+        let response = TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: to_bytes(body, usize::MAX)
+                .await
+                .expect("Response body should be readable")
+                .to_vec(),
+        };
+        for set_cookie in response.headers.get_all(header::SET_COOKIE) {
+            let Some((name, value)) = set_cookie
+                .to_str()
+                .ok()
+                .and_then(|cookie| cookie.split(';').next()?.split_once('='))
+            else {
+                continue;
+            };
+            if value.is_empty() {
+                self.cookies.remove(name);
+            } else {
+                self.cookies.insert(name.to_owned(), value.to_owned());
+            }
+        }
+        response
+    }
+    /// Send a GET request.
+    pub async fn get(&mut self, uri: &str) -> TestResponse {
+        let request = self
+            .request(Method::GET, uri)
+            .body(Body::empty())
+            .expect("Request should be valid");
+        self.send(request).await
+    }
+    /// Send a request with a JSON body, and the CSRF token from the client's
+    /// CSRF token cookie if it has one.
+    pub async fn send_json(&mut self, method: Method, uri: &str, body: &Value) -> TestResponse {
+        let request = self
+            .request_with_csrf_token(method, uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .expect("Request should be valid");
+        self.send(request).await
+    }
+    /// Send a POST request with a multipart form of named fields, and the CSRF
+    /// token from the client's CSRF token cookie if it has one.
+    pub async fn post_multipart(&mut self, uri: &str, fields: &[(&str, &[u8])]) -> TestResponse {
+        const BOUNDARY: &str = "securecart-test-boundary";
+        let mut body = Vec::new();
+        for &(name, value) in fields {
+            body.extend_from_slice(
+                format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n")
+                    .as_bytes(),
+            );
+            body.extend_from_slice(value);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{BOUNDARY}--\r\n").as_bytes());
+        let request = self
+            .request_with_csrf_token(Method::POST, uri)
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={BOUNDARY}"),
+            )
+            .body(Body::from(body))
+            .expect("Request should be valid");
+        self.send(request).await
+    }
+    /// Start building a request as `request` does, with the CSRF token from
+    /// the client's CSRF token cookie if it has one.
+    fn request_with_csrf_token(&self, method: Method, uri: &str) -> Builder {
+        let builder = self.request(method, uri);
+        match self.cookie(&CSRF_COOKIE_NAME) {
+            Some(csrf_token) => builder.header(&*CSRF_HEADER_NAME, csrf_token),
+            None => builder,
+        }
+    }
+    /// Send a POST request with a JSON body (see `send_json`).
+    pub async fn post(&mut self, uri: &str, body: &Value) -> TestResponse {
+        self.send_json(Method::POST, uri, body).await
+    }
+    /// Log in as a user stored by `store_user`, keeping the session cookies.
+    pub async fn log_in(&mut self, email: &str) -> TestResponse {
+        self.post(
+            "/auth",
+            &json!({
+                "email": email,
+                "credential": { "Password": { "password": PASSWORD } },
+            }),
+        )
+        .await
+    }
+}
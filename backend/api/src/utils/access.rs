@@ -0,0 +1,43 @@
+//! The policy for responding to requests for resources owned by a user which
+//! either do not exist or are not owned by the requester.
+use axum::http::StatusCode;
+
+use super::httperror::HttpError;
+
+/// Get the error to respond with when an owned resource is either missing or
+/// not owned by the requester. Administrators can access every resource, so
+/// are told when one genuinely does not exist (404). Customers receive a
+/// uniform 403 in both cases, so that they cannot enumerate valid IDs of
+/// resources belonging to other users.
+pub fn deny_or_not_found(is_admin: bool) -> HttpError {
+    if is_admin {
+        StatusCode::NOT_FOUND.into()
+    } else {
+        StatusCode::FORBIDDEN.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, response::IntoResponse as _};
+
+    use super::deny_or_not_found;
+
+    /// Administrators are told that a resource does not exist.
+    #[test]
+    fn administrators_get_not_found() {
+        assert_eq!(
+            deny_or_not_found(true).into_response().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    /// Customers are denied, whether or not the resource exists.
+    #[test]
+    fn customers_get_forbidden() {
+        assert_eq!(
+            deny_or_not_found(false).into_response().status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+}
@@ -0,0 +1,294 @@
+//! A structured postal address, validated when it is received from a user and
+//! stored encrypted as JSON.
+//!
+//! Addresses were originally stored as a single free-text string. These are
+//! still read, by splitting their lines into the structured fields on a best
+//! effort basis, and are stored in the structured form the next time the
+//! user's details are updated.
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    error::BoxDynError,
+    postgres::{PgTypeInfo, PgValueRef},
+    Decode, Postgres, Type,
+};
+
+use super::text;
+use crate::constants::{
+    address::{COUNTRY_CODES, LEGACY_ADDRESS_COUNTRY},
+    fields::{ADDRESS_LINE_MAX_LENGTH, POSTCODE_MAX_LENGTH},
+};
+
+/// An address as provided, before it has been validated.
+#[derive(Deserialize)]
+struct UncheckedAddress {
+    /// The first line of the address.
+    line1: String,
+    /// The optional second line of the address.
+    #[serde(default)]
+    line2: Option<String>,
+    /// The city or town.
+    city: String,
+    /// The postal code.
+    postcode: String,
+    /// The ISO 3166-1 alpha-2 country code.
+    country: String,
+}
+
+/// A postal address, guaranteed to have a non-empty first line, city and
+/// postcode, and a valid ISO 3166-1 alpha-2 country code, unless it was read
+/// from a legacy free-text address.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedAddress")]
+pub struct Address {
+    /// The first line of the address.
+    line1: String,
+    /// The optional second line of the address.
+    line2: Option<String>,
+    /// The city or town.
+    city: String,
+    /// The postal code.
+    postcode: String,
+    /// The ISO 3166-1 alpha-2 country code, in upper case.
+    country: String,
+}
+
+/// Sanitize a required field of an address, which must not be empty.
+fn required_field(
+    field: &'static str,
+    value: &str,
+    max_length: usize,
+) -> Result<String, errors::AddressError> {
+    let sanitized = text::sanitize_line(value, max_length)
+        .map_err(|err| errors::AddressError::Invalid(field, err))?;
+    if sanitized.is_empty() {
+        Err(errors::AddressError::Empty(field))
+    } else {
+        Ok(sanitized)
+    }
+}
+
+impl Address {
+    /// Construct a new address, validating and sanitizing each field.
+    pub fn new(
+        line1: &str,
+        line2: Option<&str>,
+        city: &str,
+        postcode: &str,
+        country: &str,
+    ) -> Result<Self, errors::AddressError> {
+        let second_line = line2
+            .map(|line| text::sanitize_line(line, ADDRESS_LINE_MAX_LENGTH))
+            .transpose()
+            .map_err(|err| errors::AddressError::Invalid("line2", err))?
+            .filter(|line| !line.is_empty());
+        let country_code = country.trim().to_uppercase();
+        if !COUNTRY_CODES.contains(&country_code.as_str()) {
+            return Err(errors::AddressError::UnknownCountry);
+        }
+        Ok(Self {
+            line1: required_field("line1", line1, ADDRESS_LINE_MAX_LENGTH)?,
+            line2: second_line,
+            city: required_field("city", city, ADDRESS_LINE_MAX_LENGTH)?,
+            postcode: required_field("postcode", postcode, POSTCODE_MAX_LENGTH)?,
+            country: country_code,
+        })
+    }
+
+    /// The address's ISO 3166-1 alpha-2 country code, in upper case.
+    pub fn country(&self) -> &str {
+        &self.country
+    }
+
+    /// Serialize the address to the JSON form in which it is stored.
+    pub fn to_stored(&self) -> String {
+        serde_json::to_string(self).expect("Serializing an address to JSON cannot fail.")
+    }
+
+    /// Read an address from the form in which it is stored. The address is
+    /// trusted, as it was validated before it was stored. A legacy free-text
+    /// address is split into its fields by `from_legacy`, and given the
+    /// country `LEGACY_ADDRESS_COUNTRY`.
+    pub fn from_stored(stored: &str) -> Self {
+        serde_json::from_str::<UncheckedAddress>(stored).map_or_else(
+            |_| Self::from_legacy(stored, &LEGACY_ADDRESS_COUNTRY),
+            Self::unchecked,
+        )
+    }
+
+    /// Construct an address from one which was already validated.
+    fn unchecked(address: UncheckedAddress) -> Self {
+        Self {
+            line1: address.line1,
+            line2: address.line2,
+            city: address.city,
+            postcode: address.postcode,
+            country: address.country,
+        }
+    }
+
+    /// Convert a legacy free-text address into a structured address. The
+    /// first line becomes `line1`, the last two lines become the city and
+    /// postcode, and any lines between are joined into `line2`. Addresses with
+    /// fewer lines are left with empty fields, and the country is assumed to
+    /// be `country`.
+    fn from_legacy(legacy: &str, country: &str) -> Self {
+        let legacy_lines: Vec<&str> = legacy
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect();
+        let (first_line, second_line, town, postal_code) = match *legacy_lines.as_slice() {
+            [] => ("", None, "", ""),
+            [only] => (only, None, "", ""),
+            [first, last] => (first, None, last, ""),
+            [first, ref middle @ .., city, postcode] => (
+                first,
+                Some(middle.join(", ")).filter(|line| !line.is_empty()),
+                city,
+                postcode,
+            ),
+        };
+        Self {
+            line1: first_line.to_owned(),
+            line2: second_line,
+            city: town.to_owned(),
+            postcode: postal_code.to_owned(),
+            country: country.to_owned(),
+        }
+    }
+}
+
+impl TryFrom<UncheckedAddress> for Address {
+    type Error = errors::AddressError;
+    fn try_from(address: UncheckedAddress) -> Result<Self, Self::Error> {
+        Self::new(
+            &address.line1,
+            address.line2.as_deref(),
+            &address.city,
+            &address.postcode,
+            &address.country,
+        )
+    }
+}
+
+impl Type<Postgres> for Address {
+    fn type_info() -> PgTypeInfo {
+        <String as Type<Postgres>>::type_info()
+    }
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <String as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for Address {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(Self::from_stored(<&str as Decode<Postgres>>::decode(
+            value,
+        )?))
+    }
+}
+
+/// Errors returned from this module.
+pub mod errors {
+    use thiserror::Error;
+
+    use crate::utils::text::errors::SanitizeError;
+
+    /// Errors returned when an address fails validation.
+    #[derive(Debug, Error)]
+    pub enum AddressError {
+        /// A required field of the address is empty.
+        #[error("{0} must not be empty")]
+        Empty(&'static str),
+        /// A field of the address failed sanitization.
+        #[error("{0} {1}")]
+        Invalid(&'static str, SanitizeError),
+        /// The country is not an ISO 3166-1 alpha-2 code.
+        #[error("country must be an ISO 3166-1 alpha-2 country code")]
+        UnknownCountry,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{errors::AddressError, Address};
+
+    /// A valid address is sanitized, and its country normalized to upper case.
+    #[test]
+    fn new_sanitizes_fields() {
+        let address = Address::new(
+            "  1 High   Street ",
+            Some(" "),
+            "London",
+            "SW1A 1AA",
+            " gb ",
+        )
+        .expect("Address should be valid");
+        assert_eq!(address.line1, "1 High Street");
+        assert_eq!(address.line2, None);
+        assert_eq!(address.city, "London");
+        assert_eq!(address.postcode, "SW1A 1AA");
+        assert_eq!(address.country(), "GB");
+    }
+
+    /// Required fields must not be empty once sanitized.
+    #[test]
+    fn new_rejects_empty_required_field() {
+        assert!(matches!(
+            Address::new("1 High Street", None, "   ", "SW1A 1AA", "GB"),
+            Err(AddressError::Empty("city"))
+        ));
+    }
+
+    /// The country must be an ISO 3166-1 alpha-2 code.
+    #[test]
+    fn new_rejects_unknown_country() {
+        assert!(matches!(
+            Address::new("1 High Street", None, "London", "SW1A 1AA", "UK"),
+            Err(AddressError::UnknownCountry)
+        ));
+    }
+
+    /// Lines between the first and the last two of a legacy address are
+    /// joined into the second line.
+    #[test]
+    fn from_legacy_splits_lines() {
+        let address = Address::from_legacy(
+            "1 High Street\nFlat 2\n\n  Westminster \nLondon\nSW1A 1AA\n",
+            "GB",
+        );
+        assert_eq!(address.line1, "1 High Street");
+        assert_eq!(address.line2.as_deref(), Some("Flat 2, Westminster"));
+        assert_eq!(address.city, "London");
+        assert_eq!(address.postcode, "SW1A 1AA");
+        assert_eq!(address.country(), "GB");
+    }
+
+    /// Legacy addresses with too few lines are left with empty fields.
+    #[test]
+    fn from_legacy_short_addresses() {
+        let empty = Address::from_legacy("", "GB");
+        assert_eq!(
+            (
+                empty.line1.as_str(),
+                empty.city.as_str(),
+                empty.postcode.as_str()
+            ),
+            ("", "", "")
+        );
+        let two_lines = Address::from_legacy("1 High Street\nLondon", "GB");
+        assert_eq!(two_lines.line1, "1 High Street");
+        assert_eq!(two_lines.line2, None);
+        assert_eq!(two_lines.city, "London");
+        assert_eq!(two_lines.postcode, "");
+    }
+
+    /// A three line legacy address has no second line.
+    #[test]
+    fn from_legacy_three_lines() {
+        let address = Address::from_legacy("1 High Street\nLondon\nSW1A 1AA", "GB");
+        assert_eq!(address.line2, None);
+        assert_eq!(address.city, "London");
+        assert_eq!(address.postcode, "SW1A 1AA");
+    }
+}
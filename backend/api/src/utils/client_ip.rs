@@ -0,0 +1,28 @@
+//! Identification of the client making a request, for per-client rate limiting.
+use axum::http::{HeaderMap, StatusCode};
+
+use super::httperror::HttpError;
+
+/// Get the client's IP address from the X-Real-IP header set by the reverse
+/// proxy in front of the API.
+pub fn client_ip(headers: &HeaderMap) -> Result<&str, HttpError> {
+    headers
+        .get("x-real-ip")
+        .ok_or_else(|| {
+            tracing::error!(
+                "X-Real-IP header not set, I should be running behind a reverse proxy."
+            );
+            HttpError::new(
+                StatusCode::BAD_REQUEST,
+                Some(String::from("X-Real-IP not set")),
+            )
+        })?
+        .to_str()
+        .map_err(|err| {
+            tracing::warn!("Failed to parse X-Real-IP header value: {err}");
+            HttpError::new(
+                StatusCode::BAD_REQUEST,
+                Some(String::from("X-Real-IP value unparseable")),
+            )
+        })
+}
@@ -0,0 +1,144 @@
+//! Construction of the cookies used to carry session and CSRF tokens, with
+//! attributes taken from the deployment's configuration.
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+
+use crate::constants::cookies::{
+    COOKIE_DOMAIN, COOKIE_SAMESITE, COOKIE_SECURE, CSRF_COOKIE_NAME, SESSION_COOKIE_NAME,
+};
+
+/// The deployment-specific attributes of the cookies carrying session state.
+struct CookieAttributes<'a> {
+    /// Whether the cookie is only sent over HTTPS.
+    secure: bool,
+    /// The cookie's `SameSite` policy.
+    same_site: SameSite,
+    /// The domain the cookie is scoped to, if any.
+    domain: Option<&'a str>,
+}
+
+impl CookieAttributes<'static> {
+    /// The attributes from the deployment's configuration, see
+    /// `COOKIE_SECURE`, `COOKIE_SAMESITE` and `COOKIE_DOMAIN`.
+    fn configured() -> Self {
+        Self {
+            secure: *COOKIE_SECURE,
+            same_site: *COOKIE_SAMESITE,
+            domain: COOKIE_DOMAIN.as_deref(),
+        }
+    }
+}
+
+/// Build a cookie carrying session state, with the configured secure,
+/// `SameSite` and domain attributes. `http_only` should be set for cookies
+/// which client-side scripts never need to read (i.e. the session token, but
+/// not the CSRF token).
+pub fn build_session_cookie(name: &'static str, value: String, http_only: bool) -> Cookie<'static> {
+    build_cookie(&CookieAttributes::configured(), name, value, http_only)
+}
+
+/// Build a cookie carrying session state with the given attributes.
+fn build_cookie(
+    attributes: &CookieAttributes<'_>,
+    name: &'static str,
+    value: String,
+    http_only: bool,
+) -> Cookie<'static> {
+    let cookie = Cookie::build((name, value))
+        .http_only(http_only)
+        .path("/")
+        .secure(attributes.secure)
+        .same_site(attributes.same_site);
+    match attributes.domain {
+        Some(domain) => cookie.domain(domain.to_owned()).build(),
+        None => cookie.build(),
+    }
+}
+
+/// Build a cookie which, when passed to `CookieJar::remove`, removes a cookie
+/// previously set with `build_session_cookie`. The path and domain must match
+/// for the browser to remove it.
+pub fn build_removal_cookie(name: &'static str) -> Cookie<'static> {
+    build_session_cookie(name, String::new(), false)
+}
+
+/// Remove the session and CSRF token cookies, e.g. when logging out.
+pub fn remove_session_cookies(cookies: CookieJar) -> CookieJar {
+    cookies
+        .remove(build_removal_cookie(&SESSION_COOKIE_NAME))
+        .remove(build_removal_cookie(&CSRF_COOKIE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum_extra::extract::cookie::{Cookie, SameSite};
+
+    use super::{build_cookie, CookieAttributes};
+    use crate::{constants::config::Config, testing::test_setting};
+
+    /// Build a session token cookie as configured by `settings`, on top of the
+    /// tests' own configuration.
+    fn cookie_with(settings: &[(&str, &str)]) -> Cookie<'static> {
+        let config = Config::from_lookup(&|name: &str| {
+            settings
+                .iter()
+                .find(|&&(setting, _)| setting == name)
+                .map(|&(_, value)| value.to_owned())
+                .or_else(|| test_setting(name))
+        })
+        .expect("Configuration should be valid");
+        let attributes = CookieAttributes {
+            secure: config.cookie_secure,
+            same_site: config.cookie_samesite,
+            domain: config.cookie_domain.as_deref(),
+        };
+        build_cookie(&attributes, "session", "token".to_owned(), true)
+    }
+
+    /// Without any cookie settings, cookies are secure, strictly same-site
+    /// and scoped to the host which set them.
+    #[test]
+    fn defaults_are_strict() {
+        let cookie = cookie_with(&[]);
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        assert_eq!(cookie.domain(), None);
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.path(), Some("/"));
+    }
+
+    /// `COOKIE_SECURE` can allow cookies over plain HTTP.
+    #[test]
+    fn secure_is_configurable() {
+        assert_eq!(
+            cookie_with(&[("COOKIE_SECURE", "false")]).secure(),
+            Some(false)
+        );
+    }
+
+    /// `COOKIE_SAMESITE` sets each `SameSite` policy, regardless of case.
+    #[test]
+    fn same_site_is_configurable() {
+        for (setting, same_site) in [
+            ("strict", SameSite::Strict),
+            ("Lax", SameSite::Lax),
+            ("NONE", SameSite::None),
+        ] {
+            assert_eq!(
+                cookie_with(&[("COOKIE_SAMESITE", setting)]).same_site(),
+                Some(same_site)
+            );
+        }
+    }
+
+    /// `COOKIE_DOMAIN` scopes cookies to a domain.
+    #[test]
+    fn domain_is_configurable() {
+        assert_eq!(
+            cookie_with(&[("COOKIE_DOMAIN", "example.com")]).domain(),
+            Some("example.com")
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! Encoding of CSV records, as described by RFC 4180.
+
+/// Encode a single field. Fields containing a delimiter, quote or line break
+/// are quoted, with any quotes inside them doubled.
+pub fn field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Encode a complete record from its fields, terminated by a CRLF line break.
+pub fn record(fields: &[&str]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|value| field(value))
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{field, record};
+
+    /// Fields without special characters are left as they are.
+    #[test]
+    fn plain_field_is_unquoted() {
+        assert_eq!(field("A widget"), "A widget");
+        assert_eq!(field(""), "");
+    }
+
+    /// Fields containing a delimiter, quote or line break are quoted, with
+    /// quotes inside them doubled.
+    #[test]
+    fn special_characters_are_quoted() {
+        assert_eq!(field("small, blue"), "\"small, blue\"");
+        assert_eq!(field("a \"widget\""), "\"a \"\"widget\"\"\"");
+        assert_eq!(field("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(field("line\r\nbreak"), "\"line\r\nbreak\"");
+    }
+
+    /// A record joins its encoded fields with commas, ending in CRLF.
+    #[test]
+    fn record_is_crlf_terminated() {
+        assert_eq!(record(&["1", "a, b", "c"]), "1,\"a, b\",c\r\n");
+    }
+}
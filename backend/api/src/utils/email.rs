@@ -4,6 +4,8 @@ use std::sync::LazyLock;
 
 use serde::{de, Deserialize, Serialize};
 
+use crate::constants::fields::{EMAIL_LOCAL_PART_MAX_LENGTH, EMAIL_MAX_LENGTH};
+
 /// Regex used to validate email address format. Non-comprehensive but good enough.
 static EMAIL_REGEX: LazyLock<regex::Regex> = LazyLock::new(|| {
     regex::Regex::new(r"^[a-zA-Z0-9_.+-]+@[a-zA-Z0-9-]+(\.[a-zA-Z0-9-]+)+$")
@@ -16,29 +18,41 @@ static EMAIL_REGEX: LazyLock<regex::Regex> = LazyLock::new(|| {
 pub struct EmailAddress(String);
 
 impl fmt::Display for EmailAddress {
-    #[expect(
-        clippy::min_ident_chars,
-        reason = "f is the trait function parameter name"
-    )]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
+impl EmailAddress {
+    /// Borrow the address as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 impl TryFrom<&str> for EmailAddress {
-    type Error = ();
+    type Error = errors::EmailAddressError;
     fn try_from(string: &str) -> Result<Self, Self::Error> {
         Self::try_from(string.to_owned())
     }
 }
 
 impl TryFrom<String> for EmailAddress {
-    type Error = ();
+    type Error = errors::EmailAddressError;
     fn try_from(string: String) -> Result<Self, Self::Error> {
+        if string.chars().count() > EMAIL_MAX_LENGTH {
+            return Err(errors::EmailAddressError::TooLong);
+        }
+        if string
+            .split_once('@')
+            .is_some_and(|(local, _domain)| local.chars().count() > EMAIL_LOCAL_PART_MAX_LENGTH)
+        {
+            return Err(errors::EmailAddressError::LocalPartTooLong);
+        }
         if EMAIL_REGEX.is_match(&string) {
             Ok(Self(string))
         } else {
-            Err(())
+            Err(errors::EmailAddressError::Malformed)
         }
     }
 }
@@ -60,7 +74,7 @@ impl<'de> Deserialize<'de> for EmailAddress {
         D: serde::Deserializer<'de>,
     {
         let str = String::deserialize(deserializer)?;
-        Self::try_from(str).map_err(|_err| de::Error::custom("malformed email address"))
+        Self::try_from(str).map_err(de::Error::custom)
     }
 }
 
@@ -72,3 +86,82 @@ impl Serialize for EmailAddress {
         self.0.serialize(serializer)
     }
 }
+
+/// Errors returned from this module.
+pub mod errors {
+    use thiserror::Error;
+
+    use crate::constants::fields::{EMAIL_LOCAL_PART_MAX_LENGTH, EMAIL_MAX_LENGTH};
+
+    /// Errors returned when an email address fails validation.
+    #[derive(Debug, Error)]
+    pub enum EmailAddressError {
+        /// The address as a whole is longer than `EMAIL_MAX_LENGTH`.
+        #[error("email address must be at most {EMAIL_MAX_LENGTH} characters")]
+        TooLong,
+        /// The part of the address before the `@` is longer than
+        /// `EMAIL_LOCAL_PART_MAX_LENGTH`.
+        #[error(
+            "email address must have at most {EMAIL_LOCAL_PART_MAX_LENGTH} characters before the @"
+        )]
+        LocalPartTooLong,
+        /// The address is not of the form `local@domain.tld`.
+        #[error("malformed email address")]
+        Malformed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::EmailAddress;
+    use crate::constants::fields::{EMAIL_LOCAL_PART_MAX_LENGTH, EMAIL_MAX_LENGTH};
+
+    /// The message an address fails to deserialize with.
+    fn rejection(address: &str) -> String {
+        serde_json::from_value::<EmailAddress>(json!(address))
+            .err()
+            .expect("Address should be rejected")
+            .to_string()
+    }
+
+    /// A valid address is deserialized unchanged.
+    #[test]
+    fn valid_address_is_accepted() {
+        let address = serde_json::from_value::<EmailAddress>(json!("alice@example.com"))
+            .expect("Address should be valid");
+        assert_eq!(address.as_str(), "alice@example.com");
+    }
+
+    /// An address which isn't of the form `local@domain.tld` is malformed.
+    #[test]
+    fn malformed_address_is_rejected() {
+        for address in ["alice", "alice@example", "alice@@example.com"] {
+            assert_eq!(rejection(address), "malformed email address");
+        }
+    }
+
+    /// An address longer than the maximum is rejected as too long.
+    #[test]
+    fn long_address_is_rejected() {
+        let domain = "a".repeat(EMAIL_MAX_LENGTH);
+        assert_eq!(
+            rejection(&format!("alice@{domain}.com")),
+            format!("email address must be at most {EMAIL_MAX_LENGTH} characters")
+        );
+    }
+
+    /// An address with too long a local part is rejected, even though the
+    /// address as a whole isn't too long.
+    #[test]
+    fn long_local_part_is_rejected() {
+        let local = "a".repeat(EMAIL_LOCAL_PART_MAX_LENGTH.saturating_add(1));
+        assert_eq!(
+            rejection(&format!("{local}@example.com")),
+            format!(
+                "email address must have at most {EMAIL_LOCAL_PART_MAX_LENGTH} characters before the @"
+            )
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Conditional JSON responses, so that clients can revalidate cached
+//! responses rather than re-downloading them when nothing has changed.
+use axum::{
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse as _, Response},
+};
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+
+/// Responses depend on the session (e.g. whether unlisted products are
+/// visible), so must not be stored by shared caches, and must always be
+/// revalidated since they may change at any time.
+const CACHE_POLICY: &str = "private, no-cache";
+
+/// Compute a weak `ETag` for a JSON body from a hash of its contents and, if
+/// given, the version of the data it was built from.
+fn weak_etag(body: &[u8], version: Option<u64>) -> String {
+    let mut hasher = Sha256::new();
+    if let Some(number) = version {
+        hasher.update(format!("{number}:"));
+    }
+    hasher.update(body);
+    format!("W/\"{:x}\"", hasher.finalize())
+}
+
+/// Whether an If-None-Match header value matches the given `ETag`. Uses weak
+/// comparison, as required for If-None-Match, so the W/ prefix is ignored.
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    let opaque = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == opaque)
+}
+
+/// Respond with `value` serialized as JSON, tagged with a weak `ETag`. If the
+/// request's If-None-Match header already matches the `ETag`, an empty 304
+/// Not Modified is returned instead.
+pub fn conditional_json<T: Serialize>(request_headers: &HeaderMap, value: &T) -> Response {
+    respond(request_headers, value, None)
+}
+
+/// Respond as `conditional_json`, but with `version` (e.g. of the product
+/// listings) also incorporated into the `ETag`, so that advancing the version
+/// invalidates every response tagged with the previous one.
+pub fn conditional_json_versioned<T: Serialize>(
+    request_headers: &HeaderMap,
+    value: &T,
+    version: u64,
+) -> Response {
+    respond(request_headers, value, Some(version))
+}
+
+/// Serialize `value` and respond with it, or with 304 Not Modified if the
+/// request already has the current `ETag`.
+fn respond<T: Serialize>(request_headers: &HeaderMap, value: &T, version: Option<u64>) -> Response {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!("Error serializing response body: {err}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let etag = weak_etag(&body, version);
+    let not_modified = request_headers
+        .get(IF_NONE_MATCH)
+        .and_then(|header| header.to_str().ok())
+        .is_some_and(|header| matches_etag(header, &etag));
+    let mut response = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        ([(CONTENT_TYPE, "application/json")], body).into_response()
+    };
+    let headers = response.headers_mut();
+    headers.insert(
+        ETAG,
+        HeaderValue::from_str(&etag).expect("A hex digest is always a valid header value"),
+    );
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static(CACHE_POLICY));
+    response
+}
@@ -6,7 +6,7 @@ use axum::{
 };
 use serde_json::json;
 
-use crate::db::errors::DatabaseError;
+use crate::{db::errors::DatabaseError, middleware::request_id::current_request_id};
 
 /// Represents an HTTP status code, optionally with a custom message.
 pub struct HttpError {
@@ -37,13 +37,17 @@ impl IntoResponse for HttpError {
         let message = self
             .message
             .unwrap_or_else(|| self.status.canonical_reason().unwrap_or("").to_owned());
-        (self.status, Json(json!({"message": message}))).into_response()
+        (
+            self.status,
+            Json(json!({"message": message, "request_id": current_request_id()})),
+        )
+            .into_response()
     }
 }
 
 impl From<DatabaseError> for HttpError {
     fn from(err: DatabaseError) -> Self {
-        eprintln!("Error raised from database in handler: {err}");
+        tracing::error!("Error raised from database in handler: {err}");
         Self::new(StatusCode::INTERNAL_SERVER_ERROR, Some(err.to_string()))
     }
 }
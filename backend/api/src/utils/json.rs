@@ -0,0 +1,204 @@
+//! A JSON request body extractor which reports exactly which field was invalid.
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse as _, Response},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{error::Category, json};
+
+use super::httperror::HttpError;
+use crate::middleware::request_id::current_request_id;
+
+/// A drop-in replacement for `axum::Json` when extracting request bodies. On a
+/// deserialization failure, responds with the path of the offending field and
+/// the reason it was rejected, rather than an opaque status code.
+pub struct ValidatedJson<T>(pub T);
+
+/// Whether the request declares a JSON body in its Content-Type header.
+fn has_json_content_type(req: &Request) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim)
+        .is_some_and(|mime| {
+            mime == "application/json"
+                || (mime.starts_with("application/") && mime.ends_with("+json"))
+        })
+}
+
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !has_json_content_type(&req) {
+            tracing::warn!("Request body is missing a JSON Content-Type header.");
+            return Err(HttpError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                Some(String::from(
+                    "Expected request with Content-Type: application/json",
+                )),
+            )
+            .into_response());
+        }
+        let body = Bytes::from_request(req, state).await.map_err(|rejection| {
+            // e.g. the body exceeds the size limit, reported in the same shape
+            // as every other error rather than Axum's plain text.
+            tracing::warn!("Failed to read request body: {rejection}");
+            HttpError::new(rejection.status(), Some(rejection.body_text())).into_response()
+        })?;
+        deserialize_body(&body)
+            .map(Self)
+            .map_err(|(field, err)| rejection(&field, &err))
+    }
+}
+
+/// Deserialize a whole request body, rejecting it if anything other than
+/// whitespace follows the JSON value. Fails with the path of the offending
+/// field, which is `.` for the body as a whole, and the reason.
+fn deserialize_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, (String, serde_json::Error)> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let value = serde_path_to_error::deserialize(&mut deserializer)
+        .map_err(|err| (err.path().to_string(), err.into_inner()))?;
+    deserializer.end().map_err(|err| (String::from("."), err))?;
+    Ok(value)
+}
+
+/// The response rejecting a request body whose `field` is invalid, with
+/// malformed JSON reported as a bad request and invalid values as
+/// unprocessable.
+fn rejection(field: &str, err: &serde_json::Error) -> Response {
+    let (status, error_code) = match err.classify() {
+        Category::Data => (StatusCode::UNPROCESSABLE_ENTITY, "INVALID_FIELD"),
+        Category::Syntax | Category::Eof | Category::Io => {
+            (StatusCode::BAD_REQUEST, "MALFORMED_JSON")
+        }
+    };
+    let reason = err.to_string();
+    tracing::warn!("Rejected request body, field {field} is invalid: {reason}");
+    (
+        status,
+        Json(json!({
+            "message": "Invalid request body",
+            "error_code": error_code,
+            "errors": [{"field": field, "reason": reason}],
+            "request_id": current_request_id(),
+        })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body as RequestBody,
+        http::{header::CONTENT_TYPE, Method, StatusCode},
+    };
+    use serde::Deserialize;
+    use serde_json::{error::Category, Value};
+
+    use super::{deserialize_body, rejection};
+    use crate::testing::{TestApp, TestResponse};
+
+    /// A request body to deserialize.
+    #[derive(Deserialize, Debug, PartialEq, Eq)]
+    struct Body {
+        /// A nested field.
+        inner: Inner,
+    }
+
+    /// A field nested within a request body.
+    #[derive(Deserialize, Debug, PartialEq, Eq)]
+    struct Inner {
+        /// A numeric field.
+        count: u32,
+    }
+
+    /// A valid body is deserialized, ignoring surrounding whitespace.
+    #[test]
+    fn accepts_valid_body() {
+        assert_eq!(
+            deserialize_body::<Body>(b" {\"inner\": {\"count\": 3}}\n").ok(),
+            Some(Body {
+                inner: Inner { count: 3 }
+            })
+        );
+    }
+
+    /// An invalid value is reported with the path to its field, as
+    /// unprocessable.
+    #[test]
+    fn reports_invalid_field() {
+        let (field, err) = deserialize_body::<Body>(br#"{"inner": {"count": -1}}"#)
+            .expect_err("Negative count should be rejected");
+        assert_eq!(field, "inner.count");
+        assert_eq!(err.classify(), Category::Data);
+        assert_eq!(
+            rejection(&field, &err).status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    /// Malformed JSON is a bad request.
+    #[test]
+    fn rejects_malformed_json() {
+        let (field, err) = deserialize_body::<Body>(br#"{"inner": "#)
+            .expect_err("Truncated body should be rejected");
+        assert_eq!(rejection(&field, &err).status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Anything after the JSON value is rejected as malformed.
+    #[test]
+    fn rejects_trailing_data() {
+        let (field, err) = deserialize_body::<Body>(br#"{"inner": {"count": 3}} {}"#)
+            .expect_err("Trailing data should be rejected");
+        assert_eq!(field, ".");
+        assert_eq!(err.classify(), Category::Syntax);
+        assert_eq!(rejection(&field, &err).status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Send a raw JSON request body to the login route, which never reaches
+    /// the database if the body is rejected.
+    async fn post_raw(body: Vec<u8>) -> TestResponse {
+        let mut app = TestApp::without_db();
+        let request = app
+            .request(Method::POST, "/auth")
+            .header(CONTENT_TYPE, "application/json")
+            .body(RequestBody::from(body))
+            .expect("Request should be valid");
+        app.send(request).await
+    }
+
+    /// Malformed JSON posted to a route is rejected with a structured error
+    /// body, rather than Axum's plain text.
+    #[tokio::test]
+    async fn malformed_json_gets_structured_error() {
+        let response = post_raw(b"email=alice@example.com".to_vec()).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.string_at("/error_code"), "MALFORMED_JSON");
+        assert_eq!(response.string_at("/message"), "Invalid request body");
+        assert_eq!(response.string_at("/errors/0/field"), ".");
+        assert!(response.json().pointer("/request_id").is_some());
+    }
+
+    /// An oversized body is rejected in the same JSON shape as other errors.
+    #[tokio::test]
+    async fn oversized_body_gets_structured_error() {
+        let mut body = br#"{"email": ""#.to_vec();
+        body.resize(3 * 1024 * 1024, b'a');
+        let response = post_raw(body).await;
+        assert_eq!(response.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(response
+            .json()
+            .pointer("/message")
+            .and_then(Value::as_str)
+            .is_some_and(|message| !message.is_empty()));
+    }
+}
@@ -0,0 +1,114 @@
+//! Delivery of emails, abstracted behind a trait so that the provider used can
+//! be swapped out per deployment.
+use alloc::sync::Arc;
+use core::{future::Future, pin::Pin};
+
+use thiserror::Error;
+
+use super::email::EmailAddress;
+use crate::constants::{config::MailgunSettings, mail::MAILGUN};
+
+/// The future returned by `EmailSender::send`.
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<(), MailError>> + Send + 'a>>;
+
+/// Something capable of delivering an email to an address.
+pub trait EmailSender: Send + Sync {
+    /// Send an email with a given subject and plain text body to the given
+    /// recipient.
+    fn send<'a>(
+        &'a self,
+        recipient: &'a EmailAddress,
+        subject: &'a str,
+        body: &'a str,
+    ) -> SendFuture<'a>;
+    /// Whether emails are actually delivered, i.e. a provider is configured.
+    fn is_configured(&self) -> bool {
+        true
+    }
+}
+
+/// An `EmailSender` which discards every email. Used when no email provider
+/// is configured.
+pub struct NoopEmailSender;
+
+impl EmailSender for NoopEmailSender {
+    fn send<'a>(
+        &'a self,
+        _recipient: &'a EmailAddress,
+        _subject: &'a str,
+        _body: &'a str,
+    ) -> SendFuture<'a> {
+        Box::pin(async {
+            tracing::warn!("No email provider is configured, discarding email.");
+            Ok(())
+        })
+    }
+    fn is_configured(&self) -> bool {
+        false
+    }
+}
+
+/// An `EmailSender` which delivers emails through Mailgun's messages API.
+pub struct MailgunEmailSender {
+    /// The HTTP client requests to Mailgun are made with.
+    client: reqwest::Client,
+    /// The Mailgun domain emails are sent through.
+    settings: &'static MailgunSettings,
+}
+
+impl MailgunEmailSender {
+    /// Construct a sender for the given Mailgun domain.
+    pub fn new(settings: &'static MailgunSettings) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            settings,
+        }
+    }
+}
+
+impl EmailSender for MailgunEmailSender {
+    fn send<'a>(
+        &'a self,
+        recipient: &'a EmailAddress,
+        subject: &'a str,
+        body: &'a str,
+    ) -> SendFuture<'a> {
+        Box::pin(async move {
+            self.client
+                .post(format!(
+                    "{}/v3/{}/messages",
+                    self.settings.api_base.trim_end_matches('/'),
+                    self.settings.domain
+                ))
+                .basic_auth("api", Some(&self.settings.api_key))
+                .form(&[
+                    ("from", self.settings.from.as_str()),
+                    ("to", recipient.as_str()),
+                    ("subject", subject),
+                    ("text", body),
+                ])
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|err| MailError(err.to_string()))?;
+            Ok(())
+        })
+    }
+    fn is_configured(&self) -> bool {
+        true
+    }
+}
+
+/// Get the `EmailSender` for the configured provider, or a `NoopEmailSender`
+/// if none is configured.
+pub fn configured_sender() -> Arc<dyn EmailSender> {
+    match *MAILGUN {
+        Some(settings) => Arc::new(MailgunEmailSender::new(settings)),
+        None => Arc::new(NoopEmailSender),
+    }
+}
+
+/// An error returned while sending an email.
+#[derive(Error, Debug)]
+#[error("Failed to deliver email: {0}")]
+pub struct MailError(pub String);
@@ -1,3 +1,18 @@
 //! Useful utilities used across the application in miscellaneous places.
+pub mod access;
+pub mod address;
+pub mod client_ip;
+pub mod cookies;
+pub mod csv;
 pub mod email;
+pub mod etag;
 pub mod httperror;
+pub mod json;
+pub mod mailer;
+pub mod pagination;
+pub mod pennies;
+pub mod phone;
+pub mod redact;
+pub mod retry;
+pub mod sms;
+pub mod text;
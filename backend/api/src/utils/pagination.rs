@@ -0,0 +1,122 @@
+//! The pagination shared by every list endpoint. Offset pagination is used by
+//! default, and some lists also support cursors, which keep pages stable when
+//! rows are inserted between fetching them.
+use axum::{
+    extract::{FromRequestParts, Query},
+    http::{request::Parts, StatusCode},
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine as _};
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+
+use super::httperror::HttpError;
+use crate::constants::pagination::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+
+/// The pagination query parameters exactly as provided by the client.
+#[derive(Deserialize)]
+struct PaginationParameters {
+    /// The number of results requested.
+    limit: Option<u32>,
+    /// The number of results to skip.
+    offset: Option<u32>,
+}
+
+/// The page of results to return from a list endpoint. Extracted from the
+/// `limit` and `offset` query parameters, with `limit` defaulting to
+/// `DEFAULT_PAGE_SIZE` and clamped to at most `MAX_PAGE_SIZE`. Included in
+/// list responses so that clients can see the limit actually applied.
+#[derive(Serialize, Clone, Copy)]
+pub struct Pagination {
+    /// The maximum number of results returned.
+    pub limit: u32,
+    /// The number of results skipped before the first returned.
+    pub offset: u32,
+}
+
+impl Pagination {
+    /// Construct a page from the requested limit and offset, applying the
+    /// default and maximum page sizes.
+    pub fn new(limit: Option<u32>, offset: Option<u32>) -> Self {
+        Self {
+            limit: limit.unwrap_or(*DEFAULT_PAGE_SIZE).clamp(1, *MAX_PAGE_SIZE),
+            offset: offset.unwrap_or(0),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Pagination {
+    type Rejection = HttpError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(params) = Query::<PaginationParameters>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                tracing::warn!("Invalid pagination parameters: {err}");
+                HttpError::new(
+                    StatusCode::BAD_REQUEST,
+                    Some(String::from(
+                        "limit and offset must be non-negative integers",
+                    )),
+                )
+            })?;
+        Ok(Self::new(params.limit, params.offset))
+    }
+}
+
+/// Encode a cursor marking a position in a list of results, as returned to
+/// clients. Cursors are opaque to clients, who only pass them back unchanged.
+pub fn encode_cursor<T: Serialize>(cursor: &T) -> String {
+    BASE64_URL_SAFE_NO_PAD
+        .encode(serde_json::to_vec(cursor).expect("Serializing a cursor cannot fail."))
+}
+
+/// Deserialize an optional cursor previously encoded by `encode_cursor`.
+pub fn cursor<'de, D: Deserializer<'de>, T: DeserializeOwned>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|encoded| {
+            let bytes = BASE64_URL_SAFE_NO_PAD
+                .decode(encoded)
+                .map_err(|_err| de::Error::custom("invalid cursor"))?;
+            serde_json::from_slice(&bytes).map_err(|_err| de::Error::custom("invalid cursor"))
+        })
+        .transpose()
+}
+
+/// The cursor from which the list continues after a page of `items`, built
+/// from the last item by `cursor`. None if the page is shorter than its limit,
+/// and so is the last page.
+pub fn next_cursor<T, C: Serialize, F: Fn(&T) -> C>(
+    items: &[T],
+    page: Pagination,
+    cursor: F,
+) -> Option<String> {
+    if u32::try_from(items.len()).ok()? < page.limit {
+        return None;
+    }
+    items.last().map(|item| encode_cursor(&cursor(item)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pagination;
+    use crate::constants::pagination::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+
+    /// A missing limit defaults to `DEFAULT_PAGE_SIZE`, and a missing offset
+    /// to the start of the list.
+    #[test]
+    fn missing_limit_is_defaulted() {
+        let page = Pagination::new(None, None);
+        assert_eq!((page.limit, page.offset), (*DEFAULT_PAGE_SIZE, 0));
+    }
+
+    /// A limit over `MAX_PAGE_SIZE` is clamped to it, and a limit of zero is
+    /// raised to one, while the offset is kept.
+    #[test]
+    fn limit_is_clamped() {
+        let over_max = Pagination::new(Some(MAX_PAGE_SIZE.saturating_add(1)), Some(10));
+        assert_eq!((over_max.limit, over_max.offset), (*MAX_PAGE_SIZE, 10));
+        assert_eq!(Pagination::new(Some(0), None).limit, 1);
+        assert_eq!(Pagination::new(Some(5), None).limit, 5);
+    }
+}
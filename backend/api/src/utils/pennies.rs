@@ -0,0 +1,191 @@
+//! A monetary amount in pennies (GBP), with overflow-checked arithmetic.
+use core::fmt;
+
+/// A non-negative amount of money in pennies (GBP). Stored as an `i64`, since
+/// that is how amounts are stored in the database. All arithmetic is checked,
+/// so an overflow is always surfaced as an error rather than wrapping or
+/// panicking.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pennies(i64);
+
+impl Pennies {
+    /// Zero pennies.
+    pub const ZERO: Self = Self(0);
+
+    /// Add two amounts, failing if the result would overflow.
+    pub const fn checked_add(self, other: Self) -> Result<Self, errors::PenniesOverflow> {
+        match self.0.checked_add(other.0) {
+            Some(total) => Ok(Self(total)),
+            None => Err(errors::PenniesOverflow),
+        }
+    }
+    /// Multiply an amount by a quantity, failing if the result would overflow.
+    pub fn checked_mul(self, count: u32) -> Result<Self, errors::PenniesOverflow> {
+        self.0
+            .checked_mul(i64::from(count))
+            .map(Self)
+            .ok_or(errors::PenniesOverflow)
+    }
+    /// Take a percentage of an amount, rounded down to the nearest penny,
+    /// failing if the intermediate result would overflow.
+    pub fn checked_percentage(self, percent: u8) -> Result<Self, errors::PenniesOverflow> {
+        self.0
+            .checked_mul(i64::from(percent))
+            .and_then(|scaled| scaled.checked_div(100))
+            .map(Self)
+            .ok_or(errors::PenniesOverflow)
+    }
+    /// Scale an amount by `numerator / denominator`, rounded to the nearest
+    /// penny (halves rounding up), failing if the intermediate result would
+    /// overflow or the denominator is zero.
+    pub fn checked_ratio(
+        self,
+        numerator: u32,
+        denominator: u32,
+    ) -> Result<Self, errors::PenniesOverflow> {
+        let divisor = i64::from(denominator);
+        self.0
+            .checked_mul(i64::from(numerator))
+            .and_then(|scaled| scaled.checked_add(divisor.checked_div(2)?))
+            .and_then(|scaled| scaled.checked_div(divisor))
+            .map(Self)
+            .ok_or(errors::PenniesOverflow)
+    }
+    /// Subtract an amount, stopping at zero rather than going negative.
+    pub const fn saturating_sub(self, other: Self) -> Self {
+        let difference = self.0.saturating_sub(other.0);
+        if difference < 0 {
+            Self::ZERO
+        } else {
+            Self(difference)
+        }
+    }
+    /// Construct an amount from an `i64` as stored in the database, or None if
+    /// it is negative.
+    pub const fn from_stored(amount: i64) -> Option<Self> {
+        if amount < 0 {
+            None
+        } else {
+            Some(Self(amount))
+        }
+    }
+    /// Get the amount as an `i64`, as stored in the database.
+    pub const fn as_i64(self) -> i64 {
+        self.0
+    }
+}
+
+impl From<u32> for Pennies {
+    fn from(amount: u32) -> Self {
+        Self(i64::from(amount))
+    }
+}
+
+/// Formats the amount in pounds as a decimal, e.g. 1234 pennies as "12.34".
+impl fmt::Display for Pennies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pounds = self
+            .0
+            .checked_div(100)
+            .expect("Division by a non-zero constant cannot fail.");
+        let pence = self
+            .0
+            .checked_rem(100)
+            .expect("Remainder by a non-zero constant cannot fail.");
+        write!(f, "{pounds}.{pence:02}")
+    }
+}
+
+/// Errors returned by `Pennies` arithmetic.
+pub mod errors {
+    use thiserror::Error;
+
+    /// The result of an operation on `Pennies` was above `i64::MAX`.
+    #[derive(Error, Debug, Clone, Copy)]
+    #[error("Monetary amount out of range")]
+    pub struct PenniesOverflow;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pennies;
+
+    /// The largest amount which can be represented.
+    const MAX: Pennies = Pennies(i64::MAX);
+
+    /// Adding past `i64::MAX` fails rather than wrapping.
+    #[test]
+    fn add_overflows() {
+        assert_eq!(
+            Pennies::from(1u32).checked_add(Pennies::from(2u32)).ok(),
+            Some(Pennies(3))
+        );
+        MAX.checked_add(Pennies::from(1u32))
+            .expect_err("Result should overflow");
+    }
+
+    /// Multiplying past `i64::MAX` fails rather than wrapping.
+    #[test]
+    fn mul_overflows() {
+        assert_eq!(
+            Pennies::from(250u32).checked_mul(4).ok(),
+            Some(Pennies(1000))
+        );
+        MAX.checked_mul(2).expect_err("Result should overflow");
+    }
+
+    /// Percentages round down, and overflow in the intermediate product fails.
+    #[test]
+    fn percentage_rounds_down() {
+        assert_eq!(
+            Pennies::from(999u32).checked_percentage(10).ok(),
+            Some(Pennies(99))
+        );
+        assert_eq!(
+            Pennies::from(999u32).checked_percentage(100).ok(),
+            Some(Pennies(999))
+        );
+        MAX.checked_percentage(2)
+            .expect_err("Result should overflow");
+    }
+
+    /// Ratios round halves up, and a zero denominator fails.
+    #[test]
+    fn ratio_rounds_half_up() {
+        assert_eq!(
+            Pennies::from(5u32).checked_ratio(1, 2).ok(),
+            Some(Pennies(3))
+        );
+        assert_eq!(
+            Pennies::from(4u32).checked_ratio(1, 3).ok(),
+            Some(Pennies(1))
+        );
+        Pennies::from(5u32)
+            .checked_ratio(1, 0)
+            .expect_err("Dividing by zero should fail");
+        MAX.checked_ratio(2, 1).expect_err("Result should overflow");
+    }
+
+    /// Subtraction stops at zero.
+    #[test]
+    fn saturating_sub_stops_at_zero() {
+        assert_eq!(
+            Pennies::from(3u32).saturating_sub(Pennies::from(5u32)),
+            Pennies::ZERO
+        );
+    }
+
+    /// Negative stored amounts are rejected.
+    #[test]
+    fn from_stored_rejects_negative() {
+        assert_eq!(Pennies::from_stored(-1), None);
+        assert_eq!(Pennies::from_stored(0), Some(Pennies::ZERO));
+    }
+
+    /// Amounts are shown in pounds, with two digits of pence.
+    #[test]
+    fn displays_pounds() {
+        assert_eq!(Pennies::from(1205u32).to_string(), "12.05");
+        assert_eq!(Pennies::from(7u32).to_string(), "0.07");
+    }
+}
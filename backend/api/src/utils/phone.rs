@@ -0,0 +1,105 @@
+//! Utilities for working with and parsing/validating phone numbers.
+use core::fmt;
+use std::sync::LazyLock;
+
+use serde::{de, Deserialize, Serialize};
+
+/// Regex used to validate E.164 phone number format: a leading +, then a
+/// country code and subscriber number of at most 15 digits in total.
+static PHONE_REGEX: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"^\+[1-9][0-9]{1,14}$").expect("Phone regex invalid"));
+
+/// A struct wrapping a `String` which is guaranteed to be a valid E.164 phone number.
+#[derive(Clone, sqlx::Type)]
+#[sqlx(transparent)]
+pub struct PhoneNumber(String);
+
+impl fmt::Display for PhoneNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl TryFrom<&str> for PhoneNumber {
+    type Error = ();
+    fn try_from(string: &str) -> Result<Self, Self::Error> {
+        Self::try_from(string.to_owned())
+    }
+}
+
+impl TryFrom<String> for PhoneNumber {
+    type Error = ();
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        if PHONE_REGEX.is_match(&string) {
+            Ok(Self(string))
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl From<PhoneNumber> for String {
+    #[inline]
+    fn from(number: PhoneNumber) -> Self {
+        let PhoneNumber(inner) = number;
+        inner
+    }
+}
+
+#[expect(
+    clippy::missing_trait_methods,
+    reason = "Recommended not to implement deserialize_in_place"
+)]
+impl<'de> Deserialize<'de> for PhoneNumber {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let str = String::deserialize(deserializer)?;
+        Self::try_from(str)
+            .map_err(|_err| de::Error::custom("malformed phone number, expected E.164 format"))
+    }
+}
+
+impl Serialize for PhoneNumber {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PhoneNumber;
+
+    /// Numbers in E.164 format are accepted as given.
+    #[test]
+    fn accepts_e164() {
+        let number = PhoneNumber::try_from("+447700900123").expect("Number should be valid");
+        assert_eq!(String::from(number), "+447700900123");
+    }
+
+    /// Anything not in E.164 format is rejected.
+    #[test]
+    fn rejects_malformed() {
+        for malformed in [
+            "",
+            "447700900123",
+            "+07700900123",
+            "+44 7700 900123",
+            "+4",
+            "+1234567890123456",
+        ] {
+            assert!(PhoneNumber::try_from(malformed).is_err(), "{malformed}");
+        }
+    }
+
+    /// Deserialization applies the same validation.
+    #[test]
+    fn deserialize_validates() {
+        serde_json::from_str::<PhoneNumber>(r#""+15555550100""#).expect("Number should be valid");
+        assert!(serde_json::from_str::<PhoneNumber>(r#""555-0100""#).is_err());
+    }
+}
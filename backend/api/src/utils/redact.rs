@@ -0,0 +1,44 @@
+//! Masking of personally identifiable information before it is logged.
+use core::fmt;
+
+use super::email::EmailAddress;
+use crate::constants::logging::LOG_PII;
+
+/// Mask an email address, keeping only the first character of the local part
+/// and the domain, e.g. `j***@example.com`. Anything without an `@` is masked
+/// entirely.
+pub fn mask_email(email: &str) -> String {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return String::from("***");
+    };
+    let first = local.chars().next().map(String::from).unwrap_or_default();
+    format!("{first}***@{domain}")
+}
+
+/// Displays an email address masked by `mask_email`, or in full if `LOG_PII`
+/// is set. Use when logging an email address.
+pub struct RedactedEmail<'email>(&'email str);
+
+impl<'email> RedactedEmail<'email> {
+    /// Wrap an email address (which need not be valid) for logging.
+    pub const fn new(email: &'email str) -> Self {
+        Self(email)
+    }
+}
+
+impl<'email> From<&'email EmailAddress> for RedactedEmail<'email> {
+    #[inline]
+    fn from(email: &'email EmailAddress) -> Self {
+        Self(email.as_str())
+    }
+}
+
+impl fmt::Display for RedactedEmail<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *LOG_PII {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "{}", mask_email(self.0))
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! Retrying of fallible operations with exponential backoff, used to wait for
+//! dependencies which may not yet be available when the API starts.
+use core::{fmt::Display, future::Future, time::Duration};
+use tokio::time::sleep;
+
+use crate::constants::startup::{
+    STARTUP_RETRY_ATTEMPTS, STARTUP_RETRY_BACKOFF_MS, STARTUP_RETRY_MAX_BACKOFF,
+};
+
+/// Attempt to connect to a dependency (named for logging) until it succeeds,
+/// up to `STARTUP_RETRY_ATTEMPTS` attempts in total, waiting with exponential
+/// backoff between attempts. Returns the error from the final attempt if
+/// every attempt fails.
+pub async fn connect_with_backoff<T, E, F, Fut>(dependency: &str, mut connect: F) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut backoff = Duration::from_millis(*STARTUP_RETRY_BACKOFF_MS);
+    let mut attempt = 1u32;
+    loop {
+        match connect().await {
+            Ok(connection) => return Ok(connection),
+            Err(err) if attempt < *STARTUP_RETRY_ATTEMPTS => {
+                tracing::warn!(
+                    "Could not connect to {dependency} (attempt {attempt} of {}), retrying in {}ms: {err}",
+                    *STARTUP_RETRY_ATTEMPTS,
+                    backoff.as_millis()
+                );
+                sleep(backoff).await;
+                backoff = backoff.saturating_mul(2).min(STARTUP_RETRY_MAX_BACKOFF);
+                attempt = attempt.saturating_add(1);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Delivery of SMS messages, abstracted behind a trait so that the provider
+//! used can be swapped out per deployment.
+use alloc::sync::Arc;
+use core::{future::Future, pin::Pin};
+
+use thiserror::Error;
+
+use super::phone::PhoneNumber;
+use crate::constants::{config::TwilioSettings, sms::TWILIO};
+
+/// The future returned by `SmsSender::send`.
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<(), SmsError>> + Send + 'a>>;
+
+/// Something capable of delivering an SMS message to a phone number.
+pub trait SmsSender: Send + Sync {
+    /// Send a message to the given recipient.
+    fn send<'a>(&'a self, recipient: &'a PhoneNumber, message: &'a str) -> SendFuture<'a>;
+    /// Whether messages are actually delivered, i.e. a provider is configured.
+    fn is_configured(&self) -> bool {
+        true
+    }
+}
+
+/// An `SmsSender` which discards every message. Used when no SMS provider is
+/// configured.
+pub struct NoopSmsSender;
+
+impl SmsSender for NoopSmsSender {
+    fn send<'a>(&'a self, _recipient: &'a PhoneNumber, _message: &'a str) -> SendFuture<'a> {
+        Box::pin(async {
+            tracing::warn!("No SMS provider is configured, discarding message.");
+            Ok(())
+        })
+    }
+    fn is_configured(&self) -> bool {
+        false
+    }
+}
+
+/// An `SmsSender` which delivers messages through Twilio's Messages API.
+pub struct TwilioSmsSender {
+    /// The HTTP client requests to Twilio are made with.
+    client: reqwest::Client,
+    /// The Twilio account messages are sent through.
+    settings: &'static TwilioSettings,
+}
+
+impl TwilioSmsSender {
+    /// Construct a sender for the given Twilio account.
+    pub fn new(settings: &'static TwilioSettings) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            settings,
+        }
+    }
+}
+
+impl SmsSender for TwilioSmsSender {
+    fn send<'a>(&'a self, recipient: &'a PhoneNumber, message: &'a str) -> SendFuture<'a> {
+        Box::pin(async move {
+            self.client
+                .post(format!(
+                    "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
+                    self.settings.account_sid
+                ))
+                .basic_auth(&self.settings.account_sid, Some(&self.settings.auth_token))
+                .form(&[
+                    ("To", recipient.to_string().as_str()),
+                    ("From", self.settings.from_number.as_str()),
+                    ("Body", message),
+                ])
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|err| SmsError(err.to_string()))?;
+            Ok(())
+        })
+    }
+    fn is_configured(&self) -> bool {
+        true
+    }
+}
+
+/// Get the `SmsSender` for the configured provider, or a `NoopSmsSender` if
+/// none is configured.
+pub fn configured_sender() -> Arc<dyn SmsSender> {
+    match *TWILIO {
+        Some(settings) => Arc::new(TwilioSmsSender::new(settings)),
+        None => Arc::new(NoopSmsSender),
+    }
+}
+
+/// An error returned while sending an SMS message.
+#[derive(Error, Debug)]
+#[error("Failed to deliver SMS message: {0}")]
+pub struct SmsError(pub String);
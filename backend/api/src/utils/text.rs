@@ -0,0 +1,128 @@
+//! Sanitization of free text provided by users, such as names and addresses.
+//! Whitespace is normalised so that visually identical values are stored
+//! identically, and control characters are rejected outright.
+use serde::{de, Deserialize as _, Deserializer};
+
+use crate::constants::fields::{NAME_MAX_LENGTH, PRODUCT_NAME_MAX_LENGTH, REVIEW_MAX_LENGTH};
+
+/// Trim a line of text and collapse each run of whitespace within it to a
+/// single space.
+fn collapse_whitespace(line: &str) -> String {
+    line.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Check that sanitized text has no control characters (other than the line
+/// breaks in `allowed`) and is within the maximum length.
+fn validate(
+    text: String,
+    allowed: &[char],
+    max_length: usize,
+) -> Result<String, errors::SanitizeError> {
+    if text
+        .chars()
+        .any(|character| character.is_control() && !allowed.contains(&character))
+    {
+        Err(errors::SanitizeError::ControlCharacter)
+    } else if text.chars().count() > max_length {
+        Err(errors::SanitizeError::TooLong(max_length))
+    } else {
+        Ok(text)
+    }
+}
+
+/// Sanitize a single line of text, such as a name. Leading and trailing
+/// whitespace is removed, and every run of whitespace (including line breaks)
+/// is collapsed to a single space.
+pub fn sanitize_line(text: &str, max_length: usize) -> Result<String, errors::SanitizeError> {
+    validate(collapse_whitespace(text), &[], max_length)
+}
+
+/// Deserialize a person's forename or surname, sanitizing it.
+pub fn name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    sanitize_line(&String::deserialize(deserializer)?, NAME_MAX_LENGTH).map_err(de::Error::custom)
+}
+
+/// Deserialize an optional forename or surname, sanitizing it if present.
+pub fn optional_name<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|text| sanitize_line(&text, NAME_MAX_LENGTH).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Deserialize a product's name, sanitizing it.
+pub fn product_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    sanitize_line(&String::deserialize(deserializer)?, PRODUCT_NAME_MAX_LENGTH)
+        .map_err(de::Error::custom)
+}
+
+/// Deserialize an optional product name, sanitizing it if present.
+pub fn optional_product_name<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|text| sanitize_line(&text, PRODUCT_NAME_MAX_LENGTH).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Deserialize the text of a product review, sanitizing it.
+pub fn review<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    sanitize_line(&String::deserialize(deserializer)?, REVIEW_MAX_LENGTH).map_err(de::Error::custom)
+}
+
+/// Errors returned from this module.
+pub mod errors {
+    use thiserror::Error;
+
+    /// Errors returned when text fails sanitization.
+    #[derive(Debug, Error)]
+    pub enum SanitizeError {
+        /// The text contains a disallowed control character.
+        #[error("must not contain control characters")]
+        ControlCharacter,
+        /// The text is longer than the maximum length, in characters.
+        #[error("must be at most {0} characters")]
+        TooLong(usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{errors::SanitizeError, sanitize_line};
+
+    /// Whitespace is trimmed, and each run of it within the text (including
+    /// line breaks) is collapsed to a single space.
+    #[test]
+    fn whitespace_is_normalised() {
+        assert_eq!(
+            sanitize_line("  Mary \t Jane\n Smith ", 100).expect("Text should be valid"),
+            "Mary Jane Smith"
+        );
+    }
+
+    /// Text longer than the maximum length, once sanitized, is rejected,
+    /// counting characters rather than bytes.
+    #[test]
+    fn over_length_text_is_rejected() {
+        assert_eq!(
+            sanitize_line(&format!("  {}  ", "\u{e9}".repeat(5)), 5).expect("Text should be valid"),
+            "\u{e9}".repeat(5)
+        );
+        assert!(matches!(
+            sanitize_line(&"\u{e9}".repeat(6), 5),
+            Err(SanitizeError::TooLong(5))
+        ));
+    }
+
+    /// Control characters other than whitespace are rejected.
+    #[test]
+    fn control_characters_are_rejected() {
+        for text in ["Alice\u{0}", "Al\u{1b}[31mice", "Alice\u{7f}"] {
+            assert!(matches!(
+                sanitize_line(text, 100),
+                Err(SanitizeError::ControlCharacter)
+            ));
+        }
+    }
+}
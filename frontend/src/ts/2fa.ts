@@ -1,3 +1,7 @@
+interface MfaAuthenticationResponse {
+  totp_enrolment_required: boolean;
+}
+
 function show_2fa_failure_modal(message: string) {
   document.getElementById("failure-modal-body")!.textContent = message;
   const modal = new bootstrap.Modal(document.getElementById("failure-modal")!);
@@ -18,7 +22,12 @@ async function attempt_2fa() {
     }),
   });
   if (response.status === 200) {
-    window.location.replace("/");
+    const body: MfaAuthenticationResponse = await response.json();
+    if (body.totp_enrolment_required) {
+      window.location.replace("/enroll2fa.html");
+    } else {
+      window.location.replace("/");
+    }
   } else if (response.status === 401) {
     show_2fa_failure_modal("Incorrect 2fa code. Please try again.");
   } else if (response.status === 500) {
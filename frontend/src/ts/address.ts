@@ -0,0 +1,42 @@
+interface Address {
+  line1: string;
+  line2: string | null;
+  city: string;
+  postcode: string;
+  country: string;
+}
+
+function format_address(address: Address): string {
+  return [
+    address.line1,
+    address.line2,
+    address.city,
+    address.postcode,
+    address.country,
+  ]
+    .filter((part) => part)
+    .join(", ");
+}
+
+function address_field(name: string): HTMLInputElement {
+  return document.getElementById(`address-${name}`) as HTMLInputElement;
+}
+
+function read_address_fields(): Address {
+  const line2 = address_field("line2").value.trim();
+  return {
+    line1: address_field("line1").value.trim(),
+    line2: line2.length === 0 ? null : line2,
+    city: address_field("city").value.trim(),
+    postcode: address_field("postcode").value.trim(),
+    country: address_field("country").value.trim().toUpperCase(),
+  };
+}
+
+function fill_address_fields(address: Address): void {
+  address_field("line1").value = address.line1;
+  address_field("line2").value = address.line2 ?? "";
+  address_field("city").value = address.city;
+  address_field("postcode").value = address.postcode;
+  address_field("country").value = address.country;
+}
@@ -3,7 +3,7 @@ interface User {
     email: string;
     forename: string;
     surname: string;
-    address: string;
+    address: Address;
     role: string;
 }
 
@@ -76,7 +76,7 @@ function render_users(users: User[]) {
             `${user.forename} ${user.surname}`;
         document.getElementById(`user-${user.id}-email`)!.textContent = user.email;
         document.getElementById(`user-${user.id}-address`)!.textContent =
-            user.address;
+            format_address(user.address);
     });
 }
 
@@ -1,14 +1,42 @@
+interface CsrfConfig {
+  cookie_name: string;
+  header_name: string;
+}
+
+let csrf_config: Promise<CsrfConfig> | undefined;
+
+// The names of the CSRF cookie and header are configured by the API, so are
+// fetched from it once per page.
+function get_csrf_config(): Promise<CsrfConfig> {
+  if (csrf_config === undefined) {
+    csrf_config = fetch("/api/auth/csrf/config").then((response) => {
+      if (!response.ok) {
+        throw new Error("Could not fetch CSRF configuration");
+      }
+      return response.json() as Promise<CsrfConfig>;
+    });
+    // Let a failed fetch be retried by the next request.
+    csrf_config.catch(() => {
+      csrf_config = undefined;
+    });
+  }
+  return csrf_config;
+}
+
 async function fetch_csrf(uri: string, params?: RequestInit) {
+  const { cookie_name, header_name } = await get_csrf_config();
+  const prefix = `${cookie_name}=`;
   const csrf_token = document.cookie
     .split(";")
-    .filter((c) => c.startsWith("session_csrf="))
-    .map((c) => c.substring("session_csrf=".length, c.length))
+    .map((c) => c.trim())
+    .filter((c) => c.startsWith(prefix))
+    .map((c) => c.substring(prefix.length, c.length))
     .pop();
   if (csrf_token === undefined) {
     throw new Error("CSRF token cookie is not set");
   }
   const headers = params ? new Headers(params.headers) : new Headers();
-  headers.set("X-CSRF-Token", csrf_token);
+  headers.set(header_name, csrf_token);
   return fetch(uri, {
     ...params,
     headers,
@@ -1,5 +1,6 @@
 interface AuthenticationResponse {
   mfa_required: boolean;
+  totp_enrolment_required: boolean;
 }
 
 function show_failure_modal(message: string) {
@@ -11,6 +12,9 @@ async function attempt_login() {
   const email = (document.getElementById("email")! as HTMLInputElement).value;
   const password = (document.getElementById("password")! as HTMLInputElement)
     .value;
+  const remember_me = (
+    document.getElementById("remember_me")! as HTMLInputElement
+  ).checked;
   const response = await fetch("/api/auth", {
     method: "post",
     headers: { "Content-Type": "application/json" },
@@ -21,12 +25,15 @@ async function attempt_login() {
           password: password,
         },
       },
+      remember_me: remember_me,
     }),
   });
   if (response.status === 200) {
     const body: AuthenticationResponse = await response.json();
     if (body.mfa_required) {
       window.location.replace("/2fa.html");
+    } else if (body.totp_enrolment_required) {
+      window.location.replace("/enroll2fa.html");
     } else {
       window.location.replace("/");
     }
@@ -15,7 +15,7 @@ interface User {
   email: string;
   forename: string;
   surname: string;
-  address: string;
+  address: Address;
 }
 
 document.addEventListener("DOMContentLoaded", init_order_page);
@@ -62,7 +62,11 @@ async function init_order_page() {
 
   await render_order(order_data, is_admin);
 
-  if (is_admin && order_data.order.status === "Confirmed") {
+  if (
+    is_admin &&
+    (order_data.order.status === "Confirmed" ||
+      order_data.order.status === "PartiallyFulfilled")
+  ) {
     show_fulfil_button(order_id);
   }
 }
@@ -118,7 +122,7 @@ async function render_order(
   const shipping_address_p = document.createElement("p");
   const shipping_address_label = document.createElement("strong");
   shipping_address_label.textContent = "Shipping Address: ";
-  shipping_address_p.append(shipping_address_label, user_info ? user_info.address : "");
+  shipping_address_p.append(shipping_address_label, user_info ? format_address(user_info.address) : "");
 
   const items_header = document.createElement("h3");
   items_header.textContent = "Items";
@@ -35,13 +35,19 @@ async function attempt_registration() {
     show_signup_failure_modal("email cannot be empty");
     return;
   }
-  const address = (
-    document.getElementById("address") as HTMLInputElement
-  ).value.trim();
-  if (address.length === 0) {
+  const address = read_address_fields();
+  if (
+    address.line1.length === 0 ||
+    address.city.length === 0 ||
+    address.postcode.length === 0
+  ) {
     show_signup_failure_modal("Address cannot be empty");
     return;
   }
+  if (address.country.length !== 2) {
+    show_signup_failure_modal("Country must be a two letter country code");
+    return;
+  }
   const password = (document.getElementById("password") as HTMLInputElement)
     .value;
   const confirm = (
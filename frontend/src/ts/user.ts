@@ -3,7 +3,7 @@ interface UserData {
     email: string;
     forename: string;
     surname: string;
-    address: string;
+    address: Address;
     role: string;
 }
 
@@ -77,8 +77,7 @@ function populate_form(user: UserData): void {
     (document.getElementById("forename") as HTMLInputElement).value =
         user.forename;
     (document.getElementById("surname") as HTMLInputElement).value = user.surname;
-    (document.getElementById("address") as HTMLTextAreaElement).value =
-        user.address;
+    fill_address_fields(user.address);
     (document.getElementById("role_display") as HTMLElement).textContent =
         user.role;
 
@@ -100,8 +99,7 @@ async function update_user(event: Event): Promise<void> {
         .value;
     const surname = (document.getElementById("surname") as HTMLInputElement)
         .value;
-    const address = (document.getElementById("address") as HTMLTextAreaElement)
-        .value;
+    const address = read_address_fields();
 
     const user_url = target_user_id
         ? `/api/users/${target_user_id}`